@@ -0,0 +1,109 @@
+//! Lay out every handoff type for the loader's and the kernel's targets, and
+//! save the results as snapshots for the tests to check.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[path = "src/layout.rs"]
+#[allow(dead_code)]
+mod layout;
+
+use layout::{DataLayout, Layout, HANDOFF_TYPES};
+
+/// The bootloader's UEFI stage is built for this built-in target
+const LOADER_TARGET: &str = "x86_64-unknown-uefi";
+
+/// The kernel's custom target specification
+const KERNEL_TARGET: &str = "../kernel/src/arch/x86_64/x86_64-kernel.json";
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let kernel_target = manifest_dir.join(KERNEL_TARGET);
+    println!("cargo:rerun-if-changed={}", kernel_target.display());
+    println!("cargo:rerun-if-changed=src/layout.rs");
+
+    let abis = [
+        (
+            "loader",
+            LOADER_TARGET.to_string(),
+            builtin_data_layout(LOADER_TARGET),
+        ),
+        (
+            "kernel",
+            "x86_64-kernel".to_string(),
+            file_data_layout(&kernel_target),
+        ),
+    ];
+
+    let mut out = String::from("/// Handoff type layouts for each side of the handoff\n");
+    out.push_str("pub const SNAPSHOTS: &[Snapshot] = &[\n");
+    for (abi, target, data_layout) in &abis {
+        let parsed = DataLayout::parse(data_layout)
+            .unwrap_or_else(|err| panic!("bad data layout for {target}: {err}"));
+        writeln!(
+            out,
+            "    Snapshot {{ abi: {abi:?}, target: {target:?}, data_layout: {data_layout:?}, \
+             types: &["
+        )
+        .unwrap();
+        for &(name, ty) in HANDOFF_TYPES {
+            let Layout {
+                size,
+                align,
+                fields,
+            } = Layout::of(ty, &parsed);
+            writeln!(
+                out,
+                "        TypeSnapshot {{ name: {name:?}, size: {size}, align: {align}, \
+                 fields: &{fields:?} }},"
+            )
+            .unwrap();
+        }
+        out.push_str("    ] },\n");
+    }
+    out.push_str("];\n");
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("snapshots.rs"), out).unwrap();
+}
+
+/// The data layout of one of rustc's built-in targets
+fn builtin_data_layout(target: &str) -> String {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc)
+        .args([
+            "-Z",
+            "unstable-options",
+            "--print",
+            "target-spec-json",
+            "--target",
+            target,
+        ])
+        .output()
+        .expect("could not run rustc");
+    assert!(
+        output.status.success(),
+        "could not get the target spec for {target}: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    data_layout(&output.stdout, target)
+}
+
+/// The data layout of a custom target specification
+fn file_data_layout(path: &Path) -> String {
+    let spec =
+        fs::read(path).unwrap_or_else(|err| panic!("could not read {}: {err}", path.display()));
+    data_layout(&spec, &path.display().to_string())
+}
+
+fn data_layout(spec: &[u8], target: &str) -> String {
+    let spec: serde_json::Value = serde_json::from_slice(spec)
+        .unwrap_or_else(|err| panic!("bad target spec for {target}: {err}"));
+    spec["data-layout"]
+        .as_str()
+        .unwrap_or_else(|| panic!("no data layout for {target}"))
+        .to_string()
+}
@@ -0,0 +1,205 @@
+//! A model of `repr(C)` layout, parameterized by a target's LLVM data layout
+//! string.
+//!
+//! This is shared with the build script (which includes it by path), so it
+//! only depends on `std`.
+
+use std::fmt;
+
+/// The parts of an LLVM data layout string that `repr(C)` layout depends on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataLayout {
+    pub little_endian: bool,
+    pub pointer_size: u64,
+    pub pointer_align: u64,
+    /// ABI alignment of `i8`, `i16`, `i32`, and `i64`, in bytes
+    pub int_align: [u64; 4],
+}
+
+impl DataLayout {
+    /// Parse a data layout string, like `e-m:e-i64:64-f80:128-n8:16:32:64-S128`.
+    /// Anything not given explicitly gets LLVM's default.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut layout = DataLayout {
+            little_endian: true,
+            pointer_size: 8,
+            pointer_align: 8,
+            int_align: [1, 2, 4, 4],
+        };
+
+        for item in spec.split('-').filter(|item| !item.is_empty()) {
+            let bits = |s: &str| -> Result<u64, String> {
+                s.parse::<u64>()
+                    .map(|bits| bits / 8)
+                    .map_err(|_| format!("bad size `{s}` in `{item}`"))
+            };
+            match item.as_bytes()[0] {
+                b'e' => layout.little_endian = true,
+                b'E' => layout.little_endian = false,
+                b'p' => {
+                    let mut parts = item.split(':');
+                    // Only the default address space matters for Rust pointers
+                    if !matches!(parts.next(), Some("p" | "p0")) {
+                        continue;
+                    }
+                    layout.pointer_size = bits(parts.next().unwrap_or("64"))?;
+                    layout.pointer_align = bits(parts.next().unwrap_or("64"))?;
+                }
+                b'i' => {
+                    let mut parts = item[1..].split(':');
+                    let index = match bits(parts.next().unwrap_or_default())? {
+                        1 => 0,
+                        2 => 1,
+                        4 => 2,
+                        8 => 3,
+                        _ => continue,
+                    };
+                    layout.int_align[index] = bits(parts.next().unwrap_or("8"))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(layout)
+    }
+
+    fn int_align(&self, bits: u64) -> u64 {
+        match bits {
+            8 => self.int_align[0],
+            16 => self.int_align[1],
+            32 => self.int_align[2],
+            64 => self.int_align[3],
+            _ => panic!("no {bits}-bit integers in the handoff"),
+        }
+    }
+}
+
+/// A type that crosses the loader/kernel boundary, described well enough to
+/// lay it out
+#[derive(Debug, Clone, Copy)]
+pub enum Ty {
+    Int(u64),
+    Bool,
+    /// A pointer or `usize`
+    Pointer,
+    /// A `repr(C)` struct
+    Struct(&'static [(&'static str, Ty)]),
+    /// A `repr(C)` enum with data: a C `int` tag followed by a union of each
+    /// variant's fields, laid out as a `repr(C)` struct
+    Enum(&'static [&'static [Ty]]),
+}
+
+/// Where a type's fields are, for one ABI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u64,
+    pub align: u64,
+    pub fields: Vec<(&'static str, u64)>,
+}
+
+impl Layout {
+    pub fn of(ty: Ty, abi: &DataLayout) -> Self {
+        match ty {
+            Ty::Int(bits) => Self::scalar(bits / 8, abi.int_align(bits)),
+            Ty::Bool => Self::scalar(1, abi.int_align(8)),
+            Ty::Pointer => Self::scalar(abi.pointer_size, abi.pointer_align),
+            Ty::Struct(fields) => Self::sequence(fields.iter().copied(), abi),
+            Ty::Enum(variants) => {
+                let payloads = variants
+                    .iter()
+                    .map(|fields| Self::sequence(fields.iter().map(|&ty| ("", ty)), abi))
+                    .collect::<Vec<_>>();
+                let size = payloads.iter().map(|p| p.size).max().unwrap_or(0);
+                let align = payloads.iter().map(|p| p.align).max().unwrap_or(1);
+                let union = Self {
+                    size: size.next_multiple_of(align),
+                    align,
+                    fields: Vec::new(),
+                };
+
+                let tag = Self::of(Ty::Int(32), abi);
+                let (size, align, offsets) = Self::place([tag, union].iter());
+                Self {
+                    size,
+                    align,
+                    fields: vec![("tag", offsets[0]), ("payload", offsets[1])],
+                }
+            }
+        }
+    }
+
+    fn scalar(size: u64, align: u64) -> Self {
+        Self {
+            size,
+            align,
+            fields: Vec::new(),
+        }
+    }
+
+    fn sequence(fields: impl Iterator<Item = (&'static str, Ty)>, abi: &DataLayout) -> Self {
+        let (names, layouts): (Vec<_>, Vec<_>) =
+            fields.map(|(name, ty)| (name, Self::of(ty, abi))).unzip();
+        let (size, align, offsets) = Self::place(layouts.iter());
+        Self {
+            size,
+            align,
+            fields: names.into_iter().zip(offsets).collect(),
+        }
+    }
+
+    /// Place `members` one after another, returning the overall size and
+    /// alignment and each member's offset
+    fn place<'a>(members: impl Iterator<Item = &'a Layout>) -> (u64, u64, Vec<u64>) {
+        let mut offset = 0u64;
+        let mut align = 1;
+        let mut offsets = Vec::new();
+        for member in members {
+            offset = offset.next_multiple_of(member.align);
+            offsets.push(offset);
+            offset += member.size;
+            align = align.max(member.align);
+        }
+        (offset.next_multiple_of(align), align, offsets)
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "size {}, align {}", self.size, self.align)?;
+        for (name, offset) in &self.fields {
+            write!(f, ", {name} at {offset}")?;
+        }
+        Ok(())
+    }
+}
+
+const U64: Ty = Ty::Int(64);
+
+const MEMORY_REGION_KIND: Ty = Ty::Enum(&[&[], &[], &[Ty::Int(32)], &[Ty::Int(32)]]);
+
+const TLS_TEMPLATE: Ty = Ty::Struct(&[("start_addr", U64), ("file_size", U64), ("mem_size", U64)]);
+
+/// Every `bootloader_api` type the kernel reads out of the handoff, other
+/// than `BootInfo` itself, which is made of these and primitives
+pub const HANDOFF_TYPES: &[(&str, Ty)] = &[
+    (
+        "ApiVersion",
+        Ty::Struct(&[
+            ("version_major", Ty::Int(16)),
+            ("version_minor", Ty::Int(16)),
+            ("version_patch", Ty::Int(16)),
+            ("pre_release", Ty::Bool),
+        ]),
+    ),
+    (
+        "MemoryRegions",
+        Ty::Struct(&[("ptr", Ty::Pointer), ("len", Ty::Pointer)]),
+    ),
+    (
+        "MemoryRegion",
+        Ty::Struct(&[("start", U64), ("end", U64), ("kind", MEMORY_REGION_KIND)]),
+    ),
+    ("MemoryRegionKind", MEMORY_REGION_KIND),
+    ("TlsTemplate", TLS_TEMPLATE),
+    ("Optional<u64>", Ty::Enum(&[&[U64], &[]])),
+    ("Optional<TlsTemplate>", Ty::Enum(&[&[TLS_TEMPLATE], &[]])),
+];
@@ -0,0 +1,239 @@
+//! Host-side checks of the contract between the bootloader and the kernel.
+//!
+//! The bootloader's UEFI stage fills in a `BootInfo` for the kernel, but the
+//! two are built for different targets, and nothing except the `repr(C)`
+//! attributes in `bootloader_api` keeps their views of it in sync. If the
+//! targets ever disagree about how those structures are laid out, the kernel
+//! reads garbage out of the memory map long before it can report anything
+//! useful.
+//!
+//! The build script models the layout of each handoff type under both
+//! targets' data layouts and saves the results as [`SNAPSHOTS`]. The tests
+//! check that the snapshots agree with each other, that they match what rustc
+//! actually does with the `bootloader_api` types, and that example memory maps
+//! read back the same from raw bytes.
+
+pub mod layout;
+
+/// Layouts of every handoff type for one target
+#[derive(Debug)]
+pub struct Snapshot {
+    /// Which side of the handoff this is
+    pub abi: &'static str,
+    pub target: &'static str,
+    pub data_layout: &'static str,
+    pub types: &'static [TypeSnapshot],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TypeSnapshot {
+    pub name: &'static str,
+    pub size: u64,
+    pub align: u64,
+    pub fields: &'static [(&'static str, u64)],
+}
+
+impl Snapshot {
+    pub fn get(&self, name: &str) -> &TypeSnapshot {
+        self.types
+            .iter()
+            .find(|ty| ty.name == name)
+            .unwrap_or_else(|| panic!("no snapshot of {name} for {}", self.abi))
+    }
+}
+
+impl TypeSnapshot {
+    pub fn offset(&self, field: &str) -> usize {
+        self.fields
+            .iter()
+            .find(|(name, _)| *name == field)
+            .unwrap_or_else(|| panic!("{} has no field {field}", self.name))
+            .1 as usize
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/snapshots.rs"));
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{align_of, offset_of, size_of};
+
+    use bootloader_api::info::{MemoryRegion, MemoryRegionKind, Optional, TlsTemplate};
+
+    use super::*;
+    use crate::layout::DataLayout;
+
+    fn loader() -> &'static Snapshot {
+        SNAPSHOTS.iter().find(|s| s.abi == "loader").unwrap()
+    }
+
+    fn kernel() -> &'static Snapshot {
+        SNAPSHOTS.iter().find(|s| s.abi == "kernel").unwrap()
+    }
+
+    #[test]
+    fn test_abis_agree() {
+        let (loader, kernel) = (loader(), kernel());
+        assert_eq!(
+            DataLayout::parse(loader.data_layout).unwrap(),
+            DataLayout::parse(kernel.data_layout).unwrap(),
+            "{} and {} lay out primitives differently",
+            loader.target,
+            kernel.target
+        );
+        for ty in loader.types {
+            assert_eq!(
+                ty,
+                kernel.get(ty.name),
+                "{} differs between targets",
+                ty.name
+            );
+        }
+    }
+
+    /// The host isn't either target, but it's x86-64 too, so rustc's layouts
+    /// here should match the model's
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_matches_rustc() {
+        for snapshot in SNAPSHOTS {
+            let check = |name: &str, size: usize, align: usize| {
+                let ty = snapshot.get(name);
+                assert_eq!(
+                    (ty.size as usize, ty.align as usize),
+                    (size, align),
+                    "size and alignment of {name} for {}",
+                    snapshot.target
+                );
+            };
+            check(
+                "ApiVersion",
+                size_of::<bootloader_api::config::ApiVersion>(),
+                align_of::<bootloader_api::config::ApiVersion>(),
+            );
+            check(
+                "MemoryRegions",
+                size_of::<bootloader_api::info::MemoryRegions>(),
+                align_of::<bootloader_api::info::MemoryRegions>(),
+            );
+            check(
+                "MemoryRegion",
+                size_of::<MemoryRegion>(),
+                align_of::<MemoryRegion>(),
+            );
+            check(
+                "MemoryRegionKind",
+                size_of::<MemoryRegionKind>(),
+                align_of::<MemoryRegionKind>(),
+            );
+            check(
+                "TlsTemplate",
+                size_of::<TlsTemplate>(),
+                align_of::<TlsTemplate>(),
+            );
+            check(
+                "Optional<u64>",
+                size_of::<Optional<u64>>(),
+                align_of::<Optional<u64>>(),
+            );
+            check(
+                "Optional<TlsTemplate>",
+                size_of::<Optional<TlsTemplate>>(),
+                align_of::<Optional<TlsTemplate>>(),
+            );
+
+            let region = snapshot.get("MemoryRegion");
+            assert_eq!(region.offset("start"), offset_of!(MemoryRegion, start));
+            assert_eq!(region.offset("end"), offset_of!(MemoryRegion, end));
+            assert_eq!(region.offset("kind"), offset_of!(MemoryRegion, kind));
+
+            let tls = snapshot.get("TlsTemplate");
+            assert_eq!(
+                tls.offset("start_addr"),
+                offset_of!(TlsTemplate, start_addr)
+            );
+            assert_eq!(tls.offset("file_size"), offset_of!(TlsTemplate, file_size));
+            assert_eq!(tls.offset("mem_size"), offset_of!(TlsTemplate, mem_size));
+        }
+    }
+
+    /// Read a memory region out of raw bytes the way the kernel's target lays
+    /// it out
+    fn decode_region(bytes: &[u8], snapshot: &Snapshot) -> (u64, u64, u32, u32) {
+        let region = snapshot.get("MemoryRegion");
+        let kind = snapshot.get("MemoryRegionKind");
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let kind_offset = region.offset("kind");
+        (
+            u64_at(region.offset("start")),
+            u64_at(region.offset("end")),
+            u32_at(kind_offset + kind.offset("tag")),
+            u32_at(kind_offset + kind.offset("payload")),
+        )
+    }
+
+    #[test]
+    fn test_memory_map_bytes() {
+        // Roughly what QEMU's OVMF reports: low memory, the loader's own
+        // allocations, and the local APIC as an unknown UEFI type
+        let map = [
+            MemoryRegion {
+                start: 0,
+                end: 0x9f000,
+                kind: MemoryRegionKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x10_0000,
+                end: 0x20_0000,
+                kind: MemoryRegionKind::Bootloader,
+            },
+            MemoryRegion {
+                start: 0xfee0_0000,
+                end: 0xfee0_1000,
+                kind: MemoryRegionKind::UnknownUefi(11),
+            },
+        ];
+        // SAFETY: `MemoryRegion` is plain data, and padding bytes are never read
+        let bytes = unsafe {
+            std::slice::from_raw_parts(map.as_ptr().cast::<u8>(), std::mem::size_of_val(&map))
+        };
+
+        let kernel = kernel();
+        let stride = kernel.get("MemoryRegion").size as usize;
+        assert_eq!(bytes.len(), stride * map.len());
+
+        let decoded = bytes
+            .chunks(stride)
+            .map(|chunk| {
+                let (start, end, tag, payload) = decode_region(chunk, kernel);
+                // Only the unknown kinds have a payload
+                (start, end, tag, if tag >= 2 { payload } else { 0 })
+            })
+            .collect::<Vec<_>>();
+        // Addresses are physical and in bytes, and the end is exclusive
+        assert_eq!(
+            decoded,
+            [
+                (0, 0x9f000, 0, 0),
+                (0x10_0000, 0x20_0000, 1, 0),
+                (0xfee0_0000, 0xfee0_1000, 2, 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_data_layout() {
+        let layout = DataLayout::parse("e-m:e-p270:32:32-i64:64-f80:128-n8:16:32:64-S128").unwrap();
+        assert!(layout.little_endian);
+        assert_eq!((layout.pointer_size, layout.pointer_align), (8, 8));
+        assert_eq!(layout.int_align, [1, 2, 4, 8]);
+
+        let layout = DataLayout::parse("E-p:32:32-i64:32:64").unwrap();
+        assert!(!layout.little_endian);
+        assert_eq!((layout.pointer_size, layout.pointer_align), (4, 4));
+        assert_eq!(layout.int_align, [1, 2, 4, 4]);
+    }
+}
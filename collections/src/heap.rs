@@ -0,0 +1,273 @@
+//! Fixed-capacity d-ary min-heap.
+//!
+//! A d-ary heap is a binary heap generalized to `D` children per node. Wider
+//! nodes make the tree shallower, so pushes do fewer comparisons, at the cost
+//! of more comparisons per level when popping. `D = 4` is a good default for
+//! small elements, since a node's children then tend to share a cache line.
+//!
+//! Storage is an inline array of `N` elements, so a heap never allocates and
+//! pushing onto a full heap hands the element back instead.
+
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::slice;
+
+/// Min-heap with `D` children per node and room for `N` elements.
+///
+/// [`DaryHeap::pop`] returns the smallest element. Wrap elements in
+/// [`Reverse`](core::cmp::Reverse) for a max-heap.
+pub struct DaryHeap<T, const D: usize, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+/// Priority queue that pops the smallest element first, as a 4-ary heap
+pub type PriorityQueue<T, const N: usize> = DaryHeap<T, 4, N>;
+
+impl<T, const D: usize, const N: usize> DaryHeap<T, D, N> {
+    pub const fn new() -> Self {
+        assert!(D >= 2, "heap nodes need at least two children");
+        Self {
+            items: [const { MaybeUninit::uninit() }; N],
+            len: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// The elements, in no particular order
+    pub fn as_slice(&self) -> &[T] {
+        // SAFETY: the first `len` items are initialized
+        unsafe { slice::from_raw_parts(self.items.as_ptr().cast(), self.len) }
+    }
+
+    /// Iterate over the elements in no particular order
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+
+    pub fn clear(&mut self) {
+        let len = self.len;
+        // Forget the items first, so a panicking destructor leaks instead of double-dropping
+        self.len = 0;
+        for item in &mut self.items[..len] {
+            // SAFETY: the first `len` items were initialized
+            unsafe { item.assume_init_drop() };
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        // SAFETY: the first `len` items are initialized
+        unsafe { slice::from_raw_parts_mut(self.items.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl<T: Ord, const D: usize, const N: usize> DaryHeap<T, D, N> {
+    /// Add `item` to the heap, or give it back if the heap is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[self.len].write(item);
+        self.len += 1;
+        self.sift_up(self.len - 1);
+        Ok(())
+    }
+
+    /// Get the smallest element
+    pub fn peek(&self) -> Option<&T> {
+        self.as_slice().first()
+    }
+
+    /// Remove and return the smallest element
+    pub fn pop(&mut self) -> Option<T> {
+        let last = self.len.checked_sub(1)?;
+        self.as_mut_slice().swap(0, last);
+        self.len = last;
+        // SAFETY: this was the last initialized item, and it's no longer counted in `len`
+        let item = unsafe { self.items[last].assume_init_read() };
+        self.sift_down(0);
+        Some(item)
+    }
+
+    /// Remove every element for which `keep` returns false
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        let len = self.len;
+        // As in `clear`, if `keep` panics this leaks the remaining items rather than double-dropping
+        self.len = 0;
+        let mut kept = 0;
+        for i in 0..len {
+            // SAFETY: items `kept..i` have been dropped or moved, but `i` hasn't been touched yet
+            if keep(unsafe { self.items[i].assume_init_ref() }) {
+                self.items.swap(kept, i);
+                kept += 1;
+            } else {
+                // SAFETY: as above
+                unsafe { self.items[i].assume_init_drop() };
+            }
+        }
+        self.len = kept;
+
+        // Restore the heap property, bottom-up
+        for i in (0..kept / D + 1).rev() {
+            self.sift_down(i);
+        }
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        let items = self.as_mut_slice();
+        while index > 0 {
+            let parent = (index - 1) / D;
+            if items[index] >= items[parent] {
+                break;
+            }
+            items.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        let items = self.as_mut_slice();
+        loop {
+            let first_child = index * D + 1;
+            if first_child >= items.len() {
+                break;
+            }
+            let last_child = (first_child + D).min(items.len());
+            let smallest = (first_child..last_child)
+                .min_by(|&a, &b| items[a].cmp(&items[b]))
+                .unwrap();
+            if items[smallest] >= items[index] {
+                break;
+            }
+            items.swap(index, smallest);
+            index = smallest;
+        }
+    }
+}
+
+impl<T, const D: usize, const N: usize> Default for DaryHeap<T, D, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const D: usize, const N: usize> Drop for DaryHeap<T, D, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: fmt::Debug, const D: usize, const N: usize> fmt::Debug for DaryHeap<T, D, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn drain<T: Ord, const D: usize, const N: usize>(heap: &mut DaryHeap<T, D, N>) -> Vec<T> {
+        core::iter::from_fn(|| heap.pop()).collect()
+    }
+
+    fn check_sorts<const D: usize>() {
+        let mut heap = DaryHeap::<u32, D, 64>::new();
+        // Deterministic but scrambled order
+        for i in 0..64 {
+            heap.push((i * 37) % 64).unwrap();
+        }
+        assert!(heap.is_full());
+        assert_eq!(heap.peek(), Some(&0));
+        assert_eq!(drain(&mut heap), (0..64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_ordering() {
+        check_sorts::<2>();
+        check_sorts::<3>();
+        check_sorts::<4>();
+        check_sorts::<8>();
+    }
+
+    #[test]
+    fn test_full() {
+        let mut heap = PriorityQueue::<u32, 2>::new();
+        assert_eq!(heap.push(2), Ok(()));
+        assert_eq!(heap.push(1), Ok(()));
+        assert_eq!(heap.push(3), Err(3));
+        assert_eq!(heap.pop(), Some(1));
+        assert_eq!(heap.push(3), Ok(()));
+        assert_eq!(drain(&mut heap), [2, 3]);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut heap = PriorityQueue::<u32, 32>::new();
+        for i in (0..32).rev() {
+            heap.push(i).unwrap();
+        }
+        heap.retain(|&i| i % 3 != 0);
+        assert_eq!(heap.len(), 21);
+        assert_eq!(
+            drain(&mut heap),
+            (0..32).filter(|i| i % 3 != 0).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_drops() {
+        struct Counted<'a>(u32, &'a Cell<u32>);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.1.set(self.1.get() + 1);
+            }
+        }
+        impl PartialEq for Counted<'_> {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+        impl Eq for Counted<'_> {}
+        impl PartialOrd for Counted<'_> {
+            fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Counted<'_> {
+            fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        let drops = Cell::new(0);
+        let mut heap = PriorityQueue::<Counted, 8>::new();
+        for i in 0..6 {
+            let _ = heap.push(Counted(i, &drops));
+        }
+        heap.retain(|c| c.0 >= 2);
+        assert_eq!(drops.get(), 2);
+        drop(heap.pop());
+        assert_eq!(drops.get(), 3);
+        drop(heap);
+        assert_eq!(drops.get(), 6);
+    }
+}
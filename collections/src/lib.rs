@@ -0,0 +1,22 @@
+//! Collections for kernel code that can't (or shouldn't) allocate.
+//!
+//! - [`list`] is an intrusive doubly-linked list. Nodes embed a [`Link`] and
+//!   live wherever their owner puts them, so linking and unlinking never
+//!   allocate and a node can be on several lists at once.
+//! - [`heap`] is a fixed-capacity d-ary min-heap, usable as a priority queue
+//!   for things like timer deadlines.
+//!
+//! Both can be built in a `const` context, so they work as `static`s. Neither
+//! does any locking: callers are expected to wrap them in whatever lock fits,
+//! and if interrupt handlers also touch them, that lock must mask interrupts.
+//! In exchange, no operation allocates, and misuse (like linking a value
+//! that's already on a list) panics before anything is modified, so a
+//! collection is never left half-updated.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod heap;
+pub mod list;
+
+pub use heap::{DaryHeap, PriorityQueue};
+pub use list::{Link, List, UnsafeRef};
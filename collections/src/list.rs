@@ -0,0 +1,725 @@
+//! Intrusive doubly-linked list.
+//!
+//! Values embed one [`Link`] per list they can be on, and an [`Adapter`]
+//! (declared with [`adapter!`](crate::adapter)) tells a [`List`] which link to
+//! use. The list only ever stores pointers to those links, so it doesn't own
+//! its values: they're handed over and back as [`UnsafeRef`]s, and it's up to
+//! the caller to keep them alive, typically by allocating them statically or
+//! from memory that's never freed.
+//!
+//! Like `intrusive_collections`' `LinkedList`, positions are navigated with
+//! cursors, which can point at any element or at a "null" position between the
+//! back and the front of the list. Given a reference to a value that's known to
+//! be on a list, [`List::cursor_mut_from_ptr`] finds its position in constant
+//! time, which is what makes removing a value from several lists at once cheap.
+
+use core::cell::Cell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+/// Link embedded in a list element
+pub struct Link {
+    next: Cell<Option<NonNull<Link>>>,
+    prev: Cell<Option<NonNull<Link>>>,
+    linked: Cell<bool>,
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Self {
+            next: Cell::new(None),
+            prev: Cell::new(None),
+            linked: Cell::new(false),
+        }
+    }
+
+    /// Whether the element is currently on the list this link belongs to
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_linked() {
+            f.write_str("Link(linked)")
+        } else {
+            f.write_str("Link(unlinked)")
+        }
+    }
+}
+
+// SAFETY: links are only modified through the list they're on, which requires
+// `&mut` access to the list
+unsafe impl Send for Link {}
+
+/// Converts between values and one of their embedded [`Link`]s.
+///
+/// Use [`adapter!`](crate::adapter) instead of implementing this by hand.
+///
+/// # Safety
+/// [`Adapter::value`] must be the inverse of [`Adapter::link`].
+pub unsafe trait Adapter {
+    type Value;
+
+    /// The link in `value` that this adapter's lists use
+    fn link(value: &Self::Value) -> &Link;
+
+    /// Get the value containing `link`
+    ///
+    /// # Safety
+    /// `link` must have come from [`Adapter::link`].
+    unsafe fn value(link: NonNull<Link>) -> NonNull<Self::Value>;
+}
+
+/// Declare an [`Adapter`](crate::list::Adapter) for lists threaded through a
+/// [`Link`](crate::list::Link) field:
+///
+/// ```
+/// # use platypos_collections::{adapter, Link};
+/// pub struct Task {
+///     run_link: Link,
+///     wait_link: Link,
+/// }
+///
+/// adapter!(RunQueueAdapter = Task { run_link });
+/// adapter!(pub WaitQueueAdapter = Task { wait_link });
+/// ```
+#[macro_export]
+macro_rules! adapter {
+    ($(#[$attr:meta])* $vis:vis $name:ident = $value:ty { $field:ident }) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, Default)]
+        $vis struct $name;
+
+        // SAFETY: `value` undoes the field projection in `link`
+        unsafe impl $crate::list::Adapter for $name {
+            type Value = $value;
+
+            fn link(value: &Self::Value) -> &$crate::list::Link {
+                &value.$field
+            }
+
+            unsafe fn value(
+                link: ::core::ptr::NonNull<$crate::list::Link>,
+            ) -> ::core::ptr::NonNull<Self::Value> {
+                // SAFETY: the caller guarantees `link` is the `$field` field of a `$value`
+                unsafe { link.byte_sub(::core::mem::offset_of!($value, $field)).cast() }
+            }
+        }
+    };
+}
+
+/// Unowned pointer to a list element.
+///
+/// This behaves like a `&'static T` that can be passed into and out of a
+/// [`List`] without a lifetime.
+pub struct UnsafeRef<T> {
+    ptr: NonNull<T>,
+}
+
+impl<T> UnsafeRef<T> {
+    /// # Safety
+    /// `ptr` must be non-null and valid for as long as it, or any clone of it,
+    /// is in use. The value must only be accessed through shared references
+    /// during that time.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        Self {
+            // SAFETY: guaranteed by the caller
+            ptr: unsafe { NonNull::new_unchecked(ptr.cast_mut()) },
+        }
+    }
+
+    pub fn into_raw(this: Self) -> *const T {
+        this.ptr.as_ptr()
+    }
+}
+
+impl<T> Clone for UnsafeRef<T> {
+    fn clone(&self) -> Self {
+        Self { ptr: self.ptr }
+    }
+}
+
+impl<T> Deref for UnsafeRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: guaranteed by the caller of `from_raw`
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for UnsafeRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+// SAFETY: same as for `intrusive_collections::UnsafeRef` - these are shared
+// references whose lifetimes the caller manages
+unsafe impl<T: Send> Send for UnsafeRef<T> {}
+unsafe impl<T: Sync> Sync for UnsafeRef<T> {}
+
+/// Intrusive doubly-linked list.
+///
+/// The list doesn't own its elements, so dropping it just unlinks them.
+pub struct List<A: Adapter> {
+    head: Option<NonNull<Link>>,
+    tail: Option<NonNull<Link>>,
+    _adapter: PhantomData<A>,
+}
+
+// SAFETY: the list only hands out shared references to its values, and only
+// modifies links with `&mut` access
+unsafe impl<A: Adapter> Send for List<A> where A::Value: Send {}
+unsafe impl<A: Adapter> Sync for List<A> where A::Value: Sync {}
+
+/// Read-only position in a [`List`]
+pub struct Cursor<'a, A: Adapter> {
+    current: Option<NonNull<Link>>,
+    list: &'a List<A>,
+}
+
+/// Position in a [`List`] that can add and remove elements
+pub struct CursorMut<'a, A: Adapter> {
+    current: Option<NonNull<Link>>,
+    list: &'a mut List<A>,
+}
+
+/// Iterator over the elements of a [`List`], front to back
+pub struct Iter<'a, A: Adapter> {
+    front: Option<NonNull<Link>>,
+    back: Option<NonNull<Link>>,
+    _list: &'a List<A>,
+}
+
+fn next_of(link: NonNull<Link>) -> Option<NonNull<Link>> {
+    // SAFETY: links are only reachable while their values are on a list, and
+    // values must outlive their time on a list
+    unsafe { link.as_ref().next.get() }
+}
+
+fn prev_of(link: NonNull<Link>) -> Option<NonNull<Link>> {
+    // SAFETY: see `next_of`
+    unsafe { link.as_ref().prev.get() }
+}
+
+/// # Safety
+/// `link` must be linked into a list that uses `A`
+unsafe fn value_of<'a, A: Adapter>(link: NonNull<Link>) -> &'a A::Value {
+    // SAFETY: values must outlive their time on a list
+    unsafe { A::value(link).as_ref() }
+}
+
+impl<A: Adapter> List<A> {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+            _adapter: PhantomData,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Get a cursor pointing to the null position
+    pub fn cursor(&self) -> Cursor<'_, A> {
+        Cursor {
+            current: None,
+            list: self,
+        }
+    }
+
+    /// Get a mutable cursor pointing to the null position
+    pub fn cursor_mut(&mut self) -> CursorMut<'_, A> {
+        CursorMut {
+            current: None,
+            list: self,
+        }
+    }
+
+    /// Get a cursor pointing to the first element, or the null position if
+    /// the list is empty
+    pub fn front(&self) -> Cursor<'_, A> {
+        Cursor {
+            current: self.head,
+            list: self,
+        }
+    }
+
+    /// Get a cursor pointing to the last element, or the null position if the
+    /// list is empty
+    pub fn back(&self) -> Cursor<'_, A> {
+        Cursor {
+            current: self.tail,
+            list: self,
+        }
+    }
+
+    pub fn front_mut(&mut self) -> CursorMut<'_, A> {
+        CursorMut {
+            current: self.head,
+            list: self,
+        }
+    }
+
+    pub fn back_mut(&mut self) -> CursorMut<'_, A> {
+        CursorMut {
+            current: self.tail,
+            list: self,
+        }
+    }
+
+    /// Get a cursor pointing to `value`.
+    ///
+    /// # Safety
+    /// `value` must be on this list.
+    pub unsafe fn cursor_from_ptr(&self, value: &A::Value) -> Cursor<'_, A> {
+        debug_assert!(A::link(value).is_linked());
+        Cursor {
+            current: Some(NonNull::from(A::link(value))),
+            list: self,
+        }
+    }
+
+    /// Get a mutable cursor pointing to `value`.
+    ///
+    /// # Safety
+    /// `value` must be on this list.
+    pub unsafe fn cursor_mut_from_ptr(&mut self, value: &A::Value) -> CursorMut<'_, A> {
+        debug_assert!(A::link(value).is_linked());
+        CursorMut {
+            current: Some(NonNull::from(A::link(value))),
+            list: self,
+        }
+    }
+
+    /// # Panics
+    /// If `value` is already on a list using this adapter
+    pub fn push_front(&mut self, value: UnsafeRef<A::Value>) {
+        let link = Self::unlinked(value);
+        self.link_between(link, None, self.head);
+    }
+
+    /// # Panics
+    /// If `value` is already on a list using this adapter
+    pub fn push_back(&mut self, value: UnsafeRef<A::Value>) {
+        let link = Self::unlinked(value);
+        self.link_between(link, self.tail, None);
+    }
+
+    pub fn pop_front(&mut self) -> Option<UnsafeRef<A::Value>> {
+        let link = self.head?;
+        self.unlink(link);
+        Some(Self::value_ref(link))
+    }
+
+    pub fn pop_back(&mut self) -> Option<UnsafeRef<A::Value>> {
+        let link = self.tail?;
+        self.unlink(link);
+        Some(Self::value_ref(link))
+    }
+
+    /// Unlink every element
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+
+    pub fn iter(&self) -> Iter<'_, A> {
+        Iter {
+            front: self.head,
+            back: self.tail,
+            _list: self,
+        }
+    }
+
+    /// Get the link for `value`, checking that it's not on a list already
+    fn unlinked(value: UnsafeRef<A::Value>) -> NonNull<Link> {
+        let link = A::link(&value);
+        assert!(!link.is_linked(), "value is already linked");
+        NonNull::from(link)
+    }
+
+    fn value_ref(link: NonNull<Link>) -> UnsafeRef<A::Value> {
+        UnsafeRef {
+            // SAFETY: every link on the list came from `A::link`
+            ptr: unsafe { A::value(link) },
+        }
+    }
+
+    /// Link `link` in between `prev` and `next`, which must be adjacent
+    /// (`None` meaning the ends of the list)
+    fn link_between(
+        &mut self,
+        link: NonNull<Link>,
+        prev: Option<NonNull<Link>>,
+        next: Option<NonNull<Link>>,
+    ) {
+        // SAFETY: `link` is unlinked, and `prev` and `next` are on this list
+        unsafe {
+            let node = link.as_ref();
+            node.prev.set(prev);
+            node.next.set(next);
+            node.linked.set(true);
+            match prev {
+                Some(prev) => prev.as_ref().next.set(Some(link)),
+                None => self.head = Some(link),
+            }
+            match next {
+                Some(next) => next.as_ref().prev.set(Some(link)),
+                None => self.tail = Some(link),
+            }
+        }
+    }
+
+    /// Unlink `link`, which must be on this list, and return the link after it
+    fn unlink(&mut self, link: NonNull<Link>) -> Option<NonNull<Link>> {
+        // SAFETY: `link` and its neighbors are on this list
+        unsafe {
+            let node = link.as_ref();
+            let (prev, next) = (node.prev.get(), node.next.get());
+            match prev {
+                Some(prev) => prev.as_ref().next.set(next),
+                None => self.head = next,
+            }
+            match next {
+                Some(next) => next.as_ref().prev.set(prev),
+                None => self.tail = prev,
+            }
+            node.prev.set(None);
+            node.next.set(None);
+            node.linked.set(false);
+            next
+        }
+    }
+}
+
+impl<A: Adapter> Default for List<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Adapter> Drop for List<A> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<A: Adapter> fmt::Debug for List<A>
+where
+    A::Value: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, A: Adapter> IntoIterator for &'a List<A> {
+    type Item = &'a A::Value;
+    type IntoIter = Iter<'a, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, A: Adapter> Cursor<'a, A> {
+    /// Get the element the cursor points to, or `None` at the null position
+    pub fn get(&self) -> Option<&'a A::Value> {
+        // SAFETY: `current` is on the list
+        self.current.map(|link| unsafe { value_of::<A>(link) })
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.current.is_none()
+    }
+
+    /// Move to the next element. Moving past the back goes to the null
+    /// position, and moving from the null position goes to the front.
+    pub fn move_next(&mut self) {
+        self.current = match self.current {
+            Some(link) => next_of(link),
+            None => self.list.head,
+        };
+    }
+
+    /// Move to the previous element. Moving past the front goes to the null
+    /// position, and moving from the null position goes to the back.
+    pub fn move_prev(&mut self) {
+        self.current = match self.current {
+            Some(link) => prev_of(link),
+            None => self.list.tail,
+        };
+    }
+
+    /// Get a cursor pointing to the next element
+    pub fn peek_next(&self) -> Cursor<'a, A> {
+        let mut next = self.clone();
+        next.move_next();
+        next
+    }
+
+    /// Get a cursor pointing to the previous element
+    pub fn peek_prev(&self) -> Cursor<'a, A> {
+        let mut prev = self.clone();
+        prev.move_prev();
+        prev
+    }
+
+    /// Get a new reference to the element the cursor points to
+    pub fn clone_pointer(&self) -> Option<UnsafeRef<A::Value>> {
+        self.current.map(List::<A>::value_ref)
+    }
+}
+
+impl<A: Adapter> Clone for Cursor<'_, A> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current,
+            list: self.list,
+        }
+    }
+}
+
+impl<A: Adapter> CursorMut<'_, A> {
+    /// Get the element the cursor points to, or `None` at the null position
+    pub fn get(&self) -> Option<&A::Value> {
+        // SAFETY: `current` is on the list
+        self.current.map(|link| unsafe { value_of::<A>(link) })
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.current.is_none()
+    }
+
+    /// Get a read-only cursor at the same position
+    pub fn as_cursor(&self) -> Cursor<'_, A> {
+        Cursor {
+            current: self.current,
+            list: self.list,
+        }
+    }
+
+    /// See [`Cursor::move_next`]
+    pub fn move_next(&mut self) {
+        self.current = match self.current {
+            Some(link) => next_of(link),
+            None => self.list.head,
+        };
+    }
+
+    /// See [`Cursor::move_prev`]
+    pub fn move_prev(&mut self) {
+        self.current = match self.current {
+            Some(link) => prev_of(link),
+            None => self.list.tail,
+        };
+    }
+
+    pub fn peek_next(&self) -> Cursor<'_, A> {
+        self.as_cursor().peek_next()
+    }
+
+    pub fn peek_prev(&self) -> Cursor<'_, A> {
+        self.as_cursor().peek_prev()
+    }
+
+    /// Remove the current element and move to the next one. Does nothing at
+    /// the null position.
+    pub fn remove(&mut self) -> Option<UnsafeRef<A::Value>> {
+        let link = self.current?;
+        self.current = self.list.unlink(link);
+        Some(List::<A>::value_ref(link))
+    }
+
+    /// Insert `value` before the current element, or at the back of the list
+    /// at the null position
+    ///
+    /// # Panics
+    /// If `value` is already on a list using this adapter
+    pub fn insert_before(&mut self, value: UnsafeRef<A::Value>) {
+        let link = List::<A>::unlinked(value);
+        let prev = match self.current {
+            Some(current) => prev_of(current),
+            None => self.list.tail,
+        };
+        self.list.link_between(link, prev, self.current);
+    }
+
+    /// Insert `value` after the current element, or at the front of the list
+    /// at the null position
+    ///
+    /// # Panics
+    /// If `value` is already on a list using this adapter
+    pub fn insert_after(&mut self, value: UnsafeRef<A::Value>) {
+        let link = List::<A>::unlinked(value);
+        let next = match self.current {
+            Some(current) => next_of(current),
+            None => self.list.head,
+        };
+        self.list.link_between(link, self.current, next);
+    }
+}
+
+impl<'a, A: Adapter> Iterator for Iter<'a, A> {
+    type Item = &'a A::Value;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let link = self.front?;
+        if self.front == self.back {
+            self.front = None;
+            self.back = None;
+        } else {
+            self.front = next_of(link);
+        }
+        // SAFETY: `link` is on the list
+        Some(unsafe { value_of::<A>(link) })
+    }
+}
+
+impl<A: Adapter> DoubleEndedIterator for Iter<'_, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let link = self.back?;
+        if self.front == self.back {
+            self.front = None;
+            self.back = None;
+        } else {
+            self.back = prev_of(link);
+        }
+        // SAFETY: `link` is on the list
+        Some(unsafe { value_of::<A>(link) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Node {
+        value: u32,
+        link: Link,
+        other_link: Link,
+    }
+
+    crate::adapter!(NodeAdapter = Node { link });
+    crate::adapter!(OtherAdapter = Node { other_link });
+
+    fn node(value: u32) -> UnsafeRef<Node> {
+        let node = Box::leak(Box::new(Node {
+            value,
+            link: Link::new(),
+            other_link: Link::new(),
+        }));
+        unsafe { UnsafeRef::from_raw(node) }
+    }
+
+    fn values<A: Adapter<Value = Node>>(list: &List<A>) -> Vec<u32> {
+        list.iter().map(|n| n.value).collect()
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut list = List::<NodeAdapter>::new();
+        assert!(list.is_empty());
+        list.push_back(node(2));
+        list.push_back(node(3));
+        list.push_front(node(1));
+        assert_eq!(values(&list), [1, 2, 3]);
+        assert_eq!(
+            list.iter().rev().map(|n| n.value).collect::<Vec<_>>(),
+            [3, 2, 1]
+        );
+
+        let first = list.pop_front().unwrap();
+        assert_eq!(first.value, 1);
+        assert!(!first.link.is_linked());
+        assert_eq!(list.pop_back().unwrap().value, 3);
+        assert_eq!(list.pop_back().unwrap().value, 2);
+        assert!(list.pop_front().is_none());
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mut list = List::<NodeAdapter>::new();
+        list.push_back(node(1));
+        list.push_back(node(3));
+
+        let mut cursor = list.front_mut();
+        cursor.move_next();
+        cursor.insert_before(node(2));
+        assert_eq!(cursor.get().unwrap().value, 3);
+        assert_eq!(cursor.peek_prev().get().unwrap().value, 2);
+        cursor.move_next();
+        assert!(cursor.is_null());
+        cursor.insert_before(node(4));
+        cursor.insert_after(node(0));
+        assert_eq!(values(&list), [0, 1, 2, 3, 4]);
+
+        let mut cursor = list.front_mut();
+        cursor.move_next();
+        assert_eq!(cursor.remove().unwrap().value, 1);
+        assert_eq!(cursor.get().unwrap().value, 2);
+        assert_eq!(values(&list), [0, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_multiple_lists() {
+        let mut all = List::<NodeAdapter>::new();
+        let mut odd = List::<OtherAdapter>::new();
+        for i in 0..5 {
+            let n = node(i);
+            if i % 2 == 1 {
+                odd.push_back(n.clone());
+            }
+            all.push_back(n);
+        }
+        assert_eq!(values(&odd), [1, 3]);
+
+        let three = odd.back().clone_pointer().unwrap();
+        unsafe {
+            all.cursor_mut_from_ptr(&three).remove();
+            let cursor = odd.cursor_from_ptr(&three);
+            assert_eq!(cursor.peek_prev().get().unwrap().value, 1);
+            assert!(cursor.peek_next().is_null());
+        }
+        assert_eq!(values(&all), [0, 1, 2, 4]);
+        assert!(three.other_link.is_linked());
+    }
+
+    #[test]
+    fn test_drop_unlinks() {
+        let n = node(1);
+        {
+            let mut list = List::<NodeAdapter>::new();
+            list.push_back(n.clone());
+            assert!(n.link.is_linked());
+        }
+        assert!(!n.link.is_linked());
+    }
+
+    #[test]
+    #[should_panic(expected = "already linked")]
+    fn test_double_link() {
+        let n = node(1);
+        let mut list = List::<NodeAdapter>::new();
+        list.push_back(n.clone());
+        list.push_back(n);
+    }
+}
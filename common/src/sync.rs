@@ -7,7 +7,7 @@ use core::sync::atomic::{AtomicBool, Ordering};
 
 use spin::{Mutex, MutexGuard};
 
-use platypos_hal::interrupts::{Controller, Guard};
+use platypos_hal::interrupts::{nmi_safe, Controller, Guard};
 
 pub struct InterruptSafeMutex<'a, T: ?Sized, C: Controller + ?Sized> {
     controller: &'a C,
@@ -22,6 +22,9 @@ pub struct InterruptSafeMutexGuard<'a, T: ?Sized, C: Controller + ?Sized> {
     // See also Linux's spin_lock_irqsave and spin_lock_irqrestore implementation:
     // https://elixir.bootlin.com/linux/v5.17.1/source/include/linux/spinlock_api_smp.h#L104
     inner: MutexGuard<'a, T>,
+    // Holding the lock is also an NMI-unsafe critical section, so that an NMI handler doesn't
+    // log (or otherwise end up back here) while the lock is held
+    _nmi_section: nmi_safe::Section,
     _interrupt_guard: Guard<'a, C>,
 }
 
@@ -65,11 +68,18 @@ impl<'a, T, C: Controller + ?Sized> InterruptSafeMutex<'a, T, C> {
 }
 
 impl<'a, T: ?Sized, C: Controller> InterruptSafeMutex<'a, T, C> {
+    /// Lock the mutex, spinning until it's available.
+    ///
+    /// Disabling interrupts doesn't stop NMIs, so an NMI handler must use
+    /// [`InterruptSafeMutex::try_lock`] instead. This is checked in debug
+    /// builds.
     #[inline(always)]
     pub fn lock(&self) -> InterruptSafeMutexGuard<'_, T, C> {
+        nmi_safe::assert_nmi_aware(self.controller.execution_context(), "InterruptSafeMutex");
         let interrupt_guard = self.controller.disable();
         InterruptSafeMutexGuard {
             _interrupt_guard: interrupt_guard,
+            _nmi_section: nmi_safe::enter(),
             inner: self.inner.lock(),
         }
     }
@@ -77,6 +87,7 @@ impl<'a, T: ?Sized, C: Controller> InterruptSafeMutex<'a, T, C> {
     #[inline(always)]
     pub fn try_lock(&self) -> Option<InterruptSafeMutexGuard<'_, T, C>> {
         let interrupt_guard = self.controller.disable();
+        let nmi_section = nmi_safe::enter();
         // TODO: this can probably be simplified to just `map` the Option returned by
         // `inner.try_lock`
         // The idea is that we try to acquire the lock with interrupts disabled, to
@@ -85,9 +96,11 @@ impl<'a, T: ?Sized, C: Controller> InterruptSafeMutex<'a, T, C> {
         match self.inner.try_lock() {
             Some(inner_guard) => Some(InterruptSafeMutexGuard {
                 inner: inner_guard,
+                _nmi_section: nmi_section,
                 _interrupt_guard: interrupt_guard,
             }),
             None => {
+                drop(nmi_section);
                 drop(interrupt_guard);
                 None
             }
@@ -0,0 +1,344 @@
+//! CPU context capture and restore.
+//!
+//! A [`Context`] holds a processor's general-purpose registers, instruction
+//! pointer, and flags. It's the one place that knows how to save and load
+//! register state, for everything that needs to:
+//!
+//! * [`Context::capture`] takes a snapshot of the current registers, for panic
+//!   dumps and debuggers to inspect.
+//! * [`Context::switch`] saves the current context and resumes another, so
+//!   that a later switch back returns from the first one. This is the
+//!   primitive for switching between threads.
+//! * [`Context::call_with_checkpoint`] saves the current context and calls a
+//!   function, which can [`restore`](Context::restore) the saved context to
+//!   abandon the call, like `setjmp` and `longjmp`. Tests use this to recover
+//!   from panics.
+//!
+//! Restoring a context writes two words below its stack pointer, so code
+//! running on that stack must not rely on a red zone. Kernel code is built
+//! without one.
+
+use core::arch::global_asm;
+use core::fmt;
+
+/// Saved processor state. The layout is shared with the assembly below.
+#[repr(C)]
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+/// RFLAGS bit 1 is reserved, and always set
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+extern "C" {
+    /// Save every register into `context`, as of the return from this call
+    fn platypos_context_capture(context: *mut Context);
+
+    /// Load every register from `context`, jumping to its instruction pointer
+    fn platypos_context_restore(context: *const Context) -> !;
+
+    /// Save the callee-saved registers into `old`, so that restoring it
+    /// returns from this call, then restore `new`
+    fn platypos_context_switch(old: *mut Context, new: *const Context);
+
+    /// Save the callee-saved registers into `context`, then call `f(data)`.
+    /// Returns 0 if `f` returns, or 1 if `context` is restored.
+    fn platypos_context_call(
+        context: *mut Context,
+        f: extern "C" fn(*mut u8),
+        data: *mut u8,
+    ) -> u64;
+}
+
+// System V ABI: arguments are in rdi, rsi, and rdx, and rbx, rbp, r12-r15, and rsp are
+// callee-saved. Saved contexts resume at the caller's return address, with the stack pointer as
+// it is after returning.
+global_asm!(
+    ".global platypos_context_capture",
+    "platypos_context_capture:",
+    "mov [rdi + 0x00], rax",
+    "mov [rdi + 0x08], rbx",
+    "mov [rdi + 0x10], rcx",
+    "mov [rdi + 0x18], rdx",
+    "mov [rdi + 0x20], rsi",
+    "mov [rdi + 0x28], rdi",
+    "mov [rdi + 0x30], rbp",
+    "lea rax, [rsp + 8]",
+    "mov [rdi + 0x38], rax",
+    "mov [rdi + 0x40], r8",
+    "mov [rdi + 0x48], r9",
+    "mov [rdi + 0x50], r10",
+    "mov [rdi + 0x58], r11",
+    "mov [rdi + 0x60], r12",
+    "mov [rdi + 0x68], r13",
+    "mov [rdi + 0x70], r14",
+    "mov [rdi + 0x78], r15",
+    "mov rax, [rsp]",
+    "mov [rdi + 0x80], rax",
+    "pushfq",
+    "pop rax",
+    "mov [rdi + 0x88], rax",
+    "ret",
+    "",
+    ".global platypos_context_restore",
+    "platypos_context_restore:",
+    "mov rsp, [rdi + 0x38]",
+    // Loading the instruction pointer and flags last needs them on the new stack
+    "push [rdi + 0x80]",
+    "push [rdi + 0x88]",
+    "mov rax, [rdi + 0x00]",
+    "mov rbx, [rdi + 0x08]",
+    "mov rcx, [rdi + 0x10]",
+    "mov rdx, [rdi + 0x18]",
+    "mov rsi, [rdi + 0x20]",
+    "mov rbp, [rdi + 0x30]",
+    "mov r8, [rdi + 0x40]",
+    "mov r9, [rdi + 0x48]",
+    "mov r10, [rdi + 0x50]",
+    "mov r11, [rdi + 0x58]",
+    "mov r12, [rdi + 0x60]",
+    "mov r13, [rdi + 0x68]",
+    "mov r14, [rdi + 0x70]",
+    "mov r15, [rdi + 0x78]",
+    "mov rdi, [rdi + 0x28]",
+    "popfq",
+    "ret",
+    "",
+    ".global platypos_context_switch",
+    "platypos_context_switch:",
+    "mov [rdi + 0x08], rbx",
+    "mov [rdi + 0x30], rbp",
+    "mov [rdi + 0x60], r12",
+    "mov [rdi + 0x68], r13",
+    "mov [rdi + 0x70], r14",
+    "mov [rdi + 0x78], r15",
+    "lea rax, [rsp + 8]",
+    "mov [rdi + 0x38], rax",
+    "mov rax, [rsp]",
+    "mov [rdi + 0x80], rax",
+    "pushfq",
+    "pop rax",
+    "mov [rdi + 0x88], rax",
+    "mov rdi, rsi",
+    "jmp platypos_context_restore",
+    "",
+    ".global platypos_context_call",
+    "platypos_context_call:",
+    // Restoring the context returns 1
+    "mov qword ptr [rdi + 0x00], 1",
+    "mov [rdi + 0x08], rbx",
+    "mov [rdi + 0x30], rbp",
+    "mov [rdi + 0x60], r12",
+    "mov [rdi + 0x68], r13",
+    "mov [rdi + 0x70], r14",
+    "mov [rdi + 0x78], r15",
+    "lea rax, [rsp + 8]",
+    "mov [rdi + 0x38], rax",
+    "mov rax, [rsp]",
+    "mov [rdi + 0x80], rax",
+    "pushfq",
+    "pop rax",
+    "mov [rdi + 0x88], rax",
+    // Realign the stack to 16 bytes for the call
+    "sub rsp, 8",
+    "mov rdi, rdx",
+    "call rsi",
+    "add rsp, 8",
+    "xor eax, eax",
+    "ret",
+);
+
+impl Context {
+    /// A context which starts running `entry(arg)` on the stack ending at
+    /// `stack_top`, with interrupts disabled. `entry` must never return, since
+    /// there's nothing to return to.
+    pub fn new(entry: extern "C" fn(usize) -> !, stack_top: *mut u8, arg: usize) -> Self {
+        Self {
+            rdi: arg as u64,
+            // Functions expect the stack to be 16-byte aligned before the call that pushed their
+            // return address
+            rsp: (stack_top as u64 & !0xf) - 8,
+            rip: entry as usize as u64,
+            rflags: RFLAGS_RESERVED,
+            ..Default::default()
+        }
+    }
+
+    /// Snapshot the current registers. The snapshot is only for inspecting,
+    /// since the code that took it doesn't expect to continue twice.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut context = Self::default();
+        // SAFETY: capturing only writes to `context`
+        unsafe { platypos_context_capture(&mut context) };
+        context
+    }
+
+    /// Resume this context, abandoning the current one
+    ///
+    /// # Safety
+    /// The context must come from [`Context::new`], with a valid stack, or
+    /// have been saved by [`Context::switch`] or
+    /// [`Context::call_with_checkpoint`] without being resumed since. For a
+    /// checkpoint, the caller must be running deeper on the same stack,
+    /// within the call. Nothing on the abandoned part of the stack is dropped.
+    pub unsafe fn restore(&self) -> ! {
+        platypos_context_restore(self)
+    }
+
+    /// Save the current context into `old` and resume `new`. This returns when
+    /// something resumes `old`.
+    ///
+    /// Only the registers that the C calling convention preserves across calls
+    /// are saved, since the caller expects the rest to be clobbered anyways.
+    ///
+    /// # Safety
+    /// The same as for [`Context::restore`] on `new`. `old` must stay in
+    /// place until it's resumed.
+    pub unsafe fn switch(old: &mut Context, new: &Context) {
+        platypos_context_switch(old, new)
+    }
+
+    /// Save the current context into `self`, then call `f(data)`. Returns
+    /// `false` if `f` returned, or `true` if `f` abandoned the call by
+    /// restoring `self`.
+    ///
+    /// # Safety
+    /// `self` must stay in place for the duration of the call, and `f` must be
+    /// safe to call with `data`.
+    pub unsafe fn call_with_checkpoint(
+        &mut self,
+        f: extern "C" fn(*mut u8),
+        data: *mut u8,
+    ) -> bool {
+        platypos_context_call(self, f, data) != 0
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 16] = [
+            "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11",
+            "r12", "r13", "r14", "r15",
+        ];
+        let values = [
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp, self.rsp,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+        ];
+
+        write!(f, "rip={:016x} rflags={:016x}", self.rip, self.rflags)?;
+        // Four registers to a line
+        for (i, (name, value)) in NAMES.iter().zip(values).enumerate() {
+            let separator = if i % 4 == 0 { "\n" } else { " " };
+            write!(f, "{separator}{name:>3}={value:016x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug() {
+        let context = Context {
+            rax: 1,
+            r8: 0xdead_beef,
+            rip: 0xffff_8000_0000_1000,
+            rflags: RFLAGS_RESERVED,
+            ..Default::default()
+        };
+        let lines = format!("{context:?}");
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "rip=ffff800000001000 rflags=0000000000000002");
+        assert!(lines[1].starts_with("rax=0000000000000001 rbx="));
+        assert!(lines[3].starts_with(" r8=00000000deadbeef  r9="));
+    }
+
+    #[test]
+    fn test_capture() {
+        let context = Context::capture();
+        assert_ne!(context.rsp, 0);
+        assert_ne!(context.rip, 0);
+        assert_ne!(context.rflags & RFLAGS_RESERVED, 0);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        extern "C" fn returns(data: *mut u8) {
+            // SAFETY: the test passes a pointer to a u64
+            unsafe { *(data as *mut u64) += 1 };
+        }
+        extern "C" fn abandons(data: *mut u8) {
+            // SAFETY: the test passes a pointer to its checkpoint, and this runs within the call
+            unsafe { (*(data as *mut Context)).restore() }
+        }
+
+        let mut context = Context::default();
+        let mut count = 0u64;
+        // SAFETY: `count` and `context` outlive the calls
+        unsafe {
+            assert!(!context.call_with_checkpoint(returns, &mut count as *mut u64 as *mut u8));
+            assert_eq!(count, 1);
+            let checkpoint = &mut context as *mut Context;
+            assert!((*checkpoint).call_with_checkpoint(abandons, checkpoint as *mut u8));
+        }
+    }
+
+    #[test]
+    fn test_switch() {
+        #[derive(Default)]
+        struct Threads {
+            main: Context,
+            thread: Context,
+            steps: u64,
+        }
+
+        extern "C" fn thread(arg: usize) -> ! {
+            let threads = arg as *mut Threads;
+            // SAFETY: the test passes a pointer to its `Threads`, which only one thread uses at a
+            // time
+            unsafe {
+                (*threads).steps += 1;
+                Context::switch(&mut (*threads).thread, &(*threads).main);
+                (*threads).steps += 1;
+                Context::switch(&mut (*threads).thread, &(*threads).main);
+            }
+            unreachable!("the test doesn't resume this thread again");
+        }
+
+        let mut stack = vec![0u64; 4096];
+        let top = stack.as_mut_ptr_range().end as *mut u8;
+        let threads = Box::into_raw(Box::<Threads>::default());
+        // SAFETY: the stack and `threads` outlive the thread's use of them, and each context is
+        // resumed once per save
+        unsafe {
+            (*threads).thread = Context::new(thread, top, threads as usize);
+            Context::switch(&mut (*threads).main, &(*threads).thread);
+            assert_eq!((*threads).steps, 1);
+            Context::switch(&mut (*threads).main, &(*threads).thread);
+            assert_eq!((*threads).steps, 2);
+            drop(Box::from_raw(threads));
+        }
+    }
+}
@@ -3,9 +3,35 @@ use x86_64::instructions::interrupts;
 use x86_64::structures::idt::InterruptDescriptorTable;
 
 use platypos_hal as hal;
+use platypos_hal::topology::{ProcessorState, Topology as _};
 
 mod apic;
+mod fault;
 mod handlers;
+mod ipi;
+mod latency;
+mod machine_check;
+mod nesting;
+mod pic;
+mod priority;
+mod sample;
+mod stats;
+mod storm;
+
+pub use apic::{local_apic_base, ApicTimer, TimerError};
+pub use fault::{Fault, FaultKind};
+pub use ipi::{
+    broadcast_ipi, send_ipi, send_self_ipi, set_ipi_handler, Ipi, IpiError, SelfIpiRoute,
+};
+pub use latency::{latency_histogram, LatencyHistogram, LATENCY_BUCKETS};
+pub use machine_check::{set_machine_check_hook, BankStatus, MachineCheckError};
+pub use pic::Routing as LegacyRouting;
+pub use sample::{set_sample_hook, Interrupted};
+pub use stats::{interrupt_count, Source, VECTORS};
+pub use storm::{
+    clear_storm, set_storm_threshold, storm_masked_vectors, storm_threshold,
+    DEFAULT_STORM_THRESHOLD,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Controller;
@@ -22,26 +48,126 @@ static IDT: Global<InterruptDescriptorTable> = Global::new();
 /// * Its lowest 4 bits are set, which some hardware requires
 const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
 
-/// Configure the interrupt controller
-pub fn init() -> &'static Controller {
-    apic::disable_pic();
+/// IRQ used for remote function call IPIs. IPIs are in the highest priority
+/// class, shared with the spurious vector, so that they can preempt most other
+/// interrupt handling.
+const CALL_FUNCTION_VECTOR: u8 = 0xf0;
+
+/// IRQ used for reschedule IPIs
+const RESCHEDULE_VECTOR: u8 = 0xf1;
+
+/// IRQ used for TLB shootdown IPIs
+const TLB_SHOOTDOWN_VECTOR: u8 = 0xf2;
+
+/// IRQ used for panic-stop IPIs. This is the highest vector below the
+/// spurious vector, so it's delivered ahead of any other pending IPIs.
+const PANIC_STOP_VECTOR: u8 = 0xfe;
+
+/// IRQ used for local APIC timer interrupts
+const TIMER_VECTOR: u8 = 0xef;
+
+/// IRQ used for corrected machine check interrupts. These share the timer's
+/// priority class, since they only log errors.
+const CMCI_VECTOR: u8 = 0xee;
+
+/// Kernel handler invoked when the local APIC timer fires
+static TIMER_HANDLER: Global<fn()> = Global::new();
+
+/// Kernel handler that gets a chance to resolve page faults
+static PAGE_FAULT_HANDLER: Global<fn(&Fault) -> bool> = Global::new();
+
+/// Kernel handlers for legacy ISA IRQs, which are delivered through the PIC
+static LEGACY_IRQ_HANDLERS: [Global<fn()>; 16] = [const { Global::new() }; 16];
+
+/// Configure the interrupt controller. The legacy PICs are always remapped
+/// away from the exception vectors and masked, and `legacy_routing` decides
+/// whether legacy IRQs are then delivered through them.
+pub fn init(legacy_routing: LegacyRouting) -> &'static Controller {
+    pic::init(legacy_routing);
 
     // TODO: will this force an expensive move?
     let mut idt = InterruptDescriptorTable::new();
-    for off in 0..8 {
-        idt[(apic::PIC1_OFFSET + off).into()].set_handler_fn(handlers::handle_remapped_pic);
-        idt[(apic::PIC2_OFFSET + off).into()].set_handler_fn(handlers::handle_remapped_pic);
+    idt.divide_error
+        .set_handler_fn(handlers::handle_divide_error);
+    idt.invalid_opcode
+        .set_handler_fn(handlers::handle_invalid_opcode);
+    idt.page_fault.set_handler_fn(handlers::handle_page_fault);
+    idt.alignment_check
+        .set_handler_fn(handlers::handle_alignment_check);
+    idt.non_maskable_interrupt
+        .set_handler_fn(handlers::handle_nmi);
+    idt.machine_check
+        .set_handler_fn(handlers::handle_machine_check);
+    for (irq, handler) in (0..).zip(handlers::LEGACY_IRQ_HANDLERS) {
+        idt[pic::vector(irq).into()].set_handler_fn(handler);
     }
     idt[SPURIOUS_INTERRUPT_VECTOR.into()].set_handler_fn(handlers::handle_spurious);
+    idt[CALL_FUNCTION_VECTOR.into()].set_handler_fn(handlers::handle_call_function);
+    idt[RESCHEDULE_VECTOR.into()].set_handler_fn(handlers::handle_reschedule);
+    idt[TLB_SHOOTDOWN_VECTOR.into()].set_handler_fn(handlers::handle_tlb_shootdown);
+    idt[PANIC_STOP_VECTOR.into()].set_handler_fn(handlers::handle_panic_stop);
+    idt[TIMER_VECTOR.into()].set_handler_fn(handlers::handle_timer);
+    idt[CMCI_VECTOR.into()].set_handler_fn(handlers::handle_cmci);
     IDT.init(idt);
 
     GLOBAL.init(Controller)
 }
 
-/// Perform processor-local initialization
+/// Perform processor-local initialization. The current processor must be
+/// [booting](ProcessorState::Booting), and is online afterwards.
 pub fn init_local() {
     apic::init_local();
     IDT.get().load();
+    // SAFETY: the IDT has handlers for #MC and CMCIs
+    unsafe { machine_check::init_local(Some(CMCI_VECTOR)) };
+
+    // Processors are only online once they can handle IPIs, and others know where to send them
+    crate::topology::set_local_apic_id(apic::local_id());
+    let processor = crate::topology::INSTANCE.current_processor();
+    if let Err(err) = hal::topology::transition(processor, ProcessorState::Online) {
+        panic!("{err}");
+    }
+}
+
+/// Register the function to run when this processor's APIC timer fires. The
+/// handler runs in interrupt context, with interrupts disabled.
+///
+/// # Panics
+/// If a handler was already registered
+pub fn set_timer_handler(handler: fn()) {
+    TIMER_HANDLER.init(handler);
+}
+
+/// Register the function to run on page faults. If it returns `true`, the
+/// fault was resolved (for example, by mapping the page), and the faulting
+/// instruction is retried. Otherwise, the fault is fatal. The handler runs
+/// with interrupts disabled.
+///
+/// # Panics
+/// If a handler was already registered
+pub fn set_page_fault_handler(handler: fn(&Fault) -> bool) {
+    PAGE_FAULT_HANDLER.init(handler);
+}
+
+/// Register the function to run when legacy ISA IRQ `irq` fires, and unmask
+/// it. The handler runs in interrupt context, with interrupts disabled.
+///
+/// Legacy IRQs are delivered through the 8259 PIC. This relies on the firmware
+/// configuring the bootstrap processor's LINT0 for ExtINT delivery, which is
+/// the power-on default.
+///
+/// # Panics
+/// If `irq` isn't an ISA IRQ (0-15), a handler was already registered, or
+/// legacy IRQs aren't routed through the PIC
+pub fn set_legacy_irq_handler(irq: u8, handler: fn()) {
+    LEGACY_IRQ_HANDLERS[usize::from(irq)].init(handler);
+    pic::unmask(irq);
+}
+
+/// Calibrate the current processor's APIC timer. This must be called after
+/// [`init_local`], and busy-waits for about 10ms.
+pub fn calibrate_timer() -> ApicTimer {
+    ApicTimer::calibrate(TIMER_VECTOR)
 }
 
 impl hal::interrupts::Controller for Controller {
@@ -60,4 +186,16 @@ impl hal::interrupts::Controller for Controller {
     fn wait(&self) {
         interrupts::enable_and_hlt()
     }
+
+    fn execution_context(&self) -> hal::interrupts::ExecutionContext {
+        nesting::current()
+    }
+
+    fn level(&self) -> hal::interrupts::Level {
+        priority::current()
+    }
+
+    fn set_level(&self, level: hal::interrupts::Level) {
+        priority::set(level)
+    }
 }
@@ -1,4 +1,10 @@
 //! APIC support, using x2APIC mode.
+//!
+//! Registers are accessed through the [`Registers`] trait rather than directly
+//! as MSRs, so that unit tests can run the encoding logic on the host against a
+//! mock register file. Registers that only change when the kernel writes them
+//! are cached per processor, so read-modify-write updates in interrupt paths
+//! don't need to read the MSR back (see [`cache`]).
 use bitvec::prelude::*;
 use paste::paste;
 use raw_cpuid::CpuId;
@@ -7,28 +13,68 @@ use x86_64::registers::model_specific::Msr;
 // Using deku would be nice, but it can only serialize into heap-allocated
 // vectors, which would negate all the performance gains of x2APIC mode
 
+/// Access to local APIC registers, identified by their x2APIC MSR address. In
+/// xAPIC mode, the equivalent MMIO offset is `(address - 0x800) << 4`.
+pub(super) trait Registers {
+    /// Read the register at `address`
+    fn read(&self, address: u32) -> u64;
+
+    /// Write `value` to the register at `address`
+    ///
+    /// # Safety
+    /// Modifying APIC registers can affect interrupt handling and cause faults.
+    unsafe fn write(&self, address: u32, value: u64);
+}
+
+/// The current processor's local APIC registers, accessed as x2APIC MSRs
+#[derive(Debug, Clone, Copy)]
+pub(super) struct X2Apic;
+
+impl Registers for X2Apic {
+    fn read(&self, address: u32) -> u64 {
+        // SAFETY: reading APIC MSRs has no side effects
+        unsafe { Msr::new(address).read() }
+    }
+
+    unsafe fn write(&self, address: u32, value: u64) {
+        Msr::new(address).write(value)
+    }
+}
+
+mod cache;
+#[cfg(test)]
+mod mock;
+
 // Macros for dealing with x2APIC MSRs. This would be nicer as a proc_macro, or
 // even better, a proc_macro from crates.io
 
 macro_rules! apic_msr {
     (
         $(#[$meta:meta])*
-        $reg:ident @ $addr:literal:
+        $vis:vis $reg:ident @ $addr:literal:
         struct $typ:ident {
 
         }
     ) => {
         $(#[$meta])*
-        static mut $reg: Msr = Msr::new($addr);
+        #[allow(dead_code)]
+        $vis const $reg: u32 = $addr;
 
         $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
         struct $typ(BitArray<[u64; 1], Lsb0>);
 
+        // Not every register is both read and written
+        #[allow(dead_code)]
         impl $typ {
             /// Read the current value of this MSR
             fn read() -> Self {
-                // Safety: reading APIC MSRs has no side effects
-                $typ(BitArray::new([unsafe { $reg.read() }; 1]))
+                Self::read_from(&cache::local())
+            }
+
+            /// Read the current value of this register from `regs`
+            fn read_from<R: Registers>(regs: &R) -> Self {
+                $typ(BitArray::new([regs.read($reg)]))
             }
 
             /// Update the MSR value
@@ -36,7 +82,15 @@ macro_rules! apic_msr {
             /// # Safety
             /// Modifying APIC MSRs can affect interrupt handling and cause faults.
             unsafe fn write(value: &Self) {
-                $reg.write(value.0.data[0])
+                Self::write_to(&cache::local(), value)
+            }
+
+            /// Update the value of this register in `regs`
+            ///
+            /// # Safety
+            /// Modifying APIC registers can affect interrupt handling and cause faults.
+            unsafe fn write_to<R: Registers>(regs: &R, value: &Self) {
+                regs.write($reg, value.0.data[0])
             }
         }
     };
@@ -84,6 +138,11 @@ impl IA32ApicBaseMsr {
         /// Whether or not x2APIC mode is enabled
         x2apic_enabled: 10
     );
+
+    /// Physical address of the xAPIC register page
+    fn base_address(&self) -> u64 {
+        self.0[12..52].load::<u64>() << 12
+    }
 }
 
 apic_msr!(
@@ -107,6 +166,155 @@ impl IA32SpuriousVectorRegisterMsr {
     }
 }
 
+apic_msr!(
+    /// The Interrupt Command Register (ICR), used to send IPIs
+    ///
+    /// See Intel SDM volume 3A, 10.12.9
+    IA32_ICR_MSR @ 0x830:
+    struct IA32InterruptCommandRegisterMsr {}
+);
+
+impl IA32InterruptCommandRegisterMsr {
+    /// An empty command, which sends a fixed interrupt in physical destination
+    /// mode
+    fn new() -> Self {
+        Self(BitArray::ZERO)
+    }
+
+    /// Set the vector number of the interrupt to send
+    fn set_vector(&mut self, vector: u8) {
+        self.0[..8].store(vector);
+    }
+
+    msr_field!(
+        /// The level flag. This must be set for all delivery modes except INIT
+        /// level de-assert.
+        level: 14
+    );
+
+    /// Set the x2APIC ID of the destination processor
+    fn set_destination(&mut self, apic_id: u32) {
+        self.0[32..].store(apic_id);
+    }
+}
+
+apic_msr!(
+    /// The LVT CMCI register, which configures delivery of corrected machine
+    /// check interrupts
+    ///
+    /// See Intel SDM volume 3B, 15.5.1
+    IA32_LVT_CMCI_MSR @ 0x82f:
+    struct IA32LvtCmciMsr {}
+);
+
+impl IA32LvtCmciMsr {
+    /// Set the vector number delivered for corrected machine check errors
+    fn set_vector(&mut self, vector: u8) {
+        self.0[..8].store(vector);
+    }
+
+    /// Set the delivery mode to fixed
+    fn set_fixed_delivery(&mut self) {
+        self.0[8..11].store(0u8);
+    }
+
+    msr_field!(
+        /// Whether corrected machine check interrupts are masked
+        masked: 16
+    );
+}
+
+/// The x2APIC EOI register. This is write-only, so it's not defined with
+/// `apic_msr!`
+///
+/// See Intel SDM volume 3A, 10.8.5
+const IA32_EOI_MSR: u32 = 0x80b;
+
+/// The x2APIC SELF IPI register, which sends a fixed interrupt to the current
+/// processor. It's write-only, and only the vector bits are used.
+///
+/// See Intel SDM volume 3A, 10.12.11
+const IA32_SELF_IPI_MSR: u32 = 0x83f;
+
+/// The x2APIC ID register, which is read-only
+///
+/// See Intel SDM volume 3A, 10.12.5.1
+const IA32_X2APIC_APICID_MSR: u32 = 0x802;
+
+/// The current processor's x2APIC ID
+pub(super) fn local_id() -> u32 {
+    X2Apic.read(IA32_X2APIC_APICID_MSR) as u32
+}
+
+/// Send a fixed interrupt with `vector` to the processor with x2APIC ID
+/// `apic_id`.
+///
+/// # Safety
+/// The destination processor must have a handler installed for `vector`.
+pub(super) unsafe fn send_ipi(apic_id: u32, vector: u8) {
+    send_ipi_with(&X2Apic, apic_id, vector)
+}
+
+unsafe fn send_ipi_with<R: Registers>(regs: &R, apic_id: u32, vector: u8) {
+    let mut icr = IA32InterruptCommandRegisterMsr::new();
+    icr.set_vector(vector);
+    icr.set_level(true);
+    icr.set_destination(apic_id);
+    // In x2APIC mode, a single MSR write sends the IPI
+    IA32InterruptCommandRegisterMsr::write_to(regs, &icr);
+}
+
+/// Send a fixed interrupt with `vector` to the current processor. This is
+/// cheaper than [`send_ipi`], since the local APIC doesn't need to decode a
+/// destination.
+///
+/// # Safety
+/// The current processor must have a handler installed for `vector`.
+pub(super) unsafe fn send_self_ipi(vector: u8) {
+    send_self_ipi_with(&X2Apic, vector)
+}
+
+unsafe fn send_self_ipi_with<R: Registers>(regs: &R, vector: u8) {
+    regs.write(IA32_SELF_IPI_MSR, vector.into());
+}
+
+/// Deliver corrected machine check interrupts (CMCIs) on `vector`.
+///
+/// # Safety
+/// There must be a handler installed for `vector`.
+pub(super) unsafe fn configure_cmci(vector: u8) {
+    configure_cmci_with(&cache::local(), vector)
+}
+
+unsafe fn configure_cmci_with<R: Registers>(regs: &R, vector: u8) {
+    let mut lvt = IA32LvtCmciMsr::read_from(regs);
+    lvt.set_vector(vector);
+    lvt.set_fixed_delivery();
+    lvt.set_masked(false);
+    IA32LvtCmciMsr::write_to(regs, &lvt);
+}
+
+/// Mask or unmask corrected machine check interrupts on the current processor
+pub(super) fn set_cmci_masked(masked: bool) {
+    let mut lvt = IA32LvtCmciMsr::read();
+    lvt.set_masked(masked);
+    // SAFETY: only the mask bit changes, and unmasking restores what `configure_cmci` set up
+    unsafe { IA32LvtCmciMsr::write(&lvt) }
+}
+
+/// Signal the end of interrupt handling to the local APIC. This must be called
+/// at the end of every handler for an APIC-delivered interrupt, except the
+/// spurious interrupt vector.
+pub(super) fn end_of_interrupt() {
+    // SAFETY: writing 0 to the EOI register has no effect besides signalling EOI
+    unsafe { X2Apic.write(IA32_EOI_MSR, 0) }
+}
+
+mod timer;
+
+pub(super) use timer::set_masked as set_timer_masked;
+pub use timer::{ApicTimer, TimerError};
+
 /// Initialize the local APIC on this core
 #[tracing::instrument(level = "debug")]
 pub fn init_local() {
@@ -132,20 +340,38 @@ pub fn init_local() {
 
     base.set_apic_enabled(true);
     base.set_x2apic_enabled(true);
+    // Firmware or an INIT may have changed the registers since they were cached
+    cache::invalidate_local();
     // SAFETY: yes, we do actually want to enable x2APIC mode
     // Per table 10-5 of Intel SDM volume 3, this is a valid configuration
     unsafe { IA32ApicBaseMsr::write(&base) };
 
-    let mut svr = IA32SpuriousVectorRegisterMsr::read();
-    svr.set_enabled(true);
-    svr.set_spurious_vector(super::SPURIOUS_INTERRUPT_VECTOR);
     // SAFETY: and yes, we are trying to enable interrupts, which is done via the
     // SVR
-    unsafe { IA32SpuriousVectorRegisterMsr::write(&svr) };
+    unsafe { enable_with(&cache::local(), super::SPURIOUS_INTERRUPT_VECTOR) };
 
     tracing::debug!("Enabled x2APIC mode");
 }
 
+/// Software-enable the local APIC through the SVR, delivering spurious
+/// interrupts on `spurious_vector`.
+///
+/// # Safety
+/// This allows the local APIC to deliver interrupts.
+unsafe fn enable_with<R: Registers>(regs: &R, spurious_vector: u8) {
+    let mut svr = IA32SpuriousVectorRegisterMsr::read_from(regs);
+    svr.set_enabled(true);
+    svr.set_spurious_vector(spurious_vector);
+    IA32SpuriousVectorRegisterMsr::write_to(regs, &svr);
+}
+
+/// Physical address of the current processor's xAPIC register page. The
+/// kernel uses x2APIC mode, which accesses the registers through MSRs
+/// instead, but the page is still reserved for the local APIC.
+pub fn local_apic_base() -> u64 {
+    IA32ApicBaseMsr::read().base_address()
+}
+
 /// Checks if the current processor supports x2APIC mode. It's unlikely that
 /// this will vary across processors, but is possible.
 pub fn supports_x2apic() -> bool {
@@ -153,63 +379,55 @@ pub fn supports_x2apic() -> bool {
     cpuid.get_feature_info().map_or(false, |f| f.has_x2apic())
 }
 
-// Offsets for remapping PIC interrupts
-pub(super) const PIC1_OFFSET: u8 = 32;
-pub(super) const PIC2_OFFSET: u8 = 40;
+#[cfg(test)]
+mod tests {
+    use super::mock::MockRegisters;
+    use super::*;
 
-/// Disable the legacy 8259 PIC.
-///
-/// See the OSDev wiki on [Local APIC configuration](https://wiki.osdev.org/APIC#Local_APIC_configuration)
-/// and [PIC initialization](https://wiki.osdev.org/PIC#Initialisation).
-pub(super) fn disable_pic() {
-    // Since all we're doing is disabling the PIC, we don't need a whole
-    // abstraction over it
-    use x86_64::structures::port::*;
-
-    // Data and command port numbers
-    const PIC1_COMMAND: u16 = 0x20;
-    const PIC1_DATA: u16 = 0x21;
-    const PIC2_COMMAND: u16 = 0xa0;
-    const PIC2_DATA: u16 = 0xa1;
-
-    /// Delay a few microseconds, to give the PIC time to catch up
-    /// See the [OSDev wiki](https://wiki.osdev.org/Inline_Assembly/Examples#IO_WAIT)
-    unsafe fn io_delay() {
-        u8::write_to_port(0x80, 0)
-    }
-
-    // Safety: this is the PIC initialization sequence. It's annoying.
-    unsafe {
-        // Start the initialization sequence in cascade mode
-        // First, write a command to say we're doing initialization (0x11)
-        // Then, each PIC expects 3 words on the data port:
-        // - its vector offset
-        // - how it's wired to the other PIC
-        // - information about the environment
-        u8::write_to_port(PIC1_COMMAND, 0x11);
-        io_delay();
-        u8::write_to_port(PIC2_COMMAND, 0x11);
-        io_delay();
-        // Now, write the vector offsets
-        u8::write_to_port(PIC1_DATA, PIC1_OFFSET);
-        io_delay();
-        u8::write_to_port(PIC2_DATA, PIC2_OFFSET);
-        io_delay();
-        // Tell PIC1 that PIC2 is at IRQ2 (0b00000100)
-        u8::write_to_port(PIC1_DATA, 4);
-        io_delay();
-        // Tell PIC2 its cascade identity (0b00000010)
-        u8::write_to_port(PIC2_DATA, 2);
-        io_delay();
-        // Tell both PICs to use 8086/88 (MCS-80/85) mode
-        u8::write_to_port(PIC1_DATA, 0x01);
-        io_delay();
-        u8::write_to_port(PIC2_DATA, 0x01);
-        io_delay();
-        // Mask all interrupts
-        u8::write_to_port(PIC1_DATA, 0xff);
-        io_delay();
-        u8::write_to_port(PIC2_DATA, 0xff);
-        io_delay();
+    #[test]
+    fn test_ipi_encoding() {
+        let regs = MockRegisters::new();
+        unsafe { send_ipi_with(&regs, 3, 0xf0) };
+        assert_eq!(
+            regs.writes(),
+            [(IA32_ICR_MSR, (3 << 32) | (1 << 14) | 0xf0)]
+        );
+    }
+
+    #[test]
+    fn test_self_ipi_encoding() {
+        let regs = MockRegisters::new();
+        unsafe { send_self_ipi_with(&regs, 0xf0) };
+        assert_eq!(regs.writes(), [(IA32_SELF_IPI_MSR, 0xf0)]);
+    }
+
+    #[test]
+    fn test_enable_preserves_svr_bits() {
+        let regs = MockRegisters::new();
+        // Focus processor checking and EOI broadcast suppression bits, which enabling shouldn't touch
+        regs.set(IA32_SVR_MSR, (1 << 12) | (1 << 9) | 0x0f);
+        unsafe { enable_with(&regs, 0xff) };
+        assert_eq!(
+            regs.get(IA32_SVR_MSR),
+            (1 << 12) | (1 << 9) | (1 << 8) | 0xff
+        );
+    }
+
+    #[test]
+    fn test_cmci_lvt_encoding() {
+        let regs = MockRegisters::new();
+        // Masked with SMI delivery, as firmware might leave it
+        regs.set(IA32_LVT_CMCI_MSR, (1 << 16) | (0b010 << 8) | 0x20);
+        unsafe { configure_cmci_with(&regs, 0xee) };
+        assert_eq!(regs.get(IA32_LVT_CMCI_MSR), 0xee);
+    }
+
+    #[test]
+    fn test_apic_base_fields() {
+        let base = IA32ApicBaseMsr(BitArray::new([0xfee0_0900]));
+        assert!(base.is_bsp());
+        assert!(base.apic_enabled());
+        assert!(!base.x2apic_enabled());
+        assert_eq!(base.base_address(), 0xfee0_0000);
     }
 }
@@ -0,0 +1,167 @@
+//! Shadow copies of stable local APIC registers.
+//!
+//! Registers like the SVR and LVTs only change when the kernel writes them,
+//! but the read-modify-write helpers read them back on every update, like
+//! when the timer is masked during an interrupt storm. [`Cached`] keeps a copy
+//! of the registers in [`CACHED`] for each processor, so those reads don't
+//! need an RDMSR once the copy is filled. Writes go through to the hardware
+//! and update the copy, and writes that wouldn't change a register are
+//! skipped, so re-arming the timer with the same configuration only writes
+//! the initial count. Other registers aren't cached.
+//!
+//! Cached LVT values don't include the read-only delivery status bit, which
+//! nothing checks. Software-disabling the local APIC through the SVR masks
+//! every LVT without a write the cache could see, but the kernel never does
+//! that after [`init_local`](super::init_local), which starts the cache over.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use platypos_hal::topology::Topology as _;
+
+use crate::topology::{Topology, INSTANCE};
+
+use super::timer::{IA32_LVT_TIMER_MSR, IA32_TIMER_DIVIDE_MSR};
+use super::{Registers, X2Apic, IA32_LVT_CMCI_MSR, IA32_SVR_MSR};
+
+/// Registers that are cached. These are all 32 bits wide, so [`EMPTY`] can't
+/// be a real value.
+const CACHED: [u32; 4] = [
+    IA32_SVR_MSR,
+    IA32_LVT_TIMER_MSR,
+    IA32_LVT_CMCI_MSR,
+    IA32_TIMER_DIVIDE_MSR,
+];
+
+/// Marks a register whose copy hasn't been filled
+const EMPTY: u64 = u64::MAX;
+
+const MAX_PROCESSORS: usize = Topology::MAX_PROCESSORS as usize;
+
+static SHADOWS: [Shadow; MAX_PROCESSORS] = [const { Shadow::new() }; MAX_PROCESSORS];
+
+/// One processor's copies of the registers in [`CACHED`]
+pub(super) struct Shadow {
+    values: [AtomicU64; CACHED.len()],
+}
+
+impl Shadow {
+    pub(super) const fn new() -> Self {
+        Self {
+            values: [const { AtomicU64::new(EMPTY) }; CACHED.len()],
+        }
+    }
+
+    fn slot(&self, address: u32) -> Option<&AtomicU64> {
+        let index = CACHED.iter().position(|&a| a == address)?;
+        Some(&self.values[index])
+    }
+
+    /// Forget every copy, so the next reads come from the hardware
+    pub(super) fn invalidate(&self) {
+        for value in &self.values {
+            value.store(EMPTY, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Registers that serve stable registers from a [`Shadow`]. The shadow is
+/// only touched by its own processor, but interrupt handlers can update it
+/// in between a task's accesses, so it's still atomic.
+pub(super) struct Cached<'a, R> {
+    regs: &'a R,
+    shadow: &'a Shadow,
+}
+
+impl<'a, R: Registers> Cached<'a, R> {
+    pub(super) fn new(regs: &'a R, shadow: &'a Shadow) -> Self {
+        Self { regs, shadow }
+    }
+}
+
+impl<R: Registers> Registers for Cached<'_, R> {
+    fn read(&self, address: u32) -> u64 {
+        let Some(slot) = self.shadow.slot(address) else {
+            return self.regs.read(address);
+        };
+        match slot.load(Ordering::Relaxed) {
+            EMPTY => {
+                let value = self.regs.read(address);
+                slot.store(value, Ordering::Relaxed);
+                value
+            }
+            value => value,
+        }
+    }
+
+    unsafe fn write(&self, address: u32, value: u64) {
+        match self.shadow.slot(address) {
+            Some(slot) => {
+                if slot.swap(value, Ordering::Relaxed) != value {
+                    self.regs.write(address, value);
+                }
+            }
+            None => self.regs.write(address, value),
+        }
+    }
+}
+
+/// The current processor's local APIC registers, with stable ones cached
+pub(super) fn local() -> Cached<'static, X2Apic> {
+    let processor = usize::from(INSTANCE.current_processor());
+    Cached::new(&X2Apic, &SHADOWS[processor])
+}
+
+/// Forget the current processor's cached registers, like after the local
+/// APIC is reset
+pub(super) fn invalidate_local() {
+    SHADOWS[usize::from(INSTANCE.current_processor())].invalidate();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::MockRegisters;
+    use super::super::IA32_APIC_BASE_MSR;
+    use super::*;
+
+    #[test]
+    fn test_reads_are_cached() {
+        let regs = MockRegisters::new();
+        regs.set(IA32_SVR_MSR, 0x1ff);
+        let shadow = Shadow::new();
+        let cached = Cached::new(&regs, &shadow);
+
+        assert_eq!(cached.read(IA32_SVR_MSR), 0x1ff);
+        assert_eq!(cached.read(IA32_SVR_MSR), 0x1ff);
+        assert_eq!(regs.reads(), 1);
+
+        // Uncached registers always go to the hardware
+        cached.read(IA32_APIC_BASE_MSR);
+        cached.read(IA32_APIC_BASE_MSR);
+        assert_eq!(regs.reads(), 3);
+
+        shadow.invalidate();
+        regs.set(IA32_SVR_MSR, 0xff);
+        assert_eq!(cached.read(IA32_SVR_MSR), 0xff);
+        assert_eq!(regs.reads(), 4);
+    }
+
+    #[test]
+    fn test_unchanged_writes_are_skipped() {
+        let regs = MockRegisters::new();
+        let shadow = Shadow::new();
+        let cached = Cached::new(&regs, &shadow);
+
+        unsafe {
+            cached.write(IA32_LVT_CMCI_MSR, 0xee);
+            cached.write(IA32_LVT_CMCI_MSR, 0xee);
+            cached.write(IA32_LVT_CMCI_MSR, 1 << 16);
+        }
+        assert_eq!(
+            regs.writes(),
+            [(IA32_LVT_CMCI_MSR, 0xee), (IA32_LVT_CMCI_MSR, 1 << 16)]
+        );
+        // Writes fill the cache too
+        assert_eq!(cached.read(IA32_LVT_CMCI_MSR), 1 << 16);
+        assert_eq!(regs.reads(), 0);
+    }
+}
@@ -0,0 +1,54 @@
+//! Mock local APIC register file for host unit tests.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::vec::Vec;
+
+use super::Registers;
+
+/// Register file that records every write. Registers that were never written
+/// read as 0.
+#[derive(Debug, Default)]
+pub(super) struct MockRegisters {
+    values: RefCell<BTreeMap<u32, u64>>,
+    writes: RefCell<Vec<(u32, u64)>>,
+    reads: Cell<usize>,
+}
+
+impl MockRegisters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of a register without recording a write
+    pub fn set(&self, address: u32, value: u64) {
+        self.values.borrow_mut().insert(address, value);
+    }
+
+    /// Get the current value of a register without recording a read
+    pub fn get(&self, address: u32) -> u64 {
+        self.values.borrow().get(&address).copied().unwrap_or(0)
+    }
+
+    /// Number of reads so far
+    pub fn reads(&self) -> usize {
+        self.reads.get()
+    }
+
+    /// All writes so far, as `(address, value)` pairs in order
+    pub fn writes(&self) -> Vec<(u32, u64)> {
+        self.writes.borrow().clone()
+    }
+}
+
+impl Registers for MockRegisters {
+    fn read(&self, address: u32) -> u64 {
+        self.reads.set(self.reads.get() + 1);
+        self.get(address)
+    }
+
+    unsafe fn write(&self, address: u32, value: u64) {
+        self.values.borrow_mut().insert(address, value);
+        self.writes.borrow_mut().push((address, value));
+    }
+}
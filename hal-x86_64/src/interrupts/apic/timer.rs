@@ -0,0 +1,397 @@
+//! Local APIC timer.
+//!
+//! The APIC timer counts down from an initial count at a rate derived from the
+//! processor's bus or core crystal clock. That rate isn't architecturally
+//! specified, so it's measured against the legacy PIT when the timer is
+//! calibrated. [`ApicTimer`] then converts durations into initial counts,
+//! picking a divide configuration that can represent them.
+//!
+//! See Intel SDM volume 3A, 10.5.4
+
+use core::fmt;
+use core::time::Duration;
+
+use bitvec::prelude::*;
+use paste::paste;
+use x86_64::structures::port::{PortRead, PortWrite};
+
+use super::{cache, Registers};
+
+apic_msr!(
+    /// The LVT timer register, which configures timer interrupt delivery
+    ///
+    /// See Intel SDM volume 3A, 10.5.1
+    pub(super) IA32_LVT_TIMER_MSR @ 0x832:
+    struct IA32LvtTimerMsr {}
+);
+
+impl IA32LvtTimerMsr {
+    fn new() -> Self {
+        Self(BitArray::ZERO)
+    }
+
+    /// Set the vector number delivered when the timer fires
+    fn set_vector(&mut self, vector: u8) {
+        self.0[..8].store(vector);
+    }
+
+    msr_field!(
+        /// Whether timer interrupts are masked
+        masked: 16
+    );
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.0[17..19].store(mode as u8);
+    }
+}
+
+/// Mask or unmask the current processor's timer interrupts, leaving the rest
+/// of its configuration alone. Arming the timer unmasks it again.
+pub(in crate::interrupts) fn set_masked(masked: bool) {
+    let mut lvt = IA32LvtTimerMsr::read();
+    lvt.set_masked(masked);
+    // SAFETY: only the mask bit changes, and the vector already has a handler installed
+    unsafe { IA32LvtTimerMsr::write(&lvt) }
+}
+
+apic_msr!(
+    /// The timer's initial count. Writing this starts the timer, and writing 0
+    /// stops it.
+    IA32_TIMER_INITIAL_COUNT_MSR @ 0x838:
+    struct IA32TimerInitialCountMsr {}
+);
+
+apic_msr!(
+    /// The timer's current count
+    IA32_TIMER_CURRENT_COUNT_MSR @ 0x839:
+    struct IA32TimerCurrentCountMsr {}
+);
+
+apic_msr!(
+    /// The timer's divide configuration
+    pub(super) IA32_TIMER_DIVIDE_MSR @ 0x83e:
+    struct IA32TimerDivideMsr {}
+);
+
+impl IA32TimerInitialCountMsr {
+    fn new(count: u32) -> Self {
+        Self(BitArray::new([count as u64]))
+    }
+}
+
+impl IA32TimerCurrentCountMsr {
+    fn count(&self) -> u32 {
+        self.0[..32].load()
+    }
+}
+
+impl IA32TimerDivideMsr {
+    fn new(divisor: Divisor) -> Self {
+        Self(BitArray::new([divisor.encoding() as u64]))
+    }
+}
+
+/// Timer modes, as encoded in the LVT timer register
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+}
+
+/// Supported divide configurations, as a power of 2
+#[derive(Debug, Clone, Copy)]
+struct Divisor(u8);
+
+impl Divisor {
+    /// Divisors from smallest (most precise) to largest (longest interval)
+    fn all() -> impl Iterator<Item = Divisor> {
+        (0..=7).map(Divisor)
+    }
+
+    fn value(self) -> u64 {
+        1 << self.0
+    }
+
+    /// Encoding for the divide configuration register. Bit 2 is reserved, so
+    /// the 3-bit divisor value is split around it.
+    fn encoding(self) -> u8 {
+        // The register encodes log2(divisor) - 1, with divide-by-1 wrapping around to 0b111
+        let raw = self.0.wrapping_sub(1) & 0b111;
+        (raw & 0b11) | ((raw & 0b100) << 1)
+    }
+}
+
+/// Errors arming the APIC timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// The requested interval is shorter than one timer tick
+    TooShort,
+    /// The requested interval is too long to represent, even with the largest
+    /// divide configuration
+    TooLong,
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimerError::TooShort => f.write_str("interval is shorter than one timer tick"),
+            TimerError::TooLong => f.write_str("interval is too long for the APIC timer"),
+        }
+    }
+}
+
+/// PIT input clock frequency, in Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// How long to measure the APIC timer against the PIT for, in milliseconds
+const CALIBRATION_MS: u64 = 10;
+
+/// Divisor used while calibrating. This leaves plenty of headroom in the
+/// 32-bit counter even for fast timer clocks.
+const CALIBRATION_DIVISOR: Divisor = Divisor(4);
+
+/// The current processor's local APIC timer
+#[derive(Debug, Clone, Copy)]
+pub struct ApicTimer {
+    /// Undivided timer clock frequency, in Hz
+    frequency: u64,
+    /// Vector the timer interrupt is delivered on
+    vector: u8,
+}
+
+impl ApicTimer {
+    /// Measure the current processor's APIC timer frequency, and the TSC
+    /// frequency along with it. This busy-waits for about 10ms.
+    pub(in crate::interrupts) fn calibrate(vector: u8) -> Self {
+        let mut lvt = IA32LvtTimerMsr::new();
+        lvt.set_vector(vector);
+        lvt.set_masked(true);
+        lvt.set_mode(Mode::OneShot);
+
+        let mut tsc_start = 0;
+        // SAFETY: the timer is masked, so counting down won't cause any interrupts
+        let (elapsed, tsc_elapsed) = unsafe {
+            IA32LvtTimerMsr::write(&lvt);
+            IA32TimerDivideMsr::write(&IA32TimerDivideMsr::new(CALIBRATION_DIVISOR));
+            pit_wait(|| {
+                IA32TimerInitialCountMsr::write(&IA32TimerInitialCountMsr::new(u32::MAX));
+                tsc_start = core::arch::x86_64::_rdtsc();
+            });
+            let remaining = IA32TimerCurrentCountMsr::read().count();
+            let tsc_elapsed = core::arch::x86_64::_rdtsc() - tsc_start;
+            IA32TimerInitialCountMsr::write(&IA32TimerInitialCountMsr::new(0));
+            (u32::MAX - remaining, tsc_elapsed)
+        };
+
+        let (frequency, tsc_frequency) = calibrated_frequencies(elapsed, tsc_elapsed);
+        tracing::debug!("APIC timer frequency is {} Hz", frequency);
+        tracing::debug!("TSC frequency is {} Hz", tsc_frequency);
+        crate::time::set_frequency(tsc_frequency);
+        Self { frequency, vector }
+    }
+
+    /// The undivided timer clock frequency, in Hz
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Fire a single timer interrupt after `interval`, replacing any pending
+    /// timer.
+    pub fn arm_oneshot(&self, interval: Duration) -> Result<(), TimerError> {
+        self.arm(interval, Mode::OneShot)
+    }
+
+    /// Fire a timer interrupt every `interval`, replacing any pending timer.
+    pub fn arm_periodic(&self, interval: Duration) -> Result<(), TimerError> {
+        self.arm(interval, Mode::Periodic)
+    }
+
+    /// Stop the timer
+    pub fn stop(&self) {
+        // SAFETY: a 0 initial count stops the timer without any other effects
+        unsafe { IA32TimerInitialCountMsr::write(&IA32TimerInitialCountMsr::new(0)) }
+    }
+
+    fn arm(&self, interval: Duration, mode: Mode) -> Result<(), TimerError> {
+        // SAFETY: the timer vector has a handler installed in the IDT
+        unsafe { self.arm_with(&cache::local(), interval, mode) }
+    }
+
+    /// # Safety
+    /// The timer vector must have a handler installed.
+    unsafe fn arm_with<R: Registers>(
+        &self,
+        regs: &R,
+        interval: Duration,
+        mode: Mode,
+    ) -> Result<(), TimerError> {
+        let (divisor, count) = self.count_for(interval)?;
+
+        let mut lvt = IA32LvtTimerMsr::new();
+        lvt.set_vector(self.vector);
+        lvt.set_mode(mode);
+
+        // Writing the initial count last (re)starts the timer with the new configuration
+        IA32LvtTimerMsr::write_to(regs, &lvt);
+        IA32TimerDivideMsr::write_to(regs, &IA32TimerDivideMsr::new(divisor));
+        IA32TimerInitialCountMsr::write_to(regs, &IA32TimerInitialCountMsr::new(count));
+        Ok(())
+    }
+
+    /// Pick the most precise divisor that can represent `interval`, and the
+    /// corresponding initial count
+    fn count_for(&self, interval: Duration) -> Result<(Divisor, u32), TimerError> {
+        let ticks = interval.as_nanos() * self.frequency as u128 / 1_000_000_000;
+
+        for divisor in Divisor::all() {
+            let count = ticks / divisor.value() as u128;
+            if count == 0 {
+                return Err(TimerError::TooShort);
+            }
+            if let Ok(count) = u32::try_from(count) {
+                return Ok((divisor, count));
+            }
+        }
+
+        Err(TimerError::TooLong)
+    }
+}
+
+/// The undivided APIC timer and TSC frequencies, in Hz, given how far each
+/// counted during calibration. `elapsed` is in divided timer ticks.
+fn calibrated_frequencies(elapsed: u32, tsc_elapsed: u64) -> (u64, u64) {
+    let frequency = elapsed as u64 * CALIBRATION_DIVISOR.value() * 1000 / CALIBRATION_MS;
+    let tsc_frequency = tsc_elapsed * 1000 / CALIBRATION_MS;
+    (frequency, tsc_frequency)
+}
+
+/// Run `start`, then busy-wait for [`CALIBRATION_MS`] using PIT channel 2.
+///
+/// # Safety
+/// This reprograms PIT channel 2 and the PC speaker gate.
+unsafe fn pit_wait(start: impl FnOnce()) {
+    const CHANNEL_2_DATA: u16 = 0x42;
+    const COMMAND: u16 = 0x43;
+    /// Controls the channel 2 gate (bit 0) and speaker (bit 1), and reports the
+    /// channel 2 output (bit 5)
+    const GATE: u16 = 0x61;
+
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    let original_gate = u8::read_from_port(GATE);
+    // Disable the gate and speaker while setting up
+    u8::write_to_port(GATE, original_gate & !0b11);
+    // Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+    u8::write_to_port(COMMAND, 0b1011_0000);
+    u8::write_to_port(CHANNEL_2_DATA, count as u8);
+    u8::write_to_port(CHANNEL_2_DATA, (count >> 8) as u8);
+
+    // Raising the gate starts the countdown
+    start();
+    u8::write_to_port(GATE, (original_gate & !0b10) | 0b01);
+    while u8::read_from_port(GATE) & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+
+    u8::write_to_port(GATE, original_gate);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cache::{Cached, Shadow};
+    use super::super::mock::MockRegisters;
+    use super::*;
+
+    /// A timer with a 1 GHz clock, so ticks are nanoseconds
+    const TIMER: ApicTimer = ApicTimer {
+        frequency: 1_000_000_000,
+        vector: 0xef,
+    };
+
+    #[test]
+    fn test_divisor_encoding() {
+        // Intel SDM volume 3A, figure 10-10
+        let expected = [
+            0b1011, 0b0000, 0b0001, 0b0010, 0b0011, 0b1000, 0b1001, 0b1010,
+        ];
+        for (divisor, expected) in Divisor::all().zip(expected) {
+            assert_eq!(
+                divisor.encoding(),
+                expected,
+                "divide by {}",
+                divisor.value()
+            );
+        }
+    }
+
+    #[test]
+    fn test_calibrated_frequencies() {
+        // A 100 MHz timer clock counts 62,500 divide-by-16 ticks in 10ms, while a 2 GHz TSC
+        // counts 20 million
+        assert_eq!(
+            calibrated_frequencies(62_500, 20_000_000),
+            (100_000_000, 2_000_000_000)
+        );
+        // Even a timer that counts the whole 32-bit range doesn't overflow
+        assert_eq!(
+            calibrated_frequencies(u32::MAX, 0),
+            (u32::MAX as u64 * 16 * 100, 0)
+        );
+    }
+
+    #[test]
+    fn test_count_for() {
+        assert_eq!(
+            TIMER
+                .count_for(Duration::from_millis(1))
+                .map(|(d, c)| (d.value(), c)),
+            Ok((1, 1_000_000))
+        );
+        // 10 billion ticks only fits in 32 bits when divided by 4
+        assert_eq!(
+            TIMER
+                .count_for(Duration::from_secs(10))
+                .map(|(d, c)| (d.value(), c)),
+            Ok((4, 2_500_000_000))
+        );
+        assert_eq!(
+            TIMER.count_for(Duration::ZERO).map(|_| ()),
+            Err(TimerError::TooShort)
+        );
+        assert_eq!(
+            TIMER.count_for(Duration::from_secs(1000)).map(|_| ()),
+            Err(TimerError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_arm_periodic_registers() {
+        let regs = MockRegisters::new();
+        unsafe { TIMER.arm_with(&regs, Duration::from_secs(10), Mode::Periodic) }.unwrap();
+        assert_eq!(
+            regs.writes(),
+            [
+                // Vector 0xef, unmasked, periodic mode
+                (IA32_LVT_TIMER_MSR, (0b01 << 17) | 0xef),
+                (IA32_TIMER_DIVIDE_MSR, 0b0001),
+                // The initial count must be written last, since it starts the timer
+                (IA32_TIMER_INITIAL_COUNT_MSR, 2_500_000_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rearm_only_writes_count() {
+        let regs = MockRegisters::new();
+        let shadow = Shadow::new();
+        let cached = Cached::new(&regs, &shadow);
+        let arm = |ms| unsafe { TIMER.arm_with(&cached, Duration::from_millis(ms), Mode::OneShot) };
+        arm(1).unwrap();
+        // Only the interval changed, so the LVT and divide configuration are left alone
+        arm(2).unwrap();
+        assert_eq!(
+            regs.writes()[3..],
+            [(IA32_TIMER_INITIAL_COUNT_MSR, 2_000_000)]
+        );
+    }
+}
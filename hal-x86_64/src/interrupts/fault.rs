@@ -0,0 +1,77 @@
+//! CPU exception reports.
+//!
+//! Exceptions raised by kernel code are bugs, so the handlers panic with a
+//! [`Fault`] describing what went wrong. Once there's user mode, faults from
+//! user code will be recoverable instead.
+
+use core::fmt;
+
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// Exception classes the kernel handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Division by zero, or a quotient too large for the destination (#DE)
+    DivideError,
+    /// Undefined or reserved opcode (#UD)
+    InvalidOpcode,
+    /// Access to a page that isn't mapped or doesn't allow the access (#PF)
+    PageFault,
+    /// Unaligned memory access with alignment checking enabled (#AC). This is
+    /// only raised at CPL 3.
+    AlignmentCheck,
+}
+
+impl FaultKind {
+    /// The exception's vector
+    pub(super) const fn vector(self) -> u8 {
+        match self {
+            FaultKind::DivideError => 0,
+            FaultKind::InvalidOpcode => 6,
+            FaultKind::PageFault => 14,
+            FaultKind::AlignmentCheck => 17,
+        }
+    }
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FaultKind::DivideError => "divide error",
+            FaultKind::InvalidOpcode => "invalid opcode",
+            FaultKind::PageFault => "page fault",
+            FaultKind::AlignmentCheck => "alignment check",
+        })
+    }
+}
+
+/// Report of a CPU exception
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    /// What kind of exception occurred
+    pub kind: FaultKind,
+    /// Address of the faulting instruction
+    pub instruction_pointer: u64,
+    /// Address whose access caused the fault, for page faults
+    pub address: Option<u64>,
+    /// Exception error code, for exceptions that push one
+    pub error_code: Option<u64>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(address) = self.address {
+            write!(f, " accessing {:#x}", address)?;
+        }
+        write!(f, " at {:#x}", self.instruction_pointer)?;
+
+        match (self.kind, self.error_code) {
+            (FaultKind::PageFault, Some(code)) => {
+                write!(f, " ({:?})", PageFaultErrorCode::from_bits_truncate(code))
+            }
+            (_, Some(code)) => write!(f, " (error code {:#x})", code),
+            (_, None) => Ok(()),
+        }
+    }
+}
@@ -1,11 +1,220 @@
 //! Interrupt handler entry points
 
-use x86_64::structures::idt::InterruptStackFrame;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-pub extern "x86-interrupt" fn handle_remapped_pic(_frame: InterruptStackFrame) {
-    tracing::warn!("Got an interrupt from the PIC");
+use platypos_hal::interrupts::nmi_safe;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+use super::apic;
+use super::fault::{Fault, FaultKind};
+use super::ipi::{self, Ipi};
+use super::sample::{self, Interrupted};
+use super::{latency, machine_check, nesting, pic, priority, stats, storm};
+
+/// Generates an entry point for each legacy ISA IRQ, since handlers can't tell
+/// which vector they were called for
+macro_rules! legacy_irq_handlers {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_frame: InterruptStackFrame) {
+                handle_legacy_irq($irq);
+            }
+        )*
+
+        /// Entry points for legacy ISA IRQs 0-15
+        pub const LEGACY_IRQ_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); 16] =
+            [$($name),*];
+    };
+}
+
+legacy_irq_handlers!(
+    0 => handle_irq0, 1 => handle_irq1, 2 => handle_irq2, 3 => handle_irq3,
+    4 => handle_irq4, 5 => handle_irq5, 6 => handle_irq6, 7 => handle_irq7,
+    8 => handle_irq8, 9 => handle_irq9, 10 => handle_irq10, 11 => handle_irq11,
+    12 => handle_irq12, 13 => handle_irq13, 14 => handle_irq14, 15 => handle_irq15,
+);
+
+fn handle_legacy_irq(irq: u8) {
+    let _irq = nesting::enter();
+    let _timed = latency::start(pic::vector(irq));
+    stats::record(pic::vector(irq));
+    if pic::is_spurious(irq) {
+        return;
+    }
+    if storm::check(pic::vector(irq)) {
+        pic::end_of_interrupt(irq);
+        return;
+    }
+
+    // PIC interrupts bypass the TPR, so they're masked by priority level here instead
+    if !priority::defer_legacy_irq(irq) {
+        if let Some(handler) = super::LEGACY_IRQ_HANDLERS[usize::from(irq)].try_get() {
+            handler();
+        } else {
+            tracing::warn!("Got an interrupt from the PIC (IRQ {irq})");
+        }
+    }
+    pic::end_of_interrupt(irq);
 }
 
 pub extern "x86-interrupt" fn handle_spurious(_frame: InterruptStackFrame) {
+    let _irq = nesting::enter();
+    let _timed = latency::start(super::SPURIOUS_INTERRUPT_VECTOR);
+    stats::record(super::SPURIOUS_INTERRUPT_VECTOR);
     tracing::warn!("Got a spurious interrupt");
 }
+
+pub extern "x86-interrupt" fn handle_call_function(_frame: InterruptStackFrame) {
+    handle_ipi(Ipi::CallFunction);
+}
+
+pub extern "x86-interrupt" fn handle_reschedule(_frame: InterruptStackFrame) {
+    handle_ipi(Ipi::Reschedule);
+}
+
+pub extern "x86-interrupt" fn handle_tlb_shootdown(_frame: InterruptStackFrame) {
+    handle_ipi(Ipi::TlbShootdown);
+}
+
+pub extern "x86-interrupt" fn handle_panic_stop(_frame: InterruptStackFrame) {
+    let _irq = nesting::enter();
+    stats::record(Ipi::PanicStop.vector());
+    ipi::dispatch(Ipi::PanicStop);
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+fn handle_ipi(ipi: Ipi) {
+    let _irq = nesting::enter();
+    let _timed = latency::start(ipi.vector());
+    stats::record(ipi.vector());
+    if !ipi::dispatch(ipi) {
+        tracing::warn!("Got a {ipi:?} IPI with no handler registered");
+    }
+    apic::end_of_interrupt();
+}
+
+pub extern "x86-interrupt" fn handle_timer(frame: InterruptStackFrame) {
+    let interrupted = Interrupted {
+        instruction_pointer: frame.instruction_pointer.as_u64(),
+        context: nesting::current(),
+    };
+    let _irq = nesting::enter();
+    let _timed = latency::start(super::TIMER_VECTOR);
+    stats::record(super::TIMER_VECTOR);
+    sample::run_hook(&interrupted);
+    if !storm::check(super::TIMER_VECTOR) {
+        if let Some(handler) = super::TIMER_HANDLER.try_get() {
+            handler();
+        } else {
+            tracing::warn!("Got an APIC timer interrupt with no handler registered");
+        }
+    }
+    apic::end_of_interrupt();
+}
+
+pub extern "x86-interrupt" fn handle_cmci(_frame: InterruptStackFrame) {
+    let _irq = nesting::enter();
+    let _timed = latency::start(super::CMCI_VECTOR);
+    stats::record(super::CMCI_VECTOR);
+    if !storm::check(super::CMCI_VECTOR) {
+        machine_check::poll_corrected();
+    }
+    apic::end_of_interrupt();
+}
+
+/// NMIs that weren't logged because they interrupted an NMI-unsafe critical
+/// section
+static DEFERRED_NMIS: AtomicU32 = AtomicU32::new(0);
+
+pub extern "x86-interrupt" fn handle_nmi(_frame: InterruptStackFrame) {
+    let _nmi = nesting::enter_nmi();
+    let _timed = latency::start(stats::NMI_VECTOR);
+    stats::record(stats::NMI_VECTOR);
+
+    // Logging could re-enter whatever was interrupted, so leave it for the next NMI that
+    // arrives somewhere safe. The stats above still count every one.
+    if nmi_safe::active() {
+        DEFERRED_NMIS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    // Nothing raises NMIs on purpose yet, so this is most likely a hardware error
+    let deferred = DEFERRED_NMIS.swap(0, Ordering::Relaxed);
+    if deferred == 0 {
+        tracing::warn!("Got a non-maskable interrupt");
+    } else {
+        tracing::warn!("Got a non-maskable interrupt ({deferred} more went unlogged)");
+    }
+}
+
+pub extern "x86-interrupt" fn handle_machine_check(frame: InterruptStackFrame) -> ! {
+    stats::record(stats::MACHINE_CHECK_VECTOR);
+    let restartable = machine_check::report_exception();
+    // Even if the interrupted code could be restarted, the kernel doesn't know which data the
+    // error corrupted
+    panic!(
+        "Machine check exception at {:#x} ({})",
+        frame.instruction_pointer.as_u64(),
+        if restartable {
+            "restartable"
+        } else {
+            "not restartable"
+        }
+    );
+}
+
+pub extern "x86-interrupt" fn handle_divide_error(frame: InterruptStackFrame) {
+    fatal_fault(&frame, FaultKind::DivideError, None, None);
+}
+
+pub extern "x86-interrupt" fn handle_invalid_opcode(frame: InterruptStackFrame) {
+    fatal_fault(&frame, FaultKind::InvalidOpcode, None, None);
+}
+
+pub extern "x86-interrupt" fn handle_page_fault(
+    frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _timed = latency::start(FaultKind::PageFault.vector());
+    stats::record(FaultKind::PageFault.vector());
+    // CR2 is only overwritten by another page fault, so it's safe to read here
+    let address = Cr2::read().as_u64();
+    let fault = Fault {
+        kind: FaultKind::PageFault,
+        instruction_pointer: frame.instruction_pointer.as_u64(),
+        address: Some(address),
+        error_code: Some(error_code.bits()),
+    };
+    if super::PAGE_FAULT_HANDLER
+        .try_get()
+        .map_or(false, |handler| handler(&fault))
+    {
+        return;
+    }
+    panic!("CPU exception: {}", fault);
+}
+
+pub extern "x86-interrupt" fn handle_alignment_check(frame: InterruptStackFrame, error_code: u64) {
+    fatal_fault(&frame, FaultKind::AlignmentCheck, None, Some(error_code));
+}
+
+/// Report an exception that the kernel can't recover from
+fn fatal_fault(
+    frame: &InterruptStackFrame,
+    kind: FaultKind,
+    address: Option<u64>,
+    error_code: Option<u64>,
+) -> ! {
+    stats::record(kind.vector());
+    let fault = Fault {
+        kind,
+        instruction_pointer: frame.instruction_pointer.as_u64(),
+        address,
+        error_code,
+    };
+    panic!("CPU exception: {}", fault);
+}
@@ -0,0 +1,176 @@
+//! Inter-processor interrupts with dedicated vectors.
+//!
+//! Each [`Ipi`] has a fixed vector with an entry point in the IDT, so sending
+//! one is safe as long as the destination has loaded the IDT. Processors only
+//! go [online](ProcessorState::Online) once they have (in
+//! [`super::init_local`]), and IPIs to any other processor are rejected.
+
+use core::fmt;
+
+use platypos_common::sync::Global;
+use platypos_hal::topology::{self, ProcessorId, ProcessorState, Topology as _};
+
+use crate::topology::INSTANCE;
+
+use super::apic;
+
+/// Kernel handlers for each IPI, indexed by [`Ipi::index`]
+static HANDLERS: [Global<fn()>; Ipi::COUNT] = [const { Global::new() }; Ipi::COUNT];
+
+/// An inter-processor interrupt with a dedicated vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipi {
+    /// Drain the processor's remote function call mailbox
+    CallFunction,
+    /// Ask the processor to run the scheduler
+    Reschedule,
+    /// Ask the processor to flush stale TLB entries
+    TlbShootdown,
+    /// Stop the processor because another one panicked or is shutting down.
+    /// After running the registered handler, if any, the processor halts with
+    /// interrupts disabled.
+    PanicStop,
+}
+
+impl Ipi {
+    const COUNT: usize = 4;
+
+    /// The IDT vector this IPI is delivered on
+    pub(super) const fn vector(self) -> u8 {
+        match self {
+            Ipi::CallFunction => super::CALL_FUNCTION_VECTOR,
+            Ipi::Reschedule => super::RESCHEDULE_VECTOR,
+            Ipi::TlbShootdown => super::TLB_SHOOTDOWN_VECTOR,
+            Ipi::PanicStop => super::PANIC_STOP_VECTOR,
+        }
+    }
+
+    const fn index(self) -> usize {
+        match self {
+            Ipi::CallFunction => 0,
+            Ipi::Reschedule => 1,
+            Ipi::TlbShootdown => 2,
+            Ipi::PanicStop => 3,
+        }
+    }
+}
+
+/// Errors sending an IPI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiError {
+    /// The destination processor doesn't exist, or isn't online
+    Offline(ProcessorId),
+}
+
+impl fmt::Display for IpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpiError::Offline(processor) => write!(f, "processor {processor} is not online"),
+        }
+    }
+}
+
+/// How to send an IPI to the current processor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfIpiRoute {
+    /// The x2APIC SELF IPI register. [`send_ipi`] uses this.
+    SelfRegister,
+    /// A full Interrupt Command Register write, the same as for any other
+    /// processor
+    Icr,
+}
+
+/// Register the function to run when this processor receives `ipi`. The
+/// handler runs in interrupt context, with interrupts disabled.
+///
+/// # Panics
+/// If a handler was already registered for `ipi`
+pub fn set_ipi_handler(ipi: Ipi, handler: fn()) {
+    HANDLERS[ipi.index()].init(handler);
+}
+
+/// Send `ipi` to `processor`
+///
+/// # Errors
+/// If `processor` isn't online
+pub fn send_ipi(ipi: Ipi, processor: ProcessorId) -> Result<(), IpiError> {
+    if !is_online(processor) {
+        return Err(IpiError::Offline(processor));
+    }
+    if processor == INSTANCE.current_processor() {
+        send_self_ipi(ipi, SelfIpiRoute::SelfRegister);
+        return Ok(());
+    }
+
+    let apic_id = crate::topology::apic_id(processor).ok_or(IpiError::Offline(processor))?;
+    // SAFETY: the processor is online, so it's loaded the IDT, which has an entry point for every
+    // IPI vector
+    unsafe { apic::send_ipi(apic_id, ipi.vector()) };
+    Ok(())
+}
+
+/// Send `ipi` to the current processor through `route`. This is mostly useful
+/// for comparing the two routes; [`send_ipi`] picks the faster one.
+pub fn send_self_ipi(ipi: Ipi, route: SelfIpiRoute) {
+    // SAFETY: the current processor is running this, so it's loaded the IDT (see send_ipi)
+    unsafe {
+        match route {
+            SelfIpiRoute::SelfRegister => apic::send_self_ipi(ipi.vector()),
+            SelfIpiRoute::Icr => apic::send_ipi(apic::local_id(), ipi.vector()),
+        }
+    }
+}
+
+/// Send `ipi` to every online processor except the current one
+pub fn broadcast_ipi(ipi: Ipi) {
+    let current = INSTANCE.current_processor();
+    for processor in topology::online_processors().without(current).iter() {
+        // Online processors have recorded their APIC ID
+        if let Some(apic_id) = crate::topology::apic_id(processor) {
+            // SAFETY: see send_ipi
+            unsafe { apic::send_ipi(apic_id, ipi.vector()) };
+        }
+    }
+}
+
+fn is_online(processor: ProcessorId) -> bool {
+    processor < crate::topology::Topology::MAX_PROCESSORS
+        && topology::processor_state(processor) == ProcessorState::Online
+}
+
+/// Run the handler for `ipi`, if one is registered. Returns `false` if there
+/// isn't one.
+pub(super) fn dispatch(ipi: Ipi) -> bool {
+    match HANDLERS[ipi.index()].try_get() {
+        Some(handler) => {
+            handler();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Ipi; Ipi::COUNT] = [
+        Ipi::CallFunction,
+        Ipi::Reschedule,
+        Ipi::TlbShootdown,
+        Ipi::PanicStop,
+    ];
+
+    #[test]
+    fn test_vectors_are_distinct_ipi_class() {
+        for (i, a) in ALL.iter().enumerate() {
+            assert_eq!(a.index(), i);
+            // Every IPI must be masked by the High priority level, and nothing else
+            assert_eq!(a.vector() >> 4, super::super::CALL_FUNCTION_VECTOR >> 4);
+            assert_ne!(a.vector(), super::super::SPURIOUS_INTERRUPT_VECTOR);
+            for b in &ALL[i + 1..] {
+                assert_ne!(a.vector(), b.vector(), "{a:?} and {b:?} share a vector");
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Per-vector interrupt handling latency.
+//!
+//! Entry points that return hold a [`Timed`] guard while they run, which reads
+//! the TSC on entry and adds the elapsed ticks to a histogram when the handler
+//! returns. Handlers that never return, like fatal exceptions, aren't timed.
+//! Time spent in nested handlers counts towards the handler they interrupted
+//! as well as their own.
+//!
+//! Like the interrupt counts, each processor has its own histograms, so
+//! recording is a couple of uncontended relaxed atomics. Buckets are powers of
+//! two of TSC ticks, since the TSC may not be calibrated when the first
+//! interrupts arrive.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use platypos_hal::time::Clock as _;
+use platypos_hal::topology::{ProcessorId, Topology as _};
+
+use crate::time;
+use crate::topology::{Topology, INSTANCE};
+
+use super::VECTORS;
+
+/// Number of histogram buckets
+pub const LATENCY_BUCKETS: usize = 16;
+
+/// Bucket 0 holds latencies below `2^(MIN_SHIFT + 1)` ticks, bucket 1 below
+/// `2^(MIN_SHIFT + 2)`, and so on. The last bucket holds everything longer.
+const MIN_SHIFT: u32 = 8;
+
+const MAX_PROCESSORS: usize = Topology::MAX_PROCESSORS as usize;
+
+static HISTOGRAMS: [[[AtomicU32; LATENCY_BUCKETS]; VECTORS]; MAX_PROCESSORS] =
+    [const { [const { [const { AtomicU32::new(0) }; LATENCY_BUCKETS] }; VECTORS] }; MAX_PROCESSORS];
+
+static MAX_TICKS: [[AtomicU64; VECTORS]; MAX_PROCESSORS] =
+    [const { [const { AtomicU64::new(0) }; VECTORS] }; MAX_PROCESSORS];
+
+/// Times an interrupt handler until dropped
+#[must_use = "the handler is only timed while the guard is held"]
+pub(super) struct Timed {
+    vector: u8,
+    start: u64,
+}
+
+impl Drop for Timed {
+    fn drop(&mut self) {
+        let ticks = time::INSTANCE.now().saturating_sub(self.start);
+        let processor = usize::from(INSTANCE.current_processor());
+        let vector = usize::from(self.vector);
+        HISTOGRAMS[processor][vector][bucket(ticks)].fetch_add(1, Ordering::Relaxed);
+        MAX_TICKS[processor][vector].fetch_max(ticks, Ordering::Relaxed);
+    }
+}
+
+/// Start timing a handler for `vector` on the current processor
+#[inline]
+pub(super) fn start(vector: u8) -> Timed {
+    Timed {
+        vector,
+        start: time::INSTANCE.now(),
+    }
+}
+
+/// Histogram bucket for a latency of `ticks`
+fn bucket(ticks: u64) -> usize {
+    let log = ticks.checked_ilog2().unwrap_or(0);
+    (log.saturating_sub(MIN_SHIFT) as usize).min(LATENCY_BUCKETS - 1)
+}
+
+/// Handling latencies of one vector on one processor, in TSC ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencyHistogram {
+    /// Number of handlers that finished in each bucket. See
+    /// [`upper_bound`](Self::upper_bound) for the bucket ranges.
+    pub buckets: [u32; LATENCY_BUCKETS],
+    /// Longest handler seen
+    pub max_ticks: u64,
+}
+
+impl LatencyHistogram {
+    /// Exclusive upper bound of bucket `index`, in ticks, or `None` for the
+    /// last bucket, which is unbounded
+    pub const fn upper_bound(index: usize) -> Option<u64> {
+        if index + 1 < LATENCY_BUCKETS {
+            Some(1 << (MIN_SHIFT as usize + index + 1))
+        } else {
+            None
+        }
+    }
+
+    /// Number of handlers timed
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|&n| u64::from(n)).sum()
+    }
+
+    /// Upper bound on the latency of the given `percentile` of handlers, in
+    /// ticks. This is the end of the bucket it falls in, capped at the longest
+    /// latency seen. Returns 0 if nothing was timed.
+    pub fn percentile(&self, percentile: u8) -> u64 {
+        let target = (self.count() * u64::from(percentile.min(100))).div_ceil(100);
+        let mut seen = 0;
+        for (index, &n) in self.buckets.iter().enumerate() {
+            seen += u64::from(n);
+            if seen >= target && seen > 0 {
+                return Self::upper_bound(index).map_or(self.max_ticks, |b| b.min(self.max_ticks));
+            }
+        }
+        0
+    }
+
+    /// Add `other`'s handlers to this histogram, like to combine processors
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (bucket, n) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket = bucket.saturating_add(n);
+        }
+        self.max_ticks = self.max_ticks.max(other.max_ticks);
+    }
+}
+
+/// Handling latencies of `vector` on `processor` since boot
+pub fn latency_histogram(processor: ProcessorId, vector: u8) -> LatencyHistogram {
+    let processor = usize::from(processor);
+    let vector = usize::from(vector);
+    let Some(histograms) = HISTOGRAMS.get(processor) else {
+        return LatencyHistogram::default();
+    };
+    LatencyHistogram {
+        buckets: core::array::from_fn(|i| histograms[vector][i].load(Ordering::Relaxed)),
+        max_ticks: MAX_TICKS[processor][vector].load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(511), 0);
+        assert_eq!(bucket(512), 1);
+        assert_eq!(bucket(1023), 1);
+        assert_eq!(bucket(u64::MAX), LATENCY_BUCKETS - 1);
+        assert_eq!(LatencyHistogram::upper_bound(0), Some(512));
+        assert_eq!(LatencyHistogram::upper_bound(1), Some(1024));
+        assert_eq!(LatencyHistogram::upper_bound(LATENCY_BUCKETS - 1), None);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50), 0);
+
+        histogram.buckets[0] = 90;
+        histogram.buckets[3] = 9;
+        histogram.buckets[LATENCY_BUCKETS - 1] = 1;
+        histogram.max_ticks = 1 << 30;
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(50), 512);
+        assert_eq!(histogram.percentile(99), 4096);
+        assert_eq!(histogram.percentile(100), 1 << 30);
+
+        let mut merged = LatencyHistogram::default();
+        merged.merge(&histogram);
+        merged.merge(&histogram);
+        assert_eq!(merged.count(), 200);
+        assert_eq!(merged.max_ticks, 1 << 30);
+    }
+}
@@ -0,0 +1,344 @@
+//! Machine-check architecture (MCA) error reporting.
+//!
+//! The processor reports hardware errors, like memory ECC failures or bus
+//! errors, through banks of MSRs. Uncorrected errors raise a machine check
+//! exception (#MC), which is fatal. Without CR4.MCE set, that #MC shuts the
+//! processor down instead, which looks like a silent reset. Corrected errors
+//! don't need handling, but a growing number of them is an early warning of
+//! failing hardware. If the processor supports it, they're delivered as
+//! corrected machine check interrupts (CMCIs) through the local APIC.
+//!
+//! Bank status is logged when MCA is enabled, since errors that caused the
+//! last reset are still latched in the banks, and whenever a machine check or
+//! CMCI arrives.
+//!
+//! See Intel SDM volume 3B, chapter 15
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use platypos_common::sync::Global;
+use raw_cpuid::CpuId;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+use super::apic;
+
+/// Global machine check capabilities
+const IA32_MCG_CAP_MSR: u32 = 0x179;
+/// Global machine check status
+const IA32_MCG_STATUS_MSR: u32 = 0x17a;
+/// Global machine check control, if [`MCG_CTL_P`] is set
+const IA32_MCG_CTL_MSR: u32 = 0x17b;
+/// Bank 0's CMCI control register. Each bank has one, at consecutive addresses.
+const IA32_MC0_CTL2_MSR: u32 = 0x280;
+/// Bank 0's control register. Each bank has 4 registers (CTL, STATUS, ADDR,
+/// and MISC), at consecutive addresses.
+const IA32_MC0_CTL_MSR: u32 = 0x400;
+
+/// IA32_MCG_CAP: IA32_MCG_CTL is present
+const MCG_CTL_P: u64 = 1 << 8;
+/// IA32_MCG_CAP: CMCI is supported
+const MCG_CMCI_P: u64 = 1 << 10;
+/// IA32_MCG_STATUS: the interrupted program can be restarted
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+
+/// IA32_MCi_CTL2: signal CMCIs for this bank
+const CTL2_CMCI_EN: u64 = 1 << 30;
+/// IA32_MCi_CTL2: corrected error count at which a CMCI is signalled
+const CTL2_THRESHOLD: u64 = 0x7fff;
+
+/// Number of banks enabled, or 0 if MCA isn't enabled
+static BANKS: AtomicU8 = AtomicU8::new(0);
+
+/// Kernel hook invoked for every machine check error found
+static HOOK: Global<fn(&MachineCheckError)> = Global::new();
+
+/// Status of a machine check bank (IA32_MCi_STATUS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankStatus(u64);
+
+impl BankStatus {
+    pub const fn new(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    const fn bit(&self, n: u32) -> bool {
+        self.0 & (1 << n) != 0
+    }
+
+    /// The bank holds a valid error
+    pub const fn valid(&self) -> bool {
+        self.bit(63)
+    }
+
+    /// Another error occurred while this one was still latched
+    pub const fn overflow(&self) -> bool {
+        self.bit(62)
+    }
+
+    /// The processor didn't correct the error
+    pub const fn uncorrected(&self) -> bool {
+        self.bit(61)
+    }
+
+    /// The error was enabled in the bank's control register
+    pub const fn enabled(&self) -> bool {
+        self.bit(60)
+    }
+
+    /// IA32_MCi_MISC holds more information about the error
+    pub const fn misc_valid(&self) -> bool {
+        self.bit(59)
+    }
+
+    /// IA32_MCi_ADDR holds the address the error happened at
+    pub const fn address_valid(&self) -> bool {
+        self.bit(58)
+    }
+
+    /// The processor state may have been corrupted, so execution can't
+    /// continue reliably
+    pub const fn context_corrupt(&self) -> bool {
+        self.bit(57)
+    }
+
+    /// Number of corrected errors, if the bank counts them
+    pub const fn corrected_count(&self) -> u16 {
+        ((self.0 >> 38) & 0x7fff) as u16
+    }
+
+    /// Architecturally-defined error code
+    pub const fn mca_error_code(&self) -> u16 {
+        self.0 as u16
+    }
+
+    /// Model-specific error code
+    pub const fn model_error_code(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+}
+
+/// An error reported by a machine check bank
+#[derive(Debug, Clone, Copy)]
+pub struct MachineCheckError {
+    pub bank: u8,
+    pub status: BankStatus,
+    /// Address the error happened at, if the bank reported one
+    pub address: Option<u64>,
+    /// Model-specific information about the error, if the bank reported any
+    pub misc: Option<u64>,
+}
+
+impl fmt::Display for MachineCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = self.status;
+        write!(
+            f,
+            "bank {}: {} error {:#06x} (model-specific {:#06x})",
+            self.bank,
+            if status.uncorrected() {
+                "uncorrected"
+            } else {
+                "corrected"
+            },
+            status.mca_error_code(),
+            status.model_error_code()
+        )?;
+        if let Some(address) = self.address {
+            write!(f, " at {:#x}", address)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {:#x}", misc)?;
+        }
+        if !status.uncorrected() && status.corrected_count() > 0 {
+            write!(f, ", {} corrected", status.corrected_count())?;
+        }
+        if status.overflow() {
+            f.write_str(", overflowed")?;
+        }
+        if status.context_corrupt() {
+            f.write_str(", processor context corrupt")?;
+        }
+        Ok(())
+    }
+}
+
+/// Register a hook to run for every machine check error found, in addition
+/// to logging it. The hook may run in machine check context, so it must not
+/// block or allocate.
+///
+/// # Panics
+/// If a hook was already registered
+pub fn set_machine_check_hook(hook: fn(&MachineCheckError)) {
+    HOOK.init(hook);
+}
+
+/// Enable machine check reporting on the current processor, if it supports
+/// MCA. If `cmci_vector` is set and the processor supports CMCI, corrected
+/// errors are signalled on that vector.
+///
+/// # Safety
+/// The #MC handler, and a handler for `cmci_vector`, must be installed.
+pub(super) unsafe fn init_local(cmci_vector: Option<u8>) {
+    let cpuid = CpuId::new();
+    let Some(features) = cpuid.get_feature_info() else {
+        return;
+    };
+    if !features.has_mce() || !features.has_mca() {
+        tracing::debug!("Machine check architecture isn't supported");
+        return;
+    }
+
+    let capabilities = Msr::new(IA32_MCG_CAP_MSR).read();
+    let banks = capabilities as u8;
+
+    // Report anything left over from before boot, then start with clean banks
+    for bank in 0..banks {
+        if let Some(error) = read_bank(bank) {
+            tracing::warn!("Machine check from before boot: {}", error);
+            run_hook(&error);
+        }
+        clear_bank(bank);
+    }
+
+    if capabilities & MCG_CTL_P != 0 {
+        Msr::new(IA32_MCG_CTL_MSR).write(u64::MAX);
+    }
+    // On family 6 processors before Nehalem, bank 0 is owned by the firmware
+    let skip_bank0 = features.family_id() == 6 && features.model_id() < 0x1a;
+    for bank in u32::from(skip_bank0)..u32::from(banks) {
+        Msr::new(IA32_MC0_CTL_MSR + 4 * bank).write(u64::MAX);
+    }
+
+    let cmci = match cmci_vector {
+        Some(vector) if capabilities & MCG_CMCI_P != 0 => {
+            for bank in 0..u32::from(banks) {
+                // Banks that don't support CMCI ignore the enable bit
+                let mut ctl2 = Msr::new(IA32_MC0_CTL2_MSR + bank);
+                let value = ctl2.read();
+                ctl2.write((value & !CTL2_THRESHOLD) | CTL2_CMCI_EN | 1);
+            }
+            apic::configure_cmci(vector);
+            true
+        }
+        _ => false,
+    };
+
+    BANKS.fetch_max(banks, Ordering::Relaxed);
+    Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+    tracing::debug!(
+        count = u64::from(banks),
+        "Enabled machine check reporting{}",
+        if cmci { ", with CMCI" } else { "" }
+    );
+}
+
+/// Read the error latched in `bank`, if there is one
+fn read_bank(bank: u8) -> Option<MachineCheckError> {
+    let base = IA32_MC0_CTL_MSR + 4 * u32::from(bank);
+    // SAFETY: the bank's registers exist, since it's below the bank count in IA32_MCG_CAP
+    unsafe {
+        let status = BankStatus(Msr::new(base + 1).read());
+        if !status.valid() {
+            return None;
+        }
+        Some(MachineCheckError {
+            bank,
+            status,
+            address: status.address_valid().then(|| Msr::new(base + 2).read()),
+            misc: status.misc_valid().then(|| Msr::new(base + 3).read()),
+        })
+    }
+}
+
+/// Clear the error latched in `bank`, so it can report the next one
+fn clear_bank(bank: u8) {
+    // SAFETY: writing 0 to a status register only discards the logged error
+    unsafe { Msr::new(IA32_MC0_CTL_MSR + 4 * u32::from(bank) + 1).write(0) };
+}
+
+fn run_hook(error: &MachineCheckError) {
+    if let Some(hook) = HOOK.try_get() {
+        hook(error);
+    }
+}
+
+/// Log and clear corrected errors, after a CMCI
+pub(super) fn poll_corrected() {
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        let Some(error) = read_bank(bank) else {
+            continue;
+        };
+        // Uncorrected errors are left for the #MC handler
+        if error.status.uncorrected() {
+            continue;
+        }
+        tracing::warn!(
+            bank = error.bank,
+            status = error.status.bits(),
+            "Corrected machine check: {}",
+            error
+        );
+        run_hook(&error);
+        clear_bank(bank);
+    }
+}
+
+/// Log every latched error after a machine check exception, returning
+/// whether execution can continue
+pub(super) fn report_exception() -> bool {
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        if let Some(error) = read_bank(bank) {
+            tracing::error!(
+                bank = error.bank,
+                status = error.status.bits(),
+                "Machine check: {}",
+                error
+            );
+            run_hook(&error);
+        }
+    }
+    // SAFETY: reading IA32_MCG_STATUS has no side effects, and it exists since the processor
+    // raised a machine check
+    let status = unsafe { Msr::new(IA32_MCG_STATUS_MSR).read() };
+    status & MCG_STATUS_RIPV != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_status() {
+        // Valid, enabled, address valid, 2 corrected errors, memory read error on channel 1
+        let status = BankStatus::new((1 << 63) | (1 << 60) | (1 << 58) | (2 << 38) | 0x0091);
+        assert!(status.valid());
+        assert!(!status.uncorrected());
+        assert!(status.address_valid());
+        assert!(!status.misc_valid());
+        assert_eq!(status.corrected_count(), 2);
+        assert_eq!(status.mca_error_code(), 0x0091);
+    }
+
+    #[test]
+    fn test_display() {
+        let error = MachineCheckError {
+            bank: 7,
+            status: BankStatus::new((1 << 63) | (1 << 61) | (1 << 57) | (0x1234 << 16) | 0x0150),
+            address: Some(0x1234_5000),
+            misc: None,
+        };
+        assert_eq!(
+            error.to_string(),
+            "bank 7: uncorrected error 0x0150 (model-specific 0x1234) at 0x12345000, \
+             processor context corrupt"
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Interrupt nesting, per processor.
+//!
+//! Interrupt entry points hold an [`Entered`] guard while they run, which
+//! counts how deeply handlers are nested on the current processor. NMIs are
+//! counted separately, since they can arrive in the middle of any other
+//! handler. Exceptions aren't counted at all: they run in the context of
+//! whatever raised them.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use platypos_hal::interrupts::ExecutionContext;
+use platypos_hal::topology::Topology as _;
+
+use crate::topology::{Topology, INSTANCE};
+
+const MAX_PROCESSORS: usize = Topology::MAX_PROCESSORS as usize;
+
+/// Number of maskable interrupt handlers running on each processor
+static IRQ_DEPTH: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(0) }; MAX_PROCESSORS];
+
+/// Number of NMI handlers running on each processor
+static NMI_DEPTH: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(0) }; MAX_PROCESSORS];
+
+/// Marks the current processor as running an interrupt handler until dropped
+#[must_use = "the handler is only counted while the guard is held"]
+pub(super) struct Entered {
+    depth: &'static AtomicU32,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn enter_with(depths: &'static [AtomicU32; MAX_PROCESSORS]) -> Entered {
+    let depth = &depths[usize::from(INSTANCE.current_processor())];
+    depth.fetch_add(1, Ordering::Relaxed);
+    Entered { depth }
+}
+
+/// Count a maskable interrupt handler on the current processor
+pub(super) fn enter() -> Entered {
+    enter_with(&IRQ_DEPTH)
+}
+
+/// Count an NMI handler on the current processor
+pub(super) fn enter_nmi() -> Entered {
+    enter_with(&NMI_DEPTH)
+}
+
+/// What the current processor is running
+pub(super) fn current() -> ExecutionContext {
+    let processor = usize::from(INSTANCE.current_processor());
+    if NMI_DEPTH[processor].load(Ordering::Relaxed) > 0 {
+        ExecutionContext::Nmi
+    } else if IRQ_DEPTH[processor].load(Ordering::Relaxed) > 0 {
+        ExecutionContext::Irq
+    } else {
+        ExecutionContext::Task
+    }
+}
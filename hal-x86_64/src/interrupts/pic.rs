@@ -0,0 +1,301 @@
+//! Legacy 8259 programmable interrupt controllers (PICs).
+//!
+//! PC-compatible systems have two cascaded PICs, which deliver ISA IRQs 0-15.
+//! After reset, they're mapped to vectors 0x08-0x0f and 0x70-0x77, so IRQs
+//! from PIC1 collide with CPU exceptions. [`init`] always remaps both PICs to
+//! [`PIC1_OFFSET`] and [`PIC2_OFFSET`] and masks every line, so that even
+//! spurious IRQs land on vectors with handlers. What happens next depends on
+//! the [`Routing`]:
+//! * [`Routing::Pic`] delivers legacy IRQs through the PICs, via the bootstrap
+//!   processor's LINT0. Lines are unmasked as handlers are registered. This is
+//!   for systems without an I/O APIC.
+//! * [`Routing::Disabled`] leaves every line masked, for when an I/O APIC
+//!   delivers legacy IRQs instead.
+//!
+//! Ports are accessed through the [`Ports`] trait, so that the initialization
+//! sequence can be unit tested on the host.
+//!
+//! See the OSDev wiki on [the PIC](https://wiki.osdev.org/PIC) and the Intel
+//! 8259A datasheet.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::structures::port::{PortRead, PortWrite};
+
+/// First vector of PIC1's IRQs (0-7)
+const PIC1_OFFSET: u8 = 32;
+/// First vector of PIC2's IRQs (8-15)
+const PIC2_OFFSET: u8 = 40;
+
+// PIC data and command port numbers
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+/// IRQ line on PIC1 that PIC2 is cascaded through
+const CASCADE_IRQ: u8 = 2;
+
+/// ICW1: start initialization, expecting ICW4
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086/88 mode
+const ICW4_8086: u8 = 0x01;
+/// OCW3: read the in-service register on the next command port read
+const OCW3_READ_ISR: u8 = 0x0b;
+/// OCW2: non-specific end of interrupt
+const EOI: u8 = 0x20;
+
+/// Whether legacy IRQs are delivered through the PICs
+static ROUTED: AtomicBool = AtomicBool::new(false);
+
+/// How legacy ISA IRQs are delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /// Through the PICs, for systems without an I/O APIC
+    Pic,
+    /// Not through the PICs, which stay fully masked
+    Disabled,
+}
+
+/// Access to the PICs' I/O ports
+pub(super) trait Ports {
+    fn read(&self, port: u16) -> u8;
+
+    /// # Safety
+    /// Writing to the PICs can reconfigure or unmask interrupts.
+    unsafe fn write(&self, port: u16, value: u8);
+
+    /// Give the PICs time to handle the last write. Some older PICs need a
+    /// short delay between initialization words.
+    fn delay(&self) {}
+}
+
+/// The real PIC ports
+#[derive(Debug, Clone, Copy)]
+pub(super) struct IoPorts;
+
+impl Ports for IoPorts {
+    fn read(&self, port: u16) -> u8 {
+        // SAFETY: reading the PIC data and command ports has no side effects
+        unsafe { u8::read_from_port(port) }
+    }
+
+    unsafe fn write(&self, port: u16, value: u8) {
+        u8::write_to_port(port, value)
+    }
+
+    fn delay(&self) {
+        // Writing to the POST diagnostic port takes about a microsecond. See
+        // https://wiki.osdev.org/Inline_Assembly/Examples#IO_WAIT
+        // SAFETY: nothing listens to port 0x80 after boot
+        unsafe { u8::write_to_port(0x80, 0) }
+    }
+}
+
+/// Remap both PICs and mask every line, then route legacy IRQs according to
+/// `routing`.
+pub(super) fn init(routing: Routing) {
+    // SAFETY: this only moves PIC interrupts to vectors with handlers and masks them
+    unsafe { remap_and_mask(&IoPorts) };
+    ROUTED.store(routing == Routing::Pic, Ordering::Release);
+    tracing::debug!(
+        "Remapped and masked the legacy PICs, routing is {:?}",
+        routing
+    );
+}
+
+/// Run the PIC initialization sequence, remapping both PICs in cascade mode
+/// and masking every line.
+///
+/// # Safety
+/// The PIC vectors must have handlers installed before any lines are unmasked.
+unsafe fn remap_and_mask(ports: &impl Ports) {
+    // Each PIC expects an init command, followed by three initialization words on the data
+    // port: its vector offset, how it's wired to the other PIC, and its mode
+    for (command, data, offset, wiring) in [
+        // Tell PIC1 that PIC2 is on its cascade line
+        (PIC1_COMMAND, PIC1_DATA, PIC1_OFFSET, 1 << CASCADE_IRQ),
+        // Tell PIC2 its cascade identity
+        (PIC2_COMMAND, PIC2_DATA, PIC2_OFFSET, CASCADE_IRQ),
+    ] {
+        ports.write(command, ICW1_INIT);
+        ports.delay();
+        ports.write(data, offset);
+        ports.delay();
+        ports.write(data, wiring);
+        ports.delay();
+        ports.write(data, ICW4_8086);
+        ports.delay();
+    }
+
+    ports.write(PIC1_DATA, 0xff);
+    ports.write(PIC2_DATA, 0xff);
+}
+
+/// The vector that legacy ISA IRQ `irq` is remapped to
+pub(super) const fn vector(irq: u8) -> u8 {
+    if irq < 8 {
+        PIC1_OFFSET + irq
+    } else {
+        PIC2_OFFSET + irq - 8
+    }
+}
+
+/// The data port and line for `irq`
+fn line(irq: u8) -> (u16, u8) {
+    assert!(irq < 16, "invalid ISA IRQ {irq}");
+    if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    }
+}
+
+/// Unmask legacy ISA IRQ `irq`, along with the cascade line if it's on PIC2.
+///
+/// # Panics
+/// If legacy IRQs aren't routed through the PICs
+pub(super) fn unmask(irq: u8) {
+    assert!(
+        ROUTED.load(Ordering::Acquire),
+        "legacy IRQs aren't routed through the PIC"
+    );
+    // SAFETY: clearing a mask bit only allows that line's interrupts, which have a handler
+    // installed for their remapped vector
+    unsafe { unmask_with(&IoPorts, irq) }
+}
+
+unsafe fn unmask_with(ports: &impl Ports, irq: u8) {
+    let (port, line) = line(irq);
+    ports.write(port, ports.read(port) & !(1 << line));
+    if irq >= 8 {
+        ports.write(PIC1_DATA, ports.read(PIC1_DATA) & !(1 << CASCADE_IRQ));
+    }
+}
+
+/// Mask legacy ISA IRQ `irq`
+pub(super) fn mask(irq: u8) {
+    let (port, line) = line(irq);
+    // SAFETY: setting a mask bit only blocks that line's interrupts
+    unsafe { IoPorts.write(port, IoPorts.read(port) | (1 << line)) };
+}
+
+/// Check whether `irq` is a spurious IRQ 7 or 15, which the PIC raises when an
+/// interrupt goes away before it's acknowledged. Spurious IRQs aren't in
+/// service, so they must not be handled or get an EOI, except that PIC1 still
+/// expects one for the cascade line if PIC2 raised it.
+pub(super) fn is_spurious(irq: u8) -> bool {
+    is_spurious_with(&IoPorts, irq)
+}
+
+fn is_spurious_with(ports: &impl Ports, irq: u8) -> bool {
+    let (command, line) = match irq {
+        7 => (PIC1_COMMAND, 7),
+        15 => (PIC2_COMMAND, 7),
+        _ => return false,
+    };
+
+    // SAFETY: OCW3 only selects which register the next command port read returns
+    let in_service = unsafe {
+        ports.write(command, OCW3_READ_ISR);
+        ports.read(command)
+    };
+    if in_service & (1 << line) != 0 {
+        return false;
+    }
+
+    if irq == 15 {
+        // SAFETY: PIC1 did deliver the cascade interrupt, so it's waiting for an EOI
+        unsafe { ports.write(PIC1_COMMAND, EOI) };
+    }
+    true
+}
+
+/// Signal the end of handling legacy ISA IRQ `irq`
+pub(super) fn end_of_interrupt(irq: u8) {
+    // SAFETY: sending EOI has no effect besides acknowledging the current interrupt
+    unsafe {
+        if irq >= 8 {
+            IoPorts.write(PIC2_COMMAND, EOI);
+        }
+        IoPorts.write(PIC1_COMMAND, EOI);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Ports that record every write. Ports that were never written read as 0.
+    #[derive(Default)]
+    struct MockPorts {
+        values: RefCell<BTreeMap<u16, u8>>,
+        writes: RefCell<Vec<(u16, u8)>>,
+    }
+
+    impl Ports for MockPorts {
+        fn read(&self, port: u16) -> u8 {
+            self.values.borrow().get(&port).copied().unwrap_or(0)
+        }
+
+        unsafe fn write(&self, port: u16, value: u8) {
+            self.values.borrow_mut().insert(port, value);
+            self.writes.borrow_mut().push((port, value));
+        }
+    }
+
+    #[test]
+    fn test_remap_and_mask() {
+        let ports = MockPorts::default();
+        unsafe { remap_and_mask(&ports) };
+        assert_eq!(
+            *ports.writes.borrow(),
+            [
+                (PIC1_COMMAND, 0x11),
+                (PIC1_DATA, 32),
+                (PIC1_DATA, 0b100),
+                (PIC1_DATA, 0x01),
+                (PIC2_COMMAND, 0x11),
+                (PIC2_DATA, 40),
+                (PIC2_DATA, 2),
+                (PIC2_DATA, 0x01),
+                (PIC1_DATA, 0xff),
+                (PIC2_DATA, 0xff),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vectors_avoid_exceptions() {
+        for irq in 0..16 {
+            assert!(vector(irq) >= 32, "IRQ {irq} collides with an exception");
+        }
+        assert_eq!(vector(0), 32);
+        assert_eq!(vector(15), 47);
+    }
+
+    #[test]
+    fn test_unmask_pic2_unmasks_cascade() {
+        let ports = MockPorts::default();
+        unsafe {
+            remap_and_mask(&ports);
+            unmask_with(&ports, 12);
+        }
+        assert_eq!(ports.read(PIC2_DATA), 0xff & !(1 << 4));
+        assert_eq!(ports.read(PIC1_DATA), 0xff & !(1 << CASCADE_IRQ));
+    }
+
+    #[test]
+    fn test_spurious_irq15_acknowledges_cascade() {
+        let ports = MockPorts::default();
+        // The mock reads back the OCW3 command (0x0b), which has bit 7 clear, so nothing is in
+        // service
+        assert!(is_spurious_with(&ports, 15));
+        assert_eq!(ports.writes.borrow().last(), Some(&(PIC1_COMMAND, EOI)));
+        assert!(!is_spurious_with(&ports, 1));
+    }
+}
@@ -0,0 +1,99 @@
+//! Interrupt priority levels, using the local APIC's task priority register.
+//!
+//! In 64-bit mode, CR8 is an alias for bits 7:4 of the TPR, which is the
+//! priority class (upper 4 bits of the vector) at or below which the APIC
+//! won't deliver interrupts. Writing CR8 is much cheaper than going through the
+//! APIC's MSRs.
+//!
+//! Legacy IRQs from the PIC are delivered as ExtINT interrupts, which bypass
+//! the TPR. Instead, their entry points check the current level and, if it's
+//! at least [`Level::Device`], mask the IRQ at the PIC and defer it. Deferred
+//! IRQs run when the level drops back below [`Level::Device`].
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use platypos_hal::interrupts::Level;
+
+use super::{pic, CALL_FUNCTION_VECTOR, TIMER_VECTOR};
+
+/// Priority class of the scheduler tick
+const CLOCK_CLASS: u64 = (TIMER_VECTOR >> 4) as u64;
+/// Priority class of inter-processor interrupts
+const IPI_CLASS: u64 = (CALL_FUNCTION_VECTOR >> 4) as u64;
+
+// Each level has to be able to mask the one below it without masking the next
+const _: () = assert!(CLOCK_CLASS > 1 && IPI_CLASS > CLOCK_CLASS);
+
+/// Legacy IRQs that arrived while they were masked by the priority level. Only
+/// the bootstrap processor receives PIC interrupts.
+static DEFERRED_LEGACY_IRQS: AtomicU16 = AtomicU16::new(0);
+
+/// The TPR priority class for `level`
+const fn class(level: Level) -> u64 {
+    match level {
+        Level::Passive => 0,
+        Level::Device => CLOCK_CLASS - 1,
+        Level::Clock => CLOCK_CLASS,
+        Level::High => IPI_CLASS,
+    }
+}
+
+/// The current processor's priority level
+pub(super) fn current() -> Level {
+    let cr8: u64;
+    // SAFETY: reading CR8 has no side effects
+    unsafe { asm!("mov {}, cr8", out(reg) cr8, options(nomem, nostack, preserves_flags)) };
+
+    // Classes between the defined levels mask some device interrupts, so round them down
+    match cr8 {
+        0 => Level::Passive,
+        c if c < CLOCK_CLASS => Level::Device,
+        c if c < IPI_CLASS => Level::Clock,
+        _ => Level::High,
+    }
+}
+
+/// Set the current processor's priority level, running any legacy IRQs that
+/// were deferred if this unmasks device interrupts
+pub(super) fn set(level: Level) {
+    // SAFETY: the TPR only affects which interrupts are delivered. This isn't `nomem`, so that
+    // memory accesses aren't moved out of the critical section it ends.
+    unsafe { asm!("mov cr8, {}", in(reg) class(level), options(nostack, preserves_flags)) };
+
+    if level < Level::Device {
+        run_deferred_legacy_irqs();
+    }
+}
+
+/// Handle legacy IRQ `irq` arriving while device interrupts are masked, by
+/// masking it at the PIC so that it can't fire again and deferring it.
+/// Returns `false` if device interrupts aren't masked, and the IRQ should be
+/// handled now.
+pub(super) fn defer_legacy_irq(irq: u8) -> bool {
+    if current() < Level::Device {
+        return false;
+    }
+
+    pic::mask(irq);
+    DEFERRED_LEGACY_IRQS.fetch_or(1 << irq, Ordering::AcqRel);
+    true
+}
+
+fn run_deferred_legacy_irqs() {
+    // Cheap check, since this runs every time a critical section ends
+    if DEFERRED_LEGACY_IRQS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let deferred = DEFERRED_LEGACY_IRQS.swap(0, Ordering::AcqRel);
+        for irq in (0..16).filter(|irq| deferred & (1 << irq) != 0) {
+            // The PIC already got its EOI when the IRQ was deferred
+            if let Some(handler) = super::LEGACY_IRQ_HANDLERS[usize::from(irq)].try_get() {
+                handler();
+            }
+            pic::unmask(irq);
+        }
+    });
+}
@@ -0,0 +1,109 @@
+//! Sampling what the APIC timer interrupted, for profiling.
+//!
+//! Before doing anything else, the timer handler tells the kernel's
+//! [sample hook](set_sample_hook) where the interrupted code was. The hook can
+//! also ask for a shallow [backtrace](Interrupted::backtrace) of it, found by
+//! walking frame pointers. That only works if the kernel is built with frame
+//! pointers (`-Cforce-frame-pointers`): otherwise, `rbp` can hold anything,
+//! and following it can fault.
+
+use core::arch::asm;
+
+use platypos_common::sync::Global;
+use platypos_hal::interrupts::ExecutionContext;
+
+/// Kernel hook invoked with each timer interrupt's interrupted state
+static HOOK: Global<fn(&Interrupted)> = Global::new();
+
+/// Most frames a backtrace skips to reach the interrupted code: its own, the
+/// hook's, the handler's, and anything in between that wasn't inlined
+const MAX_HANDLER_FRAMES: usize = 8;
+
+/// Largest stack frame a backtrace steps over. A saved frame pointer further
+/// up the stack than this is assumed to be garbage.
+const MAX_FRAME_SIZE: u64 = 16 * 1024;
+
+/// Where a timer interrupt arrived
+#[derive(Debug, Clone, Copy)]
+pub struct Interrupted {
+    /// Address of the next instruction the interrupted code would have run
+    pub instruction_pointer: u64,
+    /// Whether the interrupted code was a task or another interrupt handler
+    pub context: ExecutionContext,
+}
+
+/// Register the function to run with the interrupted state on every APIC
+/// timer interrupt, before the [timer handler](super::set_timer_handler). The
+/// hook runs in interrupt context, with interrupts disabled.
+///
+/// # Panics
+/// If a hook was already registered
+pub fn set_sample_hook(hook: fn(&Interrupted)) {
+    HOOK.init(hook);
+}
+
+pub(super) fn run_hook(interrupted: &Interrupted) {
+    if let Some(hook) = HOOK.try_get() {
+        hook(interrupted);
+    }
+}
+
+impl Interrupted {
+    /// Fill `frames` with the return addresses of the interrupted code,
+    /// innermost first, returning how many were found. This must be called
+    /// from the sample hook, and only with frame pointers.
+    ///
+    /// The walk stops early at a frame pointer that's misaligned, doesn't
+    /// move up the stack, or moves up more than a plausible frame.
+    #[inline(never)]
+    pub fn backtrace(&self, frames: &mut [u64]) -> usize {
+        let mut frame_pointer: u64;
+        // SAFETY: only reads rbp
+        unsafe { asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack)) };
+
+        // Skip frames up to the handler's. The CPU pushed the interrupted instruction pointer
+        // right before the handler pushed its frame pointer, so it's the handler's return address.
+        let mut skipped = 0;
+        loop {
+            let (next, return_address) = read_record(frame_pointer);
+            if skipped == MAX_HANDLER_FRAMES || !plausible(frame_pointer, next) {
+                return 0;
+            }
+            frame_pointer = next;
+            skipped += 1;
+            if return_address == self.instruction_pointer {
+                break;
+            }
+        }
+
+        let mut count = 0;
+        for frame in frames.iter_mut() {
+            let (next, return_address) = read_record(frame_pointer);
+            if return_address == 0 {
+                break;
+            }
+            *frame = return_address;
+            count += 1;
+            if !plausible(frame_pointer, next) {
+                break;
+            }
+            frame_pointer = next;
+        }
+        count
+    }
+}
+
+/// Read the saved frame pointer and return address in the frame record at
+/// `frame_pointer`
+fn read_record(frame_pointer: u64) -> (u64, u64) {
+    let record = frame_pointer as *const u64;
+    // SAFETY: `frame_pointer` is the backtrace's own frame, or was found to be plausible, so with
+    // frame pointers, it points to a frame record further up the same stack
+    unsafe { (record.read(), record.add(1).read()) }
+}
+
+/// Whether `next` could be the frame pointer of the caller of the frame at
+/// `frame_pointer`
+fn plausible(frame_pointer: u64, next: u64) -> bool {
+    next % 8 == 0 && next > frame_pointer && next - frame_pointer <= MAX_FRAME_SIZE
+}
@@ -0,0 +1,126 @@
+//! Per-vector interrupt counts.
+//!
+//! Every entry point counts the vector it was called for before doing anything
+//! else, including interrupts that turn out to be spurious, since misrouted
+//! interrupts are what these counts are best at finding. Each processor has
+//! its own row of counters, so counting is an uncontended relaxed increment.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use platypos_hal::topology::{ProcessorId, Topology as _};
+
+use crate::topology::{Topology, INSTANCE};
+
+use super::ipi::Ipi;
+use super::pic;
+
+/// Number of interrupt vectors, including exceptions
+pub const VECTORS: usize = 256;
+
+/// Vector of non-maskable interrupts
+pub(super) const NMI_VECTOR: u8 = 2;
+
+/// Vector of machine check exceptions, which aren't a [`Fault`](super::Fault)
+pub(super) const MACHINE_CHECK_VECTOR: u8 = 18;
+
+const MAX_PROCESSORS: usize = Topology::MAX_PROCESSORS as usize;
+
+static COUNTS: [[AtomicU64; VECTORS]; MAX_PROCESSORS] =
+    [const { [const { AtomicU64::new(0) }; VECTORS] }; MAX_PROCESSORS];
+
+/// Count an interrupt on `vector` on the current processor
+#[inline]
+pub(super) fn record(vector: u8) {
+    let processor = usize::from(INSTANCE.current_processor());
+    COUNTS[processor][usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of times `vector` has been delivered to `processor` since boot
+pub fn interrupt_count(processor: ProcessorId, vector: u8) -> u64 {
+    COUNTS
+        .get(usize::from(processor))
+        .map_or(0, |row| row[usize::from(vector)].load(Ordering::Relaxed))
+}
+
+/// What the kernel uses an interrupt vector for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// A CPU exception, by vector
+    Exception(u8),
+    /// A legacy ISA IRQ, delivered through the PIC
+    LegacyIrq(u8),
+    Ipi(Ipi),
+    /// The local APIC timer
+    Timer,
+    /// Corrected machine check interrupt
+    Cmci,
+    /// The local APIC's spurious interrupt vector
+    Spurious,
+}
+
+impl Source {
+    /// The source of interrupts on `vector`, if the kernel handles it
+    pub fn of(vector: u8) -> Option<Source> {
+        let ipi = [
+            Ipi::CallFunction,
+            Ipi::Reschedule,
+            Ipi::TlbShootdown,
+            Ipi::PanicStop,
+        ]
+        .into_iter()
+        .find(|ipi| ipi.vector() == vector);
+        if let Some(ipi) = ipi {
+            return Some(Source::Ipi(ipi));
+        }
+        if let Some(irq) = (0..16).find(|&irq| pic::vector(irq) == vector) {
+            return Some(Source::LegacyIrq(irq));
+        }
+        match vector {
+            0 | NMI_VECTOR | 6 | 14 | 17 | MACHINE_CHECK_VECTOR => Some(Source::Exception(vector)),
+            super::TIMER_VECTOR => Some(Source::Timer),
+            super::CMCI_VECTOR => Some(Source::Cmci),
+            super::SPURIOUS_INTERRUPT_VECTOR => Some(Source::Spurious),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Exception(0) => f.write_str("#DE divide error"),
+            Source::Exception(2) => f.write_str("NMI"),
+            Source::Exception(6) => f.write_str("#UD invalid opcode"),
+            Source::Exception(14) => f.write_str("#PF page fault"),
+            Source::Exception(17) => f.write_str("#AC alignment check"),
+            Source::Exception(18) => f.write_str("#MC machine check"),
+            Source::Exception(vector) => write!(f, "exception {vector}"),
+            Source::LegacyIrq(irq) => write!(f, "IRQ {irq}"),
+            Source::Ipi(ipi) => write!(f, "{ipi:?} IPI"),
+            Source::Timer => f.write_str("APIC timer"),
+            Source::Cmci => f.write_str("CMCI"),
+            Source::Spurious => f.write_str("spurious"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources() {
+        assert_eq!(Source::of(14), Some(Source::Exception(14)));
+        assert_eq!(Source::of(33), Some(Source::LegacyIrq(1)));
+        assert_eq!(Source::of(47), Some(Source::LegacyIrq(15)));
+        assert_eq!(Source::of(0xf1), Some(Source::Ipi(Ipi::Reschedule)));
+        assert_eq!(Source::of(0xef), Some(Source::Timer));
+        assert_eq!(Source::of(0xff), Some(Source::Spurious));
+        assert_eq!(Source::of(0x80), None);
+        assert_eq!(
+            Source::Ipi(Ipi::TlbShootdown).to_string(),
+            "TlbShootdown IPI"
+        );
+    }
+}
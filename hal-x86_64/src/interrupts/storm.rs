@@ -0,0 +1,180 @@
+//! Interrupt storm detection.
+//!
+//! A device that's stuck asserting its interrupt, or a driver that never
+//! acknowledges it, can keep a processor in its handler forever. Maskable
+//! device interrupts (legacy IRQs, the APIC timer, and CMCIs) are rate-checked
+//! as they're dispatched: if one vector is delivered more than the threshold
+//! (per second, measured over short windows) on a processor, its PIC line or
+//! LVT entry is masked and an error is logged with the rate that tripped it.
+//! The vector stays masked until [`clear_storm`] is called for it.
+//!
+//! IPIs and exceptions are never masked, since the kernel can't make progress
+//! without them. Rates can't be measured until the TSC is calibrated, so
+//! nothing is checked before then.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use platypos_hal::time::Clock as _;
+use platypos_hal::topology::Topology as _;
+
+use crate::time;
+use crate::topology::{Topology, INSTANCE};
+
+use super::stats::{Source, VECTORS};
+use super::{apic, pic};
+
+/// Default limit on deliveries of one vector to one processor, per second.
+/// This is far above anything a working device raises, including the
+/// scheduler tick.
+pub const DEFAULT_STORM_THRESHOLD: u32 = 50_000;
+
+/// Rates are measured over windows of 1/`WINDOWS_PER_SECOND` seconds
+const WINDOWS_PER_SECOND: u64 = 100;
+
+const MAX_PROCESSORS: usize = Topology::MAX_PROCESSORS as usize;
+
+/// Interrupts per second before a vector is masked, or 0 to never mask
+static THRESHOLD: AtomicU32 = AtomicU32::new(DEFAULT_STORM_THRESHOLD);
+
+/// Rate tracking for one vector on one processor
+struct Rate {
+    /// TSC timestamp of the start of the current window
+    window_start: AtomicU64,
+    /// Deliveries in the current window
+    count: AtomicU32,
+    /// Whether storm detection masked this vector
+    masked: AtomicBool,
+}
+
+impl Rate {
+    const fn new() -> Self {
+        Self {
+            window_start: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+            masked: AtomicBool::new(false),
+        }
+    }
+
+    /// Count a delivery at TSC timestamp `now`, in windows `window` ticks
+    /// long. If that puts the current window over `limit`, returns the count
+    /// and how long the window has been open.
+    ///
+    /// Only the owning processor touches a rate, and only with interrupts
+    /// disabled, so the separate loads and stores don't race.
+    fn record(&self, now: u64, window: u64, limit: u64) -> Option<(u32, u64)> {
+        let elapsed = now.saturating_sub(self.window_start.load(Ordering::Relaxed));
+        if elapsed >= window {
+            self.window_start.store(now, Ordering::Relaxed);
+            self.count.store(1, Ordering::Relaxed);
+            return None;
+        }
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        (u64::from(count) > limit).then_some((count, elapsed))
+    }
+}
+
+static RATES: [[Rate; VECTORS]; MAX_PROCESSORS] =
+    [const { [const { Rate::new() }; VECTORS] }; MAX_PROCESSORS];
+
+/// Mask vectors delivered more than `per_second` times a second to a
+/// processor. A threshold of 0 turns storm detection off.
+pub fn set_storm_threshold(per_second: u32) {
+    THRESHOLD.store(per_second, Ordering::Relaxed);
+}
+
+/// The current storm threshold, in interrupts per second, or 0 if storm
+/// detection is off
+pub fn storm_threshold() -> u32 {
+    THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Count a delivery of `vector` on the current processor, masking it if it's
+/// storming. Returns `true` if the vector is masked, in which case the caller
+/// should skip its handler, but still acknowledge the interrupt.
+pub(super) fn check(vector: u8) -> bool {
+    let rate = &RATES[usize::from(INSTANCE.current_processor())][usize::from(vector)];
+    if rate.masked.load(Ordering::Relaxed) {
+        // Already in flight when the source was masked
+        return true;
+    }
+
+    let threshold = u64::from(THRESHOLD.load(Ordering::Relaxed));
+    let Some(frequency) = time::INSTANCE.frequency().filter(|_| threshold > 0) else {
+        return false;
+    };
+    let window = frequency / WINDOWS_PER_SECOND;
+    let limit = threshold.div_ceil(WINDOWS_PER_SECOND);
+    let Some((count, elapsed)) = rate.record(time::INSTANCE.now(), window, limit) else {
+        return false;
+    };
+
+    let Some(source) = Source::of(vector).filter(|&source| set_masked(source, true)) else {
+        return false;
+    };
+    rate.masked.store(true, Ordering::Relaxed);
+    let micros = elapsed * 1_000_000 / frequency;
+    tracing::error!(
+        vector,
+        "Interrupt storm on vector {vector:#04x} ({source}): {count} interrupts in {micros} us, \
+         over the limit of {threshold} per second. Masking it."
+    );
+    true
+}
+
+/// Unmask `vector` on the current processor, if storm detection masked it.
+/// APIC timer and CMCI masks are per-processor, so this has to run on the
+/// processor that stormed; legacy IRQ lines are shared. Returns `false` if
+/// the vector wasn't masked.
+pub fn clear_storm(vector: u8) -> bool {
+    let rate = &RATES[usize::from(INSTANCE.current_processor())][usize::from(vector)];
+    if !rate.masked.load(Ordering::Relaxed) {
+        return false;
+    }
+    rate.count.store(0, Ordering::Relaxed);
+    rate.window_start.store(0, Ordering::Relaxed);
+    if let Some(source) = Source::of(vector) {
+        set_masked(source, false);
+    }
+    rate.masked.store(false, Ordering::Relaxed);
+    tracing::info!(
+        vector,
+        "Unmasked vector {vector:#04x} after an interrupt storm"
+    );
+    true
+}
+
+/// Vectors that storm detection has masked on the current processor
+pub fn storm_masked_vectors() -> impl Iterator<Item = u8> {
+    let row = &RATES[usize::from(INSTANCE.current_processor())];
+    (0..=u8::MAX).filter(move |&vector| row[usize::from(vector)].masked.load(Ordering::Relaxed))
+}
+
+/// Mask or unmask `source`. Returns `false` if it can't be masked.
+fn set_masked(source: Source, masked: bool) -> bool {
+    match source {
+        Source::LegacyIrq(irq) if masked => pic::mask(irq),
+        Source::LegacyIrq(irq) => pic::unmask(irq),
+        Source::Timer => apic::set_timer_masked(masked),
+        Source::Cmci => apic::set_cmci_masked(masked),
+        Source::Exception(_) | Source::Ipi(_) | Source::Spurious => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_windows() {
+        let rate = Rate::new();
+        // The first delivery opens a window
+        assert_eq!(rate.record(1000, 100, 3), None);
+        assert_eq!(rate.record(1010, 100, 3), None);
+        assert_eq!(rate.record(1020, 100, 3), None);
+        assert_eq!(rate.record(1030, 100, 3), Some((4, 30)));
+        // A new window starts the count over
+        assert_eq!(rate.record(1100, 100, 3), None);
+        assert_eq!(rate.record(1150, 100, 3), None);
+    }
+}
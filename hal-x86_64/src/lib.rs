@@ -1,13 +1,26 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
 
 use core::convert::Infallible;
 
+use platypos_hal::io::{TimeoutError, WriteTimeout};
+use platypos_hal::time::Deadline;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+pub mod context;
 pub mod interrupts;
+pub mod power;
+pub mod time;
 pub mod topology;
 
+/// Transmitter holding register empty flag in a UART's line status register
+const THR_EMPTY: u8 = 1 << 5;
+
 /// UART 16550 serial port writer
-pub struct SerialPort(uart_16550::SerialPort);
+pub struct SerialPort {
+    inner: uart_16550::SerialPort,
+    line_status: u16,
+}
 
 impl SerialPort {
     /// Create and initialize a serial port driver
@@ -18,7 +31,10 @@ impl SerialPort {
     pub unsafe fn new(port: u16) -> Self {
         let mut inner = uart_16550::SerialPort::new(port);
         inner.init();
-        Self(inner)
+        Self {
+            inner,
+            line_status: port + 5,
+        }
     }
 }
 
@@ -29,7 +45,7 @@ impl platypos_hal::Write for SerialPort {
         for byte in data {
             // DO NOT use `.send` - it encodes the values 8 and 0x7F specially, which causes
             // a whole bunch of problems using it with binary postcard data.
-            self.0.send_raw(*byte)
+            self.inner.send_raw(*byte)
         }
 
         Ok(())
@@ -40,6 +56,92 @@ impl platypos_hal::Write for SerialPort {
     }
 }
 
+impl WriteTimeout for SerialPort {
+    fn write_all_timeout(
+        &mut self,
+        data: &[u8],
+        deadline: &Deadline,
+    ) -> Result<(), TimeoutError<Infallible>> {
+        let mut line_status = PortReadOnly::<u8>::new(self.line_status);
+        for (written, byte) in data.iter().enumerate() {
+            // SAFETY: the port address was validated in `new`
+            while unsafe { line_status.read() } & THR_EMPTY == 0 {
+                if deadline.has_passed() {
+                    return Err(TimeoutError::TimedOut { written });
+                }
+                core::hint::spin_loop();
+            }
+            // The transmitter is ready, so this doesn't wait
+            self.inner.send_raw(*byte);
+        }
+        Ok(())
+    }
+}
+
+/// Minimal polled UART writer for use during early boot. Unlike [`SerialPort`],
+/// this can be created in a `const` context, doesn't need initialization
+/// (it relies on the firmware having configured the UART), and never blocks
+/// indefinitely.
+pub struct EarlySerialPort {
+    data: u16,
+    line_status: u16,
+}
+
+impl EarlySerialPort {
+    /// Maximum number of times to poll the line status register before giving
+    /// up and writing anyways
+    const SPIN_LIMIT: usize = 10_000;
+
+    /// Create an early serial port writer for the UART at `port`
+    ///
+    /// # Safety
+    /// The caller must ensure that the given port address points to a valid
+    /// serial port device. Otherwise, this may write to an arbitrary I/O port.
+    pub const unsafe fn new(port: u16) -> Self {
+        Self {
+            data: port,
+            line_status: port + 5,
+        }
+    }
+
+    /// Write a single byte. This is best-effort: if the UART doesn't become
+    /// ready in time, the byte is written anyways and may be lost.
+    pub fn write_byte(&self, byte: u8) {
+        let mut line_status = PortReadOnly::<u8>::new(self.line_status);
+        let mut data = Port::<u8>::new(self.data);
+
+        // SAFETY: the port addresses were validated in `new`
+        unsafe {
+            for _ in 0..Self::SPIN_LIMIT {
+                if line_status.read() & THR_EMPTY != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            data.write(byte);
+        }
+    }
+
+    /// Write a single byte once the UART is ready, or give up without writing
+    /// it if `deadline` passes first. Returns whether the byte was written.
+    pub fn write_byte_timeout(&self, byte: u8, deadline: &Deadline) -> bool {
+        let mut line_status = PortReadOnly::<u8>::new(self.line_status);
+        let mut data = Port::<u8>::new(self.data);
+
+        // SAFETY: the port addresses were validated in `new`
+        unsafe {
+            while line_status.read() & THR_EMPTY == 0 {
+                if deadline.has_passed() {
+                    return false;
+                }
+                core::hint::spin_loop();
+            }
+            data.write(byte);
+        }
+        true
+    }
+}
+
 /// Called by the kernel after panic handling completes.
 pub fn fatal_error() -> ! {
     // This function is only ever called _from_ the panic handler, so it must not
@@ -0,0 +1,56 @@
+//! Resetting the machine. Leaving QEMU is platform-independent, in
+//! [`platypos_hal::power`].
+//!
+//! This is a last-resort operation for the panic handler, so it can't fail or
+//! return. If a method doesn't work, it falls back to something cruder.
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortReadOnly};
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+/// 8042 keyboard controller status and command port
+const KBC_COMMAND: u16 = 0x64;
+
+/// Set in the 8042 status register while the controller's input buffer is
+/// full, so it can't take a command
+const KBC_INPUT_FULL: u8 = 1 << 1;
+
+/// 8042 command that pulses the CPU reset line
+const KBC_RESET: u8 = 0xfe;
+
+/// Maximum number of times to poll the 8042 before trying a reset anyways
+const SPIN_LIMIT: usize = 100_000;
+
+/// Reset the machine. This tries the keyboard controller's reset line first,
+/// and triple faults if that doesn't work.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    let mut command = PortReadOnly::<u8>::new(KBC_COMMAND);
+    // SAFETY: every PC-compatible system has an 8042, or something emulating one, at this port
+    unsafe {
+        for _ in 0..SPIN_LIMIT {
+            if command.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Port::<u8>::new(KBC_COMMAND).write(KBC_RESET);
+    }
+
+    // Give the reset a moment to happen before forcing it
+    for _ in 0..SPIN_LIMIT {
+        core::hint::spin_loop();
+    }
+
+    // With an empty IDT, the breakpoint can't be delivered, and neither can the resulting double
+    // fault, so the processor resets
+    static EMPTY: InterruptDescriptorTable = InterruptDescriptorTable::new();
+    EMPTY.load();
+    interrupts::int3();
+
+    // The processor should have reset by now, but the compiler can't know that
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
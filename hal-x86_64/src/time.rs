@@ -0,0 +1,41 @@
+//! Time stamp counter (TSC) clock.
+//!
+//! The TSC is readable as soon as the kernel starts, but its rate isn't
+//! architecturally specified. It's measured alongside the APIC timer, in
+//! [`crate::interrupts::calibrate_timer`], so timestamps taken before that can
+//! still be converted afterwards.
+//!
+//! This assumes an invariant TSC, which runs at a constant rate regardless of
+//! power states. QEMU and recent hardware both provide one.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use platypos_hal as hal;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Clock;
+
+/// Measured TSC frequency in Hz, or 0 if not calibrated yet
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+impl hal::time::Clock for Clock {
+    #[inline]
+    fn now(&self) -> u64 {
+        // SAFETY: RDTSC has no side effects, and is available on every x86-64 processor
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    fn frequency(&self) -> Option<u64> {
+        match FREQUENCY.load(Ordering::Relaxed) {
+            0 => None,
+            frequency => Some(frequency),
+        }
+    }
+}
+
+pub static INSTANCE: Clock = Clock;
+
+/// Record the TSC frequency, as measured during timer calibration
+pub(crate) fn set_frequency(frequency: u64) {
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+}
@@ -1,4 +1,8 @@
+use platypos_common::sync::Global;
 use platypos_hal as hal;
+use raw_cpuid::{CpuId, TopologyType};
+
+use hal::topology::Location;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Topology;
@@ -9,6 +13,110 @@ impl hal::topology::Topology for Topology {
     fn current_processor(&self) -> hal::topology::ProcessorId {
         0
     }
+
+    fn location(&self, processor: hal::topology::ProcessorId) -> Option<Location> {
+        LOCATIONS.get(processor as usize)?.try_get().copied()
+    }
 }
 
 pub static INSTANCE: Topology = Topology;
+
+/// Location of each processor, indexed by processor ID, once it's detected
+static LOCATIONS: [Global<Location>; Topology::MAX_PROCESSORS as usize] =
+    [const { Global::new() }; Topology::MAX_PROCESSORS as usize];
+
+/// x2APIC ID of each processor, indexed by processor ID, recorded before it
+/// goes online
+static APIC_IDS: [Global<u32>; Topology::MAX_PROCESSORS as usize] =
+    [const { Global::new() }; Topology::MAX_PROCESSORS as usize];
+
+/// Hooks for accessing processor-local statics
+pub static LOCAL_HOOKS: hal::topology::LocalHooks = hal::topology::LocalHooks {
+    current_processor: || hal::topology::Topology::current_processor(&INSTANCE),
+    interrupts_enabled: x86_64::instructions::interrupts::are_enabled,
+};
+
+/// Record the current processor's x2APIC ID, which IPIs to it are addressed
+/// to.
+///
+/// # Panics
+/// If this processor's APIC ID was already recorded
+pub(crate) fn set_local_apic_id(apic_id: u32) {
+    let processor = hal::topology::Topology::current_processor(&INSTANCE);
+    APIC_IDS[processor as usize].init(apic_id);
+}
+
+/// The x2APIC ID of `processor`, which every online processor has
+pub fn apic_id(processor: hal::topology::ProcessorId) -> Option<u32> {
+    APIC_IDS.get(processor as usize)?.try_get().copied()
+}
+
+/// Work out where the current processor is from its x2APIC ID, and record it.
+/// This must be called once on each processor as it comes online.
+///
+/// # Panics
+/// If this processor's location was already detected
+pub fn detect_local() -> Location {
+    let processor = hal::topology::Topology::current_processor(&INSTANCE);
+    *LOCATIONS[processor as usize].init(read_location())
+}
+
+fn read_location() -> Location {
+    let cpuid = CpuId::new();
+    if let Some(levels) = cpuid.get_extended_topology_info() {
+        let (mut apic_id, mut smt_shift, mut core_shift) = (0, 0, None);
+        for level in levels {
+            apic_id = level.x2apic_id();
+            match level.level_type() {
+                TopologyType::SMT => smt_shift = level.shift_right_for_next_apic_id(),
+                TopologyType::Core => core_shift = Some(level.shift_right_for_next_apic_id()),
+                _ => {}
+            }
+        }
+        return decode(apic_id, smt_shift, core_shift.unwrap_or(smt_shift));
+    }
+
+    // Without the extended topology leaf, treat every processor as its own core in a single
+    // package
+    let apic_id = cpuid
+        .get_feature_info()
+        .map_or(0, |f| f.initial_local_apic_id().into());
+    decode(apic_id, 0, u32::BITS)
+}
+
+/// Split an x2APIC ID into its topology fields. The low `smt_shift` bits
+/// identify the hardware thread within a core, and the low `core_shift` bits
+/// identify it within a package.
+///
+/// See Intel SDM volume 3A, 9.9.1
+fn decode(apic_id: u32, smt_shift: u32, core_shift: u32) -> Location {
+    let mask = |bits: u32| 1u32.checked_shl(bits).unwrap_or(0).wrapping_sub(1);
+    let shifted = |bits: u32| apic_id.checked_shr(bits).unwrap_or(0);
+    Location {
+        package: shifted(core_shift),
+        core: shifted(smt_shift) & mask(core_shift.saturating_sub(smt_shift)),
+        thread: apic_id & mask(smt_shift),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_apic_id() {
+        // 2 threads per core, 8 cores per package: package 1, core 3, thread 1
+        let location = decode(0b1_011_1, 1, 4);
+        assert_eq!(
+            location,
+            Location {
+                package: 1,
+                core: 3,
+                thread: 1
+            }
+        );
+
+        let flat = decode(5, 0, u32::BITS);
+        assert_eq!((flat.package, flat.core, flat.thread), (0, 5, 0));
+    }
+}
@@ -0,0 +1,71 @@
+#![feature(proc_macro_diagnostic)]
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, ItemStatic};
+
+/// Declare a processor-local static. See
+/// `platypos_hal::topology::ProcessorLocal` for how to access it.
+///
+/// ```ignore
+/// #[per_processor]
+/// static TICKS: Cell<u64> = Cell::new(0);
+/// ```
+#[proc_macro_attribute]
+pub fn per_processor(
+    attr: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let attr = TokenStream::from(attr);
+    if !attr.is_empty() {
+        attr.span()
+            .unwrap()
+            .error("#[per_processor] does not take arguments")
+            .emit();
+        return proc_macro::TokenStream::new();
+    }
+
+    let input = parse_macro_input!(input as ItemStatic);
+
+    proc_macro::TokenStream::from(generate_per_processor(input))
+}
+
+fn generate_per_processor(input: ItemStatic) -> TokenStream {
+    if let Some(mutability) = input.mutability {
+        mutability
+            .span()
+            .unwrap()
+            .error("Processor-local statics cannot be `mut`")
+            .emit();
+        return TokenStream::new();
+    }
+
+    let ItemStatic {
+        attrs,
+        vis,
+        ident,
+        ty,
+        expr,
+        ..
+    } = input;
+
+    let register_name = format_ident!("__PER_PROCESSOR_INIT_{}", ident);
+
+    quote! {
+        #(#attrs)*
+        #vis static #ident: ::platypos_hal::topology::ProcessorLocal<#ty> =
+            ::platypos_hal::topology::ProcessorLocal::new({
+                fn init() -> #ty {
+                    #expr
+                }
+                init
+            });
+
+        #[::platypos_hal::linkme::distributed_slice(::platypos_hal::topology::PROCESSOR_LOCAL_INIT)]
+        #[linkme(crate = ::platypos_hal::linkme)]
+        #[allow(non_upper_case_globals)]
+        #[doc(hidden)]
+        static #register_name: fn() = || #ident.init_local();
+    }
+}
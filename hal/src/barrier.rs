@@ -0,0 +1,75 @@
+//! Memory barriers for ordering accesses to device memory.
+//!
+//! Atomic fences only order memory accesses as seen by other processors. These
+//! barriers also order accesses relative to devices, which is what MMIO and DMA
+//! code needs. For example, a driver should call [`wmb`] after filling a DMA
+//! buffer and before writing the doorbell register that tells the device to
+//! read it.
+
+/// Full memory barrier: all loads and stores before this point complete before
+/// any after it.
+#[inline(always)]
+pub fn mb() {
+    arch::mb()
+}
+
+/// Read memory barrier: all loads before this point complete before any after
+/// it.
+#[inline(always)]
+pub fn rmb() {
+    arch::rmb()
+}
+
+/// Write memory barrier: all stores before this point complete before any
+/// after it.
+#[inline(always)]
+pub fn wmb() {
+    arch::wmb()
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use core::arch::asm;
+
+    // The fence instructions also act as compiler barriers, since the asm blocks aren't marked
+    // `nomem`
+
+    #[inline(always)]
+    pub(super) fn mb() {
+        // SAFETY: fences have no side effects besides ordering
+        unsafe { asm!("mfence", options(nostack, preserves_flags)) }
+    }
+
+    #[inline(always)]
+    pub(super) fn rmb() {
+        // SAFETY: fences have no side effects besides ordering
+        unsafe { asm!("lfence", options(nostack, preserves_flags)) }
+    }
+
+    #[inline(always)]
+    pub(super) fn wmb() {
+        // SAFETY: fences have no side effects besides ordering
+        unsafe { asm!("sfence", options(nostack, preserves_flags)) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod arch {
+    // Conservative fallback until ports provide device-aware barriers (such as `dsb` on ARM)
+    use core::sync::atomic::{fence, Ordering};
+
+    #[inline(always)]
+    pub(super) fn mb() {
+        fence(Ordering::SeqCst)
+    }
+
+    #[inline(always)]
+    pub(super) fn rmb() {
+        fence(Ordering::SeqCst)
+    }
+
+    #[inline(always)]
+    pub(super) fn wmb() {
+        fence(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,34 @@
+//! Block device abstraction.
+//!
+//! Block devices transfer data in fixed-size blocks, addressed by index.
+//! Transfers are asynchronous, so that the caller's thread can yield while the
+//! device works.
+
+use core::future::Future;
+
+/// A storage device that reads and writes whole blocks
+pub trait BlockDevice {
+    type Error;
+
+    /// Size of one block, in bytes
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn capacity(&self) -> u64;
+
+    /// Read blocks starting at `start` into `buf`, whose length must be a
+    /// multiple of the block size.
+    fn read_blocks<'a>(
+        &'a self,
+        start: u64,
+        buf: &'a mut [u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> + 'a;
+
+    /// Write `buf`, whose length must be a multiple of the block size, to
+    /// blocks starting at `start`.
+    fn write_blocks<'a>(
+        &'a self,
+        start: u64,
+        buf: &'a [u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> + 'a;
+}
@@ -0,0 +1,78 @@
+//! Data cache maintenance for DMA.
+//!
+//! On platforms where DMA isn't cache-coherent, drivers must clean (write back)
+//! buffers before a device reads them, and invalidate them before the CPU reads
+//! data a device wrote. Calling these functions unconditionally keeps driver
+//! code portable.
+//!
+//! On x86_64, DMA is cache-coherent, so these only order memory accesses.
+
+use crate::barrier;
+
+/// Write back any cached data in `[start, start + len)` to memory, so that a
+/// device reading it sees the CPU's writes.
+#[inline]
+pub fn clean_range(start: *const u8, len: usize) {
+    arch::clean_range(start, len)
+}
+
+/// Discard any cached data in `[start, start + len)`, so that the CPU sees data
+/// a device wrote to memory.
+///
+/// # Safety
+/// On non-coherent platforms, this discards writes the CPU hasn't written back
+/// yet. The caller must ensure the range doesn't contain any such data that's
+/// still needed, including data outside the range that shares a cache line
+/// with it.
+#[inline]
+pub unsafe fn invalidate_range(start: *const u8, len: usize) {
+    arch::invalidate_range(start, len)
+}
+
+/// Write back and then discard any cached data in `[start, start + len)`.
+///
+/// # Safety
+/// `[start, start + len)` must be mapped memory. Unlike [`invalidate_range`],
+/// this never loses data, since every line in the range is cleaned first.
+#[inline]
+pub unsafe fn clean_invalidate_range(start: *const u8, len: usize) {
+    arch::clean_range(start, len);
+    // SAFETY: the range was just cleaned, so no data is lost, and the caller ensures it's mapped
+    arch::invalidate_range(start, len)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use super::barrier;
+
+    #[inline(always)]
+    pub(super) fn clean_range(_start: *const u8, _len: usize) {
+        // Caches are coherent with DMA, but the device must not see the buffer before earlier
+        // stores are visible
+        barrier::wmb();
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn invalidate_range(_start: *const u8, _len: usize) {
+        // Caches are coherent with DMA, but later loads must not be satisfied before the device's
+        // writes
+        barrier::rmb();
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod arch {
+    use super::barrier;
+
+    // TODO: ports with non-coherent DMA need to walk the range by cache line here
+
+    #[inline(always)]
+    pub(super) fn clean_range(_start: *const u8, _len: usize) {
+        barrier::mb();
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn invalidate_range(_start: *const u8, _len: usize) {
+        barrier::mb();
+    }
+}
@@ -0,0 +1,5 @@
+//! Lock-free channels for handing data between contexts that can't share a
+//! lock, like an interrupt handler and the task that processes what it
+//! received.
+
+pub mod spsc;
@@ -0,0 +1,361 @@
+//! Wait-free single-producer, single-consumer channel.
+//!
+//! A [`Channel`] is a fixed-capacity ring of `N` values with one
+//! [`Producer`] and one [`Consumer`], neither of which ever waits for the
+//! other: a push to a full channel and a pop from an empty one fail right
+//! away. That makes it usable from interrupt context (including NMIs), for
+//! streaming bytes or records from a handler to the task that processes them.
+//!
+//! # Memory ordering
+//! The channel keeps two counters, `head` (values pushed) and `tail` (values
+//! popped). Only the producer stores `head` and only the consumer stores
+//! `tail`, so each side reads its own counter with `Relaxed` ordering.
+//!
+//! - The producer writes a value into its slot, then stores `head` with
+//!   `Release`. The consumer loads `head` with `Acquire` before reading any
+//!   slot below it, so it sees the whole value.
+//! - The consumer reads a value out of its slot, then stores `tail` with
+//!   `Release`. The producer loads `tail` with `Acquire` before reusing a
+//!   slot, so the consumer is done with it.
+//!
+//! The counters are never reduced modulo `N`, so `head - tail` is always the
+//! number of queued values. At one push per nanosecond, a 64-bit counter takes
+//! centuries to wrap.
+
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::sync::atomic::Ordering;
+
+#[cfg(loom)]
+use loom::cell::UnsafeCell;
+#[cfg(loom)]
+use loom::sync::atomic::AtomicUsize;
+
+#[cfg(not(loom))]
+use self::cell::UnsafeCell;
+#[cfg(not(loom))]
+use core::sync::atomic::AtomicUsize;
+
+/// Fixed-capacity queue of up to `N` values, for one producer and one
+/// consumer
+pub struct Channel<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Number of values pushed so far
+    head: AtomicUsize,
+    /// Number of values popped so far
+    tail: AtomicUsize,
+}
+
+// SAFETY: values are moved from the producer's context to the consumer's, and
+// each slot is only accessed by one side at a time
+unsafe impl<T: Send, const N: usize> Send for Channel<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for Channel<T, N> {}
+
+/// Sending half of a [`Channel`]
+pub struct Producer<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+    // Not Sync, since pushes from two contexts at once would race
+    _not_sync: PhantomData<core::cell::Cell<()>>,
+}
+
+/// Receiving half of a [`Channel`]
+pub struct Consumer<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+    // Not Sync, since pops from two contexts at once would race
+    _not_sync: PhantomData<core::cell::Cell<()>>,
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    /// Create an empty channel. This is `const` so that channels can be
+    /// statics shared with interrupt handlers.
+    ///
+    /// # Panics
+    /// If `N` is 0
+    #[cfg(not(loom))]
+    pub const fn new() -> Self {
+        assert!(N > 0, "Channel capacity must be nonzero");
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create an empty channel
+    ///
+    /// # Panics
+    /// If `N` is 0
+    #[cfg(loom)]
+    pub fn new() -> Self {
+        assert!(N > 0, "Channel capacity must be nonzero");
+        Self {
+            slots: core::array::from_fn(|_| UnsafeCell::new(MaybeUninit::uninit())),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Maximum number of values the channel can hold
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of values queued. If either side is active, this may already be
+    /// out of date.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        head.wrapping_sub(tail)
+    }
+
+    /// Whether no values are queued. If either side is active, this may
+    /// already be out of date.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Split an unshared channel into its two halves
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        // SAFETY: the exclusive borrow keeps any other handles from being created while these
+        // are alive
+        unsafe { (self.producer(), self.consumer()) }
+    }
+
+    /// Get the sending half of a shared channel, like a static.
+    ///
+    /// # Safety
+    /// Only one producer may be in use at a time. For example, only one
+    /// interrupt handler may push to the channel, and it must not be
+    /// reentered.
+    pub unsafe fn producer(&self) -> Producer<'_, T, N> {
+        Producer {
+            channel: self,
+            _not_sync: PhantomData,
+        }
+    }
+
+    /// Get the receiving half of a shared channel, like a static.
+    ///
+    /// # Safety
+    /// Only one consumer may be in use at a time. For example, only one task
+    /// may pop from the channel.
+    pub unsafe fn consumer(&self) -> Consumer<'_, T, N> {
+        Consumer {
+            channel: self,
+            _not_sync: PhantomData,
+        }
+    }
+
+    fn slot(&self, counter: usize) -> &UnsafeCell<MaybeUninit<T>> {
+        &self.slots[counter % N]
+    }
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Channel<T, N> {
+    fn drop(&mut self) {
+        // The split halves borrow the channel, so it's already unshared
+        let (_, mut consumer) = self.split();
+        while consumer.pop().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Queue `value`, or hand it back if the channel is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let channel = self.channel;
+        let head = channel.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(channel.tail.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        // SAFETY: the slot at `head` is outside `tail..head`, so the consumer isn't reading it,
+        // and this is the only producer
+        channel
+            .slot(head)
+            .with_mut(|slot| unsafe { (*slot).write(value) });
+        channel.head.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether a push would fail right now. The consumer may make room at
+    /// any time.
+    pub fn is_full(&self) -> bool {
+        self.channel.len() == N
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Take the oldest queued value, if there is one
+    pub fn pop(&mut self) -> Option<T> {
+        let channel = self.channel;
+        let tail = channel.tail.load(Ordering::Relaxed);
+        if tail == channel.head.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: the slot at `tail` is inside `tail..head`, so the producer initialized it and
+        // won't touch it until `tail` moves past it
+        let value = channel
+            .slot(tail)
+            .with(|slot| unsafe { (*slot).assume_init_read() });
+        channel.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Look at the oldest queued value without taking it, so that it's still
+    /// there if it can't be processed yet
+    pub fn peek(&self) -> Option<&T> {
+        let channel = self.channel;
+        let tail = channel.tail.load(Ordering::Relaxed);
+        if tail == channel.head.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: as for `pop`, and the value can't be popped while it's borrowed, since that
+        // needs the consumer mutably
+        Some(
+            channel
+                .slot(tail)
+                .with(|slot| unsafe { (*slot).assume_init_ref() }),
+        )
+    }
+}
+
+#[cfg(not(loom))]
+mod cell {
+    pub(super) struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
+
+    impl<T> UnsafeCell<T> {
+        pub(super) const fn new(data: T) -> Self {
+            Self(core::cell::UnsafeCell::new(data))
+        }
+
+        pub(super) fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+            f(self.0.get())
+        }
+
+        pub(super) fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+            f(self.0.get())
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use alloc::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn test_fifo_across_wraparound() {
+        let mut channel = Channel::<u32, 3>::new();
+        let (mut producer, mut consumer) = channel.split();
+        for round in 0..4 {
+            let base = round * 10;
+            for value in base..base + 3 {
+                assert_eq!(producer.push(value), Ok(()));
+            }
+            assert!(producer.is_full());
+            assert_eq!(producer.push(base + 3), Err(base + 3));
+
+            assert_eq!(consumer.peek(), Some(&base));
+            for value in base..base + 3 {
+                assert_eq!(consumer.pop(), Some(value));
+            }
+            assert_eq!(consumer.pop(), None);
+        }
+        assert!(channel.is_empty());
+    }
+
+    #[test]
+    fn test_drops_queued_values() {
+        let value = Rc::new(());
+        {
+            let mut channel = Channel::<Rc<()>, 4>::new();
+            let (mut producer, mut consumer) = channel.split();
+            for _ in 0..3 {
+                assert!(producer.push(value.clone()).is_ok());
+            }
+            drop(consumer.pop());
+            assert_eq!(Rc::strong_count(&value), 3);
+        }
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod test {
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use super::Channel;
+
+    /// Values arrive in order and intact, even when the producer has to wait
+    /// for the consumer to make room
+    #[test]
+    fn test_spsc_order() {
+        loom::model(|| {
+            let channel = Arc::new(Channel::<usize, 2>::new());
+
+            let producer = {
+                let channel = channel.clone();
+                thread::spawn(move || {
+                    // SAFETY: this is the only producer
+                    let mut producer = unsafe { channel.producer() };
+                    for value in 0..3 {
+                        let mut value = value;
+                        while let Err(rejected) = producer.push(value) {
+                            value = rejected;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            };
+
+            // SAFETY: this is the only consumer
+            let mut consumer = unsafe { channel.consumer() };
+            for expected in 0..3 {
+                loop {
+                    if let Some(value) = consumer.pop() {
+                        assert_eq!(value, expected);
+                        break;
+                    }
+                    thread::yield_now();
+                }
+            }
+
+            producer.join().unwrap();
+            assert!(channel.is_empty());
+        });
+    }
+
+    /// A push to a full channel fails without losing or duplicating values,
+    /// whichever order it races a pop in
+    #[test]
+    fn test_spsc_full() {
+        loom::model(|| {
+            let channel = Arc::new(Channel::<usize, 1>::new());
+            // SAFETY: no other thread has the channel yet
+            assert_eq!(unsafe { channel.producer() }.push(1), Ok(()));
+
+            let consumer = {
+                let channel = channel.clone();
+                thread::spawn(move || {
+                    // SAFETY: this is the only consumer
+                    unsafe { channel.consumer() }.pop()
+                })
+            };
+
+            // SAFETY: this is the only producer
+            let pushed = unsafe { channel.producer() }.push(2).is_ok();
+            assert_eq!(consumer.join().unwrap(), Some(1));
+
+            // SAFETY: the consumer thread is done
+            let left = unsafe { channel.consumer() }.pop();
+            assert_eq!(left, pushed.then_some(2));
+        });
+    }
+}
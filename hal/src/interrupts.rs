@@ -1,5 +1,9 @@
 //! Abstractions for managing interrupt controllers
 
+use core::marker::PhantomData;
+
+pub mod nmi_safe;
+
 /// An interrupt controller. Different platforms may have multiple interrupt
 /// controllers, different interrupt state per processor, or shared controllers.
 pub trait Controller {
@@ -44,6 +48,75 @@ pub trait Controller {
     /// }
     /// ```
     fn wait(&self);
+
+    /// The current processor's interrupt priority level
+    fn level(&self) -> Level;
+
+    /// What the current processor is running: a task, or an interrupt
+    /// handler
+    fn execution_context(&self) -> ExecutionContext;
+
+    /// Set the current processor's interrupt priority level, which may lower
+    /// it. Prefer [`Controller::raise_to`], which restores the previous level
+    /// automatically.
+    fn set_level(&self, level: Level);
+
+    /// Mask interrupts at or below `level` for as long as the guard is held.
+    /// When the guard is dropped, the previous level is restored. Unlike
+    /// [`Controller::disable`], this lets higher-priority interrupts (like the
+    /// scheduler tick) keep running.
+    ///
+    /// # Panics
+    /// If `level` is below the current level. Lowering the level could unmask
+    /// interrupts that an enclosing critical section relies on being masked.
+    fn raise_to(&self, level: Level) -> LevelGuard<'_, Self> {
+        let previous = self.level();
+        assert!(
+            level >= previous,
+            "Cannot raise interrupt level from {previous:?} to {level:?}"
+        );
+        if level > previous {
+            self.set_level(level);
+        }
+
+        LevelGuard {
+            previous,
+            controller: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Interrupt priority levels, modeled on Windows IRQLs and BSD SPLs. At each
+/// level, interrupts of that priority and below are masked, while higher
+/// priority interrupts can still be delivered.
+///
+/// Exceptions and non-maskable interrupts are never masked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Normal execution: all interrupts can be delivered
+    Passive,
+    /// Device interrupts are masked
+    Device,
+    /// Device and timekeeping interrupts are masked
+    Clock,
+    /// All maskable interrupts, including inter-processor interrupts, are
+    /// masked
+    High,
+}
+
+/// What kind of code a processor is running. Code in interrupt handlers has
+/// to be more careful than task code, since it can't block and may have
+/// interrupted code holding locks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionContext {
+    /// Normal kernel code, like a thread or the scheduler
+    Task,
+    /// A maskable interrupt handler, nested to any depth
+    Irq,
+    /// A non-maskable interrupt handler, which can arrive in the middle of
+    /// anything, including other interrupt handlers
+    Nmi,
 }
 
 /// Guard that keeps interrupts disabled while it is held
@@ -59,3 +132,24 @@ impl<'a, C: Controller + ?Sized> Drop for Guard<'a, C> {
         }
     }
 }
+
+/// Guard that keeps the interrupt priority level raised while it is held.
+/// The level is per-processor, so the guard can't be sent to another thread.
+pub struct LevelGuard<'a, C: Controller + ?Sized> {
+    previous: Level,
+    controller: &'a C,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, C: Controller + ?Sized> LevelGuard<'a, C> {
+    /// The level that will be restored when this guard is dropped
+    pub fn previous(&self) -> Level {
+        self.previous
+    }
+}
+
+impl<'a, C: Controller + ?Sized> Drop for LevelGuard<'a, C> {
+    fn drop(&mut self) {
+        self.controller.set_level(self.previous);
+    }
+}
@@ -0,0 +1,96 @@
+//! Critical sections that NMIs respect.
+//!
+//! [`Controller::disable`](super::Controller::disable) masks maskable
+//! interrupts, but an NMI can still arrive at any point, including halfway
+//! through updating tracing state or while a spinlock is held. If the NMI
+//! handler then logs, or takes the same lock, it re-enters code that was never
+//! meant to be reentrant and either corrupts it or deadlocks.
+//!
+//! Code that can't tolerate that marks itself with a [`Section`] from
+//! [`enter`]. NMI handlers check [`active`] and, if the code they interrupted
+//! is in a section, skip anything that isn't essential (like logging) or defer
+//! it until a later NMI. Sections nest, and are tracked per processor, since
+//! an NMI only interrupts the processor it's delivered to.
+//!
+//! Anything an NMI handler does touch has to be NMI-aware on its own: it must
+//! never block on something the interrupted code could be holding. Lock-free
+//! queues and try-locks that give up (like the early console's) qualify,
+//! while spinning locks don't. [`assert_nmi_aware`] catches the latter in
+//! debug builds.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::ExecutionContext;
+use crate::topology::{current_processor, ProcessorSet};
+
+const MAX_PROCESSORS: usize = ProcessorSet::CAPACITY as usize;
+
+/// Number of sections the code on each processor is in
+static DEPTH: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(0) }; MAX_PROCESSORS];
+
+/// Marks the current processor as being in an NMI-unsafe critical section
+/// until dropped
+#[must_use = "the section ends as soon as the guard is dropped"]
+pub struct Section {
+    depth: &'static AtomicU32,
+}
+
+impl Drop for Section {
+    fn drop(&mut self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Start an NMI-unsafe critical section on the current processor. The guard
+/// must be dropped on the same processor, which holds as long as interrupts
+/// are disabled or the section doesn't yield.
+pub fn enter() -> Section {
+    let depth = &DEPTH[usize::from(current_processor())];
+    // An NMI only ever observes the processor it interrupted, so relaxed
+    // ordering is enough, the same as for processor-local borrow flags
+    depth.fetch_add(1, Ordering::Relaxed);
+    Section { depth }
+}
+
+/// Whether the code running on the current processor (or, from an NMI
+/// handler, the code it interrupted) is in a critical section
+pub fn active() -> bool {
+    DEPTH[usize::from(current_processor())].load(Ordering::Relaxed) > 0
+}
+
+/// Check, in debug builds, that a lock which blocks isn't being taken from an
+/// NMI handler. `what` names the lock in the panic message.
+#[inline(always)]
+pub fn assert_nmi_aware(context: ExecutionContext, what: &str) {
+    debug_assert!(
+        context != ExecutionContext::Nmi,
+        "{what} is not NMI-aware, but was locked from an NMI handler"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nesting() {
+        // Without local hooks, everything runs as processor 0
+        assert!(!active());
+        let outer = enter();
+        let inner = enter();
+        assert!(active());
+        drop(inner);
+        assert!(active());
+        drop(outer);
+        assert!(!active());
+    }
+
+    // The check is a debug assertion, and `just loom` tests in release mode
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "not NMI-aware")]
+    fn test_assert_nmi_aware() {
+        assert_nmi_aware(ExecutionContext::Irq, "test lock");
+        assert_nmi_aware(ExecutionContext::Nmi, "test lock");
+    }
+}
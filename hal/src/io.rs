@@ -0,0 +1,282 @@
+//! I/O adapters.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::time::{Clock, Deadline};
+use crate::Write;
+
+/// Why a [`WriteTimeout::write_all_timeout`] failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutError<E> {
+    /// The deadline passed after only the first `written` bytes were written
+    TimedOut { written: usize },
+    /// The writer failed
+    Write(E),
+}
+
+/// Writers that can give up on a write instead of blocking forever, like when
+/// a serial port's FIFO never drains because nothing is connected
+pub trait WriteTimeout: Write {
+    /// Write all of `data`, unless `deadline` passes first. The default
+    /// implementation ignores the deadline, for writers that never block.
+    fn write_all_timeout(
+        &mut self,
+        data: &[u8],
+        deadline: &Deadline,
+    ) -> Result<(), TimeoutError<Self::Error>> {
+        let _ = deadline;
+        self.write_all(data).map_err(TimeoutError::Write)
+    }
+}
+
+/// Counters for an [`Instrumented`] writer, along with its bandwidth cap.
+/// These are meant to live in a static, so they can be read and configured
+/// while something else owns the writer.
+#[derive(Debug)]
+pub struct WriteStats {
+    bytes: AtomicU64,
+    writes: AtomicU64,
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+    /// Maximum throughput in bytes per second, or 0 for no limit
+    bandwidth_cap: AtomicU64,
+}
+
+impl WriteStats {
+    pub const fn new() -> Self {
+        Self {
+            bytes: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            bandwidth_cap: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of bytes successfully written
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of write calls, including failed ones
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Number of write and flush calls that failed
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of writes that timed out. These aren't counted as errors.
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// The maximum throughput, in bytes per second, if there is one
+    pub fn bandwidth_cap(&self) -> Option<u64> {
+        match self.bandwidth_cap.load(Ordering::Relaxed) {
+            0 => None,
+            cap => Some(cap),
+        }
+    }
+
+    /// Limit throughput to `cap` bytes per second, or remove the limit. Writes
+    /// that would go over the limit busy-wait, which is useful for simulating
+    /// slow links.
+    pub fn set_bandwidth_cap(&self, cap: Option<u64>) {
+        self.bandwidth_cap
+            .store(cap.unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+impl Default for WriteStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A writer that counts what's written to it in a [`WriteStats`], and enforces
+/// its bandwidth cap. Throttling uses `clock`, and only starts once the clock
+/// is calibrated.
+pub struct Instrumented<W: Write, C: Clock> {
+    inner: W,
+    stats: &'static WriteStats,
+    clock: C,
+    /// Bytes that can be written without waiting, up to a second's worth
+    credit: u64,
+    /// Clock timestamp that `credit` was last refilled at
+    refilled_at: u64,
+}
+
+impl<W: Write, C: Clock> Instrumented<W, C> {
+    pub fn new(inner: W, stats: &'static WriteStats, clock: C) -> Self {
+        let refilled_at = clock.now();
+        Self {
+            inner,
+            stats,
+            clock,
+            credit: 0,
+            refilled_at,
+        }
+    }
+
+    pub fn stats(&self) -> &'static WriteStats {
+        self.stats
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Wait until `len` bytes can be written without going over the cap
+    fn throttle(&mut self, len: usize) {
+        let (Some(cap), Some(frequency)) = (self.stats.bandwidth_cap(), self.clock.frequency())
+        else {
+            return;
+        };
+        // Writes bigger than the burst size go through once the bucket is full
+        let needed = (len as u64).min(cap);
+
+        loop {
+            let now = self.clock.now();
+            let earned = u128::from(now.saturating_sub(self.refilled_at)) * u128::from(cap)
+                / u128::from(frequency);
+            if earned > 0 {
+                // Only advance by the ticks that were converted, so that fractional bytes
+                // aren't lost
+                self.refilled_at += (earned * u128::from(frequency) / u128::from(cap)) as u64;
+                self.credit = (self.credit + earned.min(u128::from(cap)) as u64).min(cap);
+            }
+
+            if self.credit >= needed {
+                self.credit -= needed;
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<W: Write, C: Clock> Write for Instrumented<W, C> {
+    type Error = W::Error;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.throttle(data.len());
+        self.stats.writes.fetch_add(1, Ordering::Relaxed);
+        match self.inner.write_all(data) {
+            Ok(()) => {
+                self.stats
+                    .bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                Err(err)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().inspect_err(|_| {
+            self.stats.errors.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+impl<W: WriteTimeout, C: Clock> WriteTimeout for Instrumented<W, C> {
+    fn write_all_timeout(
+        &mut self,
+        data: &[u8],
+        deadline: &Deadline,
+    ) -> Result<(), TimeoutError<Self::Error>> {
+        self.throttle(data.len());
+        self.stats.writes.fetch_add(1, Ordering::Relaxed);
+        let result = self.inner.write_all_timeout(data, deadline);
+        let (written, counter) = match result {
+            Ok(()) => (data.len(), None),
+            Err(TimeoutError::TimedOut { written }) => (written, Some(&self.stats.timeouts)),
+            Err(TimeoutError::Write(_)) => (0, Some(&self.stats.errors)),
+        };
+        self.stats
+            .bytes
+            .fetch_add(written as u64, Ordering::Relaxed);
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::convert::Infallible;
+
+    use super::*;
+    use crate::time::mock::MockClock;
+    use crate::time::Duration;
+
+    /// Writer whose FIFO stops draining after `capacity` bytes, with time
+    /// passing while it waits
+    struct Stuck<'a> {
+        written: Vec<u8>,
+        capacity: usize,
+        clock: &'a MockClock,
+    }
+
+    impl Write for Stuck<'_> {
+        type Error = Infallible;
+
+        fn write_all(&mut self, _: &[u8]) -> Result<(), Infallible> {
+            unimplemented!("would block forever")
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    impl WriteTimeout for Stuck<'_> {
+        fn write_all_timeout(
+            &mut self,
+            data: &[u8],
+            deadline: &Deadline,
+        ) -> Result<(), TimeoutError<Infallible>> {
+            for (written, &byte) in data.iter().enumerate() {
+                while self.written.len() == self.capacity {
+                    if deadline.has_passed() {
+                        return Err(TimeoutError::TimedOut { written });
+                    }
+                    self.clock.advance(1);
+                }
+                self.written.push(byte);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_timeouts_are_counted() {
+        static STATS: WriteStats = WriteStats::new();
+        static CLOCK: MockClock = MockClock::new(Some(1_000));
+        let stuck = Stuck {
+            written: Vec::new(),
+            capacity: 6,
+            clock: &CLOCK,
+        };
+        let mut writer = Instrumented::new(stuck, &STATS, &CLOCK);
+
+        let deadline = Deadline::after(&CLOCK, Duration::from_millis(10));
+        assert_eq!(writer.write_all_timeout(b"abcd", &deadline), Ok(()));
+        assert_eq!(
+            writer.write_all_timeout(b"efgh", &deadline),
+            Err(TimeoutError::TimedOut { written: 2 })
+        );
+        assert_eq!(CLOCK.now(), 10);
+        assert_eq!(writer.into_inner().written, b"abcdef");
+        assert_eq!((STATS.writes(), STATS.bytes()), (2, 6));
+        assert_eq!((STATS.timeouts(), STATS.errors()), (1, 0));
+    }
+}
@@ -4,7 +4,22 @@
 
 extern crate alloc;
 
+// Lets tests declare processor-local statics, since `per_processor` refers to this crate by name
+#[cfg(test)]
+extern crate self as platypos_hal;
+
+pub mod barrier;
+pub mod block;
+pub mod cache;
+pub mod channel;
 pub mod interrupts;
+pub mod io;
+pub mod power;
+pub mod time;
 pub mod topology;
 
 pub use ciborium_io::{Read, Write};
+
+// Used by the `per_processor` macro
+#[doc(hidden)]
+pub use linkme;
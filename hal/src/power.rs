@@ -0,0 +1,95 @@
+//! Leaving a virtual machine.
+//!
+//! Test runs and other automated boots end by asking the hypervisor to exit
+//! with an [`ExitCode`], which the host tooling turns back into a result. The
+//! same codes are used by the test harness, the kernel's panic policy, and
+//! `cargo xtask`, so they can't drift apart.
+//!
+//! On x86_64, the exit goes through QEMU's `isa-debug-exit` device, which
+//! `cargo xtask` always adds at [`DEBUG_EXIT_PORT`]. Writing to the port does
+//! nothing on real hardware, so [`exit_vm`] returns there, and callers fall
+//! back to halting or rebooting.
+
+/// Why the kernel is leaving the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    /// Every test passed, or the kernel shut down on purpose
+    Success = 1,
+    /// A test failed
+    TestFailure = 2,
+    /// The kernel panicked, outside of any test
+    Panic = 3,
+}
+
+impl ExitCode {
+    pub const ALL: [ExitCode; 3] = [ExitCode::Success, ExitCode::TestFailure, ExitCode::Panic];
+
+    /// Exit status the hypervisor exits with for this code. QEMU's
+    /// `isa-debug-exit` device exits with `(code << 1) | 1`, so it can't
+    /// report 0, and 1 is left for QEMU's own errors.
+    pub const fn qemu_status(self) -> i32 {
+        ((self as i32) << 1) | 1
+    }
+
+    /// The code the kernel exited with, if `status` came from one
+    pub fn from_qemu_status(status: i32) -> Option<ExitCode> {
+        Self::ALL
+            .into_iter()
+            .find(|code| code.qemu_status() == status)
+    }
+
+    pub const fn description(self) -> &'static str {
+        match self {
+            ExitCode::Success => "success",
+            ExitCode::TestFailure => "test failure",
+            ExitCode::Panic => "kernel panic",
+        }
+    }
+}
+
+/// I/O port of QEMU's `isa-debug-exit` device on x86_64
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Ask the hypervisor to exit with `code`. This only returns if there's no VM
+/// to exit, like on real hardware, so the caller needs a fallback.
+pub fn exit_vm(code: ExitCode) {
+    arch::exit_vm(code as u32)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use core::arch::asm;
+
+    use super::DEBUG_EXIT_PORT;
+
+    pub(super) fn exit_vm(code: u32) {
+        // SAFETY: writing to an unused I/O port has no effect, so this is harmless without QEMU
+        unsafe {
+            asm!("out dx, eax", in("dx") DEBUG_EXIT_PORT, in("eax") code, options(nomem, nostack));
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod arch {
+    // TODO: exit through semihosting or PSCI on other platforms
+
+    pub(super) fn exit_vm(_code: u32) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qemu_status() {
+        assert_eq!(ExitCode::Success.qemu_status(), 3);
+        for code in ExitCode::ALL {
+            assert_eq!(ExitCode::from_qemu_status(code.qemu_status()), Some(code));
+        }
+        // QEMU's own failures aren't mistaken for the kernel's
+        assert_eq!(ExitCode::from_qemu_status(1), None);
+        assert_eq!(ExitCode::from_qemu_status(0), None);
+    }
+}
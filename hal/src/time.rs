@@ -0,0 +1,239 @@
+//! Timekeeping.
+//!
+//! Clocks count in platform-specific ticks. An [`Instant`] is a raw tick
+//! count, which can be taken at any time, even before the clock's rate is
+//! known. Turning ticks into a [`Duration`] needs the clock's
+//! [`Calibration`], which is explicit so that the kernel and the host (which
+//! receives the calibration through ktrace) do exactly the same arithmetic.
+
+use core::ops::{Add, Sub};
+
+pub use core::time::Duration;
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// A point in time, as a tick count of some clock. Instants from different
+/// clocks can't be meaningfully compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The clock's epoch, like when it was started or reset
+    pub const ZERO: Instant = Instant(0);
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    /// Ticks since the clock's epoch
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Ticks elapsed from `earlier` to this instant, or 0 if `earlier` is
+    /// actually later
+    pub const fn ticks_since(self, earlier: Instant) -> u64 {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Time elapsed from `earlier` to this instant, or zero if `earlier` is
+    /// actually later
+    pub fn duration_since(self, earlier: Instant, calibration: Calibration) -> Duration {
+        calibration.to_duration(self.ticks_since(earlier))
+    }
+}
+
+impl Add<u64> for Instant {
+    type Output = Instant;
+
+    /// Advance by a number of ticks, saturating at the end of time
+    fn add(self, ticks: u64) -> Instant {
+        Instant(self.0.saturating_add(ticks))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = u64;
+
+    /// Ticks between two instants, like [`Instant::ticks_since`]
+    fn sub(self, earlier: Instant) -> u64 {
+        self.ticks_since(earlier)
+    }
+}
+
+/// The tick rate of a clock, for converting between ticks and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Calibration {
+    ticks_per_second: u64,
+}
+
+impl Calibration {
+    /// # Panics
+    /// If `ticks_per_second` is 0
+    pub const fn new(ticks_per_second: u64) -> Self {
+        assert!(ticks_per_second != 0, "Clock frequency must be nonzero");
+        Self { ticks_per_second }
+    }
+
+    /// Calibration for a clock that ticks every `period`
+    ///
+    /// # Panics
+    /// If `period` is zero or longer than a second
+    pub const fn from_period(period: Duration) -> Self {
+        assert!(period.as_nanos() != 0, "Clock period must be nonzero");
+        Self::new((NANOS_PER_SECOND / period.as_nanos()) as u64)
+    }
+
+    pub const fn ticks_per_second(self) -> u64 {
+        self.ticks_per_second
+    }
+
+    /// Convert ticks to nanoseconds, rounding down and saturating
+    pub const fn ticks_to_nanos(self, ticks: u64) -> u64 {
+        let nanos = ticks as u128 * NANOS_PER_SECOND / self.ticks_per_second as u128;
+        if nanos > u64::MAX as u128 {
+            u64::MAX
+        } else {
+            nanos as u64
+        }
+    }
+
+    /// Convert ticks to a duration, rounding down
+    pub const fn to_duration(self, ticks: u64) -> Duration {
+        Duration::from_nanos(self.ticks_to_nanos(ticks))
+    }
+
+    /// Convert a duration to ticks, rounding up so that waiting that many
+    /// ticks takes at least `duration`
+    pub const fn to_ticks(self, duration: Duration) -> u64 {
+        let ticks = duration.as_nanos() * self.ticks_per_second as u128;
+        let ticks = ticks.div_ceil(NANOS_PER_SECOND);
+        if ticks > u64::MAX as u128 {
+            u64::MAX
+        } else {
+            ticks as u64
+        }
+    }
+}
+
+/// A monotonic clock that's readable from early boot, before it's calibrated.
+/// Timestamps are in platform-specific ticks, which can be converted to time
+/// once the tick rate is known.
+pub trait Clock: Send + Sync {
+    /// The current timestamp, in ticks. This never goes backwards on a given
+    /// processor.
+    fn now(&self) -> u64;
+
+    /// Ticks per second, if the clock has been calibrated
+    fn frequency(&self) -> Option<u64>;
+
+    /// The current time, as an [`Instant`] of this clock
+    fn instant(&self) -> Instant {
+        Instant::from_ticks(self.now())
+    }
+
+    /// The clock's tick rate, if it has been calibrated
+    fn calibration(&self) -> Option<Calibration> {
+        self.frequency().map(Calibration::new)
+    }
+
+    /// Convert a number of ticks to a duration, if the clock has been
+    /// calibrated
+    fn to_duration(&self, ticks: u64) -> Option<Duration> {
+        Some(self.calibration()?.to_duration(ticks))
+    }
+}
+
+impl<T: Clock> Clock for &'static T {
+    fn now(&self) -> u64 {
+        <T as Clock>::now(self)
+    }
+
+    fn frequency(&self) -> Option<u64> {
+        <T as Clock>::frequency(self)
+    }
+}
+
+/// A point in time on a clock, after which something should stop waiting
+#[derive(Clone, Copy)]
+pub struct Deadline<'a> {
+    clock: &'a dyn Clock,
+    at: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    /// Tick rate assumed for clocks that aren't calibrated yet. This is in
+    /// the range of timestamp counters, but timeouts before calibration are
+    /// only approximate.
+    pub const UNCALIBRATED: Calibration = Calibration::new(1_000_000_000);
+
+    /// The deadline `timeout` from now on `clock`
+    pub fn after(clock: &'a dyn Clock, timeout: Duration) -> Self {
+        let calibration = clock.calibration().unwrap_or(Self::UNCALIBRATED);
+        Self {
+            clock,
+            at: clock.instant() + calibration.to_ticks(timeout),
+        }
+    }
+
+    pub fn has_passed(&self) -> bool {
+        self.clock.instant() >= self.at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockClock;
+    use super::*;
+
+    #[test]
+    fn test_calibration_round_trip() {
+        let tsc = Calibration::new(2_500_000_000);
+        assert_eq!(tsc.ticks_to_nanos(5), 2);
+        assert_eq!(tsc.to_ticks(Duration::from_nanos(2)), 5);
+        assert_eq!(tsc.to_ticks(Duration::from_nanos(3)), 8);
+        assert_eq!(tsc.to_duration(2_500_000_000), Duration::from_secs(1));
+        assert_eq!(tsc.ticks_to_nanos(u64::MAX), 7_378_697_629_483_820_646);
+
+        let tick = Calibration::from_period(Duration::from_millis(1));
+        assert_eq!(tick.ticks_per_second(), 1000);
+        assert_eq!(tick.to_ticks(Duration::from_micros(1500)), 2);
+    }
+
+    #[test]
+    fn test_instant_arithmetic() {
+        let start = Instant::from_ticks(100);
+        let end = start + 2_500;
+        assert_eq!(end - start, 2_500);
+        assert_eq!(start - end, 0);
+        assert_eq!(
+            end.duration_since(start, Calibration::new(1_000_000)),
+            Duration::from_micros(2_500)
+        );
+        assert_eq!(
+            Instant::from_ticks(u64::MAX) + 1,
+            Instant::from_ticks(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_deadline() {
+        let clock = MockClock::new(Some(1_000));
+        let deadline = Deadline::after(&clock, Duration::from_millis(5));
+        clock.advance(4);
+        assert!(!deadline.has_passed());
+        clock.advance(1);
+        assert!(deadline.has_passed());
+
+        // Uncalibrated clocks are assumed to tick every nanosecond
+        let clock = MockClock::new(None);
+        let deadline = Deadline::after(&clock, Duration::from_micros(1));
+        clock.advance(999);
+        assert!(!deadline.has_passed());
+        clock.advance(1);
+        assert!(deadline.has_passed());
+    }
+}
@@ -0,0 +1,40 @@
+//! Mock clock for host unit tests.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::Clock;
+
+/// Clock that only moves when it's told to
+#[derive(Debug)]
+pub(crate) struct MockClock {
+    now: AtomicU64,
+    /// Ticks per second, or 0 if not calibrated
+    frequency: u64,
+}
+
+impl MockClock {
+    pub const fn new(frequency: Option<u64>) -> Self {
+        let frequency = match frequency {
+            Some(frequency) => frequency,
+            None => 0,
+        };
+        Self {
+            now: AtomicU64::new(0),
+            frequency,
+        }
+    }
+
+    pub fn advance(&self, ticks: u64) {
+        self.now.fetch_add(ticks, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    fn frequency(&self) -> Option<u64> {
+        (self.frequency != 0).then_some(self.frequency)
+    }
+}
@@ -1,13 +1,117 @@
 //! System topology, mostly for handling multiple processors/cores.
 
 use alloc::boxed::Box;
-use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU16, Ordering};
+
+mod local;
+mod state;
+
+pub(crate) use local::current_processor;
+pub use local::{
+    init_processor_locals, set_local_hooks, LocalHooks, ProcessorLocal, PROCESSOR_LOCAL_INIT,
+};
+pub use platypos_hal_macros::per_processor;
+pub use state::{
+    online_processors, processor_state, transition, ProcessorState, TransitionError,
+    PROCESSOR_STATE_HOOKS,
+};
 
 /// A processor identifier. Regardless of the underlying platform convention,
 /// these are expected to be consecutive values starting from 0, suitable for
 /// array indices.
 pub type ProcessorId = u16;
 
+/// A set of processors, stored as a bitmask. This supports up to 64
+/// processors, which is more than any platform's
+/// [`MAX_PROCESSORS`](Topology::MAX_PROCESSORS) currently is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProcessorSet(u64);
+
+impl ProcessorSet {
+    /// The largest processor ID that can be stored in a set, plus one.
+    pub const CAPACITY: u16 = u64::BITS as u16;
+
+    /// Create an empty set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Create a set containing processors `0..count`.
+    pub const fn first(count: u16) -> Self {
+        assert!(
+            count <= Self::CAPACITY,
+            "too many processors for a ProcessorSet"
+        );
+        if count == Self::CAPACITY {
+            Self(u64::MAX)
+        } else {
+            Self((1 << count) - 1)
+        }
+    }
+
+    /// Create a set containing only `processor`.
+    pub const fn single(processor: ProcessorId) -> Self {
+        Self::empty().with(processor)
+    }
+
+    /// Returns this set with `processor` added.
+    pub const fn with(self, processor: ProcessorId) -> Self {
+        assert!(processor < Self::CAPACITY, "processor ID out of range");
+        Self(self.0 | (1 << processor))
+    }
+
+    /// Returns this set with `processor` removed.
+    pub const fn without(self, processor: ProcessorId) -> Self {
+        assert!(processor < Self::CAPACITY, "processor ID out of range");
+        Self(self.0 & !(1 << processor))
+    }
+
+    /// Returns the processors in both this set and `other`.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Test whether `processor` is in the set.
+    pub const fn contains(&self, processor: ProcessorId) -> bool {
+        processor < Self::CAPACITY && self.0 & (1 << processor) != 0
+    }
+
+    /// Test whether the set is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The number of processors in the set.
+    pub const fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Iterate over the processors in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = ProcessorId> {
+        let mut bits = self.0;
+        core::iter::from_fn(move || {
+            if bits == 0 {
+                None
+            } else {
+                let processor = bits.trailing_zeros() as ProcessorId;
+                bits &= bits - 1;
+                Some(processor)
+            }
+        })
+    }
+}
+
+/// Where a processor sits in the system: hardware thread `thread` of core
+/// `core` in package (socket) `package`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+}
+
 pub trait Topology: Send + Sync {
     /// The maximum number of processors supported on this platform.
     const MAX_PROCESSORS: u16;
@@ -20,6 +124,34 @@ pub trait Topology: Send + Sync {
     /// use of platform-specific fast processor identification (e.g. via special
     /// registers) is encouraged.
     fn current_processor(&self) -> ProcessorId;
+
+    /// Where `processor` is, if it's been detected
+    fn location(&self, processor: ProcessorId) -> Option<Location> {
+        let _ = processor;
+        None
+    }
+}
+
+/// Number of processors the platform detected at boot, or 0 if it hasn't yet
+static DETECTED_PROCESSORS: AtomicU16 = AtomicU16::new(0);
+
+/// Record how many processors the platform has, as detected from firmware
+/// tables at boot. Per-processor data allocated afterwards is sized to fit
+/// them, rather than [`Topology::MAX_PROCESSORS`]. Counts beyond
+/// [`ProcessorSet::CAPACITY`] are capped, since processors past that can't be
+/// tracked.
+pub fn set_processor_count(count: u16) {
+    DETECTED_PROCESSORS.store(count.min(ProcessorSet::CAPACITY), Ordering::Relaxed);
+}
+
+/// How many processors per-processor data needs room for: the number the
+/// platform [detected](set_processor_count), or `TP::MAX_PROCESSORS` if it
+/// hasn't yet.
+pub fn processor_count<TP: Topology>() -> u16 {
+    match DETECTED_PROCESSORS.load(Ordering::Relaxed) {
+        0 => TP::MAX_PROCESSORS,
+        count => count,
+    }
 }
 
 impl<T: Topology> Topology for &'static T {
@@ -28,6 +160,10 @@ impl<T: Topology> Topology for &'static T {
     fn current_processor(&self) -> ProcessorId {
         <T as Topology>::current_processor(self)
     }
+
+    fn location(&self, processor: ProcessorId) -> Option<Location> {
+        <T as Topology>::location(self, processor)
+    }
 }
 
 #[cfg(loom)]
@@ -80,33 +216,101 @@ mod cell {
 
 // TODO: belongs in its own crate
 
+type Storage<T> = Box<[cell::UnsafeCell<Option<T>>]>;
+
+/// A value for each processor, which only that processor can access.
+///
+/// Storage is sized by [`processor_count`] when it's allocated.
+/// [`PerProcessor::new`] allocates it right away, so it has room for
+/// [`Topology::MAX_PROCESSORS`] if the platform hasn't detected how many
+/// processors there are yet. [`PerProcessor::deferred`] waits for the first
+/// access instead, so data created early in boot can still be sized to the
+/// machine it runs on.
 pub struct PerProcessor<T, TP: Topology> {
     topology: TP,
     // Ideally this would just be an array, but that's blocked on https://github.com/rust-lang/rust/issues/60551
     // One advantage of Box, though, is that it prevents PerProcessor itself from being large
-    values: Box<[cell::UnsafeCell<Option<T>>]>,
+    // Boxed again, since slice pointers can't be atomic. Null until allocated.
+    values: AtomicPtr<Storage<T>>,
+    _values: PhantomData<Storage<T>>,
 }
 
 impl<T, TP: Topology> PerProcessor<T, TP> {
     /// Create a new `PerProcessor` with the given CPU topology.
     ///
-    /// This will heap-allocate backing storage based on [`TP::MAX_PROCESSORS`].
+    /// This will heap-allocate backing storage based on [`processor_count`].
     pub fn new(topology: TP) -> Self {
-        let mut values = Vec::with_capacity(TP::MAX_PROCESSORS as usize);
-        for _ in 0..TP::MAX_PROCESSORS {
-            values.push(cell::UnsafeCell::new(None));
-        }
+        let per_processor = Self::deferred(topology);
+        per_processor.values();
+        per_processor
+    }
 
+    /// Create a new `PerProcessor` which allocates its backing storage on
+    /// first use, based on [`processor_count`] at that point. The first
+    /// access may allocate, so per-processor data that the heap allocator
+    /// uses must be created with [`PerProcessor::new`].
+    pub const fn deferred(topology: TP) -> Self {
         Self {
             topology,
-            values: values.into_boxed_slice(),
+            values: AtomicPtr::new(ptr::null_mut()),
+            _values: PhantomData,
         }
     }
 
+    /// Number of processors this has a value for, allocating storage if it
+    /// was deferred
+    pub fn capacity(&self) -> usize {
+        self.values().len()
+    }
+
     /// Get a reference to this processor's cell
+    ///
+    /// # Panics
+    /// If the current processor's ID is beyond the storage's
+    /// [capacity](PerProcessor::capacity)
     pub fn with_mut<R>(&self, f: impl FnOnce(&mut Option<T>) -> R) -> R {
         let idx = self.topology.current_processor() as usize;
-        self.values[idx].with_mut(|ptr| f(unsafe { ptr.as_mut().unwrap() }))
+        let cell = self
+            .values()
+            .get(idx)
+            .expect("processor ID is beyond the processor count");
+        cell.with_mut(|ptr| f(unsafe { ptr.as_mut().unwrap() }))
+    }
+
+    /// The backing storage, allocated if it hasn't been yet
+    fn values(&self) -> &Storage<T> {
+        let mut values = self.values.load(Ordering::Acquire);
+        if values.is_null() {
+            let storage: Storage<T> = (0..processor_count::<TP>())
+                .map(|_| cell::UnsafeCell::new(None))
+                .collect();
+            let new = Box::into_raw(Box::new(storage));
+            values = match self.values.compare_exchange(
+                ptr::null_mut(),
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                Err(existing) => {
+                    // SAFETY: `new` was never shared, since another processor allocated first
+                    drop(unsafe { Box::from_raw(new) });
+                    existing
+                }
+            };
+        }
+        // SAFETY: once allocated, storage is only freed when `self` is dropped
+        unsafe { &*values }
+    }
+}
+
+impl<T, TP: Topology> Drop for PerProcessor<T, TP> {
+    fn drop(&mut self) {
+        let values = *self.values.get_mut();
+        if !values.is_null() {
+            // SAFETY: `values` came from `Box::into_raw`, and nothing else can use it anymore
+            drop(unsafe { Box::from_raw(values) });
+        }
     }
 }
 
@@ -114,6 +318,33 @@ impl<T, TP: Topology> PerProcessor<T, TP> {
 // processor
 unsafe impl<T, TP: Topology> Sync for PerProcessor<T, TP> {}
 
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    struct FixedTopology;
+
+    impl Topology for FixedTopology {
+        const MAX_PROCESSORS: u16 = 8;
+
+        fn current_processor(&self) -> ProcessorId {
+            1
+        }
+    }
+
+    #[test]
+    fn test_deferred_sizing() {
+        let early = PerProcessor::<u32, _>::new(FixedTopology);
+        let deferred = PerProcessor::<u32, _>::deferred(FixedTopology);
+        set_processor_count(2);
+
+        assert_eq!(early.capacity(), 8);
+        assert_eq!(deferred.capacity(), 2);
+        deferred.with_mut(|value| *value = Some(1));
+        deferred.with_mut(|value| assert_eq!(*value, Some(1)));
+    }
+}
+
 #[cfg(all(test, loom))]
 mod test {
     use super::loom::LoomTopology;
@@ -0,0 +1,270 @@
+//! Processor-local statics.
+//!
+//! These are similar to `thread_local!` variables, but with one value per
+//! processor. They're declared with [`per_processor`](super::per_processor),
+//! which doesn't need a [`Topology`](super::Topology) reference. Instead, the
+//! platform registers [`LocalHooks`] once during boot.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicIsize, AtomicPtr, Ordering};
+
+use linkme::distributed_slice;
+
+use super::{ProcessorId, ProcessorSet};
+
+/// Platform functions needed to access processor-local statics
+pub struct LocalHooks {
+    /// Get the ID of the processor this function is called from
+    pub current_processor: fn() -> ProcessorId,
+    /// Test whether interrupts are enabled on the current processor
+    pub interrupts_enabled: fn() -> bool,
+}
+
+static HOOKS: AtomicPtr<LocalHooks> = AtomicPtr::new(ptr::null_mut());
+
+/// Initializers for every processor-local static, registered by
+/// [`per_processor`](super::per_processor)
+#[distributed_slice]
+pub static PROCESSOR_LOCAL_INIT: [fn()] = [..];
+
+/// Maximum number of processors with processor-local storage
+const MAX_PROCESSORS: usize = ProcessorSet::CAPACITY as usize;
+
+/// Register the platform's [`LocalHooks`]. Until this is called, all accesses
+/// use the storage for processor 0, so it must be called before any other
+/// processors are started.
+pub fn set_local_hooks(hooks: &'static LocalHooks) {
+    HOOKS.store(
+        hooks as *const LocalHooks as *mut LocalHooks,
+        Ordering::Release,
+    );
+}
+
+fn hooks() -> Option<&'static LocalHooks> {
+    // SAFETY: HOOKS is either null or set from a `'static` reference
+    unsafe { HOOKS.load(Ordering::Acquire).as_ref() }
+}
+
+/// The current processor, or processor 0 if [`set_local_hooks`] hasn't been
+/// called yet
+pub(crate) fn current_processor() -> ProcessorId {
+    hooks().map_or(0, |hooks| (hooks.current_processor)())
+}
+
+/// Initialize every processor-local static for the current processor. This
+/// should be called as part of per-processor initialization.
+pub fn init_processor_locals() {
+    for init in PROCESSOR_LOCAL_INIT {
+        init();
+    }
+}
+
+/// Storage for a processor-local static. Values are created lazily on first
+/// access if they weren't already created by [`init_processor_locals`].
+///
+/// Borrows are checked at runtime, like a `RefCell`, so conflicting accesses
+/// (for example, an interrupt handler reading a value that the code it
+/// interrupted is modifying) panic instead of causing undefined behavior.
+///
+/// Storage for every possible processor is reserved up front, so large values
+/// should be boxed.
+pub struct ProcessorLocal<T> {
+    init: fn() -> T,
+    slots: [Slot<T>; MAX_PROCESSORS],
+}
+
+struct Slot<T> {
+    /// Borrow state: 0 if unborrowed, -1 if mutably borrowed, and otherwise the
+    /// number of shared borrows
+    borrow: AtomicIsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+/// Releases a borrow of a [`Slot`] when dropped
+struct BorrowGuard<'a> {
+    borrow: &'a AtomicIsize,
+    delta: isize,
+}
+
+impl<T> ProcessorLocal<T> {
+    /// Create processor-local storage with values created by `init`. This
+    /// should generally be used through [`per_processor`](super::per_processor)
+    /// rather than directly.
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            init,
+            slots: [const { Slot::new() }; MAX_PROCESSORS],
+        }
+    }
+
+    /// Create this processor's value, if it doesn't exist yet
+    pub fn init_local(&self) {
+        self.ensure_init(self.slot());
+    }
+
+    /// Access the value for the current processor.
+    ///
+    /// # Panics
+    /// If the value is mutably borrowed.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let slot = self.slot();
+        self.ensure_init(slot);
+
+        let _guard = slot
+            .acquire(1)
+            .expect("processor-local value already mutably borrowed");
+        // SAFETY: the borrow flag guarantees that there are no mutable references to the value
+        let value = unsafe { &*slot.value.get() };
+        f(value.as_ref().unwrap())
+    }
+
+    /// Mutably access the value for the current processor.
+    ///
+    /// # Panics
+    /// If interrupts are enabled, or the value is already borrowed. Keeping
+    /// interrupts disabled ensures that a handler on this processor can't try
+    /// to access the value while it's being modified.
+    pub fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        if let Some(hooks) = hooks() {
+            assert!(
+                !(hooks.interrupts_enabled)(),
+                "interrupts must be disabled to modify processor-local values"
+            );
+        }
+
+        let slot = self.slot();
+        self.ensure_init(slot);
+
+        let _guard = slot
+            .acquire(-1)
+            .expect("processor-local value already borrowed");
+        // SAFETY: the borrow flag guarantees that this is the only reference to the value
+        let value = unsafe { &mut *slot.value.get() };
+        f(value.as_mut().unwrap())
+    }
+
+    fn slot(&self) -> &Slot<T> {
+        &self.slots[current_processor() as usize]
+    }
+
+    fn ensure_init(&self, slot: &Slot<T>) {
+        // SAFETY: once initialized, the value is never reset, so reading the discriminant can't
+        // race with anything
+        if unsafe { (*slot.value.get()).is_some() } {
+            return;
+        }
+
+        let _guard = slot
+            .acquire(-1)
+            .expect("processor-local value accessed during its own initialization");
+        // An interrupt handler may have created the value between the check above and borrowing
+        // the slot, and it may already have changed it since
+        // SAFETY: the borrow flag guarantees that this is the only reference to the value
+        if unsafe { (*slot.value.get()).is_some() } {
+            return;
+        }
+        let value = (self.init)();
+        // SAFETY: the borrow flag guarantees that this is the only reference to the value
+        unsafe { *slot.value.get() = Some(value) };
+    }
+}
+
+// SAFETY: each slot is only ever accessed from its own processor, and accesses are checked by the
+// borrow flag
+unsafe impl<T: Send> Sync for ProcessorLocal<T> {}
+
+impl<T> Slot<T> {
+    const fn new() -> Self {
+        Self {
+            borrow: AtomicIsize::new(0),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Try to borrow this slot: `delta` is 1 for a shared borrow and -1 for a
+    /// mutable borrow. Since slots are processor-local, the only possible
+    /// contention is from code on the same processor, so relaxed ordering is
+    /// sufficient.
+    fn acquire(&self, delta: isize) -> Option<BorrowGuard<'_>> {
+        let acquired = if delta < 0 {
+            self.borrow
+                .compare_exchange(0, -1, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        } else {
+            self.borrow
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+                    (b >= 0).then_some(b + 1)
+                })
+                .is_ok()
+        };
+
+        acquired.then_some(BorrowGuard {
+            borrow: &self.borrow,
+            delta,
+        })
+    }
+}
+
+impl<'a> Drop for BorrowGuard<'a> {
+    fn drop(&mut self) {
+        self.borrow.fetch_sub(self.delta, Ordering::Relaxed);
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::topology::per_processor;
+
+    // Host tests run on several threads, which all look like processor 0, so each test has
+    // statics of its own
+
+    #[per_processor]
+    static SHARED: Cell<u32> = Cell::new(1);
+
+    #[per_processor]
+    static BORROWED_MUT: u32 = 0;
+
+    #[per_processor]
+    static BORROWED: u32 = 0;
+
+    static INIT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    #[per_processor]
+    static LAZY: usize = INIT_CALLS.fetch_add(1, Ordering::Relaxed) + 10;
+
+    #[test]
+    fn test_shared_borrows() {
+        SHARED.with(|outer| {
+            SHARED.with(|inner| inner.set(inner.get() + 1));
+            assert_eq!(outer.get(), 2);
+        });
+        SHARED.with_mut(|value| value.set(5));
+        assert_eq!(SHARED.with(Cell::get), 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "processor-local value already mutably borrowed")]
+    fn test_borrow_while_mutably_borrowed() {
+        BORROWED_MUT.with_mut(|_| BORROWED_MUT.with(|_| ()));
+    }
+
+    #[test]
+    #[should_panic(expected = "processor-local value already borrowed")]
+    fn test_mutably_borrow_while_borrowed() {
+        BORROWED.with(|_| BORROWED.with_mut(|_| ()));
+    }
+
+    #[test]
+    fn test_lazy_init() {
+        assert_eq!(INIT_CALLS.load(Ordering::Relaxed), 0);
+        assert_eq!(LAZY.with(|value| *value), 10);
+        LAZY.init_local();
+        LAZY.with_mut(|value| *value += 1);
+        assert_eq!(LAZY.with(|value| *value), 11);
+        assert_eq!(INIT_CALLS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,152 @@
+//! Processor online/offline state.
+//!
+//! Every processor starts [`Offline`](ProcessorState::Offline). Bringing one up
+//! moves it to [`Booting`](ProcessorState::Booting), and then to
+//! [`Online`](ProcessorState::Online) once the platform can send it
+//! interrupts, or [`Failed`](ProcessorState::Failed) if it never comes up:
+//!
+//! ```text
+//! Offline -> Booting -> Online -> Offline
+//!               |                   ^
+//!               v                   |
+//!            Failed ----------------+
+//! ```
+//!
+//! A failed processor can also be retried by moving it back to `Booting`.
+//! Functions in [`PROCESSOR_STATE_HOOKS`] are called after every transition.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use linkme::distributed_slice;
+
+use super::{ProcessorId, ProcessorSet};
+
+/// Maximum number of processors with tracked state
+const MAX_PROCESSORS: usize = ProcessorSet::CAPACITY as usize;
+
+static STATES: [AtomicU8; MAX_PROCESSORS] =
+    [const { AtomicU8::new(ProcessorState::Offline as u8) }; MAX_PROCESSORS];
+
+/// Functions called with the processor, its old state, and its new state after
+/// every state transition. They may run on any processor, possibly in
+/// interrupt context.
+#[distributed_slice]
+pub static PROCESSOR_STATE_HOOKS: [fn(ProcessorId, ProcessorState, ProcessorState)] = [..];
+
+/// Lifecycle state of a processor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProcessorState {
+    /// Not running kernel code
+    Offline,
+    /// Being brought up, but not ready to receive interrupts yet
+    Booting,
+    /// Running the kernel and able to receive interrupts
+    Online,
+    /// Didn't finish booting
+    Failed,
+}
+
+impl ProcessorState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ProcessorState::Offline,
+            1 => ProcessorState::Booting,
+            2 => ProcessorState::Online,
+            3 => ProcessorState::Failed,
+            _ => unreachable!("invalid processor state {value}"),
+        }
+    }
+
+    /// Whether a processor can move directly from this state to `to`
+    pub const fn can_transition_to(self, to: ProcessorState) -> bool {
+        use ProcessorState::*;
+        matches!(
+            (self, to),
+            (Offline, Booting)
+                | (Booting, Online)
+                | (Booting, Failed)
+                | (Online, Offline)
+                | (Failed, Booting)
+                | (Failed, Offline)
+        )
+    }
+}
+
+impl fmt::Display for ProcessorState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProcessorState::Offline => "offline",
+            ProcessorState::Booting => "booting",
+            ProcessorState::Online => "online",
+            ProcessorState::Failed => "failed",
+        })
+    }
+}
+
+/// Error returned for invalid processor state transitions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransitionError {
+    pub processor: ProcessorId,
+    pub from: ProcessorState,
+    pub to: ProcessorState,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "processor {} can't go from {} to {}",
+            self.processor, self.from, self.to
+        )
+    }
+}
+
+/// The current state of `processor`
+///
+/// # Panics
+/// If `processor` is out of range
+pub fn processor_state(processor: ProcessorId) -> ProcessorState {
+    ProcessorState::from_u8(STATES[usize::from(processor)].load(Ordering::Acquire))
+}
+
+/// Move `processor` to state `to`, returning its previous state
+///
+/// # Errors
+/// If `processor` can't move directly from its current state to `to`. Racing
+/// transitions for the same processor are resolved in some order, so at most
+/// one of them succeeds for each state.
+///
+/// # Panics
+/// If `processor` is out of range
+pub fn transition(
+    processor: ProcessorId,
+    to: ProcessorState,
+) -> Result<ProcessorState, TransitionError> {
+    let state = &STATES[usize::from(processor)];
+    let from = state
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            ProcessorState::from_u8(current)
+                .can_transition_to(to)
+                .then_some(to as u8)
+        })
+        .map(ProcessorState::from_u8)
+        .map_err(|current| TransitionError {
+            processor,
+            from: ProcessorState::from_u8(current),
+            to,
+        })?;
+
+    for hook in PROCESSOR_STATE_HOOKS {
+        hook(processor, from, to);
+    }
+    Ok(from)
+}
+
+/// The set of processors that are currently online
+pub fn online_processors() -> ProcessorSet {
+    (0..ProcessorSet::CAPACITY)
+        .filter(|&p| processor_state(p) == ProcessorState::Online)
+        .fold(ProcessorSet::empty(), ProcessorSet::with)
+}
@@ -1,11 +1,29 @@
 mod entry;
+mod firmware;
+mod madt;
+mod paging;
+mod protect;
+mod tls;
+
+#[cfg(test)]
+mod exception_tests;
 
 pub mod display;
 pub mod mm;
+pub mod vga;
+// Only tests map zeroed or copy-on-write regions so far
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod vmm;
 
 /// The base page size for this platform.
 pub const PAGE_SIZE: usize = 4096;
 
+/// Serial port for [`crate::earlycon`] output. This is the same COM1 port that
+/// tracing uses once it's initialized.
+// SAFETY: COM1 is always at 0x3f8 on PC-compatible systems
+pub static EARLY_SERIAL: hal_impl::EarlySerialPort =
+    unsafe { hal_impl::EarlySerialPort::new(0x3f8) };
+
 // HAL bindings - other parts of the kernel need to know which HAL
 // implementation they're using (mostly to put it in static vars)
 pub use platypos_hal_x86_64 as hal_impl;
@@ -1,4 +1,6 @@
 use core::convert::Infallible;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 use bootloader_api::info::{FrameBuffer, PixelFormat};
 use embedded_graphics::draw_target::DrawTarget;
@@ -10,6 +12,10 @@ pub type Display = FrameBufferTarget<'static>;
 pub type Color = Bgr888;
 pub type Error = Infallible;
 
+/// The boot framebuffer, so that the panic screen can draw to it no matter
+/// who owns the [`Display`]
+static BOOT_FRAMEBUFFER: AtomicPtr<FrameBuffer> = AtomicPtr::new(ptr::null_mut());
+
 pub struct FrameBufferTarget<'a> {
     inner: &'a mut FrameBuffer,
 }
@@ -20,6 +26,30 @@ impl<'a> FrameBufferTarget<'a> {
     }
 }
 
+impl FrameBufferTarget<'static> {
+    /// Create a display for the framebuffer provided by the bootloader, and
+    /// register it for use by [`steal`].
+    pub fn for_boot(buffer: &'static mut FrameBuffer) -> Display {
+        let buffer: *mut FrameBuffer = buffer;
+        BOOT_FRAMEBUFFER.store(buffer, Ordering::Release);
+        // SAFETY: `buffer` came from a unique reference, and is only aliased by `steal`
+        FrameBufferTarget::new(unsafe { &mut *buffer })
+    }
+}
+
+/// Get a second handle to the boot framebuffer, if there is one.
+///
+/// # Safety
+/// This aliases the [`Display`] returned by [`FrameBufferTarget::for_boot`].
+/// It's only meant for taking over the screen when nothing else will draw to it
+/// again, such as during a panic.
+pub unsafe fn steal() -> Option<Display> {
+    BOOT_FRAMEBUFFER
+        .load(Ordering::Acquire)
+        .as_mut()
+        .map(FrameBufferTarget::new)
+}
+
 impl<'a> DrawTarget for FrameBufferTarget<'a> {
     // TODO: this assumes RGB byte support as the default and converts on the fly if
     // not
@@ -33,9 +63,15 @@ impl<'a> DrawTarget for FrameBufferTarget<'a> {
     {
         let info = self.inner.info();
         let stride: i32 = info.stride.try_into().expect("stride larger than i32::MAX");
+        let (width, height) = (info.width as i32, info.height as i32);
         let buffer = self.inner.buffer_mut();
 
         for Pixel(coord, color) in pixels.into_iter() {
+            // Draw targets are expected to ignore out-of-bounds pixels
+            if coord.x < 0 || coord.y < 0 || coord.x >= width || coord.y >= height {
+                continue;
+            }
+
             let pixel_offset = coord.y * stride + coord.x;
             let byte_offset = pixel_offset as usize * info.bytes_per_pixel;
             match info.pixel_format {
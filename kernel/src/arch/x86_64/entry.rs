@@ -1,44 +1,101 @@
 //! Entry point for x86_64 systems
 
-use core::fmt;
 use core::mem::MaybeUninit;
 
+use alloc::vec::Vec;
+
 use {platypos_hal_x86_64 as hal_impl, platypos_ktrace as ktrace};
 
-use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
 use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
-
-use crate::arch::mm::MemoryAccess;
-use crate::mm::map::Region;
-use crate::mm::{heap_allocator, root_allocator};
+use platypos_common::sync::Global;
+use platypos_hal::interrupts::Controller as _;
+use platypos_hal::topology::ProcessorState;
+
+use crate::arch::mm::{MemoryAccess, PHYSICAL_MAP_BASE};
+use crate::boot_phase::{self, Entry, InterruptsReady};
+use crate::boot_time::{self, Phase};
+use crate::milestone::{self, Milestone};
+use crate::mm::map::{self, MemoryMapBuilder, Region};
+use crate::mm::{bootmem, heap_allocator, layout, root_allocator};
 use crate::{trace, BootArgs};
 
 use super::display::FrameBufferTarget;
+use super::protect;
+
+/// Maximum number of distinct memory regions, after merging
+const MAX_MEMORY_REGIONS: usize = 128;
+
+/// The bootstrap processor's APIC timer, which drives the scheduler tick
+static TIMER: Global<hal_impl::interrupts::ApicTimer> = Global::new();
+
+crate::power::shutdown_hook!(Timers, "scheduler tick", stop_timer);
+crate::power::shutdown_hook!(Interrupts, "interrupts", disable_interrupts);
+
+fn stop_timer() {
+    if let Some(timer) = TIMER.try_get() {
+        timer.stop();
+    }
+}
+
+fn disable_interrupts() {
+    hal_impl::interrupts::Controller.force_disable();
+}
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
-    config.mappings.physical_memory = Some(bootloader_api::config::Mapping::Dynamic);
+    // Map physical memory at the start of its layout region, rather than wherever the bootloader
+    // finds space
+    config.mappings.physical_memory = Some(bootloader_api::config::Mapping::FixedAddress(
+        PHYSICAL_MAP_BASE as u64,
+    ));
     config
 };
 
 /// Entry point called by the bootloader
 fn start(info: &'static mut BootInfo) -> ! {
-    unsafe {
-        heap_allocator::init();
-    }
+    boot_time::record(Phase::LoaderHandoff);
+    let entry = Entry::take().expect("Kernel entered twice");
+    // SAFETY: nothing has allocated yet
+    let heap = unsafe { heap_allocator::init(entry) };
+    platypos_hal::topology::set_local_hooks(&hal_impl::topology::LOCAL_HOOKS);
+    // The bootstrap processor goes online in `init_local`
+    platypos_hal::topology::transition(0, ProcessorState::Booting).unwrap();
+
+    let display = info.framebuffer.as_mut().map(FrameBufferTarget::for_boot);
+    #[cfg(feature = "splash")]
+    let display = display.map(|mut display| {
+        crate::screen::draw_splash(&mut display).unwrap();
+        display
+    });
 
-    let ic = hal_impl::interrupts::init();
+    // There's no I/O APIC driver yet, so legacy IRQs have to go through the PIC
+    let ic = hal_impl::interrupts::init(hal_impl::interrupts::LegacyRouting::Pic);
 
     trace::init(
         unsafe { hal_impl::SerialPort::new(0x3f8) },
         &crate::arch::hal_impl::topology::INSTANCE,
         ic,
+        &heap,
     );
+    boot_time::trace_pre_kernel();
     trace::flush();
 
     let _span = tracing::info_span!("start").entered();
     trace::flush();
 
+    // Neither the serial port nor the hardware the HAL drives has a driver of its own to claim it
+    if let Err(err) = crate::resources::claim_platform() {
+        tracing::warn!("Could not claim platform resources: {}", err);
+    }
+    if let Err(err) = crate::resources::claim_ports(0x3f8..0x400, "com1") {
+        tracing::warn!("Could not claim the serial port: {}", err);
+    }
+
+    // Only the heap is needed for TLS blocks, so set them up early to make thread-locals usable
+    // by as much of the kernel as possible
+    super::tls::init(info.tls_template.into_option(), &heap);
+    super::tls::init_local();
+
     let version = info.api_version;
     tracing::info!(
         "Booting from bootloader v{}.{}.{}{}",
@@ -52,28 +109,32 @@ fn start(info: &'static mut BootInfo) -> ! {
         }
     );
 
-    tracing::info!("Memory Regions:");
-    trace::flush();
     // The bootloader doesn't combine adjacent functionally-equivalent regions, so
     // do it here
     // It also marks UEFI runtime service memory as usable...
-    // TODO: accumulate into a vec
-    let last = info.memory_regions.iter().cloned().reduce(|prev, next| {
-        if prev.kind == next.kind && prev.end == next.start {
-            // Combine!
-            MemoryRegion {
-                start: prev.start,
-                end: next.end,
-                kind: prev.kind,
-            }
-        } else {
-            // Can't merge, report the previous region
-            log_region(prev);
-            next
+    let mut memory_map = MemoryMapBuilder::<MAX_MEMORY_REGIONS>::new();
+    for region in info.memory_regions.iter() {
+        let region = Region::from(region);
+        if let Err(err) = memory_map.try_push(region) {
+            tracing::warn!("Ignoring memory region {}: {}", region, err);
         }
-    });
-    if let Some(last) = last {
-        log_region(last);
+    }
+    // Anything dropped or merged wrongly above would let the allocator hand out memory the
+    // loader reserved
+    let loader_map = info
+        .memory_regions
+        .iter()
+        .map(Region::from)
+        .collect::<Vec<_>>();
+    map::sanity_check(&loader_map, memory_map.regions());
+    bootmem::init(memory_map.regions());
+
+    layout::log_layout();
+    super::paging::init();
+
+    tracing::info!("Memory Regions:");
+    for region in memory_map.regions() {
+        tracing::info!(" - {}", region);
     }
     trace::flush();
 
@@ -85,10 +146,21 @@ fn start(info: &'static mut BootInfo) -> ! {
             info.physical_memory_offset.into_option().unwrap() as usize as *mut MaybeUninit<u8>
         )
     };
+    super::firmware::report(
+        info.api_version,
+        info.rsdp_addr.into_option(),
+        &info.memory_regions,
+        access,
+    );
+    // Before anything sizes per-processor data
+    super::madt::detect(info.rsdp_addr.into_option(), access);
     trace::flush();
 
+    // The root allocator builds its first bookkeeping in scratch memory, and frees it afterwards
+    let scratch = bootmem::allocate(root_allocator::SCRATCH_PAGES, 1)
+        .expect("Could not allocate root allocator scratch space");
     // TODO: add kernel?
-    let reserved = &[];
+    let handoff = bootmem::handoff();
 
     tracing::debug!("Before allocator init");
     trace::flush();
@@ -96,81 +168,139 @@ fn start(info: &'static mut BootInfo) -> ! {
     let root_allocator = root_allocator::init(
         &access,
         ic,
-        info.memory_regions.iter().map(Region::from), /* TODO: end of failing region seems off,
-                                                       * but also start isn't page-aligned?
-                                                       * right after bootloader */
-        reserved,
+        memory_map.regions().iter().copied(), /* TODO: end of failing region seems off,
+                                               * but also start isn't page-aligned?
+                                               * right after bootloader */
+        handoff.used(),
+        scratch,
     )
-    .expect("Root allocator initialization failed");
+    .unwrap_or_else(|err| panic!("Root allocator initialization failed: {err}"));
     trace::flush();
 
     tracing::debug!("After allocator init");
     trace::flush();
 
-    heap_allocator::enable_expansion(root_allocator);
+    // Before anything else can allocate the crash dump's memory
+    crate::crash_dump::init(access, root_allocator, memory_map.regions());
+    trace::flush();
+
+    let memory = heap_allocator::enable_expansion(root_allocator, heap);
+
+    if let Some(addr) = info.ramdisk_addr.into_option() {
+        // SAFETY: the bootloader maps the ramdisk at `addr` and never reuses that memory
+        let data = unsafe {
+            core::slice::from_raw_parts(addr as usize as *const u8, info.ramdisk_len as usize)
+        };
+        if let Err(err) = crate::initrd::init(data) {
+            tracing::warn!("Ignoring initrd: {}", err);
+        }
+    }
+
+    crate::smp::init(ic, &memory);
+
+    protect::enforce(access).expect("Could not protect kernel image");
+    debug_assert!(
+        protect::verify(access),
+        "W+X mappings remain after protection"
+    );
+    super::vmm::init(access, root_allocator, ic).expect("Could not set up copy-on-write mappings");
+    milestone::reached(Milestone::MemoryInitComplete);
+    boot_time::record(Phase::MemoryInit);
+    trace::flush();
 
     // Initialize the local interrupt controller after setting up memory allocation,
     // in case there's any dynamic data
     hal_impl::interrupts::init_local();
+    platypos_hal::topology::init_processor_locals();
+    crate::trace::announce_processor();
+    let timer = hal_impl::interrupts::calibrate_timer();
+    let interrupts = boot_phase::advance::<InterruptsReady>(memory);
+    tracing::info!("APIC timer frequency: {} Hz", timer.frequency());
+    crate::trace::announce_calibration();
+    boot_time::record(Phase::ApicInit);
+    // Application processors aren't started yet, so the bootstrap processor is the only one
+    milestone::reached(Milestone::ApsOnline);
+    boot_time::record(Phase::ApBringUp);
+
+    crate::sched::init(ic);
+    crate::sched::drift::init();
+    crate::workqueue::init();
+    crate::interrupt_stats::init();
+    crate::drivers::fw_cfg::init(ic);
+    crate::fault::init();
+    crate::panic::configure();
+    crate::trace::configure();
+    crate::interrupt_stats::configure();
+    crate::profiler::init();
+    crate::debug_keys::init();
+    crate::drivers::ps2::init(ic);
+    crate::drivers::virtio_blk::init(access, root_allocator, ic);
+    hal_impl::interrupts::set_timer_handler(crate::sched::tick);
+    TIMER
+        .init(timer)
+        .arm_periodic(crate::sched::TICK)
+        .expect("Could not start the scheduler tick");
 
     tracing::debug!("Platform-specific initialization complete, entering kmain");
     trace::flush();
 
     let args = BootArgs {
-        display: info.framebuffer.as_mut().map(FrameBufferTarget::new),
+        display,
         memory_access: access,
         root_allocator,
         interrupt_controller: ic,
+        phase: interrupts,
     };
 
     crate::kmain(args);
 }
 
-fn log_region(region: MemoryRegion) {
-    let size = region.end - region.start;
-    tracing::info!(
-        " - {:#012x} - {:#012x} {} ({} bytes =~ {} KiB =~ {} MiB)",
-        region.start,
-        region.end,
-        DisplayRegion(region.kind),
-        size,
-        size / 1024,
-        size / 1024 / 1024
-    );
-}
+entry_point!(start, config = &BOOTLOADER_CONFIG);
 
-struct DisplayRegion(MemoryRegionKind);
-
-impl fmt::Display for DisplayRegion {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.0 {
-            MemoryRegionKind::UnknownBios(v) => write!(f, "Unknown BIOS type {v}"),
-            MemoryRegionKind::UnknownUefi(v) => match v {
-                // See https://uefi.org/specs/ACPI/6.4/15_System_Address_Map_Interfaces/uefi-getmemorymap-boot-services-function.html
-                0 => write!(f, "UEFI Reserved"),
-                1 => write!(f, "UEFI Loader (Code)"),
-                2 => write!(f, "UEFI Loader (Data)"),
-                3 => write!(f, "UEFI Boot Services (Code)"),
-                4 => write!(f, "UEFI Boot Services (Data)"),
-                5 => write!(f, "UEFI Runtime Services (Code)"),
-                6 => write!(f, "UEFI Runtime Services (Data)"),
-                7 => write!(f, "Conventional memory"),
-                8 => write!(f, "Unusable"),
-                9 => write!(f, "ACPI (reclaimable)"),
-                10 => write!(f, "ACPI NVS"),
-                11 => write!(f, "Memory-Mapped I/O"),
-                12 => write!(f, "Memory-Mapped I/O Port Space"),
-                13 => write!(f, "UEFI PAL Code"),
-                14 => write!(f, "Persistent memory"),
-                other => write!(f, "Unknown UEFI type {other}"),
-            },
-            other => write!(f, "{other:?}"),
-        }
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use ktest::*;
+    use platypos_hal::time::Clock;
+
+    use super::*;
+    use crate::sched;
+
+    #[ktest::test]
+    fn test_oneshot_timer() {
+        let timer = TIMER.get();
+        let clock = &hal_impl::time::INSTANCE;
+        let calibration = clock.calibration().unwrap();
+        let spin = |duration| {
+            let start = clock.instant();
+            while clock.instant().duration_since(start, calibration) < duration {
+                core::hint::spin_loop();
+            }
+        };
+
+        // Stop the scheduler tick, and let any interrupt it already raised arrive
+        timer.stop();
+        spin(Duration::from_millis(2));
+
+        // Each timer interrupt advances the scheduler clock
+        let armed = sched::now();
+        timer.arm_oneshot(Duration::from_millis(10)).unwrap();
+        spin(Duration::from_millis(2));
+        let early = sched::now();
+        spin(Duration::from_millis(50));
+        let fired = sched::now();
+        // A one-shot timer doesn't fire again
+        spin(Duration::from_millis(50));
+        let after = sched::now();
+
+        timer.arm_periodic(sched::TICK).unwrap();
+        ktassert_eq!(early.ticks_since(armed), 0);
+        ktassert_eq!(fired.ticks_since(armed), 1);
+        ktassert_eq!(after.ticks_since(armed), 1);
     }
 }
 
-entry_point!(start, config = &BOOTLOADER_CONFIG);
-
 /*
 
 Physical memory management
@@ -221,8 +351,6 @@ Physical memory access
 - platform_common should provide a default implementation for platforms where all physical memory is mapped
 
 Other stuff:
-- x86_64 bootloader crate (and probably other platforms) can pass TLS (thread-local storage) info to kernel
-- see if that can be repurposed as CPU-local storage (need to figure out how it's accessed)
 - use thingbuf to send info from interrupt handlers to regular (or high-priority even) tasks
 
 */
@@ -0,0 +1,125 @@
+//! Self-tests for CPU exception handling.
+//!
+//! Each test deliberately raises an exception and checks that the handler
+//! reports the right fault. Kernel-mode faults are fatal by design, so these
+//! rely on `#[should_panic]` and ktest's panic recovery.
+//!
+//! Recovery abandons the faulting code without undoing its side effects, so
+//! anything a test changes (like unmapping its guard page) stays changed.
+
+use core::arch::asm;
+
+use ktest::*;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::paging::{Mapper, Page, Size4KiB, Translate};
+use x86_64::VirtAddr;
+
+use super::mm::MemoryAccess;
+use super::paging::active_mapper;
+
+/// Page-aligned static to unmap and use as a guard page
+#[repr(C, align(4096))]
+struct GuardPage([u8; 4096]);
+
+static mut GUARD_PAGE: GuardPage = GuardPage([0; 4096]);
+
+#[ktest::test]
+#[should_panic(expected = "CPU exception: divide error at")]
+fn test_divide_error() {
+    // SAFETY: deliberately divides by zero
+    unsafe {
+        asm!(
+            "div {divisor:e}",
+            divisor = in(reg) 0u32,
+            inout("eax") 1u32 => _,
+            inout("edx") 0u32 => _,
+            options(nomem, nostack),
+        );
+    }
+}
+
+#[ktest::test]
+#[should_panic(expected = "CPU exception: invalid opcode at")]
+fn test_invalid_opcode() {
+    // SAFETY: deliberately executes an undefined instruction
+    unsafe { asm!("ud2", options(nomem, nostack)) };
+}
+
+#[ktest::test]
+#[should_panic(expected = "CPU exception: page fault accessing 0x0 at")]
+fn test_unmapped_page_fault() {
+    // SAFETY: the page tables aren't modified while the mapper exists
+    let mapper = unsafe { active_mapper(MemoryAccess::get()) };
+    ktassert!(mapper.translate_addr(VirtAddr::zero()).is_none());
+
+    // SAFETY: deliberately reads the unmapped null page. This uses assembly because a null
+    // dereference in Rust is undefined behavior, not a guaranteed fault.
+    unsafe {
+        asm!(
+            "mov {tmp}, qword ptr [{addr}]",
+            addr = in(reg) 0u64,
+            tmp = out(reg) _,
+            options(nostack, readonly),
+        );
+    }
+}
+
+#[ktest::test]
+#[should_panic(expected = "CPU exception: page fault accessing 0x")]
+fn test_guard_page_fault() {
+    // SAFETY: GUARD_PAGE isn't used anywhere else
+    let addr = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(GUARD_PAGE) });
+    let page = Page::<Size4KiB>::containing_address(addr);
+
+    // SAFETY: only GUARD_PAGE is unmapped, and nothing else references it
+    let mut mapper = unsafe { active_mapper(MemoryAccess::get()) };
+    match mapper.unmap(page) {
+        Ok((_, flush)) => flush.flush(),
+        Err(err) => {
+            tracing::error!("Could not unmap guard page: {:?}", err);
+            return Outcome::Fail;
+        }
+    }
+
+    // SAFETY: deliberately writes to the unmapped guard page
+    unsafe {
+        asm!(
+            "mov byte ptr [{addr}], 1",
+            addr = in(reg) addr.as_u64(),
+            options(nostack),
+        );
+    }
+}
+
+/// Alignment checking only applies at CPL 3, so the kernel can make unaligned
+/// accesses even with it enabled. This test has to change once there's user
+/// mode to raise #AC from.
+#[ktest::test]
+fn test_alignment_check_ignored_in_kernel_mode() {
+    let buf = [0u8; 16];
+    let original = Cr0::read();
+
+    // SAFETY: setting CR0.AM and RFLAGS.AC only affects CPL 3 accesses, and both are restored
+    // afterwards
+    let value = unsafe {
+        Cr0::write(original | Cr0Flags::ALIGNMENT_MASK);
+        let value: u64;
+        asm!(
+            "pushfq",
+            "or qword ptr [rsp], {ac}",
+            "popfq",
+            "mov {value}, qword ptr [{addr}]",
+            "pushfq",
+            "and qword ptr [rsp], {not_ac}",
+            "popfq",
+            ac = const 1i64 << 18,
+            not_ac = const !(1i64 << 18),
+            addr = in(reg) buf.as_ptr().add(1),
+            value = out(reg) value,
+        );
+        Cr0::write(original);
+        value
+    };
+
+    ktassert_eq!(value, 0);
+}
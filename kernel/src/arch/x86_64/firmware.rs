@@ -0,0 +1,94 @@
+//! What the loader tells us about the firmware.
+//!
+//! The bootloader exits UEFI boot services before jumping to the kernel, and
+//! doesn't pass along the UEFI system table, so the firmware vendor string and
+//! Secure Boot variable aren't available. The next best vendor information is
+//! the OEM ID and revision in the ACPI RSDP, which the firmware writes.
+
+use core::mem::size_of;
+use core::str;
+
+use bootloader_api::config::ApiVersion;
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+
+use crate::boot_env::{BootEnvironment, MemoryMapHash, SecureBoot};
+use crate::fmt::HexDump;
+
+use super::mm::MemoryAccess;
+
+/// The start of the ACPI Root System Description Pointer, which is the same
+/// for every ACPI revision
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    _checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+}
+
+/// Report the boot environment, based on what the loader passed in. These are
+/// taken separately rather than as a whole `BootInfo`, since the framebuffer
+/// is already borrowed.
+pub(super) fn report(
+    version: ApiVersion,
+    rsdp_addr: Option<u64>,
+    regions: &[MemoryRegion],
+    access: &MemoryAccess,
+) {
+    let loader = alloc::format!(
+        "bootloader {}.{}.{}{}",
+        version.version_major(),
+        version.version_minor(),
+        version.version_patch(),
+        if version.pre_release() { "-pre" } else { "" }
+    );
+
+    let rsdp = rsdp_addr.map(|addr| {
+        // SAFETY: the bootloader found an RSDP at `addr`, and all physical memory is mapped
+        unsafe { ((access.physical_offset() + addr as usize) as *const Rsdp).read_unaligned() }
+    });
+    if let Some(rsdp) = rsdp.filter(|rsdp| &rsdp.signature != b"RSD PTR ") {
+        // SAFETY: `Rsdp` is plain bytes, with no padding since it's packed
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&rsdp as *const Rsdp as *const u8, size_of::<Rsdp>())
+        };
+        tracing::warn!(
+            "Ignoring RSDP with a bad signature:\n{}",
+            HexDump::new(bytes)
+        );
+    }
+    let rsdp = rsdp.filter(|rsdp| &rsdp.signature == b"RSD PTR ");
+    let oem_id = rsdp.map(|rsdp| rsdp.oem_id);
+    let vendor = oem_id
+        .as_ref()
+        .and_then(|id| str::from_utf8(id).ok())
+        .map_or("unknown", str::trim_end);
+
+    let memory_map = MemoryMapHash::new(regions.iter().map(|region| {
+        let kind = match region.kind {
+            MemoryRegionKind::Usable => 0,
+            MemoryRegionKind::Bootloader => 1,
+            MemoryRegionKind::UnknownUefi(typ) => 2 << 32 | u64::from(typ),
+            MemoryRegionKind::UnknownBios(typ) => 3 << 32 | u64::from(typ),
+            _ => u64::MAX,
+        };
+        (region.start, region.end, kind)
+    }));
+
+    let env = BootEnvironment {
+        loader: &loader,
+        vendor,
+        revision: rsdp.map_or(0, |rsdp| rsdp.revision.into()),
+        secure_boot: SecureBoot::Unknown,
+        memory_map,
+    };
+    tracing::info!(
+        "Boot environment: {}, firmware {} (ACPI revision {}), memory map {}",
+        env.loader,
+        env.vendor,
+        env.revision,
+        env.memory_map
+    );
+    env.report();
+}
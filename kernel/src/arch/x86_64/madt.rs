@@ -0,0 +1,167 @@
+//! Counting processors from the ACPI MADT.
+//!
+//! The Multiple APIC Description Table has a local APIC or x2APIC entry for
+//! every processor the firmware knows about. Processors that are neither
+//! enabled nor online-capable can never come up, so they aren't counted. The
+//! count sizes per-processor data (see [`topology::set_processor_count`]);
+//! without an MADT, that falls back to the platform maximum.
+//!
+//! This only reads as much of ACPI as it takes to find the MADT: the RSDP,
+//! then the XSDT on ACPI 2.0 and later or the RSDT before that.
+
+use core::slice;
+
+use platypos_hal::topology;
+
+use super::mm::MemoryAccess;
+
+/// Size of the header every system description table starts with
+const SDT_HEADER_LEN: usize = 36;
+
+/// Where interrupt controller entries start in the MADT, after the header,
+/// local APIC address, and flags
+const MADT_ENTRIES: usize = SDT_HEADER_LEN + 8;
+
+/// Processor Local APIC entry type
+const LOCAL_APIC: u8 = 0;
+
+/// Processor Local x2APIC entry type
+const LOCAL_X2APIC: u8 = 9;
+
+/// Local APIC flags: the processor is usable now, or can be brought online
+const ENABLED: u32 = 1 << 0;
+const ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Find the MADT through the RSDP at `rsdp_addr`, and record how many
+/// processors it lists
+pub(super) fn detect(rsdp_addr: Option<u64>, access: &MemoryAccess) {
+    // SAFETY: the bootloader found the RSDP at `rsdp_addr`, and all physical memory is mapped
+    let madt = rsdp_addr.and_then(|addr| unsafe { find_madt(addr, access) });
+    let Some(madt) = madt else {
+        tracing::info!("No MADT, so assuming the maximum number of processors");
+        return;
+    };
+    match count_processors(madt) {
+        0 => tracing::warn!("The MADT doesn't list any usable processors"),
+        count => {
+            tracing::info!("The MADT lists {} processors", count);
+            topology::set_processor_count(count);
+        }
+    }
+}
+
+/// Find the MADT through the RSDP at `rsdp_addr`, if its checksum is valid
+///
+/// # Safety
+/// `rsdp_addr` must be the physical address of the RSDP
+unsafe fn find_madt(rsdp_addr: u64, access: &MemoryAccess) -> Option<&'static [u8]> {
+    let rsdp = physical(access, rsdp_addr, 36);
+    if &rsdp[..8] != b"RSD PTR " {
+        return None;
+    }
+    // ACPI 2.0 added the XSDT, which has 64-bit table addresses
+    let (root, pointer_len) = match rsdp[15] {
+        0 => (u64::from(read_u32(rsdp, 16)), 4),
+        _ => (read_u64(rsdp, 24), 8),
+    };
+
+    let root = table(access, root);
+    let pointers = root.get(SDT_HEADER_LEN..)?;
+    let madt = pointers
+        .chunks_exact(pointer_len)
+        .map(|pointer| match pointer_len {
+            4 => u64::from(read_u32(pointer, 0)),
+            _ => read_u64(pointer, 0),
+        })
+        .map(|address| table(access, address))
+        .find(|table| &table[..4] == b"APIC")?;
+
+    if madt.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+        tracing::warn!("Ignoring MADT with a bad checksum");
+        return None;
+    }
+    Some(madt)
+}
+
+/// Count the enabled or online-capable processors in `madt`
+fn count_processors(madt: &[u8]) -> u16 {
+    let mut entries = madt.get(MADT_ENTRIES..).unwrap_or_default();
+    let mut count = 0u16;
+    while let [kind, len, ..] = *entries {
+        let len = usize::from(len);
+        // Every entry has at least its type and length
+        let Some(entry) = entries.get(..len).filter(|_| len >= 2) else {
+            break;
+        };
+        let flags = match kind {
+            LOCAL_APIC if len >= 8 => Some(read_u32(entry, 4)),
+            LOCAL_X2APIC if len >= 12 => Some(read_u32(entry, 8)),
+            _ => None,
+        };
+        if flags.is_some_and(|flags| flags & (ENABLED | ONLINE_CAPABLE) != 0) {
+            count = count.saturating_add(1);
+        }
+        entries = &entries[len..];
+    }
+    count
+}
+
+/// The system description table at physical address `address`, including its
+/// header
+///
+/// # Safety
+/// `address` must be the physical address of a system description table
+unsafe fn table(access: &MemoryAccess, address: u64) -> &'static [u8] {
+    let header = physical(access, address, SDT_HEADER_LEN);
+    physical(access, address, read_u32(header, 4) as usize)
+}
+
+/// `len` bytes of physical memory, starting at `address`
+///
+/// # Safety
+/// The memory must be firmware tables, which nothing writes to
+unsafe fn physical(access: &MemoryAccess, address: u64, len: usize) -> &'static [u8] {
+    let start = access.physical_offset() + address as usize;
+    slice::from_raw_parts(start as *const u8, len)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_count_processors() {
+        let mut madt = Vec::from([0u8; MADT_ENTRIES]);
+        // Enabled, disabled, and online-capable local APICs
+        for flags in [ENABLED, 0, ONLINE_CAPABLE] {
+            madt.extend_from_slice(&[LOCAL_APIC, 8, 0, 0]);
+            madt.extend_from_slice(&flags.to_le_bytes());
+        }
+        // An I/O APIC, which isn't a processor
+        madt.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // An enabled x2APIC
+        madt.extend_from_slice(&[LOCAL_X2APIC, 16, 0, 0, 0, 1, 0, 0]);
+        madt.extend_from_slice(&ENABLED.to_le_bytes());
+        madt.extend_from_slice(&[0; 4]);
+        ktassert_eq!(count_processors(&madt), 3);
+
+        // A truncated entry ends the list
+        madt.extend_from_slice(&[LOCAL_APIC, 8, 0]);
+        ktassert_eq!(count_processors(&madt), 3);
+        madt.truncate(MADT_ENTRIES);
+        madt.extend_from_slice(&[LOCAL_APIC, 0]);
+        ktassert_eq!(count_processors(&madt), 0);
+    }
+}
@@ -3,6 +3,7 @@ use core::slice;
 
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
 
+use crate::mm::layout::{self, Region as LayoutRegion};
 use crate::mm::map::{Kind, Region};
 use crate::prelude::*;
 use platypos_common::sync::Global;
@@ -25,6 +26,33 @@ impl From<&MemoryRegion> for Region {
     }
 }
 
+/// Size of one level 4 page table entry's worth of address space (512 GiB).
+/// Layout regions are aligned to this, so they never share level 4 entries.
+/// With 5-level paging, they all share the last level 5 entry (see
+/// [`super::paging`]).
+const L4_ENTRY_SIZE: usize = 1 << 39;
+
+/// Start of the physical memory map: the beginning of the upper half of the
+/// address space
+pub const PHYSICAL_MAP_BASE: usize = 0xffff_8000_0000_0000;
+
+/// Bounds of each kernel address space region. The physical memory map covers
+/// 64 TiB, the most physical memory supported with 4-level paging.
+///
+/// These are the same with 5-level paging: every region is in the top 128 TiB,
+/// which is canonical either way, and the loader maps physical memory at a
+/// fixed offset that doesn't depend on the paging mode.
+pub const fn region_bounds(region: LayoutRegion) -> VirtualAddressRange {
+    let (start, entries) = match region {
+        LayoutRegion::PhysicalMap => (PHYSICAL_MAP_BASE, 128),
+        LayoutRegion::Mmio => (0xffff_c000_0000_0000, 8),
+        LayoutRegion::Heap => (0xffff_c800_0000_0000, 8),
+        LayoutRegion::PerProcessor => (0xffff_d000_0000_0000, 2),
+        LayoutRegion::Stacks => (0xffff_d800_0000_0000, 2),
+    };
+    VirtualAddressRange::from_start_size(VirtualAddress::new(start), entries * L4_ENTRY_SIZE)
+}
+
 /// Accessor for physical memory. The kernel cannot assume that physical memory
 /// is mapped into its address space. Instead, it uses this type to create
 /// temporary or permanent mappings.
@@ -36,12 +64,26 @@ pub struct MemoryAccess {
 unsafe impl Send for MemoryAccess {}
 unsafe impl Sync for MemoryAccess {}
 
+static GLOBAL: Global<MemoryAccess> = Global::new();
+
 impl MemoryAccess {
     pub(super) unsafe fn init(base: *mut MaybeUninit<u8>) -> &'static Self {
-        static GLOBAL: Global<MemoryAccess> = Global::new();
+        debug_assert_eq!(
+            base.addr(),
+            PHYSICAL_MAP_BASE,
+            "physical memory is not mapped at the start of its layout region"
+        );
         GLOBAL.init(MemoryAccess::new(base))
     }
 
+    /// Get the global `MemoryAccess` instance
+    ///
+    /// # Panics
+    /// If called before platform initialization sets up memory access
+    pub fn get() -> &'static Self {
+        GLOBAL.get()
+    }
+
     unsafe fn new(base: *mut MaybeUninit<u8>) -> Self {
         Self { base }
     }
@@ -85,7 +127,20 @@ impl MemoryAccess {
             .start_address()
             .as_usize()
             .try_into()
-            .map_err(|_| Error::new(ErrorKind::AddressOutOfBounds))?;
-        Ok(self.base.offset(start_offset))
+            .map_err(|_| Error::new(MmError::AddressOutOfBounds))?;
+        let base = self.base.offset(start_offset);
+        layout::debug_assert_in_region(
+            LayoutRegion::PhysicalMap,
+            VirtualAddressRange::from_start_size(
+                VirtualAddress::new(base.addr()),
+                range.size() * PAGE_SIZE,
+            ),
+        );
+        Ok(base)
+    }
+
+    /// Virtual address where physical memory is mapped
+    pub(super) fn physical_offset(&self) -> usize {
+        self.base.addr()
     }
 }
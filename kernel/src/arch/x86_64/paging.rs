@@ -0,0 +1,215 @@
+//! Page table depth.
+//!
+//! With 4-level paging, virtual addresses are 48 bits wide. CPUs with LA57
+//! support 5-level paging and 57-bit addresses, but only if the loader turns
+//! it on before entering long mode, so the kernel has to work with whichever
+//! [`PagingMode`] it's started in.
+//!
+//! The kernel's layout regions all sit in the top 128 TiB of the address
+//! space, which is canonical in both modes. With 5-level paging, that's all
+//! under the last level 5 entry, so [`KernelAddressSpace`] hands out the level
+//! 4 table below it, and code that only deals with kernel mappings (like
+//! [`active_mapper`]) doesn't need to care which mode is active.
+
+use core::arch::x86_64::__cpuid_count;
+use core::fmt;
+
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::{OffsetPageTable, PageTable};
+use x86_64::VirtAddr;
+
+use crate::mm::layout::Region;
+
+use super::mm::MemoryAccess;
+
+/// CPUID leaf 7, ECX bit 16: 57-bit linear addresses are supported
+const CPUID_LA57: u32 = 1 << 16;
+
+/// Index of the level 5 entry covering `0xffff_0000_0000_0000` and up
+const KERNEL_L5_INDEX: usize = 511;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    FourLevel,
+    FiveLevel,
+}
+
+impl PagingMode {
+    /// The paging mode the processor is running in
+    pub fn active() -> Self {
+        if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+            PagingMode::FiveLevel
+        } else {
+            PagingMode::FourLevel
+        }
+    }
+
+    /// Whether the processor supports 5-level paging, even if it isn't
+    /// enabled
+    pub fn five_level_supported() -> bool {
+        // SAFETY: every x86-64 processor the kernel supports has CPUID leaf 7
+        unsafe { __cpuid_count(7, 0) }.ecx & CPUID_LA57 != 0
+    }
+
+    /// Number of page table levels
+    pub const fn levels(self) -> u8 {
+        match self {
+            PagingMode::FourLevel => 4,
+            PagingMode::FiveLevel => 5,
+        }
+    }
+
+    /// Number of significant bits in a virtual address
+    pub const fn address_bits(self) -> u32 {
+        12 + 9 * self.levels() as u32
+    }
+
+    /// Bits set in a higher-half address above the significant ones, which
+    /// must all match the top significant bit
+    pub const fn sign_extension(self) -> u64 {
+        !0 << self.address_bits()
+    }
+
+    /// Whether `address` is canonical in this mode
+    pub const fn is_canonical(self, address: u64) -> bool {
+        let upper = address >> (self.address_bits() - 1);
+        upper == 0 || upper == (!0 >> (self.address_bits() - 1))
+    }
+}
+
+impl fmt::Display for PagingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-level paging ({}-bit addresses)",
+            self.levels(),
+            self.address_bits()
+        )
+    }
+}
+
+/// The active page tables, as far as the kernel's own mappings are concerned
+pub struct KernelAddressSpace {
+    mode: PagingMode,
+    root: u64,
+}
+
+impl KernelAddressSpace {
+    pub fn active() -> Self {
+        let (frame, _) = Cr3::read();
+        Self {
+            mode: PagingMode::active(),
+            root: frame.start_address().as_u64(),
+        }
+    }
+
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    /// The top-level page table, for reading
+    ///
+    /// # Safety
+    /// The page tables must not change while the returned reference is alive.
+    pub(super) unsafe fn root_table<'a>(&self, access: &'a MemoryAccess) -> &'a PageTable {
+        table_at(access, self.root)
+    }
+
+    /// The level 4 table that maps the kernel's half of the address space.
+    /// Only addresses that are canonical with 4-level paging can be mapped
+    /// through it.
+    ///
+    /// # Safety
+    /// The caller must not create aliasing references to the page tables.
+    pub(super) unsafe fn kernel_table<'a>(&self, access: &'a MemoryAccess) -> &'a mut PageTable {
+        match self.mode {
+            PagingMode::FourLevel => table_at(access, self.root),
+            PagingMode::FiveLevel => {
+                let entry = &self.root_table(access)[KERNEL_L5_INDEX];
+                table_at(access, entry.addr().as_u64())
+            }
+        }
+    }
+}
+
+/// Log the paging mode, and make sure every layout region is addressable
+/// with it
+pub fn init() {
+    let mode = PagingMode::active();
+    tracing::info!(
+        "Using {} (5-level paging {})",
+        mode,
+        if PagingMode::five_level_supported() {
+            "supported"
+        } else {
+            "not supported"
+        }
+    );
+
+    for region in Region::ALL {
+        let bounds = region.bounds();
+        assert!(
+            mode.is_canonical(bounds.start().as_usize() as u64)
+                && mode.is_canonical(bounds.end().as_usize() as u64 - 1),
+            "{:?} region {} is not canonical with {}",
+            region,
+            bounds,
+            mode
+        );
+    }
+}
+
+/// Build a mapper for the kernel's part of the active page tables. With
+/// 4-level paging, this covers the whole address space.
+///
+/// # Safety
+/// The caller must not create aliasing references to the page tables.
+pub(super) unsafe fn active_mapper(access: &MemoryAccess) -> OffsetPageTable<'_> {
+    let table = KernelAddressSpace::active().kernel_table(access);
+    OffsetPageTable::new(table, VirtAddr::new(access.physical_offset() as u64))
+}
+
+/// Access the page table at physical address `addr`, for as long as `access`
+/// is borrowed
+///
+/// # Safety
+/// `addr` must point to a page table, and the caller must not create aliasing
+/// mutable references to it.
+pub(super) unsafe fn table_at(access: &MemoryAccess, addr: u64) -> &mut PageTable {
+    let virt = access.physical_offset() + addr as usize;
+    &mut *(virt as *mut PageTable)
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_canonical() {
+        let four = PagingMode::FourLevel;
+        ktassert!(four.is_canonical(0x0000_7fff_ffff_ffff));
+        ktassert!(four.is_canonical(0xffff_8000_0000_0000));
+        ktassert!(!four.is_canonical(0x0000_8000_0000_0000));
+        ktassert!(!four.is_canonical(0xff00_0000_0000_0000));
+
+        let five = PagingMode::FiveLevel;
+        ktassert!(five.is_canonical(0x0000_8000_0000_0000));
+        ktassert!(five.is_canonical(0xff00_0000_0000_0000));
+        ktassert!(!five.is_canonical(0x0100_0000_0000_0000));
+        ktassert_eq!(five.sign_extension(), 0xfe00_0000_0000_0000);
+    }
+
+    #[ktest::test]
+    fn test_regions_canonical_in_both_modes() {
+        for region in Region::ALL {
+            let bounds = region.bounds();
+            let start = bounds.start().as_usize() as u64;
+            let last = bounds.end().as_usize() as u64 - 1;
+            for mode in [PagingMode::FourLevel, PagingMode::FiveLevel] {
+                ktassert!(mode.is_canonical(start) && mode.is_canonical(last));
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Kernel image memory protection.
+//!
+//! Depending on the loader, the kernel image may be mapped with pages that are
+//! both writable and executable. After boot, [`enforce`] remaps each part of
+//! the image with the minimum permissions it needs:
+//! * `.text` is read-only and executable
+//! * `.rodata` is read-only and non-executable
+//! * `.data` and `.bss` (which includes the initial heap) are writable and
+//!   non-executable
+//!
+//! Sections are rounded out to whole pages. Where two share a page, it gets
+//! the stricter permissions, so a page holding the end of `.rodata` and the
+//! start of `.data` is read-only. Code is the exception: a page with any code
+//! on it stays executable, so that the code still runs.
+//!
+//! Nothing outside the image runs, so every other top-level entry of the
+//! kernel's half of the address space is made non-executable. That covers the
+//! loader's map of physical memory, which the heap grows into and page tables
+//! are accessed through, along with its other mappings like the framebuffer.
+//!
+//! [`verify`] walks the active page tables afterwards to make sure nothing in
+//! the kernel's half of the address space is mapped writable and executable
+//! (W+X).
+
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page as X86Page, PageTable, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::prelude::*;
+
+use super::mm::MemoryAccess;
+use super::paging::{active_mapper, table_at, KernelAddressSpace, PagingMode};
+
+const TEXT_FLAGS: PageTableFlags = PageTableFlags::PRESENT;
+const RODATA_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::NO_EXECUTE);
+const DATA_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+// Section boundaries, defined in link/sections.ld
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __bss_end: u8;
+}
+
+/// Remap the kernel image so that no part of it is both writable and
+/// executable.
+pub fn enforce(access: &MemoryAccess) -> Result<(), Error> {
+    let _span = tracing::info_span!("enforce_wx").entered();
+
+    // SAFETY: setting NXE and WP only restricts what the kernel is allowed to do. NXE must be set
+    // before any NO_EXECUTE mappings are used, and the loader already enabled paging.
+    unsafe {
+        Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let [text, rodata, data] = sections();
+
+    let mut mapper = unsafe { active_mapper(access) };
+
+    // Later sections win on shared pages, so go from the least strict to the most
+    remap(&mut mapper, data, DATA_FLAGS)?;
+    remap(&mut mapper, rodata, RODATA_FLAGS)?;
+    remap(&mut mapper, text, TEXT_FLAGS)?;
+    drop(mapper);
+
+    let restricted = restrict_outside_text(access, text);
+
+    x86_64::instructions::tlb::flush_all();
+
+    tracing::info!(
+        "Kernel image protected: text {} ({}), rodata {} ({}), data {} ({})",
+        text,
+        text.size_bytes().as_size(),
+        rodata,
+        rodata.size_bytes().as_size(),
+        data,
+        data.size_bytes().as_size()
+    );
+    tracing::info!(
+        "Made {} top-level mappings outside the kernel image non-executable",
+        restricted
+    );
+    Ok(())
+}
+
+/// Check that nothing in the kernel's half of the address space is mapped both
+/// writable and executable, logging any mappings that are.
+pub fn verify(access: &MemoryAccess) -> bool {
+    let mut ok = true;
+    for_each_wx_mapping(access, |start, size| {
+        ok = false;
+        tracing::error!(
+            "W+X mapping at {} ({})",
+            VirtualAddressRange::from_start_size(start, size),
+            size.as_size()
+        );
+    });
+    ok
+}
+
+/// Set NO_EXECUTE on every present top-level entry of the kernel's half of the
+/// address space that doesn't map any of `text`. Returns how many entries
+/// were changed.
+fn restrict_outside_text(access: &MemoryAccess, text: PageRange) -> usize {
+    let space = KernelAddressSpace::active();
+    // With 4-level paging, the kernel's table is the root, and its lower half is user space
+    let first = match space.mode() {
+        PagingMode::FourLevel => 256,
+        PagingMode::FiveLevel => 0,
+    };
+    let text = text.address_range();
+    let text_entries = l4_index(text.start())..=l4_index(text.end() - 1);
+
+    // SAFETY: the mapper used to remap the image is gone, so this is the only reference to the
+    // table. The caller flushes the TLB.
+    let table = unsafe { space.kernel_table(access) };
+    let mut restricted = 0;
+    for (i, entry) in table.iter_mut().enumerate().skip(first) {
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT)
+            && !flags.contains(PageTableFlags::NO_EXECUTE)
+            && !text_entries.contains(&i)
+        {
+            entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+            restricted += 1;
+        }
+    }
+    restricted
+}
+
+/// Index of the level 4 page table entry that maps `address`
+fn l4_index(address: VirtualAddress) -> usize {
+    (address.as_usize() >> 39) & 0x1ff
+}
+
+/// Walk the active page tables, calling `f` with the starting address and size
+/// in bytes of every mapping in the kernel's half of the address space that is
+/// both writable and executable.
+fn for_each_wx_mapping<F: FnMut(VirtualAddress, usize)>(access: &MemoryAccess, f: F) {
+    let space = KernelAddressSpace::active();
+    let mode = space.mode();
+    let mut walk = Walk {
+        access,
+        mode,
+        // The lowest higher-half address
+        start: mode.sign_extension() | 1 << (mode.address_bits() - 1),
+        f,
+    };
+    // SAFETY: nothing changes the page tables during the walk, and its borrows of them end with
+    // it
+    let table = unsafe { space.root_table(access) };
+    walk.table(table, walk.mode.levels(), 0, PageTableFlags::WRITABLE);
+}
+
+/// A walk of the page tables for W+X mappings from `start` to the end of the
+/// address space
+struct Walk<'a, F> {
+    access: &'a MemoryAccess,
+    mode: PagingMode,
+    start: u64,
+    f: F,
+}
+
+impl<F: FnMut(VirtualAddress, usize)> Walk<'_, F> {
+    /// Recursively walk `table`, which is at `level` of the page table
+    /// hierarchy and maps addresses starting at `base`. `inherited` contains
+    /// the effective permissions of the parent entries: an entry is only
+    /// writable if all its parents are, and is non-executable if any of its
+    /// parents are.
+    fn table(&mut self, table: &PageTable, level: u8, base: u64, inherited: PageTableFlags) {
+        let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+
+        for (i, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+
+            let mut start = base + i as u64 * entry_size;
+            if level == self.mode.levels() && i >= 256 {
+                // Sign-extend into the canonical higher half
+                start |= self.mode.sign_extension();
+            }
+            if start + (entry_size - 1) < self.start {
+                continue;
+            }
+
+            let mut effective = inherited & flags & PageTableFlags::WRITABLE;
+            effective |= (inherited | flags) & PageTableFlags::NO_EXECUTE;
+
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                if effective.contains(PageTableFlags::WRITABLE)
+                    && !effective.contains(PageTableFlags::NO_EXECUTE)
+                {
+                    (self.f)(VirtualAddress::new(start as usize), entry_size as usize);
+                }
+            } else {
+                // SAFETY: a present, non-leaf entry points to the next level table, and the
+                // caller makes sure nothing changes it during the walk
+                let next = unsafe { table_at(self.access, entry.addr().as_u64()) };
+                self.table(next, level - 1, start, effective);
+            }
+        }
+    }
+}
+
+/// Update the flags for every page in `range`.
+fn remap(
+    mapper: &mut OffsetPageTable<'_>,
+    range: PageRange,
+    flags: PageTableFlags,
+) -> Result<(), Error> {
+    for i in 0..range.size() {
+        let page = X86Page::<Size4KiB>::containing_address(VirtAddr::new(
+            (range.start() + i).start().as_usize() as u64,
+        ));
+        // SAFETY: these pages belong to the kernel image, and `flags` grants every permission the
+        // corresponding section needs. The TLB is flushed once all sections are updated.
+        unsafe {
+            mapper
+                .update_flags(page, flags)
+                .map_err(|_| Error::new(MmError::InvalidAddress))?
+                .ignore();
+        }
+    }
+    Ok(())
+}
+
+/// Pages of the text, rodata and data sections
+fn sections() -> [PageRange; 3] {
+    // SAFETY: the linker script defines these symbols, and only their addresses are used
+    unsafe {
+        [
+            section(&__text_start, &__text_end),
+            section(&__rodata_start, &__rodata_end),
+            section(&__data_start, &__bss_end),
+        ]
+    }
+}
+
+/// Page range covering the section between `start` and `end`, including any
+/// pages it shares with its neighbors
+fn section(start: &u8, end: &u8) -> PageRange {
+    let start = (start as *const u8).addr();
+    let end = (end as *const u8).addr();
+    PageRange::new(
+        Page::new(start.div_floor(PAGE_SIZE)),
+        Page::new(end.div_ceil(PAGE_SIZE)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_no_wx_mappings() {
+        ktassert!(verify(MemoryAccess::get()));
+    }
+}
@@ -0,0 +1,117 @@
+//! Per-processor thread-local storage.
+//!
+//! Statics marked `#[thread_local]` live in the kernel's `PT_TLS` segment, and
+//! the compiler accesses them relative to the FS base using the local-exec
+//! model. The bootloader finds that segment and passes its location along as
+//! a [`TlsTemplate`]. Since kernel code runs on processors rather than
+//! threads, each processor gets one copy of the template, which makes
+//! `#[thread_local]` statics per-processor variables without any of the
+//! bookkeeping [`ProcessorLocal`](platypos_hal::topology::ProcessorLocal)
+//! needs. Unlike `ProcessorLocal`, there's no borrow checking, so they're best
+//! suited to atomics and `Cell`s that interrupt handlers can't break.
+//!
+//! x86-64 uses TLS variant II: the thread pointer (the FS base) points just
+//! past the end of the TLS block, variables sit at negative offsets from it,
+//! and the first word at the thread pointer points to itself.
+//!
+//! Thread-local statics can't be touched until [`init_local`] has run on the
+//! current processor. Before that, the FS base is 0 and accesses fault.
+
+use core::alloc::Layout;
+use core::ptr;
+
+use bootloader_api::info::TlsTemplate;
+use platypos_common::sync::Global;
+use platypos_hal::topology::Topology as _;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::VirtAddr;
+
+use crate::boot_phase::HeapReady;
+use crate::prelude::*;
+
+/// Alignment of each processor's TLS block. The bootloader doesn't pass on the
+/// segment's alignment, so this is a cache line, which is at least as strict
+/// as anything the kernel declares.
+const TLS_ALIGN: usize = 64;
+
+static TEMPLATE: Global<Option<TlsTemplate>> = Global::new();
+
+/// Record the kernel's TLS template. This must be called once, before
+/// [`init_local`] runs on any processor. TLS blocks are allocated from the
+/// heap.
+pub(super) fn init(template: Option<TlsTemplate>, _: &HeapReady) {
+    match &template {
+        Some(template) => tracing::debug!(
+            "TLS template at {:#x}: {} initialized, {} total",
+            template.start_addr,
+            (template.file_size as usize).as_size(),
+            (template.mem_size as usize).as_size()
+        ),
+        None => tracing::debug!("Kernel has no TLS segment"),
+    }
+    TEMPLATE.init(template);
+}
+
+/// Allocate and initialize the current processor's TLS block, and point the
+/// FS base at it. The block is never freed.
+///
+/// # Panics
+/// If [`init`] hasn't been called, or the block can't be allocated
+pub(super) fn init_local() {
+    let Some(template) = TEMPLATE.get() else {
+        return;
+    };
+    let file_size = template.file_size as usize;
+    let block_size = (template.mem_size as usize).next_multiple_of(TLS_ALIGN);
+
+    // The block, then the thread pointer's self-reference
+    let layout = Layout::from_size_align(block_size + 8, TLS_ALIGN).unwrap();
+    // SAFETY: the layout isn't zero-sized, since it includes the self-pointer
+    let block = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if block.is_null() {
+        alloc::alloc::handle_alloc_error(layout);
+    }
+
+    // SAFETY: the bootloader mapped the template as part of the kernel image, the block is big
+    // enough for the whole template plus the self-pointer, and it was just allocated, so nothing
+    // else refers to it. The tail past `file_size` is already zeroed (`.tbss`).
+    let thread_pointer = unsafe {
+        ptr::copy_nonoverlapping(template.start_addr as *const u8, block, file_size);
+        let thread_pointer = block.add(block_size);
+        (thread_pointer as *mut usize).write(thread_pointer as usize);
+        thread_pointer
+    };
+    FsBase::write(VirtAddr::from_ptr(thread_pointer));
+
+    tracing::debug!(
+        "Processor {} TLS block at {:#x}",
+        hal_impl::topology::INSTANCE.current_processor(),
+        block as usize
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use ktest::*;
+
+    #[thread_local]
+    static INITIALIZED: Cell<u64> = Cell::new(0x1234_5678);
+
+    #[thread_local]
+    static ZEROED: Cell<u64> = Cell::new(0);
+
+    #[ktest::test]
+    fn test_thread_locals() {
+        // Values come from the template, not whatever else is in memory
+        ktassert_eq!(INITIALIZED.get(), 0x1234_5678);
+        ktassert_eq!(ZEROED.get(), 0);
+
+        INITIALIZED.set(1);
+        ZEROED.set(2);
+        ktassert_eq!((INITIALIZED.get(), ZEROED.get()), (1, 2));
+        INITIALIZED.set(0x1234_5678);
+        ZEROED.set(0);
+    }
+}
@@ -0,0 +1,84 @@
+//! VGA text mode buffer.
+//!
+//! PC-compatible firmware that boots in a legacy text mode leaves an 80x25
+//! grid of character cells at physical address `0xb8000`. Each cell is a code
+//! page 437 character in the low byte and a color attribute in the high byte.
+//! UEFI systems without a graphics output protocol may not have it at all, in
+//! which case writes are ignored.
+
+use core::ptr;
+
+use x86_64::instructions::port::Port;
+
+use super::mm::MemoryAccess;
+use crate::prelude::*;
+
+/// Physical address of the text buffer, which is page-aligned
+const BUFFER_ADDRESS: usize = 0xb8000;
+
+// CRT controller ports, for moving the hardware cursor
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+
+/// The VGA text buffer
+pub struct TextBuffer {
+    cells: *mut u16,
+}
+
+// SAFETY: the buffer is only accessed through `&mut self`
+unsafe impl Send for TextBuffer {}
+
+impl TextBuffer {
+    pub const WIDTH: usize = 80;
+    pub const HEIGHT: usize = 25;
+
+    /// Map the text buffer through the physical memory map
+    ///
+    /// # Safety
+    /// There must only be one `TextBuffer` at a time.
+    pub unsafe fn new(access: &MemoryAccess) -> Result<Self, Error> {
+        let frames = PageFrameRange::from_start_size(
+            PageFrame::containing(PhysicalAddress::new(BUFFER_ADDRESS)),
+            (Self::WIDTH * Self::HEIGHT * 2).div_ceil(PAGE_SIZE),
+        );
+        let base = access.map_permanent(frames)?;
+        Ok(Self { cells: base.cast() })
+    }
+
+    /// Write `character` with color `attribute` to the cell at `row` and
+    /// `column`
+    pub fn write(&mut self, row: usize, column: usize, character: u8, attribute: u8) {
+        assert!(row < Self::HEIGHT && column < Self::WIDTH);
+        let cell = u16::from(attribute) << 8 | u16::from(character);
+        // SAFETY: the cell is in bounds, and the buffer is device memory, so it's written volatile
+        unsafe { ptr::write_volatile(self.cells.add(row * Self::WIDTH + column), cell) }
+    }
+
+    /// Copy row `from` to row `to`
+    pub fn copy_row(&mut self, from: usize, to: usize) {
+        assert!(from < Self::HEIGHT && to < Self::HEIGHT);
+        for column in 0..Self::WIDTH {
+            // SAFETY: both cells are in bounds
+            unsafe {
+                let cell = ptr::read_volatile(self.cells.add(from * Self::WIDTH + column));
+                ptr::write_volatile(self.cells.add(to * Self::WIDTH + column), cell);
+            }
+        }
+    }
+
+    /// Move the blinking hardware cursor to `row` and `column`
+    pub fn set_cursor(&mut self, row: usize, column: usize) {
+        let position = (row * Self::WIDTH + column) as u16;
+        let mut index = Port::<u8>::new(CRTC_INDEX);
+        let mut data = Port::<u8>::new(CRTC_DATA);
+        // SAFETY: the cursor location registers only affect where the cursor is drawn
+        unsafe {
+            index.write(CRTC_CURSOR_HIGH);
+            data.write((position >> 8) as u8);
+            index.write(CRTC_CURSOR_LOW);
+            data.write(position as u8);
+        }
+    }
+}
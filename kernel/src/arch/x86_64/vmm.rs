@@ -0,0 +1,459 @@
+//! Zero-page and copy-on-write mappings.
+//!
+//! Large zeroed regions don't need any memory until they're written:
+//! [`map_zeroed`] maps every page to a single shared frame of zeroes. In the
+//! same way, [`clone_cow`] maps a copy of a region onto the same frames as the
+//! original, which makes snapshots (like of test state) cheap.
+//!
+//! Both kinds of mapping are read-only, and marked copy-on-write with a bit
+//! that's reserved for software in the page table entry. Writing to one
+//! faults, and the page fault handler gives the page a private frame, copying
+//! the old contents (or zeroing it, for the zero page), and makes it writable.
+//! The number of mappings of each shared frame is counted, so the last one to
+//! be written takes the frame over instead of copying it.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use platypos_common::sync::Global;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page as X86Page, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::mm::root_allocator::{Allocator as RootAllocator, Owner, Subsystem};
+use crate::prelude::*;
+
+use super::hal_impl::interrupts::{Fault, FaultKind};
+use super::mm::MemoryAccess;
+use super::paging::active_mapper;
+
+/// Page table bit marking a read-only mapping as copy-on-write
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Flags for intermediate page tables created while mapping. Permissions are
+/// enforced by the leaf entries, so the tables themselves allow everything.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+static VMM: Global<InterruptSafeMutex<'static, Vmm>> = Global::new();
+
+struct Vmm {
+    access: &'static MemoryAccess,
+    allocator: &'static RootAllocator<'static>,
+    /// The shared frame of zeroes
+    zero: PageFrame,
+    /// Number of copy-on-write mappings of each frame mapped more than once
+    shared: BTreeMap<PageFrame, usize>,
+    /// Frames allocated for private copies, which are freed when unmapped.
+    /// Other frames belong to whoever originally mapped them.
+    owned: BTreeSet<PageFrame>,
+}
+
+/// Allocates frames for new page tables
+struct TableFrames(&'static RootAllocator<'static>);
+
+// SAFETY: frames come from the root allocator, so they're unused
+unsafe impl FrameAllocator<Size4KiB> for TableFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let range = self
+            .0
+            .allocate_for(1, Owner::new(Subsystem::PageTables))
+            .ok()?;
+        Some(phys_frame(range.start()))
+    }
+}
+
+/// Set up the zero page and start handling copy-on-write faults
+pub fn init(
+    access: &'static MemoryAccess,
+    allocator: &'static RootAllocator<'static>,
+    controller: &'static hal_impl::interrupts::Controller,
+) -> Result<(), Error> {
+    let zero = allocator
+        .allocate_for(1, Owner::new(Subsystem::Vmm))
+        .context("could not allocate the zero page")?;
+    // SAFETY: the frame was just allocated, so nothing else has it mapped
+    unsafe {
+        access.with_memory(zero, |_, memory| {
+            memory.fill(core::mem::MaybeUninit::new(0))
+        })?
+    };
+
+    VMM.init(InterruptSafeMutex::new(
+        controller,
+        Vmm {
+            access,
+            allocator,
+            zero: zero.start(),
+            shared: BTreeMap::new(),
+            owned: BTreeSet::new(),
+        },
+    ));
+    hal_impl::interrupts::set_page_fault_handler(handle_page_fault);
+    Ok(())
+}
+
+/// Map every page in `range` to the zero page. The pages read as zero, and
+/// get their own memory once they're written to.
+///
+/// # Safety
+/// `range` must be unmapped address space reserved for the caller, like from
+/// [`layout::reserve`](crate::mm::layout::reserve).
+pub unsafe fn map_zeroed(range: PageRange) -> Result<(), Error> {
+    let mut vmm = VMM.get().lock();
+    let mut mapper = active_mapper(vmm.access);
+    let zero = vmm.zero;
+    for i in 0..range.size() {
+        vmm.map_shared(
+            &mut mapper,
+            range.start() + i,
+            zero,
+            PageTableFlags::NO_EXECUTE,
+        )?;
+    }
+    Ok(())
+}
+
+/// Map `destination` onto the same memory as `source`, so that both read the
+/// same until one of them is written to. `source` becomes copy-on-write too.
+///
+/// # Safety
+/// `destination` must be unmapped address space reserved for the caller, and
+/// `source` must be mapped with 4 KiB pages. `source`'s memory must stay
+/// allocated until `destination` is [unmapped](unmap) or every page of it
+/// has been written to.
+pub unsafe fn clone_cow(source: PageRange, destination: PageRange) -> Result<(), Error> {
+    if source.size() != destination.size() {
+        return Err(Error::new(MmError::InvalidAddress)
+            .context("copy-on-write source and destination are different sizes"));
+    }
+
+    let mut vmm = VMM.get().lock();
+    let mut mapper = active_mapper(vmm.access);
+    for i in 0..source.size() {
+        let page = source.start() + i;
+        let (frame, flags) = translate(&mapper, page)
+            .ok_or_else(|| Error::new(MmError::InvalidAddress).context("source is not mapped"))?;
+
+        if !flags.contains(COPY_ON_WRITE) {
+            // The source's own mapping becomes copy-on-write as well
+            let shared = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            mapper
+                .update_flags(x86_page(page), shared)
+                .map_err(|_| Error::new(MmError::InvalidAddress))?
+                .flush();
+        }
+        let flags = flags & PageTableFlags::NO_EXECUTE;
+        vmm.map_shared(&mut mapper, destination.start() + i, frame, flags)?;
+    }
+    Ok(())
+}
+
+/// Unmap `range`, which was mapped by [`map_zeroed`] or [`clone_cow`],
+/// freeing any private copies of its pages
+///
+/// # Safety
+/// Nothing may use `range` afterwards.
+pub unsafe fn unmap(range: PageRange) -> Result<(), Error> {
+    let mut vmm = VMM.get().lock();
+    let mut mapper = active_mapper(vmm.access);
+    for i in 0..range.size() {
+        let page = range.start() + i;
+        let Ok((frame, flush)) = mapper.unmap(x86_page(page)) else {
+            continue;
+        };
+        flush.flush();
+        vmm.release(page_frame(frame))?;
+    }
+    Ok(())
+}
+
+impl Vmm {
+    /// Map `page` read-only and copy-on-write to `frame`, counting it as
+    /// another mapping of the frame. Frames that aren't counted yet have one
+    /// existing mapping.
+    fn map_shared(
+        &mut self,
+        mapper: &mut OffsetPageTable<'_>,
+        page: Page,
+        frame: PageFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), Error> {
+        let flags = flags | PageTableFlags::PRESENT | COPY_ON_WRITE;
+        // SAFETY: the caller reserved `page`, and `frame` is only mapped read-only
+        unsafe {
+            mapper
+                .map_to_with_table_flags(
+                    x86_page(page),
+                    phys_frame(frame),
+                    flags,
+                    TABLE_FLAGS,
+                    &mut TableFrames(self.allocator),
+                )
+                .map_err(|_| Error::new(MmError::InvalidAddress).context("page is already mapped"))?
+                .flush();
+        }
+        if frame != self.zero {
+            *self.shared.entry(frame).or_insert(1) += 1;
+        }
+        Ok(())
+    }
+
+    /// Drop one mapping of `frame`
+    fn release(&mut self, frame: PageFrame) -> Result<(), Error> {
+        if frame == self.zero {
+            return Ok(());
+        }
+        if let Some(count) = self.shared.get_mut(&frame) {
+            *count -= 1;
+            if *count == 1 {
+                self.shared.remove(&frame);
+            }
+            return Ok(());
+        }
+        if self.owned.remove(&frame) {
+            self.allocator
+                .deallocate(PageFrameRange::from_start_size(frame, 1))?;
+        }
+        Ok(())
+    }
+
+    /// Give `page`, which is mapped copy-on-write to `frame`, a private,
+    /// writable frame
+    fn resolve(
+        &mut self,
+        mapper: &mut OffsetPageTable<'_>,
+        page: Page,
+        frame: PageFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), Error> {
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let target = x86_page(page);
+
+        if frame != self.zero && !self.shared.contains_key(&frame) {
+            // This is the last mapping, so it can have the frame
+            // SAFETY: nothing else maps the frame anymore
+            unsafe {
+                mapper
+                    .update_flags(target, flags)
+                    .map_err(|_| Error::new(MmError::InvalidAddress))?
+                    .flush();
+            }
+            return Ok(());
+        }
+
+        let copy = self
+            .allocator
+            .allocate_for(1, Owner::at(Subsystem::Vmm, page.start().as_usize()))
+            .context("could not allocate a copy-on-write page")?;
+        let copy = copy.start();
+        let source = page.start().as_usize() as *const u8;
+        // SAFETY: the copy was just allocated, and the faulting page is still mapped for reading
+        unsafe {
+            self.access
+                .with_memory(PageFrameRange::from_start_size(copy, 1), |_, memory| {
+                    core::ptr::copy_nonoverlapping(source, memory.as_mut_ptr().cast(), PAGE_SIZE)
+                })?;
+            let (_, flush) = mapper
+                .unmap(target)
+                .map_err(|_| Error::new(MmError::InvalidAddress))?;
+            flush.flush();
+            mapper
+                .map_to_with_table_flags(
+                    target,
+                    phys_frame(copy),
+                    flags,
+                    TABLE_FLAGS,
+                    &mut TableFrames(self.allocator),
+                )
+                .map_err(|_| Error::new(MmError::InvalidAddress))?
+                .flush();
+        }
+        self.owned.insert(copy);
+        self.release(frame)
+    }
+}
+
+/// Resolve writes to copy-on-write pages. Any other page fault is left for
+/// the HAL to report.
+fn handle_page_fault(fault: &Fault) -> bool {
+    let (FaultKind::PageFault, Some(address), Some(code)) =
+        (fault.kind, fault.address, fault.error_code)
+    else {
+        return false;
+    };
+    let write_to_present =
+        PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if !PageFaultErrorCode::from_bits_truncate(code).contains(write_to_present) {
+        return false;
+    }
+    let Some(vmm) = VMM.try_get() else {
+        return false;
+    };
+    // A fault while the VMM is locked means it faulted itself, which can't be resolved here
+    let Some(mut vmm) = vmm.try_lock() else {
+        return false;
+    };
+
+    let page = Page::containing(VirtualAddress::new(address as usize));
+    // SAFETY: the VMM lock serializes page table changes
+    let mut mapper = unsafe { active_mapper(vmm.access) };
+    let Some((frame, flags)) = translate(&mapper, page) else {
+        return false;
+    };
+    if !flags.contains(COPY_ON_WRITE) {
+        return false;
+    }
+
+    match vmm.resolve(&mut mapper, page, frame, flags) {
+        Ok(()) => true,
+        Err(err) => {
+            tracing::error!(
+                "Could not resolve copy-on-write fault at {:#x}: {}",
+                address,
+                err
+            );
+            false
+        }
+    }
+}
+
+/// The frame and flags `page` is mapped with, if it's mapped with a 4 KiB page
+fn translate(mapper: &OffsetPageTable<'_>, page: Page) -> Option<(PageFrame, PageTableFlags)> {
+    match mapper.translate(VirtAddr::new(page.start().as_usize() as u64)) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => Some((page_frame(frame), flags)),
+        _ => None,
+    }
+}
+
+fn x86_page(page: Page) -> X86Page<Size4KiB> {
+    X86Page::containing_address(VirtAddr::new(page.start().as_usize() as u64))
+}
+
+fn phys_frame(frame: PageFrame) -> PhysFrame<Size4KiB> {
+    PhysFrame::containing_address(PhysAddr::new(frame.start().as_usize() as u64))
+}
+
+fn page_frame(frame: PhysFrame<Size4KiB>) -> PageFrame {
+    PageFrame::containing(PhysicalAddress::new(frame.start_address().as_u64() as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+    use crate::mm::layout::{self, Region};
+
+    /// Address space the tests map pages in. Each test uses its own pages,
+    /// and unmaps them before it returns so the leak check sees their private
+    /// copies freed.
+    struct Window(PageRange);
+
+    impl Window {
+        fn pages(&self, first: usize, count: usize) -> PageRange {
+            PageRange::from_start_size(self.0.start() + first, count)
+        }
+    }
+
+    impl Drop for Window {
+        fn drop(&mut self) {
+            // Pages left mapped by a failed test
+            unsafe { unmap(self.0).unwrap() };
+        }
+    }
+
+    /// Reserve the window, and fork a page in it once, so the page tables and
+    /// the VMM's bookkeeping are already allocated when the leak check takes
+    /// its first snapshot
+    #[ktest::fixture(scope = "run")]
+    fn window() -> Window {
+        let range = layout::reserve(Region::Heap, 6 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let window = Window(PageRange::from_start_size(
+            Page::containing(range.start()),
+            6,
+        ));
+        unsafe {
+            map_zeroed(window.0).unwrap();
+            let page = window.pages(0, 1);
+            (page.start_address().as_usize() as *mut u8).write_volatile(1);
+            unmap(window.pages(1, 1)).unwrap();
+            clone_cow(page, window.pages(1, 1)).unwrap();
+            unmap(window.0).unwrap();
+        }
+        window
+    }
+
+    #[ktest::test]
+    fn test_zero_page(window: &Window) {
+        let range = window.pages(0, 2);
+        unsafe {
+            map_zeroed(range).unwrap();
+            let memory = range.start_address().as_usize() as *mut u8;
+
+            ktassert_eq!(memory.read_volatile(), 0);
+            memory.add(PAGE_SIZE).write_volatile(7);
+            ktassert_eq!(memory.add(PAGE_SIZE).read_volatile(), 7);
+            // Writing one page doesn't affect the zero page the other still maps
+            ktassert_eq!(memory.read_volatile(), 0);
+
+            unmap(range).unwrap();
+        }
+    }
+
+    #[ktest::test]
+    fn test_clone_cow(window: &Window) {
+        let source = window.pages(2, 1);
+        let destination = window.pages(3, 1);
+        unsafe {
+            map_zeroed(source).unwrap();
+            let original = source.start_address().as_usize() as *mut u8;
+            original.write_volatile(1);
+
+            clone_cow(source, destination).unwrap();
+            let copy = destination.start_address().as_usize() as *mut u8;
+            ktassert_eq!(copy.read_volatile(), 1);
+
+            copy.write_volatile(2);
+            ktassert_eq!(original.read_volatile(), 1);
+            ktassert_eq!(copy.read_volatile(), 2);
+
+            // The original is the last mapping of its frame, so it's made writable in place
+            original.write_volatile(3);
+            ktassert_eq!(original.read_volatile(), 3);
+
+            unmap(destination).unwrap();
+            unmap(source).unwrap();
+        }
+    }
+
+    #[ktest::test]
+    fn test_fork_zeroed(window: &Window) {
+        let source = window.pages(4, 1);
+        let destination = window.pages(5, 1);
+        unsafe {
+            map_zeroed(source).unwrap();
+            // Both copies map the zero page, so each write gets a private frame of zeroes
+            clone_cow(source, destination).unwrap();
+            let original = source.start_address().as_usize() as *mut u8;
+            let copy = destination.start_address().as_usize() as *mut u8;
+
+            original.write_volatile(1);
+            ktassert_eq!(copy.read_volatile(), 0);
+            copy.add(1).write_volatile(2);
+            ktassert_eq!(original.read_volatile(), 1);
+            ktassert_eq!(original.add(1).read_volatile(), 0);
+            ktassert_eq!(copy.read_volatile(), 0);
+            ktassert_eq!(copy.add(1).read_volatile(), 2);
+
+            unmap(destination).unwrap();
+            unmap(source).unwrap();
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Boot environment report.
+//!
+//! Flaky boots are easier to track down when every run's trace says what it
+//! booted on. The platform code collects a [`BootEnvironment`] from whatever
+//! the loader and firmware hand over, and [`BootEnvironment::report`] records
+//! it as a `boot_environment` event so the host keeps it with the rest of the
+//! run's logs. The memory map fingerprint makes it easy to spot runs where the
+//! firmware laid out memory differently, and the build ID tells the host which
+//! of its kernel images booted.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::fault::fnv1a;
+
+platypos_ktrace::schema! {
+    /// The environment the kernel booted in. `hash` is a fingerprint of the
+    /// memory map the loader passed in, and `build_id` is empty if the kernel
+    /// wasn't linked with one.
+    event boot_environment(INFO) {
+        loader: str,
+        vendor: str,
+        revision: u64,
+        secure_boot: str,
+        hash: hex,
+        build_id: hex,
+    }
+}
+
+/// Whether the firmware enforced Secure Boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureBoot {
+    Enabled,
+    Disabled,
+    /// The loader didn't say
+    Unknown,
+}
+
+impl SecureBoot {
+    pub const fn name(self) -> &'static str {
+        match self {
+            SecureBoot::Enabled => "enabled",
+            SecureBoot::Disabled => "disabled",
+            SecureBoot::Unknown => "unknown",
+        }
+    }
+}
+
+pub struct BootEnvironment<'a> {
+    /// Loader name and version
+    pub loader: &'a str,
+    /// Firmware vendor
+    pub vendor: &'a str,
+    /// Firmware revision
+    pub revision: u64,
+    pub secure_boot: SecureBoot,
+    /// Fingerprint of the loader's memory map
+    pub memory_map: MemoryMapHash,
+}
+
+impl BootEnvironment<'_> {
+    pub fn report(&self) {
+        let build_id = crate::build_id::build_id();
+        boot_environment(
+            self.loader,
+            self.vendor,
+            self.revision,
+            self.secure_boot.name(),
+            &self.memory_map.0.to_be_bytes(),
+            build_id.as_ref().map_or(&[][..], |id| &id[..]),
+        );
+    }
+}
+
+/// Fingerprint of a memory map, covering each region's bounds and kind in
+/// the order the loader listed them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapHash(u64);
+
+impl MemoryMapHash {
+    /// Hash regions given as `(start, end, kind)`, where `kind` is any
+    /// loader-specific number that distinguishes region types
+    pub fn new(regions: impl Iterator<Item = (u64, u64, u64)>) -> Self {
+        let mut bytes = Vec::new();
+        for (start, end, kind) in regions {
+            bytes.extend_from_slice(&start.to_le_bytes());
+            bytes.extend_from_slice(&end.to_le_bytes());
+            bytes.extend_from_slice(&kind.to_le_bytes());
+        }
+        Self(fnv1a(&bytes))
+    }
+}
+
+impl fmt::Display for MemoryMapHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_memory_map_hash() {
+        let map = [
+            (0, 0x9f000, 0),
+            (0x100000, 0x800000, 0),
+            (0x800000, 0x900000, 1),
+        ];
+        let hash = MemoryMapHash::new(map.iter().copied());
+        ktassert_eq!(hash, MemoryMapHash::new(map.iter().copied()));
+
+        // Changing a region's kind or the order of regions changes the hash
+        let mut changed = map;
+        changed[2].2 = 2;
+        ktassert!(hash != MemoryMapHash::new(changed.iter().copied()));
+        ktassert!(hash != MemoryMapHash::new(map.iter().rev().copied()));
+    }
+}
@@ -0,0 +1,133 @@
+//! Typestate tokens for the phases of boot.
+//!
+//! Boot has ordering rules that used to be kept by convention alone: the heap
+//! has to exist before the trace worker, memory management before anything
+//! that sizes per-processor data, and the local APIC before the scheduler
+//! tick. Each phase is represented by a token that only the code finishing
+//! the phase makes, by [`advance`]-ing from the previous phase's token. Init
+//! functions that finish a phase take the previous token by value, and ones
+//! that depend on a phase borrow its token, so calling them out of order in
+//! the entry code doesn't compile.
+//!
+//! Tokens can't be copied, and the first one, [`Entry`], can only be taken
+//! once, so a phase can't be reached twice to initialize something again.
+//! Later tokens dereference to earlier ones, since each phase includes the
+//! ones before it.
+
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether the [`Entry`] token has been taken
+static ENTERED: AtomicBool = AtomicBool::new(false);
+
+mod sealed {
+    pub trait Sealed {
+        type Previous;
+
+        fn from_previous(previous: Self::Previous) -> Self;
+    }
+}
+
+/// A phase of boot, which has been reached once its token exists
+pub trait BootPhase: sealed::Sealed {
+    /// Description of the phase, for the trace
+    const NAME: &'static str;
+}
+
+/// Reach phase `P`, once the code finishing it is done. This is the only way
+/// to make a token, other than [`Entry::take`].
+pub fn advance<P: BootPhase>(previous: P::Previous) -> P {
+    tracing::debug!("Boot phase reached: {}", P::NAME);
+    P::from_previous(previous)
+}
+
+/// The kernel has just been entered, and nothing is set up
+pub struct Entry(());
+
+impl Entry {
+    /// Take the token for the start of boot, or `None` if it's already been
+    /// taken
+    pub fn take() -> Option<Self> {
+        let taken = ENTERED.swap(true, Ordering::AcqRel);
+        (!taken).then_some(Entry(()))
+    }
+}
+
+/// The kernel heap can be allocated from, but can't grow yet
+pub struct HeapReady(());
+
+impl sealed::Sealed for HeapReady {
+    type Previous = Entry;
+
+    fn from_previous(_: Entry) -> Self {
+        HeapReady(())
+    }
+}
+
+impl BootPhase for HeapReady {
+    const NAME: &'static str = "heap ready";
+}
+
+/// The root allocator is managing page frames, and the heap can grow
+pub struct MemoryReady {
+    heap: HeapReady,
+}
+
+impl sealed::Sealed for MemoryReady {
+    type Previous = HeapReady;
+
+    fn from_previous(heap: HeapReady) -> Self {
+        MemoryReady { heap }
+    }
+}
+
+impl BootPhase for MemoryReady {
+    const NAME: &'static str = "memory ready";
+}
+
+impl Deref for MemoryReady {
+    type Target = HeapReady;
+
+    fn deref(&self) -> &HeapReady {
+        &self.heap
+    }
+}
+
+/// The bootstrap processor's local interrupt controller is set up, and its
+/// timer is calibrated
+pub struct InterruptsReady {
+    memory: MemoryReady,
+}
+
+impl sealed::Sealed for InterruptsReady {
+    type Previous = MemoryReady;
+
+    fn from_previous(memory: MemoryReady) -> Self {
+        InterruptsReady { memory }
+    }
+}
+
+impl BootPhase for InterruptsReady {
+    const NAME: &'static str = "interrupts ready";
+}
+
+impl Deref for InterruptsReady {
+    type Target = MemoryReady;
+
+    fn deref(&self) -> &MemoryReady {
+        &self.memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_entry_taken_once() {
+        // Booting took it before the tests started
+        ktassert!(Entry::take().is_none());
+    }
+}
@@ -0,0 +1,172 @@
+//! Boot time breakdown.
+//!
+//! The kernel records a timestamp at each [`Phase`] of boot. Once the first
+//! task runs, [`report`] logs how long each phase took, plus a single-line
+//! summary under [`BOOT_TIME_TARGET`] that `cargo xtask` saves for tracking
+//! boot time regressions:
+//!
+//! ```text
+//! reset=0us loader-handoff=1523004us memory-init=1531127us first-task=1544548us
+//! ```
+//!
+//! Timestamps are raw [`Clock`] ticks, so phases before the clock is calibrated
+//! can still be recorded. They're converted when reporting.
+//!
+//! The firmware and loader run before the kernel can record anything, but the
+//! clock (the TSC, on x86_64) starts counting at reset, so the loader handoff
+//! timestamp is also how long they took. The loader doesn't pass along any
+//! timestamps of its own, so they're a single phase. [`trace_pre_kernel`]
+//! reports it as a span once tracing is up, so the trace covers all of boot.
+//! If the clock evidently didn't start at reset, times are measured from the
+//! handoff instead, and the summary starts with `loader-handoff=0us`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use platypos_hal::time::Clock;
+use platypos_ktrace::proto::BOOT_TIME_TARGET;
+
+use crate::fmt::HumanDuration;
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The bootloader jumped to the kernel
+    LoaderHandoff,
+    /// Physical memory and the heap are set up
+    MemoryInit,
+    /// The local APIC and its timer are set up
+    ApicInit,
+    /// Every processor that's going to be started is online
+    ApBringUp,
+    /// The scheduler ran its first task
+    FirstTask,
+}
+
+impl Phase {
+    const COUNT: usize = 5;
+
+    const ALL: [Phase; Phase::COUNT] = [
+        Phase::LoaderHandoff,
+        Phase::MemoryInit,
+        Phase::ApicInit,
+        Phase::ApBringUp,
+        Phase::FirstTask,
+    ];
+
+    /// Name of the phase in the summary line
+    pub const fn name(self) -> &'static str {
+        match self {
+            Phase::LoaderHandoff => "loader-handoff",
+            Phase::MemoryInit => "memory-init",
+            Phase::ApicInit => "apic-init",
+            Phase::ApBringUp => "ap-bring-up",
+            Phase::FirstTask => "first-task",
+        }
+    }
+}
+
+/// Timestamp of each phase, indexed by `Phase as usize`. 0 means the phase
+/// hasn't been reached.
+static TIMESTAMPS: [AtomicU64; Phase::COUNT] = [const { AtomicU64::new(0) }; Phase::COUNT];
+
+/// Longest plausible time from reset to the loader handoff. Firmware can
+/// adjust the clock, and it may keep counting across a warm reboot, so a
+/// longer gap means it didn't start at reset.
+const MAX_PRE_KERNEL: Duration = Duration::from_secs(300);
+
+/// Record that boot reached `phase`. Only the first call for each phase counts,
+/// so this is safe to call on every processor.
+pub fn record(phase: Phase) {
+    let now = hal_impl::time::INSTANCE.now();
+    let _ =
+        TIMESTAMPS[phase as usize].compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Trace the firmware and loader phase, which ran before the kernel could.
+/// This is a span named `firmware`, with events at reset and at the handoff
+/// carrying their clock `ticks`. Call this once tracing is set up.
+pub fn trace_pre_kernel() {
+    let handoff = TIMESTAMPS[Phase::LoaderHandoff as usize].load(Ordering::Relaxed);
+    if handoff == 0 {
+        return;
+    }
+    let _span = tracing::info_span!("firmware").entered();
+    tracing::info!(ticks = 0u64, "Reset");
+    tracing::info!(ticks = handoff, "Loader handed off to the kernel");
+}
+
+/// Timestamp that boot times are measured from, and its name in the summary:
+/// reset if the clock plausibly started then, otherwise the loader handoff
+fn origin() -> Option<(u64, &'static str)> {
+    let handoff = TIMESTAMPS[Phase::LoaderHandoff as usize].load(Ordering::Relaxed);
+    if handoff == 0 {
+        return None;
+    }
+    match hal_impl::time::INSTANCE.to_duration(handoff) {
+        Some(pre_kernel) if pre_kernel <= MAX_PRE_KERNEL => Some((0, "reset")),
+        _ => Some((handoff, Phase::LoaderHandoff.name())),
+    }
+}
+
+/// Microseconds from the [`origin`] to `phase`, if it was recorded and the
+/// clock is calibrated
+fn elapsed_micros(phase: Phase) -> Option<u64> {
+    let (start, _) = origin()?;
+    let at = TIMESTAMPS[phase as usize].load(Ordering::Relaxed);
+    if at == 0 {
+        return None;
+    }
+    let elapsed = hal_impl::time::INSTANCE.to_duration(at.saturating_sub(start))?;
+    Some(elapsed.as_micros().try_into().unwrap_or(u64::MAX))
+}
+
+/// Single-line summary of every recorded phase
+struct Summary;
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        if let Some((0, name)) = origin() {
+            write!(f, "{name}=0us")?;
+            first = false;
+        }
+        for phase in Phase::ALL {
+            if let Some(micros) = elapsed_micros(phase) {
+                if !first {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{}={}us", phase.name(), micros)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Log how long each phase of boot took
+pub fn report() {
+    let _span = tracing::info_span!("boot_time").entered();
+    let Some((_, origin)) = origin() else {
+        return;
+    };
+    let mut previous = 0;
+    for phase in Phase::ALL {
+        let Some(micros) = elapsed_micros(phase) else {
+            continue;
+        };
+        let phase_us = micros.saturating_sub(previous);
+        tracing::info!(
+            elapsed_us = micros,
+            phase_us,
+            "{}: {} ({} since {})",
+            phase.name(),
+            HumanDuration(Duration::from_micros(phase_us)),
+            HumanDuration(Duration::from_micros(micros)),
+            origin
+        );
+        previous = micros;
+    }
+    tracing::info!(target: BOOT_TIME_TARGET, "{}", Summary);
+}
@@ -0,0 +1,74 @@
+//! The kernel's build ID.
+//!
+//! The kernel is linked with `--build-id=sha1`, which stores a hash of the
+//! image in an ELF note. Crash reports include it, so the host can tell
+//! whether the binary it symbolizes them with is the one that crashed.
+
+use core::slice;
+
+use platypos_ktrace::proto::BUILD_ID_LEN;
+
+// Bounds of the build ID note, defined in link/sections.ld
+extern "C" {
+    static __build_id_start: u8;
+    static __build_id_end: u8;
+}
+
+/// ELF note type of a GNU build ID
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// The kernel's build ID, if it was linked with one
+pub fn build_id() -> Option<[u8; BUILD_ID_LEN]> {
+    // SAFETY: the linker script defines these around the build ID note, which is loaded along with
+    // the rest of the image
+    let note = unsafe {
+        let start = &__build_id_start as *const u8;
+        let end = &__build_id_end as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    parse_note(note)
+}
+
+/// Parse an ELF note holding a GNU build ID. The note is a header with the
+/// name size, descriptor size, and type, followed by the name and then the
+/// descriptor, each padded to 4 bytes.
+fn parse_note(note: &[u8]) -> Option<[u8; BUILD_ID_LEN]> {
+    let word = |offset: usize| {
+        let bytes = note.get(offset..offset + 4)?;
+        Some(u32::from_ne_bytes(bytes.try_into().ok()?) as usize)
+    };
+    let (name_size, desc_size, ty) = (word(0)?, word(4)?, word(8)?);
+    if ty != NT_GNU_BUILD_ID as usize || desc_size != BUILD_ID_LEN {
+        return None;
+    }
+    let name_end = 12 + name_size;
+    if note.get(12..name_end)? != b"GNU\0" {
+        return None;
+    }
+    let desc = name_end.next_multiple_of(4);
+    note.get(desc..desc + BUILD_ID_LEN)?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_parse_note() {
+        let mut note = [0u8; 16 + BUILD_ID_LEN];
+        note[0..4].copy_from_slice(&4u32.to_ne_bytes());
+        note[4..8].copy_from_slice(&(BUILD_ID_LEN as u32).to_ne_bytes());
+        note[8..12].copy_from_slice(&NT_GNU_BUILD_ID.to_ne_bytes());
+        note[12..16].copy_from_slice(b"GNU\0");
+        for (i, byte) in note[16..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        ktassert_eq!(parse_note(&note), Some(core::array::from_fn(|i| i as u8)));
+
+        ktassert_eq!(parse_note(&note[..note.len() - 1]), None);
+        note[8] = 1;
+        ktassert_eq!(parse_note(&note), None);
+    }
+}
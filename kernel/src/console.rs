@@ -1,4 +1,8 @@
-//! Text console
+//! Text console.
+//!
+//! The console draws text on the framebuffer if the bootloader provided one.
+//! Otherwise, it falls back to VGA text mode, so that systems without a
+//! graphics output protocol still show output.
 
 use core::fmt;
 
@@ -8,9 +12,80 @@ use embedded_graphics::prelude::*;
 use embedded_graphics::text::renderer::TextRenderer;
 use embedded_graphics::text::{Alignment, Text, TextStyle};
 
-use crate::arch::display::{Color, Display, Error};
+use crate::arch::display::{Color, Display};
+use crate::arch::mm::MemoryAccess;
+use crate::arch::vga::TextBuffer;
+use crate::error::{Error, ResultExt};
 
-pub struct Console {
+use self::vga::VgaConsole;
+
+// There's no shell to read input for yet, so only tests use this
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod line_editor;
+mod vga;
+
+pub enum Console {
+    Framebuffer(FramebufferConsole),
+    Vga(VgaConsole),
+}
+
+impl Console {
+    /// Create a console on `display` if there is one, or in VGA text mode
+    /// otherwise.
+    ///
+    /// # Safety
+    /// Only one console may be created, since it takes over the display or
+    /// text buffer.
+    pub unsafe fn new(display: Option<Display>, access: &MemoryAccess) -> Result<Self, Error> {
+        match display {
+            Some(display) => Ok(Console::Framebuffer(FramebufferConsole::new(display))),
+            None => {
+                tracing::info!("No framebuffer, using a VGA text mode console");
+                let buffer = TextBuffer::new(access).context("mapping the VGA text buffer")?;
+                Ok(Console::Vga(VgaConsole::new(buffer)))
+            }
+        }
+    }
+
+    pub fn write(&mut self, s: &str) -> Result<(), Error> {
+        match self {
+            Console::Framebuffer(console) => console.write(s),
+            Console::Vga(console) => {
+                console.write(s);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn clear(&mut self) -> Result<(), Error> {
+        match self {
+            Console::Framebuffer(console) => console.clear(),
+            Console::Vga(console) => {
+                console.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// Make room for another line at the bottom of the console
+    pub fn scroll(&mut self) -> Result<(), Error> {
+        match self {
+            Console::Framebuffer(console) => console.scroll(),
+            Console::Vga(console) => {
+                console.scroll();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s).map_err(|_| fmt::Error)
+    }
+}
+
+pub struct FramebufferConsole {
     text_style: TextStyle,
     character_style: MonoTextStyle<'static, Color>,
     cursor: Point,
@@ -27,7 +102,7 @@ const BG_COLOR: Color = Color::BLACK;
 // TODO: consider the embedded-text crate, although it doesn't support appending
 // + reflowing text
 
-impl Console {
+impl FramebufferConsole {
     pub fn new(display: Display) -> Self {
         let text_style = TextStyle::with_alignment(Alignment::Left);
         let character_style = MonoTextStyle::new(&ascii::FONT_10X20, FG_COLOR);
@@ -78,7 +153,8 @@ impl Console {
                     self.character_style,
                     self.text_style,
                 )
-                .draw(&mut self.display)?;
+                .draw(&mut self.display)
+                .context("drawing console text")?;
                 self.newline()?;
 
                 line_start = if ch == '\n' { idx + 1 } else { idx };
@@ -94,13 +170,14 @@ impl Console {
                 self.character_style,
                 self.text_style,
             )
-            .draw(&mut self.display)?;
+            .draw(&mut self.display)
+            .context("drawing console text")?;
         }
         Ok(())
     }
 
     pub fn clear(&mut self) -> Result<(), Error> {
-        self.display.clear(BG_COLOR)?;
+        self.display.clear(BG_COLOR).context("clearing console")?;
         self.cursor = self.origin;
         Ok(())
     }
@@ -108,13 +185,19 @@ impl Console {
     pub fn newline(&mut self) -> Result<(), Error> {
         let new_y = self.cursor.y + line_height(&self.text_style, &self.character_style);
         if new_y > self.display.size().height.saturating_cast() {
-            self.clear()
+            self.scroll()
         } else {
             self.cursor = Point::new(MARGIN, new_y);
             Ok(())
         }
     }
 
+    /// Reading back the framebuffer to move lines up is slow, so scrolling
+    /// starts over from the top instead
+    pub fn scroll(&mut self) -> Result<(), Error> {
+        self.clear()
+    }
+
     /// Gets the underlying display
     #[inline(always)]
     #[allow(dead_code)]
@@ -123,12 +206,6 @@ impl Console {
     }
 }
 
-impl fmt::Write for Console {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write(s).map_err(|_| fmt::Error)
-    }
-}
-
 fn line_height<S: TextRenderer>(text_style: &TextStyle, character_style: &S) -> i32 {
     text_style
         .line_height
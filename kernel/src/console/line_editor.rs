@@ -0,0 +1,363 @@
+//! Line editing and command history for interactive input.
+//!
+//! [`LineEditor`] only tracks the line being edited. It doesn't do any I/O, so
+//! the same editor works for serial input and the framebuffer console: input
+//! sources decode keystrokes into [`Key`]s, and after each one the renderer
+//! redraws [`LineEditor::line`] with the cursor at [`LineEditor::cursor`].
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Maximum length of a line, in characters. Input past this is ignored.
+pub const MAX_LINE_LENGTH: usize = 256;
+
+/// Number of submitted lines kept in the history
+pub const HISTORY_SIZE: usize = 32;
+
+/// An editing keystroke
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// Insert a character at the cursor
+    Char(char),
+    /// Delete the character before the cursor
+    Backspace,
+    /// Delete the character under the cursor
+    Delete,
+    Left,
+    Right,
+    /// Move to the start of the line
+    Home,
+    /// Move to the end of the line
+    End,
+    /// Delete from the cursor to the end of the line
+    KillLine,
+    /// Delete from the start of the line to the cursor
+    KillToStart,
+    /// Replace the line with the previous history entry
+    Up,
+    /// Replace the line with the next history entry
+    Down,
+    /// Submit the line
+    Enter,
+}
+
+impl Key {
+    /// Decode a byte from a terminal, using the usual Emacs-style control keys
+    /// for movement since they don't need escape sequences. Returns `None` for
+    /// bytes that aren't editing keys.
+    pub fn from_ascii(byte: u8) -> Option<Key> {
+        Some(match byte {
+            b'\r' | b'\n' => Key::Enter,
+            0x08 | 0x7f => Key::Backspace,
+            0x04 => Key::Delete,      // Ctrl-D
+            0x02 => Key::Left,        // Ctrl-B
+            0x06 => Key::Right,       // Ctrl-F
+            0x01 => Key::Home,        // Ctrl-A
+            0x05 => Key::End,         // Ctrl-E
+            0x0b => Key::KillLine,    // Ctrl-K
+            0x15 => Key::KillToStart, // Ctrl-U
+            0x10 => Key::Up,          // Ctrl-P
+            0x0e => Key::Down,        // Ctrl-N
+            b' '..=b'~' => Key::Char(byte as char),
+            _ => return None,
+        })
+    }
+}
+
+/// Result of handling a keystroke
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    /// Nothing visible changed
+    Unchanged,
+    /// The line or cursor changed, so it should be redrawn
+    Redraw,
+    /// A line was submitted. The editor is now empty.
+    Submit(String),
+}
+
+/// Editor for a single line of input, with command history
+pub struct LineEditor {
+    line: Vec<char>,
+    /// Cursor position, as an index into `line`
+    cursor: usize,
+    /// Submitted lines, oldest first
+    history: VecDeque<String>,
+    /// Index into `history` of the entry being shown, if browsing history
+    browsing: Option<usize>,
+    /// The line being edited before browsing history, restored by moving past
+    /// the newest entry
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            browsing: None,
+            draft: Vec::new(),
+        }
+    }
+
+    /// The line being edited
+    pub fn line(&self) -> impl Iterator<Item = char> + '_ {
+        self.line.iter().copied()
+    }
+
+    /// The cursor position, in characters from the start of the line
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Submitted lines, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Apply a keystroke
+    pub fn handle(&mut self, key: Key) -> Edit {
+        match key {
+            Key::Char(c) => {
+                if self.line.len() >= MAX_LINE_LENGTH {
+                    return Edit::Unchanged;
+                }
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Backspace => {
+                if self.cursor == 0 {
+                    return Edit::Unchanged;
+                }
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete => {
+                if self.cursor == self.line.len() {
+                    return Edit::Unchanged;
+                }
+                self.line.remove(self.cursor);
+            }
+            Key::Left => return self.move_to(self.cursor.saturating_sub(1)),
+            Key::Right => return self.move_to((self.cursor + 1).min(self.line.len())),
+            Key::Home => return self.move_to(0),
+            Key::End => return self.move_to(self.line.len()),
+            Key::KillLine => {
+                if self.cursor == self.line.len() {
+                    return Edit::Unchanged;
+                }
+                self.line.truncate(self.cursor);
+            }
+            Key::KillToStart => {
+                if self.cursor == 0 {
+                    return Edit::Unchanged;
+                }
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Up => {
+                let previous = match self.browsing {
+                    Some(0) => return Edit::Unchanged,
+                    Some(index) => index - 1,
+                    None if self.history.is_empty() => return Edit::Unchanged,
+                    None => {
+                        self.draft = core::mem::take(&mut self.line);
+                        self.history.len() - 1
+                    }
+                };
+                self.show_history(Some(previous));
+            }
+            Key::Down => match self.browsing {
+                None => return Edit::Unchanged,
+                Some(index) if index + 1 < self.history.len() => self.show_history(Some(index + 1)),
+                Some(_) => self.show_history(None),
+            },
+            Key::Enter => return Edit::Submit(self.submit()),
+        }
+        Edit::Redraw
+    }
+
+    fn move_to(&mut self, cursor: usize) -> Edit {
+        if cursor == self.cursor {
+            Edit::Unchanged
+        } else {
+            self.cursor = cursor;
+            Edit::Redraw
+        }
+    }
+
+    /// Replace the line with history entry `index`, or the draft if `None`
+    fn show_history(&mut self, index: Option<usize>) {
+        self.browsing = index;
+        self.line = match index {
+            Some(index) => self.history[index].chars().collect(),
+            None => core::mem::take(&mut self.draft),
+        };
+        self.cursor = self.line.len();
+    }
+
+    fn submit(&mut self) -> String {
+        let line: String = self.line.drain(..).collect();
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+
+        // Skip blank lines and immediate repeats, like most shells
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use ktest::*;
+
+    use super::*;
+
+    fn type_str(editor: &mut LineEditor, s: &str) {
+        for c in s.chars() {
+            editor.handle(Key::Char(c));
+        }
+    }
+
+    fn line(editor: &LineEditor) -> String {
+        editor.line().collect()
+    }
+
+    #[ktest::test]
+    fn test_insert() {
+        let mut editor = LineEditor::new();
+        ktassert_eq!(editor.handle(Key::Char('b')), Edit::Redraw);
+        editor.handle(Key::Home);
+        ktassert_eq!(editor.handle(Key::Char('a')), Edit::Redraw);
+        editor.handle(Key::End);
+        type_str(&mut editor, "c");
+        ktassert_eq!(line(&editor), "abc".to_string());
+        ktassert_eq!(editor.cursor(), 3);
+
+        // Input past the maximum length is dropped
+        type_str(&mut editor, &"x".repeat(MAX_LINE_LENGTH));
+        ktassert_eq!(editor.line().count(), MAX_LINE_LENGTH);
+        ktassert_eq!(editor.handle(Key::Char('y')), Edit::Unchanged);
+        ktassert!(!editor.line().any(|c| c == 'y'));
+    }
+
+    #[ktest::test]
+    fn test_backspace() {
+        let mut editor = LineEditor::new();
+        ktassert_eq!(editor.handle(Key::Backspace), Edit::Unchanged);
+
+        type_str(&mut editor, "abcd");
+        ktassert_eq!(editor.handle(Key::Backspace), Edit::Redraw);
+        ktassert_eq!(line(&editor), "abc".to_string());
+        ktassert_eq!(editor.cursor(), 3);
+
+        // In the middle of the line, the characters after the cursor move back
+        editor.handle(Key::Left);
+        editor.handle(Key::Backspace);
+        ktassert_eq!(line(&editor), "ac".to_string());
+        ktassert_eq!(editor.cursor(), 1);
+
+        editor.handle(Key::Backspace);
+        ktassert_eq!(line(&editor), "c".to_string());
+        ktassert_eq!(editor.handle(Key::Backspace), Edit::Unchanged);
+        ktassert_eq!(line(&editor), "c".to_string());
+    }
+
+    #[ktest::test]
+    fn test_edit_in_middle() {
+        let mut editor = LineEditor::new();
+        type_str(&mut editor, "helo");
+        editor.handle(Key::Left);
+        type_str(&mut editor, "l");
+        ktassert_eq!(line(&editor), "hello".to_string());
+        ktassert_eq!(editor.cursor(), 4);
+
+        editor.handle(Key::Home);
+        editor.handle(Key::Delete);
+        ktassert_eq!(line(&editor), "ello".to_string());
+        ktassert_eq!(editor.handle(Key::Backspace), Edit::Unchanged);
+
+        editor.handle(Key::Right);
+        editor.handle(Key::KillLine);
+        ktassert_eq!(line(&editor), "e".to_string());
+
+        type_str(&mut editor, "xyz");
+        editor.handle(Key::Left);
+        editor.handle(Key::KillToStart);
+        ktassert_eq!(line(&editor), "z".to_string());
+        ktassert_eq!(editor.cursor(), 0);
+    }
+
+    #[ktest::test]
+    fn test_history() {
+        let mut editor = LineEditor::new();
+        ktassert_eq!(editor.handle(Key::Up), Edit::Unchanged);
+
+        for command in ["first", "second", "second", "  "] {
+            type_str(&mut editor, command);
+            ktassert_eq!(editor.handle(Key::Enter), Edit::Submit(command.to_string()));
+        }
+        // Repeats and blank lines aren't recorded
+        ktassert_eq!(editor.history().count(), 2);
+
+        type_str(&mut editor, "draft");
+        editor.handle(Key::Up);
+        ktassert_eq!(line(&editor), "second".to_string());
+        editor.handle(Key::Up);
+        ktassert_eq!(line(&editor), "first".to_string());
+        ktassert_eq!(editor.handle(Key::Up), Edit::Unchanged);
+        editor.handle(Key::Down);
+        editor.handle(Key::Down);
+        ktassert_eq!(line(&editor), "draft".to_string());
+        ktassert_eq!(editor.cursor(), 5);
+    }
+
+    #[ktest::test]
+    fn test_editing_history_entry() {
+        let mut editor = LineEditor::new();
+        ktassert_eq!(editor.handle(Key::Down), Edit::Unchanged);
+        type_str(&mut editor, "ls");
+        editor.handle(Key::Enter);
+
+        // Editing a recalled line submits the edited copy, and leaves the entry alone
+        editor.handle(Key::Up);
+        editor.handle(Key::Backspace);
+        type_str(&mut editor, "sblk");
+        ktassert_eq!(editor.handle(Key::Enter), Edit::Submit("lsblk".to_string()));
+        ktassert_eq!(editor.history().collect::<Vec<_>>(), ["ls", "lsblk"]);
+
+        // The editor starts over with an empty line, past the newest entry
+        ktassert_eq!(editor.line().count(), 0);
+        ktassert_eq!(editor.handle(Key::Down), Edit::Unchanged);
+        editor.handle(Key::Up);
+        ktassert_eq!(line(&editor), "lsblk".to_string());
+    }
+
+    #[ktest::test]
+    fn test_history_is_bounded() {
+        let mut editor = LineEditor::new();
+        for i in 0..HISTORY_SIZE + 3 {
+            type_str(&mut editor, &i.to_string());
+            editor.handle(Key::Enter);
+        }
+        ktassert_eq!(editor.history().count(), HISTORY_SIZE);
+        ktassert_eq!(editor.history().next(), Some("3"));
+    }
+
+    #[ktest::test]
+    fn test_from_ascii() {
+        ktassert_eq!(Key::from_ascii(b'a'), Some(Key::Char('a')));
+        ktassert_eq!(Key::from_ascii(0x7f), Some(Key::Backspace));
+        ktassert_eq!(Key::from_ascii(0x10), Some(Key::Up));
+        ktassert_eq!(Key::from_ascii(0x1b), None);
+    }
+}
@@ -0,0 +1,79 @@
+//! Console backend for VGA text mode, for when there's no framebuffer.
+
+use crate::arch::vga::TextBuffer;
+
+/// Light green on black, to match the framebuffer console
+const ATTRIBUTE: u8 = 0x0a;
+
+/// Code page 437 character for anything that isn't printable ASCII (`■`)
+const REPLACEMENT: u8 = 0xfe;
+
+pub struct VgaConsole {
+    buffer: TextBuffer,
+    row: usize,
+    column: usize,
+}
+
+impl VgaConsole {
+    pub fn new(buffer: TextBuffer) -> Self {
+        Self {
+            buffer,
+            row: 0,
+            column: 0,
+        }
+    }
+
+    pub fn write(&mut self, s: &str) {
+        for ch in s.chars() {
+            match ch {
+                '\n' => self.newline(),
+                '\r' => self.column = 0,
+                ch => {
+                    if self.column == TextBuffer::WIDTH {
+                        self.newline();
+                    }
+                    let byte = match ch {
+                        ' '..='~' => ch as u8,
+                        _ => REPLACEMENT,
+                    };
+                    self.buffer.write(self.row, self.column, byte, ATTRIBUTE);
+                    self.column += 1;
+                }
+            }
+        }
+        self.buffer
+            .set_cursor(self.row, self.column.min(TextBuffer::WIDTH - 1));
+    }
+
+    pub fn clear(&mut self) {
+        for row in 0..TextBuffer::HEIGHT {
+            self.clear_row(row);
+        }
+        self.row = 0;
+        self.column = 0;
+        self.buffer.set_cursor(0, 0);
+    }
+
+    pub fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < TextBuffer::HEIGHT {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Move every line up by one, discarding the top line
+    pub fn scroll(&mut self) {
+        for row in 1..TextBuffer::HEIGHT {
+            self.buffer.copy_row(row, row - 1);
+        }
+        self.clear_row(TextBuffer::HEIGHT - 1);
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        for column in 0..TextBuffer::WIDTH {
+            self.buffer.write(row, column, b' ', ATTRIBUTE);
+        }
+    }
+}
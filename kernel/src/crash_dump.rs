@@ -0,0 +1,143 @@
+//! Crash dumps kept in memory across a reboot.
+//!
+//! Crash reports normally go out over the serial port, so they're lost if
+//! nothing was listening. When the kernel panics, it also writes a
+//! [dump](platypos_ktrace::dump) of the report and the last trace messages
+//! before it into a page of memory set aside for it. On the next boot, a
+//! valid dump is sent to the host as a crash from the previous boot, and
+//! then cleared.
+//!
+//! The loader is an external crate, so its boot info can't describe the
+//! dump's memory. Instead, the kernel uses the last page frame of the highest
+//! usable memory region, which is in the same place every boot as long as the
+//! memory map doesn't change, and removes it from the root allocator as soon
+//! as that's set up. Boot memory and the root allocator's own bookkeeping
+//! take frames from the start of a region, so they don't reach it before
+//! then. Nothing stops the firmware or loader from using that page while
+//! booting, in which case the dump just doesn't validate. QEMU keeps memory
+//! intact across a reset, so a reboot after a panic (`--panic-policy reboot`)
+//! finds the dump.
+
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use platypos_ktrace::dump;
+use platypos_ktrace::proto::CrashReport;
+
+use crate::arch::mm::MemoryAccess;
+use crate::mm::map::Region;
+use crate::mm::root_allocator::Allocator;
+use crate::prelude::*;
+
+/// Page frames set aside for the dump
+const DUMP_FRAMES: usize = 1;
+
+/// Where the dump is mapped, or null until [`init`] sets it up. It's taken
+/// when a dump is written, so a panic while writing one can't write another.
+static DUMP: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+/// Take the crash dump's memory out of `allocator`, report and clear any
+/// dump the previous boot left there, and set up dumps for this boot. This
+/// must be called right after the root allocator is set up.
+pub fn init(access: &MemoryAccess, allocator: &Allocator, regions: &[Region]) {
+    let Some(range) = dump_range(regions) else {
+        tracing::warn!("No room for crash dumps");
+        return;
+    };
+    if let Err(err) = allocator.remove_range(range) {
+        tracing::warn!("Could not set aside memory for crash dumps: {}", err);
+        return;
+    }
+    // SAFETY: the dump's frames were just removed from the allocator, so nothing else uses them
+    let base = match unsafe { access.map_permanent(range) } {
+        Ok(base) => base.cast::<u8>(),
+        Err(err) => {
+            tracing::warn!("Could not map the crash dump: {}", err);
+            return;
+        }
+    };
+    // SAFETY: `base` maps all of `range`, which only this module uses. Memory that was never
+    // written holds arbitrary bytes, which isn't a valid dump.
+    let region = unsafe { slice::from_raw_parts_mut(base, range.size() * PAGE_SIZE) };
+
+    if let Some(dump) = dump::read(region) {
+        tracing::warn!("The previous boot crashed: {}", dump.report.reason);
+        platypos_ktrace::report_previous_crash(&dump);
+    }
+    dump::clear(region);
+    DUMP.store(base, Ordering::Release);
+}
+
+/// Save `report` and the tail of the trace in the crash dump, if it's set up.
+/// This is only called while panicking, after the trace is flushed.
+pub fn write(report: &CrashReport) {
+    let base = DUMP.swap(ptr::null_mut(), Ordering::AcqRel);
+    if base.is_null() {
+        return;
+    }
+    let mut trace = [0; dump::MAX_TRACE_LEN];
+    let len = crate::trace::copy_tail(&mut trace);
+    // SAFETY: `init` mapped the dump at `base`, and swapping it out means nothing else is writing
+    // it
+    let region = unsafe { slice::from_raw_parts_mut(base, DUMP_FRAMES * PAGE_SIZE) };
+    dump::write(region, report, &trace[..len]);
+}
+
+/// The last [`DUMP_FRAMES`] whole page frames of the highest usable region
+fn dump_range(regions: &[Region]) -> Option<PageFrameRange> {
+    let region = regions.iter().rev().find(|r| r.usable())?;
+    let end = PageFrame::containing(region.end()).as_usize();
+    let start = PageFrame::new(end.checked_sub(DUMP_FRAMES)?);
+    (start.start_address() >= region.start())
+        .then(|| PageFrameRange::from_start_size(start, DUMP_FRAMES))
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+    use platypos_ktrace::proto::CrashSource;
+
+    use super::*;
+    use crate::mm::map::Kind;
+
+    fn region(kind: Kind, start: usize, end: usize) -> Region {
+        Region::new(kind, PhysicalAddress::new(start), PhysicalAddress::new(end))
+    }
+
+    #[ktest::test]
+    fn test_dump_range() {
+        let regions = [
+            region(Kind::Usable, 0x1000, 0x8000),
+            region(Kind::Usable, 0x10000, 0x20800),
+            region(Kind::Reserved, 0x30000, 0x40000),
+        ];
+        // The partial frame at the end of the region isn't used
+        ktassert_eq!(
+            dump_range(&regions),
+            Some(PageFrameRange::from_start_size(PageFrame::new(0x1f), 1))
+        );
+
+        ktassert_eq!(dump_range(&[region(Kind::Usable, 0x1800, 0x2000)]), None);
+        ktassert_eq!(dump_range(&[region(Kind::Reserved, 0x0, 0x8000)]), None);
+    }
+
+    #[ktest::test]
+    fn test_dump_round_trip() {
+        let mut region = [0u8; PAGE_SIZE];
+        ktassert!(dump::read(&region).is_none());
+
+        let report = CrashReport::new(CrashSource::Kernel, "out of frames");
+        ktassert!(dump::write(&mut region, &report, b"trace"));
+        let dump = dump::read(&region).unwrap();
+        ktassert_eq!(dump.report, report);
+        ktassert_eq!(dump.trace, b"trace");
+
+        // A dump that was only partly written, or has since been overwritten, isn't valid
+        region[dump::HEADER_LEN] ^= 0xff;
+        ktassert!(dump::read(&region).is_none());
+
+        dump::clear(&mut region);
+        ktassert!(dump::read(&region).is_none());
+    }
+}
@@ -0,0 +1,157 @@
+//! Debug hotkeys.
+//!
+//! Function keys on the PS/2 keyboard run debugging commands, for poking at a
+//! running kernel before there's a shell. Under QEMU, `sendkey f1` in the
+//! monitor presses a key too.
+//!
+//! | Key | Command                                                   |
+//! |-----|-----------------------------------------------------------|
+//! | F1  | Interrupt counts and rates ([`interrupt_stats::report`])  |
+//! | F2  | Trace output statistics ([`trace::log_stats`])            |
+//! | F3  | Unmask storming vectors ([`interrupt_stats::unmask_all`]) |
+//! | F4  | Interrupt handler latency ([`interrupt_stats::latency`])  |
+//! | F5  | Claimed I/O ports and memory ([`resources::dump`])        |
+//! | F6  | Live kernel objects ([`kobject::dump_stats`])             |
+//!
+//! Keys are decoded from scan code set 2, since the PS/2 driver turns off
+//! translation. Commands run on the work queue rather than in the keyboard's
+//! interrupt handler.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::drivers::ps2::{self, DeviceKind, Event, Port};
+use crate::interrupt_stats;
+use crate::kobject;
+use crate::resources;
+use crate::trace;
+use crate::workqueue::{self, WorkItem};
+
+/// Prefix byte for keys that aren't in the original XT layout
+const EXTENDED_PREFIX: u8 = 0xe0;
+/// Prefix byte for key releases
+const BREAK_PREFIX: u8 = 0xf0;
+
+/// Set 2 make codes of the bound keys, and the commands they run
+const BINDINGS: &[(u8, fn())] = &[
+    (0x05, interrupt_stats::report),
+    (0x06, trace::log_stats),
+    (0x04, interrupt_stats::unmask_all),
+    (0x0c, interrupt_stats::latency),
+    (0x03, resources::dump),
+    (0x0b, kobject::dump_stats),
+];
+
+/// Which port the keyboard is on: 0 for none, otherwise the port number
+static KEYBOARD_PORT: AtomicU8 = AtomicU8::new(0);
+
+static DECODER: Decoder = Decoder::new();
+
+/// Start listening for hotkeys. This must be called before [`ps2::init`], so
+/// that it sees which port the keyboard is on.
+pub fn init() {
+    ps2::set_data_handler(handle_byte);
+    ps2::set_event_handler(handle_event);
+}
+
+fn port_number(port: Port) -> u8 {
+    match port {
+        Port::First => 1,
+        Port::Second => 2,
+    }
+}
+
+fn handle_event(event: Event) {
+    match event {
+        Event::Connected(port, DeviceKind::Keyboard) => {
+            KEYBOARD_PORT.store(port_number(port), Ordering::Relaxed);
+        }
+        Event::Connected(port, _) | Event::Disconnected(port) => {
+            let _ = KEYBOARD_PORT.compare_exchange(
+                port_number(port),
+                0,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+    tracing::info!("{}", event);
+}
+
+/// Data handler, run from the keyboard's interrupt handler
+fn handle_byte(port: Port, byte: u8) {
+    if KEYBOARD_PORT.load(Ordering::Relaxed) != port_number(port) {
+        return;
+    }
+    if let Some(index) = DECODER.push(byte) {
+        if let Err(err) = workqueue::queue(WorkItem::new(run_binding, index)) {
+            tracing::warn!("Dropped debug hotkey: {}", err);
+        }
+    }
+}
+
+/// Work item that runs `BINDINGS[index]`
+fn run_binding(index: usize) {
+    (BINDINGS[index].1)();
+}
+
+/// Scan code decoder state. Only the keyboard's interrupt handler feeds it, so
+/// it's never used concurrently.
+struct Decoder {
+    /// Prefix bytes seen since the last complete scan code
+    prefixes: AtomicU8,
+}
+
+impl Decoder {
+    const EXTENDED: u8 = 1 << 0;
+    const BREAK: u8 = 1 << 1;
+
+    const fn new() -> Self {
+        Self {
+            prefixes: AtomicU8::new(0),
+        }
+    }
+
+    /// Feed the decoder the next byte from the keyboard, returning the index
+    /// of the binding for a bound key that was just pressed
+    fn push(&self, byte: u8) -> Option<usize> {
+        let prefix = match byte {
+            EXTENDED_PREFIX => Self::EXTENDED,
+            BREAK_PREFIX => Self::BREAK,
+            _ => {
+                // Bound keys are all unextended, and only presses run them
+                let prefixes = self.prefixes.swap(0, Ordering::Relaxed);
+                if prefixes != 0 {
+                    return None;
+                }
+                return BINDINGS.iter().position(|&(code, _)| code == byte);
+            }
+        };
+        self.prefixes.fetch_or(prefix, Ordering::Relaxed);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_decode_presses() {
+        let decoder = Decoder::new();
+        ktassert_eq!(decoder.push(0x05), Some(0));
+        // Releasing the key doesn't run it again
+        ktassert_eq!(decoder.push(BREAK_PREFIX), None);
+        ktassert_eq!(decoder.push(0x05), None);
+        // Neither does an extended key with the same code
+        ktassert_eq!(decoder.push(EXTENDED_PREFIX), None);
+        ktassert_eq!(decoder.push(0x05), None);
+        ktassert_eq!(decoder.push(EXTENDED_PREFIX), None);
+        ktassert_eq!(decoder.push(BREAK_PREFIX), None);
+        ktassert_eq!(decoder.push(0x05), None);
+        // Unbound keys are ignored
+        ktassert_eq!(decoder.push(0x1c), None);
+        ktassert_eq!(decoder.push(0x05), Some(0));
+    }
+}
@@ -0,0 +1,10 @@
+//! Device drivers.
+
+#[cfg(target_arch = "x86_64")]
+pub mod fw_cfg;
+#[cfg(target_arch = "x86_64")]
+pub mod pci;
+#[cfg(target_arch = "x86_64")]
+pub mod ps2;
+#[cfg(target_arch = "x86_64")]
+pub mod virtio_blk;
@@ -0,0 +1,148 @@
+//! QEMU firmware configuration (fw_cfg) driver.
+//!
+//! fw_cfg lets the host pass named blobs to the guest, with QEMU's `-fw_cfg
+//! name=...,file=...` option. The kernel uses it to receive test filters and
+//! other configuration at boot without being rebuilt. By convention, the
+//! kernel's own blobs are named under [`PREFIX`].
+//!
+//! This uses the legacy I/O port interface, which reads one byte at a time.
+//! That's slow, but fine for the small blobs the kernel reads.
+//!
+//! See <https://www.qemu.org/docs/master/specs/fw_cfg.html>
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use platypos_common::sync::Global;
+use x86_64::instructions::port::Port as IoPort;
+
+use crate::prelude::*;
+
+/// Prefix for blobs intended for PlatypOS. QEMU reserves the `opt/` namespace
+/// for user-provided blobs.
+pub const PREFIX: &str = "opt/platypos/";
+
+/// Selector register, written to choose an item
+const SELECTOR_PORT: u16 = 0x510;
+/// Data register, read to get the selected item's next byte
+const DATA_PORT: u16 = 0x511;
+
+// Well-known items
+const SIGNATURE: u16 = 0x0000;
+const FILE_DIR: u16 = 0x0019;
+
+/// Expected contents of the signature item
+const QEMU_SIGNATURE: &[u8; 4] = b"QEMU";
+
+/// Length of the name field in a file directory entry
+const NAME_LENGTH: usize = 56;
+
+static FW_CFG: Global<InterruptSafeMutex<'static, FwCfg>> = Global::new();
+
+/// A named blob in the fw_cfg file directory
+#[derive(Debug, Clone)]
+pub struct File {
+    pub name: String,
+    pub size: usize,
+    select: u16,
+}
+
+struct FwCfg {
+    selector: IoPort<u16>,
+    data: IoPort<u8>,
+    files: Vec<File>,
+}
+
+/// Detect fw_cfg and read its file directory. If the kernel isn't running
+/// under QEMU, this logs that fw_cfg is unavailable and leaves it disabled.
+pub fn init(controller: &'static hal_impl::interrupts::Controller) {
+    let _span = tracing::info_span!("fw_cfg_init").entered();
+
+    let mut fw_cfg = FwCfg {
+        selector: IoPort::new(SELECTOR_PORT),
+        data: IoPort::new(DATA_PORT),
+        files: Vec::new(),
+    };
+
+    let mut signature = [0; 4];
+    fw_cfg.read_item(SIGNATURE, &mut signature);
+    if &signature != QEMU_SIGNATURE {
+        tracing::info!("fw_cfg unavailable");
+        return;
+    }
+
+    fw_cfg.files = fw_cfg.read_directory();
+    for file in fw_cfg.files.iter().filter(|f| f.name.starts_with(PREFIX)) {
+        tracing::info!("fw_cfg provides {} ({})", file.name, file.size.as_size());
+    }
+    FW_CFG.init(InterruptSafeMutex::new(controller, fw_cfg));
+}
+
+/// The blobs provided by the host
+pub fn files() -> Vec<File> {
+    FW_CFG
+        .try_get()
+        .map(|f| f.lock().files.clone())
+        .unwrap_or_default()
+}
+
+/// Read the contents of blob `name`
+///
+/// # Errors
+/// If fw_cfg isn't available, or there's no blob named `name`
+pub fn read(name: &str) -> Result<Vec<u8>, Error> {
+    let fw_cfg = FW_CFG
+        .try_get()
+        .ok_or(Error::new(DriverError::Unsupported))
+        .context("fw_cfg unavailable")?;
+
+    let mut fw_cfg = fw_cfg.lock();
+    let file = fw_cfg
+        .files
+        .iter()
+        .find(|f| f.name == name)
+        .ok_or(Error::new(IoError::NotFound))?;
+    let mut contents = alloc::vec![0; file.size];
+    let select = file.select;
+    fw_cfg.read_item(select, &mut contents);
+    Ok(contents)
+}
+
+impl FwCfg {
+    /// Select `item` and read the start of it into `buf`
+    fn read_item(&mut self, item: u16, buf: &mut [u8]) {
+        // SAFETY: the fw_cfg ports have no side effects besides selecting and reading items, and
+        // the lock keeps anyone else from changing the selection while this reads
+        unsafe {
+            self.selector.write(item);
+            for byte in buf {
+                *byte = self.data.read();
+            }
+        }
+    }
+
+    fn read_directory(&mut self) -> Vec<File> {
+        let mut count = [0; 4];
+        self.read_item(FILE_DIR, &mut count);
+        let count = u32::from_be_bytes(count) as usize;
+
+        // The directory is a count followed by entries, and reading always starts at the
+        // beginning of an item, so read it all at once
+        const ENTRY_LENGTH: usize = 8 + NAME_LENGTH;
+        let mut directory = alloc::vec![0; 4 + count * ENTRY_LENGTH];
+        self.read_item(FILE_DIR, &mut directory);
+
+        directory[4..]
+            .chunks_exact(ENTRY_LENGTH)
+            .map(|entry| {
+                let name = &entry[8..];
+                let name_length = name.iter().position(|&b| b == 0).unwrap_or(NAME_LENGTH);
+                File {
+                    name: String::from_utf8_lossy(&name[..name_length]).into_owned(),
+                    size: u32::from_be_bytes(entry[0..4].try_into().unwrap()) as usize,
+                    select: u16::from_be_bytes(entry[4..6].try_into().unwrap()),
+                }
+            })
+            .collect()
+    }
+}
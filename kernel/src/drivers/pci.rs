@@ -0,0 +1,217 @@
+//! PCI configuration space access and device enumeration.
+//!
+//! This uses the legacy I/O port configuration mechanism, which only reaches
+//! the first 256 bytes of each function's configuration space but is always
+//! available on PC-compatible systems.
+
+use core::fmt;
+
+use x86_64::instructions::port::Port;
+
+/// Port to write the configuration address to
+const CONFIG_ADDRESS: u16 = 0xcf8;
+/// Port to read or write the addressed configuration register through
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Configuration space offsets
+const VENDOR_ID: u8 = 0x00;
+const DEVICE_ID: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const HEADER_TYPE: u8 = 0x0e;
+const BAR0: u8 = 0x10;
+const SUBSYSTEM_ID: u8 = 0x2e;
+const INTERRUPT_LINE: u8 = 0x3c;
+
+/// Command register bit enabling I/O space decoding
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Command register bit allowing the device to perform DMA
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Header type bit set on multi-function devices
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+
+/// Vendor ID read from functions that don't exist
+const NO_DEVICE: u16 = 0xffff;
+
+/// Address of a PCI function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A base address register's decoded value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// I/O port space, starting at the given port
+    Io(u16),
+    /// Memory space, starting at the given physical address
+    Memory(u64),
+}
+
+/// A PCI function
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+impl Device {
+    /// The subsystem ID, which some device families (like virtio) use to say
+    /// what kind of device this is
+    pub fn subsystem_id(&self) -> u16 {
+        self.read_u16(SUBSYSTEM_ID)
+    }
+
+    /// Read base address register `index`
+    pub fn bar(&self, index: u8) -> Bar {
+        let offset = BAR0 + index * 4;
+        let low = self.read_u32(offset);
+        if low & 1 != 0 {
+            Bar::Io((low & !0b11) as u16)
+        } else if (low >> 1) & 0b11 == 0b10 {
+            // 64-bit memory BAR, which takes up the next register too
+            let high = self.read_u32(offset + 4);
+            Bar::Memory((u64::from(high) << 32) | u64::from(low & !0b1111))
+        } else {
+            Bar::Memory(u64::from(low & !0b1111))
+        }
+    }
+
+    /// The legacy ISA IRQ that this function's interrupt pin is routed to
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_u8(INTERRUPT_LINE)
+    }
+
+    /// Allow the function to respond to I/O port accesses and perform DMA
+    pub fn enable_io_and_bus_master(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command | COMMAND_IO_SPACE | COMMAND_BUS_MASTER);
+    }
+
+    fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset & !0b11) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset & !0b11) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    fn read_u32(&self, offset: u8) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    fn write_u16(&self, offset: u8, value: u16) {
+        let aligned = offset & !0b11;
+        let shift = (offset & 0b10) * 8;
+        let old = read_config(self.address, aligned);
+        let new = (old & !(0xffff << shift)) | (u32::from(value) << shift);
+        write_config(self.address, aligned, new);
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}]",
+            self.address, self.vendor_id, self.device_id
+        )
+    }
+}
+
+/// Iterate over every function on every bus
+pub fn devices() -> impl Iterator<Item = Device> {
+    (0..=255u8)
+        .flat_map(|bus| (0..32u8).map(move |device| (bus, device)))
+        .flat_map(|(bus, device)| {
+            let first = probe(Address {
+                bus,
+                device,
+                function: 0,
+            });
+            let functions = match first {
+                Some(first) if first.read_u8(HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 => 8,
+                Some(_) => 1,
+                None => 0,
+            };
+            (0..functions).filter_map(move |function| {
+                probe(Address {
+                    bus,
+                    device,
+                    function,
+                })
+            })
+        })
+}
+
+/// Find the first function with the given vendor and device ID
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Device> {
+    devices().find(|d| d.vendor_id == vendor_id && d.device_id == device_id)
+}
+
+fn probe(address: Address) -> Option<Device> {
+    let ids = read_config(address, VENDOR_ID);
+    let vendor_id = ids as u16;
+    (vendor_id != NO_DEVICE).then(|| Device {
+        address,
+        vendor_id,
+        device_id: (ids >> (u32::from(DEVICE_ID) * 8)) as u16,
+    })
+}
+
+fn config_address(address: Address, offset: u8) -> u32 {
+    (1 << 31)
+        | (u32::from(address.bus) << 16)
+        | (u32::from(address.device) << 11)
+        | (u32::from(address.function) << 8)
+        | u32::from(offset & !0b11)
+}
+
+fn read_config(address: Address, offset: u8) -> u32 {
+    // SAFETY: reading configuration space has no side effects. The address and data ports are
+    // only used here, with interrupts disabled so that accesses can't interleave.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(config_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    })
+}
+
+fn write_config(address: Address, offset: u8, value: u32) {
+    // SAFETY: as with `read_config`. Callers are responsible for the effects of the write.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(config_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA).write(value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_config_address() {
+        let address = Address {
+            bus: 1,
+            device: 2,
+            function: 3,
+        };
+        ktassert_eq!(config_address(address, 0x3e), 0x8001_133c);
+    }
+
+    #[ktest::test]
+    fn test_host_bridge_present() {
+        // Every PC has a host bridge at 00:00.0
+        ktassert!(devices().any(|d| d.address.bus == 0 && d.address.device == 0));
+    }
+}
@@ -0,0 +1,562 @@
+//! i8042 PS/2 controller driver.
+//!
+//! [`init`] resets and self-tests the controller, finds out which of its two
+//! ports exist, and identifies the keyboard or mouse attached to each one.
+//! Afterwards, bytes from each device are delivered to the handler registered
+//! with [`set_data_handler`], from interrupt context.
+//!
+//! PS/2 has no real hotplug signalling, but a newly-connected device runs its
+//! self-test and sends [`SELF_TEST_PASSED`]. When that arrives on an empty
+//! port (or one with a keyboard, since the byte is never a set 2 scan code), a
+//! work item identifies the new device and reports [`Event::Connected`].
+//! Mice may send the same byte in a movement packet, so a replaced mouse is
+//! only noticed by [`check_devices`], which pings each device and reports
+//! [`Event::Disconnected`] for ones that stop responding.
+//!
+//! All controller I/O is done by polling with the controller lock held, which
+//! also keeps the IRQ handlers from consuming command responses.
+
+use core::fmt;
+
+use platypos_common::sync::Global;
+use x86_64::instructions::port::Port as IoPort;
+
+use crate::prelude::*;
+use crate::resources;
+use crate::workqueue::{self, WorkItem};
+
+/// Data port, for reading device output and writing device input
+const DATA_PORT: u16 = 0x60;
+/// Status register when read, command register when written
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+/// Status bit set when there's data to read from the data port
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Status bit set when the controller hasn't consumed the last byte written
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+/// Send the next data byte to the second port's device
+const WRITE_SECOND_PORT: u8 = 0xd4;
+
+// Controller configuration byte
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+/// Translate keyboard scan codes to set 1
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// Device commands
+const DEVICE_STATUS_REQUEST: u8 = 0xe9;
+const DEVICE_ECHO: u8 = 0xee;
+const DEVICE_IDENTIFY: u8 = 0xf2;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const DEVICE_RESET: u8 = 0xff;
+
+// Controller and device responses
+const CONTROLLER_SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const SELF_TEST_PASSED: u8 = 0xaa;
+
+/// Number of status register polls before giving up on the controller. Reads
+/// from I/O ports take about a microsecond, so this is on the order of 100ms.
+const POLL_ATTEMPTS: usize = 100_000;
+
+/// Number of times to retry a device command that the device asks to resend
+const MAX_RESENDS: usize = 3;
+
+static CONTROLLER: Global<InterruptSafeMutex<'static, Controller>> = Global::new();
+
+static DATA_HANDLER: Global<fn(Port, u8)> = Global::new();
+
+static EVENT_HANDLER: Global<fn(Event)> = Global::new();
+
+/// One of the controller's device ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    /// The first port, which is usually the keyboard
+    First,
+    /// The second port, which is usually the mouse
+    Second,
+}
+
+impl Port {
+    const ALL: [Port; 2] = [Port::First, Port::Second];
+
+    /// The legacy ISA IRQ for this port
+    fn irq(self) -> u8 {
+        match self {
+            Port::First => 1,
+            Port::Second => 12,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Port::First => f.write_str("PS/2 port 1"),
+            Port::Second => f.write_str("PS/2 port 2"),
+        }
+    }
+}
+
+/// Kinds of PS/2 devices, as reported by the identify command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+    /// Mouse with a scroll wheel
+    ScrollMouse,
+    /// Mouse with a scroll wheel and 5 buttons
+    FiveButtonMouse,
+    /// A device with an unrecognized identity
+    Unknown(u8),
+}
+
+impl DeviceKind {
+    /// Interpret the response to [`DEVICE_IDENTIFY`]
+    fn from_identity(identity: &[u8]) -> Self {
+        match identity {
+            // Ancient AT keyboards don't send an identity
+            [] => DeviceKind::Keyboard,
+            [0x00] => DeviceKind::Mouse,
+            [0x03] => DeviceKind::ScrollMouse,
+            [0x04] => DeviceKind::FiveButtonMouse,
+            [0xab, ..] | [0xac, ..] => DeviceKind::Keyboard,
+            [other, ..] => DeviceKind::Unknown(*other),
+        }
+    }
+
+    fn is_keyboard(self) -> bool {
+        self == DeviceKind::Keyboard
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceKind::Keyboard => f.write_str("keyboard"),
+            DeviceKind::Mouse => f.write_str("mouse"),
+            DeviceKind::ScrollMouse => f.write_str("mouse with scroll wheel"),
+            DeviceKind::FiveButtonMouse => f.write_str("5-button mouse"),
+            DeviceKind::Unknown(id) => write!(f, "unknown device {id:#04x}"),
+        }
+    }
+}
+
+/// A change in which devices are connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Connected(Port, DeviceKind),
+    Disconnected(Port),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Connected(port, kind) => write!(f, "{kind} connected to {port}"),
+            Event::Disconnected(port) => write!(f, "Device disconnected from {port}"),
+        }
+    }
+}
+
+/// Initialize the PS/2 controller and identify attached devices. If there's
+/// no working controller, this logs a warning and leaves PS/2 disabled.
+///
+/// This must be called after the work queue is initialized.
+pub fn init(controller: &'static hal_impl::interrupts::Controller) {
+    let _span = tracing::info_span!("ps2_init").entered();
+
+    let claimed = resources::claim_ports(DATA_PORT..DATA_PORT + 1, "ps2")
+        .and_then(|()| resources::claim_ports(STATUS_COMMAND_PORT..STATUS_COMMAND_PORT + 1, "ps2"));
+    if let Err(err) = claimed {
+        tracing::warn!("PS/2 controller unavailable: {}", err);
+        return;
+    }
+
+    let mut ps2 = Controller::new();
+    if let Err(err) = ps2.init() {
+        tracing::warn!("PS/2 controller unavailable: {}", err);
+        return;
+    }
+
+    let mut events = [None; 2];
+    for port in ps2.ports() {
+        match ps2.reset_and_identify(port) {
+            Ok(kind) => {
+                ps2.devices[port.index()] = Some(kind);
+                events[port.index()] = Some(Event::Connected(port, kind));
+            }
+            Err(err) => tracing::debug!("No device on {}: {}", port, err),
+        }
+    }
+
+    let ports = ps2.ports();
+    if let Err(err) = ps2.enable_interrupts() {
+        tracing::warn!("Could not enable PS/2 interrupts: {}", err);
+        return;
+    }
+    CONTROLLER.init(InterruptSafeMutex::new(controller, ps2));
+
+    for port in ports {
+        hal_impl::interrupts::set_legacy_irq_handler(
+            port.irq(),
+            match port {
+                Port::First => handle_first_port,
+                Port::Second => handle_second_port,
+            },
+        );
+    }
+
+    events.into_iter().flatten().for_each(report);
+}
+
+/// Register the function that receives bytes from PS/2 devices. It's called
+/// from interrupt context.
+///
+/// # Panics
+/// If a handler was already registered
+pub fn set_data_handler(handler: fn(Port, u8)) {
+    DATA_HANDLER.init(handler);
+}
+
+/// Register the function that receives device connection events. If no
+/// handler is registered, events are logged.
+///
+/// # Panics
+/// If a handler was already registered
+pub fn set_event_handler(handler: fn(Event)) {
+    EVENT_HANDLER.init(handler);
+}
+
+/// Ping every connected device, reporting [`Event::Disconnected`] for any that
+/// don't respond. This polls the controller with interrupts disabled, so it
+/// shouldn't be called from interrupt context.
+pub fn check_devices() {
+    let Some(ps2) = CONTROLLER.try_get() else {
+        return;
+    };
+
+    for port in Port::ALL {
+        let mut ps2 = ps2.lock();
+        let Some(kind) = ps2.devices[port.index()] else {
+            continue;
+        };
+        if let Err(err) = ps2.ping(port, kind) {
+            tracing::debug!("{} stopped responding: {}", port, err);
+            ps2.devices[port.index()] = None;
+            drop(ps2);
+            report(Event::Disconnected(port));
+        }
+    }
+}
+
+fn report(event: Event) {
+    match EVENT_HANDLER.try_get() {
+        Some(handler) => handler(event),
+        None => tracing::info!("{}", event),
+    }
+}
+
+fn handle_first_port() {
+    handle_irq(Port::First);
+}
+
+fn handle_second_port() {
+    handle_irq(Port::Second);
+}
+
+fn handle_irq(port: Port) {
+    let mut ps2 = CONTROLLER.get().lock();
+    // The byte may already have been consumed while polling for a command response
+    let Some(byte) = ps2.try_read_data() else {
+        return;
+    };
+
+    let device = ps2.devices[port.index()];
+    drop(ps2);
+
+    if byte == SELF_TEST_PASSED && device.map_or(true, DeviceKind::is_keyboard) {
+        let item = WorkItem::new(identify_new_device, port.index());
+        if let Err(err) = workqueue::queue(item) {
+            tracing::warn!("Could not identify new device on {}: {}", port, err);
+        }
+        return;
+    }
+
+    if let Some(handler) = DATA_HANDLER.try_get() {
+        handler(port, byte);
+    }
+}
+
+/// Work item run after a device on `Port::ALL[index]` passes its self-test
+fn identify_new_device(index: usize) {
+    let port = Port::ALL[index];
+    let mut ps2 = CONTROLLER.get().lock();
+    // The device already reset itself, so it just needs to be identified
+    match ps2.identify(port) {
+        Ok(kind) => {
+            let previous = ps2.devices[port.index()].replace(kind);
+            drop(ps2);
+            if previous.is_some() {
+                report(Event::Disconnected(port));
+            }
+            report(Event::Connected(port, kind));
+        }
+        Err(err) => tracing::warn!("Could not identify new device on {}: {}", port, err),
+    }
+}
+
+/// The i8042 controller's registers and state
+struct Controller {
+    data: IoPort<u8>,
+    status_command: IoPort<u8>,
+    /// Whether the controller has a second port
+    dual_channel: bool,
+    /// Device attached to each port, if any
+    devices: [Option<DeviceKind>; 2],
+}
+
+impl Controller {
+    fn new() -> Self {
+        Self {
+            data: IoPort::new(DATA_PORT),
+            status_command: IoPort::new(STATUS_COMMAND_PORT),
+            dual_channel: false,
+            devices: [None; 2],
+        }
+    }
+
+    /// Ports that exist on this controller
+    fn ports(&self) -> impl Iterator<Item = Port> {
+        Port::ALL
+            .into_iter()
+            .take(if self.dual_channel { 2 } else { 1 })
+    }
+
+    /// Reset and test the controller, leaving both ports enabled with
+    /// interrupts and translation off
+    fn init(&mut self) -> Result<(), Error> {
+        self.command(DISABLE_FIRST_PORT)?;
+        self.command(DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        let mut config = self.command_with_response(READ_CONFIG)?;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        // The self-test may reset the controller, so restore the configuration afterwards
+        let result = self.command_with_response(SELF_TEST)?;
+        if result != CONTROLLER_SELF_TEST_PASSED {
+            let err = Error::new(DriverError::SelfTestFailed);
+            return Err(err.context("PS/2 controller self-test"));
+        }
+        self.write_config(config)?;
+
+        // If the second port's clock is enabled after enabling it, it exists
+        self.command(ENABLE_SECOND_PORT)?;
+        self.dual_channel =
+            self.command_with_response(READ_CONFIG)? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        self.command(DISABLE_SECOND_PORT)?;
+
+        if self.command_with_response(TEST_FIRST_PORT)? != PORT_TEST_PASSED {
+            let err = Error::new(DriverError::SelfTestFailed);
+            return Err(err.context("PS/2 port 1 test"));
+        }
+        if self.dual_channel && self.command_with_response(TEST_SECOND_PORT)? != PORT_TEST_PASSED {
+            tracing::warn!("PS/2 port 2 failed its test, ignoring it");
+            self.dual_channel = false;
+        }
+
+        self.command(ENABLE_FIRST_PORT)?;
+        if self.dual_channel {
+            self.command(ENABLE_SECOND_PORT)?;
+        }
+
+        tracing::debug!(
+            "PS/2 controller initialized with {} port(s)",
+            if self.dual_channel { 2 } else { 1 }
+        );
+        Ok(())
+    }
+
+    fn enable_interrupts(&mut self) -> Result<(), Error> {
+        let mut config = self.command_with_response(READ_CONFIG)?;
+        config |= CONFIG_FIRST_IRQ;
+        if self.dual_channel {
+            config |= CONFIG_SECOND_IRQ;
+        }
+        self.write_config(config)
+    }
+
+    fn reset_and_identify(&mut self, port: Port) -> Result<DeviceKind, Error> {
+        self.device_command(port, DEVICE_RESET)?;
+        let result = self.read_data()?;
+        if result != SELF_TEST_PASSED {
+            let err = Error::new(DriverError::SelfTestFailed);
+            return Err(err.context("PS/2 device reset"));
+        }
+        // Mice follow the self-test result with their ID
+        self.flush();
+
+        self.identify(port)
+    }
+
+    fn identify(&mut self, port: Port) -> Result<DeviceKind, Error> {
+        self.device_command(port, DEVICE_DISABLE_SCANNING)?;
+        self.device_command(port, DEVICE_IDENTIFY)?;
+
+        // The identity is 0-2 bytes, so there's no way to know it's done except by timing out
+        let mut identity = [0; 2];
+        let mut len = 0;
+        while len < identity.len() {
+            match self.read_data() {
+                Ok(byte) => {
+                    identity[len] = byte;
+                    len += 1;
+                }
+                Err(_) => break,
+            }
+        }
+
+        self.device_command(port, DEVICE_ENABLE_SCANNING)?;
+        let kind = DeviceKind::from_identity(&identity[..len]);
+        tracing::debug!("Found {} on {}", kind, port);
+        Ok(kind)
+    }
+
+    /// Check that the device on `port` is still responding
+    fn ping(&mut self, port: Port, kind: DeviceKind) -> Result<(), Error> {
+        if kind.is_keyboard() {
+            // Keyboards respond to echo with echo, rather than ACK
+            self.send(port, DEVICE_ECHO)?;
+            match self.read_data()? {
+                DEVICE_ECHO => Ok(()),
+                other => Err(Error::new(DriverError::UnexpectedResponse(other))),
+            }
+        } else {
+            // Mice don't support echo, but the status request is harmless
+            self.device_command(port, DEVICE_STATUS_REQUEST)?;
+            for _ in 0..3 {
+                self.read_data()?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Send `command` to the device on `port`, and wait for it to be
+    /// acknowledged
+    fn device_command(&mut self, port: Port, command: u8) -> Result<(), Error> {
+        for _ in 0..MAX_RESENDS {
+            self.send(port, command)?;
+            match self.read_data()? {
+                ACK => return Ok(()),
+                RESEND => continue,
+                other => return Err(Error::new(DriverError::UnexpectedResponse(other))),
+            }
+        }
+        Err(Error::new(DriverError::Timeout).context("PS/2 device kept asking to resend"))
+    }
+
+    /// Send a byte to the device on `port`
+    fn send(&mut self, port: Port, byte: u8) -> Result<(), Error> {
+        if port == Port::Second {
+            self.command(WRITE_SECOND_PORT)?;
+        }
+        self.write_data(byte)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Error> {
+        self.wait_for(STATUS_INPUT_FULL, false)?;
+        // SAFETY: writing to the command port only affects the PS/2 controller
+        unsafe { self.status_command.write(command) };
+        Ok(())
+    }
+
+    fn command_with_response(&mut self, command: u8) -> Result<u8, Error> {
+        self.command(command)?;
+        self.read_data()
+    }
+
+    fn write_data(&mut self, byte: u8) -> Result<(), Error> {
+        self.wait_for(STATUS_INPUT_FULL, false)?;
+        // SAFETY: writing to the data port only affects the PS/2 controller and its devices
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    fn read_data(&mut self) -> Result<u8, Error> {
+        self.wait_for(STATUS_OUTPUT_FULL, true)?;
+        // SAFETY: the controller has data ready, and reading it has no other effects
+        Ok(unsafe { self.data.read() })
+    }
+
+    /// Read a byte if one is available, without waiting
+    fn try_read_data(&mut self) -> Option<u8> {
+        // SAFETY: reading the PS/2 status and data registers has no side effects besides
+        // consuming the available byte
+        unsafe { (self.status_command.read() & STATUS_OUTPUT_FULL != 0).then(|| self.data.read()) }
+    }
+
+    /// Discard any pending output
+    fn flush(&mut self) {
+        while self.try_read_data().is_some() {}
+    }
+
+    /// Wait until the status bit `mask` is `set`
+    fn wait_for(&mut self, mask: u8, set: bool) -> Result<(), Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            // SAFETY: reading the status register has no side effects
+            let status = unsafe { self.status_command.read() };
+            if (status & mask != 0) == set {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Error::new(DriverError::Timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_identify_devices() {
+        ktassert_eq!(DeviceKind::from_identity(&[]), DeviceKind::Keyboard);
+        ktassert_eq!(
+            DeviceKind::from_identity(&[0xab, 0x83]),
+            DeviceKind::Keyboard
+        );
+        ktassert_eq!(DeviceKind::from_identity(&[0x00]), DeviceKind::Mouse);
+        ktassert_eq!(DeviceKind::from_identity(&[0x03]), DeviceKind::ScrollMouse);
+        ktassert_eq!(
+            DeviceKind::from_identity(&[0x42]),
+            DeviceKind::Unknown(0x42)
+        );
+    }
+}
@@ -0,0 +1,717 @@
+//! virtio-blk driver, using the legacy virtio PCI interface.
+//!
+//! The device has a single virtqueue of requests. Each request is a chain of
+//! three descriptors: a header saying what to do, the data buffer, and a
+//! status byte that the device writes when it's done. Transfers go through a
+//! bounce buffer allocated from the root allocator, since callers' buffers
+//! aren't necessarily physically contiguous.
+//!
+//! Requests are submitted from async code. When the device finishes one, it
+//! raises an interrupt, and the handler wakes the task waiting on it. Up to
+//! [`MAX_REQUESTS`] requests can be in flight at once; later ones wait for a
+//! free slot. Under [memory pressure](crate::mm::pressure), only one request
+//! is in flight at a time, since each one holds a bounce buffer.
+//!
+//! See the virtio 1.1 specification, sections 2.6, 4.1.4.8, and 5.2.
+
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::mem;
+use core::ptr::{self, addr_of_mut};
+use core::slice;
+use core::sync::atomic::{fence, Ordering};
+use core::task::{Poll, Waker};
+
+use platypos_common::sync::Global;
+use platypos_hal::block::BlockDevice;
+use x86_64::instructions::port::Port;
+
+use crate::arch::mm::MemoryAccess;
+use crate::mm::pressure::{self, Pressure};
+use crate::mm::root_allocator::{Allocator, Owner, Subsystem};
+use crate::prelude::*;
+use crate::sched::wait::WaitQueue;
+
+use super::pci::{self, Bar};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+/// Device ID of transitional (legacy-capable) virtio-blk devices
+const TRANSITIONAL_BLOCK_DEVICE_ID: u16 = 0x1001;
+
+// Legacy interface registers, as offsets into BAR 0
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+/// Start of the block device configuration, which begins with the capacity
+const DEVICE_CONFIG: u16 = 0x14;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+/// Feature bit for read-only devices
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+
+// Request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+
+// Request statuses
+const REQUEST_STATUS_OK: u8 = 0;
+
+// Descriptor flags
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+/// virtio-blk always uses 512-byte sectors, regardless of the device's actual
+/// block size
+const SECTOR_SIZE: usize = 512;
+
+/// Alignment of the used ring in the legacy virtqueue layout
+const QUEUE_ALIGN: usize = 4096;
+
+/// Maximum number of requests in flight
+const MAX_REQUESTS: usize = 16;
+
+/// Descriptors used by each request
+const DESCRIPTORS_PER_REQUEST: usize = 3;
+
+// All slots' headers and statuses share one page
+static_assertions::const_assert!(MAX_REQUESTS * mem::size_of::<SlotMemory>() <= PAGE_SIZE);
+
+static DEVICE: Global<VirtioBlk> = Global::new();
+
+/// A virtio-blk device
+pub struct VirtioBlk {
+    io_base: u16,
+    capacity: u64,
+    read_only: bool,
+    allocator: &'static Allocator<'static>,
+    access: &'static MemoryAccess,
+    inner: InterruptSafeMutex<'static, Inner>,
+    /// Tasks waiting for a free slot
+    slot_freed: WaitQueue,
+}
+
+struct Inner {
+    queue: Virtqueue,
+    slots: Vec<Slot>,
+    /// DMA memory for request headers and status bytes, one [`SlotMemory`] per
+    /// slot
+    slot_memory: *mut SlotMemory,
+    slot_memory_phys: PhysicalAddress,
+}
+
+// SAFETY: the raw pointers refer to DMA memory owned by the driver, which is only accessed with
+// the lock held
+unsafe impl Send for Inner {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    InFlight,
+    Done,
+    /// The request was submitted, but the future waiting on it was dropped.
+    /// The slot is released when the device finishes with it.
+    Abandoned,
+}
+
+struct Slot {
+    state: SlotState,
+    waker: Option<Waker>,
+    bounce: Option<PageFrameRange>,
+}
+
+/// Request header and status byte, as the device reads and writes them
+#[repr(C)]
+struct SlotMemory {
+    header: RequestHeader,
+    status: u8,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Find and initialize the first virtio-blk device, if there is one.
+///
+/// This must be called after the work queue and root allocator are
+/// initialized.
+pub fn init(
+    access: &'static MemoryAccess,
+    allocator: &'static Allocator<'static>,
+    controller: &'static hal_impl::interrupts::Controller,
+) {
+    let Some(pci) = pci::find(VIRTIO_VENDOR_ID, TRANSITIONAL_BLOCK_DEVICE_ID) else {
+        tracing::debug!("No virtio-blk device found");
+        return;
+    };
+    let _span = tracing::info_span!("virtio_blk_init").entered();
+
+    match VirtioBlk::new(&pci, access, allocator, controller) {
+        Ok(device) => {
+            tracing::info!(
+                "virtio-blk device at {}: {} sectors{}",
+                pci.address,
+                device.capacity,
+                if device.read_only { " (read-only)" } else { "" }
+            );
+            DEVICE.init(device);
+            hal_impl::interrupts::set_legacy_irq_handler(pci.interrupt_line(), handle_irq);
+        }
+        Err(err) => tracing::warn!("Could not initialize virtio-blk device {}: {}", pci, err),
+    }
+}
+
+/// The virtio-blk device, if one was found
+pub fn device() -> Option<&'static VirtioBlk> {
+    DEVICE.try_get()
+}
+
+fn handle_irq() {
+    let Some(device) = DEVICE.try_get() else {
+        return;
+    };
+    // SAFETY: reading the ISR status acknowledges the interrupt, which is what we want
+    let isr = unsafe { device.read8(ISR_STATUS) };
+    if isr & 1 != 0 {
+        device.complete_requests();
+    }
+}
+
+impl VirtioBlk {
+    fn new(
+        pci: &pci::Device,
+        access: &'static MemoryAccess,
+        allocator: &'static Allocator<'static>,
+        controller: &'static hal_impl::interrupts::Controller,
+    ) -> Result<Self, Error> {
+        let Bar::Io(io_base) = pci.bar(0) else {
+            return Err(Error::new(DriverError::Unsupported)
+                .context("virtio legacy BAR is not in I/O space"));
+        };
+        pci.enable_io_and_bus_master();
+
+        let mut device = Self {
+            io_base,
+            capacity: 0,
+            read_only: false,
+            allocator,
+            access,
+            // Replaced once the queue is set up
+            inner: InterruptSafeMutex::new(controller, Inner::empty()),
+            slot_freed: WaitQueue::new(controller, "virtio-blk slot"),
+        };
+
+        // SAFETY: this follows the legacy device initialization sequence (virtio 1.1, 3.1.2)
+        unsafe {
+            device.write8(DEVICE_STATUS, 0);
+            device.write8(DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+            device.write8(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+            // No optional features are needed, but note whether the device is read-only
+            device.read_only = device.read32(DEVICE_FEATURES) & FEATURE_READ_ONLY != 0;
+            device.write32(GUEST_FEATURES, 0);
+
+            match device.setup_queue() {
+                Ok(inner) => *device.inner.lock() = inner,
+                Err(err) => {
+                    device.write8(DEVICE_STATUS, STATUS_FAILED);
+                    return Err(err);
+                }
+            }
+
+            let low = device.read32(DEVICE_CONFIG);
+            let high = device.read32(DEVICE_CONFIG + 4);
+            device.capacity = (u64::from(high) << 32) | u64::from(low);
+
+            device.write8(
+                DEVICE_STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+            );
+        }
+
+        Ok(device)
+    }
+
+    /// Allocate and register the request queue
+    unsafe fn setup_queue(&self) -> Result<Inner, Error> {
+        self.write16(QUEUE_SELECT, 0);
+        let size = usize::from(self.read16(QUEUE_SIZE));
+        if size < DESCRIPTORS_PER_REQUEST {
+            return Err(Error::new(DriverError::Unsupported)
+                .context("virtio-blk request queue is too small"));
+        }
+
+        let queue = Virtqueue::new(size, self.access, self.allocator)
+            .context("allocating virtio-blk queue")?;
+        let pfn = queue.phys.as_usize() / PAGE_SIZE;
+        self.write32(
+            QUEUE_ADDRESS,
+            u32::try_from(pfn).map_err(|_| Error::new(MmError::AddressOutOfBounds))?,
+        );
+
+        let slot_count = (size / DESCRIPTORS_PER_REQUEST).min(MAX_REQUESTS);
+        let memory = self
+            .allocator
+            .allocate_for(1, Owner::new(Subsystem::Driver))
+            .context("allocating virtio-blk request headers")?;
+        let slot_memory = self.access.map_permanent(memory)?.cast::<SlotMemory>();
+
+        Ok(Inner {
+            queue,
+            slots: (0..slot_count)
+                .map(|_| Slot {
+                    state: SlotState::Free,
+                    waker: None,
+                    bounce: None,
+                })
+                .collect(),
+            slot_memory,
+            slot_memory_phys: memory.start_address(),
+        })
+    }
+
+    /// Transfer `len` bytes starting at `sector`. For writes, `fill` copies the
+    /// data into the bounce buffer, and for reads, `drain` copies it out.
+    async fn transfer(
+        &self,
+        kind: u32,
+        sector: u64,
+        len: usize,
+        fill: impl FnOnce(&mut [u8]),
+        drain: impl FnOnce(&[u8]),
+    ) -> Result<(), Error> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(Error::new(IoError::PartialBlock));
+        }
+        let sectors = (len / SECTOR_SIZE) as u64;
+        if sector
+            .checked_add(sectors)
+            .map_or(true, |end| end > self.capacity)
+        {
+            return Err(Error::new(IoError::OutOfRange));
+        }
+        if kind == REQUEST_OUT && self.read_only {
+            return Err(Error::new(IoError::ReadOnly));
+        }
+
+        // Claim a slot before allocating the bounce buffer, so waiting requests don't hold memory
+        let mut slot = None;
+        self.slot_freed
+            .wait_until(|| {
+                slot = self.claim_slot();
+                slot.is_some()
+            })
+            .await;
+        let slot = slot.expect("slot wait finished without claiming a slot");
+
+        let bounce = match self
+            .allocator
+            .allocate_for(
+                len.div_ceil(PAGE_SIZE).max(1),
+                Owner::new(Subsystem::Driver),
+            )
+            .context("allocating virtio-blk bounce buffer")
+        {
+            Ok(bounce) => bounce,
+            Err(err) => {
+                self.release(slot);
+                return Err(err);
+            }
+        };
+        // Record the buffer in the slot so releasing the slot frees it
+        self.inner.lock().slots[slot].bounce = Some(bounce);
+        // SAFETY: the bounce buffer was just allocated, so nothing else is using it
+        let ptr = match unsafe { self.access.map_permanent(bounce) } {
+            Ok(ptr) => ptr.cast::<u8>(),
+            Err(err) => {
+                self.release(slot);
+                return Err(err);
+            }
+        };
+        // SAFETY: the mapping covers at least `len` bytes of the bounce buffer
+        let buffer = unsafe { slice::from_raw_parts_mut(ptr, len) };
+        fill(buffer);
+
+        // If this future is dropped from here on, the slot is released when the device finishes
+        let mut guard = SlotGuard {
+            device: self,
+            slot,
+            armed: true,
+        };
+
+        self.inner.lock().submit(slot, kind, sector, bounce, len);
+        // SAFETY: notifying queue 0 just tells the device to check the available ring
+        unsafe { self.write16(QUEUE_NOTIFY, 0) };
+
+        let status = poll_fn(|cx| {
+            let mut inner = self.inner.lock();
+            if inner.slots[slot].state == SlotState::Done {
+                // Read the status only after seeing that the device finished
+                fence(Ordering::SeqCst);
+                Poll::Ready(inner.status(slot))
+            } else {
+                inner.slots[slot].waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+        guard.armed = false;
+
+        if status == REQUEST_STATUS_OK {
+            drain(buffer);
+        }
+        self.release(slot);
+
+        if status == REQUEST_STATUS_OK {
+            Ok(())
+        } else {
+            Err(Error::new(IoError::DeviceFailed))
+        }
+    }
+
+    /// Mark requests the device has finished as done, waking their tasks
+    fn complete_requests(&self) {
+        let mut inner = self.inner.lock();
+        while let Some(head) = inner.queue.pop_used() {
+            let slot = head / DESCRIPTORS_PER_REQUEST;
+            match inner.slots[slot].state {
+                SlotState::InFlight => {
+                    inner.slots[slot].state = SlotState::Done;
+                    if let Some(waker) = inner.slots[slot].waker.take() {
+                        waker.wake();
+                    }
+                }
+                SlotState::Abandoned => {
+                    drop(inner);
+                    self.release(slot);
+                    inner = self.inner.lock();
+                }
+                state => tracing::warn!("virtio-blk completed request in {:?} slot", state),
+            }
+        }
+    }
+
+    /// Free `slot` and its bounce buffer, and let another task use it
+    fn release(&self, slot: usize) {
+        let mut inner = self.inner.lock();
+        let bounce = inner.slots[slot].bounce.take();
+        inner.slots[slot].state = SlotState::Free;
+        drop(inner);
+
+        if let Some(bounce) = bounce {
+            if let Err(err) = self.allocator.deallocate(bounce) {
+                tracing::warn!("Could not free virtio-blk bounce buffer: {}", err);
+            }
+        }
+        self.slot_freed.wake_one();
+    }
+
+    /// Claim a free slot for a new request, if there is one. Under memory
+    /// pressure, that's only if no other request is in flight.
+    fn claim_slot(&self) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        if pressure::current() != Pressure::None
+            && inner.slots.iter().any(|s| s.state != SlotState::Free)
+        {
+            return None;
+        }
+        let slot = inner
+            .slots
+            .iter()
+            .position(|s| s.state == SlotState::Free)?;
+        inner.slots[slot].state = SlotState::InFlight;
+        Some(slot)
+    }
+
+    unsafe fn read8(&self, offset: u16) -> u8 {
+        Port::<u8>::new(self.io_base + offset).read()
+    }
+
+    unsafe fn read16(&self, offset: u16) -> u16 {
+        Port::<u16>::new(self.io_base + offset).read()
+    }
+
+    unsafe fn read32(&self, offset: u16) -> u32 {
+        Port::<u32>::new(self.io_base + offset).read()
+    }
+
+    unsafe fn write8(&self, offset: u16, value: u8) {
+        Port::<u8>::new(self.io_base + offset).write(value)
+    }
+
+    unsafe fn write16(&self, offset: u16, value: u16) {
+        Port::<u16>::new(self.io_base + offset).write(value)
+    }
+
+    unsafe fn write32(&self, offset: u16, value: u32) {
+        Port::<u32>::new(self.io_base + offset).write(value)
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    type Error = Error;
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks<'a>(
+        &'a self,
+        start: u64,
+        buf: &'a mut [u8],
+    ) -> impl Future<Output = Result<(), Error>> + 'a {
+        let len = buf.len();
+        self.transfer(
+            REQUEST_IN,
+            start,
+            len,
+            |_| {},
+            |data| buf.copy_from_slice(data),
+        )
+    }
+
+    fn write_blocks<'a>(
+        &'a self,
+        start: u64,
+        buf: &'a [u8],
+    ) -> impl Future<Output = Result<(), Error>> + 'a {
+        self.transfer(
+            REQUEST_OUT,
+            start,
+            buf.len(),
+            |data| data.copy_from_slice(buf),
+            |_| {},
+        )
+    }
+}
+
+/// Releases an in-flight slot if the future waiting on it is dropped
+struct SlotGuard<'a> {
+    device: &'a VirtioBlk,
+    slot: usize,
+    armed: bool,
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            let mut inner = self.device.inner.lock();
+            inner.slots[self.slot].state = SlotState::Abandoned;
+            inner.slots[self.slot].waker = None;
+        }
+    }
+}
+
+impl Inner {
+    fn empty() -> Self {
+        Self {
+            queue: Virtqueue::empty(),
+            slots: Vec::new(),
+            slot_memory: ptr::null_mut(),
+            slot_memory_phys: PhysicalAddress::new(0),
+        }
+    }
+
+    /// Fill in `slot`'s header and descriptors and make the request available
+    /// to the device
+    fn submit(&mut self, slot: usize, kind: u32, sector: u64, bounce: PageFrameRange, len: usize) {
+        self.slots[slot].bounce = Some(bounce);
+
+        let memory_offset = slot * mem::size_of::<SlotMemory>();
+        let header_phys = self.slot_memory_phys + memory_offset;
+        let status_phys = header_phys + mem::offset_of!(SlotMemory, status);
+        // SAFETY: the slot's memory is only used by this request, which the device isn't
+        // processing yet
+        unsafe {
+            let memory = self.slot_memory.add(slot);
+            addr_of_mut!((*memory).header).write_volatile(RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            });
+            addr_of_mut!((*memory).status).write_volatile(0xff);
+        }
+
+        let head = slot * DESCRIPTORS_PER_REQUEST;
+        let data_flags = if kind == REQUEST_IN {
+            DESCRIPTOR_NEXT | DESCRIPTOR_WRITE
+        } else {
+            DESCRIPTOR_NEXT
+        };
+        self.queue.set_descriptor(
+            head,
+            header_phys,
+            mem::size_of::<RequestHeader>(),
+            DESCRIPTOR_NEXT,
+            head + 1,
+        );
+        self.queue
+            .set_descriptor(head + 1, bounce.start_address(), len, data_flags, head + 2);
+        self.queue
+            .set_descriptor(head + 2, status_phys, 1, DESCRIPTOR_WRITE, 0);
+        self.queue.push_available(head);
+    }
+
+    /// The status byte the device wrote for `slot`
+    fn status(&self, slot: usize) -> u8 {
+        // SAFETY: the device is done with the request, so it won't write the status again
+        unsafe { addr_of_mut!((*self.slot_memory.add(slot)).status).read_volatile() }
+    }
+}
+
+/// A virtqueue in the legacy layout: the descriptor table, then the available
+/// ring, then the used ring on the next [`QUEUE_ALIGN`] boundary
+struct Virtqueue {
+    size: usize,
+    phys: PhysicalAddress,
+    descriptors: *mut Descriptor,
+    available: *mut u16,
+    used: *mut u8,
+    /// Index of the next entry in the used ring to process
+    last_used: u16,
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl Virtqueue {
+    fn empty() -> Self {
+        Self {
+            size: 0,
+            phys: PhysicalAddress::new(0),
+            descriptors: ptr::null_mut(),
+            available: ptr::null_mut(),
+            used: ptr::null_mut(),
+            last_used: 0,
+        }
+    }
+
+    fn new(
+        size: usize,
+        access: &MemoryAccess,
+        allocator: &Allocator<'static>,
+    ) -> Result<Self, Error> {
+        let (used_offset, total) = Self::layout(size);
+        let range = allocator.allocate_for(total / PAGE_SIZE, Owner::new(Subsystem::Driver))?;
+        // SAFETY: the memory was just allocated, so nothing else is using it
+        let base = unsafe {
+            let base = access.map_permanent(range)?;
+            ptr::write_bytes(base, 0, total);
+            base.cast::<u8>()
+        };
+
+        Ok(Self {
+            size,
+            phys: range.start_address(),
+            descriptors: base.cast(),
+            // SAFETY: both offsets are inside the allocation
+            available: unsafe { base.add(size * mem::size_of::<Descriptor>()).cast() },
+            used: unsafe { base.add(used_offset) },
+            last_used: 0,
+        })
+    }
+
+    /// Offset of the used ring, and total size, of a queue with `size` entries
+    fn layout(size: usize) -> (usize, usize) {
+        let descriptors = size * mem::size_of::<Descriptor>();
+        // flags, index, ring, and used_event
+        let available = 2 * (3 + size);
+        let used = 2 * 3 + mem::size_of::<u64>() * size;
+        let used_offset = (descriptors + available).next_multiple_of(QUEUE_ALIGN);
+        (
+            used_offset,
+            used_offset + used.next_multiple_of(QUEUE_ALIGN),
+        )
+    }
+
+    fn set_descriptor(
+        &mut self,
+        index: usize,
+        address: PhysicalAddress,
+        len: usize,
+        flags: u16,
+        next: usize,
+    ) {
+        assert!(index < self.size);
+        // SAFETY: the index is in bounds, and the driver owns descriptors that aren't available
+        unsafe {
+            self.descriptors.add(index).write_volatile(Descriptor {
+                address: address.as_usize() as u64,
+                len: len as u32,
+                flags,
+                next: next as u16,
+            })
+        }
+    }
+
+    /// Make the descriptor chain starting at `head` available to the device
+    fn push_available(&mut self, head: usize) {
+        // SAFETY: the ring index is in bounds, and only the driver writes the available ring
+        unsafe {
+            let index = self.available.add(1).read_volatile();
+            self.available
+                .add(2 + usize::from(index) % self.size)
+                .write_volatile(head as u16);
+            // The device must see the ring entry before the new index
+            fence(Ordering::SeqCst);
+            self.available.add(1).write_volatile(index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Take the head of the next descriptor chain the device has finished with
+    fn pop_used(&mut self) -> Option<usize> {
+        // SAFETY: the used ring's index and entries are in bounds
+        unsafe {
+            let index = self.used.add(2).cast::<u16>().read_volatile();
+            if index == self.last_used {
+                return None;
+            }
+            // Read the entry only after seeing the new index
+            fence(Ordering::SeqCst);
+            let entry = self
+                .used
+                .add(4 + usize::from(self.last_used) % self.size * mem::size_of::<u64>())
+                .cast::<u32>()
+                .read_volatile();
+            self.last_used = self.last_used.wrapping_add(1);
+            Some(entry as usize)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_queue_layout() {
+        // QEMU's default queue size: 4 KiB of descriptors plus the available ring, then one page
+        // for the used ring
+        ktassert_eq!(Virtqueue::layout(256), (8192, 12288));
+        ktassert_eq!(Virtqueue::layout(16), (4096, 8192));
+    }
+}
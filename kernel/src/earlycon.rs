@@ -0,0 +1,100 @@
+//! Early console for output before tracing is initialized.
+//!
+//! If the kernel fails before [`crate::trace`] is set up, there's otherwise no
+//! way to report what happened. The early console writes plain text directly
+//! to a serial port, without needing memory allocation, interrupt handling,
+//! or any other initialization. This makes it usable from the very start of
+//! boot, including from exception handlers.
+//!
+//! Output is best-effort: if the console is already in use (for example, when
+//! an exception interrupts another write), the new output is dropped rather
+//! than blocking. That also makes it NMI-aware: an NMI handler can use it
+//! even if the code it interrupted was halfway through a write. Likewise, if
+//! the serial port stops draining, the rest of a write is dropped after
+//! [`WRITE_TIMEOUT`], and counted in [`timeouts`].
+//!
+//! Once tracing is initialized, it takes over the serial port and the early
+//! console is disabled.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use platypos_hal::time::Deadline;
+
+use crate::arch::{hal_impl, EARLY_SERIAL};
+
+/// How long one write can wait for the serial port. This is usually before
+/// the clock is calibrated, so it's approximate.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Whether or not the early console is still active
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Set while a write is in progress, so concurrent or reentrant writes don't
+/// interleave
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Number of writes that were cut short by [`WRITE_TIMEOUT`]
+static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// [`fmt::Write`] adapter for the early console
+pub struct EarlyConsole;
+
+/// Print to the early console, if it's still active
+macro_rules! early_println {
+    ($($arg:tt)*) => {{
+        use ::core::fmt::Write;
+        let _ = writeln!($crate::earlycon::EarlyConsole, $($arg)*);
+    }};
+}
+
+pub(crate) use early_println;
+
+/// Write `s` to the early console, if it's still active.
+pub fn write_str(s: &str) {
+    if !is_enabled() {
+        return;
+    }
+
+    if BUSY
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    let deadline = Deadline::after(&hal_impl::time::INSTANCE, WRITE_TIMEOUT);
+    let write = |byte| EARLY_SERIAL.write_byte_timeout(byte, &deadline);
+    let written = s
+        .bytes()
+        .all(|byte| (byte != b'\n' || write(b'\r')) && write(byte));
+    if !written {
+        TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    BUSY.store(false, Ordering::Release);
+}
+
+/// Number of writes that timed out before the serial port took all of them
+pub fn timeouts() -> u64 {
+    TIMEOUTS.load(Ordering::Relaxed)
+}
+
+/// Check if the early console is still active
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Permanently disable the early console. This is called when the real
+/// tracing infrastructure takes over the serial port.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+impl fmt::Write for EarlyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
@@ -1,13 +1,53 @@
-//! Standard kernel error type. Modules may use more specific errors if
-//! appropriate.
+//! Standard kernel error type.
+//!
+//! An [`Error`] is a [`KError`], which says what went wrong and in which
+//! subsystem, plus a chain of context strings describing what the kernel was
+//! doing at the time. Context is added with [`ResultExt::context`] as an
+//! error propagates, so the final message reads like
+//! `initializing root allocator: mapping tracking space: address out of bounds`.
+//!
+//! Errors can't allocate, since they're used to report allocation failures, so
+//! context is limited to [`MAX_CONTEXT`] static strings. Beyond that, the
+//! outermost context is dropped and the error notes that it was truncated.
+//!
+//! Checks that can fail at runtime use [`kensure!`], which returns an error
+//! tagged with its source location, rather than panicking. Invariants whose
+//! violation means a kernel bug use [`kassert!`], which records a trace event
+//! with the failed condition before panicking.
+
+use core::convert::Infallible;
+use core::fmt;
+use core::panic::Location;
+
+use crate::arch::hal_impl::interrupts::TimerError;
+
+/// Maximum number of context strings an [`Error`] keeps
+pub const MAX_CONTEXT: usize = 4;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Error {
-    kind: ErrorKind,
+    kind: KError,
+    /// Context strings, innermost first
+    context: [&'static str; MAX_CONTEXT],
+    context_len: usize,
+    /// Whether context was dropped because there was no room for it
+    truncated: bool,
+    /// Where the error was raised, if it was raised by [`kensure!`]
+    location: Option<&'static Location<'static>>,
+}
+
+/// What went wrong, by subsystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KError {
+    Mm(MmError),
+    Sched(SchedError),
+    Driver(DriverError),
+    Io(IoError),
 }
 
+/// Memory management errors
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ErrorKind {
+pub enum MmError {
     /// There was not enough memory to complete the operation.
     InsufficientMemory,
     /// The address was out of bounds (for example, it's outside of the current
@@ -16,14 +56,357 @@ pub enum ErrorKind {
     /// The caller provided an invalid address (for example, they tried to free
     /// an address that had not been allocated).
     InvalidAddress,
+    /// A fixed-capacity memory map didn't have room for another region.
+    TooManyRegions,
+}
+
+/// Scheduling and deferred work errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedError {
+    /// The maximum number of threads are already running.
+    TooManyThreads,
+    /// The current processor's work queue is full.
+    WorkQueueFull,
+    /// A wait timed out before its condition held.
+    TimedOut,
+}
+
+/// Device driver errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverError {
+    /// The timer couldn't be programmed
+    Timer(TimerError),
+    /// A device didn't respond in time
+    Timeout,
+    /// A device or controller failed its self-test
+    SelfTestFailed,
+    /// A device sent a response the driver didn't expect
+    UnexpectedResponse(u8),
+    /// The device is configured in a way the driver doesn't support
+    Unsupported,
+    /// Another driver already claimed the device's I/O ports or MMIO range
+    ResourceConflict,
+    /// There's no room to record another resource claim
+    TooManyResources,
+}
+
+/// Input and output errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    /// Formatting output failed
+    Format,
+    /// The device reported that a transfer failed
+    DeviceFailed,
+    /// The device can't be written to
+    ReadOnly,
+    /// The transfer extends past the end of the device
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks
+    PartialBlock,
+    /// No file exists at the given path
+    NotFound,
+    /// The path names a file where a directory was expected
+    NotADirectory,
+    /// The path names a directory where a file was expected
+    IsADirectory,
+    /// Stored data is malformed
+    Corrupt,
 }
 
 impl Error {
-    pub fn new(kind: ErrorKind) -> Self {
-        Self { kind }
+    pub fn new(kind: impl Into<KError>) -> Self {
+        Self {
+            kind: kind.into(),
+            context: [""; MAX_CONTEXT],
+            context_len: 0,
+            truncated: false,
+            location: None,
+        }
+    }
+
+    /// Record that this error was raised at `location`
+    pub fn at(mut self, location: &'static Location<'static>) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    /// Where this error was raised, if known
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.location
     }
 
-    pub fn kind(&self) -> ErrorKind {
+    pub fn kind(&self) -> KError {
         self.kind
     }
+
+    /// Add a description of what was happening when this error occurred
+    pub fn context(mut self, context: &'static str) -> Self {
+        if self.context_len < MAX_CONTEXT {
+            self.context[self.context_len] = context;
+            self.context_len += 1;
+        } else {
+            self.truncated = true;
+        }
+        self
+    }
+
+    /// Context strings, outermost first
+    pub fn contexts(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.context[..self.context_len].iter().rev().copied()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.truncated {
+            f.write_str("...: ")?;
+        }
+        for context in self.contexts() {
+            write!(f, "{context}: ")?;
+        }
+        write!(f, "{}", self.kind)?;
+        if let Some(location) = self.location {
+            write!(f, " (at {location})")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KError::Mm(err) => fmt::Display::fmt(err, f),
+            KError::Sched(err) => fmt::Display::fmt(err, f),
+            KError::Driver(err) => fmt::Display::fmt(err, f),
+            KError::Io(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl fmt::Display for MmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MmError::InsufficientMemory => "insufficient memory",
+            MmError::AddressOutOfBounds => "address out of bounds",
+            MmError::InvalidAddress => "invalid address",
+            MmError::TooManyRegions => "too many memory regions",
+        })
+    }
+}
+
+impl fmt::Display for SchedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SchedError::TooManyThreads => "too many threads",
+            SchedError::WorkQueueFull => "work queue full",
+            SchedError::TimedOut => "timed out",
+        })
+    }
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriverError::Timer(err) => write!(f, "timer: {err}"),
+            DriverError::Timeout => f.write_str("device timed out"),
+            DriverError::SelfTestFailed => f.write_str("device self-test failed"),
+            DriverError::UnexpectedResponse(byte) => {
+                write!(f, "unexpected device response {byte:#04x}")
+            }
+            DriverError::Unsupported => f.write_str("unsupported device configuration"),
+            DriverError::ResourceConflict => f.write_str("resource claimed by another driver"),
+            DriverError::TooManyResources => f.write_str("too many resource claims"),
+        }
+    }
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoError::Format => f.write_str("formatting failed"),
+            IoError::DeviceFailed => f.write_str("device reported an I/O error"),
+            IoError::ReadOnly => f.write_str("device is read-only"),
+            IoError::OutOfRange => f.write_str("transfer past the end of the device"),
+            IoError::PartialBlock => f.write_str("buffer is not a whole number of blocks"),
+            IoError::NotFound => f.write_str("no such file or directory"),
+            IoError::NotADirectory => f.write_str("not a directory"),
+            IoError::IsADirectory => f.write_str("is a directory"),
+            IoError::Corrupt => f.write_str("data is corrupt"),
+        }
+    }
+}
+
+/// Generates `From` conversions into both [`KError`] and [`Error`]
+macro_rules! from_impls {
+    ($($source:ty => |$e:ident| $kind:expr;)*) => {
+        $(
+            impl From<$source> for KError {
+                fn from($e: $source) -> Self {
+                    $kind
+                }
+            }
+
+            impl From<$source> for Error {
+                fn from(err: $source) -> Self {
+                    Error::new(err)
+                }
+            }
+        )*
+    };
+}
+
+from_impls! {
+    MmError => |e| KError::Mm(e);
+    SchedError => |e| KError::Sched(e);
+    DriverError => |e| KError::Driver(e);
+    IoError => |e| KError::Io(e);
+    TimerError => |e| KError::Driver(DriverError::Timer(e));
+    fmt::Error => |_e| KError::Io(IoError::Format);
+}
+
+impl From<KError> for Error {
+    fn from(kind: KError) -> Self {
+        Error::new(kind)
+    }
+}
+
+impl From<Infallible> for Error {
+    fn from(err: Infallible) -> Self {
+        match err {}
+    }
+}
+
+/// Extension methods for adding context to errors in a `Result`
+pub trait ResultExt<T> {
+    /// Convert the error into an [`Error`] and add `context` to it
+    fn context(self, context: &'static str) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for Result<T, E> {
+    fn context(self, context: &'static str) -> Result<T, Error> {
+        self.map_err(|err| err.into().context(context))
+    }
+}
+
+/// Return an [`Error`] of kind `$kind` from the enclosing function unless
+/// `$cond` holds, optionally with `$context`. The error records where the
+/// check failed.
+///
+/// ```ignore
+/// kensure!(range.size() > 0, MmError::InvalidAddress, "adding memory");
+/// ```
+macro_rules! kensure {
+    ($cond:expr, $kind:expr $(,)?) => {
+        if !$cond {
+            return Err($crate::error::Error::new($kind).at(::core::panic::Location::caller()));
+        }
+    };
+    ($cond:expr, $kind:expr, $context:expr $(,)?) => {
+        if !$cond {
+            return Err($crate::error::Error::new($kind)
+                .at(::core::panic::Location::caller())
+                .context($context));
+        }
+    };
+}
+
+/// Panic unless `$cond` holds, like [`assert!`], but record a trace event
+/// with the failed condition first. Extra arguments format a message, as with
+/// `assert!`.
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::error::assertion_failed(::core::stringify!($cond), ::core::format_args!(""));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::error::assertion_failed(
+                ::core::stringify!($cond),
+                ::core::format_args!($($arg)+),
+            );
+        }
+    };
+}
+
+pub(crate) use {kassert, kensure};
+
+/// Report a failed [`kassert!`] and panic. This is out of line to keep the
+/// check itself small.
+#[cold]
+#[track_caller]
+#[doc(hidden)]
+pub fn assertion_failed(condition: &'static str, message: fmt::Arguments) -> ! {
+    let location = Location::caller();
+    // Only declared fields can be recorded, so the details go in the message
+    tracing::error!("Assertion failed at {location}: {condition}: {message}");
+    if message.as_str() == Some("") {
+        panic!("assertion failed: {condition}");
+    } else {
+        panic!("assertion failed: {condition}: {message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_context_chain() {
+        let result: Result<(), _> = Err(MmError::InsufficientMemory);
+        let err = result
+            .context("allocating tracking space")
+            .context("adding memory")
+            .unwrap_err();
+        ktassert_eq!(err.kind(), KError::Mm(MmError::InsufficientMemory));
+        ktassert_eq!(
+            format!("{err}"),
+            "adding memory: allocating tracking space: insufficient memory"
+        );
+    }
+
+    #[ktest::test]
+    fn test_context_truncated() {
+        let mut err = Error::new(IoError::Format);
+        for context in ["a", "b", "c", "d", "e"] {
+            err = err.context(context);
+        }
+        ktassert_eq!(format!("{err}"), "...: d: c: b: a: formatting failed");
+    }
+
+    fn check_even(value: usize) -> Result<usize, Error> {
+        kensure!(value % 2 == 0, MmError::InvalidAddress, "checking value");
+        Ok(value / 2)
+    }
+
+    #[ktest::test]
+    fn test_kensure() {
+        ktassert_eq!(check_even(4), Ok(2));
+
+        let err = check_even(3).unwrap_err();
+        ktassert_eq!(err.kind(), KError::Mm(MmError::InvalidAddress));
+        let location = err.location().unwrap();
+        ktassert_eq!(location.file(), file!());
+        ktassert!(format!("{err}").starts_with("checking value: invalid address (at "));
+    }
+
+    #[ktest::test]
+    fn test_driver_conversion() {
+        let err = Error::from(TimerError::TooShort);
+        ktassert_eq!(
+            err.kind(),
+            KError::Driver(DriverError::Timer(TimerError::TooShort))
+        );
+    }
+
+    #[ktest::test]
+    #[should_panic(expected = "assertion failed: value % 2 == 0: 3 is odd")]
+    fn test_kassert_failure() {
+        let value = core::hint::black_box(3);
+        kassert!(value % 2 == 0, "{value} is odd");
+    }
 }
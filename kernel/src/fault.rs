@@ -0,0 +1,327 @@
+//! Deterministic fault injection.
+//!
+//! Error paths are hard to test, since the failures that trigger them are
+//! rare. Fallible operations can be annotated with [`fault_point!`], which
+//! returns `true` when the operation should fail on purpose. Whether a given
+//! call fails is decided by a pseudorandom function of the injection seed, the
+//! site's name, and how many times the site was hit, so a run with the same
+//! seed fails at the same points every time (as long as each site is reached
+//! in the same order).
+//!
+//! Injection is off until a [`Policy`] is set, either by [`init`] from the
+//! `opt/platypos/fault-injection` fw_cfg blob (`cargo xtask run --fault-seed`)
+//! or by [`sweep`] in tests. Without the `fault-injection` feature,
+//! [`fault_point!`] is always `false` and no sites are registered.
+#![cfg_attr(not(feature = "fault-injection"), allow(dead_code))]
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use platypos_hal::linkme::distributed_slice;
+
+use crate::drivers::fw_cfg;
+
+/// fw_cfg blob with the boot-time injection policy, as `seed=N,rate=M`
+const POLICY_BLOB: &str = "opt/platypos/fault-injection";
+
+/// Rate used if the policy blob doesn't set one
+const DEFAULT_RATE: u32 = 100;
+
+/// Every fault injection site in the kernel
+#[distributed_slice]
+#[linkme(crate = platypos_hal::linkme)]
+pub static SITES: [Site] = [..];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SEED: AtomicU64 = AtomicU64::new(0);
+static RATE: AtomicU32 = AtomicU32::new(0);
+
+/// Check whether the fallible operation at this point should fail. `$name`
+/// identifies the site in statistics, and should be unique, like
+/// `"mm.root_allocator.allocate"`.
+#[cfg(feature = "fault-injection")]
+macro_rules! fault_point {
+    ($name:literal) => {{
+        #[::platypos_hal::linkme::distributed_slice($crate::fault::SITES)]
+        #[linkme(crate = ::platypos_hal::linkme)]
+        static SITE: $crate::fault::Site = $crate::fault::Site::new($name);
+        $crate::fault::should_fail(&SITE)
+    }};
+}
+
+#[cfg(not(feature = "fault-injection"))]
+macro_rules! fault_point {
+    ($name:literal) => {
+        false
+    };
+}
+
+pub(crate) use fault_point;
+
+/// When to inject faults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub seed: u64,
+    /// Each call to a fault point fails with probability `1 / rate`. A rate
+    /// of 0 never fails.
+    pub rate: u32,
+}
+
+impl Policy {
+    /// Parse a policy like `seed=42,rate=10`. The rate is optional.
+    fn parse(s: &str) -> Option<Policy> {
+        let mut seed = None;
+        let mut rate = DEFAULT_RATE;
+        for field in s.trim().split(',') {
+            match field.split_once('=')? {
+                ("seed", value) => seed = Some(value.trim().parse().ok()?),
+                ("rate", value) => rate = value.trim().parse().ok()?,
+                _ => return None,
+            }
+        }
+        Some(Policy { seed: seed?, rate })
+    }
+}
+
+/// A fault point, with statistics on how often it was reached and failed
+pub struct Site {
+    name: &'static str,
+    /// Hash of `name`, so that sites hit the same number of times don't fail
+    /// together
+    hash: u64,
+    hits: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl Site {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            hash: fnv1a(name.as_bytes()),
+            hits: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Number of times this site was reached while injection was enabled
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of faults injected at this site
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+}
+
+/// Set up fault injection from the boot-time policy, if there is one. This
+/// must be called after [`fw_cfg::init`].
+pub fn init() {
+    let Ok(blob) = fw_cfg::read(POLICY_BLOB) else {
+        return;
+    };
+
+    if !cfg!(feature = "fault-injection") {
+        tracing::warn!("Ignoring fault injection policy, since the kernel was built without it");
+        return;
+    }
+
+    match core::str::from_utf8(&blob).ok().and_then(Policy::parse) {
+        Some(policy) => set_policy(Some(policy)),
+        None => tracing::warn!("Ignoring malformed fault injection policy"),
+    }
+}
+
+/// Start injecting faults according to `policy`, or stop if it's `None`.
+/// Enabling a policy resets site statistics, so that the same seed injects the
+/// same faults no matter what ran before.
+pub fn set_policy(policy: Option<Policy>) {
+    ENABLED.store(false, Ordering::SeqCst);
+    let Some(policy) = policy else {
+        return;
+    };
+
+    for site in SITES {
+        site.hits.store(0, Ordering::Relaxed);
+        site.injected.store(0, Ordering::Relaxed);
+    }
+    SEED.store(policy.seed, Ordering::Relaxed);
+    RATE.store(policy.rate, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::SeqCst);
+    tracing::info!(
+        seed = policy.seed,
+        rate = policy.rate as u64,
+        "Fault injection enabled"
+    );
+}
+
+/// The current injection policy, if injection is enabled
+pub fn policy() -> Option<Policy> {
+    ENABLED.load(Ordering::SeqCst).then(|| Policy {
+        seed: SEED.load(Ordering::Relaxed),
+        rate: RATE.load(Ordering::Relaxed),
+    })
+}
+
+/// Decide whether the call at `site` should fail. Use [`fault_point!`]
+/// instead of calling this directly.
+#[inline]
+pub fn should_fail(site: &Site) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let rate = RATE.load(Ordering::Relaxed);
+    if rate == 0 {
+        return false;
+    }
+
+    let hit = site.hits.fetch_add(1, Ordering::Relaxed);
+    let roll = splitmix64(SEED.load(Ordering::Relaxed) ^ site.hash ^ hit.rotate_left(32));
+    let fail = roll % u64::from(rate) == 0;
+    if fail {
+        site.injected.fetch_add(1, Ordering::Relaxed);
+    }
+    fail
+}
+
+/// Log statistics for every site that was reached
+pub fn dump_stats() {
+    tracing::info!("Fault injection sites:");
+    for site in SITES.iter().filter(|s| s.hits() > 0) {
+        tracing::info!(
+            " - {}: {} faults in {} calls",
+            site.name(),
+            site.injected(),
+            site.hits()
+        );
+    }
+}
+
+/// Report the active policy, if any, and stop injecting faults. The panic
+/// handler calls this so that the seed that caused a panic is in the trace,
+/// and so that recovering from a panicking test doesn't leave injection on.
+pub fn on_panic() {
+    if let Some(policy) = policy() {
+        ENABLED.store(false, Ordering::SeqCst);
+        tracing::error!(
+            seed = policy.seed,
+            rate = policy.rate as u64,
+            "Fault injection was enabled"
+        );
+    }
+}
+
+/// Run `f` once for each seed in `seeds`, with faults injected at `rate`. This
+/// is meant for tests that look for error paths that panic or break
+/// invariants. If `f` panics, the panic handler reports the seed, which can be
+/// rerun with `cargo xtask run --fault-seed`.
+pub fn sweep(seeds: Range<u64>, rate: u32, mut f: impl FnMut(u64)) {
+    let previous = policy();
+    for seed in seeds {
+        set_policy(Some(Policy { seed, rate }));
+        f(seed);
+    }
+    dump_stats();
+    set_policy(previous);
+}
+
+/// 64-bit FNV-1a hash
+pub(crate) const fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+/// The SplitMix64 output function, which scrambles nearby inputs into
+/// unrelated outputs
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(all(test, feature = "fault-injection"))]
+mod tests {
+    use alloc::vec::Vec;
+
+    use ktest::*;
+
+    use super::*;
+    use crate::mm::map::{Kind, MemoryMapBuilder, Region};
+    use crate::prelude::*;
+
+    #[ktest::test]
+    fn test_parse_policy() {
+        ktassert_eq!(
+            Policy::parse("seed=42,rate=10"),
+            Some(Policy { seed: 42, rate: 10 })
+        );
+        ktassert_eq!(
+            Policy::parse("seed=7\n"),
+            Some(Policy {
+                seed: 7,
+                rate: DEFAULT_RATE
+            })
+        );
+        ktassert_eq!(Policy::parse("rate=10"), None);
+        ktassert_eq!(Policy::parse("seed=x"), None);
+    }
+
+    #[ktest::test]
+    fn test_same_seed_same_faults() {
+        static SITE: Site = Site::new("fault.test");
+        let run = |seed| {
+            // This site isn't registered, so set_policy doesn't reset it
+            SITE.hits.store(0, Ordering::Relaxed);
+            set_policy(Some(Policy { seed, rate: 4 }));
+            let faults = (0..64).map(|_| should_fail(&SITE)).collect::<Vec<_>>();
+            set_policy(None);
+            faults
+        };
+
+        let first = run(1);
+        ktassert!(first.contains(&true));
+        ktassert!(first.contains(&false));
+        ktassert_eq!(run(1), first);
+        ktassert!(run(2) != first);
+    }
+
+    /// Failed pushes must leave the memory map unchanged. Assertions inside
+    /// the sweep panic, so that the panic handler reports the seed.
+    #[ktest::test]
+    fn test_sweep_memory_map() {
+        fn region(kind: Kind, start: usize, end: usize) -> Region {
+            Region::new(kind, PhysicalAddress::new(start), PhysicalAddress::new(end))
+        }
+
+        sweep(0..32, 3, |_| {
+            let mut builder = MemoryMapBuilder::<8>::new();
+            for i in 0..8 {
+                let kind = if i % 2 == 0 {
+                    Kind::Usable
+                } else {
+                    Kind::Reserved
+                };
+                let before = builder.regions().to_vec();
+                let result = builder.try_push(region(kind, i * 0x1000, (i + 1) * 0x1000));
+                if result.is_err() {
+                    assert_eq!(builder.regions(), before.as_slice());
+                }
+                assert!(builder
+                    .regions()
+                    .windows(2)
+                    .all(|w| w[0].end() <= w[1].start()));
+            }
+        });
+    }
+}
@@ -0,0 +1,153 @@
+//! Self-tests for ktest fixtures.
+//!
+//! The test-scoped fixtures here record when they're set up and torn down.
+//! Tests can run in any order, or be filtered out, so instead of expecting a
+//! fixed history, each test checks that everything recorded before its own
+//! setup is whole lifetimes: set up in parameter order and torn down in
+//! reverse, including for the test that panics. The run-scoped fixture checks
+//! the history once more when it's torn down, after every test has run. That
+//! teardown isn't recovered from, so a failed check there panics the kernel.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+use ktest::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Event {
+    OuterSetUp = 1,
+    InnerSetUp,
+    OuterTornDown,
+    InnerTornDown,
+}
+
+impl Event {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Event::OuterSetUp,
+            2 => Event::InnerSetUp,
+            3 => Event::OuterTornDown,
+            _ => Event::InnerTornDown,
+        }
+    }
+}
+
+/// Fixture events, in order
+static EVENTS: [AtomicU8; 64] = [const { AtomicU8::new(0) }; 64];
+static EVENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// How many times [`Shared`] has been set up
+static SHARED_SETUPS: AtomicUsize = AtomicUsize::new(0);
+
+fn record(event: Event) {
+    let index = EVENT_COUNT.fetch_add(1, Ordering::Relaxed);
+    assert!(index < EVENTS.len(), "too many fixture events");
+    EVENTS[index].store(event as u8, Ordering::Relaxed);
+}
+
+fn events() -> Vec<Event> {
+    EVENTS[..EVENT_COUNT.load(Ordering::Relaxed)]
+        .iter()
+        .map(|event| Event::from_u8(event.load(Ordering::Relaxed)))
+        .collect()
+}
+
+/// Whether `events` is a series of whole lifetimes of a pair of test-scoped
+/// fixtures, torn down in the reverse of the order they were set up in
+fn whole_lifetimes(events: &[Event]) -> bool {
+    use Event::*;
+
+    events.chunks(4).all(|lifetime| {
+        matches!(
+            lifetime,
+            [OuterSetUp, InnerSetUp, InnerTornDown, OuterTornDown]
+                | [InnerSetUp, OuterSetUp, OuterTornDown, InnerTornDown]
+        )
+    })
+}
+
+struct Outer;
+
+#[ktest::fixture]
+fn outer() -> Outer {
+    record(Event::OuterSetUp);
+    Outer
+}
+
+impl Drop for Outer {
+    fn drop(&mut self) {
+        record(Event::OuterTornDown);
+    }
+}
+
+struct Inner;
+
+#[ktest::fixture]
+fn inner() -> Inner {
+    record(Event::InnerSetUp);
+    Inner
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        record(Event::InnerTornDown);
+    }
+}
+
+struct Shared;
+
+#[ktest::fixture(scope = "run")]
+fn shared() -> Shared {
+    SHARED_SETUPS.fetch_add(1, Ordering::Relaxed);
+    Shared
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        assert!(
+            ktest::current_test().is_none(),
+            "run-scoped fixture torn down during a test"
+        );
+        let events = events();
+        assert!(
+            whole_lifetimes(&events),
+            "fixtures were not all torn down in reverse order: {:?}",
+            events
+        );
+    }
+}
+
+#[ktest::test]
+fn test_setup_in_parameter_order(_outer: &Outer, _inner: &Inner) {
+    let events = events();
+    let (earlier, own) = events.split_at(events.len() - 2);
+    ktassert!(whole_lifetimes(earlier));
+    ktassert_eq!(own, [Event::OuterSetUp, Event::InnerSetUp]);
+}
+
+#[ktest::test]
+fn test_setup_in_reversed_parameter_order(_inner: &Inner, _outer: &Outer) {
+    let events = events();
+    let (earlier, own) = events.split_at(events.len() - 2);
+    ktassert!(whole_lifetimes(earlier));
+    ktassert_eq!(own, [Event::InnerSetUp, Event::OuterSetUp]);
+}
+
+// Taking the run-scoped fixture means its teardown checks this test's fixtures were torn down,
+// even if no other test runs after it
+#[ktest::test]
+#[should_panic(expected = "panicking with fixtures")]
+fn test_teardown_after_panic(_shared: &Shared, _outer: &Outer, _inner: &Inner) {
+    panic!("panicking with fixtures");
+}
+
+#[ktest::test]
+fn test_run_scope_set_up_once(_shared: &Shared) {
+    ktassert_eq!(SHARED_SETUPS.load(Ordering::Relaxed), 1);
+}
+
+#[ktest::test]
+fn test_run_scope_shared_between_tests(_shared: &Shared) {
+    ktassert_eq!(SHARED_SETUPS.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,176 @@
+//! Display adapters for log output.
+//!
+//! Wrap a value in one of these to log it in a consistent, readable form:
+//! [`HumanBytes`] for sizes, [`HumanDuration`] for times, and [`HexDump`] for
+//! raw memory. None of them allocate, so they're usable before the heap is
+//! set up.
+
+use core::fmt;
+use core::time::Duration;
+
+use platypos_hal::time::Clock;
+
+use crate::prelude::*;
+
+/// A size in bytes, shown in the largest binary unit it fills, like `1.5 MiB`
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanBytes(pub u64);
+
+const BYTE_UNITS: &[(u64, &str)] = &[
+    (1 << 40, "TiB"),
+    (1 << 30, "GiB"),
+    (1 << 20, "MiB"),
+    (1 << 10, "KiB"),
+];
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match BYTE_UNITS.iter().find(|(unit, _)| self.0 >= *unit) {
+            Some(&(unit, suffix)) => write_tenths(f, self.0, unit, suffix),
+            None => write!(f, "{} B", self.0),
+        }
+    }
+}
+
+/// A length of time, shown in the largest unit it fills, like `12.5 ms`
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    /// Convert clock ticks, if the clock is calibrated
+    pub fn from_ticks(ticks: u64) -> Option<Self> {
+        hal_impl::time::INSTANCE.to_duration(ticks).map(Self)
+    }
+}
+
+const DURATION_UNITS: &[(u64, &str)] = &[(1_000_000_000, "s"), (1_000_000, "ms"), (1_000, "us")];
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nanos = self.0.as_nanos().try_into().unwrap_or(u64::MAX);
+        match DURATION_UNITS.iter().find(|(unit, _)| nanos >= *unit) {
+            Some(&(unit, suffix)) => write_tenths(f, nanos, unit, suffix),
+            None => write!(f, "{nanos} ns"),
+        }
+    }
+}
+
+/// Write `value / unit` with at most one (truncated) decimal place
+fn write_tenths(f: &mut fmt::Formatter, value: u64, unit: u64, suffix: &str) -> fmt::Result {
+    let whole = value / unit;
+    let tenths = (u128::from(value % unit) * 10 / u128::from(unit)) as u64;
+    if tenths == 0 {
+        write!(f, "{whole} {suffix}")
+    } else {
+        write!(f, "{whole}.{tenths} {suffix}")
+    }
+}
+
+/// Bytes per line of a [`HexDump`]
+const DUMP_WIDTH: usize = 16;
+
+/// A classic hex dump, 16 bytes per line with offsets and the printable ASCII
+/// characters:
+///
+/// ```text
+/// 00000000  52 53 44 20 50 54 52 20  42 4f 43 48 53 20 00 00  |RSD PTR BOCHS ..|
+/// ```
+///
+/// Each line ends with a newline, so it reads best on its own after a heading.
+pub struct HexDump<'a> {
+    bytes: &'a [u8],
+    base: usize,
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, base: 0 }
+    }
+
+    /// Label lines with offsets starting from `base`, like the address the
+    /// bytes were read from
+    pub fn with_base(self, base: usize) -> Self {
+        Self { base, ..self }
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, line) in self.bytes.chunks(DUMP_WIDTH).enumerate() {
+            write!(f, "{:08x} ", self.base + i * DUMP_WIDTH)?;
+            for column in 0..DUMP_WIDTH {
+                if column % 8 == 0 {
+                    f.write_char(' ')?;
+                }
+                match line.get(column) {
+                    Some(byte) => write!(f, "{byte:02x} ")?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str(" |")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                f.write_char(c)?;
+            }
+            f.write_str("|\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_human_bytes() {
+        ktassert_eq!(format!("{}", HumanBytes(0)), "0 B");
+        ktassert_eq!(format!("{}", HumanBytes(1023)), "1023 B");
+        ktassert_eq!(format!("{}", HumanBytes(4096)), "4 KiB");
+        ktassert_eq!(format!("{}", HumanBytes(3 << 19)), "1.5 MiB");
+        ktassert_eq!(format!("{}", HumanBytes((1 << 30) - 1)), "1023.9 MiB");
+    }
+
+    #[ktest::test]
+    fn test_human_duration() {
+        ktassert_eq!(
+            format!("{}", HumanDuration(Duration::from_nanos(850))),
+            "850 ns"
+        );
+        ktassert_eq!(
+            format!("{}", HumanDuration(Duration::from_micros(12))),
+            "12 us"
+        );
+        ktassert_eq!(
+            format!("{}", HumanDuration(Duration::from_micros(4560))),
+            "4.5 ms"
+        );
+        ktassert_eq!(
+            format!("{}", HumanDuration(Duration::from_secs(90))),
+            "90 s"
+        );
+    }
+
+    #[ktest::test]
+    fn test_hex_dump() {
+        let dump = format!(
+            "{}",
+            HexDump::new(b"RSD PTR \x00\x01abcdefghij").with_base(0x10)
+        );
+        ktassert_eq!(
+            dump,
+            "00000010  52 53 44 20 50 54 52 20  00 01 61 62 63 64 65 66  |RSD PTR ..abcdef|\n\
+             00000020  67 68 69 6a                                       |ghij|\n"
+        );
+    }
+}
@@ -0,0 +1,463 @@
+//! Read-only filesystem for the initial ramdisk.
+//!
+//! The bootloader can load an initrd alongside the kernel, so that tests, fonts,
+//! and configuration files can ship with the kernel image without needing a
+//! block device filesystem. The initrd is a tar archive, in either POSIX ustar
+//! or GNU format, which is parsed in place: file contents are borrowed directly
+//! from the loaded image.
+//!
+//! Only regular files and directories are supported. Other entries, like
+//! symbolic links, are skipped.
+
+use core::fmt;
+use core::ops::Range;
+use core::str;
+
+use platypos_common::sync::Global;
+
+use crate::prelude::*;
+
+/// Tar archives are made of 512-byte blocks
+const BLOCK_SIZE: usize = 512;
+
+// Header field locations
+const NAME: Range<usize> = 0..100;
+const SIZE: Range<usize> = 124..136;
+const CHECKSUM: Range<usize> = 148..156;
+const TYPE_FLAG: usize = 156;
+const MAGIC: Range<usize> = 257..263;
+const PREFIX: Range<usize> = 345..500;
+
+const MAGIC_USTAR: &[u8] = b"ustar\0";
+/// GNU headers use the same magic, but a different version and no prefix field
+const MAGIC_GNU: &[u8] = b"ustar ";
+
+const TYPE_FILE: u8 = b'0';
+/// Regular files in pre-POSIX archives
+const TYPE_FILE_OLD: u8 = b'\0';
+const TYPE_DIRECTORY: u8 = b'5';
+/// GNU extension: the entry's data is the full path of the next entry
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+
+static INITRD: Global<Archive<'static>> = Global::new();
+
+/// Parse the initrd loaded at `data` and make it available through [`open`]
+/// and [`read_dir`].
+///
+/// # Errors
+/// Returns [`IoError::Corrupt`] if `data` isn't a valid tar archive.
+pub fn init(data: &'static [u8]) -> Result<(), Error> {
+    let archive = Archive::new(data).context("parsing initrd")?;
+    tracing::info!(
+        size = data.len(),
+        "Loaded initrd with {} entries",
+        archive.entries().count()
+    );
+    INITRD.init(archive);
+    Ok(())
+}
+
+/// The initrd, if the bootloader provided one
+pub fn get() -> Option<&'static Archive<'static>> {
+    INITRD.try_get()
+}
+
+/// Get the contents of the initrd file at `path`
+///
+/// # Errors
+/// Returns [`IoError::NotFound`] if there is no such file, or if there is no
+/// initrd.
+pub fn open(path: &str) -> Result<&'static [u8], Error> {
+    get()
+        .ok_or_else(|| Error::new(IoError::NotFound).context("no initrd loaded"))?
+        .open(path)
+}
+
+/// List the initrd directory at `path`
+///
+/// # Errors
+/// Returns [`IoError::NotFound`] if there is no such directory, or if there is
+/// no initrd.
+pub fn read_dir(path: &str) -> Result<impl Iterator<Item = Entry<'static>> + '_, Error> {
+    get()
+        .ok_or_else(|| Error::new(IoError::NotFound).context("no initrd loaded"))?
+        .read_dir(path)
+}
+
+/// A tar archive in memory
+#[derive(Debug, Clone, Copy)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    /// Validate the archive in `data`. Every header is checked up front, so
+    /// later lookups can't fail because of corruption.
+    ///
+    /// # Errors
+    /// Returns [`IoError::Corrupt`] if a header is malformed, or if an entry
+    /// extends past the end of `data`.
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let archive = Self { data };
+        let mut offset = 0;
+        while let Some((_, next)) = archive.parse(offset)? {
+            offset = next;
+        }
+        Ok(archive)
+    }
+
+    /// Iterate over every file and directory in the archive, in archive order
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            archive: *self,
+            offset: 0,
+        }
+    }
+
+    /// Find the entry at `path`
+    pub fn find(&self, path: &str) -> Option<Entry<'a>> {
+        self.entries().find(|entry| entry.is(path))
+    }
+
+    /// Get the contents of the file at `path`
+    ///
+    /// # Errors
+    /// Returns [`IoError::NotFound`] if there is no such file, or
+    /// [`IoError::IsADirectory`] if `path` is a directory.
+    pub fn open(&self, path: &str) -> Result<&'a [u8], Error> {
+        match self.find(path) {
+            Some(entry) if entry.kind() == EntryKind::File => Ok(entry.data()),
+            Some(_) => Err(Error::new(IoError::IsADirectory)),
+            None => Err(Error::new(IoError::NotFound)),
+        }
+    }
+
+    /// List the entries directly inside the directory at `path`. Archives
+    /// don't have to include entries for each directory, so a directory
+    /// exists if any entry is inside it. However, only directories with their
+    /// own entries are listed.
+    ///
+    /// # Errors
+    /// Returns [`IoError::NotFound`] if there is no such directory, or
+    /// [`IoError::NotADirectory`] if `path` is a file.
+    pub fn read_dir<'p>(&self, path: &'p str) -> Result<impl Iterator<Item = Entry<'a>> + 'p, Error>
+    where
+        'a: 'p,
+    {
+        let depth = components(path).count();
+        match self.find(path) {
+            Some(entry) if entry.kind() == EntryKind::File => {
+                return Err(Error::new(IoError::NotADirectory))
+            }
+            Some(_) => (),
+            // The root directory always exists
+            None if depth == 0 => (),
+            None if self.entries().any(|entry| entry.is_within(path)) => (),
+            None => return Err(Error::new(IoError::NotFound)),
+        }
+
+        Ok(self
+            .entries()
+            .filter(move |entry| entry.depth() == depth + 1 && entry.is_within(path)))
+    }
+
+    /// Parse the entry whose header starts at `offset`, skipping unsupported
+    /// entries. Returns the entry and the offset of the next header, or `None`
+    /// at the end of the archive.
+    fn parse(&self, mut offset: usize) -> Result<Option<(Entry<'a>, usize)>, Error> {
+        let corrupt = || Error::new(IoError::Corrupt);
+        let mut long_name = None;
+
+        loop {
+            let Some(header) = self.data.get(offset..offset + BLOCK_SIZE) else {
+                // Archives should end with two zero blocks, but tolerate ones
+                // that stop at a block boundary
+                return if offset == self.data.len() {
+                    Ok(None)
+                } else {
+                    Err(corrupt())
+                };
+            };
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+
+            let magic = &header[MAGIC];
+            if (magic != MAGIC_USTAR && magic != MAGIC_GNU) || !checksum_valid(header) {
+                return Err(corrupt().context("invalid tar header"));
+            }
+
+            let size = parse_octal(&header[SIZE]).ok_or_else(corrupt)? as usize;
+            let data_start = offset + BLOCK_SIZE;
+            let data = data_start
+                .checked_add(size)
+                .and_then(|data_end| self.data.get(data_start..data_end))
+                .ok_or_else(corrupt)?;
+            let next = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+            let kind = match header[TYPE_FLAG] {
+                TYPE_FILE | TYPE_FILE_OLD => EntryKind::File,
+                TYPE_DIRECTORY => EntryKind::Directory,
+                TYPE_GNU_LONG_NAME => {
+                    long_name = Some(str_field(data)?);
+                    offset = next;
+                    continue;
+                }
+                _ => {
+                    long_name = None;
+                    offset = next;
+                    continue;
+                }
+            };
+
+            let (prefix, name) = match long_name {
+                Some(name) => ("", name),
+                None if magic == MAGIC_USTAR => {
+                    (str_field(&header[PREFIX])?, str_field(&header[NAME])?)
+                }
+                None => ("", str_field(&header[NAME])?),
+            };
+
+            let entry = Entry {
+                prefix,
+                name,
+                kind,
+                data,
+            };
+            return Ok(Some((entry, next)));
+        }
+    }
+}
+
+/// Iterator over the entries in an [`Archive`]
+pub struct Entries<'a> {
+    archive: Archive<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        // The archive was validated when it was created, so this won't fail
+        let (entry, next) = self.archive.parse(self.offset).ok()??;
+        self.offset = next;
+        Some(entry)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// A file or directory in an [`Archive`]
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    // ustar archives split long paths between the name and prefix fields
+    prefix: &'a str,
+    name: &'a str,
+    kind: EntryKind,
+    data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// The file contents. This is empty for directories.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The last component of the entry's path
+    pub fn name(&self) -> &'a str {
+        self.components().last().unwrap_or("")
+    }
+
+    fn components(&self) -> impl Iterator<Item = &'a str> {
+        components(self.prefix).chain(components(self.name))
+    }
+
+    fn depth(&self) -> usize {
+        self.components().count()
+    }
+
+    /// Whether this entry is at `path`
+    fn is(&self, path: &str) -> bool {
+        self.components().eq(components(path))
+    }
+
+    /// Whether this entry is somewhere under the directory `path`
+    fn is_within(&self, path: &str) -> bool {
+        let mut entry = self.components();
+        components(path).all(|component| entry.next() == Some(component)) && entry.next().is_some()
+    }
+}
+
+impl<'a> fmt::Display for Entry<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for component in self.components() {
+            write!(f, "/{component}")?;
+        }
+        if self.kind == EntryKind::Directory {
+            f.write_str("/")?;
+        }
+        Ok(())
+    }
+}
+
+/// Split `path` into its components, ignoring empty and `.` components so that
+/// `./etc//motd` and `/etc/motd` are the same
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+}
+
+/// Read a NUL-terminated string field
+fn str_field(field: &[u8]) -> Result<&str, Error> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len])
+        .map_err(|_| Error::new(IoError::Corrupt).context("non-UTF-8 path in initrd"))
+}
+
+/// Parse an octal number field, which may be padded with spaces and NULs
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != b' ' && b != 0);
+    let mut value: u64 = 0;
+    let mut any = false;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add(u64::from(digit - b'0'))?;
+        any = true;
+    }
+    any.then_some(value)
+}
+
+/// Check a header's checksum, which is the sum of its bytes with the checksum
+/// field itself counted as spaces
+fn checksum_valid(header: &[u8]) -> bool {
+    let Some(expected) = parse_octal(&header[CHECKSUM]) else {
+        return false;
+    };
+    let actual: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if CHECKSUM.contains(&i) {
+                u64::from(b' ')
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum();
+    actual == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use ktest::*;
+
+    use super::*;
+
+    /// Append a ustar entry to `archive`
+    fn push_entry(archive: &mut Vec<u8>, name: &str, type_flag: u8, data: &[u8]) {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = alloc::format!("{:011o}\0", data.len());
+        header[SIZE].copy_from_slice(size.as_bytes());
+        header[TYPE_FLAG] = type_flag;
+        header[MAGIC].copy_from_slice(MAGIC_USTAR);
+        header[CHECKSUM].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        let checksum = alloc::format!("{checksum:06o}\0 ");
+        header[CHECKSUM].copy_from_slice(checksum.as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+    }
+
+    fn test_archive() -> Vec<u8> {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "etc/", TYPE_DIRECTORY, &[]);
+        push_entry(&mut archive, "etc/motd", TYPE_FILE, b"hello");
+        push_entry(&mut archive, "./fonts/default.psf", TYPE_FILE, &[0xab; 600]);
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+        archive
+    }
+
+    #[ktest::test]
+    fn test_open() {
+        let data = test_archive();
+        let archive = Archive::new(&data).unwrap();
+
+        ktassert_eq!(archive.open("/etc/motd").unwrap(), &b"hello"[..]);
+        ktassert_eq!(archive.open("fonts/default.psf").unwrap().len(), 600);
+        ktassert_eq!(
+            archive.open("/etc").unwrap_err().kind(),
+            KError::Io(IoError::IsADirectory)
+        );
+        ktassert_eq!(
+            archive.open("/etc/passwd").unwrap_err().kind(),
+            KError::Io(IoError::NotFound)
+        );
+    }
+
+    #[ktest::test]
+    fn test_read_dir() {
+        let data = test_archive();
+        let archive = Archive::new(&data).unwrap();
+
+        let root = archive
+            .read_dir("/")
+            .unwrap()
+            .map(|e| e.name())
+            .collect::<Vec<_>>();
+        ktassert_eq!(root, vec!["etc"]);
+        let etc = archive
+            .read_dir("/etc")
+            .unwrap()
+            .map(|e| e.name())
+            .collect::<Vec<_>>();
+        ktassert_eq!(etc, vec!["motd"]);
+        // Implicit directories can be listed, even though they don't have
+        // their own entry
+        let fonts = archive.read_dir("fonts/").unwrap().count();
+        ktassert_eq!(fonts, 1);
+
+        ktassert_eq!(
+            archive.read_dir("/etc/motd").err().map(|e| e.kind()),
+            Some(KError::Io(IoError::NotADirectory))
+        );
+        ktassert_eq!(
+            archive.read_dir("/usr").err().map(|e| e.kind()),
+            Some(KError::Io(IoError::NotFound))
+        );
+    }
+
+    #[ktest::test]
+    fn test_corrupt() {
+        let mut data = test_archive();
+        // Flip a byte in the second header's name
+        data[BLOCK_SIZE] ^= 1;
+        ktassert_eq!(
+            Archive::new(&data).unwrap_err().kind(),
+            KError::Io(IoError::Corrupt)
+        );
+
+        // Truncate the last file's data
+        let data = test_archive();
+        ktassert!(Archive::new(&data[..4 * BLOCK_SIZE]).is_err());
+    }
+}
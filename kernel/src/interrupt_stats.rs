@@ -0,0 +1,340 @@
+//! Interrupt statistics.
+//!
+//! The HAL counts every interrupt by vector and processor. A sampler thread
+//! turns those counts into per-second rates, which it feeds to ktrace as
+//! `interrupt_rate` events, and [`report`] shows them all as a table. Together,
+//! they make misrouted or storming interrupts easy to spot during bring-up.
+//!
+//! The HAL also times every handler that returns, into a histogram per vector
+//! and processor. The sampler reports their percentiles as `interrupt_latency`
+//! events for the vectors that were delivered since the last sample, and
+//! [`latency`] shows them as a table.
+//!
+//! Vectors that storm badly enough are masked by the HAL. The threshold comes
+//! from the `opt/platypos/irq-storm-threshold` fw_cfg blob
+//! (`cargo xtask run --irq-storm-threshold`), and [`unmask`] turns a vector
+//! back on.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+use platypos_hal::time::Clock as _;
+use platypos_hal::topology::{self, Topology as _};
+
+use crate::arch::hal_impl::interrupts::{
+    self, interrupt_count, latency_histogram, storm_masked_vectors, LatencyHistogram, Source,
+    VECTORS,
+};
+use crate::drivers::fw_cfg;
+use crate::prelude::*;
+use crate::sched::{self, Priority};
+
+/// How often interrupt rates are sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// fw_cfg blob with the interrupt storm threshold, in interrupts per second,
+/// or 0 to never mask storming vectors
+const STORM_THRESHOLD_BLOB: &str = "opt/platypos/irq-storm-threshold";
+
+/// Interrupts per second on each vector, across all processors, as of the
+/// last sample
+static RATES: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+
+platypos_ktrace::schema! {
+    /// `count` interrupts on `vector` were delivered to `processor` in the
+    /// last sample, which is `rate` per second
+    event interrupt_rate(DEBUG) {
+        vector: u64,
+        processor: u64,
+        count: u64,
+        rate: u64,
+    }
+
+    /// Handlers for `vector` on `processor` have taken at most `median_ns`
+    /// half the time, `p99_ns` 99% of the time, and `max_ns` at worst, over
+    /// the `count` handled since boot
+    event interrupt_latency(DEBUG) {
+        vector: u64,
+        processor: u64,
+        count: u64,
+        median_ns: u64,
+        p99_ns: u64,
+        max_ns: u64,
+    }
+}
+
+/// Start sampling interrupt rates. This must be called after the scheduler is
+/// initialized.
+pub fn init() {
+    sched::spawn(Priority::Normal, sample_rates()).expect("Could not start interrupt sampler");
+}
+
+async fn sample_rates() {
+    let max_processors = hal_impl::topology::Topology::MAX_PROCESSORS as usize;
+    let mut previous = vec![[0u64; VECTORS]; max_processors];
+    let mut last = sched::uptime();
+    loop {
+        sched::sleep(SAMPLE_INTERVAL).await;
+        let now = sched::uptime();
+        let elapsed = (now - last).as_nanos().max(1);
+        last = now;
+
+        let mut totals = [0u64; VECTORS];
+        for processor in topology::online_processors().iter() {
+            let previous = &mut previous[processor as usize];
+            for (vector, previous) in (0..=u8::MAX).zip(previous.iter_mut()) {
+                let count = interrupt_count(processor, vector);
+                let delta = count - *previous;
+                *previous = count;
+                if delta == 0 {
+                    continue;
+                }
+
+                let rate = (u128::from(delta) * 1_000_000_000 / elapsed) as u64;
+                totals[usize::from(vector)] += rate;
+                interrupt_rate(vector.into(), processor.into(), delta, rate);
+
+                let latency = latency_histogram(processor, vector);
+                if latency.count() > 0 {
+                    interrupt_latency(
+                        vector.into(),
+                        processor.into(),
+                        latency.count(),
+                        ticks_to_nanos(latency.percentile(50)),
+                        ticks_to_nanos(latency.percentile(99)),
+                        ticks_to_nanos(latency.max_ticks),
+                    );
+                }
+            }
+        }
+        for (rate, total) in RATES.iter().zip(totals) {
+            rate.store(total, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Write a table of interrupt counts, with a column per online processor and
+/// the latest rate, for every vector that has been delivered at least once
+pub fn write_report(w: &mut impl Write) -> core::fmt::Result {
+    let processors = topology::online_processors();
+
+    write!(w, "{:>6}  {:<22}", "vector", "source")?;
+    for processor in processors.iter() {
+        write!(w, " {:>10}", format!("CPU {processor}"))?;
+    }
+    writeln!(w, " {:>10}", "rate/s")?;
+
+    for vector in 0..=u8::MAX {
+        if processors.iter().all(|p| interrupt_count(p, vector) == 0) {
+            continue;
+        }
+
+        let source = match Source::of(vector) {
+            Some(source) => format!("{source}"),
+            None => String::from("(unhandled)"),
+        };
+        write!(w, "{vector:>#6x}  {source:<22}")?;
+        for processor in processors.iter() {
+            write!(w, " {:>10}", interrupt_count(processor, vector))?;
+        }
+        writeln!(
+            w,
+            " {:>10}",
+            RATES[usize::from(vector)].load(Ordering::Relaxed)
+        )?;
+    }
+    Ok(())
+}
+
+/// Convert handler latency from TSC ticks to nanoseconds. The TSC is
+/// calibrated before the scheduler starts, so this only falls back to ticks
+/// for reports made very early in boot.
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    hal_impl::time::INSTANCE
+        .calibration()
+        .map_or(ticks, |calibration| calibration.ticks_to_nanos(ticks))
+}
+
+/// Write a table of handler latency percentiles for every vector that has
+/// been handled at least once, combining all online processors
+pub fn write_latency_report(w: &mut impl Write) -> core::fmt::Result {
+    let unit = if hal_impl::time::INSTANCE.calibration().is_some() {
+        "ns"
+    } else {
+        "ticks"
+    };
+    writeln!(
+        w,
+        "{:>6}  {:<22} {:>10} {:>12} {:>12} {:>12}",
+        "vector",
+        "source",
+        "count",
+        format!("p50 {unit}"),
+        format!("p99 {unit}"),
+        format!("max {unit}"),
+    )?;
+
+    for vector in 0..=u8::MAX {
+        let mut latency = LatencyHistogram::default();
+        for processor in topology::online_processors().iter() {
+            latency.merge(&latency_histogram(processor, vector));
+        }
+        if latency.count() == 0 {
+            continue;
+        }
+
+        let source = match Source::of(vector) {
+            Some(source) => format!("{source}"),
+            None => String::from("(unhandled)"),
+        };
+        writeln!(
+            w,
+            "{vector:>#6x}  {source:<22} {:>10} {:>12} {:>12} {:>12}",
+            latency.count(),
+            ticks_to_nanos(latency.percentile(50)),
+            ticks_to_nanos(latency.percentile(99)),
+            ticks_to_nanos(latency.max_ticks),
+        )?;
+    }
+    Ok(())
+}
+
+/// Apply the interrupt storm threshold passed through fw_cfg, if any. This
+/// must be called after [`fw_cfg::init`].
+pub fn configure() {
+    let Ok(blob) = fw_cfg::read(STORM_THRESHOLD_BLOB) else {
+        return;
+    };
+    match core::str::from_utf8(&blob)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+    {
+        Some(0) => {
+            interrupts::set_storm_threshold(0);
+            tracing::info!("Interrupt storm detection is off");
+        }
+        Some(threshold) => {
+            interrupts::set_storm_threshold(threshold);
+            tracing::info!("Masking vectors that exceed {threshold} interrupts per second");
+        }
+        None => tracing::warn!("Ignoring malformed interrupt storm threshold"),
+    }
+}
+
+/// Unmask `vector` after storm detection masked it on this processor. Returns
+/// `false`, after logging which vectors are masked, if it wasn't.
+pub fn unmask(vector: u8) -> bool {
+    let unmasked = interrupts::clear_storm(vector);
+    if !unmasked {
+        let masked = storm_masked_vectors()
+            .map(|vector| format!("{vector:#04x}"))
+            .collect::<Vec<_>>();
+        let masked = if masked.is_empty() {
+            String::from("none")
+        } else {
+            masked.join(", ")
+        };
+        tracing::warn!("Vector {vector:#04x} isn't masked (masked vectors: {masked})");
+    }
+    unmasked
+}
+
+/// Unmask every vector that storm detection masked on this processor. This is
+/// bound to F3 by [`debug_keys`], so it runs on the processor that handles the
+/// keyboard.
+///
+/// [`debug_keys`]: crate::debug_keys
+pub fn unmask_all() {
+    let masked = storm_masked_vectors().collect::<Vec<_>>();
+    if masked.is_empty() {
+        tracing::info!("No vectors are masked");
+    }
+    for vector in masked {
+        unmask(vector);
+    }
+}
+
+/// Log the interrupt table. This is bound to F1 by [`debug_keys`].
+///
+/// [`debug_keys`]: crate::debug_keys
+pub fn report() {
+    let mut table = String::new();
+    let _ = write_report(&mut table);
+    tracing::info!("Interrupts:\n{table}");
+}
+
+/// Log the handler latency table. This is bound to F4 by [`debug_keys`].
+///
+/// [`debug_keys`]: crate::debug_keys
+pub fn latency() {
+    let mut table = String::new();
+    let _ = write_latency_report(&mut table);
+    tracing::info!("Interrupt handler latency:\n{table}");
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    /// Puts back the storm threshold, which may have come from fw_cfg, and
+    /// unmasks any vectors a test left storming, even if the test panicked
+    struct StormThreshold(u32);
+
+    #[ktest::fixture]
+    fn storm_threshold() -> StormThreshold {
+        StormThreshold(interrupts::storm_threshold())
+    }
+
+    impl Drop for StormThreshold {
+        fn drop(&mut self) {
+            interrupts::set_storm_threshold(self.0);
+            for vector in storm_masked_vectors().collect::<Vec<_>>() {
+                interrupts::clear_storm(vector);
+            }
+        }
+    }
+
+    #[ktest::test]
+    fn test_mask_and_unmask(_threshold: &StormThreshold) {
+        let timer = (0..=u8::MAX)
+            .find(|&vector| Source::of(vector) == Some(Source::Timer))
+            .unwrap();
+        let clock = &hal_impl::time::INSTANCE;
+        let calibration = clock.calibration().unwrap();
+
+        // At 100 per second, the scheduler tick storms within a window
+        interrupts::set_storm_threshold(100);
+        let start = clock.instant();
+        while !storm_masked_vectors().any(|vector| vector == timer)
+            && clock.instant().duration_since(start, calibration) < Duration::from_secs(1)
+        {
+            core::hint::spin_loop();
+        }
+        // Stop detecting storms until the fixture restores the threshold, so the tick isn't
+        // masked again
+        interrupts::set_storm_threshold(0);
+
+        let masked = storm_masked_vectors().any(|vector| vector == timer);
+        let unmasked = unmask(timer);
+        ktassert!(masked);
+        ktassert!(unmasked);
+        ktassert!(!storm_masked_vectors().any(|vector| vector == timer));
+        ktassert!(!unmask(timer));
+
+        // The tick is running again
+        let ticks = sched::now();
+        let start = clock.instant();
+        while sched::now() == ticks
+            && clock.instant().duration_since(start, calibration) < Duration::from_secs(1)
+        {
+            core::hint::spin_loop();
+        }
+        ktassert!(sched::now() > ticks);
+    }
+}
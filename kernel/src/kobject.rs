@@ -0,0 +1,424 @@
+//! Reference-counted kernel objects.
+//!
+//! Kernel objects like timers, tasks, and drivers are shared between
+//! subsystems, so none of them owns the object outright. Instead of giving
+//! each one its own static, objects embed a [`RefCount`] and implement
+//! [`KObject`] (usually through [`kobject!`]), and are shared through typed
+//! [`Handle`]s. The object is freed when its last handle is dropped.
+//!
+//! Because the count is part of the object, a handle can be recovered from a
+//! plain reference with [`Handle::from_ref`], so callbacks that only get a
+//! `&T` can still keep the object alive.
+//!
+//! With the `object-accounting` feature, each [`ObjectType`] counts how many
+//! of its objects are alive, which helps track down leaks. See
+//! [`dump_stats`].
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::NonNull;
+use core::sync::atomic::{self, AtomicU64, AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+
+use platypos_hal::linkme::distributed_slice;
+
+/// Reference counts above this are assumed to come from leaked handles. The
+/// limit leaves headroom so that concurrent increments can't wrap the count
+/// before one of them notices.
+const MAX_REF_COUNT: usize = isize::MAX as usize;
+
+/// Every kernel object type
+#[distributed_slice]
+#[linkme(crate = platypos_hal::linkme)]
+pub static TYPES: [ObjectType] = [..];
+
+/// Implement [`KObject`] for `$ty`, whose [`RefCount`] is in `$field`.
+/// `$name` identifies the type in statistics, like `"timer"`.
+macro_rules! kobject {
+    ($ty:ty, $field:ident, $name:literal) => {
+        // SAFETY: the count is only exposed through `ref_count`
+        unsafe impl $crate::kobject::KObject for $ty {
+            fn ref_count(&self) -> &$crate::kobject::RefCount {
+                &self.$field
+            }
+
+            fn object_type() -> &'static $crate::kobject::ObjectType {
+                #[::platypos_hal::linkme::distributed_slice($crate::kobject::TYPES)]
+                #[linkme(crate = ::platypos_hal::linkme)]
+                static TYPE: $crate::kobject::ObjectType = $crate::kobject::ObjectType::new($name);
+                &TYPE
+            }
+        }
+    };
+}
+
+pub(crate) use kobject;
+
+/// An intrusive reference count. New counts start at 1, for the handle that
+/// will own the object.
+pub struct RefCount(AtomicUsize);
+
+impl RefCount {
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(1))
+    }
+
+    /// The current number of references. This may be out of date as soon as
+    /// it's returned.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Add a reference
+    ///
+    /// # Panics
+    /// If there are too many references, which means handles are being leaked
+    fn increment(&self) {
+        // Like `Arc`, new references can only be made from existing ones, so no synchronization
+        // is needed
+        let old = self.0.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_REF_COUNT {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+            panic!("kernel object reference count overflowed");
+        }
+    }
+
+    /// Remove a reference, returning `true` if it was the last one
+    fn decrement(&self) -> bool {
+        if self.0.fetch_sub(1, Ordering::Release) != 1 {
+            return false;
+        }
+        // Make sure every other handle's use of the object happens before it's freed
+        atomic::fence(Ordering::Acquire);
+        true
+    }
+}
+
+impl Default for RefCount {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RefCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RefCount").field(&self.get()).finish()
+    }
+}
+
+/// A type of kernel object, with statistics on how many exist
+pub struct ObjectType {
+    name: &'static str,
+    live: AtomicUsize,
+    created: AtomicU64,
+}
+
+impl ObjectType {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            live: AtomicUsize::new(0),
+            created: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Number of objects of this type that haven't been freed. This is always
+    /// 0 without the `object-accounting` feature.
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// Number of objects of this type ever created. This is always 0 without
+    /// the `object-accounting` feature.
+    pub fn created(&self) -> u64 {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    fn record_created(&self) {
+        if cfg!(feature = "object-accounting") {
+            self.live.fetch_add(1, Ordering::Relaxed);
+            self.created.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn record_freed(&self) {
+        if cfg!(feature = "object-accounting") {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A reference-counted kernel object
+///
+/// # Safety
+/// `ref_count` must always return the same counter, and nothing but
+/// [`Handle`] may change it.
+pub unsafe trait KObject: Send + Sync {
+    fn ref_count(&self) -> &RefCount;
+
+    /// Statistics for this type of object
+    fn object_type() -> &'static ObjectType;
+}
+
+/// A shared reference to a heap-allocated kernel object
+pub struct Handle<T: KObject> {
+    ptr: NonNull<T>,
+    _owns: PhantomData<T>,
+}
+
+// SAFETY: handles only give out shared references, and `KObject`s are Send + Sync
+unsafe impl<T: KObject> Send for Handle<T> {}
+unsafe impl<T: KObject> Sync for Handle<T> {}
+
+impl<T: KObject> Handle<T> {
+    /// Move `object` to the heap, returning the first handle to it
+    ///
+    /// # Panics
+    /// If `object`'s reference count isn't new
+    pub fn new(object: T) -> Self {
+        assert_eq!(
+            object.ref_count().get(),
+            1,
+            "new kernel objects must have a fresh reference count"
+        );
+        T::object_type().record_created();
+        Self {
+            ptr: NonNull::from(Box::leak(Box::new(object))),
+            _owns: PhantomData,
+        }
+    }
+
+    /// Make a new handle to an object, given a reference to it
+    ///
+    /// # Safety
+    /// `object` must have been allocated by [`Handle::new`], as opposed to
+    /// being on the stack or in a static.
+    pub unsafe fn from_ref(object: &T) -> Self {
+        object.ref_count().increment();
+        Self {
+            ptr: NonNull::from(object),
+            _owns: PhantomData,
+        }
+    }
+
+    /// Give up this handle without releasing its reference, returning a
+    /// pointer to the object. This is for passing handles through untyped
+    /// context like [`crate::workqueue::WorkItem`] arguments.
+    pub fn into_raw(handle: Self) -> *const T {
+        let ptr = handle.ptr.as_ptr();
+        core::mem::forget(handle);
+        ptr
+    }
+
+    /// Reclaim a handle given up by [`Handle::into_raw`]
+    ///
+    /// # Safety
+    /// `ptr` must come from [`Handle::into_raw`], and each pointer may only be
+    /// reclaimed once.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        Self {
+            ptr: NonNull::new_unchecked(ptr as *mut T),
+            _owns: PhantomData,
+        }
+    }
+
+    /// Whether two handles refer to the same object
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+
+    /// Number of handles to this object
+    pub fn ref_count(handle: &Self) -> usize {
+        handle.ref_count().get()
+    }
+}
+
+impl<T: KObject> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        self.ref_count().increment();
+        Self {
+            ptr: self.ptr,
+            _owns: PhantomData,
+        }
+    }
+}
+
+impl<T: KObject> Drop for Handle<T> {
+    fn drop(&mut self) {
+        if self.ref_count().decrement() {
+            // SAFETY: this was the last handle, and the object was allocated by `Handle::new`
+            drop(unsafe { Box::from_raw(self.ptr.as_ptr()) });
+            T::object_type().record_freed();
+        }
+    }
+}
+
+impl<T: KObject> Deref for Handle<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the object lives at least as long as this handle
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: KObject + fmt::Debug> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Log statistics for every kernel object type. Without the
+/// `object-accounting` feature, there are no statistics to log. This is bound
+/// to F6 by [`debug_keys`].
+///
+/// [`debug_keys`]: crate::debug_keys
+pub fn dump_stats() {
+    if !cfg!(feature = "object-accounting") {
+        tracing::info!("Kernel object accounting is disabled");
+        return;
+    }
+
+    tracing::info!("Kernel objects:");
+    for ty in TYPES.iter() {
+        tracing::info!(
+            " - {}: {} live, {} created",
+            ty.name(),
+            ty.live(),
+            ty.created()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+
+    use alloc::vec::Vec;
+
+    use ktest::*;
+
+    use super::*;
+
+    struct TestObject {
+        refs: RefCount,
+        dropped: &'static AtomicBool,
+    }
+
+    kobject!(TestObject, refs, "test");
+
+    impl Drop for TestObject {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[ktest::test]
+    fn test_last_handle_frees() {
+        static DROPPED: AtomicBool = AtomicBool::new(false);
+        let handle = Handle::new(TestObject {
+            refs: RefCount::new(),
+            dropped: &DROPPED,
+        });
+        let other = handle.clone();
+        ktassert_eq!(Handle::ref_count(&handle), 2);
+        ktassert!(Handle::ptr_eq(&handle, &other));
+
+        drop(handle);
+        ktassert!(!DROPPED.load(Ordering::Relaxed));
+        drop(other);
+        ktassert!(DROPPED.load(Ordering::Relaxed));
+    }
+
+    #[ktest::test]
+    fn test_from_ref_and_raw() {
+        static DROPPED: AtomicBool = AtomicBool::new(false);
+        let handle = Handle::new(TestObject {
+            refs: RefCount::new(),
+            dropped: &DROPPED,
+        });
+        let from_ref = unsafe { Handle::from_ref(&*handle) };
+        let raw = Handle::into_raw(handle);
+        ktassert_eq!(Handle::ref_count(&from_ref), 2);
+
+        drop(unsafe { Handle::from_raw(raw) });
+        ktassert!(!DROPPED.load(Ordering::Relaxed));
+        drop(from_ref);
+        ktassert!(DROPPED.load(Ordering::Relaxed));
+    }
+
+    struct Slot {
+        refs: RefCount,
+        index: usize,
+    }
+
+    kobject!(Slot, refs, "test-slot");
+
+    #[ktest::test]
+    fn test_allocate_many() {
+        let handles = (0..64)
+            .map(|index| {
+                Handle::new(Slot {
+                    refs: RefCount::new(),
+                    index,
+                })
+            })
+            .collect::<Vec<_>>();
+        for (index, handle) in handles.iter().enumerate() {
+            ktassert_eq!(handle.index, index);
+            ktassert_eq!(Handle::ref_count(handle), 1);
+        }
+        ktassert!(!handles
+            .iter()
+            .zip(handles.iter().skip(1))
+            .any(|(a, b)| Handle::ptr_eq(a, b)));
+    }
+
+    #[ktest::test]
+    fn test_lookup_through_work_item_argument() {
+        /// Work item function that looks its object back up from `arg`
+        fn lookup(arg: usize) -> Handle<Slot> {
+            // SAFETY: `arg` came from `Handle::into_raw`, and is only reclaimed once
+            unsafe { Handle::from_raw(arg as *const Slot) }
+        }
+
+        let handle = Handle::new(Slot {
+            refs: RefCount::new(),
+            index: 7,
+        });
+        let args = [handle.clone(), handle.clone()].map(|h| Handle::into_raw(h) as usize);
+        ktassert_eq!(Handle::ref_count(&handle), 3);
+
+        for arg in args {
+            let found = lookup(arg);
+            ktassert!(Handle::ptr_eq(&found, &handle));
+            ktassert_eq!(found.index, 7);
+        }
+        ktassert_eq!(Handle::ref_count(&handle), 1);
+    }
+
+    #[cfg(feature = "object-accounting")]
+    #[ktest::test]
+    fn test_accounting() {
+        static DROPPED: AtomicBool = AtomicBool::new(false);
+        let ty = TestObject::object_type();
+        let (live, created) = (ty.live(), ty.created());
+
+        let handle = Handle::new(TestObject {
+            refs: RefCount::new(),
+            dropped: &DROPPED,
+        });
+        ktassert_eq!(ty.live(), live + 1);
+        ktassert_eq!(ty.created(), created + 1);
+        ktassert!(TYPES.iter().any(|t| core::ptr::eq(t, ty)));
+
+        drop(handle);
+        ktassert_eq!(ty.live(), live);
+    }
+}
@@ -7,6 +7,7 @@
 #![feature(int_roundings)]
 #![feature(maybe_uninit_uninit_array)]
 #![feature(negative_impls)]
+#![feature(thread_local)]
 
 extern crate alloc;
 extern crate ktest;
@@ -16,19 +17,60 @@ use core::fmt::Write;
 use platypos_hal::interrupts::Controller;
 
 use arch::mm::MemoryAccess;
+use boot_phase::InterruptsReady;
 use console::Console;
-use mm::root_allocator::Allocator;
+use mm::root_allocator::{Allocator, Owner, Subsystem};
 
 use crate::arch::display::Display;
 
 mod arch;
 
+mod boot_env;
+mod boot_phase;
+mod boot_time;
+mod build_id;
 mod console;
+mod crash_dump;
+mod debug_keys;
+mod drivers;
+mod earlycon;
 mod error;
+mod fault;
+#[cfg(test)]
+mod fixture_tests;
+mod fmt;
+mod initrd;
+mod interrupt_stats;
+// Only tests allocate kernel objects so far
+#[cfg_attr(not(test), allow(dead_code))]
+mod kobject;
+mod milestone;
 mod mm;
 mod panic;
+mod power;
 mod prelude;
+mod profiler;
+mod resources;
+mod sched;
+mod screen;
+mod smp;
 mod trace;
+mod workqueue;
+
+/// Name of the boot profile (set of features) the kernel was built with. This is
+/// set by `cargo xtask`, so it's `custom` for other builds.
+pub const PROFILE: &str = match option_env!("PLATYPOS_PROFILE") {
+    Some(profile) => profile,
+    None => "custom",
+};
+
+/// fw_cfg blob with a comma-separated list of tests to run
+#[cfg(test)]
+const TEST_FILTER: &str = "opt/platypos/test-filter";
+
+/// fw_cfg blob with the shard of tests to run, as `INDEX/COUNT`
+#[cfg(test)]
+const TEST_SHARD: &str = "opt/platypos/test-shard";
 
 /// Arguments passed from the platform-specific initialization code to
 /// [`kmain`].
@@ -43,6 +85,9 @@ pub struct BootArgs {
     pub root_allocator: &'static Allocator<'static>,
 
     pub interrupt_controller: &'static arch::hal_impl::interrupts::Controller,
+
+    /// Proof that platform-specific initialization finished
+    pub phase: InterruptsReady,
 }
 
 /// The shared kernel entry point.
@@ -52,23 +97,40 @@ pub fn kmain(mut args: BootArgs) -> ! {
 
     #[cfg(test)]
     {
-        ktest::run_tests();
-        trace::flush();
+        mm::leak_check::configure(args.root_allocator);
+
+        // `cargo xtask test --filter` passes the filter through fw_cfg, so that changing it
+        // doesn't need a rebuild. `--shards` passes each VM its shard the same way.
+        let filter = drivers::fw_cfg::read(TEST_FILTER).unwrap_or_default();
+        let filter = core::str::from_utf8(&filter).unwrap_or("");
+        let shard = drivers::fw_cfg::read(TEST_SHARD).ok().map(|shard| {
+            core::str::from_utf8(&shard)
+                .ok()
+                .and_then(ktest::Shard::parse)
+                .expect("malformed test shard")
+        });
+        match shard {
+            Some(shard) => ktest::run_shard(filter, shard),
+            None => ktest::run_filtered_tests(filter),
+        }
     }
 
-    let display = args.display.unwrap();
-    let mut console = Console::new(display);
+    // SAFETY: this is the only console
+    let mut console = unsafe { Console::new(args.display, args.memory_access) }
+        .expect("Could not create the console");
     console.clear().unwrap();
 
     let _ = writeln!(
         &mut console,
-        "Hello from PlatypOS v{}",
-        env!("CARGO_PKG_VERSION")
+        "Hello from PlatypOS v{} ({} profile)",
+        env!("CARGO_PKG_VERSION"),
+        PROFILE
     );
-
-    loop {
-        args.interrupt_controller.wait();
+    if let Ok(motd) = initrd::open("/etc/motd") {
+        let _ = console.write_str(core::str::from_utf8(motd).unwrap_or_default());
     }
+
+    sched::run(args.interrupt_controller, &args.phase);
 }
 
 #[inline(always)]
@@ -77,8 +139,12 @@ fn test_inline() {
 }
 
 fn test_alloc(allocator: &Allocator) {
-    let small_allocation = allocator.allocate(4).unwrap();
-    let big_allocation = allocator.allocate(1000).unwrap();
+    let small_allocation = allocator
+        .allocate_for(4, Owner::new(Subsystem::Test))
+        .unwrap();
+    let big_allocation = allocator
+        .allocate_for(1000, Owner::new(Subsystem::Test))
+        .unwrap();
     tracing::info!(
         "Small allocation: {}\nBig allocation: {}",
         small_allocation,
@@ -86,6 +152,7 @@ fn test_alloc(allocator: &Allocator) {
     );
 
     allocator.dump_state();
+    allocator.dump_owners();
 
     allocator.deallocate(big_allocation).unwrap();
     assert!(
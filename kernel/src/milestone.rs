@@ -0,0 +1,34 @@
+//! Boot milestones.
+//!
+//! Milestones are trace events marking progress through boot. `cargo xtask
+//! test` checks that the kernel reaches them in order, so that a boot
+//! regression fails at the first milestone it breaks rather than timing out.
+
+use platypos_ktrace::proto::MILESTONE_TARGET;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Milestone {
+    /// Physical memory and the heap are set up, and the kernel image is
+    /// protected
+    MemoryInitComplete,
+    /// Every processor that's going to be started is online
+    ApsOnline,
+    /// The scheduler is running threads
+    SchedulerStarted,
+}
+
+impl Milestone {
+    /// Name of the milestone, as `cargo xtask test --milestones` expects it
+    pub const fn name(self) -> &'static str {
+        match self {
+            Milestone::MemoryInitComplete => "memory-init-complete",
+            Milestone::ApsOnline => "aps-online",
+            Milestone::SchedulerStarted => "scheduler-started",
+        }
+    }
+}
+
+/// Record that the kernel reached `milestone`
+pub fn reached(milestone: Milestone) {
+    tracing::info!(target: MILESTONE_TARGET, "{}", milestone.name());
+}
@@ -3,6 +3,8 @@
 use core::fmt;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 
+use crate::prelude::kassert;
+
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct PhysicalAddress(usize);
@@ -246,7 +248,7 @@ impl<A: Address> AddressRange<A> {
     }
 
     pub fn new(start: A, end: A) -> Self {
-        assert!(end >= start);
+        kassert!(end >= start);
         Self {
             start,
             size: end - start,
@@ -350,6 +352,17 @@ mod tests {
         ktassert_eq!(addr, PhysicalAddress::new(7168));
     }
 
+    #[ktest::test]
+    #[case(zero, 0x0, 0, true)]
+    #[case(first_page_end, 0xfff, 0, false)]
+    #[case(page_start, 0x2000, 2, true)]
+    #[case(mid_page, 0x2abc, 2, false)]
+    fn test_page_containing(address: usize, page: usize, is_start: bool) {
+        let address = VirtualAddress::new(address);
+        ktassert_eq!(Page::containing(address), Page::new(page));
+        ktassert_eq!(Page::from_start(address).is_ok(), is_start);
+    }
+
     #[ktest::test]
     fn test_format() {
         ktassert_eq!(
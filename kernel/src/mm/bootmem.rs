@@ -0,0 +1,235 @@
+//! Early boot memory allocator.
+//!
+//! Some initialization needs physical memory before the
+//! [root allocator](super::root_allocator) is set up. Boot memory is a bump
+//! allocator seeded directly from the loader's memory map: it hands out page
+//! frames from the start of each usable region in turn, and never frees them.
+//!
+//! Once the root allocator is ready to take over, [`handoff`] seals boot
+//! memory and returns every block it handed out. The root allocator records
+//! those blocks as allocated, so they're neither handed out a second time nor
+//! lost: whoever owns one can give it back to the root allocator like any
+//! other allocation. Frames skipped to satisfy alignment were never handed
+//! out, so the root allocator treats them as free.
+
+use crate::prelude::*;
+
+use super::map::Region;
+
+/// Maximum number of usable regions boot memory can allocate from
+const MAX_REGIONS: usize = 32;
+
+/// Maximum number of separate blocks boot memory can hand out. Adjacent
+/// allocations are merged, so this is rarely the limit.
+const MAX_BLOCKS: usize = 32;
+
+static BOOTMEM: spin::Mutex<BootMem> = spin::Mutex::new(BootMem::new());
+
+/// Bump allocator over the usable regions of a memory map
+pub struct BootMem {
+    /// What's left of each usable region, in address order
+    free: [PageFrameRange; MAX_REGIONS],
+    free_len: usize,
+    /// Blocks handed out so far, in allocation order
+    used: [PageFrameRange; MAX_BLOCKS],
+    used_len: usize,
+    /// Set once the root allocator has taken over
+    sealed: bool,
+}
+
+/// Page frames handed out by boot memory, for the root allocator to account
+/// for
+#[derive(Debug, Clone)]
+pub struct Handoff {
+    used: [PageFrameRange; MAX_BLOCKS],
+    used_len: usize,
+}
+
+impl BootMem {
+    pub const fn new() -> Self {
+        Self {
+            free: [PageFrameRange::empty(); MAX_REGIONS],
+            free_len: 0,
+            used: [PageFrameRange::empty(); MAX_BLOCKS],
+            used_len: 0,
+            sealed: false,
+        }
+    }
+
+    /// Add the usable parts of `regions` to allocate from. Partial page
+    /// frames at either end of a region are left out.
+    ///
+    /// # Errors
+    /// * [`MmError::TooManyRegions`] if there are more usable regions than
+    ///   boot memory can track. The regions added before that are still
+    ///   usable.
+    pub fn seed(&mut self, regions: &[Region]) -> Result<(), Error> {
+        for region in regions.iter().filter(|r| r.usable()) {
+            let start = PageFrame::containing(region.start() + (PAGE_SIZE - 1));
+            let end = PageFrame::containing(region.end());
+            if end <= start {
+                continue;
+            }
+            kensure!(
+                self.free_len < MAX_REGIONS,
+                MmError::TooManyRegions,
+                "seeding boot memory"
+            );
+            self.free[self.free_len] = PageFrameRange::new(start, end);
+            self.free_len += 1;
+        }
+        Ok(())
+    }
+
+    /// Allocate `count` contiguous page frames, starting at a multiple of
+    /// `align` page frames.
+    ///
+    /// # Errors
+    /// * [`MmError::InsufficientMemory`] if no region has room, or if boot
+    ///   memory has already been handed off
+    /// * [`MmError::TooManyRegions`] if boot memory can't track another block
+    pub fn allocate(&mut self, count: usize, align: usize) -> Result<PageFrameRange, Error> {
+        kensure!(
+            !self.sealed,
+            MmError::InsufficientMemory,
+            "boot memory was handed off to the root allocator"
+        );
+
+        let (free, range) = self.free[..self.free_len]
+            .iter_mut()
+            .find_map(|free| {
+                let start = PageFrame::new(free.start().as_usize().next_multiple_of(align));
+                let range = PageFrameRange::from_start_size(start, count);
+                (range.end() <= free.end()).then_some((free, range))
+            })
+            .ok_or_else(|| {
+                tracing::warn!(count, "Out of boot memory (aligned to {align} frames)");
+                Error::new(MmError::InsufficientMemory)
+            })?;
+
+        let merged = match self.used[..self.used_len].last_mut() {
+            Some(last) if last.end() == range.start() => {
+                last.extend_right(count);
+                true
+            }
+            _ => false,
+        };
+        if !merged {
+            kensure!(
+                self.used_len < MAX_BLOCKS,
+                MmError::TooManyRegions,
+                "recording boot memory block"
+            );
+            self.used[self.used_len] = range;
+            self.used_len += 1;
+        }
+        *free = PageFrameRange::new(range.end(), free.end());
+
+        tracing::trace!(%range, "Allocated boot memory");
+        Ok(range)
+    }
+
+    /// Stop allocating, and return everything handed out so far
+    pub fn handoff(&mut self) -> Handoff {
+        self.sealed = true;
+        Handoff {
+            used: self.used,
+            used_len: self.used_len,
+        }
+    }
+}
+
+impl Default for BootMem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handoff {
+    /// Blocks that were handed out, which the root allocator must not reuse
+    pub fn used(&self) -> &[PageFrameRange] {
+        &self.used[..self.used_len]
+    }
+
+    /// Total page frames handed out
+    pub fn used_pages(&self) -> usize {
+        self.used().iter().map(|r| r.size()).sum()
+    }
+}
+
+/// Seed boot memory from the loader's memory map. Allocation fails until this
+/// is called.
+pub fn init(regions: &[Region]) {
+    if let Err(err) = BOOTMEM.lock().seed(regions) {
+        tracing::warn!("Boot memory is missing some regions: {}", err);
+    }
+}
+
+/// Allocate `count` contiguous page frames before the root allocator is up.
+/// See [`BootMem::allocate`].
+pub fn allocate(count: usize, align: usize) -> Result<PageFrameRange, Error> {
+    BOOTMEM.lock().allocate(count, align)
+}
+
+/// Seal boot memory, returning what it handed out for the root allocator to
+/// take over. Allocating from boot memory fails after this.
+pub fn handoff() -> Handoff {
+    let handoff = BOOTMEM.lock().handoff();
+    tracing::debug!(
+        "Handing off {} of boot memory in {} blocks",
+        (handoff.used_pages() * PAGE_SIZE).as_size(),
+        handoff.used().len()
+    );
+    handoff
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+    use crate::mm::map::Kind;
+
+    fn region(kind: Kind, start: usize, end: usize) -> Region {
+        Region::new(kind, PhysicalAddress::new(start), PhysicalAddress::new(end))
+    }
+
+    fn frames(start: usize, end: usize) -> PageFrameRange {
+        PageFrameRange::new(PageFrame::new(start), PageFrame::new(end))
+    }
+
+    #[ktest::test]
+    fn test_bump_and_handoff() {
+        let mut bootmem = BootMem::new();
+        ktassert!(bootmem
+            .seed(&[
+                // Partial page frames are left out
+                region(Kind::Usable, 0x800, 0x4000),
+                region(Kind::Reserved, 0x4000, 0x10000),
+                region(Kind::Usable, 0x10000, 0x20000),
+            ])
+            .is_ok());
+
+        ktassert_eq!(bootmem.allocate(2, 1).unwrap(), frames(1, 3));
+        ktassert_eq!(bootmem.allocate(1, 1).unwrap(), frames(3, 4));
+        // The first region is used up, and alignment skips some frames in the second
+        ktassert_eq!(bootmem.allocate(2, 2).unwrap(), frames(0x10, 0x12));
+        ktassert_eq!(bootmem.allocate(4, 8).unwrap(), frames(0x18, 0x1c));
+        ktassert!(bootmem.allocate(8, 1).is_err());
+
+        let handoff = bootmem.handoff();
+        ktassert_eq!(
+            handoff.used(),
+            &[frames(1, 4), frames(0x10, 0x12), frames(0x18, 0x1c)]
+        );
+        ktassert_eq!(handoff.used_pages(), 9);
+        ktassert!(bootmem.allocate(1, 1).is_err());
+    }
+
+    #[ktest::test]
+    fn test_sealed_after_boot() {
+        // The root allocator took over before tests run
+        let err = allocate(1, 1).unwrap_err();
+        ktassert_eq!(err.kind(), KError::Mm(MmError::InsufficientMemory));
+    }
+}
@@ -1,9 +1,15 @@
 //! The kernel heap allocator. This is the global allocator that the Rust
 //! `alloc` crate expects.
+//!
+//! When an allocation fails, the heap reports critical
+//! [memory pressure](super::pressure) and retries once, so that caches get a
+//! chance to shrink before the kernel gives up and panics.
 
 use core::alloc::GlobalAlloc;
 use core::mem::MaybeUninit;
 
+use crate::boot_phase::{self, Entry, HeapReady, MemoryReady};
+use crate::mm::pressure;
 use crate::mm::root_allocator::Allocator as RootAllocator;
 use crate::prelude::*;
 use platypos_common::sync::Global;
@@ -26,15 +32,22 @@ static mut BUF: [MaybeUninit<u8>; 32768] = MaybeUninit::uninit_array();
 /// Bootstrap the kernel keap allocator.
 ///
 /// # Safety
-/// This must be called exactly once, and before any allocations are made
-pub unsafe fn init() {
+/// This must be called before any allocations are made
+pub unsafe fn init(entry: Entry) -> HeapReady {
     KERNEL_HEAP.inner.lock().init_from_slice(&mut BUF);
+    boot_phase::advance(entry)
 }
 
 /// Provide the root memory allocator after it's been initialized, enabling the
 /// heap to grow.
-pub fn enable_expansion(root: &'static RootAllocator<'static>) {
+pub fn enable_expansion(root: &'static RootAllocator<'static>, heap: HeapReady) -> MemoryReady {
     KERNEL_HEAP.root.init(root);
+    boot_phase::advance(heap)
+}
+
+/// Number of bytes currently allocated from the heap
+pub fn used_bytes() -> usize {
+    KERNEL_HEAP.inner.lock().used()
 }
 
 impl KernelHeapAllocator {
@@ -50,7 +63,10 @@ impl KernelHeapAllocator {
 unsafe impl GlobalAlloc for KernelHeapAllocator {
     #[tracing::instrument(level = "trace", skip_all, fields(size = layout.size()))]
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let res = self.inner.alloc(layout);
+        let mut res = self.inner.alloc(layout);
+        if res.is_null() && pressure::reclaim() {
+            res = self.inner.alloc(layout);
+        }
         if res.is_null() {
             tracing::warn!("allocation failed");
         } else {
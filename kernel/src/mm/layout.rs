@@ -0,0 +1,187 @@
+//! Kernel virtual address space layout.
+//!
+//! The kernel's part of the address space is split into named [`Region`]s with
+//! fixed, architecture-defined bounds, so that different kinds of mappings
+//! can't collide. Code that needs virtual address space for a new mapping
+//! calls [`reserve`] to carve out a sub-range of the appropriate region.
+//!
+//! Reservations are never released. Every region is far larger than the
+//! kernel will ever need, so this is simpler than tracking free ranges.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::prelude::*;
+
+/// A region of the kernel's virtual address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// Window where all physical memory is mapped, used by
+    /// [`MemoryAccess`](crate::arch::mm::MemoryAccess)
+    PhysicalMap,
+    /// Memory-mapped device registers
+    Mmio,
+    /// Kernel heap
+    Heap,
+    /// Per-processor data areas
+    PerProcessor,
+    /// Kernel thread and interrupt stacks
+    Stacks,
+}
+
+impl Region {
+    pub const ALL: [Region; 5] = [
+        Region::PhysicalMap,
+        Region::Mmio,
+        Region::Heap,
+        Region::PerProcessor,
+        Region::Stacks,
+    ];
+
+    /// The full range of addresses in this region
+    pub const fn bounds(self) -> VirtualAddressRange {
+        crate::arch::mm::region_bounds(self)
+    }
+
+    /// Find the region containing `address`, if any
+    pub fn containing(address: VirtualAddress) -> Option<Region> {
+        let range = VirtualAddressRange::from_start_size(address, 1);
+        Region::ALL
+            .into_iter()
+            .find(|region| region.bounds().contains(&range))
+    }
+
+    fn arena(self) -> &'static Arena {
+        &ARENAS[self as usize]
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::PhysicalMap => f.write_str("physical memory map"),
+            Region::Mmio => f.write_str("MMIO"),
+            Region::Heap => f.write_str("heap"),
+            Region::PerProcessor => f.write_str("per-processor data"),
+            Region::Stacks => f.write_str("stacks"),
+        }
+    }
+}
+
+static ARENAS: [Arena; Region::ALL.len()] = [
+    Arena::new(Region::PhysicalMap.bounds()),
+    Arena::new(Region::Mmio.bounds()),
+    Arena::new(Region::Heap.bounds()),
+    Arena::new(Region::PerProcessor.bounds()),
+    Arena::new(Region::Stacks.bounds()),
+];
+
+/// Reserve `size` bytes of address space in `region`, aligned to `align`
+/// bytes. Both are rounded up to a multiple of the page size, so reservations
+/// never share a page.
+///
+/// # Errors
+/// [`MmError::InsufficientMemory`] if the region doesn't have enough space
+/// left.
+pub fn reserve(region: Region, size: usize, align: usize) -> Result<VirtualAddressRange, Error> {
+    let range = region.arena().reserve(size, align)?;
+    tracing::debug!("Reserved {} in the {} region", range, region);
+    Ok(range)
+}
+
+/// Assert (in debug builds) that `range` is entirely inside `region`. Code
+/// that creates kernel mappings should call this with the mapped range.
+#[track_caller]
+pub fn debug_assert_in_region(region: Region, range: VirtualAddressRange) {
+    debug_assert!(
+        region.bounds().contains(&range),
+        "mapping {} is outside the {} region ({})",
+        range,
+        region,
+        region.bounds()
+    );
+}
+
+/// Log the kernel address space layout
+pub fn log_layout() {
+    tracing::info!("Kernel address space layout:");
+    for region in Region::ALL {
+        let bounds = region.bounds();
+        tracing::info!(" - {} {} ({})", bounds, region, bounds.size().as_size());
+    }
+}
+
+/// Bump allocator for one region's address space
+struct Arena {
+    bounds: VirtualAddressRange,
+    /// Offset of the next unreserved byte from the start of the region
+    next: AtomicUsize,
+}
+
+impl Arena {
+    const fn new(bounds: VirtualAddressRange) -> Self {
+        Self {
+            bounds,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn reserve(&self, size: usize, align: usize) -> Result<VirtualAddressRange, Error> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let align = align.max(PAGE_SIZE).next_power_of_two();
+        let base = self.bounds.start().as_usize();
+
+        let mut start = 0;
+        self.next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                start = (base + next).checked_add(align - 1)? & !(align - 1);
+                start -= base;
+                let end = start.checked_add(size)?;
+                (end <= self.bounds.size()).then_some(end)
+            })
+            .map_err(|_| Error::new(MmError::InsufficientMemory))?;
+
+        Ok(VirtualAddressRange::from_start_size(
+            self.bounds.start() + start,
+            size,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_regions_disjoint() {
+        for (i, a) in Region::ALL.iter().enumerate() {
+            for b in &Region::ALL[i + 1..] {
+                let (a, b) = (a.bounds(), b.bounds());
+                ktassert!(a.end() <= b.start() || b.end() <= a.start());
+            }
+        }
+    }
+
+    #[ktest::test]
+    fn test_reserve_aligned() {
+        let arena = Arena::new(VirtualAddressRange::from_start_size(
+            VirtualAddress::new(0x10_0000),
+            16 * PAGE_SIZE,
+        ));
+
+        let first = arena.reserve(100, 1).unwrap();
+        ktassert_eq!(first.start(), VirtualAddress::new(0x10_0000));
+        ktassert_eq!(first.size(), PAGE_SIZE);
+
+        let second = arena.reserve(PAGE_SIZE, 4 * PAGE_SIZE).unwrap();
+        ktassert_eq!(
+            second.start(),
+            VirtualAddress::new(0x10_0000 + 4 * PAGE_SIZE)
+        );
+
+        ktassert!(arena.reserve(12 * PAGE_SIZE, 1).is_err());
+        ktassert!(arena.reserve(11 * PAGE_SIZE, 1).is_ok());
+    }
+}
@@ -0,0 +1,58 @@
+//! Memory usage for the test runner's leak checks.
+//!
+//! `cargo xtask test --leak-threshold BYTES` passes a threshold through
+//! fw_cfg, and tests that leave more than that much allocated, from either the
+//! heap or the frame allocator, fail. With the `frame-owners` feature, the
+//! failure report lists which subsystems' page frames grew.
+
+use alloc::boxed::Box;
+
+use ktest::{LeakCheck, MemoryProbe, MemoryUsage};
+
+use crate::drivers::fw_cfg;
+use crate::mm::heap_allocator;
+use crate::mm::root_allocator::Allocator;
+use crate::prelude::*;
+
+/// fw_cfg blob with the leak threshold, in bytes
+const LEAK_THRESHOLD_BLOB: &str = "opt/platypos/test-leak-threshold";
+
+struct KernelMemory(&'static Allocator<'static>);
+
+impl MemoryProbe for KernelMemory {
+    fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            heap_bytes: heap_allocator::used_bytes() as u64,
+            frames: self.0.allocated_pages() as u64,
+        }
+    }
+
+    fn owners(&self, visit: &mut dyn FnMut(&'static str, u64)) {
+        self.0
+            .pages_by_subsystem(|subsystem, pages| visit(subsystem.name(), pages as u64));
+    }
+}
+
+/// Turn on leak checks if a threshold was passed through fw_cfg. This must be
+/// called after [`fw_cfg::init`].
+pub fn configure(root_allocator: &'static Allocator<'static>) {
+    let Ok(blob) = fw_cfg::read(LEAK_THRESHOLD_BLOB) else {
+        return;
+    };
+    let Some(threshold) = core::str::from_utf8(&blob)
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+    else {
+        tracing::warn!("Ignoring malformed leak threshold");
+        return;
+    };
+
+    tracing::info!("Checking tests for leaks of more than {threshold} bytes");
+    ktest::check_leaks(Box::leak(Box::new(LeakCheck {
+        probe: Box::leak(Box::new(KernelMemory(root_allocator))),
+        threshold: MemoryUsage {
+            heap_bytes: threshold,
+            frames: threshold.div_ceil(PAGE_SIZE as u64),
+        },
+    })));
+}
@@ -2,8 +2,13 @@
 
 use core::fmt;
 
+use crate::fault;
 use crate::prelude::*;
 
+mod diff;
+
+pub use diff::{diff, sanity_check, Discrepancy, Side};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
 pub enum Kind {
@@ -28,10 +33,42 @@ pub enum Kind {
     Bios(BiosMemoryKind),
 }
 
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Usable => f.write_str("Usable"),
+            Kind::Reserved => f.write_str("Reserved"),
+            Kind::AcpiTables => f.write_str("ACPI tables"),
+            Kind::AcpiNonVolatile => f.write_str("ACPI NVS"),
+            Kind::Persistent => f.write_str("Persistent memory"),
+            Kind::Bios(v) => write!(f, "Unknown BIOS type {v}"),
+            Kind::Uefi(v) => match v {
+                // See https://uefi.org/specs/ACPI/6.4/15_System_Address_Map_Interfaces/uefi-getmemorymap-boot-services-function.html
+                0 => write!(f, "UEFI Reserved"),
+                1 => write!(f, "UEFI Loader (Code)"),
+                2 => write!(f, "UEFI Loader (Data)"),
+                3 => write!(f, "UEFI Boot Services (Code)"),
+                4 => write!(f, "UEFI Boot Services (Data)"),
+                5 => write!(f, "UEFI Runtime Services (Code)"),
+                6 => write!(f, "UEFI Runtime Services (Data)"),
+                7 => write!(f, "Conventional memory"),
+                8 => write!(f, "Unusable"),
+                9 => write!(f, "ACPI (reclaimable)"),
+                10 => write!(f, "ACPI NVS"),
+                11 => write!(f, "Memory-Mapped I/O"),
+                12 => write!(f, "Memory-Mapped I/O Port Space"),
+                13 => write!(f, "UEFI PAL Code"),
+                14 => write!(f, "Persistent memory"),
+                other => write!(f, "Unknown UEFI type {other}"),
+            },
+        }
+    }
+}
+
 pub type UefiMemoryKind = u32;
 pub type BiosMemoryKind = u32;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Region {
     kind: Kind,
     start: PhysicalAddress,
@@ -76,7 +113,7 @@ impl fmt::Display for Region {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{} - {} {:?} ({})",
+            "{} - {} {} ({})",
             self.start,
             self.end,
             self.kind,
@@ -84,3 +121,162 @@ impl fmt::Display for Region {
         )
     }
 }
+
+/// Builds a memory map from loader-provided regions, without allocating.
+///
+/// Regions are kept sorted by starting address. Adjacent or overlapping
+/// regions of the same kind are merged as they're inserted, since loaders
+/// often split up functionally-equivalent memory.
+pub struct MemoryMapBuilder<const N: usize> {
+    regions: [Region; N],
+    len: usize,
+}
+
+impl<const N: usize> MemoryMapBuilder<N> {
+    const CAPACITY_CHECK: () = assert!(N > 0, "memory map capacity must be nonzero");
+
+    const EMPTY: Region = Region::new(
+        Kind::Reserved,
+        PhysicalAddress::new(0),
+        PhysicalAddress::new(0),
+    );
+
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::CAPACITY_CHECK;
+        Self {
+            regions: [Self::EMPTY; N],
+            len: 0,
+        }
+    }
+
+    /// The regions added so far, sorted by address
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+
+    /// Add `region` to the map, merging it with neighboring regions of the
+    /// same kind. Empty regions are ignored.
+    ///
+    /// # Errors
+    /// * [`MmError::TooManyRegions`] if the map is full
+    /// * [`MmError::InvalidAddress`] if `region` overlaps a region of a
+    ///   different kind
+    pub fn try_push(&mut self, region: Region) -> Result<(), Error> {
+        if region.start >= region.end {
+            return Ok(());
+        }
+        if fault::fault_point!("mm.map.push") {
+            return Err(Error::new(MmError::TooManyRegions).context("injected fault"));
+        }
+
+        // Index of the first region starting after `region`
+        let idx = self.regions().partition_point(|r| r.start <= region.start);
+
+        let overlaps_prev = idx > 0
+            && self.regions[idx - 1].end > region.start
+            && self.regions[idx - 1].kind != region.kind;
+        let overlaps_next = self.regions()[idx..]
+            .iter()
+            .take_while(|r| r.start < region.end)
+            .any(|r| r.kind != region.kind);
+        kensure!(
+            !overlaps_prev && !overlaps_next,
+            MmError::InvalidAddress,
+            "region overlaps one of a different kind"
+        );
+
+        let merge_prev = idx > 0
+            && self.regions[idx - 1].kind == region.kind
+            && self.regions[idx - 1].end >= region.start;
+
+        let merged = if merge_prev {
+            let prev = &mut self.regions[idx - 1];
+            prev.end = prev.end.max(region.end);
+            idx - 1
+        } else {
+            kensure!(self.len < N, MmError::TooManyRegions);
+            self.regions.copy_within(idx..self.len, idx + 1);
+            self.regions[idx] = region;
+            self.len += 1;
+            idx
+        };
+
+        // The grown region may now reach into the ones after it
+        while merged + 1 < self.len {
+            let next = self.regions[merged + 1];
+            let current = &mut self.regions[merged];
+            if next.start > current.end || next.kind != current.kind {
+                break;
+            }
+            current.end = current.end.max(next.end);
+            self.regions.copy_within(merged + 2..self.len, merged + 1);
+            self.len -= 1;
+        }
+
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for MemoryMapBuilder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    fn region(kind: Kind, start: usize, end: usize) -> Region {
+        Region::new(kind, PhysicalAddress::new(start), PhysicalAddress::new(end))
+    }
+
+    #[ktest::test]
+    fn test_builder_sorts_and_merges() {
+        let mut builder = MemoryMapBuilder::<4>::new();
+        ktassert!(builder
+            .try_push(region(Kind::Usable, 0x3000, 0x4000))
+            .is_ok());
+        ktassert!(builder
+            .try_push(region(Kind::Reserved, 0x0, 0x1000))
+            .is_ok());
+        ktassert!(builder
+            .try_push(region(Kind::Usable, 0x1000, 0x2000))
+            .is_ok());
+        // Fills the gap, so all the usable regions merge
+        ktassert!(builder
+            .try_push(region(Kind::Usable, 0x2000, 0x3000))
+            .is_ok());
+
+        ktassert_eq!(
+            builder.regions(),
+            &[
+                region(Kind::Reserved, 0x0, 0x1000),
+                region(Kind::Usable, 0x1000, 0x4000)
+            ]
+        );
+    }
+
+    #[ktest::test]
+    fn test_builder_errors() {
+        let mut builder = MemoryMapBuilder::<1>::new();
+        ktassert!(builder
+            .try_push(region(Kind::Usable, 0x1000, 0x2000))
+            .is_ok());
+        ktassert_eq!(
+            builder
+                .try_push(region(Kind::Reserved, 0x1800, 0x2800))
+                .map_err(|e| e.kind()),
+            Err(KError::Mm(MmError::InvalidAddress))
+        );
+        ktassert_eq!(
+            builder
+                .try_push(region(Kind::Reserved, 0x4000, 0x5000))
+                .map_err(|e| e.kind()),
+            Err(KError::Mm(MmError::TooManyRegions))
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! Comparing memory maps.
+//!
+//! The kernel builds its own memory map from the one the loader hands off,
+//! merging regions and dropping ones it can't make sense of along the way. If
+//! the two ever disagree about what some memory is, the kernel could hand out
+//! memory the loader or firmware still owns, so [`sanity_check`] compares them
+//! at boot and warns about every [`Discrepancy`].
+
+use alloc::vec::Vec;
+
+use super::{Kind, Region};
+use crate::prelude::*;
+
+/// Which of the two maps passed to [`diff`] something refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// A way two memory maps disagree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// Two regions in the same map overlap
+    Overlap {
+        side: Side,
+        first: Region,
+        second: Region,
+    },
+    /// The maps describe `range` as different kinds of memory
+    KindMismatch {
+        range: PhysicalAddressRange,
+        left: Kind,
+        right: Kind,
+    },
+    /// Only one map describes `range`, as `kind` memory
+    Gap {
+        range: PhysicalAddressRange,
+        missing_from: Side,
+        kind: Kind,
+    },
+}
+
+impl Discrepancy {
+    /// Combine this discrepancy with `next`, if it's the same difference over
+    /// the range right after this one
+    fn merge(&self, next: &Discrepancy) -> Option<Discrepancy> {
+        let extend = |a: &PhysicalAddressRange, b: &PhysicalAddressRange| {
+            (a.end() == b.start()).then(|| PhysicalAddressRange::new(a.start(), b.end()))
+        };
+        match (*self, *next) {
+            (
+                Discrepancy::KindMismatch { range, left, right },
+                Discrepancy::KindMismatch {
+                    range: next_range,
+                    left: next_left,
+                    right: next_right,
+                },
+            ) if (left, right) == (next_left, next_right) => Some(Discrepancy::KindMismatch {
+                range: extend(&range, &next_range)?,
+                left,
+                right,
+            }),
+            (
+                Discrepancy::Gap {
+                    range,
+                    missing_from,
+                    kind,
+                },
+                Discrepancy::Gap {
+                    range: next_range,
+                    missing_from: next_missing_from,
+                    kind: next_kind,
+                },
+            ) if (missing_from, kind) == (next_missing_from, next_kind) => Some(Discrepancy::Gap {
+                range: extend(&range, &next_range)?,
+                missing_from,
+                kind,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Find everywhere `left` and `right` disagree. Neither map needs to be
+/// sorted. Addresses that neither map describes aren't discrepancies.
+pub fn diff(left: &[Region], right: &[Region]) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+    find_overlaps(left, Side::Left, &mut discrepancies);
+    find_overlaps(right, Side::Right, &mut discrepancies);
+
+    // Between consecutive region boundaries, each map describes the memory as at most one kind
+    // (ignoring overlaps, which were reported above)
+    let mut bounds = left
+        .iter()
+        .chain(right)
+        .flat_map(|r| [r.start(), r.end()])
+        .collect::<Vec<_>>();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut pending: Option<Discrepancy> = None;
+    for window in bounds.windows(2) {
+        let range = PhysicalAddressRange::new(window[0], window[1]);
+        let current = match (kind_at(left, range.start()), kind_at(right, range.start())) {
+            (Some(left), Some(right)) if left != right => {
+                Some(Discrepancy::KindMismatch { range, left, right })
+            }
+            (Some(kind), None) => Some(Discrepancy::Gap {
+                range,
+                missing_from: Side::Right,
+                kind,
+            }),
+            (None, Some(kind)) => Some(Discrepancy::Gap {
+                range,
+                missing_from: Side::Left,
+                kind,
+            }),
+            _ => None,
+        };
+
+        pending = match (pending, current) {
+            (Some(prev), Some(current)) => match prev.merge(&current) {
+                Some(merged) => Some(merged),
+                None => {
+                    discrepancies.push(prev);
+                    Some(current)
+                }
+            },
+            (prev, current) => {
+                discrepancies.extend(prev);
+                current
+            }
+        };
+    }
+    discrepancies.extend(pending);
+
+    discrepancies
+}
+
+/// Kind of the first region in `regions` containing `address`
+fn kind_at(regions: &[Region], address: PhysicalAddress) -> Option<Kind> {
+    regions
+        .iter()
+        .find(|r| r.start() <= address && address < r.end())
+        .map(Region::kind)
+}
+
+fn find_overlaps(regions: &[Region], side: Side, discrepancies: &mut Vec<Discrepancy>) {
+    let mut sorted = regions
+        .iter()
+        .filter(|r| r.start() < r.end())
+        .copied()
+        .collect::<Vec<_>>();
+    sorted.sort_unstable_by_key(Region::start);
+
+    // A long region can overlap several after it, so compare against whichever reaches furthest
+    let mut furthest: Option<Region> = None;
+    for region in sorted {
+        match furthest {
+            Some(prev) if region.start() < prev.end() => {
+                discrepancies.push(Discrepancy::Overlap {
+                    side,
+                    first: prev,
+                    second: region,
+                });
+                if region.end() > prev.end() {
+                    furthest = Some(region);
+                }
+            }
+            _ => furthest = Some(region),
+        }
+    }
+}
+
+/// Compare the memory map the loader handed off with the one the kernel
+/// built from it, and log a warning for each discrepancy. Returns the number
+/// of discrepancies.
+pub fn sanity_check(loader: &[Region], kernel: &[Region]) -> usize {
+    let side = |side| match side {
+        Side::Left => "loader",
+        Side::Right => "kernel",
+    };
+
+    let discrepancies = diff(loader, kernel);
+    for discrepancy in discrepancies.iter() {
+        match *discrepancy {
+            Discrepancy::Overlap {
+                side: s,
+                first,
+                second,
+            } => tracing::warn!(
+                "Overlapping regions in the {} memory map: {first} and {second}",
+                side(s)
+            ),
+            Discrepancy::KindMismatch { range, left, right } => tracing::warn!(
+                "Memory at {range} is {left} to the loader but {right} to the kernel"
+            ),
+            Discrepancy::Gap {
+                range,
+                missing_from,
+                kind,
+            } => tracing::warn!(
+                "Memory at {range} ({kind}) is missing from the {} memory map",
+                side(missing_from)
+            ),
+        }
+    }
+    if discrepancies.is_empty() {
+        tracing::debug!("Kernel memory map matches the loader's");
+    }
+    discrepancies.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use ktest::*;
+
+    use super::*;
+
+    fn region(kind: Kind, start: usize, end: usize) -> Region {
+        Region::new(kind, PhysicalAddress::new(start), PhysicalAddress::new(end))
+    }
+
+    fn range(start: usize, end: usize) -> PhysicalAddressRange {
+        PhysicalAddressRange::new(PhysicalAddress::new(start), PhysicalAddress::new(end))
+    }
+
+    #[ktest::test]
+    fn test_equivalent_maps() {
+        // Same memory, split up differently and out of order
+        let loader = [
+            region(Kind::Usable, 0x2000, 0x3000),
+            region(Kind::Reserved, 0x0, 0x1000),
+            region(Kind::Usable, 0x1000, 0x2000),
+        ];
+        let kernel = [
+            region(Kind::Reserved, 0x0, 0x1000),
+            region(Kind::Usable, 0x1000, 0x3000),
+        ];
+        ktassert!(diff(&loader, &kernel).is_empty());
+    }
+
+    #[ktest::test]
+    fn test_mismatches_and_gaps() {
+        let loader = [
+            region(Kind::Usable, 0x0, 0x4000),
+            region(Kind::AcpiTables, 0x4000, 0x5000),
+        ];
+        let kernel = [
+            region(Kind::Usable, 0x0, 0x1000),
+            region(Kind::Reserved, 0x1000, 0x2000),
+            region(Kind::Reserved, 0x2000, 0x3000),
+            region(Kind::Usable, 0x3000, 0x4000),
+            region(Kind::Usable, 0x6000, 0x7000),
+        ];
+        ktassert_eq!(
+            diff(&loader, &kernel),
+            vec![
+                Discrepancy::KindMismatch {
+                    range: range(0x1000, 0x3000),
+                    left: Kind::Usable,
+                    right: Kind::Reserved,
+                },
+                Discrepancy::Gap {
+                    range: range(0x4000, 0x5000),
+                    missing_from: Side::Right,
+                    kind: Kind::AcpiTables,
+                },
+                Discrepancy::Gap {
+                    range: range(0x6000, 0x7000),
+                    missing_from: Side::Left,
+                    kind: Kind::Usable,
+                },
+            ]
+        );
+    }
+
+    #[ktest::test]
+    fn test_overlaps() {
+        let loader = [
+            region(Kind::Usable, 0x0, 0x4000),
+            region(Kind::Reserved, 0x1000, 0x2000),
+            region(Kind::Reserved, 0x3000, 0x5000),
+        ];
+        let overlaps = diff(&loader, &loader)
+            .into_iter()
+            .filter(|d| matches!(d, Discrepancy::Overlap { .. }))
+            .collect::<Vec<_>>();
+        ktassert_eq!(
+            overlaps,
+            vec![
+                Discrepancy::Overlap {
+                    side: Side::Left,
+                    first: loader[0],
+                    second: loader[1],
+                },
+                Discrepancy::Overlap {
+                    side: Side::Left,
+                    first: loader[0],
+                    second: loader[2],
+                },
+                Discrepancy::Overlap {
+                    side: Side::Right,
+                    first: loader[0],
+                    second: loader[1],
+                },
+                Discrepancy::Overlap {
+                    side: Side::Right,
+                    first: loader[0],
+                    second: loader[2],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,389 @@
+//! Memory pressure notifications.
+//!
+//! The root allocator compares its free page frames against two watermarks
+//! after every allocation and deallocation. When free memory drops below one,
+//! or recovers above it, every listener registered with
+//! [`pressure_listener!`] is told the new [`Pressure`] level, so subsystems
+//! that can give memory back or shed load get a chance to do so before
+//! allocations start failing. The heap also reports [`Pressure::Critical`]
+//! itself when an allocation fails, and retries once listeners have had their
+//! chance to free memory.
+//!
+//! Code with optional allocations can also check [`current`] and back off
+//! before the system runs out.
+//!
+//! Listeners run on whichever processor crossed the watermark, right after an
+//! allocation or deallocation and possibly with interrupts disabled, so they
+//! must be quick and mustn't allocate. Freeing memory is fine: listeners
+//! aren't re-entered for the changes they cause themselves, but hear about
+//! them once they all return. Each change is delivered once, even if it
+//! happens while another processor is notifying listeners.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use platypos_hal::linkme::distributed_slice;
+
+/// How short of memory the system is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Pressure {
+    /// Free memory is above the low watermark
+    None,
+    /// Free memory is below the low watermark. Listeners should trim caches
+    /// and stop doing optional work.
+    Low,
+    /// Free memory is below the critical watermark, or an allocation failed.
+    /// Listeners should give back everything they can.
+    Critical,
+}
+
+impl Pressure {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Pressure::None,
+            1 => Pressure::Low,
+            _ => Pressure::Critical,
+        }
+    }
+}
+
+/// Free page frame counts at which pressure is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    pub low: usize,
+    pub critical: usize,
+}
+
+impl Watermarks {
+    /// Default watermarks for a system with `usable` page frames: 1/16 of
+    /// memory for low pressure and 1/64 for critical pressure, but at least
+    /// 4 MiB and 1 MiB.
+    pub fn for_usable(usable: usize) -> Self {
+        Self {
+            low: (usable / 16).max(1024),
+            critical: (usable / 64).max(256),
+        }
+    }
+
+    /// The pressure level with `free` page frames left
+    pub fn level(&self, free: usize) -> Pressure {
+        if free < self.critical {
+            Pressure::Critical
+        } else if free < self.low {
+            Pressure::Low
+        } else {
+            Pressure::None
+        }
+    }
+}
+
+/// A subsystem that responds to memory pressure, registered with
+/// [`pressure_listener!`]
+pub struct PressureListener {
+    /// What's listening, for logging
+    pub name: &'static str,
+    pub notify: fn(Pressure),
+}
+
+/// Every registered pressure listener
+#[distributed_slice]
+#[linkme(crate = platypos_hal::linkme)]
+pub static PRESSURE_LISTENERS: [PressureListener] = [..];
+
+/// Register `$notify` to be called with the new [`Pressure`] level whenever it
+/// changes. `$name` identifies it in logs, like `"trace output"`.
+macro_rules! pressure_listener {
+    ($name:literal, $notify:expr) => {
+        const _: () = {
+            #[::platypos_hal::linkme::distributed_slice($crate::mm::pressure::PRESSURE_LISTENERS)]
+            #[linkme(crate = ::platypos_hal::linkme)]
+            static LISTENER: $crate::mm::pressure::PressureListener =
+                $crate::mm::pressure::PressureListener {
+                    name: $name,
+                    notify: $notify,
+                };
+        };
+    };
+}
+
+pub(crate) use pressure_listener;
+
+static LOW_WATERMARK: AtomicUsize = AtomicUsize::new(0);
+static CRITICAL_WATERMARK: AtomicUsize = AtomicUsize::new(0);
+
+/// The current [`Pressure`] level
+static LEVEL: AtomicU8 = AtomicU8::new(Pressure::None as u8);
+
+/// The last [`Pressure`] level listeners were told about
+static DELIVERED: AtomicU8 = AtomicU8::new(Pressure::None as u8);
+
+/// Set while listeners are running, so that memory they free doesn't notify
+/// them again from inside their own callback
+static NOTIFYING: AtomicBool = AtomicBool::new(false);
+
+/// The current pressure level. Code with optional allocations can check this
+/// and back off before the system runs out.
+pub fn current() -> Pressure {
+    Pressure::from_u8(LEVEL.load(Ordering::Acquire))
+}
+
+/// Free page frame counts at which pressure is currently reported
+pub fn watermarks() -> Watermarks {
+    Watermarks {
+        low: LOW_WATERMARK.load(Ordering::Relaxed),
+        critical: CRITICAL_WATERMARK.load(Ordering::Relaxed),
+    }
+}
+
+/// Report pressure when free memory drops below `watermarks`
+pub fn set_watermarks(watermarks: Watermarks) {
+    LOW_WATERMARK.store(watermarks.low, Ordering::Relaxed);
+    CRITICAL_WATERMARK.store(watermarks.critical, Ordering::Relaxed);
+}
+
+/// Notify listeners if `free` page frames puts memory on the other side of a
+/// watermark. The root allocator calls this once it's released its lock.
+pub(super) fn update(free: usize) {
+    let level = watermarks().level(free);
+    LEVEL.store(level as u8, Ordering::SeqCst);
+    if DELIVERED.load(Ordering::SeqCst) != level as u8 {
+        notify(false);
+    }
+}
+
+/// Tell listeners memory is critically short, because an allocation failed.
+/// Returns whether any listeners ran, in which case retrying might succeed.
+pub(super) fn reclaim() -> bool {
+    LEVEL.store(Pressure::Critical as u8, Ordering::SeqCst);
+    notify(true)
+}
+
+/// Tell listeners the current level if it's changed since they last heard,
+/// or regardless if `force` is set. Returns whether listeners ran.
+///
+/// Storing the level, claiming [`NOTIFYING`], and releasing it are all
+/// sequentially consistent, so either a processor that changed the level
+/// claims it, or the one releasing it sees the new level.
+fn notify(mut force: bool) -> bool {
+    let mut notified = false;
+    loop {
+        if NOTIFYING.swap(true, Ordering::SeqCst) {
+            // Whoever is notifying checks the level again once they're done
+            return notified;
+        }
+
+        let level = current();
+        if DELIVERED.swap(level as u8, Ordering::SeqCst) != level as u8 || force {
+            match level {
+                Pressure::None => tracing::info!("Memory pressure relieved"),
+                _ => tracing::warn!("Memory pressure is {:?}", level),
+            }
+            for listener in PRESSURE_LISTENERS.iter() {
+                tracing::debug!("Notifying {} of memory pressure", listener.name);
+                (listener.notify)(level);
+            }
+            notified = !PRESSURE_LISTENERS.is_empty();
+            force = false;
+        }
+        NOTIFYING.store(false, Ordering::SeqCst);
+
+        // The level may have changed while listeners ran, either because of memory they freed or
+        // on another processor which found them busy
+        if LEVEL.load(Ordering::SeqCst) == DELIVERED.load(Ordering::SeqCst) {
+            return notified;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::Layout;
+    use core::ptr;
+    use core::sync::atomic::AtomicPtr;
+
+    use alloc::vec::Vec;
+
+    use ktest::*;
+
+    use super::*;
+
+    /// Whether the test listener is recording
+    static RECORDING: AtomicBool = AtomicBool::new(false);
+    /// Levels the test listener heard, in order
+    static HEARD: [AtomicU8; 8] = [const { AtomicU8::new(0) }; 8];
+    static HEARD_COUNT: AtomicUsize = AtomicUsize::new(0);
+    /// If set, the test listener reports this many free frames when it hears
+    /// about critical pressure, as if it had freed memory
+    static RELIEVE_TO: AtomicUsize = AtomicUsize::new(0);
+    /// Heap block that the test listener frees when it hears about critical
+    /// pressure
+    static BALLAST: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+    const CHUNK: Layout = match Layout::from_size_align(512, 8) {
+        Ok(layout) => layout,
+        Err(_) => panic!("bad chunk layout"),
+    };
+
+    pressure_listener!("pressure tests", record);
+
+    fn record(level: Pressure) {
+        if !RECORDING.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(heard) = HEARD.get(HEARD_COUNT.fetch_add(1, Ordering::Relaxed)) {
+            heard.store(level as u8, Ordering::Relaxed);
+        }
+        if level == Pressure::Critical {
+            let ballast = BALLAST.swap(ptr::null_mut(), Ordering::Relaxed);
+            if !ballast.is_null() {
+                // SAFETY: the ballast was allocated with CHUNK, and only this frees it
+                unsafe { alloc::alloc::dealloc(ballast, CHUNK) };
+            }
+            match RELIEVE_TO.swap(0, Ordering::Relaxed) {
+                0 => {}
+                free => update(free),
+            }
+        }
+    }
+
+    fn heard() -> Vec<Pressure> {
+        let count = HEARD_COUNT.load(Ordering::Relaxed).min(HEARD.len());
+        HEARD[..count]
+            .iter()
+            .map(|level| Pressure::from_u8(level.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Records what the test listener hears. Tearing it down puts back the
+    /// watermarks and tells listeners the real pressure level again.
+    struct Recording {
+        watermarks: Watermarks,
+        level: Pressure,
+    }
+
+    #[ktest::fixture]
+    fn recording() -> Recording {
+        HEARD_COUNT.store(0, Ordering::Relaxed);
+        RECORDING.store(true, Ordering::Relaxed);
+        Recording {
+            watermarks: watermarks(),
+            level: current(),
+        }
+    }
+
+    impl Drop for Recording {
+        fn drop(&mut self) {
+            RECORDING.store(false, Ordering::Relaxed);
+            RELIEVE_TO.store(0, Ordering::Relaxed);
+            let ballast = BALLAST.swap(ptr::null_mut(), Ordering::Relaxed);
+            if !ballast.is_null() {
+                // SAFETY: the ballast was allocated with CHUNK, and the listener didn't free it
+                unsafe { alloc::alloc::dealloc(ballast, CHUNK) };
+            }
+            set_watermarks(self.watermarks);
+            LEVEL.store(self.level as u8, Ordering::SeqCst);
+            notify(false);
+        }
+    }
+
+    #[ktest::test]
+    fn test_transitions_delivered_once(recording: &Recording) {
+        ktassert_eq!(recording.level, Pressure::None);
+        set_watermarks(Watermarks {
+            low: 100,
+            critical: 10,
+        });
+
+        for free in [500, 50, 40, 5, 4, 50, 500, 400] {
+            update(free);
+        }
+        ktassert_eq!(
+            heard(),
+            [
+                Pressure::Low,
+                Pressure::Critical,
+                Pressure::Low,
+                Pressure::None
+            ]
+        );
+    }
+
+    #[ktest::test]
+    fn test_change_while_notifying(recording: &Recording) {
+        ktassert_eq!(recording.level, Pressure::None);
+        set_watermarks(Watermarks {
+            low: 100,
+            critical: 10,
+        });
+
+        // Memory freed by a listener changes the level while listeners are running. They hear
+        // about it once they've all returned.
+        RELIEVE_TO.store(500, Ordering::Relaxed);
+        update(5);
+        ktassert_eq!(heard(), [Pressure::Critical, Pressure::None]);
+        ktassert_eq!(current(), Pressure::None);
+
+        // Likewise if another processor is notifying when the level changes
+        NOTIFYING.store(true, Ordering::SeqCst);
+        update(50);
+        ktassert_eq!(heard().len(), 2);
+        NOTIFYING.store(false, Ordering::SeqCst);
+        update(50);
+        ktassert_eq!(heard(), [Pressure::Critical, Pressure::None, Pressure::Low]);
+    }
+
+    #[ktest::test]
+    fn test_heap_reclaims_and_retries(_recording: &Recording) {
+        // Fill the heap with chunks until another one doesn't fit, then give the last one to the
+        // listener to free
+        let mut chunks = Vec::with_capacity(256);
+        while chunks.len() < chunks.capacity() {
+            // SAFETY: CHUNK has a non-zero size
+            let chunk = unsafe { alloc::alloc::alloc(CHUNK) };
+            if chunk.is_null() {
+                break;
+            }
+            chunks.push(chunk);
+        }
+        ktassert!(chunks.len() < chunks.capacity());
+        BALLAST.store(chunks.pop().unwrap(), Ordering::Relaxed);
+        HEARD_COUNT.store(0, Ordering::Relaxed);
+
+        // The first attempt fails, but listeners free enough for the retry
+        // SAFETY: CHUNK has a non-zero size
+        let chunk = unsafe { alloc::alloc::alloc(CHUNK) };
+        chunks.push(chunk);
+        let ballast_freed = BALLAST.load(Ordering::Relaxed).is_null();
+        for chunk in chunks.drain(..).filter(|chunk| !chunk.is_null()) {
+            // SAFETY: each chunk was allocated with CHUNK
+            unsafe { alloc::alloc::dealloc(chunk, CHUNK) };
+        }
+
+        ktassert!(ballast_freed);
+        ktassert!(!chunk.is_null());
+        ktassert_eq!(heard(), [Pressure::Critical]);
+    }
+
+    #[ktest::test]
+    fn test_levels() {
+        let watermarks = Watermarks {
+            low: 100,
+            critical: 10,
+        };
+        ktassert_eq!(watermarks.level(500), Pressure::None);
+        ktassert_eq!(watermarks.level(100), Pressure::None);
+        ktassert_eq!(watermarks.level(99), Pressure::Low);
+        ktassert_eq!(watermarks.level(9), Pressure::Critical);
+        ktassert_eq!(watermarks.level(0), Pressure::Critical);
+
+        // Small systems still get some headroom
+        ktassert_eq!(
+            Watermarks::for_usable(2048),
+            Watermarks {
+                low: 1024,
+                critical: 256
+            }
+        );
+        ktassert_eq!(Watermarks::for_usable(1 << 20).critical, 1 << 14);
+    }
+}
@@ -10,6 +10,27 @@
 //! below a certain address"). However, deallocation is slow - we have to scan
 //! the list to find out which range the allocation came from. Unlike Fuschia,
 //! ranges cannot overlap.
+//!
+//! Memory can also be added and removed after initialization, for example when
+//! reclaiming bootloader memory or when QEMU balloons memory in and out. Both
+//! operations go through the same lock as allocation, so they don't add any
+//! cost to the allocation path.
+//!
+//! For huge page mappings, [`Allocator::allocate_huge_2m`] and
+//! [`Allocator::allocate_huge_1g`] find blocks aligned to their own size, and
+//! [`Allocator::huge_block_stats`] reports how many such blocks are free.
+//!
+//! With the `frame-owners` feature, the allocator also records who each block
+//! was allocated for (see [`Allocator::allocate_for`] and
+//! [`Allocator::frame_owner`]).
+//!
+//! Reserved ranges passed to [`init`], like the blocks handed out by
+//! [boot memory](super::bootmem), start out allocated, so their owners can
+//! free them like any other allocation.
+//!
+//! Free memory is checked against the [pressure](super::pressure) watermarks
+//! after every allocation and deallocation, which are set from the amount of
+//! usable memory whenever it changes.
 
 use core::alloc::Layout;
 use core::cell::RefCell;
@@ -18,15 +39,23 @@ use core::mem::{self, MaybeUninit};
 
 use alloc::vec::Vec;
 
-use intrusive_collections::linked_list::CursorMut;
-use intrusive_collections::{intrusive_adapter, LinkedList, LinkedListLink, UnsafeRef};
 use linked_list_allocator::LockedHeap;
+use platypos_collections::list::CursorMut;
+use platypos_collections::{adapter, Link, List, UnsafeRef};
 use platypos_common::sync::Global;
 
 use crate::arch::mm::MemoryAccess;
+use crate::fault;
 use crate::prelude::*;
 
 use super::map::Region;
+use super::pressure::{self, Watermarks};
+
+#[cfg(all(test, feature = "bench"))]
+mod bench;
+mod owners;
+
+pub use owners::{Owner, Subsystem};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 enum Status {
@@ -44,13 +73,13 @@ enum Status {
 #[derive(Debug)]
 struct Run {
     /// Link in the free list. Only set if the status is [`Free`]
-    free_link: LinkedListLink,
+    free_link: Link,
 
     /// Link in the overall range list
-    link: LinkedListLink,
+    link: Link,
 
-    /// Mutable state of a Run
-    /// See https://github.com/Amanieu/intrusive-rs/issues/38
+    /// Mutable state of a Run. Lists only hand out shared references to their
+    /// elements, so this needs interior mutability.
     inner: RefCell<RunState>,
 }
 
@@ -63,36 +92,63 @@ struct RunState {
     status: Status,
 }
 
-intrusive_adapter!(RunAdapter = UnsafeRef<Run>: Run { link: LinkedListLink });
-intrusive_adapter!(FreeRunAdapter = UnsafeRef<Run>: Run { free_link: LinkedListLink });
+adapter!(RunAdapter = Run { link });
+adapter!(FreeRunAdapter = Run { free_link });
 
 /// Size in page frames of the scratch region to allocate for bookkeeping while
 /// configuring the allocator
-const SCRATCH_PAGES: usize = 2;
+pub const SCRATCH_PAGES: usize = 2;
 
 /// Minimum number of pages to allocate towards tracking memory
 const MIN_TRACKING_PAGES: usize = 2;
 
+/// Page frames in a 2MiB huge page
+pub const HUGE_2M_PAGES: usize = (2 << 20) / crate::arch::PAGE_SIZE;
+
+/// Page frames in a 1GiB huge page
+pub const HUGE_1G_PAGES: usize = (1 << 30) / crate::arch::PAGE_SIZE;
+
+/// How many huge-page-sized blocks could currently be allocated. Each count
+/// is independent: allocating a 1GiB block uses up 512 of the 2MiB blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HugeBlockStats {
+    /// Free, 2MiB-aligned 2MiB blocks
+    pub free_2m: usize,
+    /// Free, 1GiB-aligned 1GiB blocks
+    pub free_1g: usize,
+}
+
+static GLOBAL: Global<Allocator<'static>> = Global::new();
+
 /// Physical memory allocator
 pub struct Allocator<'a> {
     access: &'a MemoryAccess,
     inner: InterruptSafeMutex<'a, AllocatorInner>,
 }
 
-/// Initialize the root memory allocator
+/// Initialize the root memory allocator. The page frames in `reserved`, which
+/// boot memory handed out, start out allocated. `scratch` is one of those
+/// blocks, [`SCRATCH_PAGES`] long, which the allocator uses while it's built
+/// and then frees.
 pub fn init<I>(
     access: &'static MemoryAccess,
     controller: &'static hal_impl::interrupts::Controller,
     memory_map: I,
     reserved: &[PageFrameRange],
+    scratch: PageFrameRange,
 ) -> Result<&'static Allocator<'static>, Error>
 where
     I: Iterator<Item = Region> + Clone,
 {
     // TODO: need a workaround/way to have static generics
-    static GLOBAL: Global<Allocator<'static>> = Global::new();
-    let allocator = Allocator::build(access, controller, memory_map, reserved)?;
-    Ok(GLOBAL.init(allocator))
+    let allocator = GLOBAL.init(Allocator::build(
+        access, controller, memory_map, reserved, scratch,
+    )?);
+    allocator
+        .deallocate(scratch)
+        .context("freeing scratch space")?;
+    allocator.update_watermarks();
+    Ok(allocator)
 }
 
 impl<'a> Allocator<'a> {
@@ -102,17 +158,18 @@ impl<'a> Allocator<'a> {
         controller: &'a hal_impl::interrupts::Controller,
         memory_map: I,
         reserved: &[PageFrameRange],
+        scratch: PageFrameRange,
     ) -> Result<Self, Error>
     where
         I: Iterator<Item = Region> + Clone,
     {
         let _span = tracing::info_span!("init").entered();
 
-        // First, find a scratch region:
-        let (scratch, initial_tracking) = memory_map
+        // First, find room for the initial tracking pages:
+        let initial_tracking = memory_map
             .clone()
             .find_map(|r| {
-                if r.usable() && r.size() >= ((MIN_TRACKING_PAGES + SCRATCH_PAGES) * PAGE_SIZE) {
+                if r.usable() && r.size() >= MIN_TRACKING_PAGES * PAGE_SIZE {
                     for reserved_region in reserved.iter() {
                         if reserved_region.address_range().intersects(&r.range()) {
                             return None;
@@ -121,16 +178,16 @@ impl<'a> Allocator<'a> {
 
                     let start_pf = PageFrame::from_start(r.start())
                         .expect("Memory region is not page-aligned!");
-
-                    let initial_tracking = PageFrameRange::from_start_size(start_pf, SCRATCH_PAGES);
-                    let scratch =
-                        PageFrameRange::from_start_size(initial_tracking.end(), MIN_TRACKING_PAGES);
-                    Some((scratch, initial_tracking))
+                    Some(PageFrameRange::from_start_size(
+                        start_pf,
+                        MIN_TRACKING_PAGES,
+                    ))
                 } else {
                     None
                 }
             })
-            .ok_or(Error::new(ErrorKind::InsufficientMemory))?;
+            .ok_or(MmError::InsufficientMemory)
+            .context("finding initial tracking space")?;
 
         tracing::debug!(
             range = %scratch.address_range(),
@@ -156,16 +213,16 @@ impl<'a> Allocator<'a> {
                                 .entered();
                         let start = PageFrame::from_start(region.start())
                             .expect("Memory region is not page-aligned!");
-                        assert!(
+                        kassert!(
                             region.size() % PAGE_SIZE == 0,
                             "Region size is not a whole number of pages!"
                         );
                         let size = region.size() / PAGE_SIZE;
                         let mut range = PageFrameRange::from_start_size(start, size);
                         // Make sure the initial tracking memory isn't double-allocated. This works
-                        // because we allocate the tracking and scratch ranges from the _start_ of a
-                        // usable range. Note that the scratch range is not removed here, so it will
-                        // become usable once the allocator is initialized.
+                        // because we allocate the tracking range from the _start_ of a usable
+                        // range. The scratch range came from boot memory, so it's reserved below
+                        // and freed once the allocator is initialized.
                         if range.start() == initial_tracking.start() {
                             range.shrink_left(MIN_TRACKING_PAGES);
                         }
@@ -193,13 +250,19 @@ impl<'a> Allocator<'a> {
                     }
                 }
 
-                // TODO: remove reserved ranges
-
                 let mut allocator = AllocatorInner::new();
-                allocator.init_tracking_space(access, initial_tracking)?;
+                allocator
+                    .init_tracking_space(access, initial_tracking)
+                    .context("setting up initial tracking space")?;
                 for range in &ranges {
                     allocator.add_allocatable_range(*range);
                 }
+                for range in reserved {
+                    allocator.reserve(*range).context("reserving memory")?;
+                    owners::TABLE
+                        .lock()
+                        .insert(*range, Owner::new(Subsystem::BootMem));
+                }
 
                 tracing::info!(
                     "Post-initialization allocator state:\n{}",
@@ -221,20 +284,172 @@ impl<'a> Allocator<'a> {
 
     /// Allocate `count` pages of contiguous physical memory.
     pub fn allocate(&self, count: usize) -> Result<PageFrameRange, Error> {
+        self.allocate_for(count, Owner::UNKNOWN)
+    }
+
+    /// Allocate `count` pages of contiguous physical memory on behalf of
+    /// `owner`. The owner is only recorded with the `frame-owners` feature.
+    pub fn allocate_for(&self, count: usize, owner: Owner) -> Result<PageFrameRange, Error> {
+        if fault::fault_point!("mm.root_allocator.allocate") {
+            return Err(Error::new(MmError::InsufficientMemory).context("injected fault"));
+        }
         let mut inner = self.inner.lock();
-        inner.allocate(count)
+        let range = inner.allocate(count)?;
+        owners::TABLE.lock().insert(range, owner);
+        let free = inner.free_pages();
+        drop(inner);
+        pressure::update(free);
+        Ok(range)
+    }
+
+    /// Allocate `count` pages of contiguous physical memory, starting at a
+    /// multiple of `align` page frames, on behalf of `owner`.
+    pub fn allocate_aligned_for(
+        &self,
+        count: usize,
+        align: usize,
+        owner: Owner,
+    ) -> Result<PageFrameRange, Error> {
+        if fault::fault_point!("mm.root_allocator.allocate") {
+            return Err(Error::new(MmError::InsufficientMemory).context("injected fault"));
+        }
+        let mut inner = self.inner.lock();
+        let range = inner.allocate_aligned(count, align)?;
+        owners::TABLE.lock().insert(range, owner);
+        let free = inner.free_pages();
+        drop(inner);
+        pressure::update(free);
+        Ok(range)
+    }
+
+    /// Allocate a 2MiB block of physical memory that can be mapped as a single
+    /// huge page.
+    pub fn allocate_huge_2m(&self, owner: Owner) -> Result<PageFrameRange, Error> {
+        self.allocate_aligned_for(HUGE_2M_PAGES, HUGE_2M_PAGES, owner)
+    }
+
+    /// Allocate a 1GiB block of physical memory that can be mapped as a single
+    /// huge page.
+    pub fn allocate_huge_1g(&self, owner: Owner) -> Result<PageFrameRange, Error> {
+        self.allocate_aligned_for(HUGE_1G_PAGES, HUGE_1G_PAGES, owner)
+    }
+
+    /// Count the huge-page-sized blocks that are currently free
+    pub fn huge_block_stats(&self) -> HugeBlockStats {
+        let inner = self.inner.lock();
+        HugeBlockStats {
+            free_2m: inner.count_aligned(HUGE_2M_PAGES),
+            free_1g: inner.count_aligned(HUGE_1G_PAGES),
+        }
+    }
+
+    /// Number of page frames currently allocated
+    pub fn allocated_pages(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.usable_pages() - inner.free_pages()
+    }
+
+    /// Call `visit` with the number of allocated page frames recorded for
+    /// each subsystem. Nothing is recorded without the `frame-owners` feature.
+    pub fn pages_by_subsystem(&self, mut visit: impl FnMut(Subsystem, usize)) {
+        let _inner = self.inner.lock();
+        let table = owners::TABLE.lock();
+        for subsystem in Subsystem::ALL {
+            let pages = table
+                .iter()
+                .filter(|(_, owner)| owner.subsystem == subsystem)
+                .map(|(range, _)| range.size())
+                .sum();
+            if pages > 0 {
+                visit(subsystem, pages);
+            }
+        }
     }
 
     /// Deallocate the physical memory allocation `range`.
     pub fn deallocate(&self, range: PageFrameRange) -> Result<(), Error> {
         let mut inner = self.inner.lock();
-        inner.deallocate(range)
+        inner.deallocate(range)?;
+        owners::TABLE.lock().remove(range);
+        let free = inner.free_pages();
+        drop(inner);
+        pressure::update(free);
+        Ok(())
+    }
+
+    /// Find the allocated block containing `frame` and who it was allocated
+    /// for. This always returns `None` without the `frame-owners` feature.
+    pub fn frame_owner(&self, frame: PageFrame) -> Option<(PageFrameRange, Owner)> {
+        // Take the allocator lock too, so that interrupts are disabled and the table can't
+        // change underneath us
+        let _inner = self.inner.lock();
+        let table = owners::TABLE.lock();
+        table.containing(frame)
+    }
+
+    /// Log every tracked allocation and its owner
+    pub fn dump_owners(&self) {
+        let _inner = self.inner.lock();
+        let table = owners::TABLE.lock();
+        tracing::info!("Allocated page frames by owner:");
+        for (range, owner) in table.iter() {
+            tracing::info!(
+                " - {} - {} ({} page frames): {}",
+                range.start(),
+                range.end(),
+                range.size(),
+                owner
+            );
+        }
+    }
+
+    /// Add `range` as allocatable memory. If the allocator is out of tracking
+    /// memory, the start of `range` is used for it.
+    ///
+    /// # Safety
+    /// The caller must ensure that `range` is usable RAM and not in use for
+    /// another purpose.
+    // Nothing hot-adds memory outside tests yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub unsafe fn add_range(&self, range: PageFrameRange) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+        inner.hot_add(self.access, range)?;
+        drop(inner);
+        self.update_watermarks();
+        Ok(())
+    }
+
+    /// Remove `range` from the allocator, so that it's never allocated again.
+    /// Only free memory can be removed.
+    pub fn remove_range(&self, range: PageFrameRange) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+        inner.hot_remove(range)?;
+        drop(inner);
+        self.update_watermarks();
+        Ok(())
+    }
+
+    /// Scale the pressure watermarks to the amount of usable memory, and
+    /// check free memory against them
+    fn update_watermarks(&self) {
+        let inner = self.inner.lock();
+        let (usable, free) = (inner.usable_pages(), inner.free_pages());
+        drop(inner);
+        pressure::set_watermarks(Watermarks::for_usable(usable));
+        pressure::update(free);
     }
 
     /// Log allocator state
     pub fn dump_state(&self) {
         let inner = self.inner.lock();
         tracing::info!("Allocator state:{}", inner.display_state());
+        let free = inner.free_pages();
+        tracing::info!(
+            "Free memory: {}, including {} x 2MiB and {} x 1GiB huge blocks",
+            (free * PAGE_SIZE).as_size(),
+            inner.count_aligned(HUGE_2M_PAGES),
+            inner.count_aligned(HUGE_1G_PAGES)
+        );
     }
 }
 
@@ -265,15 +480,15 @@ struct AllocatorInner {
     // methods won't _also_ use runs. Something like view types (https://smallcultfollowing.com/babysteps/blog/2021/11/05/view-types/)
     // would solve the problem. For now, put all the non-runs fields in a separate struct, and use
     // associated functions instead of methods.
-    runs: LinkedList<RunAdapter>,
+    runs: List<RunAdapter>,
     tracking: AllocatorTracking,
 }
 
 struct AllocatorTracking {
     /// List of runs identifying free RAM
-    free: LinkedList<FreeRunAdapter>,
+    free: List<FreeRunAdapter>,
     /// List of allocated-but-unused [`Run`] structures
-    unused_runs: LinkedList<RunAdapter>,
+    unused_runs: List<RunAdapter>,
 }
 
 struct DisplayAllocatorState<'a> {
@@ -283,10 +498,10 @@ struct DisplayAllocatorState<'a> {
 impl AllocatorInner {
     fn new() -> Self {
         AllocatorInner {
-            runs: LinkedList::new(RunAdapter::new()),
+            runs: List::new(),
             tracking: AllocatorTracking {
-                free: LinkedList::new(FreeRunAdapter::new()),
-                unused_runs: LinkedList::new(RunAdapter::new()),
+                free: List::new(),
+                unused_runs: List::new(),
             },
         }
     }
@@ -312,7 +527,7 @@ impl AllocatorInner {
                     }
                     None => {
                         tracing::warn!("Insufficient free memory");
-                        return Err(Error::new(ErrorKind::InsufficientMemory));
+                        return Err(Error::new(MmError::InsufficientMemory));
                     }
                 }
             }
@@ -360,6 +575,91 @@ impl AllocatorInner {
         }
     }
 
+    /// Allocate `count` pages of contiguous physical memory, starting at a
+    /// multiple of `align` page frames. Like [`AllocatorInner::allocate`], this
+    /// is first-fit.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip(self, align))]
+    fn allocate_aligned(&mut self, count: usize, align: usize) -> Result<PageFrameRange, Error> {
+        let range = self
+            .tracking
+            .free
+            .iter()
+            .find_map(|run| {
+                let range = PageFrameRange::from_start_size(align_up(run.start(), align), count);
+                (range.end() <= run.end()).then_some(range)
+            })
+            .ok_or_else(|| {
+                tracing::warn!(count, "No free memory aligned to {align} page frames");
+                Error::new(MmError::InsufficientMemory)
+            })?;
+
+        // Carving `range` out of the middle of a run needs one run for the remainder, and the
+        // allocation itself needs another. Check up front so a failure doesn't leak the range.
+        kensure!(
+            self.tracking.unused_runs.iter().take(2).count() >= 2,
+            MmError::InsufficientMemory,
+            "no unused runs left to split free memory"
+        );
+        self.take_free(range)?;
+
+        let allocated_run = self.tracking.unused_runs.pop_front().unwrap();
+        allocated_run.initialize(range, Status::Allocated);
+        let cursor = Self::find_next(&mut self.runs, &allocated_run);
+        Self::add_run(allocated_run, cursor, &mut self.tracking);
+
+        tracing::trace!(%range, "Split off aligned run");
+        Ok(range)
+    }
+
+    /// Mark the free memory in `range` as allocated, so it's only ever freed
+    /// and never handed out.
+    fn reserve(&mut self, range: PageFrameRange) -> Result<(), Error> {
+        kensure!(
+            self.tracking.unused_runs.iter().take(2).count() >= 2,
+            MmError::InsufficientMemory,
+            "no unused runs left to reserve memory"
+        );
+        self.take_free(range)?;
+
+        let reserved_run = self.tracking.unused_runs.pop_front().unwrap();
+        reserved_run.initialize(range, Status::Allocated);
+        let cursor = Self::find_next(&mut self.runs, &reserved_run);
+        Self::add_run(reserved_run, cursor, &mut self.tracking);
+
+        tracing::debug!(%range, "Reserved memory");
+        Ok(())
+    }
+
+    /// Number of free page frames
+    fn free_pages(&self) -> usize {
+        self.tracking.free.iter().map(|run| run.size()).sum()
+    }
+
+    /// Number of page frames that are free or allocated, as opposed to holding
+    /// the allocator's own bookkeeping
+    fn usable_pages(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|run| matches!(run.status(), Status::Free | Status::Allocated))
+            .map(|run| run.size())
+            .sum()
+    }
+
+    /// Count how many blocks of `size` page frames, aligned to their size,
+    /// could be allocated right now.
+    fn count_aligned(&self, size: usize) -> usize {
+        self.tracking
+            .free
+            .iter()
+            .map(|run| {
+                let start = align_up(run.start(), size).as_usize();
+                let end = run.end().as_usize() / size * size;
+                end.saturating_sub(start) / size
+            })
+            .sum()
+    }
+
     /// Deallocate the physical memory allocation `range`.
     #[must_use]
     #[tracing::instrument(level = "trace", skip(self))]
@@ -372,7 +672,7 @@ impl AllocatorInner {
                 if inner.range == range {
                     if inner.status != Status::Allocated {
                         tracing::error!("Run is not allocated! Has status {:?}", inner.status);
-                        break Err(Error::new(ErrorKind::InvalidAddress));
+                        break Err(Error::new(MmError::InvalidAddress));
                     }
 
                     inner.status = Status::Free;
@@ -390,7 +690,7 @@ impl AllocatorInner {
                 }
             } else {
                 tracing::error!("Could not find existing run");
-                break Err(Error::new(ErrorKind::AddressOutOfBounds));
+                break Err(Error::new(MmError::AddressOutOfBounds));
             }
         }
     }
@@ -422,8 +722,115 @@ impl AllocatorInner {
         Self::add_run(run, cursor, &mut self.tracking);
     }
 
+    /// Adds `range` after initialization, creating more tracking space first if
+    /// there are no unused runs left.
+    ///
+    /// # Safety
+    /// The caller must ensure that `range` is usable RAM and not already in use
+    /// for another purpose.
+    unsafe fn hot_add(
+        &mut self,
+        access: &MemoryAccess,
+        mut range: PageFrameRange,
+    ) -> Result<(), Error> {
+        if range.size() == 0 {
+            return Ok(());
+        }
+
+        // Adjacent runs are fine (they'll be coalesced), but overlapping ones aren't
+        let overlaps = self
+            .runs
+            .iter()
+            .any(|run| run.start() < range.end() && range.start() < run.end());
+        if overlaps {
+            tracing::error!(%range, "Range overlaps memory the allocator already tracks");
+            return Err(Error::new(MmError::InvalidAddress));
+        }
+
+        if self.tracking.unused_runs.is_empty() {
+            // Same approach as initialization: take tracking space from the start of the range
+            if range.size() <= MIN_TRACKING_PAGES {
+                tracing::warn!(%range, "Range is too small to add without more tracking space");
+                return Err(Error::new(MmError::InsufficientMemory));
+            }
+            let tracking = PageFrameRange::from_start_size(range.start(), MIN_TRACKING_PAGES);
+            self.init_tracking_space(access, tracking)
+                .context("setting up tracking space for added memory")?;
+            range.shrink_left(MIN_TRACKING_PAGES);
+        }
+
+        self.add_allocatable_range(range);
+        tracing::debug!(%range, "Added memory");
+        Ok(())
+    }
+
+    /// Removes the free memory in `range` from the allocator.
+    fn hot_remove(&mut self, range: PageFrameRange) -> Result<(), Error> {
+        if range.size() == 0 {
+            return Ok(());
+        }
+
+        self.take_free(range)?;
+        tracing::debug!(%range, "Removed memory");
+        Ok(())
+    }
+
+    /// Removes `range`, which must be entirely free, from the runs list. The
+    /// caller decides what becomes of it.
+    fn take_free(&mut self, range: PageFrameRange) -> Result<(), Error> {
+        // Free runs are always coalesced, so a range that's entirely free is always within a single
+        // free run
+        let run = self
+            .tracking
+            .free
+            .iter()
+            .find(|run| run.inner.borrow().range.contains(&range))
+            .map(|run| run as *const Run)
+            .ok_or_else(|| {
+                tracing::error!(%range, "Range is not entirely free memory");
+                Error::new(MmError::InvalidAddress)
+            })?;
+        // Safety: runs are allocated from permanent tracking memory, and this one stays linked
+        // until it's explicitly removed below
+        let run = unsafe { &*run };
+        let (start, end) = (run.start(), run.end());
+
+        match (range.start() == start, range.end() == end) {
+            (true, true) => {
+                // Safety: `run` is free, so it's in both the free list and the list of all runs
+                let ptr = unsafe {
+                    self.tracking.free.cursor_mut_from_ptr(run).remove();
+                    self.runs.cursor_mut_from_ptr(run).remove().unwrap()
+                };
+                let mut state = ptr.inner.borrow_mut();
+                state.range = PageFrameRange::empty();
+                state.status = Status::Unused;
+                drop(state);
+                self.tracking.unused_runs.push_back(ptr);
+            }
+            (true, false) => run.inner.borrow_mut().range.shrink_left(range.size()),
+            (false, true) => run.inner.borrow_mut().range.shrink_right(range.size()),
+            (false, false) => {
+                // Split off the part of the run after `range`
+                let tail = self.tracking.unused_runs.pop_front().ok_or_else(|| {
+                    tracing::warn!("No unused runs left to split free memory");
+                    Error::new(MmError::InsufficientMemory)
+                })?;
+                run.inner.borrow_mut().range.set_size(range.start() - start);
+                tail.initialize(PageFrameRange::new(range.end(), end), Status::Free);
+
+                // Safety: `run` is free, so it's in the list of all runs
+                let mut cursor = unsafe { self.runs.cursor_mut_from_ptr(run) };
+                cursor.move_next();
+                Self::add_run(tail, cursor, &mut self.tracking);
+            }
+        }
+
+        Ok(())
+    }
+
     /// Search through the ordered list `list` for the next run after `run`
-    fn find_next<'a>(list: &'a mut LinkedList<RunAdapter>, run: &Run) -> CursorMut<'a, RunAdapter> {
+    fn find_next<'a>(list: &'a mut List<RunAdapter>, run: &Run) -> CursorMut<'a, RunAdapter> {
         let mut cursor = list.front_mut();
         while matches!(cursor.get(), Some(r) if r.start() < run.start()) {
             cursor.move_next();
@@ -439,8 +846,8 @@ impl AllocatorInner {
         mut cursor: CursorMut<'_, RunAdapter>,
         tracking: &mut AllocatorTracking,
     ) {
-        assert!(!run.link.is_linked());
-        assert!(!run.free_link.is_linked());
+        kassert!(!run.link.is_linked());
+        kassert!(!run.free_link.is_linked());
 
         if run.status() == Status::Free {
             tracking.free.push_back(run.clone());
@@ -448,17 +855,17 @@ impl AllocatorInner {
 
         // Runs cannot overlap
         if let Some(next) = cursor.get() {
-            assert!(next.start() >= run.end(), "{} and {} overlap", next, &*run);
+            kassert!(next.start() >= run.end(), "{} and {} overlap", next, &*run);
         } else {
             // If the cursor is "null", then the current run must be going at
             // the end of the list
             let last_cursor = cursor.peek_prev();
             if let Some(last) = last_cursor.get() {
-                assert!(last.end() <= run.start(), "{} and {} overlap", last, &*run);
+                kassert!(last.end() <= run.start(), "{} and {} overlap", last, &*run);
             }
         }
         if let Some(prev) = cursor.peek_prev().get() {
-            assert!(prev.end() <= run.start(), "{} and {} overlap", prev, &*run);
+            kassert!(prev.end() <= run.start(), "{} and {} overlap", prev, &*run);
         }
 
         // Don't coalesce `Allocated` runs so that we can later free them without having
@@ -552,19 +959,22 @@ impl AllocatorInner {
         // put padding between array elements, but if that changes, then the calculation
         // above will be wrong. So, validate that against the array size Rust thinks we
         // should need.
-        assert!(
+        kassert!(
             Layout::array::<Run>(run_count)
-                .map_err(|_| Error::new(ErrorKind::AddressOutOfBounds))?
+                .map_err(|_| Error::new(MmError::AddressOutOfBounds))?
                 .size()
                 <= range.size_bytes()
         );
 
-        let mut ptr = access.map_permanent(range)?.cast::<MaybeUninit<Run>>();
+        let mut ptr = access
+            .map_permanent(range)
+            .context("mapping tracking space")?
+            .cast::<MaybeUninit<Run>>();
 
         for _ in 0..run_count {
             (*ptr).write(Run {
-                free_link: LinkedListLink::new(),
-                link: LinkedListLink::new(),
+                free_link: Link::new(),
+                link: Link::new(),
                 inner: RefCell::new(RunState {
                     range: PageFrameRange::empty(),
                     status: Status::Unused,
@@ -588,6 +998,11 @@ impl AllocatorInner {
     }
 }
 
+/// Round `frame` up to a multiple of `align` page frames
+fn align_up(frame: PageFrame, align: usize) -> PageFrame {
+    PageFrame::new(frame.as_usize().next_multiple_of(align))
+}
+
 impl Run {
     fn status(&self) -> Status {
         self.inner.borrow().status
@@ -649,8 +1064,14 @@ impl fmt::Display for RunState {
 
 impl<'a> fmt::Display for DisplayAllocatorState<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Callers hold the allocator lock, so the owner table can't be locked elsewhere
+        let owners = owners::TABLE.lock();
         for run in self.allocator.runs.iter() {
-            writeln!(f, "* {}", run)?;
+            write!(f, "* {}", run)?;
+            match owners.owner_of(run.start()) {
+                Some(owner) if run.status() == Status::Allocated => writeln!(f, " for {owner}")?,
+                _ => writeln!(f)?,
+            }
             if run.status() == Status::Free {
                 // Safety: free nodes are always in the free list
                 debug_assert!(run.free_link.is_linked());
@@ -676,3 +1097,191 @@ impl<'a> fmt::Display for DisplayAllocatorState<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    fn owner() -> Owner {
+        Owner::new(Subsystem::Test)
+    }
+
+    /// Allocate a huge block with `allocate`, which must be aligned to its
+    /// size of `pages` page frames, or fail if there are no free ones
+    fn check_huge(
+        pages: usize,
+        free: usize,
+        allocate: impl FnOnce(&Allocator) -> Result<PageFrameRange, Error>,
+    ) {
+        let allocator = GLOBAL.get();
+        match allocate(allocator) {
+            Ok(range) => {
+                ktassert!(free > 0);
+                ktassert_eq!(range.size(), pages);
+                ktassert_eq!(range.start().as_usize() % pages, 0);
+                allocator.deallocate(range).unwrap();
+            }
+            Err(err) => {
+                ktassert_eq!(free, 0);
+                ktassert_eq!(err.kind(), KError::Mm(MmError::InsufficientMemory));
+            }
+        }
+    }
+
+    #[ktest::test]
+    fn test_allocate_huge_2m() {
+        let free = GLOBAL.get().huge_block_stats().free_2m;
+        check_huge(HUGE_2M_PAGES, free, |a| a.allocate_huge_2m(owner()));
+    }
+
+    #[ktest::test]
+    fn test_allocate_huge_1g() {
+        // Test VMs usually don't have a free 1GiB-aligned gigabyte, so this mostly checks the
+        // failure path
+        let free = GLOBAL.get().huge_block_stats().free_1g;
+        check_huge(HUGE_1G_PAGES, free, |a| a.allocate_huge_1g(owner()));
+    }
+
+    #[ktest::test]
+    fn test_allocate_aligned_out_of_memory() {
+        let allocator = GLOBAL.get();
+        let before = allocator.allocated_pages();
+        // A terabyte is more than any test VM has
+        let err = allocator
+            .allocate_aligned_for(HUGE_1G_PAGES * 1024, HUGE_2M_PAGES, owner())
+            .unwrap_err();
+        ktassert_eq!(err.kind(), KError::Mm(MmError::InsufficientMemory));
+        ktassert_eq!(allocator.allocated_pages(), before);
+    }
+
+    /// Run `test` against an empty allocator of its own, passing it `pages`
+    /// page frames borrowed from the global allocator to add
+    fn with_isolated(
+        pages: usize,
+        test: impl FnOnce(&mut AllocatorInner, &MemoryAccess, PageFrameRange),
+    ) {
+        let global = GLOBAL.get();
+        let tracking = global.allocate_for(MIN_TRACKING_PAGES, owner()).unwrap();
+        let memory = global.allocate_for(pages, owner()).unwrap();
+
+        let mut inner = AllocatorInner::new();
+        // SAFETY: the tracking pages were just allocated, so nothing else uses them
+        unsafe { inner.init_tracking_space(global.access, tracking) }.unwrap();
+        test(&mut inner, global.access, memory);
+
+        // The runs live in the tracking pages, so unlink them before freeing those
+        drop(inner);
+        global.deallocate(memory).unwrap();
+        global.deallocate(tracking).unwrap();
+    }
+
+    /// The free runs of `inner`, in address order
+    fn free_runs(inner: &AllocatorInner) -> Vec<PageFrameRange> {
+        inner
+            .runs
+            .iter()
+            .filter(|run| run.status() == Status::Free)
+            .map(|run| run.inner.borrow().range)
+            .collect()
+    }
+
+    #[ktest::test]
+    fn test_hot_add() {
+        with_isolated(16, |inner, access, memory| {
+            let first = PageFrameRange::from_start_size(memory.start(), 12);
+            let rest = PageFrameRange::new(first.end(), memory.end());
+            // SAFETY: `with_isolated` hands over `memory` for the allocator to use
+            unsafe { inner.hot_add(access, first) }.unwrap();
+            ktassert_eq!(inner.free_pages(), 12);
+
+            // SAFETY: as above, and overlapping memory is rejected before it's touched
+            let err = unsafe { inner.hot_add(access, memory) }.unwrap_err();
+            ktassert_eq!(err.kind(), KError::Mm(MmError::InvalidAddress));
+
+            // Adjacent memory joins the existing free run
+            // SAFETY: as above
+            unsafe { inner.hot_add(access, rest) }.unwrap();
+            ktassert_eq!(inner.free_pages(), 16);
+            ktassert_eq!(inner.usable_pages(), 16);
+            ktassert_eq!(free_runs(inner), [memory]);
+        });
+    }
+
+    #[ktest::test]
+    fn test_hot_remove_edges() {
+        with_isolated(16, |inner, access, memory| {
+            // SAFETY: `with_isolated` hands over `memory` for the allocator to use
+            unsafe { inner.hot_add(access, memory) }.unwrap();
+            let left = PageFrameRange::from_start_size(memory.start(), 2);
+            let right = PageFrameRange::new(memory.end() - 2, memory.end());
+
+            inner.hot_remove(left).unwrap();
+            inner.hot_remove(right).unwrap();
+            ktassert_eq!(inner.free_pages(), 12);
+            ktassert_eq!(inner.usable_pages(), 12);
+            ktassert_eq!(
+                free_runs(inner),
+                [PageFrameRange::new(left.end(), right.start())]
+            );
+
+            // Memory that's already gone can't be removed again
+            let err = inner.hot_remove(left).unwrap_err();
+            ktassert_eq!(err.kind(), KError::Mm(MmError::InvalidAddress));
+
+            // SAFETY: both edges were removed above, so the allocator doesn't use them
+            unsafe {
+                inner.hot_add(access, left).unwrap();
+                inner.hot_add(access, right).unwrap();
+            }
+            ktassert_eq!(free_runs(inner), [memory]);
+        });
+    }
+
+    #[ktest::test]
+    fn test_hot_remove_middle() {
+        with_isolated(16, |inner, access, memory| {
+            // SAFETY: `with_isolated` hands over `memory` for the allocator to use
+            unsafe { inner.hot_add(access, memory) }.unwrap();
+            let head = PageFrameRange::from_start_size(memory.start(), 4);
+            let middle = PageFrameRange::from_start_size(head.end(), 4);
+            let tail = PageFrameRange::new(middle.end(), memory.end());
+
+            // Removing the middle splits the free run in two
+            inner.hot_remove(middle).unwrap();
+            ktassert_eq!(inner.free_pages(), 12);
+            ktassert_eq!(free_runs(inner), [head, tail]);
+
+            // Removing a whole run drops it
+            inner.hot_remove(head).unwrap();
+            ktassert_eq!(inner.usable_pages(), 8);
+            ktassert_eq!(free_runs(inner), [tail]);
+
+            // SAFETY: both ranges were removed above, so the allocator doesn't use them
+            unsafe {
+                inner.hot_add(access, middle).unwrap();
+                inner.hot_add(access, head).unwrap();
+            }
+            ktassert_eq!(free_runs(inner), [memory]);
+        });
+    }
+
+    #[ktest::test]
+    fn test_remove_and_add_range() {
+        let allocator = GLOBAL.get();
+        let usable = || allocator.inner.lock().usable_pages();
+        let range = allocator.allocate_for(4, owner()).unwrap();
+        allocator.deallocate(range).unwrap();
+        let (before, allocated) = (usable(), allocator.allocated_pages());
+
+        allocator.remove_range(range).unwrap();
+        ktassert_eq!(usable(), before - 4);
+        ktassert_eq!(allocator.allocated_pages(), allocated);
+
+        // SAFETY: `range` was just removed, so nothing uses it
+        unsafe { allocator.add_range(range) }.unwrap();
+        ktassert_eq!(usable(), before);
+        ktassert_eq!(allocator.allocated_pages(), allocated);
+    }
+}
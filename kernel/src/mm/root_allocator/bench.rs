@@ -0,0 +1,124 @@
+//! Frame allocator benchmarks.
+//!
+//! These are ktests built with the `bench` feature, which `cargo xtask bench`
+//! runs. Each one times allocate/free cycles on the real root allocator with
+//! the calibrated clock, and reports the result as an
+//! [`alloc_bench`](platypos_ktrace_proto::ALLOC_BENCH_EVENT) event so that
+//! xtask can compare it against a stored baseline.
+
+use alloc::vec::Vec;
+
+use ktest::*;
+use platypos_hal::time::Clock;
+
+use super::*;
+
+/// Allocate/free cycles timed per benchmark
+const OPS: usize = 2000;
+
+platypos_ktrace::schema! {
+    /// Benchmark `bench` timed `ops` operations on blocks of `pages` frames,
+    /// taking `ns_per_op` each on average
+    event alloc_bench(INFO) {
+        bench: str,
+        pages: u64,
+        ops: u64,
+        ns_per_op: u64,
+    }
+}
+
+fn owner() -> Owner {
+    Owner::new(Subsystem::Test)
+}
+
+/// Time `ops` calls to `op` and report the mean
+fn measure(bench: &str, pages: usize, ops: usize, mut op: impl FnMut()) {
+    let clock = &hal_impl::time::INSTANCE;
+    let calibration = clock
+        .calibration()
+        .expect("Benchmarks need a calibrated clock");
+
+    let start = clock.instant();
+    for _ in 0..ops {
+        op();
+    }
+    let elapsed = clock.instant().duration_since(start, calibration);
+
+    let ns_per_op = (elapsed.as_nanos() / ops as u128) as u64;
+    alloc_bench(bench, pages as u64, ops as u64, ns_per_op);
+}
+
+/// Time allocating and immediately freeing blocks of `pages` frames
+fn cycle(bench: &str, pages: usize) {
+    let allocator = GLOBAL.get();
+    measure(bench, pages, OPS, || {
+        let range = allocator.allocate_for(pages, owner()).unwrap();
+        allocator.deallocate(range).unwrap();
+    });
+}
+
+#[ktest::test]
+fn bench_single_frames() {
+    cycle("single", 1);
+}
+
+#[ktest::test]
+fn bench_small_blocks() {
+    cycle("small", 16);
+}
+
+#[ktest::test]
+fn bench_large_blocks() {
+    cycle("large", 512);
+}
+
+#[ktest::test]
+fn bench_huge_2m() {
+    let allocator = GLOBAL.get();
+    if allocator.huge_block_stats().free_2m == 0 {
+        tracing::warn!("No free 2MiB blocks to benchmark");
+        return;
+    }
+    measure("huge-2m", HUGE_2M_PAGES, OPS, || {
+        let range = allocator.allocate_huge_2m(owner()).unwrap();
+        allocator.deallocate(range).unwrap();
+    });
+}
+
+/// Allocate and free two-frame blocks when free memory is chopped into
+/// single-frame holes, so every allocation has to search past them
+#[ktest::test]
+fn bench_fragmented() {
+    let allocator = GLOBAL.get();
+    let frames = (0..512)
+        .map(|_| allocator.allocate_for(1, owner()).unwrap())
+        .collect::<Vec<_>>();
+    let (holes, kept): (Vec<_>, Vec<_>) = frames
+        .into_iter()
+        .enumerate()
+        .partition(|(i, _)| i % 2 == 0);
+    for (_, frame) in holes {
+        allocator.deallocate(frame).unwrap();
+    }
+
+    cycle("fragmented", 2);
+
+    for (_, frame) in kept {
+        allocator.deallocate(frame).unwrap();
+    }
+}
+
+/// Allocate many blocks before freeing any, so the run list grows and frees
+/// have to search it
+#[ktest::test]
+fn bench_batch() {
+    let allocator = GLOBAL.get();
+    let mut ranges = Vec::with_capacity(OPS);
+    measure("batch-allocate", 4, OPS, || {
+        ranges.push(allocator.allocate_for(4, owner()).unwrap());
+    });
+    let mut ranges = ranges.into_iter();
+    measure("batch-free", 4, OPS, || {
+        allocator.deallocate(ranges.next().unwrap()).unwrap();
+    });
+}
@@ -0,0 +1,156 @@
+//! Ownership tracking for allocated page frames, for debugging leaks.
+//!
+//! With the `frame-owners` feature, the root allocator records an [`Owner`]
+//! for every block it hands out in a fixed-size side table. Without it, the
+//! table has no capacity and recording is a no-op, so callers can always pass
+//! owners without any `cfg`s of their own.
+
+use core::fmt;
+
+use crate::prelude::*;
+
+/// Maximum number of allocated blocks whose owners can be tracked
+const MAX_TRACKED: usize = if cfg!(feature = "frame-owners") {
+    1024
+} else {
+    0
+};
+
+/// Which part of the kernel allocated a block of page frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// The allocator wasn't told who the block is for
+    Unknown,
+    Heap,
+    PageTables,
+    Driver,
+    Stack,
+    /// The zero page and private copies of copy-on-write pages
+    Vmm,
+    /// Handed out by [boot memory](crate::mm::bootmem) before the root
+    /// allocator was set up
+    BootMem,
+    Test,
+}
+
+/// The owner of an allocated block of page frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub subsystem: Subsystem,
+    /// Optional address identifying the owner more precisely, like the
+    /// virtual address the block is mapped at or the object it belongs to
+    pub address: Option<usize>,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 8] = [
+        Subsystem::Unknown,
+        Subsystem::Heap,
+        Subsystem::PageTables,
+        Subsystem::Driver,
+        Subsystem::Stack,
+        Subsystem::Vmm,
+        Subsystem::BootMem,
+        Subsystem::Test,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Subsystem::Unknown => "unknown",
+            Subsystem::Heap => "heap",
+            Subsystem::PageTables => "page-tables",
+            Subsystem::Driver => "driver",
+            Subsystem::Stack => "stack",
+            Subsystem::Vmm => "vmm",
+            Subsystem::BootMem => "bootmem",
+            Subsystem::Test => "test",
+        }
+    }
+}
+
+impl Owner {
+    pub const UNKNOWN: Owner = Owner::new(Subsystem::Unknown);
+
+    pub const fn new(subsystem: Subsystem) -> Self {
+        Self {
+            subsystem,
+            address: None,
+        }
+    }
+
+    pub const fn at(subsystem: Subsystem, address: usize) -> Self {
+        Self {
+            subsystem,
+            address: Some(address),
+        }
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.subsystem)?;
+        if let Some(address) = self.address {
+            write!(f, " @ {address:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Side table of allocated blocks and their owners. Only accessed with the
+/// root allocator's lock held.
+pub(super) static TABLE: spin::Mutex<OwnerTable> = spin::Mutex::new(OwnerTable::new());
+
+pub(super) struct OwnerTable {
+    entries: [Option<(PageFrameRange, Owner)>; MAX_TRACKED],
+    /// Whether a block couldn't be recorded because the table was full
+    overflowed: bool,
+}
+
+impl OwnerTable {
+    const fn new() -> Self {
+        Self {
+            entries: [None; MAX_TRACKED],
+            overflowed: false,
+        }
+    }
+
+    /// Record that `range` was allocated for `owner`
+    pub(super) fn insert(&mut self, range: PageFrameRange, owner: Owner) {
+        match self.entries.iter_mut().find(|e| e.is_none()) {
+            Some(entry) => *entry = Some((range, owner)),
+            None if MAX_TRACKED > 0 && !self.overflowed => {
+                tracing::warn!("Frame owner table is full, later allocations won't be tracked");
+                self.overflowed = true;
+            }
+            None => (),
+        }
+    }
+
+    /// Forget the owner of `range`, which was freed
+    pub(super) fn remove(&mut self, range: PageFrameRange) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|e| matches!(e, Some((r, _)) if *r == range))
+        {
+            *entry = None;
+        }
+    }
+
+    /// The owner of the allocated block starting at `start`
+    pub(super) fn owner_of(&self, start: PageFrame) -> Option<Owner> {
+        self.iter()
+            .find(|(range, _)| range.start() == start)
+            .map(|(_, owner)| owner)
+    }
+
+    /// The allocated block containing `frame`, and its owner
+    pub(super) fn containing(&self, frame: PageFrame) -> Option<(PageFrameRange, Owner)> {
+        self.iter()
+            .find(|(range, _)| range.start() <= frame && frame < range.end())
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (PageFrameRange, Owner)> + '_ {
+        self.entries.iter().flatten().copied()
+    }
+}
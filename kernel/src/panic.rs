@@ -1,12 +1,136 @@
+//! Kernel panic handling.
+//!
+//! After reporting a panic, the kernel follows the [`Policy`] passed in the
+//! `opt/platypos/panic-policy` fw_cfg blob (`cargo xtask run --panic-policy`).
+//! Without one, it halts, leaving the panic screen up for debugging. Exiting
+//! and rebooting go through the usual [shutdown hooks](crate::power), so
+//! trace output is flushed and other processors are parked first.
+//!
+//! Before stopping, the kernel sends a [`CrashReport`], so the host can render
+//! the panic and tell that the kernel crashed rather than hung. The report is
+//! also saved in the [crash dump](crate::crash_dump), in case nothing was
+//! listening.
+
 use core::alloc::Layout;
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use mini_backtrace::Backtrace;
+use platypos_hal::power::ExitCode;
+use platypos_hal::time::Clock;
+use platypos_ktrace::proto::{CrashLocation, CrashReport, CrashSource, Words, CRASH_REGISTERS};
+
+use crate::arch::hal_impl;
+use crate::arch::hal_impl::context::Context;
+use crate::arch::hal_impl::interrupts::Ipi;
+use crate::drivers::fw_cfg;
+use crate::power;
 
 const BACKTRACE_DEPTH: usize = 16;
 
+/// Longest panic message sent in a crash report. Longer ones are cut off.
+const MAX_REASON_LEN: usize = 256;
+
+/// fw_cfg blob with the panic policy, as `halt`, `exit`, or `reboot=SECONDS`
+const POLICY_BLOB: &str = "opt/platypos/panic-policy";
+
+/// Longest reboot delay, in seconds. Longer ones don't fit in the encoded
+/// policy, and would never end anyway.
+const MAX_REBOOT_DELAY: u64 = u64::MAX - 2;
+
+/// What to do once a panic is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Halt with the panic screen up, so the machine can be inspected
+    Halt,
+    /// Exit QEMU with [`ExitCode::Panic`], for automated runs. On real
+    /// hardware, this halts instead.
+    Exit,
+    /// Reset the machine after a delay, in seconds, for unattended soak tests
+    Reboot(u64),
+}
+
+impl Policy {
+    /// Parse a policy like `halt`, `exit`, or `reboot=10`. The reboot delay is
+    /// optional, and defaults to none. Delays over [`MAX_REBOOT_DELAY`] are
+    /// rejected.
+    fn parse(s: &str) -> Option<Policy> {
+        match s.trim().split_once('=') {
+            None => match s.trim() {
+                "halt" => Some(Policy::Halt),
+                "exit" => Some(Policy::Exit),
+                "reboot" => Some(Policy::Reboot(0)),
+                _ => None,
+            },
+            Some(("reboot", delay)) => {
+                let delay = delay.trim().parse().ok()?;
+                (delay <= MAX_REBOOT_DELAY).then_some(Policy::Reboot(delay))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Pack the policy into a `u64` for [`POLICY`]
+    ///
+    /// # Panics
+    /// If the reboot delay is over [`MAX_REBOOT_DELAY`]
+    const fn encode(self) -> u64 {
+        match self {
+            Policy::Halt => 0,
+            Policy::Exit => 1,
+            Policy::Reboot(delay) => {
+                assert!(delay <= MAX_REBOOT_DELAY, "reboot delay is too long");
+                delay + 2
+            }
+        }
+    }
+
+    const fn decode(raw: u64) -> Policy {
+        match raw {
+            0 => Policy::Halt,
+            1 => Policy::Exit,
+            delay => Policy::Reboot(delay - 2),
+        }
+    }
+}
+
+/// The current policy, encoded so it can be read without locking while
+/// panicking
+static POLICY: AtomicU64 = AtomicU64::new(Policy::Halt.encode());
+
+/// Set what to do after a panic
+///
+/// # Panics
+/// If the reboot delay is over [`MAX_REBOOT_DELAY`]
+pub fn set_policy(policy: Policy) {
+    POLICY.store(policy.encode(), Ordering::Relaxed);
+}
+
+pub fn policy() -> Policy {
+    Policy::decode(POLICY.load(Ordering::Relaxed))
+}
+
+/// Apply the panic policy passed through fw_cfg, if there is one. This must
+/// be called after [`fw_cfg::init`].
+pub fn configure() {
+    let Ok(blob) = fw_cfg::read(POLICY_BLOB) else {
+        return;
+    };
+    match core::str::from_utf8(&blob).ok().and_then(Policy::parse) {
+        Some(policy) => {
+            set_policy(policy);
+            tracing::info!("Panic policy: {:?}", policy);
+        }
+        None => tracing::warn!("Ignoring malformed panic policy"),
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // If tracing isn't set up yet, this is the only way to report the panic
+    crate::earlycon::early_println!("KERNEL PANIC: {}", info);
+
     crate::trace::flush();
     let span = tracing::error_span!("panic").entered();
 
@@ -22,12 +146,174 @@ fn panic(info: &PanicInfo) -> ! {
         tracing::error!("... <frames omitted>");
     }
 
+    crate::fault::on_panic();
+
     span.exit(); // Close the span before spin-looping
+
+    #[cfg(test)]
+    if let Some(test) = ktest::current_test() {
+        crate::trace::flush();
+        // SAFETY: this is the panic handler, running on the panicking stack
+        unsafe { ktest::recover_from_panic(info) };
+
+        // Recovery failed, so stop running tests
+        tracing::error!("{}... FAIL (panicked)", test);
+        crate::trace::flush();
+        ktest::exit(false);
+    }
+
+    // Stop other processors, so they can't make things worse or draw over the panic screen
+    crate::arch::hal_impl::interrupts::broadcast_ipi(Ipi::PanicStop);
+
+    report_crash(info, &bt);
+    let policy = policy();
+    crate::screen::draw_panic(info, &bt.frames, policy);
+    match policy {
+        Policy::Halt => hal_impl::fatal_error(),
+        Policy::Exit => power::shutdown(ExitCode::Panic),
+        Policy::Reboot(delay) => {
+            wait(delay);
+            power::reboot();
+        }
+    }
+}
+
+/// Send the host a crash report for the panic, and save it in the crash dump.
+/// Recovered test panics aren't crashes, so this is only called once the
+/// kernel is going down.
+fn report_crash(info: &PanicInfo, bt: &Backtrace<BACKTRACE_DEPTH>) {
+    let mut reason = Truncated::default();
+    let _ = write!(reason, "{}", info.message());
+
+    let mut report = CrashReport::new(CrashSource::Kernel, reason.as_str());
+    report.location = info.location().map(|location| CrashLocation {
+        file: location.file(),
+        line: location.line(),
+        column: location.column(),
+    });
+    let Context {
+        rax,
+        rbx,
+        rcx,
+        rdx,
+        rsi,
+        rdi,
+        rbp,
+        rsp,
+        r8,
+        r9,
+        r10,
+        r11,
+        r12,
+        r13,
+        r14,
+        r15,
+        rip,
+        rflags,
+    } = Context::capture();
+    let registers = [
+        rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip, rflags,
+    ];
+    let mut registers_buf = [0; CRASH_REGISTERS.len() * 8];
+    report.registers = Words::encode(registers, &mut registers_buf);
+    let mut backtrace_buf = [0; BACKTRACE_DEPTH * 8];
+    let frames = bt.frames.iter().map(|&frame| frame as u64);
+    report.backtrace = Words::encode(frames, &mut backtrace_buf);
+    report.frames_omitted = bt.frames_omitted;
+    report.build_id = crate::build_id::build_id();
+
+    platypos_ktrace::report_crash(&report);
+    // Flush first, so the dump has the panic message and backtrace
     crate::trace::flush();
-    crate::arch::hal_impl::fatal_error();
+    crate::crash_dump::write(&report);
+}
+
+/// A fixed-size string that drops whatever doesn't fit, since the allocator
+/// may be what panicked
+struct Truncated {
+    bytes: [u8; MAX_REASON_LEN],
+    len: usize,
+}
+
+impl Default for Truncated {
+    fn default() -> Self {
+        Self {
+            bytes: [0; MAX_REASON_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl Truncated {
+    fn as_str(&self) -> &str {
+        // Only whole characters are copied in
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for Truncated {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(MAX_REASON_LEN - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Busy-wait for `seconds`, or not at all if the clock isn't calibrated. This
+/// can't use the scheduler, which may be what panicked.
+fn wait(seconds: u64) {
+    let clock = &hal_impl::time::INSTANCE;
+    let Some(frequency) = clock.frequency() else {
+        return;
+    };
+    let deadline = clock
+        .now()
+        .saturating_add(seconds.saturating_mul(frequency));
+    while clock.now() < deadline {
+        core::hint::spin_loop();
+    }
 }
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("memory allocation of {} bytes failed", layout.size());
 }
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_parse_policy() {
+        ktassert_eq!(Policy::parse("halt"), Some(Policy::Halt));
+        ktassert_eq!(Policy::parse("exit\n"), Some(Policy::Exit));
+        ktassert_eq!(Policy::parse("reboot"), Some(Policy::Reboot(0)));
+        ktassert_eq!(Policy::parse("reboot=30"), Some(Policy::Reboot(30)));
+        ktassert_eq!(Policy::parse("reboot=soon"), None);
+        ktassert_eq!(Policy::parse("exit=1"), None);
+        ktassert_eq!(Policy::parse("reboot=18446744073709551615"), None);
+
+        for policy in [
+            Policy::Halt,
+            Policy::Exit,
+            Policy::Reboot(0),
+            Policy::Reboot(30),
+        ] {
+            ktassert_eq!(Policy::decode(policy.encode()), policy);
+        }
+        let longest = Policy::Reboot(MAX_REBOOT_DELAY);
+        ktassert_eq!(Policy::decode(longest.encode()), longest);
+    }
+
+    #[ktest::test]
+    #[should_panic(expected = "reboot delay is too long")]
+    fn test_encode_rejects_long_delay() {
+        Policy::Reboot(u64::MAX).encode();
+    }
+}
@@ -0,0 +1,136 @@
+//! Orderly shutdown and reboot.
+//!
+//! Subsystems register teardown work with [`shutdown_hook!`], tagged with the
+//! [`Stage`] they belong to. Before the machine exits or resets, the hooks run
+//! in the reverse of the order those subsystems were initialized in: other
+//! processors are parked and the scheduler tick stopped before interrupts are
+//! disabled, and trace output is flushed last, once nothing else can add to
+//! it. Exiting abruptly instead loses whatever was still queued, like the
+//! final test results.
+//!
+//! The test runner exits through the same hooks, via
+//! [`ktest::EXIT_HOOKS`](ktest::EXIT_HOOKS).
+//!
+//! Hooks may run with other processors still going and with the scheduler in
+//! any state, so they mustn't block or allocate.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use platypos_hal::linkme::distributed_slice;
+use platypos_hal::power::{exit_vm, ExitCode};
+
+use crate::arch::hal_impl::{self, power as platform};
+
+/// Subsystems with shutdown work, in the order they're initialized. Later
+/// stages depend on earlier ones, so they're shut down first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Trace output, which is needed until the very end
+    Tracing,
+    /// Interrupt handling on the current processor
+    Interrupts,
+    /// Timer interrupts, like the scheduler tick
+    Timers,
+    /// Every processor other than the one shutting down
+    Processors,
+}
+
+impl Stage {
+    const ALL: [Stage; 4] = [
+        Stage::Tracing,
+        Stage::Interrupts,
+        Stage::Timers,
+        Stage::Processors,
+    ];
+}
+
+/// Teardown work for a subsystem, registered with [`shutdown_hook!`]
+pub struct ShutdownHook {
+    /// What's being shut down, for logging
+    pub name: &'static str,
+    pub stage: Stage,
+    pub run: fn(),
+}
+
+/// Every registered shutdown hook
+#[distributed_slice]
+#[linkme(crate = platypos_hal::linkme)]
+pub static SHUTDOWN_HOOKS: [ShutdownHook] = [..];
+
+/// Register `$run` to be called at shutdown, in [`Stage`] `$stage`. `$name`
+/// identifies it in logs, like `"scheduler tick"`.
+macro_rules! shutdown_hook {
+    ($stage:ident, $name:literal, $run:expr) => {
+        const _: () = {
+            #[::platypos_hal::linkme::distributed_slice($crate::power::SHUTDOWN_HOOKS)]
+            #[linkme(crate = ::platypos_hal::linkme)]
+            static HOOK: $crate::power::ShutdownHook = $crate::power::ShutdownHook {
+                name: $name,
+                stage: $crate::power::Stage::$stage,
+                run: $run,
+            };
+        };
+    };
+}
+
+pub(crate) use shutdown_hook;
+
+/// Set once shutdown hooks have started running
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Run every shutdown hook, latest stage first. Only the first call does
+/// anything, so every way out of the kernel can call this.
+pub fn run_shutdown_hooks() {
+    if SHUTTING_DOWN.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    for stage in Stage::ALL.into_iter().rev() {
+        for hook in SHUTDOWN_HOOKS.iter().filter(|hook| hook.stage == stage) {
+            tracing::debug!("Shutting down {}", hook.name);
+            (hook.run)();
+        }
+    }
+}
+
+/// Shut down cleanly and exit QEMU with `code`. There's no ACPI support to
+/// power off real hardware, so outside of QEMU this halts instead.
+pub fn shutdown(code: ExitCode) -> ! {
+    tracing::info!("Shutting down");
+    run_shutdown_hooks();
+    exit_vm(code);
+    hal_impl::fatal_error();
+}
+
+/// Shut down cleanly and reset the machine
+pub fn reboot() -> ! {
+    tracing::info!("Rebooting");
+    run_shutdown_hooks();
+    platform::reboot();
+}
+
+#[cfg(test)]
+#[distributed_slice(ktest::EXIT_HOOKS)]
+#[linkme(crate = platypos_hal::linkme)]
+static TEST_EXIT: fn() = run_shutdown_hooks;
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_hooks_registered() {
+        let stage_of = |name| {
+            SHUTDOWN_HOOKS
+                .iter()
+                .find(|hook| hook.name == name)
+                .map(|hook| hook.stage)
+        };
+        ktassert_eq!(stage_of("trace output"), Some(Stage::Tracing));
+        ktassert_eq!(stage_of("other processors"), Some(Stage::Processors));
+        // Trace output is flushed after everything else
+        ktassert!(Stage::ALL.iter().all(|&stage| stage >= Stage::Tracing));
+    }
+}
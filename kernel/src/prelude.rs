@@ -3,12 +3,14 @@
 pub use core::fmt::Write;
 
 pub use crate::arch::PAGE_SIZE;
-pub use crate::error::{Error, ErrorKind};
+pub use crate::error::{DriverError, Error, IoError, KError, MmError, ResultExt, SchedError};
 pub use crate::mm::{
     ByteSizeExt, Page, PageFrame, PageFrameRange, PageRange, PhysicalAddress, PhysicalAddressRange,
     VirtualAddress, VirtualAddressRange,
 };
 
+pub(crate) use crate::error::{kassert, kensure};
+
 pub use sptr::Strict;
 
 pub use crate::arch::hal_impl;
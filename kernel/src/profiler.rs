@@ -0,0 +1,271 @@
+//! Sampling profiler.
+//!
+//! Every APIC timer tick hands what it interrupted to [`sample`], which records
+//! every so many of them in the current processor's ring: the interrupted
+//! instruction pointer, and optionally a few return addresses found by walking
+//! frame pointers. A drain thread sends the samples to the host as ktrace
+//! messages, and `cargo xtask trace` turns them into a flat profile and a
+//! flamegraph.
+//!
+//! Profiling is off unless the `opt/platypos/profile` fw_cfg blob turns it on
+//! (`cargo xtask trace --profile-rate`), as `rate=HZ,depth=N`. Backtraces need a
+//! kernel built with frame pointers, so the depth defaults to 0.
+//!
+//! Samples are only taken on the scheduler tick, which only runs on the
+//! bootstrap processor. Code that runs with interrupts disabled is never
+//! sampled, and its time is charged to wherever it turns them back on. If a
+//! ring fills up before it's drained, new samples are counted and dropped, and
+//! the count is reported to the host.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use platypos_hal::channel::spsc::Channel;
+use platypos_hal::interrupts::ExecutionContext;
+use platypos_hal::topology::{self, Topology as _};
+
+use crate::arch::hal_impl::interrupts::{self, Interrupted};
+use crate::drivers::fw_cfg;
+use crate::prelude::*;
+use crate::sched::{self, Priority};
+
+/// fw_cfg blob with the sampling settings, as `rate=HZ,depth=N`
+const PROFILE_BLOB: &str = "opt/platypos/profile";
+
+/// Samples each processor's ring holds
+const RING_LEN: usize = 32;
+
+/// Most frames in a sample, including the instruction pointer
+const MAX_FRAMES: usize = 8;
+
+/// How often the rings are drained. At the highest rate, one sample per tick,
+/// a ring fills in [`RING_LEN`] ticks.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+const MAX_PROCESSORS: usize = hal_impl::topology::Topology::MAX_PROCESSORS as usize;
+
+/// Ticks between samples, or 0 if profiling is off
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+
+/// Return addresses to collect after the instruction pointer
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+static RINGS: [Ring; MAX_PROCESSORS] = [const { Ring::new() }; MAX_PROCESSORS];
+
+/// How often to sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Settings {
+    /// Samples per second
+    rate: u32,
+    /// Return addresses to walk per sample
+    depth: usize,
+}
+
+impl Settings {
+    /// Parse settings like `rate=100,depth=4`. The depth is optional.
+    fn parse(s: &str) -> Option<Settings> {
+        let mut rate = None;
+        let mut depth = 0;
+        for field in s.trim().split(',') {
+            match field.split_once('=')? {
+                ("rate", value) => rate = Some(value.trim().parse().ok()?),
+                ("depth", value) => depth = value.trim().parse().ok()?,
+                _ => return None,
+            }
+        }
+        Some(Settings { rate: rate?, depth })
+    }
+}
+
+struct Sample {
+    context: ExecutionContext,
+    /// Frames in `stack` that were filled in
+    len: usize,
+    /// The instruction pointer, then return addresses, innermost first
+    stack: [u64; MAX_FRAMES],
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        context: ExecutionContext::Task,
+        len: 0,
+        stack: [0; MAX_FRAMES],
+    };
+
+    fn frames(&self) -> &[u64] {
+        &self.stack[..self.len]
+    }
+}
+
+/// One processor's samples. Only that processor's timer interrupt pushes, and
+/// only the drain thread pops.
+struct Ring {
+    samples: Channel<Sample, RING_LEN>,
+    /// Ticks left until the next sample
+    countdown: AtomicU32,
+    /// Samples dropped since the last report
+    dropped: AtomicU64,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            samples: Channel::new(),
+            countdown: AtomicU32::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a sample filled in by `fill`, or count a dropped sample if the
+    /// ring is full. Only one context may push to a ring.
+    fn push(&self, fill: impl FnOnce(&mut Sample)) {
+        let mut sample = Sample::EMPTY;
+        fill(&mut sample);
+        // SAFETY: only one context pushes
+        let mut producer = unsafe { self.samples.producer() };
+        if producer.push(sample).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Pass queued samples to `send`, oldest first, until it returns `false`
+    /// or the ring is empty. Only one context may pop from a ring.
+    fn pop_while(&self, mut send: impl FnMut(&Sample) -> bool) {
+        // SAFETY: only one context pops
+        let mut consumer = unsafe { self.samples.consumer() };
+        while let Some(sample) = consumer.peek() {
+            if !send(sample) {
+                break;
+            }
+            consumer.pop();
+        }
+    }
+}
+
+/// Start profiling, if the profile blob asks for it. This must be called
+/// after [`fw_cfg::init`] and after the scheduler is initialized.
+pub fn init() {
+    let Ok(blob) = fw_cfg::read(PROFILE_BLOB) else {
+        return;
+    };
+    let Some(settings) = core::str::from_utf8(&blob).ok().and_then(Settings::parse) else {
+        tracing::warn!("Ignoring malformed profiler settings");
+        return;
+    };
+    if settings.rate == 0 {
+        return;
+    }
+
+    let period = (sched::CLOCK.ticks_per_second() / u64::from(settings.rate)).max(1);
+    let depth = settings.depth.min(MAX_FRAMES - 1);
+    DEPTH.store(depth, Ordering::Relaxed);
+    sched::spawn(Priority::Normal, drain()).expect("Could not start the profiler");
+    interrupts::set_sample_hook(sample);
+    PERIOD.store(period.try_into().unwrap_or(u32::MAX), Ordering::Release);
+    tracing::info!("Profiling every {period} ticks, with {depth} return addresses per sample");
+}
+
+/// Sample hook, run by every APIC timer interrupt
+fn sample(interrupted: &Interrupted) {
+    let period = PERIOD.load(Ordering::Acquire);
+    if period == 0 {
+        return;
+    }
+    let processor = hal_impl::topology::INSTANCE.current_processor();
+    let ring = &RINGS[usize::from(processor)];
+
+    let countdown = ring.countdown.load(Ordering::Relaxed);
+    if countdown > 1 {
+        ring.countdown.store(countdown - 1, Ordering::Relaxed);
+        return;
+    }
+    ring.countdown.store(period, Ordering::Relaxed);
+
+    let depth = DEPTH.load(Ordering::Relaxed);
+    ring.push(|sample| {
+        sample.context = interrupted.context;
+        sample.stack[0] = interrupted.instruction_pointer;
+        let returns = match depth {
+            0 => 0,
+            depth => interrupted.backtrace(&mut sample.stack[1..=depth]),
+        };
+        sample.len = 1 + returns;
+    });
+}
+
+/// Send every processor's samples to the host. Samples that don't fit in the
+/// ktrace queue stay in their ring until the next pass.
+async fn drain() {
+    loop {
+        sched::sleep(DRAIN_INTERVAL).await;
+        for processor in topology::online_processors().iter() {
+            let ring = &RINGS[usize::from(processor)];
+            ring.pop_while(|sample| {
+                platypos_ktrace::send_profile_sample(
+                    processor.into(),
+                    sample.context,
+                    sample.frames(),
+                )
+            });
+
+            let dropped = ring.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 && !platypos_ktrace::report_dropped_samples(processor.into(), dropped) {
+                ring.dropped.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_parse_settings() {
+        ktassert_eq!(
+            Settings::parse("rate=100,depth=4"),
+            Some(Settings {
+                rate: 100,
+                depth: 4
+            })
+        );
+        ktassert_eq!(
+            Settings::parse("rate=10\n"),
+            Some(Settings { rate: 10, depth: 0 })
+        );
+        ktassert_eq!(Settings::parse("depth=4"), None);
+        ktassert_eq!(Settings::parse("rate=fast"), None);
+    }
+
+    #[ktest::test]
+    fn test_ring() {
+        let ring = Ring::new();
+        for address in 0..RING_LEN as u64 + 2 {
+            ring.push(|sample| {
+                sample.stack[0] = address;
+                sample.len = 1;
+            });
+        }
+        ktassert_eq!(ring.dropped.load(Ordering::Relaxed), 2);
+
+        // A sample that isn't sent stays queued
+        let mut sent = Vec::new();
+        ring.pop_while(|sample| {
+            sent.push(sample.frames()[0]);
+            sent.len() < 3
+        });
+        ktassert_eq!(sent, [0, 1, 2]);
+
+        sent.clear();
+        ring.pop_while(|sample| {
+            sent.push(sample.frames()[0]);
+            true
+        });
+        ktassert_eq!(sent.len(), RING_LEN - 2);
+        ktassert_eq!(sent[0], 2);
+    }
+}
@@ -0,0 +1,187 @@
+//! Ownership of I/O ports and MMIO ranges.
+//!
+//! Drivers claim the port ranges and physical MMIO ranges they drive before
+//! touching them, like `resources::claim_ports(0x3f8..0x400, "com1")`. A claim
+//! that overlaps one made by a different driver fails with
+//! [`DriverError::ResourceConflict`], so two drivers fighting over the same
+//! hardware show up as an error at initialization rather than as a device that
+//! behaves strangely. [`write_report`] lists every claim.
+//!
+//! Claims are kept in a fixed-size table, so they can be made before the heap
+//! is available. Drivers don't unload, so claims are never released.
+
+use core::fmt;
+use core::ops::Range;
+
+use alloc::string::String;
+
+use crate::prelude::*;
+
+/// Maximum number of claims
+const MAX_CLAIMS: usize = 64;
+
+static REGISTRY: spin::Mutex<Registry> = spin::Mutex::new(Registry::new());
+
+/// A hardware resource that a driver can claim
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    /// A range of I/O ports
+    Ports(Range<u16>),
+    /// A range of memory-mapped registers
+    Mmio(PhysicalAddressRange),
+}
+
+/// A resource and the driver that claimed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claim {
+    pub resource: Resource,
+    pub owner: &'static str,
+}
+
+struct Registry {
+    claims: [Option<Claim>; MAX_CLAIMS],
+}
+
+/// Claim the I/O ports in `ports` for `owner`
+pub fn claim_ports(ports: Range<u16>, owner: &'static str) -> Result<(), Error> {
+    REGISTRY.lock().claim(Resource::Ports(ports), owner)
+}
+
+/// Claim the MMIO registers in `range` for `owner`
+pub fn claim_mmio(range: PhysicalAddressRange, owner: &'static str) -> Result<(), Error> {
+    REGISTRY.lock().claim(Resource::Mmio(range), owner)
+}
+
+/// Claim the legacy PC hardware that the HAL drives itself, which has no
+/// kernel driver to claim it
+pub fn claim_platform() -> Result<(), Error> {
+    claim_ports(0x20..0x22, "pic1")?;
+    claim_ports(0xa0..0xa2, "pic2")?;
+    claim_ports(0x40..0x44, "pit")?;
+    // The PIT's channel 2 gate, which timer calibration uses
+    claim_ports(0x61..0x62, "pit")?;
+    let apic = hal_impl::interrupts::local_apic_base() as usize;
+    claim_mmio(
+        PhysicalAddressRange::from_start_size(PhysicalAddress::new(apic), PAGE_SIZE),
+        "local-apic",
+    )
+}
+
+impl Resource {
+    /// The resource's bounds, as a half-open range
+    fn bounds(&self) -> Range<usize> {
+        match self {
+            Resource::Ports(ports) => usize::from(ports.start)..usize::from(ports.end),
+            Resource::Mmio(range) => range.start().as_usize()..range.end().as_usize(),
+        }
+    }
+
+    /// Whether this resource and `other` share any ports or addresses
+    fn overlaps(&self, other: &Resource) -> bool {
+        let (a, b) = (self.bounds(), other.bounds());
+        let same_space = matches!(
+            (self, other),
+            (Resource::Ports(_), Resource::Ports(_)) | (Resource::Mmio(_), Resource::Mmio(_))
+        );
+        same_space && a.start < b.end && b.start < a.end
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::Ports(ports) => write!(f, "ports {:#06x}-{:#06x}", ports.start, ports.end),
+            Resource::Mmio(range) => write!(f, "MMIO {range}"),
+        }
+    }
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            claims: [const { None }; MAX_CLAIMS],
+        }
+    }
+
+    /// Claim `resource` for `owner`. Overlapping claims by the same owner are
+    /// allowed, so a driver can claim its registers piecemeal.
+    #[track_caller]
+    fn claim(&mut self, resource: Resource, owner: &'static str) -> Result<(), Error> {
+        kensure!(
+            !resource.bounds().is_empty(),
+            DriverError::Unsupported,
+            "claiming an empty resource range"
+        );
+        if let Some(existing) = self
+            .iter()
+            .find(|c| c.owner != owner && c.resource.overlaps(&resource))
+        {
+            tracing::warn!(
+                "{owner} tried to claim {resource}, which overlaps {} owned by {}",
+                existing.resource,
+                existing.owner
+            );
+            return Err(Error::new(DriverError::ResourceConflict));
+        }
+
+        let slot = self.claims.iter_mut().find(|c| c.is_none());
+        let Some(slot) = slot else {
+            return Err(Error::new(DriverError::TooManyResources));
+        };
+        *slot = Some(Claim { resource, owner });
+        Ok(())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Claim> {
+        self.claims.iter().flatten()
+    }
+}
+
+/// Write a table of every claimed resource and its owner
+pub fn write_report(w: &mut impl Write) -> fmt::Result {
+    let registry = REGISTRY.lock();
+    for claim in registry.iter() {
+        writeln!(w, "{:<16} {}", claim.owner, claim.resource)?;
+    }
+    Ok(())
+}
+
+/// Log the resource table. This is bound to F5 by [`debug_keys`].
+///
+/// [`debug_keys`]: crate::debug_keys
+pub fn dump() {
+    let mut table = String::new();
+    let _ = write_report(&mut table);
+    tracing::info!("Claimed resources:\n{table}");
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_conflicts() {
+        let mut registry = Registry::new();
+        let err = registry
+            .claim(Resource::Ports(0x60..0x60), "empty")
+            .unwrap_err();
+        ktassert_eq!(err.kind(), KError::Driver(DriverError::Unsupported));
+
+        let mut claim = |resource, owner| registry.claim(resource, owner).is_ok();
+        ktassert!(claim(Resource::Ports(0x3f8..0x400), "com1"));
+        ktassert!(claim(Resource::Ports(0x2f8..0x300), "com2"));
+        // Adjacent ranges don't conflict, but overlapping ones do
+        ktassert!(claim(Resource::Ports(0x400..0x408), "other"));
+        ktassert!(!claim(Resource::Ports(0x3fc..0x3fd), "other"));
+        // The same owner can claim its own ports again
+        ktassert!(claim(Resource::Ports(0x3f8..0x3f9), "com1"));
+
+        // Ports and MMIO are separate address spaces
+        let mmio = PhysicalAddressRange::from_start_size(PhysicalAddress::new(0x3f8), 8);
+        ktassert!(claim(Resource::Mmio(mmio), "mmio"));
+        let err = registry.claim(Resource::Mmio(mmio), "other").unwrap_err();
+        ktassert_eq!(err.kind(), KError::Driver(DriverError::ResourceConflict));
+    }
+}
@@ -0,0 +1,612 @@
+//! Kernel thread scheduler.
+//!
+//! Kernel threads are futures, scheduled cooperatively on the boot processor.
+//! Each thread belongs to one of a small, fixed set of [`Priority`] classes.
+//! The scheduler always runs a thread from the highest-priority class that has
+//! one runnable, and runs threads within a class round-robin.
+//!
+//! A thread blocks by returning `Poll::Pending`, and becomes runnable again
+//! when it's [woken](wake). Since interrupt handlers need to wake threads,
+//! waking doesn't take any locks: it sets the thread's bit in a wake bitmap,
+//! which the scheduler drains at each scheduling point. Thread IDs are reused,
+//! so a stale wakeup may spuriously poll a newer thread, which futures already
+//! have to tolerate.
+//!
+//! [`sleep_until`] parks the current thread on a timer wheel, which advances
+//! with the APIC timer tick. Ticks are only nominally [`TICK`] apart, so code
+//! that needs elapsed time rather than tick counts should use [`uptime`],
+//! which is [corrected for drift](drift). Threads waiting for a condition
+//! rather than a time, like a driver waiting for a free request slot, block on
+//! a [wait queue](wait).
+//!
+//! Threads have a few slots of [task-local storage](local), which are swapped
+//! in while they're polled. Each thread gets its own ktrace context this way,
+//! so that spans it's suspended in don't leak into other threads.
+//!
+//! Every context switch and thread state change is traced, with raw clock
+//! ticks as timestamps, so the decoder can work out per-thread CPU time and
+//! scheduling latency once it has the clock's calibration.
+//! Threads are identified by their ktrace context in these events, since
+//! unlike [`Thread`] IDs, contexts aren't reused.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use platypos_common::sync::Global;
+use platypos_hal::interrupts::Controller;
+use platypos_hal::time::{Calibration, Clock, Instant};
+use platypos_hal::topology::{self, ProcessorState, Topology as _};
+
+use crate::boot_phase::InterruptsReady;
+use crate::boot_time::{self, Phase};
+use crate::milestone::{self, Milestone};
+use crate::prelude::*;
+use crate::trace;
+
+use self::local::Locals;
+use self::timer::TimerWheel;
+
+pub use self::drift::uptime;
+
+pub mod drift;
+pub mod local;
+mod timer;
+pub mod wait;
+
+/// Interval between scheduler ticks
+pub const TICK: Duration = Duration::from_millis(1);
+
+/// Rate of the scheduler clock, for converting its [`Instant`]s
+pub const CLOCK: Calibration = Calibration::from_period(TICK);
+
+/// Maximum number of threads that can exist at once
+const MAX_THREADS: usize = 256;
+
+/// Handle to a kernel thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thread(u16);
+
+/// Scheduling classes, from highest to lowest priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Deferred interrupt processing, which should run as soon as possible
+    BottomHalf,
+    Normal,
+    /// Only runs when nothing else is runnable
+    Idle,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// The event names must match `CONTEXT_SWITCH_EVENT` and `THREAD_STATE_EVENT` in the ktrace
+// protocol, which the decoder uses to find them
+platypos_ktrace::schema! {
+    /// The scheduler switched from thread context `prev` to `next`, where 0
+    /// means the scheduler was idle. `reason` is why `prev` stopped running.
+    event context_switch(TRACE) {
+        prev: u64,
+        next: u64,
+        reason: str,
+        depth: u64,
+        ticks: u64,
+    }
+
+    /// A thread became `runnable`, `blocked`, or `exited`
+    event thread_state(TRACE) {
+        thread: u64,
+        state: str,
+        ticks: u64,
+    }
+}
+
+struct Scheduler {
+    /// Threads, indexed by ID
+    threads: Vec<Option<ThreadState>>,
+    run_queues: [VecDeque<u16>; Priority::COUNT],
+    timers: TimerWheel,
+    /// Where wakeups for this scheduler's threads are posted
+    wakeups: &'static Wakeups,
+}
+
+/// Bitmap of threads with pending wakeups
+struct Wakeups([AtomicU64; MAX_THREADS / 64]);
+
+struct ThreadState {
+    priority: Priority,
+    /// The thread's future, or `None` while it's being polled
+    future: Option<BoxFuture>,
+    /// Whether the thread is already in its run queue
+    queued: bool,
+    /// Task-local storage, while the thread isn't being polled
+    locals: Locals,
+}
+
+/// A thread picked to run next
+struct Next {
+    id: u16,
+    future: BoxFuture,
+    locals: Locals,
+    /// Number of threads still waiting in run queues
+    depth: usize,
+}
+
+static SCHEDULER: Global<InterruptSafeMutex<'static, Scheduler>> = Global::new();
+
+/// Number of ticks since the scheduler started
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static WAKEUPS: Wakeups = Wakeups::new();
+
+/// Initialize the scheduler. Threads can be spawned afterwards, but don't run
+/// until [`run`] is called.
+pub fn init(controller: &'static hal_impl::interrupts::Controller) {
+    SCHEDULER.init(InterruptSafeMutex::new(
+        controller,
+        Scheduler::new(&WAKEUPS),
+    ));
+}
+
+/// Advance the scheduler clock. This is called from the timer interrupt every
+/// [`TICK`].
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    drift::advance();
+}
+
+/// The current time on the scheduler clock, which starts at [`Instant::ZERO`]
+/// and advances every [`TICK`]
+pub fn now() -> Instant {
+    Instant::from_ticks(TICKS.load(Ordering::Relaxed))
+}
+
+/// The tick `duration` from now, rounded up
+pub fn deadline_after(duration: Duration) -> Instant {
+    now() + CLOCK.to_ticks(duration)
+}
+
+/// Start a new thread running `future`
+pub fn spawn<F>(priority: Priority, future: F) -> Result<Thread, Error>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    spawn_with(priority, Locals::new(), future)
+}
+
+/// Start a new thread running `future`, with initial task-local values. If
+/// `locals` doesn't set a trace context, the thread gets a new one.
+pub fn spawn_with<F>(priority: Priority, mut locals: Locals, future: F) -> Result<Thread, Error>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    if locals.get(&trace::CONTEXT) == 0 {
+        locals = locals.with(&trace::CONTEXT, trace::new_context());
+    }
+    SCHEDULER
+        .get()
+        .lock()
+        .insert(priority, locals, Box::pin(future))
+}
+
+/// Make `thread` runnable. This is lock-free, so it's safe to call from
+/// interrupt handlers.
+pub fn wake(thread: Thread) {
+    WAKEUPS.set(thread.0);
+}
+
+/// Run scheduled threads forever, halting the processor while there's nothing
+/// to do. This needs the local APIC, since its timer drives the tick.
+pub fn run(controller: &'static hal_impl::interrupts::Controller, _: &InterruptsReady) -> ! {
+    let current = hal_impl::topology::INSTANCE.current_processor();
+    assert_eq!(
+        topology::processor_state(current),
+        ProcessorState::Online,
+        "scheduler started on a processor that isn't online"
+    );
+    milestone::reached(Milestone::SchedulerStarted);
+
+    let scheduler = SCHEDULER.get();
+    let mut first_task = true;
+    // Context of the thread that ran last, and why it stopped
+    let mut previous: Option<(u64, &'static str)> = None;
+    loop {
+        let next = scheduler.lock().next();
+        let Some(Next {
+            id,
+            mut future,
+            locals,
+            depth,
+        }) = next
+        else {
+            if let Some((prev, reason)) = previous.take() {
+                context_switch(prev, 0, reason, 0, timestamp());
+            }
+            trace::poll();
+
+            // Make sure a wakeup can't sneak in between checking and halting. The next timer tick
+            // wakes the processor even if no other interrupts arrive.
+            let _guard = controller.disable();
+            if !WAKEUPS.pending() {
+                controller.wait();
+            }
+            continue;
+        };
+
+        if first_task {
+            first_task = false;
+            boot_time::record(Phase::FirstTask);
+            boot_time::report();
+        }
+
+        let context = locals.get(&trace::CONTEXT);
+        let (prev, reason) = previous.unwrap_or((0, "idle"));
+        context_switch(prev, context, reason, depth as u64, timestamp());
+
+        let waker = waker_for(id);
+        let mut cx = Context::from_waker(&waker);
+        locals.load();
+        trace::switch_context(trace::CONTEXT.get());
+        let done = future.as_mut().poll(&mut cx).is_ready();
+        trace::switch_context(0);
+        let locals = Locals::take();
+        previous = Some((context, if done { "exited" } else { "blocked" }));
+        scheduler.lock().finish(id, future, locals, done);
+    }
+}
+
+/// Wait until the scheduler clock reaches `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        registered: false,
+    }
+}
+
+/// Wait for at least `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(deadline_after(duration))
+}
+
+/// Future returned by [`sleep_until`]
+#[must_use = "futures do nothing unless polled"]
+pub struct Sleep {
+    deadline: Instant,
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        if !self.registered {
+            SCHEDULER
+                .get()
+                .lock()
+                .timers
+                .insert(self.deadline, cx.waker().clone());
+            self.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+impl Scheduler {
+    fn new(wakeups: &'static Wakeups) -> Self {
+        Scheduler {
+            threads: Vec::new(),
+            run_queues: Default::default(),
+            timers: TimerWheel::new(),
+            wakeups,
+        }
+    }
+
+    /// Add a runnable thread
+    fn insert(
+        &mut self,
+        priority: Priority,
+        locals: Locals,
+        future: BoxFuture,
+    ) -> Result<Thread, Error> {
+        let id = match self.threads.iter().position(Option::is_none) {
+            Some(id) => id,
+            None if self.threads.len() < MAX_THREADS => {
+                self.threads.push(None);
+                self.threads.len() - 1
+            }
+            None => return Err(Error::new(SchedError::TooManyThreads)),
+        };
+
+        self.threads[id] = Some(ThreadState {
+            priority,
+            future: Some(future),
+            queued: true,
+            locals,
+        });
+        self.run_queues[priority as usize].push_back(id as u16);
+        thread_state(locals.get(&trace::CONTEXT), "runnable", timestamp());
+        Ok(Thread(id as u16))
+    }
+
+    /// Pick the next thread to run, taking its future and task-local values
+    fn next(&mut self) -> Option<Next> {
+        // Expiring timers wakes threads, so do that before draining wakeups
+        self.timers.advance(now(), Waker::wake);
+        self.drain_wakeups();
+
+        let Scheduler {
+            threads,
+            run_queues,
+            ..
+        } = self;
+        let (id, future, locals) = run_queues.iter_mut().find_map(|queue| {
+            while let Some(id) = queue.pop_front() {
+                let Some(state) = threads[id as usize].as_mut() else {
+                    continue;
+                };
+                state.queued = false;
+                if let Some(future) = state.future.take() {
+                    return Some((id, future, state.locals));
+                }
+            }
+            None
+        })?;
+        Some(Next {
+            id,
+            future,
+            locals,
+            depth: run_queues.iter().map(VecDeque::len).sum(),
+        })
+    }
+
+    /// Return a polled thread's future and task-local values, or remove the
+    /// thread if it finished
+    fn finish(&mut self, id: u16, future: BoxFuture, locals: Locals, done: bool) {
+        let slot = &mut self.threads[id as usize];
+        let context = locals.get(&trace::CONTEXT);
+        if done {
+            *slot = None;
+            thread_state(context, "exited", timestamp());
+        } else if let Some(state) = slot {
+            state.future = Some(future);
+            state.locals = locals;
+            thread_state(context, "blocked", timestamp());
+        }
+    }
+
+    /// Move threads with pending wakeups onto their run queues
+    fn drain_wakeups(&mut self) {
+        for (i, word) in self.wakeups.0.iter().enumerate() {
+            let mut bits = word.swap(0, Ordering::Acquire);
+            while bits != 0 {
+                let id = i * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+
+                if let Some(Some(state)) = self.threads.get_mut(id) {
+                    if !state.queued {
+                        state.queued = true;
+                        self.run_queues[state.priority as usize].push_back(id as u16);
+                        // Latency is measured from here rather than the wakeup itself, since
+                        // wakeups can come from interrupt handlers that shouldn't trace
+                        thread_state(state.locals.get(&trace::CONTEXT), "runnable", timestamp());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Timestamp for scheduler trace events, in raw ticks of the platform clock.
+/// The decoder converts these with the calibration ktrace sends, so they're
+/// consistent even if taken before the clock was calibrated.
+fn timestamp() -> u64 {
+    hal_impl::time::INSTANCE.now()
+}
+
+impl Wakeups {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NONE: AtomicU64 = AtomicU64::new(0);
+        Wakeups([NONE; MAX_THREADS / 64])
+    }
+
+    fn set(&self, id: u16) {
+        let id = id as usize;
+        self.0[id / 64].fetch_or(1 << (id % 64), Ordering::Release);
+    }
+
+    fn pending(&self) -> bool {
+        self.0.iter().any(|w| w.load(Ordering::Relaxed) != 0)
+    }
+}
+
+/// Build a waker for thread `id`. Wakers just hold the thread ID, so they don't
+/// need to allocate.
+fn waker_for(id: u16) -> Waker {
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(
+        |data| RawWaker::new(data, &VTABLE),
+        |data| wake(Thread(data as usize as u16)),
+        |data| wake(Thread(data as usize as u16)),
+        |_| {},
+    );
+
+    // SAFETY: the vtable functions don't dereference the data pointer
+    unsafe { Waker::from_raw(RawWaker::new(id as usize as *const (), &VTABLE)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::poll_fn;
+
+    use platypos_hal::interrupts::Level;
+
+    use ktest::*;
+
+    use super::*;
+
+    /// Build a waker which posts a wakeup for thread `id` to `wakeups`, rather
+    /// than the global scheduler's
+    fn test_waker(wakeups: &'static Wakeups, id: u16) -> Waker {
+        type Target = (&'static Wakeups, u16);
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(
+            |data| {
+                // SAFETY: the data pointer is always a boxed Target
+                let target = unsafe { *(data as *const Target) };
+                RawWaker::new(Box::into_raw(Box::new(target)) as *const (), &VTABLE)
+            },
+            |data| {
+                // SAFETY: the data pointer is always a boxed Target, which this consumes
+                let (wakeups, id) = *unsafe { Box::from_raw(data as *mut Target) };
+                wakeups.set(id);
+            },
+            |data| {
+                // SAFETY: the data pointer is always a boxed Target
+                let (wakeups, id) = unsafe { *(data as *const Target) };
+                wakeups.set(id);
+            },
+            // SAFETY: the data pointer is always a boxed Target, which this consumes
+            |data| drop(unsafe { Box::from_raw(data as *mut Target) }),
+        );
+        let target: Box<Target> = Box::new((wakeups, id));
+        // SAFETY: the vtable functions treat the data pointer as the boxed Target it is
+        unsafe { Waker::from_raw(RawWaker::new(Box::into_raw(target) as *const (), &VTABLE)) }
+    }
+
+    /// Poll `scheduler`'s threads until none are runnable, returning their IDs
+    /// in the order they ran
+    fn run_until_idle(scheduler: &mut Scheduler) -> Vec<u16> {
+        let mut order = Vec::new();
+        while let Some(Next {
+            id,
+            mut future,
+            locals,
+            ..
+        }) = scheduler.next()
+        {
+            order.push(id);
+            let waker = test_waker(scheduler.wakeups, id);
+            let done = future
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_ready();
+            scheduler.finish(id, future, locals, done);
+        }
+        order
+    }
+
+    /// A thread which yields `count` times before exiting
+    fn yielding(count: usize) -> BoxFuture {
+        let mut remaining = count;
+        Box::pin(poll_fn(move |cx| {
+            if remaining == 0 {
+                return Poll::Ready(());
+            }
+            remaining -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }))
+    }
+
+    fn spawn_on(scheduler: &mut Scheduler, priority: Priority, future: BoxFuture) -> u16 {
+        scheduler.insert(priority, Locals::new(), future).unwrap().0
+    }
+
+    #[ktest::test]
+    fn test_priority_order() {
+        static WAKEUPS: Wakeups = Wakeups::new();
+        let mut scheduler = Scheduler::new(&WAKEUPS);
+        let idle = spawn_on(&mut scheduler, Priority::Idle, yielding(0));
+        let normal = spawn_on(&mut scheduler, Priority::Normal, yielding(1));
+        let bottom_half = spawn_on(&mut scheduler, Priority::BottomHalf, yielding(2));
+
+        // Each class runs until it has nothing runnable, even though its threads yield
+        ktassert_eq!(
+            run_until_idle(&mut scheduler),
+            [bottom_half, bottom_half, bottom_half, normal, normal, idle]
+        );
+        ktassert!(scheduler.threads.iter().all(Option::is_none));
+    }
+
+    #[ktest::test]
+    fn test_round_robin() {
+        static WAKEUPS: Wakeups = Wakeups::new();
+        let mut scheduler = Scheduler::new(&WAKEUPS);
+        let a = spawn_on(&mut scheduler, Priority::Normal, yielding(2));
+        let b = spawn_on(&mut scheduler, Priority::Normal, yielding(1));
+        let c = spawn_on(&mut scheduler, Priority::Normal, yielding(2));
+
+        ktassert_eq!(run_until_idle(&mut scheduler), [a, b, c, a, b, c, a, c]);
+        ktassert!(scheduler.threads.iter().all(Option::is_none));
+    }
+
+    #[ktest::test]
+    fn test_wake_while_polled() {
+        static WAKEUPS: Wakeups = Wakeups::new();
+        let mut scheduler = Scheduler::new(&WAKEUPS);
+
+        // Wakeups that arrive while a thread is running requeue it once it's done, however many
+        // there are
+        let mut polls = 0;
+        let woken = spawn_on(
+            &mut scheduler,
+            Priority::Normal,
+            Box::pin(poll_fn(move |cx| {
+                polls += 1;
+                if polls > 1 {
+                    return Poll::Ready(());
+                }
+                cx.waker().wake_by_ref();
+                WAKEUPS.set(0);
+                Poll::Pending
+            })),
+        );
+        ktassert_eq!(woken, 0);
+
+        // Threads that block without a wakeup stay blocked
+        let blocked = spawn_on(
+            &mut scheduler,
+            Priority::Normal,
+            Box::pin(poll_fn(|_| Poll::<()>::Pending)),
+        );
+
+        ktassert_eq!(run_until_idle(&mut scheduler), [woken, blocked, woken]);
+        ktassert!(scheduler.threads[woken as usize].is_none());
+        ktassert!(scheduler.threads[blocked as usize].is_some());
+
+        WAKEUPS.set(blocked);
+        ktassert_eq!(run_until_idle(&mut scheduler), [blocked]);
+    }
+
+    #[ktest::test]
+    fn test_tick_at_device_level() {
+        let controller = hal_impl::interrupts::Controller;
+        {
+            let guard = controller.raise_to(Level::Device);
+            ktassert_eq!(guard.previous(), Level::Passive);
+            ktassert_eq!(controller.level(), Level::Device);
+
+            // Masking device interrupts shouldn't stop the scheduler tick
+            let start = now();
+            let mut spins = 0u64;
+            while now() == start && spins < 1_000_000_000 {
+                core::hint::spin_loop();
+                spins += 1;
+            }
+            ktassert!(now() > start);
+        }
+        ktassert_eq!(controller.level(), Level::Passive);
+    }
+}
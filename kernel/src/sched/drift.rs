@@ -0,0 +1,148 @@
+//! Scheduler clock drift correction.
+//!
+//! The scheduler clock counts APIC timer ticks, which are nominally [`TICK`]
+//! apart. The APIC timer's rate is only measured once, at boot, so any error
+//! in that measurement makes tick-based time drift away from real time, which
+//! long soak tests notice. [`uptime`] doesn't assume ticks are exactly
+//! [`TICK`] long: each tick adds the current corrected tick length instead.
+//!
+//! A correction thread periodically compares uptime against the TSC, which is
+//! invariant and so a better reference, and nudges the corrected tick length
+//! toward the measured one, plus a little more to work off the error that's
+//! already built up. Changes are gradual and bounded, so uptime never jumps or
+//! runs backwards, and a timer that's badly wrong still shows up instead of
+//! being hidden. Each measurement is traced as a `timer_drift` event, with the
+//! measured and corrected tick lengths in picoseconds.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use platypos_hal::time::Clock;
+
+use crate::prelude::*;
+
+use super::{Priority, CLOCK, TICK};
+
+/// Fractional bits in tick lengths, which are fixed-point nanoseconds
+const FRACTION_BITS: u32 = 32;
+
+/// Nominal tick length, in fixed-point nanoseconds
+const NOMINAL: u64 = (TICK.as_nanos() as u64) << FRACTION_BITS;
+
+/// How often drift is measured
+const INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of intervals to spread correcting accumulated error over
+const SLEW_INTERVALS: u64 = 6;
+
+/// Fraction of the difference between the corrected and target tick lengths
+/// made up each interval
+const GAIN: i128 = 4;
+
+/// Largest correction to the tick length, in parts per million. Drift beyond
+/// this is a timer bug rather than calibration error.
+const MAX_CORRECTION_PPM: i128 = 1000;
+
+/// Current corrected tick length, in fixed-point nanoseconds
+static TICK_LENGTH: AtomicU64 = AtomicU64::new(NOMINAL);
+
+/// Corrected time since the scheduler clock started, in nanoseconds
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Fractional nanoseconds carried over to the next tick. Only the tick
+/// handler touches this.
+static CARRY: AtomicU64 = AtomicU64::new(0);
+
+platypos_ktrace::schema! {
+    /// Over the time since correction started, scheduler ticks were actually
+    /// `tick_ps` picoseconds long, and uptime now counts `corrected_ps` per
+    /// tick
+    event timer_drift(DEBUG) {
+        tick_ps: u64,
+        corrected_ps: u64,
+    }
+}
+
+/// Add a tick's worth of corrected time to [`uptime`]. This is called from
+/// the timer interrupt, along with the tick itself.
+pub(super) fn advance() {
+    let length = TICK_LENGTH.load(Ordering::Relaxed) + CARRY.load(Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(length >> FRACTION_BITS, Ordering::Relaxed);
+    CARRY.store(length & ((1 << FRACTION_BITS) - 1), Ordering::Relaxed);
+}
+
+/// Time since the scheduler clock started, corrected for drift. This advances
+/// every tick, like [`now`](super::now).
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::Relaxed))
+}
+
+/// Start correcting drift. This must be called after the scheduler is
+/// initialized and the TSC is calibrated.
+pub fn init() {
+    super::spawn(Priority::Normal, correct()).expect("Could not start drift correction");
+}
+
+async fn correct() {
+    let tsc = &hal_impl::time::INSTANCE;
+    let Some(calibration) = tsc.calibration() else {
+        tracing::warn!("The TSC isn't calibrated, so timer drift can't be corrected");
+        return;
+    };
+
+    let start_tsc = tsc.now();
+    let start_ticks = super::now();
+    let start_nanos = UPTIME_NANOS.load(Ordering::Relaxed);
+    let limit = NOMINAL as i128 * MAX_CORRECTION_PPM / 1_000_000;
+    let mut warned = false;
+    loop {
+        super::sleep(INTERVAL).await;
+        let ticks = super::now() - start_ticks;
+        let reference = calibration.ticks_to_nanos(tsc.now().saturating_sub(start_tsc));
+        let uptime = UPTIME_NANOS.load(Ordering::Relaxed) - start_nanos;
+        if ticks == 0 {
+            continue;
+        }
+
+        // Measure over the whole run, so the rounding to whole ticks averages out
+        let measured = ((reference as i128) << FRACTION_BITS) / ticks as i128;
+        let error = uptime as i128 - reference as i128;
+        let slew = (error << FRACTION_BITS) / (CLOCK.to_ticks(INTERVAL) * SLEW_INTERVALS) as i128;
+        let target = (measured - slew).clamp(NOMINAL as i128 - limit, NOMINAL as i128 + limit);
+
+        let current = TICK_LENGTH.load(Ordering::Relaxed) as i128;
+        let corrected = current + (target - current) / GAIN;
+        TICK_LENGTH.store(corrected as u64, Ordering::Relaxed);
+
+        timer_drift(picoseconds(measured), picoseconds(corrected));
+        let drift_ppm = (measured - NOMINAL as i128) * 1_000_000 / NOMINAL as i128;
+        if drift_ppm.abs() > MAX_CORRECTION_PPM && !warned {
+            tracing::warn!(
+                "Scheduler ticks are off by {drift_ppm} ppm, too much to correct for. The APIC \
+                 timer may be miscalibrated."
+            );
+            warned = true;
+        }
+    }
+}
+
+/// Convert a fixed-point tick length to picoseconds
+fn picoseconds(length: i128) -> u64 {
+    ((length.max(0) * 1000) >> FRACTION_BITS) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    #[ktest::test]
+    fn test_picoseconds() {
+        ktassert_eq!(picoseconds(NOMINAL as i128), 1_000_000_000);
+        // 50 ppm slow
+        let slow = NOMINAL as i128 + NOMINAL as i128 / 20_000;
+        ktassert_eq!(picoseconds(slow), 1_000_050_000);
+        ktassert_eq!(picoseconds(-1), 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! Task-local storage.
+//!
+//! Each thread has a small, fixed number of slots for per-thread context, like
+//! the span stack tracing should use. Slots are claimed by adding a variant to
+//! [`Slot`], so that two subsystems can't accidentally share one, and are
+//! accessed through typed [`TaskLocal`] keys.
+//!
+//! While a thread is being polled, its slots are loaded into [`CURRENT`], so
+//! reading and writing them doesn't need the scheduler lock. The scheduler
+//! only runs on the boot processor, so there's only one set of current slots.
+//! Outside of a thread (in the scheduler itself, or an interrupt that arrives
+//! while it's idle), every slot reads as 0.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Task-local slots, one per subsystem that needs one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// ktrace execution context, so spans follow the thread
+    TraceContext,
+}
+
+impl Slot {
+    const COUNT: usize = 1;
+}
+
+/// Slots of the thread being polled
+static CURRENT: [AtomicU64; Slot::COUNT] = [const { AtomicU64::new(0) }; Slot::COUNT];
+
+/// A value that can be kept in a task-local slot. Slots start out as 0, so
+/// that must be a sensible default.
+pub trait LocalValue: Copy {
+    fn into_raw(self) -> u64;
+    fn from_raw(raw: u64) -> Self;
+}
+
+impl LocalValue for u64 {
+    fn into_raw(self) -> u64 {
+        self
+    }
+
+    fn from_raw(raw: u64) -> Self {
+        raw
+    }
+}
+
+impl LocalValue for usize {
+    fn into_raw(self) -> u64 {
+        self as u64
+    }
+
+    fn from_raw(raw: u64) -> Self {
+        raw as usize
+    }
+}
+
+/// Typed key for a task-local slot
+pub struct TaskLocal<T: LocalValue> {
+    slot: Slot,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: LocalValue> TaskLocal<T> {
+    pub const fn new(slot: Slot) -> Self {
+        Self {
+            slot,
+            _value: PhantomData,
+        }
+    }
+
+    /// The current thread's value
+    pub fn get(&self) -> T {
+        T::from_raw(CURRENT[self.slot as usize].load(Ordering::Relaxed))
+    }
+
+    /// Set the current thread's value. Outside of a thread, this is
+    /// discarded the next time a thread is polled.
+    pub fn set(&self, value: T) {
+        CURRENT[self.slot as usize].store(value.into_raw(), Ordering::Relaxed);
+    }
+}
+
+/// Initial slot values for a new thread
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Locals([u64; Slot::COUNT]);
+
+impl Locals {
+    pub const fn new() -> Self {
+        Self([0; Slot::COUNT])
+    }
+
+    /// Set `key`'s slot to `value`
+    pub fn with<T: LocalValue>(mut self, key: &TaskLocal<T>, value: T) -> Self {
+        self.0[key.slot as usize] = value.into_raw();
+        self
+    }
+
+    pub fn get<T: LocalValue>(&self, key: &TaskLocal<T>) -> T {
+        T::from_raw(self.0[key.slot as usize])
+    }
+
+    /// Make these the current thread's slots
+    pub(super) fn load(&self) {
+        for (current, value) in CURRENT.iter().zip(self.0) {
+            current.store(value, Ordering::Relaxed);
+        }
+    }
+
+    /// Take the current thread's slots, resetting them to 0
+    pub(super) fn take() -> Self {
+        let mut locals = Self::new();
+        for (current, value) in CURRENT.iter().zip(locals.0.iter_mut()) {
+            *value = current.swap(0, Ordering::Relaxed);
+        }
+        locals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    static KEY: TaskLocal<usize> = TaskLocal::new(Slot::TraceContext);
+
+    #[ktest::test]
+    fn test_load_and_take() {
+        let saved = Locals::take();
+
+        let locals = Locals::new().with(&KEY, 42);
+        ktassert_eq!(locals.get(&KEY), 42);
+        locals.load();
+        ktassert_eq!(KEY.get(), 42);
+        KEY.set(7);
+
+        let taken = Locals::take();
+        ktassert_eq!(taken.get(&KEY), 7);
+        ktassert_eq!(KEY.get(), 0);
+
+        saved.load();
+    }
+}
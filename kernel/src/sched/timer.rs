@@ -0,0 +1,108 @@
+//! Hashed timer wheel.
+//!
+//! Timers are bucketed by deadline modulo the number of slots, so inserting is
+//! constant-time and advancing only looks at the slots for elapsed ticks.
+//! Deadlines more than one revolution away stay in their slot until a later
+//! pass reaches them.
+
+use core::task::Waker;
+
+use alloc::vec::Vec;
+
+use platypos_hal::time::Instant;
+
+/// Number of slots in the wheel. At the default tick, this covers 256ms per
+/// revolution.
+const SLOTS: usize = 256;
+
+pub(super) struct TimerWheel {
+    slots: [Vec<(Instant, Waker)>; SLOTS],
+    /// The next tick that hasn't been processed yet
+    current: Instant,
+}
+
+impl TimerWheel {
+    pub(super) fn new() -> Self {
+        Self {
+            slots: core::array::from_fn(|_| Vec::new()),
+            current: Instant::ZERO,
+        }
+    }
+
+    /// Wake `waker` once the wheel is advanced to `deadline`
+    pub(super) fn insert(&mut self, deadline: Instant, waker: Waker) {
+        // Deadlines that already passed fire on the next advance
+        let deadline = deadline.max(self.current);
+        self.slots[deadline.ticks() as usize % SLOTS].push((deadline, waker));
+    }
+
+    /// Process every tick up to and including `now`, calling `fire` for each
+    /// expired timer
+    pub(super) fn advance(&mut self, now: Instant, mut fire: impl FnMut(Waker)) {
+        if now < self.current {
+            return;
+        }
+
+        // If more than a revolution has passed, every slot needs to be checked exactly once
+        let elapsed = (now - self.current + 1).min(SLOTS as u64);
+        let current = self.current.ticks();
+        for tick in current..current + elapsed {
+            let slot = &mut self.slots[tick as usize % SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].0 <= now {
+                    fire(slot.swap_remove(i).1);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current = now + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{RawWaker, RawWakerVTable};
+
+    use ktest::*;
+
+    use super::*;
+
+    /// Build a waker which increments `counter` when woken
+    fn counting_waker(counter: &'static AtomicUsize) -> Waker {
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(
+            |data| RawWaker::new(data, &VTABLE),
+            |data| {
+                // SAFETY: the data pointer is always a &'static AtomicUsize
+                unsafe { &*(data as *const AtomicUsize) }.fetch_add(1, Ordering::Relaxed);
+            },
+            |data| {
+                // SAFETY: the data pointer is always a &'static AtomicUsize
+                unsafe { &*(data as *const AtomicUsize) }.fetch_add(1, Ordering::Relaxed);
+            },
+            |_| {},
+        );
+        // SAFETY: the vtable only treats the data pointer as a &'static AtomicUsize
+        unsafe { Waker::from_raw(RawWaker::new(counter as *const _ as *const (), &VTABLE)) }
+    }
+
+    #[ktest::test]
+    fn test_fires_at_deadline() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        let mut wheel = TimerWheel::new();
+        let deadline = Instant::from_ticks(5);
+        wheel.insert(deadline, counting_waker(&FIRED));
+        wheel.insert(deadline + SLOTS as u64, counting_waker(&FIRED));
+
+        wheel.advance(Instant::from_ticks(4), Waker::wake);
+        ktassert_eq!(FIRED.load(Ordering::Relaxed), 0);
+        wheel.advance(deadline, Waker::wake);
+        ktassert_eq!(FIRED.load(Ordering::Relaxed), 1);
+
+        // Skipping more than a revolution ahead still fires the second timer
+        wheel.advance(Instant::from_ticks(10 * SLOTS as u64), Waker::wake);
+        ktassert_eq!(FIRED.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,323 @@
+//! Wait queues.
+//!
+//! A [`WaitQueue`] lets threads block until some condition holds, like a
+//! device having a free request slot. Waiters pass a predicate, and whoever
+//! might have made it true calls [`WaitQueue::wake_one`] or
+//! [`WaitQueue::wake_all`]. Waking only takes an interrupt-safe lock and never
+//! allocates, so interrupt handlers can do it.
+//!
+//! Waiters recheck their predicate every time they're polled, so spurious
+//! wakeups are harmless. A waiter registers itself before checking, so a
+//! wakeup that races with the check isn't lost. If a waiter picked by
+//! [`wake_one`](WaitQueue::wake_one) is dropped before it runs (for example,
+//! because it timed out), the wakeup is passed on to the next waiter.
+//!
+//! Waits that block are traced as `wait_done` events, with how long the
+//! thread waited and whether it timed out.
+
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use alloc::collections::VecDeque;
+
+use crate::prelude::*;
+
+platypos_ktrace::schema! {
+    /// A thread stopped waiting on `queue` after `elapsed_us`, with an
+    /// `outcome` of `woken` or `timed out`
+    event wait_done(DEBUG) {
+        queue: str,
+        elapsed_us: u64,
+        outcome: str,
+    }
+}
+
+/// Queue of threads waiting for a condition
+pub struct WaitQueue {
+    /// Name for tracing
+    name: &'static str,
+    waiters: InterruptSafeMutex<'static, Waiters>,
+}
+
+struct Waiters {
+    /// Registered waiters, oldest first
+    queue: VecDeque<(u64, Waker)>,
+    /// ID for the next waiter to register
+    next_id: u64,
+}
+
+impl WaitQueue {
+    pub const fn new(
+        controller: &'static hal_impl::interrupts::Controller,
+        name: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            waiters: InterruptSafeMutex::new(
+                controller,
+                Waiters {
+                    queue: VecDeque::new(),
+                    next_id: 0,
+                },
+            ),
+        }
+    }
+
+    /// Wait until `pred` returns true. `pred` is checked right away, and then
+    /// again each time the waiting thread is woken.
+    pub async fn wait_until(&self, mut pred: impl FnMut() -> bool) {
+        let mut waiter = Waiter::new(self);
+        poll_fn(|cx| waiter.poll(cx, &mut pred)).await
+    }
+
+    /// Wait until `pred` returns true, for at most `timeout`
+    ///
+    /// # Errors
+    /// * [`SchedError::TimedOut`] if `pred` was still false after `timeout`
+    pub async fn wait_timeout(
+        &self,
+        mut pred: impl FnMut() -> bool,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let mut waiter = Waiter::new(self);
+        let mut sleep = super::sleep(timeout);
+        poll_fn(|cx| {
+            if waiter.poll(cx, &mut pred).is_ready() {
+                Poll::Ready(Ok(()))
+            } else if Pin::new(&mut sleep).poll(cx).is_ready() {
+                waiter.finish("timed out");
+                Poll::Ready(Err(Error::new(SchedError::TimedOut)))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Wake the longest-waiting thread, returning whether there was one. This
+    /// is safe to call from interrupt handlers.
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().queue.pop_front();
+        match waiter {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread, returning how many there were. Threads that
+    /// start waiting while this runs aren't woken. This is safe to call from
+    /// interrupt handlers.
+    pub fn wake_all(&self) -> usize {
+        let end = self.waiters.lock().next_id;
+        let mut woken = 0;
+        loop {
+            // Wake outside the lock, and one at a time so that nothing allocates
+            let waiter = {
+                let mut waiters = self.waiters.lock();
+                match waiters.queue.front() {
+                    Some((id, _)) if *id < end => waiters.queue.pop_front(),
+                    _ => None,
+                }
+            };
+            let Some((_, waker)) = waiter else {
+                return woken;
+            };
+            waker.wake();
+            woken += 1;
+        }
+    }
+}
+
+/// One thread's place in a [`WaitQueue`]
+struct Waiter<'a> {
+    queue: &'a WaitQueue,
+    /// ID of this waiter's entry, if it's registered. If the entry isn't in
+    /// the queue anymore, the waiter was woken but hasn't run yet.
+    id: Option<u64>,
+    /// Uptime when the waiter first blocked
+    blocked_at: Option<Duration>,
+}
+
+impl<'a> Waiter<'a> {
+    fn new(queue: &'a WaitQueue) -> Self {
+        Self {
+            queue,
+            id: None,
+            blocked_at: None,
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>, pred: &mut impl FnMut() -> bool) -> Poll<()> {
+        // Checking before registering the first time avoids the lock when there's no need to wait
+        if self.blocked_at.is_none() && pred() {
+            return Poll::Ready(());
+        }
+
+        self.register(cx.waker());
+        if pred() {
+            self.finish("woken");
+            return Poll::Ready(());
+        }
+        self.blocked_at.get_or_insert_with(super::uptime);
+        Poll::Pending
+    }
+
+    /// Add this waiter to the queue, or update its waker if it's still there
+    fn register(&mut self, waker: &Waker) {
+        let mut guard = self.queue.waiters.lock();
+        let waiters = &mut *guard;
+        let entry = self
+            .id
+            .and_then(|id| waiters.queue.iter_mut().find(|(i, _)| *i == id));
+        match entry {
+            Some((_, existing)) => {
+                if !existing.will_wake(waker) {
+                    *existing = waker.clone();
+                }
+            }
+            None => {
+                let id = waiters.next_id;
+                waiters.next_id += 1;
+                waiters.queue.push_back((id, waker.clone()));
+                self.id = Some(id);
+            }
+        }
+    }
+
+    /// Stop waiting, tracing how long the wait took if it blocked
+    fn finish(&mut self, outcome: &str) {
+        if let Some(id) = self.id.take() {
+            self.queue.waiters.lock().queue.retain(|(i, _)| *i != id);
+        }
+        if let Some(blocked_at) = self.blocked_at.take() {
+            let elapsed = super::uptime().saturating_sub(blocked_at);
+            wait_done(self.queue.name, elapsed.as_micros() as u64, outcome);
+        }
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let mut waiters = self.queue.waiters.lock();
+        match waiters.queue.iter().position(|(i, _)| *i == id) {
+            Some(index) => {
+                waiters.queue.remove(index);
+            }
+            None => {
+                // This waiter was woken, but won't act on it, so pass the wakeup on
+                drop(waiters);
+                self.queue.wake_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::task::{RawWaker, RawWakerVTable};
+
+    use alloc::boxed::Box;
+
+    use ktest::*;
+
+    use super::*;
+
+    static CONTROLLER: hal_impl::interrupts::Controller = hal_impl::interrupts::Controller;
+
+    /// Build a waker which increments `counter` when woken
+    fn counting_waker(counter: &'static AtomicUsize) -> Waker {
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(
+            |data| RawWaker::new(data, &VTABLE),
+            |data| {
+                // SAFETY: the data pointer is always a &'static AtomicUsize
+                unsafe { &*(data as *const AtomicUsize) }.fetch_add(1, Ordering::Relaxed);
+            },
+            |data| {
+                // SAFETY: the data pointer is always a &'static AtomicUsize
+                unsafe { &*(data as *const AtomicUsize) }.fetch_add(1, Ordering::Relaxed);
+            },
+            |_| {},
+        );
+        // SAFETY: the vtable functions only use the data pointer as a &'static AtomicUsize
+        unsafe { Waker::from_raw(RawWaker::new(counter as *const _ as *const (), &VTABLE)) }
+    }
+
+    fn poll<F: Future + ?Sized>(future: Pin<&mut F>, waker: &Waker) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(waker))
+    }
+
+    #[ktest::test]
+    fn test_wake_one() {
+        static QUEUE: WaitQueue = WaitQueue::new(&CONTROLLER, "test");
+        static READY: AtomicBool = AtomicBool::new(false);
+        static WOKEN: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let wakers = [counting_waker(&WOKEN[0]), counting_waker(&WOKEN[1])];
+
+        let mut first = Box::pin(QUEUE.wait_until(|| READY.load(Ordering::Relaxed)));
+        let mut second = Box::pin(QUEUE.wait_until(|| READY.load(Ordering::Relaxed)));
+        ktassert!(poll(first.as_mut(), &wakers[0]).is_pending());
+        ktassert!(poll(second.as_mut(), &wakers[1]).is_pending());
+
+        // Waiters are woken oldest first, and a spurious wakeup just blocks again
+        ktassert!(QUEUE.wake_one());
+        ktassert_eq!(WOKEN[0].load(Ordering::Relaxed), 1);
+        ktassert!(poll(first.as_mut(), &wakers[0]).is_pending());
+
+        READY.store(true, Ordering::Relaxed);
+        ktassert!(QUEUE.wake_one());
+        ktassert_eq!(WOKEN[1].load(Ordering::Relaxed), 1);
+        ktassert!(poll(second.as_mut(), &wakers[1]).is_ready());
+        ktassert!(poll(first.as_mut(), &wakers[0]).is_ready());
+        ktassert!(!QUEUE.wake_one());
+    }
+
+    #[ktest::test]
+    fn test_dropped_waiter_passes_wakeup_on() {
+        static QUEUE: WaitQueue = WaitQueue::new(&CONTROLLER, "test");
+        static WOKEN: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let wakers = [counting_waker(&WOKEN[0]), counting_waker(&WOKEN[1])];
+
+        let mut first = Box::pin(QUEUE.wait_until(|| false));
+        let mut second = Box::pin(QUEUE.wait_until(|| false));
+        ktassert!(poll(first.as_mut(), &wakers[0]).is_pending());
+        ktassert!(poll(second.as_mut(), &wakers[1]).is_pending());
+
+        QUEUE.wake_one();
+        drop(first);
+        ktassert_eq!(WOKEN[1].load(Ordering::Relaxed), 1);
+
+        // A waiter that's still registered just leaves the queue
+        ktassert!(poll(second.as_mut(), &wakers[1]).is_pending());
+        drop(second);
+        ktassert!(!QUEUE.wake_one());
+    }
+
+    #[ktest::test]
+    fn test_wake_all() {
+        static QUEUE: WaitQueue = WaitQueue::new(&CONTROLLER, "test");
+        static WOKEN: AtomicUsize = AtomicUsize::new(0);
+        let waker = counting_waker(&WOKEN);
+
+        let never = || false;
+        let mut waiters = [
+            Box::pin(QUEUE.wait_until(never)),
+            Box::pin(QUEUE.wait_until(never)),
+            Box::pin(QUEUE.wait_until(never)),
+        ];
+        for waiter in &mut waiters {
+            ktassert!(poll(waiter.as_mut(), &waker).is_pending());
+        }
+        ktassert_eq!(QUEUE.wake_all(), 3);
+        ktassert_eq!(WOKEN.load(Ordering::Relaxed), 3);
+        ktassert_eq!(QUEUE.wake_all(), 0);
+    }
+}
@@ -0,0 +1,201 @@
+//! Full-screen graphics: the boot splash and the panic screen.
+//!
+//! Unlike the [console](crate::console), these draw straight to the display
+//! without keeping any state, so the panic screen works no matter what the
+//! console was doing when the kernel panicked.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embedded_graphics::mono_font::{ascii, MonoFont, MonoTextStyle};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+
+use crate::arch::display::{self, Color, Display, Error};
+use crate::panic::Policy;
+
+const FONT: &MonoFont<'static> = &ascii::FONT_10X20;
+
+/// Screen margin, in pixels
+const MARGIN: i32 = 10;
+
+/// Height of the banner across the top of the screen, in pixels
+const BANNER_HEIGHT: u32 = 48;
+
+/// Height of the accent stripe below the banner, in pixels
+const STRIPE_HEIGHT: u32 = 4;
+
+const TEXT_COLOR: Color = Color::WHITE;
+
+const SPLASH_BG_COLOR: Color = Color::BLACK;
+const SPLASH_BANNER_COLOR: Color = Color::new(0x1b, 0x5e, 0x20);
+const SPLASH_STRIPE_COLOR: Color = Color::new(0xa5, 0xd6, 0xa7);
+
+const PANIC_BG_COLOR: Color = Color::new(0x4a, 0x00, 0x00);
+const PANIC_BANNER_COLOR: Color = Color::new(0xc6, 0x28, 0x28);
+const PANIC_STRIPE_COLOR: Color = Color::new(0xff, 0xeb, 0x3b);
+
+/// Set once a panic screen has been drawn. A panic while drawing the panic
+/// screen would otherwise recurse forever.
+static PANIC_DRAWN: AtomicBool = AtomicBool::new(false);
+
+/// Draw the boot splash screen.
+pub fn draw_splash(display: &mut Display) -> Result<(), Error> {
+    let mut screen = Screen::new(
+        display,
+        SPLASH_BG_COLOR,
+        SPLASH_BANNER_COLOR,
+        SPLASH_STRIPE_COLOR,
+        concat!("PlatypOS v", env!("CARGO_PKG_VERSION")),
+    )?;
+    let _ = writeln!(screen, "Booting...");
+    Ok(())
+}
+
+/// Take over the display to show a panic message and backtrace, and what the
+/// kernel will do next under `policy`. This does nothing if there's no
+/// display, or if a panic screen was already drawn.
+pub fn draw_panic(info: &PanicInfo, backtrace: &[usize], policy: Policy) {
+    if PANIC_DRAWN.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    // SAFETY: the kernel is panicking, so nothing else will draw to the
+    // display again
+    let Some(mut display) = (unsafe { display::steal() }) else {
+        return;
+    };
+
+    let Ok(mut screen) = Screen::new(
+        &mut display,
+        PANIC_BG_COLOR,
+        PANIC_BANNER_COLOR,
+        PANIC_STRIPE_COLOR,
+        "KERNEL PANIC",
+    ) else {
+        return;
+    };
+
+    let _ = writeln!(screen, "{info}");
+    let _ = writeln!(screen);
+    let _ = writeln!(screen, "Backtrace:");
+    for (i, address) in backtrace.iter().enumerate() {
+        let _ = writeln!(screen, "  #{i:<2} {address:#018x}");
+    }
+    let _ = writeln!(screen);
+    let _ = match policy {
+        Policy::Halt => writeln!(screen, "The system has been halted."),
+        Policy::Exit => writeln!(screen, "The system is shutting down."),
+        Policy::Reboot(0) => writeln!(screen, "The system is restarting."),
+        Policy::Reboot(1) => writeln!(screen, "The system will restart in 1 second."),
+        Policy::Reboot(delay) => writeln!(screen, "The system will restart in {delay} seconds."),
+    };
+}
+
+/// A screen with a banner, and a grid of text below it. Text that doesn't fit
+/// on the screen is dropped.
+struct Screen<'a> {
+    display: &'a mut Display,
+    style: MonoTextStyle<'static, Color>,
+    /// Top-left corner of the text area
+    origin: Point,
+    columns: i32,
+    rows: i32,
+    column: i32,
+    row: i32,
+}
+
+impl<'a> Screen<'a> {
+    /// Clear `display` and draw a banner with `title`.
+    fn new(
+        display: &'a mut Display,
+        background: Color,
+        banner: Color,
+        stripe: Color,
+        title: &str,
+    ) -> Result<Self, Error> {
+        let size = display.size();
+        let style = MonoTextStyle::new(FONT, TEXT_COLOR);
+
+        display.clear(background)?;
+        Rectangle::new(Point::zero(), Size::new(size.width, BANNER_HEIGHT))
+            .into_styled(PrimitiveStyle::with_fill(banner))
+            .draw(display)?;
+        Rectangle::new(
+            Point::new(0, BANNER_HEIGHT as i32),
+            Size::new(size.width, STRIPE_HEIGHT),
+        )
+        .into_styled(PrimitiveStyle::with_fill(stripe))
+        .draw(display)?;
+
+        let title_y = (BANNER_HEIGHT - FONT.character_size.height) as i32 / 2;
+        Text::with_baseline(title, Point::new(MARGIN, title_y), style, Baseline::Top)
+            .draw(display)?;
+
+        let origin = Point::new(MARGIN, (BANNER_HEIGHT + STRIPE_HEIGHT) as i32 + MARGIN);
+        let cell = char_cell();
+        let columns = (size.width as i32 - 2 * MARGIN) / cell.width as i32;
+        let rows = (size.height as i32 - origin.y - MARGIN) / cell.height as i32;
+
+        Ok(Self {
+            display,
+            style,
+            origin,
+            columns: columns.max(1),
+            rows: rows.max(0),
+            column: 0,
+            row: 0,
+        })
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        self.row += 1;
+    }
+
+    fn put_char(&mut self, ch: char) -> Result<(), Error> {
+        if self.column >= self.columns {
+            self.newline();
+        }
+        if self.row >= self.rows {
+            return Ok(());
+        }
+
+        let cell = char_cell();
+        let position = self.origin
+            + Point::new(
+                self.column * cell.width as i32,
+                self.row * cell.height as i32,
+            );
+        let mut buf = [0; 4];
+        Text::with_baseline(
+            ch.encode_utf8(&mut buf),
+            position,
+            self.style,
+            Baseline::Top,
+        )
+        .draw(self.display)?;
+        self.column += 1;
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Write for Screen<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            match ch {
+                '\n' => self.newline(),
+                '\r' => {}
+                ch => self.put_char(ch).map_err(|_| fmt::Error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size of a single character, including spacing
+fn char_cell() -> Size {
+    FONT.character_size + Size::new(FONT.character_spacing, 0)
+}
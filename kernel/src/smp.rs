@@ -0,0 +1,298 @@
+//! Cross-processor function calls.
+//!
+//! [`call_on`] and [`call_on_async`] run a function on a set of processors.
+//! Each processor has a mailbox of pending calls. The caller adds the call to
+//! every target's mailbox, then sends a dedicated IPI so the targets drain
+//! their mailboxes.
+//!
+//! # Reentrancy
+//! * Remote calls run in interrupt context, with interrupts disabled. They
+//!   must not block or wait on other processors.
+//! * A remote call must not use [`call_on`], since the caller's processor
+//!   could be waiting on this one. It may use [`call_on_async`].
+//! * [`call_on`] panics if interrupts are disabled. A processor with
+//!   interrupts disabled can't service calls, so two processors calling each
+//!   other would deadlock.
+//! * Calls queued for the same processor run in the order they were queued.
+//!   Calls queued for different processors are not ordered with each other.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hint;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use platypos_common::sync::Global;
+use platypos_hal::interrupts::Controller as _;
+use platypos_hal::linkme::distributed_slice;
+use platypos_hal::topology::{self, ProcessorId, ProcessorSet, ProcessorState, Topology};
+
+use crate::arch::hal_impl::interrupts::Ipi;
+use crate::boot_phase::MemoryReady;
+use crate::prelude::*;
+
+type Mailbox = InterruptSafeMutex<'static, VecDeque<Call>>;
+
+static MAILBOXES: Global<Box<[Mailbox]>> = Global::new();
+
+static CONTROLLER: Global<&'static hal_impl::interrupts::Controller> = Global::new();
+
+/// A pending call in a processor's mailbox
+struct Call {
+    work: Work,
+    /// Number of processors which haven't finished a synchronous call yet.
+    /// This lives on the caller's stack.
+    pending: Option<*const AtomicUsize>,
+}
+
+enum Work {
+    /// Borrowed function for a synchronous call. It's only valid until
+    /// `pending` reaches 0.
+    Borrowed(*const (dyn Fn() + Sync)),
+    /// Function shared by all targets of a fire-and-forget call.
+    Shared(Arc<dyn Fn() + Send + Sync>),
+}
+
+// SAFETY: the raw pointers in a `Call` refer to data that the caller of
+// `call_on` keeps alive (and doesn't mutate) until the call completes. The
+// function itself is `Sync`, so it's safe to run from another processor.
+unsafe impl Send for Call {}
+
+/// Set up cross-processor calls. This must be called after the processor
+/// count is detected, and before any other processors are started.
+pub fn init(controller: &'static hal_impl::interrupts::Controller, _: &MemoryReady) {
+    let mailboxes = (0..topology::processor_count::<hal_impl::topology::Topology>())
+        .map(|_| InterruptSafeMutex::new(controller, VecDeque::new()))
+        .collect::<Vec<_>>();
+    MAILBOXES.init(mailboxes.into_boxed_slice());
+    CONTROLLER.init(controller);
+    hal_impl::interrupts::set_ipi_handler(Ipi::CallFunction, handle_ipi);
+}
+
+/// Run `f` on every online processor in `targets`, and wait for all of them to
+/// finish. If the current processor is in `targets`, `f` runs directly rather
+/// than through an IPI.
+///
+/// # Panics
+/// If interrupts are disabled on the current processor (see the
+/// [module-level documentation](self)).
+pub fn call_on<F: Fn() + Sync>(targets: ProcessorSet, f: F) {
+    assert!(
+        CONTROLLER.get().enabled(),
+        "call_on with interrupts disabled could deadlock"
+    );
+
+    let current = current_processor();
+    let remote = targets
+        .intersection(topology::online_processors())
+        .without(current);
+    let pending = AtomicUsize::new(remote.len());
+
+    let f_ref: &(dyn Fn() + Sync) = &f;
+    // SAFETY: this only erases the lifetime. We wait below until every target
+    // is done with `f`, so it outlives all uses of the pointer.
+    let f_ptr: *const (dyn Fn() + Sync) = unsafe { core::mem::transmute(f_ref) };
+
+    for processor in remote.iter() {
+        send(
+            processor,
+            Call {
+                work: Work::Borrowed(f_ptr),
+                pending: Some(&pending),
+            },
+        );
+    }
+
+    if targets.contains(current) {
+        f();
+    }
+
+    while pending.load(Ordering::Acquire) != 0 {
+        hint::spin_loop();
+    }
+}
+
+/// Run `f` on every online processor in `targets`, without waiting for it to
+/// finish. This is also used for the current processor, if it's in `targets`,
+/// in which case `f` runs once interrupts are enabled.
+pub fn call_on_async<F: Fn() + Send + Sync + 'static>(targets: ProcessorSet, f: F) {
+    let f: Arc<dyn Fn() + Send + Sync> = Arc::new(f);
+    for processor in targets.intersection(topology::online_processors()).iter() {
+        send(
+            processor,
+            Call {
+                work: Work::Shared(f.clone()),
+                pending: None,
+            },
+        );
+    }
+}
+
+/// Add `call` to `processor`'s mailbox and notify it
+fn send(processor: ProcessorId, call: Call) {
+    let mailbox = MAILBOXES
+        .get()
+        .get(processor as usize)
+        .expect("processor ID out of range");
+    mailbox.lock().push_back(call);
+    hal_impl::interrupts::send_ipi(Ipi::CallFunction, processor)
+        .unwrap_or_else(|err| panic!("could not send remote call: {err}"));
+}
+
+/// IPI handler which runs everything in the current processor's mailbox
+fn handle_ipi() {
+    let mailbox = &MAILBOXES.get()[current_processor() as usize];
+    // Only hold the lock while taking calls out, so that calls can queue more
+    // calls (including for this processor)
+    loop {
+        let Some(call) = mailbox.lock().pop_front() else {
+            break;
+        };
+        match &call.work {
+            // SAFETY: the caller waits for `pending` to reach 0 before
+            // dropping the function
+            Work::Borrowed(f) => unsafe { (**f)() },
+            Work::Shared(f) => f(),
+        }
+
+        if let Some(pending) = call.pending {
+            // SAFETY: the caller keeps `pending` alive until it reaches 0, and
+            // this is the last use of it
+            unsafe { (*pending).fetch_sub(1, Ordering::Release) };
+        }
+    }
+}
+
+/// Log processor state changes, so bring-up problems show up in traces
+#[distributed_slice(topology::PROCESSOR_STATE_HOOKS)]
+#[linkme(crate = platypos_hal::linkme)]
+fn log_state_change(processor: ProcessorId, from: ProcessorState, to: ProcessorState) {
+    match to {
+        ProcessorState::Failed => tracing::error!("Processor {processor} failed to boot"),
+        _ => tracing::info!("Processor {processor} is {to} (was {from})"),
+    }
+}
+
+crate::power::shutdown_hook!(Processors, "other processors", park_others);
+
+/// Halt every other processor, so nothing else is running during shutdown.
+/// The panic-stop IPI is the only one that doesn't need the target to respond.
+fn park_others() {
+    hal_impl::interrupts::broadcast_ipi(Ipi::PanicStop);
+}
+
+fn current_processor() -> ProcessorId {
+    hal_impl::topology::INSTANCE.current_processor()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicU64;
+    use core::time::Duration;
+
+    use ktest::*;
+    use platypos_hal::time::Clock;
+
+    use super::*;
+    use crate::arch::hal_impl::interrupts::SelfIpiRoute;
+
+    platypos_ktrace::schema! {
+        /// Round-trip latency of sending an IPI to the current processor
+        /// through `route`, until its handler starts running
+        event ipi_latency(INFO) {
+            route: str,
+            count: u64,
+            min_ns: u64,
+            median_ns: u64,
+            max_ns: u64,
+        }
+    }
+
+    #[ktest::test]
+    fn test_call_on_self() {
+        let calls = AtomicUsize::new(0);
+        call_on(ProcessorSet::single(current_processor()), || {
+            calls.fetch_add(1, Ordering::Relaxed);
+        });
+        // The current processor runs the call directly, before call_on returns
+        ktassert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Processors outside the targets never run the call
+        call_on(ProcessorSet::empty(), || {
+            calls.fetch_add(1, Ordering::Relaxed);
+        });
+        ktassert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[ktest::test]
+    fn test_call_on_async_self() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        ktassert!(CONTROLLER.get().enabled());
+        let clock = &hal_impl::time::INSTANCE;
+        let calibration = clock.calibration().unwrap();
+
+        // The call goes through the current processor's mailbox and a self-IPI
+        call_on_async(ProcessorSet::single(current_processor()), || {
+            CALLS.fetch_add(1, Ordering::Release);
+        });
+        let start = clock.instant();
+        while CALLS.load(Ordering::Acquire) == 0
+            && clock.instant().duration_since(start, calibration) < Duration::from_secs(1)
+        {
+            hint::spin_loop();
+        }
+        ktassert_eq!(CALLS.load(Ordering::Acquire), 1);
+        let mailbox = &MAILBOXES.get()[current_processor() as usize];
+        ktassert!(mailbox.lock().is_empty());
+    }
+
+    #[ktest::test]
+    fn test_self_ipi_latency() {
+        const ROUNDS: usize = 64;
+        /// Clock reading when the handler ran, or 0 if it hasn't yet
+        static RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+        ktassert!(CONTROLLER.get().enabled());
+        let clock = &hal_impl::time::INSTANCE;
+        let mailbox = &MAILBOXES.get()[current_processor() as usize];
+        let record: Arc<dyn Fn() + Send + Sync> =
+            Arc::new(|| RECEIVED.store(hal_impl::time::INSTANCE.now(), Ordering::Release));
+
+        for (route, name) in [
+            (SelfIpiRoute::SelfRegister, "self-ipi"),
+            (SelfIpiRoute::Icr, "icr"),
+        ] {
+            let mut samples = Vec::with_capacity(ROUNDS);
+            for _ in 0..ROUNDS {
+                RECEIVED.store(0, Ordering::Relaxed);
+                mailbox.lock().push_back(Call {
+                    work: Work::Shared(record.clone()),
+                    pending: None,
+                });
+
+                let start = clock.now();
+                hal_impl::interrupts::send_self_ipi(Ipi::CallFunction, route);
+                let end = loop {
+                    match RECEIVED.load(Ordering::Acquire) {
+                        0 => hint::spin_loop(),
+                        end => break end,
+                    }
+                };
+                samples.push(end.saturating_sub(start));
+            }
+
+            samples.sort_unstable();
+            let ns = |ticks: u64| clock.to_duration(ticks).map_or(0, |d| d.as_nanos() as u64);
+            let (min, median, max) = (samples[0], samples[ROUNDS / 2], samples[ROUNDS - 1]);
+            tracing::info!(
+                "{name}: {} ns median ({} - {} ns)",
+                ns(median),
+                ns(min),
+                ns(max)
+            );
+            ipi_latency(name, ROUNDS as u64, ns(min), ns(median), ns(max));
+        }
+    }
+}
@@ -4,28 +4,255 @@
 //! In particular, it manages the background I/O task, with an emphasis on being
 //! able to get traces during a panic.
 
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
 use platypos_common::sync::Global;
-use platypos_ktrace::Worker;
+use platypos_hal::interrupts::{Controller as _, ExecutionContext};
+use platypos_hal::time::Clock as _;
+use platypos_hal::topology::Topology;
+use platypos_ktrace::{FlushPolicy, Worker};
+use tracing::level_filters::LevelFilter;
 
 use crate::arch::hal_impl::SerialPort;
+use crate::boot_phase::HeapReady;
+use crate::drivers::fw_cfg;
+use crate::fmt::HumanBytes;
+use crate::mm::pressure::Pressure;
 use crate::prelude::InterruptSafeMutex;
+use crate::sched::local::{Slot, TaskLocal};
+
+/// With the `trace-stats` feature, trace output is counted in [`WRITE_STATS`]
+#[cfg(feature = "trace-stats")]
+type TraceWriter =
+    platypos_hal::io::Instrumented<SerialPort, &'static crate::arch::hal_impl::time::Clock>;
+#[cfg(not(feature = "trace-stats"))]
+type TraceWriter = SerialPort;
+
+/// Only ever taken with `try_lock`, which keeps it NMI-aware: a flush from an
+/// NMI handler gives up if the code it interrupted holds the worker.
+static WORKER: Global<InterruptSafeMutex<'static, Worker<TraceWriter>>> = Global::new();
+
+/// Throughput and errors writing traces to the serial port
+#[cfg(feature = "trace-stats")]
+pub(crate) static WRITE_STATS: platypos_hal::io::WriteStats = platypos_hal::io::WriteStats::new();
+
+/// Each thread's ktrace execution context, so that its spans follow it
+/// instead of staying on whichever processor it was suspended on
+pub(crate) static CONTEXT: TaskLocal<u64> = TaskLocal::new(Slot::TraceContext);
+
+/// Next ktrace context to hand out. Context 0 is a processor's own context.
+static NEXT_CONTEXT: AtomicU64 = AtomicU64::new(1);
+
+/// fw_cfg blob with a cap on trace throughput in bytes per second, for
+/// simulating a slow serial link
+const BANDWIDTH_BLOB: &str = "opt/platypos/trace-bandwidth";
 
-static WORKER: Global<InterruptSafeMutex<'static, Worker<SerialPort>>> = Global::new();
+/// fw_cfg blob with the most verbose level of events to send, like `debug`
+const LOG_LEVEL_BLOB: &str = "opt/platypos/log-level";
 
-/// Initialize kernel tracing
+/// How long a single write to the serial port can take before the worker drops
+/// the rest of it, so a host that stops reading can't hang the kernel
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Rate limits for trace points that can fire often enough to drown out
+/// everything else, as `target=N/s` directives
+const RATE_LIMITS: &str = concat!(
+    "platypos_kernel::mm::heap_allocator=200/s,",
+    "platypos_kernel::mm::root_allocator=200/s",
+);
+
+/// Initialize kernel tracing. Registering the trace dispatcher allocates.
 pub(crate) fn init(
     writer: SerialPort,
     topology: &'static crate::arch::hal_impl::topology::Topology,
     controller: &'static crate::arch::hal_impl::interrupts::Controller,
+    _: &HeapReady,
 ) {
-    let worker = platypos_ktrace::init(writer, topology);
+    // ktrace writes binary data to the serial port, so stop sending text to it
+    crate::earlycon::disable();
+    #[cfg(feature = "trace-stats")]
+    let writer = platypos_hal::io::Instrumented::new(
+        writer,
+        &WRITE_STATS,
+        &crate::arch::hal_impl::time::INSTANCE,
+    );
+    let mut worker = platypos_ktrace::init(writer, topology);
+    worker.set_flush_policy(FlushPolicy::default(), clock_micros);
+    worker.set_write_timeout(&crate::arch::hal_impl::time::INSTANCE, WRITE_TIMEOUT);
     WORKER.init(InterruptSafeMutex::new(controller, worker));
+    platypos_ktrace::set_rate_limits(RATE_LIMITS, clock_micros)
+        .unwrap_or_else(|err| panic!("{err}"));
+    platypos_ktrace::set_context_hook(execution_context);
+    platypos_ktrace::set_timestamp_hook(timestamp);
+
+    // Lead the trace with the build, so captured traces say what produced them
+    tracing::info!(
+        "PlatypOS v{} ({} profile)",
+        env!("CARGO_PKG_VERSION"),
+        crate::PROFILE
+    );
+}
+
+/// Apply trace settings passed through fw_cfg. This must be called after
+/// [`fw_cfg::init`].
+pub(crate) fn configure() {
+    if let Ok(blob) = fw_cfg::read(LOG_LEVEL_BLOB) {
+        set_log_level(&blob);
+    }
+    let Ok(blob) = fw_cfg::read(BANDWIDTH_BLOB) else {
+        return;
+    };
+    #[cfg(not(feature = "trace-stats"))]
+    {
+        let _ = blob;
+        tracing::warn!("Ignoring trace bandwidth cap, since the kernel was built without it");
+    }
+    #[cfg(feature = "trace-stats")]
+    set_bandwidth_cap(&blob);
+}
+
+fn set_log_level(blob: &[u8]) {
+    match core::str::from_utf8(blob)
+        .ok()
+        .and_then(|s| s.trim().parse::<LevelFilter>().ok())
+    {
+        Some(level) => {
+            tracing::info!("Log level: {}", level);
+            platypos_ktrace::set_max_level(level);
+        }
+        None => tracing::warn!("Ignoring malformed log level"),
+    }
+}
+
+#[cfg(feature = "trace-stats")]
+fn set_bandwidth_cap(blob: &[u8]) {
+    match core::str::from_utf8(blob)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+    {
+        Some(cap) => {
+            WRITE_STATS.set_bandwidth_cap(Some(cap));
+            tracing::info!("Limiting trace output to {} bytes per second", cap);
+        }
+        None => tracing::warn!("Ignoring malformed trace bandwidth cap"),
+    }
+}
+
+/// Log how much trace data was batched and, with the `trace-stats` feature,
+/// written. This is bound to F2 by [`debug_keys`].
+///
+/// [`debug_keys`]: crate::debug_keys
+pub(crate) fn log_stats() {
+    #[cfg(feature = "trace-stats")]
+    tracing::info!(
+        "Trace output: {} in {} writes, {} errors, {} timeouts",
+        HumanBytes(WRITE_STATS.bytes()),
+        WRITE_STATS.writes(),
+        WRITE_STATS.errors(),
+        WRITE_STATS.timeouts()
+    );
+    if let Some(worker) = WORKER.try_get().and_then(|m| m.try_lock()) {
+        let stats = worker.stats();
+        tracing::info!(
+            "Trace batching: {} messages in {} batches ({} full, {} idle, {} deadline, {} forced)",
+            stats.messages,
+            stats.writes,
+            stats.full_flushes,
+            stats.idle_flushes,
+            stats.deadline_flushes,
+            stats.forced_flushes
+        );
+        tracing::info!(
+            "Trace write timeouts: {} ({} dropped), {} more on the early console",
+            stats.timeouts,
+            HumanBytes(stats.dropped_bytes),
+            crate::earlycon::timeouts()
+        );
+    }
+}
+
+/// Allocate a ktrace context for a new thread
+pub(crate) fn new_context() -> u64 {
+    NEXT_CONTEXT.fetch_add(1, Ordering::Relaxed)
 }
 
+/// Detect where the current processor is in the topology, and tell the host
+pub(crate) fn announce_processor() {
+    let processor = crate::arch::hal_impl::topology::INSTANCE.current_processor();
+    let location = crate::arch::hal_impl::topology::detect_local();
+    platypos_ktrace::announce_processor(
+        processor.into(),
+        platypos_ktrace::proto::ProcessorLocation {
+            package: location.package,
+            core: location.core,
+            thread: location.thread,
+        },
+    );
+    tracing::info!(
+        "Processor {processor}: package {}, core {}, thread {}",
+        location.package,
+        location.core,
+        location.thread
+    );
+}
+
+/// Tell the host how fast the platform clock runs, so it can convert event
+/// timestamps. This does nothing if the clock isn't calibrated yet.
+pub(crate) fn announce_calibration() {
+    if let Some(calibration) = crate::arch::hal_impl::time::INSTANCE.calibration() {
+        platypos_ktrace::announce_calibration(calibration);
+    }
+}
+
+/// Tell ktrace that this processor switched to `context`, or back to its own
+/// context if `context` is 0
+pub(crate) fn switch_context(context: u64) {
+    let processor = crate::arch::hal_impl::topology::INSTANCE.current_processor();
+    platypos_ktrace::switch_context(processor.into(), context);
+}
+
+/// Whether the current processor is in an interrupt handler, for tagging
+/// spans and events
+fn execution_context() -> ExecutionContext {
+    crate::arch::hal_impl::interrupts::Controller.execution_context()
+}
+
+/// Clock for trace rate limiting. This only advances once the scheduler tick
+/// is running, so callsites can't refill their budget during early boot.
+fn clock_micros() -> u64 {
+    crate::sched::uptime().as_micros() as u64
+}
+
+/// Timestamp for span transitions and context switches, in raw ticks of the
+/// platform clock like the scheduler's events
+fn timestamp() -> u64 {
+    crate::arch::hal_impl::time::INSTANCE.now()
+}
+
+/// Process pending trace events, leaving them batched if the flush policy
+/// allows. The scheduler calls this when it's idle.
+pub(crate) fn poll() {
+    if let Some(mut worker) = WORKER.try_get().and_then(|m| m.try_lock()) {
+        worker.work();
+    }
+}
+
+/// Copy the last trace messages into `buf`, returning how many bytes were
+/// copied. This copies nothing if another processor is running the worker.
+pub(crate) fn copy_tail(buf: &mut [u8]) -> usize {
+    WORKER
+        .try_get()
+        .and_then(|m| m.try_lock())
+        .map_or(0, |worker| worker.copy_tail(buf))
+}
+
+crate::power::shutdown_hook!(Tracing, "trace output", flush);
+
 /// Try to flush any pending trace events.
 pub(crate) fn flush() {
     if let Some(mut worker) = WORKER.try_get().and_then(|m| m.try_lock()) {
-        worker.work();
+        worker.flush();
     }
     // Silently ignore if:
     // - another core is already running the worker (we don't care _which_ core
@@ -33,5 +260,14 @@ pub(crate) fn flush() {
     // - tracing hasn't been initialized yet
 }
 
+crate::mm::pressure::pressure_listener!("trace output", shed_under_pressure);
+
+/// Drop debug and trace events while memory is short. The allocators trace
+/// heavily at those levels, which only adds to the load while the system is
+/// trying to recover.
+fn shed_under_pressure(level: Pressure) {
+    platypos_ktrace::set_shedding(level != Pressure::None);
+}
+
 // Once we have a scheduler, it'll start a task which holds the spinlock and
 // runs the worker
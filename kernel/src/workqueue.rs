@@ -0,0 +1,244 @@
+//! Deferred work for interrupt handlers (bottom halves).
+//!
+//! Interrupt handlers should do as little as possible with interrupts
+//! disabled. Instead, they can [`queue`] a [`WorkItem`] to run later on a
+//! dedicated [`Priority::BottomHalf`] thread, which runs before any normal
+//! threads.
+//!
+//! Each processor has its own fixed-size, lock-free queue, so queueing work
+//! never blocks or allocates. To keep an interrupt flood from taking over the
+//! system:
+//! * Work queued while a processor's queue is full is dropped, and the number
+//!   of dropped items is logged the next time the worker runs.
+//! * The worker runs at most [`BATCH_SIZE`] items per processor before
+//!   yielding to other bottom-half threads.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use platypos_common::sync::Global;
+use platypos_hal::topology::{self, Topology};
+
+use crate::fault;
+use crate::prelude::*;
+use crate::sched::{self, Priority, Thread};
+
+/// Capacity of each processor's queue. This must be a power of 2.
+const QUEUE_CAPACITY: usize = 64;
+
+/// Maximum number of items to run from one processor's queue before yielding
+const BATCH_SIZE: usize = 16;
+
+/// A unit of deferred work: `func` is called with `arg`
+#[derive(Debug, Clone, Copy)]
+pub struct WorkItem {
+    func: fn(usize),
+    arg: usize,
+}
+
+impl WorkItem {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        Self { func, arg }
+    }
+}
+
+static QUEUES: Global<Box<[Queue]>> = Global::new();
+
+static WORKER: Global<Thread> = Global::new();
+
+/// Number of work items dropped because a queue was full
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Set up work queues and start the worker thread. This must be called after
+/// the scheduler is initialized.
+pub fn init() {
+    let queues = (0..topology::processor_count::<hal_impl::topology::Topology>())
+        .map(|_| Queue::new())
+        .collect::<Vec<_>>();
+    QUEUES.init(queues.into_boxed_slice());
+
+    let worker = sched::spawn(Priority::BottomHalf, Worker).expect("Could not start work queue");
+    WORKER.init(worker);
+}
+
+/// Queue `item` to run on the worker thread. This never blocks, so it's safe to
+/// call from interrupt handlers.
+///
+/// # Errors
+/// If the current processor's queue is full, the item is dropped and this
+/// returns [`SchedError::WorkQueueFull`].
+pub fn queue(item: WorkItem) -> Result<(), Error> {
+    let processor = hal_impl::topology::INSTANCE.current_processor();
+    let result = if fault::fault_point!("workqueue.queue") {
+        Err(item)
+    } else {
+        QUEUES.get()[processor as usize].push(item)
+    };
+    // Wake the worker either way, so that it makes room
+    sched::wake(*WORKER.get());
+
+    result.map_err(|_| {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        Error::new(SchedError::WorkQueueFull)
+    })
+}
+
+/// The worker thread
+struct Worker;
+
+impl Future for Worker {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            tracing::warn!(count = dropped as u64, "Dropped work items");
+        }
+
+        let mut more = false;
+        for queue in QUEUES.get().iter() {
+            for _ in 0..BATCH_SIZE {
+                let Some(item) = queue.pop() else {
+                    break;
+                };
+                let _span = tracing::debug_span!("work", at = item.func as usize).entered();
+                (item.func)(item.arg);
+            }
+            more |= !queue.is_empty();
+        }
+
+        if more {
+            // Yield so a flood of work doesn't starve other bottom halves
+            cx.waker().wake_by_ref();
+        }
+        // Otherwise, `queue` wakes the worker
+        Poll::Pending
+    }
+}
+
+/// Bounded lock-free queue, based on Dmitry Vyukov's MPMC queue. Each slot has
+/// a sequence number which says whether it's ready to be written or read on
+/// the current lap around the buffer.
+struct Queue {
+    slots: Box<[Slot]>,
+    enqueue: AtomicUsize,
+    dequeue: AtomicUsize,
+}
+
+struct Slot {
+    sequence: AtomicUsize,
+    item: UnsafeCell<MaybeUninit<WorkItem>>,
+}
+
+// SAFETY: slot contents are only accessed by the thread that claimed the slot through the sequence
+// numbers
+unsafe impl Sync for Queue {}
+
+impl Queue {
+    fn new() -> Self {
+        let slots = (0..QUEUE_CAPACITY)
+            .map(|i| Slot {
+                sequence: AtomicUsize::new(i),
+                item: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        Self {
+            slots,
+            enqueue: AtomicUsize::new(0),
+            dequeue: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, item: WorkItem) -> Result<(), WorkItem> {
+        let mut pos = self.enqueue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % QUEUE_CAPACITY];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos as isize) {
+                0 => match self.enqueue.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: winning the CAS gives exclusive access to the slot
+                        unsafe { (*slot.item.get()).write(item) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot hasn't been read since the last lap, so the queue is full
+                d if d < 0 => return Err(item),
+                _ => pos = self.enqueue.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<WorkItem> {
+        let mut pos = self.dequeue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % QUEUE_CAPACITY];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                0 => match self.dequeue.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: winning the CAS gives exclusive access to the slot, which the
+                        // sequence number says was written
+                        let item = unsafe { (*slot.item.get()).assume_init_read() };
+                        slot.sequence
+                            .store(pos.wrapping_add(QUEUE_CAPACITY), Ordering::Release);
+                        return Some(item);
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot hasn't been written yet, so the queue is empty
+                d if d < 0 => return None,
+                _ => pos = self.dequeue.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        let pos = self.dequeue.load(Ordering::Relaxed);
+        let slot = &self.slots[pos % QUEUE_CAPACITY];
+        slot.sequence.load(Ordering::Acquire) != pos.wrapping_add(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ktest::*;
+
+    use super::*;
+
+    fn nothing(_: usize) {}
+
+    #[ktest::test]
+    fn test_queue_fifo_and_full() {
+        let queue = Queue::new();
+        for i in 0..QUEUE_CAPACITY {
+            ktassert!(queue.push(WorkItem::new(nothing, i)).is_ok());
+        }
+        ktassert!(queue.push(WorkItem::new(nothing, QUEUE_CAPACITY)).is_err());
+
+        for i in 0..QUEUE_CAPACITY {
+            ktassert_eq!(queue.pop().map(|item| item.arg), Some(i));
+        }
+        ktassert!(queue.is_empty());
+        ktassert!(queue.pop().is_none());
+    }
+}
@@ -2,8 +2,12 @@
 
 use proc_macro2::TokenStream;
 use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
 use syn::spanned::Spanned;
-use syn::{parse_macro_input, ItemFn, ReturnType};
+use syn::{
+    parse_macro_input, Attribute, AttributeArgs, Expr, FnArg, Ident, ItemFn, Lit, Meta, NestedMeta,
+    ReturnType, Token, Type,
+};
 
 #[proc_macro_attribute]
 pub fn test(
@@ -25,24 +29,44 @@ fn generate_test(input: ItemFn) -> TokenStream {
         return TokenStream::new();
     }
 
-    if !input.sig.inputs.is_empty() {
-        input
-            .sig
-            .inputs
-            .span()
-            .unwrap()
-            .error("Tests cannot take arguments")
-            .emit();
-        return TokenStream::new();
+    let cases = match parse_cases(&input.attrs) {
+        Ok(cases) => cases,
+        Err(err) => return err.to_compile_error(),
+    };
+
+    // Without cases, every argument is a fixture
+    let fixtures = if cases.is_empty() {
+        match parse_fixtures(&input) {
+            Ok(fixtures) => fixtures,
+            Err(err) => return err.to_compile_error(),
+        }
+    } else {
+        Vec::new()
+    };
+
+    for case in &cases {
+        if case.args.len() != input.sig.inputs.len() {
+            case.name
+                .span()
+                .unwrap()
+                .error(format!(
+                    "Test case has {} arguments, but the test takes {}",
+                    case.args.len(),
+                    input.sig.inputs.len()
+                ))
+                .emit();
+            return TokenStream::new();
+        }
     }
 
+    let should_panic = match parse_should_panic(&input.attrs) {
+        Ok(should_panic) => should_panic,
+        Err(err) => return err.to_compile_error(),
+    };
+
     let test_name = input.sig.ident;
-    let static_name = format_ident!("REGISTER_{}", test_name);
     let impl_name = format_ident!("{}_impl", test_name);
-
-    let test_full_name = quote! {
-        concat!(module_path!(), "::", stringify!(#test_name))
-    };
+    let inputs = input.sig.inputs;
 
     let test_impl = match input.sig.output {
         ReturnType::Default => {
@@ -67,17 +91,241 @@ fn generate_test(input: ItemFn) -> TokenStream {
         }
     };
 
+    let registrations = if !fixtures.is_empty() {
+        let static_name = format_ident!("REGISTER_{}", test_name);
+        let fixtures_impl_name = format_ident!("{}_fixtures_impl", test_name);
+        quote! {
+            #[::ktest::linkme::distributed_slice(::ktest::TESTS)]
+            #[linkme(crate = ::ktest::linkme)]
+            #[allow(non_upper_case_globals)]
+            static #static_name: ::ktest::Test =
+              ::ktest::Test::new(
+                  concat!(module_path!(), "::", stringify!(#test_name)),
+                  #fixtures_impl_name,
+              )
+              .with_should_panic(#should_panic)
+              .with_fixtures(&[#(::ktest::fixture::slot_of::<#fixtures>),*]);
+
+            fn #fixtures_impl_name() -> ::ktest::Outcome {
+                #impl_name(#(<#fixtures as ::ktest::Fixture>::slot().get()),*)
+            }
+        }
+    } else if cases.is_empty() {
+        let static_name = format_ident!("REGISTER_{}", test_name);
+        quote! {
+            #[::ktest::linkme::distributed_slice(::ktest::TESTS)]
+            #[linkme(crate = ::ktest::linkme)]
+            #[allow(non_upper_case_globals)]
+            static #static_name: ::ktest::Test =
+              ::ktest::Test::new(
+                  concat!(module_path!(), "::", stringify!(#test_name)),
+                  #impl_name,
+              ).with_should_panic(#should_panic);
+        }
+    } else {
+        cases
+            .iter()
+            .map(|case| {
+                let case_name = &case.name;
+                let args = &case.args;
+                let static_name = format_ident!("REGISTER_{}_{}", test_name, case_name);
+                let case_impl_name = format_ident!("{}_{}_impl", test_name, case_name);
+                quote! {
+                    #[::ktest::linkme::distributed_slice(::ktest::TESTS)]
+                    #[linkme(crate = ::ktest::linkme)]
+                    #[allow(non_upper_case_globals)]
+                    static #static_name: ::ktest::Test =
+                      ::ktest::Test::new(
+                          concat!(module_path!(), "::", stringify!(#test_name), "::", stringify!(#case_name)),
+                          #case_impl_name,
+                      )
+                      .with_should_panic(#should_panic)
+                      .with_arguments(stringify!(#(#args),*));
+
+                    #[allow(non_snake_case)]
+                    fn #case_impl_name() -> ::ktest::Outcome {
+                        #impl_name(#(#args),*)
+                    }
+                }
+            })
+            .collect()
+    };
+
     let expanded = quote! {
-        #[::ktest::linkme::distributed_slice(::ktest::TESTS)]
-        #[linkme(crate = ::ktest::linkme)]
-        #[allow(non_upper_case_globals)]
-        static #static_name: ::ktest::Test =
-          ::ktest::Test::new(#test_full_name, #impl_name);
+        #registrations
 
-        fn #impl_name() -> ::ktest::Outcome {
+        fn #impl_name(#inputs) -> ::ktest::Outcome {
             #test_impl
         }
     };
 
     expanded
 }
+
+/// Fixture types that a test without cases takes. Every argument must be a
+/// shared reference to a fixture type.
+fn parse_fixtures(input: &ItemFn) -> syn::Result<Vec<Type>> {
+    input
+        .sig
+        .inputs
+        .iter()
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => match &*arg.ty {
+                Type::Reference(r) if r.mutability.is_none() => Ok((*r.elem).clone()),
+                _ => Err(syn::Error::new(
+                    arg.ty.span(),
+                    "Test arguments must be `#[case(...)]` arguments or `&Fixture` references",
+                )),
+            },
+            FnArg::Receiver(r) => Err(syn::Error::new(r.span(), "Tests cannot take `self`")),
+        })
+        .collect()
+}
+
+#[proc_macro_attribute]
+pub fn fixture(
+    attr: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let attr = parse_macro_input!(attr as AttributeArgs);
+    let input = parse_macro_input!(input as ItemFn);
+
+    proc_macro::TokenStream::from(match generate_fixture(attr, input) {
+        Ok(tokens) => tokens,
+        Err(err) => err.to_compile_error(),
+    })
+}
+
+/// Expand a `#[ktest::fixture]` or `#[ktest::fixture(scope = "run")]`
+/// function, implementing `ktest::Fixture` for the type it returns
+fn generate_fixture(attr: AttributeArgs, input: ItemFn) -> syn::Result<TokenStream> {
+    let mut scope = quote!(::ktest::fixture::Scope::Test);
+    for arg in attr {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("scope") => {
+                scope = match &nv.lit {
+                    Lit::Str(s) if s.value() == "test" => quote!(::ktest::fixture::Scope::Test),
+                    Lit::Str(s) if s.value() == "run" => quote!(::ktest::fixture::Scope::Run),
+                    lit => {
+                        return Err(syn::Error::new(
+                            lit.span(),
+                            "fixture scope must be \"test\" or \"run\"",
+                        ))
+                    }
+                }
+            }
+            arg => return Err(syn::Error::new(arg.span(), "expected `scope = \"...\"`")),
+        }
+    }
+
+    if !input.sig.inputs.is_empty() || input.sig.asyncness.is_some() {
+        return Err(syn::Error::new(
+            input.sig.span(),
+            "Fixtures must be plain functions without arguments",
+        ));
+    }
+    let ty = match &input.sig.output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => {
+            return Err(syn::Error::new(
+                input.sig.span(),
+                "Fixtures must return the value tests use",
+            ))
+        }
+    };
+
+    let name = &input.sig.ident;
+    Ok(quote! {
+        #input
+
+        const _: () = {
+            static SLOT: ::ktest::fixture::Slot<#ty> = ::ktest::fixture::Slot::new(
+                concat!(module_path!(), "::", stringify!(#name)),
+                #scope,
+                #name,
+            );
+
+            impl ::ktest::Fixture for #ty {
+                fn slot() -> &'static ::ktest::fixture::Slot<Self> {
+                    &SLOT
+                }
+            }
+        };
+    })
+}
+
+/// One `#[case(name, args...)]` attribute on a parameterized test
+struct Case {
+    name: Ident,
+    args: Vec<Expr>,
+}
+
+impl Parse for Case {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let mut args = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            args.push(input.parse()?);
+        }
+        Ok(Case { name, args })
+    }
+}
+
+/// Collect the test's `#[case(...)]` attributes, in order
+fn parse_cases(attrs: &[Attribute]) -> syn::Result<Vec<Case>> {
+    let cases = attrs
+        .iter()
+        .filter(|a| a.path.is_ident("case"))
+        .map(|a| a.parse_args::<Case>())
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    for (i, case) in cases.iter().enumerate() {
+        if cases[..i].iter().any(|other| other.name == case.name) {
+            return Err(syn::Error::new(
+                case.name.span(),
+                "duplicate test case name",
+            ));
+        }
+    }
+
+    Ok(cases)
+}
+
+/// Convert a `#[should_panic]` attribute, if present, into a
+/// `ktest::ShouldPanic` expression. This accepts the same forms as the
+/// standard test harness.
+fn parse_should_panic(attrs: &[Attribute]) -> syn::Result<TokenStream> {
+    let attr = match attrs.iter().find(|a| a.path.is_ident("should_panic")) {
+        Some(attr) => attr,
+        None => return Ok(quote!(::ktest::ShouldPanic::No)),
+    };
+
+    let expected = match attr.parse_meta()? {
+        Meta::Path(_) => None,
+        Meta::NameValue(nv) => Some(nv.lit),
+        Meta::List(list) => match list.nested.iter().next() {
+            Some(NestedMeta::Meta(Meta::NameValue(nv))) if nv.path.is_ident("expected") => {
+                Some(nv.lit.clone())
+            }
+            _ => {
+                return Err(syn::Error::new(
+                    list.span(),
+                    "expected `#[should_panic(expected = \"...\")]`",
+                ))
+            }
+        },
+    };
+
+    match expected {
+        None => Ok(quote!(::ktest::ShouldPanic::Yes)),
+        Some(Lit::Str(message)) => Ok(quote!(::ktest::ShouldPanic::WithMessage(#message))),
+        Some(lit) => Err(syn::Error::new(
+            lit.span(),
+            "expected panic message must be a string",
+        )),
+    }
+}
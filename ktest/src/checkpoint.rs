@@ -0,0 +1,71 @@
+//! Checkpoints for recovering from panics in tests.
+//!
+//! The kernel doesn't unwind, so a panicking test can't return normally. Instead,
+//! the runner calls each test through [`run`], which saves a checkpoint of the
+//! processor's [`Context`] first. If the test panics, the panic handler
+//! calls [`resume`] to restore that context, which makes [`run`] return `None`
+//! as if the test had returned.
+//!
+//! Nothing on the abandoned part of the stack is dropped. Memory the test
+//! allocated is leaked, and locks it held stay locked, so a panicking test may
+//! still break later tests that use the same resources.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use platypos_hal_x86_64::context::Context;
+
+/// Checkpoint to resume from if the current test panics
+static ACTIVE: AtomicPtr<Context> = AtomicPtr::new(ptr::null_mut());
+
+struct State<F, R> {
+    f: Option<F>,
+    result: Option<R>,
+}
+
+extern "C" fn trampoline<F: FnOnce() -> R, R>(data: *mut u8) {
+    // SAFETY: `run` passes a pointer to its `State`, which outlives this call
+    let state = unsafe { &mut *(data as *mut State<F, R>) };
+    let f = state.f.take().unwrap();
+    state.result = Some(f());
+}
+
+/// Run `f` with a checkpoint, returning its result or `None` if it panicked
+/// and the panic handler called [`resume`].
+pub(crate) fn run<R>(f: impl FnOnce() -> R) -> Option<R> {
+    let mut context = Context::default();
+    let mut state = State {
+        f: Some(f),
+        result: None,
+    };
+
+    let previous = ACTIVE.swap(&mut context, Ordering::AcqRel);
+    // SAFETY: `context` and `state` outlive the call, and `trampoline` matches the type of `state`
+    let resumed = unsafe {
+        context.call_with_checkpoint(trampoline_for(&state), &mut state as *mut _ as *mut u8)
+    };
+    ACTIVE.store(previous, Ordering::Release);
+
+    if resumed {
+        None
+    } else {
+        state.result
+    }
+}
+
+fn trampoline_for<F: FnOnce() -> R, R>(_state: &State<F, R>) -> extern "C" fn(*mut u8) {
+    trampoline::<F, R>
+}
+
+/// Resume from the innermost active checkpoint. This returns only if there is
+/// no active checkpoint.
+///
+/// # Safety
+/// The caller must be running on the same stack as the checkpoint, deeper
+/// than the [`run`] call that created it.
+pub(crate) unsafe fn resume() {
+    let context = ACTIVE.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !context.is_null() {
+        (*context).restore();
+    }
+}
@@ -0,0 +1,177 @@
+//! Fixtures: shared, prepared resources for tests.
+//!
+//! A `#[ktest::fixture]` function builds a value of some type, and any test
+//! can ask for it with a parameter of type `&T`:
+//!
+//! ```ignore
+//! #[ktest::fixture(scope = "run")]
+//! fn scratch() -> Scratch {
+//!     Scratch::map(16)
+//! }
+//!
+//! #[ktest::test]
+//! fn test_fill(scratch: &Scratch) {
+//!     ...
+//! }
+//! ```
+//!
+//! The runner sets fixtures up before the test starts, in parameter order,
+//! and tears them down by dropping them. [`Scope::Test`] fixtures are built
+//! fresh for each test and torn down in reverse order right after it, even if
+//! it panicked. [`Scope::Run`] fixtures are built the first time a test needs
+//! them and shared by every later test, then torn down in reverse order of
+//! setup once all tests have run.
+//!
+//! If a fixture's setup panics, the tests that need it fail without running.
+//! A run-scoped fixture isn't retried, so one broken fixture fails fast
+//! instead of panicking once per test.
+//!
+//! Fixture types must be defined in the same crate as their fixture function,
+//! since it implements [`Fixture`] for them.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::checkpoint;
+
+/// How long a fixture's value lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Set up before and torn down after each test that uses it
+    Test,
+    /// Set up once, before the first test that uses it, and torn down after
+    /// every test has run
+    Run,
+}
+
+/// A type that a fixture function produces. `#[ktest::fixture]` implements
+/// this.
+pub trait Fixture: Sized + 'static {
+    fn slot() -> &'static Slot<Self>;
+}
+
+/// Slot state: no value
+const EMPTY: u8 = 0;
+/// Slot state: holds a value
+const READY: u8 = 1;
+/// Slot state: setup panicked, and won't be retried
+const POISONED: u8 = 2;
+
+/// Order that fixtures were set up in, so they're torn down in reverse
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Storage for a fixture's value
+pub struct Slot<T> {
+    name: &'static str,
+    scope: Scope,
+    setup: fn() -> T,
+    state: AtomicU8,
+    /// When the current value was set up, from [`SEQUENCE`]
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: tests run one at a time, and only the runner sets up or tears down fixtures, so the
+// value is never accessed concurrently
+unsafe impl<T> Sync for Slot<T> {}
+
+impl<T: 'static> Slot<T> {
+    pub const fn new(name: &'static str, scope: Scope, setup: fn() -> T) -> Self {
+        Self {
+            name,
+            scope,
+            setup,
+            state: AtomicU8::new(EMPTY),
+            sequence: AtomicUsize::new(0),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The fixture's value. The runner sets it up before the test starts.
+    ///
+    /// # Panics
+    /// If the fixture isn't set up, which means the test didn't declare it
+    pub fn get(&'static self) -> &'static T {
+        assert_eq!(
+            self.state.load(Ordering::Acquire),
+            READY,
+            "fixture {} is not set up",
+            self.name
+        );
+        // SAFETY: the value is initialized while the slot is READY, and only the runner tears it
+        // down, after the test is done with it
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+/// Type-erased [`Slot`], for the runner
+pub trait AnySlot: Sync {
+    fn name(&self) -> &'static str;
+    fn scope(&self) -> Scope;
+    /// Set up the value if it isn't already. Returns `false` if setup
+    /// panicked, now or for a previous test.
+    fn set_up(&self) -> bool;
+    /// Drop the value, if there is one. Returns `false` if that panicked.
+    fn tear_down(&self) -> bool;
+    /// Setup order of the current value, if there is one
+    fn sequence(&self) -> Option<usize>;
+}
+
+impl<T: 'static> AnySlot for Slot<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn scope(&self) -> Scope {
+        self.scope
+    }
+
+    fn set_up(&self) -> bool {
+        match self.state.load(Ordering::Acquire) {
+            READY => return true,
+            POISONED => return false,
+            _ => {}
+        }
+
+        match checkpoint::run(self.setup) {
+            Some(value) => {
+                // SAFETY: the slot is EMPTY, so nothing else has a reference to the value
+                unsafe { (*self.value.get()).write(value) };
+                self.sequence
+                    .store(SEQUENCE.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+                self.state.store(READY, Ordering::Release);
+                true
+            }
+            None => {
+                // Test-scoped fixtures get another chance with the next test
+                if self.scope == Scope::Run {
+                    self.state.store(POISONED, Ordering::Release);
+                }
+                false
+            }
+        }
+    }
+
+    fn tear_down(&self) -> bool {
+        if self
+            .state
+            .compare_exchange(READY, EMPTY, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return true;
+        }
+        // SAFETY: the slot was READY, so the value is initialized, and the test using it is done
+        checkpoint::run(|| unsafe { (*self.value.get()).assume_init_drop() }).is_some()
+    }
+
+    fn sequence(&self) -> Option<usize> {
+        (self.state.load(Ordering::Acquire) == READY).then(|| self.sequence.load(Ordering::Relaxed))
+    }
+}
+
+/// Find `T`'s fixture slot. Tests register their fixtures as pointers to
+/// this, since trait methods can't be called in a `static` initializer.
+pub fn slot_of<T: Fixture>() -> &'static dyn AnySlot {
+    T::slot()
+}
@@ -0,0 +1,141 @@
+//! Memory leak detection around tests.
+//!
+//! Once the kernel installs a [`LeakCheck`] with [`check_leaks`], the runner
+//! snapshots memory usage through its [`MemoryProbe`] before and after each
+//! test. A test that leaves more memory allocated than the threshold allows
+//! fails, and its report shows how much each allocator grew. If the probe
+//! can attribute page frames to owners, the owners whose frames grew are
+//! listed too.
+//!
+//! Snapshots are taken after the test's fixtures are set up and before
+//! they're torn down, so memory that fixtures hold isn't counted. Tests that
+//! panic aren't checked, since they can't clean up after themselves. Some
+//! growth is expected even from correct tests, like caches filling on first
+//! use, which is what the threshold is for.
+
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Maximum number of owners that snapshots can break page frames down by
+pub const MAX_OWNERS: usize = 16;
+
+/// How much memory the kernel has allocated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes allocated from the heap
+    pub heap_bytes: u64,
+    /// Page frames allocated from the frame allocator
+    pub frames: u64,
+}
+
+/// Reads the kernel's memory usage for leak checks
+pub trait MemoryProbe: Sync {
+    fn usage(&self) -> MemoryUsage;
+
+    /// Call `visit` with the number of page frames allocated for each owner,
+    /// if the kernel tracks them. By default, nothing is tracked.
+    fn owners(&self, _visit: &mut dyn FnMut(&'static str, u64)) {}
+}
+
+/// Leak detection settings
+pub struct LeakCheck {
+    pub probe: &'static dyn MemoryProbe,
+    /// How much each kind of usage can grow across a test before it fails
+    pub threshold: MemoryUsage,
+}
+
+static LEAK_CHECK: AtomicPtr<LeakCheck> = AtomicPtr::new(ptr::null_mut());
+
+/// Check every test that runs from now on for leaks
+pub fn check_leaks(check: &'static LeakCheck) {
+    LEAK_CHECK.store(
+        check as *const LeakCheck as *mut LeakCheck,
+        Ordering::Release,
+    );
+}
+
+/// Memory usage at some point in a test run
+pub(crate) struct Snapshot {
+    usage: MemoryUsage,
+    owners: [(&'static str, u64); MAX_OWNERS],
+    owner_count: usize,
+}
+
+/// How a test's memory usage grew, if by more than the threshold
+pub(crate) struct Leak {
+    before: Snapshot,
+    after: Snapshot,
+}
+
+/// Take a snapshot, if leak checking is on
+pub(crate) fn snapshot() -> Option<Snapshot> {
+    // SAFETY: LEAK_CHECK only ever points to a 'static LeakCheck
+    let check = unsafe { LEAK_CHECK.load(Ordering::Acquire).as_ref() }?;
+    let mut snapshot = Snapshot {
+        usage: check.probe.usage(),
+        owners: [("", 0); MAX_OWNERS],
+        owner_count: 0,
+    };
+    check.probe.owners(&mut |owner, frames| {
+        if snapshot.owner_count < MAX_OWNERS {
+            snapshot.owners[snapshot.owner_count] = (owner, frames);
+            snapshot.owner_count += 1;
+        }
+    });
+    Some(snapshot)
+}
+
+/// Compare a snapshot from before a test with the current usage
+pub(crate) fn check(before: Snapshot) -> Option<Leak> {
+    // SAFETY: LEAK_CHECK only ever points to a 'static LeakCheck
+    let check = unsafe { LEAK_CHECK.load(Ordering::Acquire).as_ref() }?;
+    let after = snapshot()?;
+    let heap = after
+        .usage
+        .heap_bytes
+        .saturating_sub(before.usage.heap_bytes);
+    let frames = after.usage.frames.saturating_sub(before.usage.frames);
+    (heap > check.threshold.heap_bytes || frames > check.threshold.frames)
+        .then_some(Leak { before, after })
+}
+
+impl Snapshot {
+    fn owners(&self) -> &[(&'static str, u64)] {
+        &self.owners[..self.owner_count]
+    }
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (before, after) = (self.before.usage, self.after.usage);
+        write!(
+            f,
+            "heap {:+} bytes, {:+} page frames",
+            delta(before.heap_bytes, after.heap_bytes),
+            delta(before.frames, after.frames)
+        )?;
+
+        let mut grew = self.after.owners().iter().filter_map(|&(owner, frames)| {
+            let previous = self
+                .before
+                .owners()
+                .iter()
+                .find(|(o, _)| *o == owner)
+                .map_or(0, |&(_, frames)| frames);
+            (frames > previous).then(|| (owner, frames - previous))
+        });
+        if let Some((owner, frames)) = grew.next() {
+            write!(f, " (grew: {owner} +{frames}")?;
+            for (owner, frames) in grew {
+                write!(f, ", {owner} +{frames}")?;
+            }
+            f.write_char(')')?;
+        }
+        Ok(())
+    }
+}
+
+fn delta(before: u64, after: u64) -> i64 {
+    (after as i64).wrapping_sub(before as i64)
+}
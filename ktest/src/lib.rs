@@ -1,11 +1,67 @@
+//! Kernel unit test framework.
+//!
+//! Tests can be registered in two ways:
+//! * `#[ktest::test]` functions, which are collected into a [`linkme`]
+//!   distributed slice. This doesn't require any crate-level attributes.
+//! * `#[test_case]` statics of type [`Test`], with the `custom_test_frameworks`
+//!   feature and `#![test_runner(ktest::runner)]`.
+//!
+//! Both kinds of tests share the same runner, QEMU exit handling, and panic
+//! reporting. If the kernel's panic handler calls [`recover_from_panic`], a
+//! panicking test is marked as failed and the runner continues with the next
+//! test.
+//!
+//! Like standard Rust tests, `#[ktest::test]` functions can be marked with
+//! `#[should_panic]` or `#[should_panic(expected = "...")]`. They pass only
+//! if they panic (with a message containing `expected`, if given). This
+//! requires panic recovery.
+//!
+//! Table-driven tests can take arguments, with one `#[case(name, args...)]`
+//! attribute per set of arguments:
+//!
+//! ```ignore
+//! #[ktest::test]
+//! #[case(zero, 0, 0)]
+//! #[case(unaligned, 4097, 8192)]
+//! fn test_align_up(value: usize, expected: usize) {
+//!     ktassert_eq!(value.next_multiple_of(4096), expected);
+//! }
+//! ```
+//!
+//! Each case is registered and reported as its own test, named like
+//! `module::test_align_up::unaligned`.
+//!
+//! Tests without cases can instead take `&T` parameters for
+//! [fixtures](fixture), which the runner sets up before the test and tears down
+//! afterwards.
+//!
+//! Each test's outcome is also reported as a structured event under
+//! [`TEST_RESULT_TARGET`], so that test runners can collect results without
+//! parsing log messages.
+//!
+//! The kernel can also have the runner [check tests for memory leaks](leak).
 #![no_std]
 
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use fixture::{AnySlot, Scope};
 use linkme::distributed_slice;
-use qemu_exit::QEMUExit;
+use platypos_hal::power::{exit_vm, ExitCode};
+use platypos_ktrace_proto::TEST_RESULT_TARGET;
 
 pub mod assertions;
+mod checkpoint;
+pub mod fixture;
+pub mod leak;
+mod shard;
 
-pub use ktest_macros::test;
+pub use fixture::Fixture;
+pub use ktest_macros::{fixture, test};
+pub use leak::{check_leaks, LeakCheck, MemoryProbe, MemoryUsage};
+pub use shard::Shard;
 
 // For expansion in the macro
 #[doc(hidden)]
@@ -17,7 +73,20 @@ pub use linkme;
 pub struct Test {
     name: &'static str,
     imp: fn() -> Outcome,
-    // TODO: support should_fail, etc.
+    should_panic: ShouldPanic,
+    /// Source text of the arguments, for parameterized test cases
+    arguments: Option<&'static str>,
+    /// Fixtures the test takes, in parameter order
+    fixtures: &'static [fn() -> &'static dyn AnySlot],
+}
+
+/// Whether a test is expected to panic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShouldPanic {
+    No,
+    Yes,
+    /// The test must panic with a message containing this string
+    WithMessage(&'static str),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -26,53 +95,314 @@ pub enum Outcome {
     Fail,
 }
 
+impl Outcome {
+    /// Name of the outcome in test result events
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+        }
+    }
+}
+
 #[doc(hidden)]
 #[distributed_slice]
 pub static TESTS: [Test] = [..];
 
+/// Functions called by [`exit`] before leaving the VM, so the kernel can
+/// flush trace output and otherwise shut down cleanly. Without them, the last
+/// test results can be lost with the VM.
+#[distributed_slice]
+pub static EXIT_HOOKS: [fn()] = [..];
+
+/// The test that is currently running, if any. This is used to attribute
+/// panics to the test that caused them.
+static CURRENT_TEST: AtomicPtr<Test> = AtomicPtr::new(ptr::null_mut());
+
+/// Whether the last panic matched the current test's [`ShouldPanic`]
+/// expectation
+static PANIC_MATCHED: AtomicBool = AtomicBool::new(false);
+
 /// Test framework entry point. The kernel calls this when running in test mode,
 /// after performing the bare minimum platform setup (for example, initializing
 /// logging and memory allocation).
 pub fn run_tests() -> ! {
+    runner(&[])
+}
+
+/// Like [`run_tests`], but only runs tests whose names contain one of the
+/// comma-separated patterns in `filter`. An empty filter runs every test.
+pub fn run_filtered_tests(filter: &str) -> ! {
+    run_matching(&[], filter, Shard::ALL)
+}
+
+/// Like [`run_filtered_tests`], but only runs the tests in `shard`
+pub fn run_shard(filter: &str, shard: Shard) -> ! {
+    run_matching(&[], filter, shard)
+}
+
+/// Test runner for `custom_test_frameworks`. This runs `tests` along with all
+/// tests registered via `#[ktest::test]`.
+pub fn runner(tests: &[&'static Test]) -> ! {
+    run_matching(tests, "", Shard::ALL)
+}
+
+fn run_matching(tests: &[&'static Test], filter: &str, shard: Shard) -> ! {
     let _enter = tracing::info_span!("run_tests").entered();
-    tracing::info!("Running {} kernel tests", TESTS.len());
+    let matches = |test: &&'static Test| {
+        let mut patterns = filter.split(',').map(str::trim).filter(|p| !p.is_empty());
+        shard.contains(test.name)
+            && (filter.trim().is_empty() || patterns.any(|p| test.name.contains(p)))
+    };
+    if shard != Shard::ALL {
+        tracing::info!("Running test shard {}", shard);
+    }
+
+    let all = TESTS.len() + tests.len();
+    let total = TESTS
+        .iter()
+        .chain(tests.iter().copied())
+        .filter(matches)
+        .count();
+    if total < all {
+        tracing::info!(
+            "Running {} kernel tests ({} filtered out)",
+            total,
+            all - total
+        );
+    } else {
+        tracing::info!("Running {} kernel tests", total);
+    }
     let mut failures = 0;
 
-    for test in TESTS {
-        let result = (test.imp)();
-        match result {
-            Outcome::Pass => tracing::info!("{}... OK", test.name),
-            Outcome::Fail => {
-                failures += 1;
-                tracing::error!("{}... FAIL", test.name);
-            }
+    for test in TESTS.iter().chain(tests.iter().copied()).filter(matches) {
+        if test.run() == Outcome::Fail {
+            failures += 1;
         }
     }
-    tracing::info!(
-        "Done! {} passed and {} failed",
-        TESTS.len() - failures,
-        failures
-    );
+    tear_down_run_fixtures(TESTS.iter().chain(tests.iter().copied()));
+    tracing::info!("Done! {} passed and {} failed", total - failures, failures);
 
     exit(failures == 0);
 }
 
+/// Resume the test runner after the current test panicked. The test is marked
+/// as failed, and the runner continues with the next one. This returns only if
+/// no test is running.
+///
+/// # Safety
+/// This must only be called from the panic handler, on the stack of the
+/// panicking code.
+pub unsafe fn recover_from_panic(info: &PanicInfo) {
+    // SAFETY: CURRENT_TEST only ever points to a 'static Test
+    if let Some(test) = CURRENT_TEST.load(Ordering::Acquire).as_ref() {
+        PANIC_MATCHED.store(test.should_panic.matches(info), Ordering::Release);
+    }
+    checkpoint::resume();
+}
+
+/// Name of the currently-running test, if any. The kernel panic handler uses
+/// this to report which test panicked before exiting.
+pub fn current_test() -> Option<&'static str> {
+    // SAFETY: CURRENT_TEST only ever points to a 'static Test
+    unsafe { CURRENT_TEST.load(Ordering::Acquire).as_ref() }.map(|t| t.name)
+}
+
 impl Test {
     pub const fn new(name: &'static str, imp: fn() -> Outcome) -> Self {
-        Test { name, imp }
+        Test {
+            name,
+            imp,
+            should_panic: ShouldPanic::No,
+            arguments: None,
+            fixtures: &[],
+        }
+    }
+
+    /// Set whether this test is expected to panic
+    pub const fn with_should_panic(self, should_panic: ShouldPanic) -> Self {
+        Test {
+            should_panic,
+            ..self
+        }
+    }
+
+    /// Record the arguments a parameterized test case is called with, so they
+    /// can be reported if it fails
+    pub const fn with_arguments(self, arguments: &'static str) -> Self {
+        Test {
+            arguments: Some(arguments),
+            ..self
+        }
+    }
+
+    /// Set the fixtures the test takes, in parameter order
+    pub const fn with_fixtures(self, fixtures: &'static [fn() -> &'static dyn AnySlot]) -> Self {
+        Test { fixtures, ..self }
+    }
+
+    /// Run this test, reporting its outcome
+    fn run(&'static self) -> Outcome {
+        // Fixtures are set up and torn down as part of the test, so their panics are recovered
+        // from too
+        CURRENT_TEST.store(self as *const Test as *mut Test, Ordering::Release);
+        let mut outcome = match self.fixtures.iter().find(|slot| !slot().set_up()) {
+            Some(slot) => {
+                tracing::error!("{}... FAIL (fixture {} panicked)", self.name, slot().name());
+                Outcome::Fail
+            }
+            None => {
+                PANIC_MATCHED.store(false, Ordering::Release);
+                let before = leak::snapshot();
+                let result = checkpoint::run(self.imp);
+                let leak = match (result, before) {
+                    (Some(Outcome::Pass), Some(before)) => leak::check(before),
+                    _ => None,
+                };
+                match leak {
+                    Some(leak) => {
+                        tracing::error!("{}... FAIL (leaked memory: {})", self.name, leak);
+                        Outcome::Fail
+                    }
+                    None => self.report(result),
+                }
+            }
+        };
+
+        for slot in self.fixtures.iter().rev().map(|slot| slot()) {
+            if slot.scope() == Scope::Test && !slot.tear_down() {
+                tracing::error!(
+                    "{}... FAIL (fixture {} teardown panicked)",
+                    self.name,
+                    slot.name()
+                );
+                outcome = Outcome::Fail;
+            }
+        }
+        CURRENT_TEST.store(ptr::null_mut(), Ordering::Release);
+
+        if let (Outcome::Fail, Some(arguments)) = (outcome, self.arguments) {
+            tracing::error!("{} was called with ({})", self.name, arguments);
+        }
+        tracing::debug!(target: TEST_RESULT_TARGET, test = self.name, outcome = outcome.as_str());
+        outcome
+    }
+
+    /// Log the result of running this test, and decide whether it passed
+    fn report(&self, result: Option<Outcome>) -> Outcome {
+        match (result, self.should_panic) {
+            (Some(Outcome::Pass), ShouldPanic::No) => {
+                tracing::info!("{}... OK", self.name);
+                Outcome::Pass
+            }
+            (Some(Outcome::Fail), _) => {
+                tracing::error!("{}... FAIL", self.name);
+                Outcome::Fail
+            }
+            (Some(Outcome::Pass), _) => {
+                tracing::error!("{}... FAIL (did not panic)", self.name);
+                Outcome::Fail
+            }
+            (None, ShouldPanic::No) => {
+                tracing::error!("{}... FAIL (panicked)", self.name);
+                Outcome::Fail
+            }
+            (None, _) if PANIC_MATCHED.load(Ordering::Acquire) => {
+                tracing::info!("{}... OK (panicked as expected)", self.name);
+                Outcome::Pass
+            }
+            (None, _) => {
+                tracing::error!(
+                    "{}... FAIL (panic did not match {:?})",
+                    self.name,
+                    self.should_panic
+                );
+                Outcome::Fail
+            }
+        }
+    }
+}
+
+/// Tear down every run-scoped fixture that `tests` set up, most recent first.
+/// No test is running, so a panic here isn't recovered from.
+fn tear_down_run_fixtures<'a>(tests: impl Iterator<Item = &'a Test> + Clone) {
+    loop {
+        let last = tests
+            .clone()
+            .flat_map(|test| test.fixtures.iter().map(|slot| slot()))
+            .filter(|slot| slot.scope() == Scope::Run)
+            .filter_map(|slot| Some((slot.sequence()?, slot)))
+            .max_by_key(|&(sequence, _)| sequence);
+        match last {
+            Some((_, slot)) => {
+                slot.tear_down();
+            }
+            None => break,
+        }
+    }
+}
+
+impl ShouldPanic {
+    /// Check whether `info` satisfies this expectation
+    fn matches(&self, info: &PanicInfo) -> bool {
+        match self {
+            ShouldPanic::No => false,
+            ShouldPanic::Yes => true,
+            ShouldPanic::WithMessage(expected) => {
+                let mut message = MessageBuffer::new();
+                let _ = write!(message, "{}", info);
+                message.as_str().contains(expected)
+            }
+        }
+    }
+}
+
+/// Fixed-size buffer for formatting panic messages without allocating. Longer
+/// messages are truncated.
+struct MessageBuffer {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl MessageBuffer {
+    fn new() -> Self {
+        Self {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // SAFETY: `write_str` only copies whole characters
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
     }
 }
 
-/// Exits the VM
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Exits the VM with [`ExitCode::Success`] or [`ExitCode::TestFailure`], after
+/// running the [`EXIT_HOOKS`]. Outside of a VM, this spins forever instead.
 pub fn exit(success: bool) -> ! {
-    #[cfg(target_arch = "x86_64")]
-    let handle = qemu_exit::X86::new(0xf4, 3);
-    #[cfg(not(target_arch = "x86_64"))]
-    compile_error!("QEMU exit not configured for {}" cfg!(target_arch));
+    for hook in EXIT_HOOKS {
+        hook();
+    }
 
-    if success {
-        handle.exit_success()
+    exit_vm(if success {
+        ExitCode::Success
     } else {
-        handle.exit_failure()
+        ExitCode::TestFailure
+    });
+    loop {
+        core::hint::spin_loop();
     }
 }
@@ -0,0 +1,55 @@
+//! Splitting tests across several runs.
+//!
+//! `cargo xtask test --shards N` boots N VMs at once, and tells each one which
+//! [`Shard`] of the tests to run. Tests are assigned to shards by a hash of
+//! their names, so every VM agrees on the split without any coordination, and
+//! a test stays in the same shard as others are added or removed.
+
+use core::fmt;
+
+/// One of `count` disjoint subsets of the registered tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    index: u32,
+    count: u32,
+}
+
+impl Shard {
+    /// The only shard of an unsharded run, which contains every test
+    pub const ALL: Shard = Shard { index: 0, count: 1 };
+
+    /// The `index`th of `count` shards, counting from 0. Returns `None` if
+    /// `index` is out of range.
+    pub const fn new(index: u32, count: u32) -> Option<Self> {
+        if index < count {
+            Some(Shard { index, count })
+        } else {
+            None
+        }
+    }
+
+    /// Parse a shard written as `INDEX/COUNT`, like `0/4`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (index, count) = s.trim().split_once('/')?;
+        Self::new(index.parse().ok()?, count.parse().ok()?)
+    }
+
+    /// Whether the test named `name` belongs to this shard
+    pub fn contains(&self, name: &str) -> bool {
+        fnv1a(name) % u64::from(self.count) == u64::from(self.index)
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// 64-bit FNV-1a, which is stable across builds and platforms, unlike the
+/// hashers in `core`
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
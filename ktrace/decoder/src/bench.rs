@@ -0,0 +1,191 @@
+//! Reads frame allocator benchmark results, and compares them to a baseline.
+//!
+//! The kernel's benchmarks each report an
+//! [`ALLOC_BENCH_EVENT`](proto::ALLOC_BENCH_EVENT) with the mean time per
+//! operation. Baselines are stored as text, one `name ns_per_op` line per
+//! benchmark, so they're easy to review when they change.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+/// One benchmark's result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchResult {
+    pub name: String,
+    /// Frames per allocation
+    pub pages: u64,
+    /// Number of operations timed
+    pub ops: u64,
+    /// Mean time per operation, in nanoseconds
+    pub ns_per_op: u64,
+}
+
+impl BenchResult {
+    /// Extract a benchmark result from `message`, if it is one
+    pub fn from_message(message: &proto::ReceiverMessage) -> Option<Self> {
+        let proto::Message::Event(event) = message else {
+            return None;
+        };
+        if event.metadata.name != proto::ALLOC_BENCH_EVENT {
+            return None;
+        }
+
+        let mut result = BenchResult {
+            name: String::new(),
+            pages: 0,
+            ops: 0,
+            ns_per_op: 0,
+        };
+        for (field, value) in event.fields.iter() {
+            match (*field, value) {
+                ("bench", proto::Value::String(s)) => result.name = s.to_string(),
+                ("pages", proto::Value::U64(n)) => result.pages = *n,
+                ("ops", proto::Value::U64(n)) => result.ops = *n,
+                ("ns_per_op", proto::Value::U64(n)) => result.ns_per_op = *n,
+                _ => {}
+            }
+        }
+        Some(result)
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}ns/op ({} ops of {} pages)",
+            self.name, self.ns_per_op, self.ops, self.pages
+        )
+    }
+}
+
+/// Stored benchmark times, in nanoseconds per operation, by benchmark name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Baseline(pub BTreeMap<String, u64>);
+
+impl Baseline {
+    pub fn from_results<'a>(results: impl IntoIterator<Item = &'a BenchResult>) -> Self {
+        Self(
+            results
+                .into_iter()
+                .map(|r| (r.name.clone(), r.ns_per_op))
+                .collect(),
+        )
+    }
+
+    /// Parse a stored baseline. Blank lines and `#` comments are ignored.
+    /// Returns `None` if any other line is malformed.
+    pub fn parse(text: &str) -> Option<Self> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (name, ns) = line.split_once(char::is_whitespace)?;
+                Some((name.to_string(), ns.trim().parse().ok()?))
+            })
+            .collect::<Option<_>>()
+            .map(Self)
+    }
+
+    /// Compare `results` against this baseline. Each benchmark may be up to
+    /// `tolerance_percent` slower than its baseline before it counts as a
+    /// regression. Benchmarks missing from either side are skipped.
+    pub fn compare<'a>(
+        &self,
+        results: impl IntoIterator<Item = &'a BenchResult>,
+        tolerance_percent: u64,
+    ) -> Vec<Comparison> {
+        results
+            .into_iter()
+            .filter_map(|result| {
+                let baseline = *self.0.get(&result.name)?;
+                let limit = baseline + baseline * tolerance_percent / 100;
+                Some(Comparison {
+                    name: result.name.clone(),
+                    baseline,
+                    current: result.ns_per_op,
+                    regressed: result.ns_per_op > limit,
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Baseline {
+    /// Formats in the form [`Baseline::parse`] reads
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, ns) in self.0.iter() {
+            writeln!(f, "{name} {ns}")?;
+        }
+        Ok(())
+    }
+}
+
+/// How one benchmark did compared to its baseline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    pub name: String,
+    pub baseline: u64,
+    pub current: u64,
+    /// Whether the benchmark got slower by more than the tolerance
+    pub regressed: bool,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let change = (self.current as f64 / self.baseline.max(1) as f64 - 1.0) * 100.0;
+        write!(
+            f,
+            "{}: {}ns/op (baseline {}ns/op, {change:+.1}%)",
+            self.name, self.current, self.baseline
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(name: &str, ns_per_op: u64) -> BenchResult {
+        BenchResult {
+            name: name.to_string(),
+            pages: 1,
+            ops: 100,
+            ns_per_op,
+        }
+    }
+
+    #[test]
+    fn test_baseline_round_trip() {
+        let baseline = Baseline::from_results(&[result("single", 120), result("large", 900)]);
+        let text = baseline.to_string();
+        assert_eq!(text, "large 900\nsingle 120\n");
+        assert_eq!(
+            Baseline::parse(&format!("# comment\n\n{text}")),
+            Some(baseline)
+        );
+        assert_eq!(Baseline::parse("single fast"), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let baseline = Baseline::parse("single 100\nlarge 1000\nold 5").unwrap();
+        let results = [
+            result("single", 110),
+            result("large", 1200),
+            result("new", 1),
+        ];
+        let comparisons = baseline.compare(&results, 10);
+        let regressed = comparisons
+            .iter()
+            .map(|c| (c.name.as_str(), c.regressed))
+            .collect::<Vec<_>>();
+        assert_eq!(regressed, [("single", false), ("large", true)]);
+        assert_eq!(
+            comparisons[1].to_string(),
+            "large: 1200ns/op (baseline 1000ns/op, +20.0%)"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Reads the kernel's boot environment report.
+//!
+//! Early in boot, the kernel records a
+//! [`BOOT_ENVIRONMENT_EVENT`](proto::BOOT_ENVIRONMENT_EVENT) describing the
+//! loader and firmware it booted from, along with a fingerprint of the memory
+//! map and the kernel's build ID. Keeping these with each run makes it easier
+//! to tell whether a flaky boot lines up with a particular environment, and
+//! which kernel image it was.
+
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEnvironment {
+    pub loader: String,
+    pub vendor: String,
+    pub revision: u64,
+    pub secure_boot: String,
+    /// Memory map fingerprint, as hex
+    pub memory_map: String,
+    /// The kernel's build ID, as hex. Empty if it doesn't have one.
+    pub build_id: String,
+}
+
+impl BootEnvironment {
+    /// Extract the boot environment from `message`, if it's a report
+    pub fn from_message(message: &proto::ReceiverMessage) -> Option<Self> {
+        let proto::Message::Event(event) = message else {
+            return None;
+        };
+        if event.metadata.name != proto::BOOT_ENVIRONMENT_EVENT {
+            return None;
+        }
+
+        let mut env = BootEnvironment {
+            loader: String::new(),
+            vendor: String::new(),
+            revision: 0,
+            secure_boot: String::new(),
+            memory_map: String::new(),
+            build_id: String::new(),
+        };
+        for (field, value) in event.fields.iter() {
+            match (*field, value) {
+                ("loader", proto::Value::String(s)) => env.loader = s.to_string(),
+                ("vendor", proto::Value::String(s)) => env.vendor = s.to_string(),
+                ("revision", proto::Value::U64(n)) => env.revision = *n,
+                ("secure_boot", proto::Value::String(s)) => env.secure_boot = s.to_string(),
+                ("hash", proto::Value::Bytes { bytes, .. }) => {
+                    env.memory_map = hex(bytes);
+                }
+                ("build_id", proto::Value::Bytes { bytes, .. }) => env.build_id = hex(bytes),
+                _ => {}
+            }
+        }
+        Some(env)
+    }
+}
+
+impl fmt::Display for BootEnvironment {
+    /// Formats as a single line of `key=value` pairs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "loader={:?} vendor={:?} revision={} secure-boot={} memory-map={} build-id={}",
+            self.loader,
+            self.vendor,
+            self.revision,
+            self.secure_boot,
+            self.memory_map,
+            self.build_id
+        )
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,70 @@
+//! Reads the kernel's boot time summary.
+//!
+//! Once the kernel finishes booting, it logs a single-line summary under
+//! [`BOOT_TIME_TARGET`](proto::BOOT_TIME_TARGET), with the time from the
+//! start of boot to each boot phase, like
+//! `reset=0us loader-handoff=1523004us memory-init=1531127us`. The first
+//! phase is where times are measured from: reset, if the kernel could tell
+//! how long the firmware and loader took, and otherwise the loader handoff.
+
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+/// Time from the start of boot to each boot phase, in the order the kernel
+/// reported them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootTimes {
+    pub phases: Vec<(String, u64)>,
+}
+
+impl BootTimes {
+    /// Extract the boot time summary from `message`, if it is one
+    pub fn from_message(message: &proto::ReceiverMessage) -> Option<Self> {
+        let proto::Message::Event(event) = message else {
+            return None;
+        };
+        if event.metadata.target != proto::BOOT_TIME_TARGET {
+            return None;
+        }
+        event.fields.iter().find_map(|(field, value)| match value {
+            proto::Value::String(line) if *field == "message" => Self::parse(line),
+            _ => None,
+        })
+    }
+
+    /// Parse a summary line. Returns `None` if any phase is malformed.
+    pub fn parse(line: &str) -> Option<Self> {
+        let phases = line
+            .split_whitespace()
+            .map(|pair| {
+                let (name, micros) = pair.split_once('=')?;
+                let micros = micros.strip_suffix("us")?.parse().ok()?;
+                Some((name.to_string(), micros))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { phases })
+    }
+
+    /// Total boot time, in microseconds
+    pub fn total_micros(&self) -> u64 {
+        self.phases
+            .iter()
+            .map(|&(_, micros)| micros)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Display for BootTimes {
+    /// Formats as the kernel's summary line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, micros)) in self.phases.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{name}={micros}us")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+//! Correlating kernel timestamps with host wall-clock time.
+//!
+//! Kernel timestamps are clock ticks since boot, which don't line up with
+//! anything on the host. [`ClockSync`] pairs the timestamps in events, like
+//! the scheduler's `ticks`, with when the host received those events, and fits
+//! a line through the pairs to estimate the offset between the two clocks and
+//! how fast they drift apart. The resulting [`ClockFit`] converts any kernel
+//! timestamp to wall-clock time.
+//!
+//! Receive times are always late, by however long the kernel batched the
+//! event and the serial port took to deliver it, and that delay varies. So
+//! the slope of the fit is a Theil-Sen estimate, which shrugs off bursts of
+//! delayed samples, and the line is anchored near the low end of the
+//! residuals, since the earliest arrivals were delayed the least.
+//!
+//! Live decoding can take receive times straight from the system clock. For
+//! saved captures, a [`ReceiveLog`] saved alongside records when each chunk
+//! of the capture was read.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use platypos_ktrace_proto as proto;
+
+/// Maximum number of samples the fit uses. Theil-Sen looks at every pair of
+/// samples, so longer traces are thinned out evenly.
+const MAX_FIT_SAMPLES: usize = 512;
+
+/// Quantile of the residuals that the fitted line passes through
+const ANCHOR_QUANTILE: f64 = 0.1;
+
+/// A kernel timestamp, in whichever units the event recorded it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelTime {
+    /// Clock ticks, which need the kernel's calibration to convert
+    Ticks(u64),
+    /// Microseconds, from older kernels
+    Micros(u64),
+}
+
+impl KernelTime {
+    /// Find the timestamp among an event's numeric fields
+    pub fn from_fields<'a>(fields: impl IntoIterator<Item = (&'a str, u64)>) -> Option<Self> {
+        fields.into_iter().find_map(|(name, value)| match name {
+            "ticks" => Some(KernelTime::Ticks(value)),
+            "time_us" => Some(KernelTime::Micros(value)),
+            _ => None,
+        })
+    }
+
+    /// Find the timestamp in a decoded event's fields
+    pub fn from_event(fields: &proto::DeserializedFields) -> Option<Self> {
+        Self::from_fields(fields.iter().filter_map(|(name, value)| match value {
+            proto::Value::U64(n) => Some((*name, *n)),
+            _ => None,
+        }))
+    }
+
+    /// Seconds since the kernel's clock started, if it can be converted with
+    /// `ticks_per_second`
+    pub fn seconds(self, ticks_per_second: Option<u64>) -> Option<f64> {
+        match self {
+            KernelTime::Ticks(ticks) => {
+                let rate = ticks_per_second.filter(|&t| t != 0)?;
+                Some(ticks as f64 / rate as f64)
+            }
+            KernelTime::Micros(micros) => Some(micros as f64 / 1e6),
+        }
+    }
+}
+
+/// Collects (kernel timestamp, receive time) pairs to correlate the clocks
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    ticks_per_second: Option<u64>,
+    samples: Vec<(KernelTime, SystemTime)>,
+    /// Most recent fit, and how many samples it used
+    cached: Option<(ClockFit, usize)>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a decoded message that the host received at `received`
+    pub fn receive(&mut self, message: &proto::ReceiverMessage, received: SystemTime) {
+        match message {
+            proto::Message::Calibration { ticks_per_second } => {
+                self.set_ticks_per_second(*ticks_per_second)
+            }
+            proto::Message::Event(event) => {
+                if let Some(time) = KernelTime::from_event(&event.fields) {
+                    self.add_sample(time, received);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Record the kernel's clock rate. Timestamps from before the kernel sent
+    /// it are still used.
+    pub fn set_ticks_per_second(&mut self, ticks_per_second: u64) {
+        self.ticks_per_second = Some(ticks_per_second).filter(|&t| t != 0);
+        self.cached = None;
+    }
+
+    pub fn ticks_per_second(&self) -> Option<u64> {
+        self.ticks_per_second
+    }
+
+    /// Record that an event timestamped `time` arrived at `received`
+    pub fn add_sample(&mut self, time: KernelTime, received: SystemTime) {
+        self.samples.push((time, received));
+    }
+
+    /// Fit the samples so far, or `None` if there aren't at least two usable
+    /// samples at different kernel times
+    pub fn fit(&self) -> Option<ClockFit> {
+        let points: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .filter_map(|&(time, received)| {
+                let kernel = time.seconds(self.ticks_per_second)?;
+                let host = received.duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+                Some((kernel, host))
+            })
+            .collect();
+        let step = points.len().div_ceil(MAX_FIT_SAMPLES).max(1);
+        let points: Vec<(f64, f64)> = points.into_iter().step_by(step).collect();
+
+        let mut slopes = Vec::new();
+        for (i, &(k1, h1)) in points.iter().enumerate() {
+            for &(k2, h2) in &points[i + 1..] {
+                if k2 != k1 {
+                    slopes.push((h2 - h1) / (k2 - k1));
+                }
+            }
+        }
+        let rate = quantile(&mut slopes, 0.5)?;
+
+        let mut residuals: Vec<f64> = points.iter().map(|&(k, h)| h - rate * k).collect();
+        let offset = quantile(&mut residuals, ANCHOR_QUANTILE)?;
+
+        Some(ClockFit {
+            offset,
+            rate,
+            ticks_per_second: self.ticks_per_second,
+            samples: points.len(),
+        })
+    }
+
+    /// Like [`ClockSync::fit`], but only refits once enough new samples have
+    /// arrived to make a difference, for calling on every message
+    pub fn current_fit(&mut self) -> Option<ClockFit> {
+        let fitted = self.cached.map_or(0, |(_, count)| count);
+        let count = self.samples.len();
+        if self.cached.is_none() || count >= fitted + (fitted / 8).max(16) {
+            self.cached = self.fit().map(|fit| (fit, count));
+        }
+        self.cached.map(|(fit, _)| fit)
+    }
+}
+
+/// Linear mapping from kernel time to host wall-clock time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockFit {
+    /// Host time, in seconds since the Unix epoch, when the kernel's clock
+    /// read zero
+    offset: f64,
+    /// Host seconds per kernel second
+    rate: f64,
+    ticks_per_second: Option<u64>,
+    /// Number of samples the fit used
+    samples: usize,
+}
+
+impl ClockFit {
+    /// Wall-clock time at kernel timestamp `time`, if it can be converted
+    pub fn wall_clock(&self, time: KernelTime) -> Option<SystemTime> {
+        let seconds = time.seconds(self.ticks_per_second)?;
+        Some(from_unix_seconds(self.offset + self.rate * seconds))
+    }
+
+    /// Wall-clock time when the kernel's clock started
+    pub fn clock_start(&self) -> SystemTime {
+        from_unix_seconds(self.offset)
+    }
+
+    /// How much faster the host clock runs than the kernel's, in parts per
+    /// million. This includes any error in the kernel's calibration.
+    pub fn drift_ppm(&self) -> f64 {
+        (self.rate - 1.0) * 1e6
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+}
+
+impl fmt::Display for ClockFit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kernel clock started at {}, drift {:+.1} ppm ({} samples)",
+            WallClock(self.clock_start()),
+            self.drift_ppm(),
+            self.samples
+        )
+    }
+}
+
+fn from_unix_seconds(seconds: f64) -> SystemTime {
+    if seconds >= 0.0 {
+        UNIX_EPOCH + Duration::from_secs_f64(seconds)
+    } else {
+        UNIX_EPOCH
+    }
+}
+
+/// The `q` quantile of `values`, which are reordered
+fn quantile(values: &mut [f64], q: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let index = ((values.len() - 1) as f64 * q).round() as usize;
+    let (_, value, _) = values.select_nth_unstable_by(index, f64::total_cmp);
+    Some(*value)
+}
+
+/// Displays a wall-clock time in UTC, like `2024-03-01 12:34:56.789012Z`. The
+/// alternate form, `{:#}`, leaves out the date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WallClock(pub SystemTime);
+
+impl fmt::Display for WallClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = since_epoch.as_secs();
+        let (days, time) = (seconds / 86_400, seconds % 86_400);
+        let (hour, minute, second) = (time / 3600, time / 60 % 60, time % 60);
+        let micros = since_epoch.subsec_micros();
+        if !f.alternate() {
+            let (year, month, day) = civil_from_days(days);
+            write!(f, "{year:04}-{month:02}-{day:02} ")?;
+        }
+        write!(f, "{hour:02}:{minute:02}:{second:02}.{micros:06}")?;
+        if !f.alternate() {
+            f.write_str("Z")?;
+        }
+        Ok(())
+    }
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) date, using
+/// Howard Hinnant's algorithm
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// When each chunk of a capture arrived. This is saved next to the capture
+/// (see [`ReceiveLog::path_for`]) as one line per chunk, with the length of
+/// the capture after the chunk and the Unix time it arrived in nanoseconds.
+#[derive(Debug, Default)]
+pub struct ReceiveLog {
+    /// (capture length, arrival time), in capture order
+    entries: Vec<(u64, SystemTime)>,
+}
+
+impl ReceiveLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where the receive log for `capture` is saved
+    pub fn path_for(capture: &Path) -> PathBuf {
+        capture.with_extension("times")
+    }
+
+    /// Load a saved receive log
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).wrap_err_with(|| format!("could not open {}", path.display()))?;
+        let mut log = Self::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let entry = line
+                .split_once(' ')
+                .and_then(|(offset, nanos)| Some((offset.parse().ok()?, nanos.parse().ok()?)))
+                .ok_or_else(|| eyre!("malformed receive log entry on line {}", number + 1))?;
+            log.record(entry.0, UNIX_EPOCH + Duration::from_nanos(entry.1));
+        }
+        Ok(log)
+    }
+
+    /// Write one line of a receive log, for saving it as the capture is made
+    pub fn write_entry<W: Write>(mut w: W, offset: u64, received: SystemTime) -> io::Result<()> {
+        let nanos = received
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        writeln!(w, "{offset} {nanos}")
+    }
+
+    /// Record that the capture was `offset` bytes long once the data up to
+    /// there arrived at `received`
+    pub fn record(&mut self, offset: u64, received: SystemTime) {
+        self.entries.push((offset, received));
+    }
+
+    /// When the byte just before `offset` arrived, if it's covered by the log
+    pub fn received(&self, offset: u64) -> Option<SystemTime> {
+        let index = self.entries.partition_point(|&(end, _)| end < offset);
+        self.entries.get(index).map(|&(_, received)| received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: f64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn test_fit_ignores_delays() {
+        let mut sync = ClockSync::new();
+        sync.set_ticks_per_second(1_000_000);
+        let start = 1_700_000_000.0;
+        for i in 0..200u64 {
+            let kernel = i as f64 * 0.01;
+            // Most samples arrive 1ms late, and every 7th is held up in a batch
+            let delay = if i % 7 == 0 { 0.05 } else { 0.001 };
+            let host = start + kernel * (1.0 + 50e-6) + delay;
+            sync.add_sample(KernelTime::Ticks(i * 10_000), at(host));
+        }
+
+        let fit = sync.fit().unwrap();
+        assert!((fit.drift_ppm() - 50.0).abs() < 1.0, "{fit}");
+        let clock_start = fit.clock_start().duration_since(at(start)).unwrap();
+        assert!(clock_start.as_secs_f64() < 0.0015, "{fit}");
+    }
+
+    #[test]
+    fn test_fit_needs_calibration() {
+        let mut sync = ClockSync::new();
+        sync.add_sample(KernelTime::Ticks(0), at(10.0));
+        sync.add_sample(KernelTime::Ticks(100), at(11.0));
+        assert_eq!(sync.fit(), None);
+
+        sync.set_ticks_per_second(100);
+        let fit = sync.fit().unwrap();
+        assert_eq!(fit.wall_clock(KernelTime::Ticks(50)), Some(at(10.5)));
+        assert_eq!(
+            fit.wall_clock(KernelTime::Micros(2_000_000)),
+            Some(at(12.0))
+        );
+    }
+
+    #[test]
+    fn test_wall_clock_display() {
+        let time = at(1_709_296_496.789_012_5);
+        assert_eq!(WallClock(time).to_string(), "2024-03-01 12:34:56.789012Z");
+        assert_eq!(format!("{:#}", WallClock(time)), "12:34:56.789012");
+    }
+
+    #[test]
+    fn test_receive_log() {
+        let mut log = ReceiveLog::new();
+        log.record(64, at(1.0));
+        log.record(100, at(2.0));
+        assert_eq!(log.received(10), Some(at(1.0)));
+        assert_eq!(log.received(64), Some(at(1.0)));
+        assert_eq!(log.received(65), Some(at(2.0)));
+        assert_eq!(log.received(101), None);
+
+        let mut line = Vec::new();
+        ReceiveLog::write_entry(&mut line, 64, at(1.5)).unwrap();
+        assert_eq!(line, b"64 1500000000\n");
+    }
+}
@@ -0,0 +1,237 @@
+//! Reads crash reports.
+//!
+//! Any boot stage that crashes sends a [`proto::CrashReport`] as its last
+//! message. [`Crash`] is an owned copy of one, which renders the same way no
+//! matter which stage it came from.
+//!
+//! The kernel also saves crashes in a dump that it reports on the next boot,
+//! along with the last messages it sent beforehand, which
+//! [`previous_trace`] decodes.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+pub use proto::CrashSource;
+
+/// A crash report from the kernel or loader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crash {
+    pub source: CrashSource,
+    pub reason: String,
+    /// Source location as `file:line:column`
+    pub location: Option<String>,
+    /// Registers, named by [`proto::CRASH_REGISTERS`], if they were captured
+    pub registers: Vec<u64>,
+    /// Return addresses, innermost first
+    pub backtrace: Vec<u64>,
+    pub frames_omitted: bool,
+    pub build_id: Option<[u8; proto::BUILD_ID_LEN]>,
+}
+
+impl Crash {
+    /// Extract the crash report from `message`, if it is one
+    pub fn from_message(message: &proto::ReceiverMessage) -> Option<Self> {
+        match message {
+            proto::Message::Crash(report) => Some(report.into()),
+            _ => None,
+        }
+    }
+
+    /// Extract the crash report from `message`, if it's one from an earlier
+    /// boot
+    pub fn from_previous(message: &proto::ReceiverMessage) -> Option<Self> {
+        match message {
+            proto::Message::PreviousCrash(report) => Some(report.into()),
+            _ => None,
+        }
+    }
+
+    /// The build ID as hex, if the report had one
+    pub fn build_id_hex(&self) -> Option<String> {
+        self.build_id
+            .map(|id| id.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Render the crash, formatting return addresses and the instruction
+    /// pointer with `symbolize`
+    pub fn display<'a, F>(&'a self, symbolize: F) -> impl fmt::Display + 'a
+    where
+        F: Fn(u64, &mut fmt::Formatter) -> fmt::Result + 'a,
+    {
+        DisplayCrash {
+            crash: self,
+            symbolize,
+        }
+    }
+}
+
+impl From<&proto::CrashReport<'_>> for Crash {
+    fn from(report: &proto::CrashReport<'_>) -> Self {
+        Crash {
+            source: report.source,
+            reason: report.reason.to_string(),
+            location: report
+                .location
+                .map(|l| format!("{}:{}:{}", l.file, l.line, l.column)),
+            registers: report.registers.iter().collect(),
+            backtrace: report.backtrace.iter().collect(),
+            frames_omitted: report.frames_omitted,
+            build_id: report.build_id,
+        }
+    }
+}
+
+impl fmt::Display for Crash {
+    /// Formats as a multi-line report, with addresses in hex
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(|address, f| write!(f, "{address:#018x}"))
+            .fmt(f)
+    }
+}
+
+/// Decode the messages in a [`proto::Message::PreviousTrace`], passing each
+/// to `f`. Interned metadata is resolved if it was defined within the trace,
+/// and is [`proto::Metadata::UNKNOWN`] otherwise. Decoding stops at the first
+/// malformed message.
+pub fn previous_trace<F>(mut data: &[u8], mut f: F)
+where
+    F: FnMut(proto::ReceiverMessage),
+{
+    let mut callsites = HashMap::new();
+    while let Ok((message, rest)) = postcard::take_from_bytes(data) {
+        f(crate::resolve_metadata(&mut callsites, message));
+        data = rest;
+    }
+}
+
+struct DisplayCrash<'a, F> {
+    crash: &'a Crash,
+    symbolize: F,
+}
+
+impl<'a, F> fmt::Display for DisplayCrash<'a, F>
+where
+    F: Fn(u64, &mut fmt::Formatter) -> fmt::Result,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let crash = self.crash;
+        write!(f, "{} crashed: {}", crash.source, crash.reason)?;
+        if let Some(ref location) = crash.location {
+            write!(f, "\n  at {location}")?;
+        }
+        if let Some(id) = crash.build_id_hex() {
+            write!(f, "\n  build ID {id}")?;
+        }
+
+        let named = proto::CRASH_REGISTERS.iter().zip(&crash.registers);
+        for (i, (name, &value)) in named.enumerate() {
+            f.write_str(if i % 4 == 0 { "\n  " } else { " " })?;
+            if *name == "rip" {
+                write!(f, "{name:>6}=")?;
+                (self.symbolize)(value, f)?;
+            } else {
+                write!(f, "{name:>6}={value:016x}")?;
+            }
+        }
+
+        if !crash.backtrace.is_empty() {
+            f.write_str("\n  backtrace:")?;
+        }
+        for (i, &address) in crash.backtrace.iter().enumerate() {
+            write!(f, "\n  {i:>4}: ")?;
+            (self.symbolize)(address, f)?;
+        }
+        if crash.frames_omitted {
+            f.write_str("\n        ... <frames omitted>")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::OwnedMetadata;
+
+    #[test]
+    fn test_from_report() {
+        let mut report = proto::CrashReport::new(CrashSource::Kernel, "out of frames");
+        report.location = Some(proto::CrashLocation {
+            file: "kernel/src/mm.rs",
+            line: 12,
+            column: 5,
+        });
+        let mut backtrace = [0; 8 * 4];
+        report.backtrace = proto::Words::encode(0x1000..0x1004, &mut backtrace);
+        report.frames_omitted = true;
+        report.build_id = Some([0xab; proto::BUILD_ID_LEN]);
+
+        let mut buf = [0u8; 1024];
+        let message = proto::Message::<(), ()>::Crash(report);
+        let bytes = postcard::to_slice(&message, &mut buf).unwrap();
+        let decoded: proto::ReceiverMessage = postcard::from_bytes(bytes).unwrap();
+
+        let crash = Crash::from_message(&decoded).unwrap();
+        assert_eq!(crash.reason, "out of frames");
+        assert_eq!(crash.location.as_deref(), Some("kernel/src/mm.rs:12:5"));
+        assert_eq!(crash.backtrace, [0x1000, 0x1001, 0x1002, 0x1003]);
+        assert!(crash.registers.is_empty());
+        assert!(crash.frames_omitted);
+        assert_eq!(
+            crash.build_id_hex().unwrap(),
+            "ab".repeat(proto::BUILD_ID_LEN)
+        );
+
+        let rendered = crash.to_string();
+        assert!(rendered.starts_with("kernel crashed: out of frames\n  at kernel/src/mm.rs:12:5"));
+        assert!(rendered.contains("     1: 0x0000000000001001"));
+        assert!(rendered.ends_with("<frames omitted>"));
+    }
+
+    #[test]
+    fn test_previous_trace() {
+        type Fields = BTreeMap<&'static str, u64>;
+        let metadata = proto::Metadata {
+            name: "tick",
+            target: "platypos_kernel::sched",
+            level: proto::Level::Debug,
+            file: None,
+            line: None,
+        };
+        let event = |id| {
+            proto::Message::<Fields, Fields>::InternedEvent(proto::Event {
+                span_id: proto::Parent::Root,
+                context: proto::ExecutionContext::Task,
+                metadata: id,
+                fields: Fields::new(),
+            })
+        };
+        let messages = [
+            // Defined before the tail starts
+            event(3),
+            proto::Message::MetadataDefined { id: 7, metadata },
+            event(7),
+        ];
+
+        let mut data = Vec::new();
+        let mut buf = [0u8; 128];
+        for message in &messages {
+            data.extend_from_slice(postcard::to_slice(message, &mut buf).unwrap());
+        }
+        // A message cut off partway through is ignored
+        data.push(0xff);
+
+        let mut events = Vec::new();
+        previous_trace(&data, |message| {
+            if let proto::Message::Event(event) = message {
+                events.push(OwnedMetadata::from(&event.metadata));
+            }
+        });
+        let expected = [proto::Metadata::UNKNOWN, metadata].map(|m| OwnedMetadata::from(&m));
+        assert_eq!(events, expected);
+    }
+}
@@ -0,0 +1,577 @@
+//! Filter expressions over spans and events.
+//!
+//! A capture holds far more than any one investigation needs. A [`Filter`]
+//! picks out the interesting spans and events with an expression like
+//! `target == "platypos_kernel::mm" && level <= debug`, and a [`Matcher`]
+//! applies it to a stream of messages, so the formatter, replayer, stats, and
+//! report can all be given the same subset. Expressions compare fields:
+//!
+//! | Field       | Operators                   | Compares                                 |
+//! |-------------|-----------------------------|------------------------------------------|
+//! | `target`    | `==` `!=` `~=`              | The target, or with `==` a module in it  |
+//! | `name`      | `==` `!=` `~=`              | The span or event's name                 |
+//! | `span`      | `==` `!=` `~=`              | The span's own name, or any it's in      |
+//! | `level`     | `==` `!=` `<` `<=` `>` `>=` | The level, from `error` up to `trace`    |
+//! | `processor` | `==` `!=` `<` `<=` `>` `>=` | The processor it was recorded on         |
+//!
+//! `~=` matches strings that contain the value, and `!=` matches whatever
+//! `==` doesn't. Values are quoted strings or bare words, and comparisons are
+//! combined with `&&`, `||`, `!`, and parentheses.
+//!
+//! Only events with a contextual parent know which processor they're on, so
+//! `processor` comparisons don't match the rest. Entering, exiting, and
+//! closing a span are kept or dropped along with the span, so consumers never
+//! see half of one. Messages that aren't about spans or events, like crash
+//! reports and processor topology, always match.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use platypos_ktrace_proto::{self as proto, ReceiverMessage};
+
+use crate::spans::{SpanKey, SpanTree};
+
+/// A parsed filter expression. The default filter matches everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    expr: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Field, Op),
+}
+
+/// A field to compare, along with the value to compare it to
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Target(String),
+    Name(String),
+    Span(String),
+    Level(proto::Level),
+    Processor(proto::ProcessorId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Contains,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn is_ordering(self) -> bool {
+        matches!(self, Op::Lt | Op::Le | Op::Gt | Op::Ge)
+    }
+
+    fn compare<T: Ord>(self, actual: T, value: T) -> bool {
+        match self {
+            Op::Eq => actual == value,
+            Op::Ne => actual != value,
+            Op::Lt => actual < value,
+            Op::Le => actual <= value,
+            Op::Gt => actual > value,
+            Op::Ge => actual >= value,
+            Op::Contains => false,
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Contains => "~=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        })
+    }
+}
+
+/// What a span or event is matched against
+struct Subject<'a> {
+    metadata: &'a proto::Metadata<'a>,
+    processor: Option<proto::ProcessorId>,
+    /// Names of the span itself, if it is one, and the spans it's in
+    spans: &'a [&'a str],
+}
+
+impl Expr {
+    fn matches(&self, subject: &Subject) -> bool {
+        match self {
+            Expr::Not(expr) => !expr.matches(subject),
+            Expr::And(lhs, rhs) => lhs.matches(subject) && rhs.matches(subject),
+            Expr::Or(lhs, rhs) => lhs.matches(subject) || rhs.matches(subject),
+            Expr::Compare(field, op) => field.matches(*op, subject),
+        }
+    }
+}
+
+impl Field {
+    fn matches(&self, op: Op, subject: &Subject) -> bool {
+        if op == Op::Ne {
+            return !self.matches(Op::Eq, subject);
+        }
+        match self {
+            Field::Target(value) => match op {
+                Op::Contains => subject.metadata.target.contains(value.as_str()),
+                _ => in_module(subject.metadata.target, value),
+            },
+            Field::Name(value) => match op {
+                Op::Contains => subject.metadata.name.contains(value.as_str()),
+                _ => subject.metadata.name == value,
+            },
+            Field::Span(value) => subject.spans.iter().any(|name| match op {
+                Op::Contains => name.contains(value.as_str()),
+                _ => name == value,
+            }),
+            Field::Level(value) => op.compare(verbosity(subject.metadata.level), verbosity(*value)),
+            Field::Processor(value) => subject
+                .processor
+                .is_some_and(|processor| op.compare(processor, *value)),
+        }
+    }
+}
+
+/// Whether `target` is `module` or one of its submodules
+fn in_module(target: &str, module: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+/// How verbose `level` is, so that `error` is the lowest level and `trace`
+/// the highest, as in `tracing`
+fn verbosity(level: proto::Level) -> u8 {
+    match level {
+        proto::Level::Error => 0,
+        proto::Level::Warn => 1,
+        proto::Level::Info => 2,
+        proto::Level::Debug => 3,
+        proto::Level::Trace => 4,
+    }
+}
+
+impl FromStr for Filter {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?.into_iter().peekable(),
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.next() {
+            bail!("unexpected {token} in filter");
+        }
+        Ok(Filter { expr: Some(expr) })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "`{word}`"),
+            Token::Op(op) => write!(f, "`{op}`"),
+            Token::And => f.write_str("`&&`"),
+            Token::Or => f.write_str("`||`"),
+            Token::Not => f.write_str("`!`"),
+            Token::Open => f.write_str("`(`"),
+            Token::Close => f.write_str("`)`"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut chars = s.chars().peekable();
+    let mut tokens = Vec::new();
+    while let Some(c) = chars.next() {
+        let mut followed_by = |next| chars.next_if_eq(&next).is_some();
+        let token = match c {
+            _ if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' if followed_by('&') => Token::And,
+            '|' if followed_by('|') => Token::Or,
+            '=' if followed_by('=') => Token::Op(Op::Eq),
+            '~' if followed_by('=') => Token::Op(Op::Contains),
+            '!' if followed_by('=') => Token::Op(Op::Ne),
+            '!' => Token::Not,
+            '<' if followed_by('=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if followed_by('=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '"' => Token::Word(quoted(&mut chars)?),
+            _ if is_word_char(c) => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|&c| is_word_char(c)) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            _ => bail!("unexpected `{c}` in filter"),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | ':' | '.' | '-')
+}
+
+/// The rest of a quoted string, after the opening quote
+fn quoted(chars: &mut Peekable<Chars>) -> Result<String> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => s.extend(chars.next()),
+            Some(c) => s.push(c),
+            None => bail!("unterminated string in filter"),
+        }
+    }
+}
+
+/// Recursive descent parser for filter expressions, where `!` binds tightest
+/// and `||` loosest
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => bail!("missing `)` in filter"),
+                }
+            }
+            Some(Token::Word(field)) => self.comparison(&field),
+            Some(token) => bail!("expected a comparison, found {token}"),
+            None => bail!("filter ended early"),
+        }
+    }
+
+    fn comparison(&mut self, field: &str) -> Result<Expr> {
+        let op = match self.tokens.next() {
+            Some(Token::Op(op)) => op,
+            _ => bail!("expected an operator after `{field}`"),
+        };
+        let Some(Token::Word(value)) = self.tokens.next() else {
+            bail!("expected a value after `{field} {op}`");
+        };
+
+        let field = match field {
+            "target" | "name" | "span" if op.is_ordering() => {
+                bail!("`{field}` can't be compared with `{op}`")
+            }
+            "level" | "processor" if op == Op::Contains => {
+                bail!("`{field}` can't be compared with `{op}`")
+            }
+            "target" => Field::Target(value),
+            "name" => Field::Name(value),
+            "span" => Field::Span(value),
+            "level" => Field::Level(parse_level(&value)?),
+            "processor" => Field::Processor(
+                value
+                    .parse()
+                    .map_err(|_| eyre!("invalid processor `{value}`"))?,
+            ),
+            _ => bail!("unknown filter field `{field}`"),
+        };
+        Ok(Expr::Compare(field, op))
+    }
+}
+
+fn parse_level(s: &str) -> Result<proto::Level> {
+    Ok(match s.to_ascii_lowercase().as_str() {
+        "error" => proto::Level::Error,
+        "warn" => proto::Level::Warn,
+        "info" => proto::Level::Info,
+        "debug" => proto::Level::Debug,
+        "trace" => proto::Level::Trace,
+        _ => bail!("unknown level `{s}`"),
+    })
+}
+
+/// Applies a [`Filter`] to a stream of messages, keeping track of spans so
+/// that events can be matched by the spans they're in
+pub struct Matcher {
+    filter: Filter,
+    spans: SpanTree<SpanInfo>,
+}
+
+struct SpanInfo {
+    name: String,
+    /// Whether the span matched when it was created
+    kept: bool,
+}
+
+impl Matcher {
+    pub fn new(filter: Filter) -> Self {
+        Self {
+            filter,
+            spans: SpanTree::new(),
+        }
+    }
+
+    /// Whether `message` should be passed on. Every message must go through
+    /// here, in order, even if the caller ignores some kinds.
+    pub fn matches(&mut self, message: &ReceiverMessage) -> bool {
+        let Some(expr) = &self.filter.expr else {
+            return true;
+        };
+        let kept = match message {
+            proto::Message::SpanCreated(span) => {
+                let parent = self.spans.resolve(&span.parent);
+                let mut names = self.span_names(parent);
+                names.insert(0, span.metadata.name);
+                let kept = expr.matches(&Subject {
+                    metadata: &span.metadata,
+                    processor: processor(&span.parent),
+                    spans: &names,
+                });
+                let name = span.metadata.name.to_string();
+                self.spans
+                    .create(span.id, &span.parent, |_, _| SpanInfo { name, kept });
+                kept
+            }
+            proto::Message::Event(event) => {
+                let parent = self.spans.resolve(&event.span_id);
+                expr.matches(&Subject {
+                    metadata: &event.metadata,
+                    processor: processor(&event.span_id),
+                    spans: &self.span_names(parent),
+                })
+            }
+            proto::Message::Suppressed { metadata, .. } => expr.matches(&Subject {
+                metadata,
+                processor: None,
+                spans: &[],
+            }),
+            &proto::Message::SpanEntered { id, processor, .. } => {
+                let key = self.spans.enter(id, processor);
+                self.kept(key)
+            }
+            &proto::Message::SpanExited { id, processor, .. } => {
+                let key = self.spans.resolve(&proto::Parent::Explicit(id));
+                self.spans.exit(id, processor);
+                self.kept(key)
+            }
+            &proto::Message::SpanClosed { id } => {
+                let key = self.spans.close(id);
+                self.kept(key)
+            }
+            &proto::Message::ContextSwitched {
+                processor, context, ..
+            } => {
+                self.spans.switch_context(processor, context);
+                true
+            }
+            _ => true,
+        };
+        // Whoever displays the trace reports anomalies, so there's no need to keep them here
+        self.spans.take_anomalies();
+        kept
+    }
+
+    /// Names of `key` and its ancestors, innermost first
+    fn span_names(&self, key: Option<SpanKey>) -> Vec<&str> {
+        key.map(|key| self.spans.ancestors(key))
+            .into_iter()
+            .flatten()
+            .map(|(_, span)| span.name.as_str())
+            .collect()
+    }
+
+    /// Whether the span `key` was kept. Unknown spans are kept, since
+    /// there's no way to tell whether they'd match.
+    fn kept(&self, key: Option<SpanKey>) -> bool {
+        key.and_then(|key| self.spans.get(key))
+            .is_none_or(|span| span.kept)
+    }
+}
+
+/// The processor a span or event with `parent` was recorded on, if it's known
+fn processor(parent: &proto::Parent) -> Option<proto::ProcessorId> {
+    match *parent {
+        proto::Parent::Current(processor) => Some(processor),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    type Fields = BTreeMap<&'static str, u64>;
+    type Message = proto::Message<'static, Fields, Fields>;
+
+    fn metadata(
+        name: &'static str,
+        target: &'static str,
+        level: proto::Level,
+    ) -> proto::Metadata<'static> {
+        proto::Metadata {
+            name,
+            target,
+            level,
+            file: None,
+            line: None,
+        }
+    }
+
+    fn span(id: proto::SpanId, name: &'static str, processor: proto::ProcessorId) -> Message {
+        proto::Message::SpanCreated(proto::SpanCreated {
+            id,
+            parent: proto::Parent::Current(processor),
+            context: proto::ExecutionContext::Task,
+            metadata: metadata(name, "platypos_kernel::sched", proto::Level::Info),
+            fields: Fields::new(),
+        })
+    }
+
+    fn event(target: &'static str, level: proto::Level, processor: proto::ProcessorId) -> Message {
+        proto::Message::Event(proto::Event {
+            span_id: proto::Parent::Current(processor),
+            context: proto::ExecutionContext::Task,
+            metadata: metadata("event", target, level),
+            fields: Fields::new(),
+        })
+    }
+
+    /// Which of `messages` `filter` keeps, after a round trip through
+    /// postcard to get [`ReceiverMessage`]s
+    fn kept(filter: &str, messages: &[Message]) -> Vec<bool> {
+        let mut matcher = Matcher::new(filter.parse().unwrap());
+        let mut buf = [0u8; 256];
+        messages
+            .iter()
+            .map(|message| {
+                let bytes = postcard::to_slice(message, &mut buf).unwrap();
+                let decoded: ReceiverMessage = postcard::from_bytes(bytes).unwrap();
+                matcher.matches(&decoded)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_metadata() {
+        let messages = [
+            event("platypos_kernel::mm", proto::Level::Debug, 0),
+            event("platypos_kernel::mm::frame", proto::Level::Trace, 0),
+            event("platypos_kernel::mmio", proto::Level::Warn, 0),
+            event("platypos_hal", proto::Level::Error, 0),
+        ];
+        let mm_debug = r#"target=="platypos_kernel::mm" && level<=Debug"#;
+        assert_eq!(kept(mm_debug, &messages), [true, false, false, false]);
+        assert_eq!(
+            kept("target ~= mm || !(level > warn)", &messages),
+            [true, true, true, true]
+        );
+        assert_eq!(
+            kept("target != platypos_kernel::mm && level == ERROR", &messages),
+            [false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_spans_and_processors() {
+        let messages = [
+            span(1, "schedule", 0),
+            proto::Message::SpanEntered {
+                id: 1,
+                processor: 0,
+                ticks: 0,
+            },
+            span(2, "switch", 0),
+            event("platypos_kernel::sched", proto::Level::Info, 0),
+            event("platypos_kernel::sched", proto::Level::Info, 1),
+            proto::Message::SpanExited {
+                id: 1,
+                processor: 0,
+                ticks: 0,
+            },
+            proto::Message::SpanClosed { id: 1 },
+            event("platypos_kernel::sched", proto::Level::Info, 0),
+            proto::Message::Calibration {
+                ticks_per_second: 1,
+            },
+        ];
+        assert_eq!(
+            kept("span == schedule", &messages),
+            [true, true, true, true, false, true, true, false, true]
+        );
+        assert_eq!(
+            kept("name ~= wit || processor == 1", &messages),
+            [false, false, true, false, true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for filter in [
+            "",
+            "target",
+            "target ==",
+            "level ~= info",
+            "name < x",
+            "level == loud",
+            "processor == -1",
+            "(name == x",
+            "name == x)",
+            "name == \"x",
+            "colour == red",
+            "name = x",
+        ] {
+            assert!(filter.parse::<Filter>().is_err(), "{filter:?} parsed");
+        }
+        assert!(Matcher::new(Filter::default()).matches(&proto::Message::SpanClosed { id: 1 }));
+    }
+}
@@ -1,122 +1,478 @@
 //! Stateful pretty-printer for ktrace
+//!
+//! Output can be tuned with [`Options`]:
+//! * [`Profile::Pretty`] (the default) draws spans as nested boxes
+//! * [`Profile::Compact`] prints one line per event, prefixed by its span
+//!   stack, which works better in CI logs
+//! * [`Profile::Verbose`] is like `Pretty`, but also shows each field's
+//!   declared type and source locations
+//!
+//! Field values are rendered based on their schema type: kernel addresses are
+//! symbolized, physical and virtual addresses are printed in hex, byte sizes
+//! use human-readable units, and raw bytes and arrays are printed in hex, up
+//! to [`Options::max_bytes`] and [`Options::max_array_len`]. Fields can get
+//! custom rendering from [`Renderers`], set with [`Formatter::set_renderers`].
+//! Spans and events that don't match the schema the kernel declared for them
+//! are flagged, as are span lifecycle
+//! problems found by [`SpanTree`]. Verbose output also labels events with the
+//! processor they were recorded on, and where it is in the topology.
+//!
+//! Spans and events from interrupt handlers are tagged `[irq]` or `[nmi]`, so
+//! handler-side output stands out from task code.
+//!
+//! Crash reports are rendered as one block with a symbolized backtrace,
+//! whether the kernel or the loader sent them. Crashes from an earlier boot,
+//! which the kernel recovers from its crash dump, are rendered as warnings,
+//! followed by the events logged just before them.
+//!
+//! With [`Options::wall_clock`], events passed to [`Formatter::receive_at`] are
+//! stamped with the host's time of day. Events with a kernel timestamp get the
+//! time it corresponds to according to [`ClockSync`]; others get the time they
+//! were received, marked with a `~`.
 
-use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
+use std::time::SystemTime;
 
-use owo_colors::{OwoColorize, Stream};
 use platypos_ktrace_proto as proto;
 
+use crate::clock::{ClockSync, KernelTime, WallClock};
+use crate::crash::Crash;
+use crate::schema::Schemas;
+use crate::spans::{SpanKey, SpanTree};
+use crate::theme::{Padded, Paint, Theme};
+use crate::topology::ProcessorMap;
+
+pub use crate::theme::ColorMode;
+
+pub use self::render::{ByteSizes, MemoryMap, Names, Renderer, Renderers};
+
+mod render;
+
 pub struct Formatter<S: Symbolizer> {
-    spans: HashMap<proto::SpanId, SpanState>,
-    span_stacks: HashMap<proto::ProcessorId, Vec<proto::SpanId>>,
+    spans: SpanTree<SpanState>,
+    schemas: Schemas,
+    processors: ProcessorMap,
     symbolizer: S,
+    options: Options,
+    renderers: Renderers,
+    clock: ClockSync,
+    /// When the message being formatted was received, if known
+    received: Option<SystemTime>,
 }
 
 /// Interface for resolving `KernelAddress` values into symbols.
 pub trait Symbolizer {
     fn symbolize(&self, address: u64, f: &mut fmt::Formatter) -> fmt::Result;
+
+    /// Names of the functions containing `address`, innermost first, with
+    /// a function inlined into another before it. Empty if unknown.
+    fn functions(&self, address: u64) -> Vec<String> {
+        let _ = address;
+        Vec::new()
+    }
+}
+
+/// Output configuration for a [`Formatter`]
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub profile: Profile,
+    pub color: ColorMode,
+    pub theme: Theme,
+    /// Maximum number of bytes to print for byte slice fields
+    pub max_bytes: usize,
+    /// Maximum number of elements to print for array fields
+    pub max_array_len: usize,
+    /// Stamp events with the host's time of day
+    pub wall_clock: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            profile: Profile::default(),
+            color: ColorMode::default(),
+            theme: Theme::default(),
+            max_bytes: 32,
+            max_array_len: proto::MAX_ARRAY_LEN,
+            wall_clock: false,
+        }
+    }
+}
+
+/// Output layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    #[default]
+    Pretty,
+    Compact,
+    Verbose,
 }
 
 impl<S: Symbolizer> Formatter<S> {
     pub fn new(symbolizer: S) -> Self {
+        Self::with_options(symbolizer, Options::default())
+    }
+
+    pub fn with_options(symbolizer: S, options: Options) -> Self {
         Formatter {
-            spans: HashMap::new(),
-            span_stacks: HashMap::new(),
+            spans: SpanTree::new(),
+            schemas: Schemas::new(),
+            processors: ProcessorMap::new(),
             symbolizer,
+            options,
+            renderers: Renderers::new(),
+            clock: ClockSync::new(),
+            received: None,
         }
     }
 
-    fn stack(&mut self, processor: proto::ProcessorId) -> &mut Vec<proto::SpanId> {
-        self.span_stacks.entry(processor).or_default()
+    /// Render fields with `renderers` where they apply
+    pub fn set_renderers(&mut self, renderers: Renderers) {
+        self.renderers = renderers;
     }
 
-    fn resolve_parent(&mut self, parent: &proto::Parent) -> Option<proto::SpanId> {
-        match parent {
-            proto::Parent::Root => None,
-            proto::Parent::Current(processor) => self.stack(*processor).last().cloned(),
-            proto::Parent::Explicit(id) => Some(*id),
-        }
+    /// Depth of an event in `parent`
+    fn event_depth(&self, parent: Option<SpanKey>) -> usize {
+        parent
+            .and_then(|k| self.spans.get(k))
+            .map_or(0, |s| s.depth)
+            + 1
     }
 
     pub fn receive(&mut self, message: &proto::ReceiverMessage) {
+        self.receive_message(message);
+        for anomaly in self.spans.take_anomalies() {
+            println!(
+                "{} {}",
+                self.level(proto::Level::Warn, "span anomaly:"),
+                anomaly
+            );
+        }
+    }
+
+    /// Like [`Formatter::receive`], for a message the host received at
+    /// `received`. See [`Options::wall_clock`].
+    pub fn receive_at(&mut self, message: &proto::ReceiverMessage, received: SystemTime) {
+        if self.options.wall_clock {
+            self.clock.receive(message, received);
+            self.received = Some(received);
+        }
+        self.receive(message);
+        self.received = None;
+    }
+
+    /// Time of day to stamp an event with, if enabled
+    fn wall_clock(&mut self, fields: &proto::DeserializedFields) -> String {
+        let Some(received) = self.received else {
+            return String::new();
+        };
+        let fitted = KernelTime::from_event(fields)
+            .zip(self.clock.current_fit())
+            .and_then(|(time, fit)| fit.wall_clock(time));
+        match fitted {
+            Some(time) => format!("{:#}  ", WallClock(time)),
+            None => format!("{:#}~ ", WallClock(received)),
+        }
+    }
+
+    fn receive_message(&mut self, message: &proto::ReceiverMessage) {
         match message {
             proto::Message::SpanCreated(span) => {
-                let parent = self
-                    .resolve_parent(&span.parent)
-                    .and_then(|s| self.spans.get(&s));
-                let depth = parent.map_or(0, |s| s.depth + 1);
-
-                let state = SpanState {
-                    id: span.id,
-                    depth,
-                    name: span.metadata.name.to_string(),
-                    target: span.metadata.target.to_string(),
-                    level: span.metadata.level,
-                };
-                print!(
-                    "{}╔ {} {}",
-                    Indent::spaces(depth),
-                    LevelColor(span.metadata.level, span.metadata.level),
-                    state.name()
-                );
-                if let Some(parent) = parent {
-                    print!(" ⇜ {}", parent.name());
-                }
-                println!();
-                if !span.fields.is_empty() {
-                    println!(
-                        "{}  {}",
+                let key = self
+                    .spans
+                    .create(span.id, &span.parent, |key, parent| SpanState {
+                        key,
+                        depth: parent.map_or(0, |p| p.depth + 1),
+                        name: span.metadata.name.to_string(),
+                        target: span.metadata.target.to_string(),
+                        level: span.metadata.level,
+                    });
+                let state = self.spans.get(key).unwrap();
+                let depth = state.depth;
+                let parent = self.spans.parent(key).and_then(|k| self.spans.get(k));
+                if self.options.profile != Profile::Compact {
+                    print!(
+                        "{}╔ {} {}{}",
                         Indent::spaces(depth),
-                        DisplayFields {
-                            fields: &span.fields,
-                            depth: depth + 2,
-                            symbolizer: &self.symbolizer,
-                        }
+                        self.level(span.metadata.level, span.metadata.level),
+                        self.context_tag(span.context),
+                        state.name()
                     );
+                    if let Some(parent) = parent {
+                        print!(" ⇜ {}", parent.name());
+                    }
+                    println!();
+                    if self.options.profile == Profile::Verbose {
+                        write_location(depth + 1, &span.metadata);
+                    }
+                    if !span.fields.is_empty() {
+                        println!(
+                            "{}  {}",
+                            Indent::spaces(depth),
+                            self.fields(&span.fields, depth + 2)
+                        );
+                    }
                 }
-                self.spans.insert(span.id, state);
+                self.check_schema(
+                    proto::SchemaKind::Span,
+                    &span.metadata,
+                    &span.fields,
+                    depth + 1,
+                );
             }
             proto::Message::Event(event) => {
-                let depth = self
-                    .resolve_parent(&event.span_id)
-                    .and_then(|s| self.spans.get(&s))
-                    .map_or(0, |s| s.depth)
-                    + 1;
-                println!(
-                    "{}└ {} {}",
-                    Indent::spaces(depth),
-                    LevelColor(event.metadata.level, event.metadata.level),
-                    DisplayFields {
-                        fields: &event.fields,
-                        depth: depth + 1,
-                        symbolizer: &self.symbolizer,
+                let parent = self.spans.resolve(&event.span_id);
+                let wall_clock = self.wall_clock(&event.fields);
+                match self.options.profile {
+                    Profile::Compact => {
+                        println!(
+                            "{wall_clock}{} {}{}{} {}",
+                            self.level(event.metadata.level, level_name(event.metadata.level)),
+                            self.context_tag(event.context),
+                            event.metadata.target,
+                            SpanPath {
+                                spans: &self.spans,
+                                current: parent,
+                            },
+                            self.fields(&event.fields, 0)
+                        );
                     }
+                    Profile::Pretty | Profile::Verbose => {
+                        let depth = self.event_depth(parent);
+                        println!(
+                            "{}└ {} {wall_clock}{}{}",
+                            Indent::spaces(depth),
+                            self.level(event.metadata.level, event.metadata.level),
+                            self.context_tag(event.context),
+                            self.fields(&event.fields, depth + 1)
+                        );
+                        if self.options.profile == Profile::Verbose {
+                            write_location(depth + 1, &event.metadata);
+                            if let proto::Parent::Current(processor) = event.span_id {
+                                println!(
+                                    "{}  on {}",
+                                    Indent::spaces(depth + 1),
+                                    self.processors.label(processor)
+                                );
+                            }
+                        }
+                    }
+                }
+                let depth = self.event_depth(parent);
+                self.check_schema(
+                    proto::SchemaKind::Event,
+                    &event.metadata,
+                    &event.fields,
+                    depth,
                 );
             }
             proto::Message::SpanClosed { id } => {
-                if let Some(span) = self.spans.remove(id) {
+                let span = self.spans.close(*id).and_then(|k| self.spans.get(k));
+                if let Some(span) = span {
+                    if self.options.profile != Profile::Compact {
+                        println!(
+                            "{}╚ {} {}",
+                            Indent::spaces(span.depth),
+                            self.level(span.level, "END"),
+                            span.name()
+                        )
+                    }
+                }
+            }
+            proto::Message::SpanEntered { id, processor, .. } => {
+                self.spans.enter(*id, *processor);
+            }
+            proto::Message::SpanExited { id, processor, .. } => {
+                self.spans.exit(*id, *processor);
+            }
+            proto::Message::ContextSwitched {
+                processor, context, ..
+            } => {
+                self.spans.switch_context(*processor, *context);
+            }
+            proto::Message::Processor {
+                processor,
+                location,
+            } => {
+                self.processors.insert(*processor, *location);
+                let label = self.processors.label(*processor);
+                match self.options.profile {
+                    Profile::Compact => println!(
+                        "{} {label} online",
+                        self.level(proto::Level::Info, level_name(proto::Level::Info))
+                    ),
+                    Profile::Pretty | Profile::Verbose => {
+                        println!("{} {label} online", self.level(proto::Level::Info, "⚙"))
+                    }
+                }
+            }
+            proto::Message::Calibration { ticks_per_second } => {
+                if self.options.profile == Profile::Verbose {
                     println!(
-                        "{}╚ {} {}",
-                        Indent::spaces(span.depth),
-                        LevelColor(span.level, "END"),
-                        span.name()
-                    )
+                        "{} clock runs at {ticks_per_second} Hz",
+                        self.level(proto::Level::Debug, "⏱")
+                    );
                 }
             }
-            proto::Message::SpanEntered { id, processor } => {
-                self.stack(*processor).push(*id);
+            // Samples are only useful aggregated into a profile
+            proto::Message::ProfileSample { .. } => {}
+            proto::Message::ProfileSamplesDropped { processor, count } => {
+                if self.options.profile == Profile::Verbose {
+                    println!(
+                        "{} {} dropped {count} profile samples",
+                        self.level(proto::Level::Debug, "⏱"),
+                        self.processors.label(*processor)
+                    );
+                }
+            }
+            proto::Message::Schema(schema) => {
+                self.schemas.declare(schema);
             }
-            proto::Message::SpanExited { id, processor } => {
-                let prev = self.stack(*processor).pop();
-                assert!(prev == Some(*id), "Exited span was not current!");
+            proto::Message::Suppressed { metadata, count } => {
+                let times = if *count == 1 { "time" } else { "times" };
+                match self.options.profile {
+                    Profile::Compact => println!(
+                        "{} {} {} repeated {} more {}",
+                        self.level(metadata.level, level_name(metadata.level)),
+                        metadata.target,
+                        metadata.name,
+                        count,
+                        times
+                    ),
+                    Profile::Pretty | Profile::Verbose => {
+                        println!(
+                            "{} {} repeated {} more {}",
+                            self.level(metadata.level, "↻"),
+                            metadata.name,
+                            count,
+                            times
+                        );
+                        if self.options.profile == Profile::Verbose {
+                            write_location(1, metadata);
+                        }
+                    }
+                }
             }
+            proto::Message::Crash(report) => {
+                let crash = Crash::from(report);
+                let symbolizer = &self.symbolizer;
+                let crash = crash.display(|a, f| symbolizer.symbolize(a, f));
+                match self.options.profile {
+                    Profile::Compact => println!(
+                        "{} {crash}",
+                        self.level(proto::Level::Error, level_name(proto::Level::Error))
+                    ),
+                    Profile::Pretty | Profile::Verbose => {
+                        println!("{} {crash}", self.level(proto::Level::Error, "✖"))
+                    }
+                }
+            }
+            proto::Message::PreviousCrash(report) => {
+                let crash = Crash::from(report);
+                let symbolizer = &self.symbolizer;
+                let crash = crash.display(|a, f| symbolizer.symbolize(a, f));
+                let level = proto::Level::Warn;
+                match self.options.profile {
+                    Profile::Compact => {
+                        println!(
+                            "{} previous boot: {crash}",
+                            self.level(level, level_name(level))
+                        )
+                    }
+                    Profile::Pretty | Profile::Verbose => {
+                        println!("{} previous boot: {crash}", self.level(level, "↶"))
+                    }
+                }
+            }
+            proto::Message::PreviousTrace(data) => {
+                // Spans from the previous boot can't be matched up with anything, so only events
+                // are shown
+                crate::crash::previous_trace(data, |message| {
+                    let proto::Message::Event(event) = message else {
+                        return;
+                    };
+                    let level = event.metadata.level;
+                    match self.options.profile {
+                        Profile::Compact => println!(
+                            "{} previous boot: {} {}",
+                            self.level(level, level_name(level)),
+                            event.metadata.target,
+                            self.fields(&event.fields, 0)
+                        ),
+                        Profile::Pretty | Profile::Verbose => println!(
+                            "{} {} {}",
+                            self.level(level, "↶"),
+                            self.level(level, level),
+                            self.fields(&event.fields, 1)
+                        ),
+                    }
+                });
+            }
+            // The decoder resolves interned metadata
+            proto::Message::MetadataDefined { .. }
+            | proto::Message::InternedSpanCreated(_)
+            | proto::Message::InternedEvent(_) => {}
+        }
+    }
+
+    /// Warn about fields that don't match the declared schema
+    fn check_schema(
+        &self,
+        kind: proto::SchemaKind,
+        metadata: &proto::Metadata,
+        fields: &proto::DeserializedFields,
+        depth: usize,
+    ) {
+        let indent = match self.options.profile {
+            Profile::Compact => 0,
+            _ => depth,
+        };
+        for mismatch in self.schemas.check(kind, metadata.name, fields) {
+            println!(
+                "{}{} {}: {}",
+                Indent::spaces(indent),
+                self.level(proto::Level::Warn, "schema mismatch in"),
+                metadata.name,
+                mismatch
+            );
+        }
+    }
+
+    /// Colorize a value based on a trace level
+    fn level<D: Display>(&self, level: proto::Level, value: D) -> Paint<D> {
+        Paint::new(value, self.options.theme.level(level), self.options.color)
+    }
+
+    /// Tag for output from interrupt handlers, followed by a space. This is
+    /// empty for task context, which is the common case.
+    fn context_tag(&self, context: proto::ExecutionContext) -> String {
+        let style = match context {
+            proto::ExecutionContext::Task => return String::new(),
+            proto::ExecutionContext::Irq => self.options.theme.irq,
+            proto::ExecutionContext::Nmi => self.options.theme.nmi,
+        };
+        format!(
+            "{} ",
+            Paint::new(format!("[{context}]"), style, self.options.color)
+        )
+    }
+
+    fn fields<'a>(
+        &'a self,
+        fields: &'a proto::DeserializedFields<'a>,
+        depth: usize,
+    ) -> DisplayFields<'a, S> {
+        DisplayFields {
+            fields,
+            depth,
+            symbolizer: &self.symbolizer,
+            renderers: &self.renderers,
+            options: self.options,
         }
     }
 }
 
-// TODO: this clutters the output too much
-#[allow(dead_code)]
 fn write_location(depth: usize, metadata: &proto::Metadata) {
     if let Some(file) = metadata.file {
         print!("{}  @ {}", Indent::spaces(depth), file);
@@ -129,11 +485,11 @@ fn write_location(depth: usize, metadata: &proto::Metadata) {
 }
 
 struct SpanState {
+    key: SpanKey,
     depth: usize,
     target: String,
     name: String,
     level: proto::Level,
-    id: u64,
 }
 
 impl SpanState {
@@ -146,52 +502,54 @@ struct SpanName<'a>(&'a SpanState);
 
 impl<'a> fmt::Display for SpanName<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}::{}#{}", self.0.target, self.0.name, self.0.id)
+        write!(f, "{}::{}#{}", self.0.target, self.0.name, self.0.key)
     }
 }
 
-/// Colorize a value based on a trace level
-struct LevelColor<D: fmt::Display>(proto::Level, D);
+/// Span ancestry for compact output, like ` kmain>start:`
+struct SpanPath<'a> {
+    spans: &'a SpanTree<SpanState>,
+    current: Option<SpanKey>,
+}
 
-impl<D: fmt::Display> fmt::Display for LevelColor<D> {
+impl<'a> fmt::Display for SpanPath<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.0 {
-            proto::Level::Error => {
-                write!(
-                    f,
-                    "{}",
-                    self.1.if_supports_color(Stream::Stdout, |l| l.red())
-                )
+        let path: Vec<&str> = match self.current {
+            Some(key) => self
+                .spans
+                .ancestors(key)
+                .map(|(_, s)| s.name.as_str())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        if path.is_empty() {
+            return f.write_str(":");
+        }
+
+        f.write_str(" ")?;
+        for (i, name) in path.iter().rev().enumerate() {
+            if i > 0 {
+                f.write_str(">")?;
             }
-            proto::Level::Warn => write!(
-                f,
-                "{}",
-                self.1.if_supports_color(Stream::Stdout, |l| l.yellow())
-            ),
-            proto::Level::Info => write!(
-                f,
-                "{}",
-                self.1.if_supports_color(Stream::Stdout, |l| l.green())
-            ),
-            proto::Level::Debug => write!(
-                f,
-                "{}",
-                self.1.if_supports_color(Stream::Stdout, |l| l.blue())
-            ),
-            proto::Level::Trace => write!(
-                f,
-                "{}",
-                self.1.if_supports_color(Stream::Stdout, |l| l.dimmed())
-            ),
+            f.write_str(name)?;
         }
+        f.write_str(":")
     }
 }
 
+/// Fixed-width level name, for aligned compact output
+fn level_name(level: proto::Level) -> Padded<proto::Level> {
+    Padded::right(level, 5)
+}
+
 struct DisplayFields<'a, S: Symbolizer> {
     fields: &'a proto::DeserializedFields<'a>,
     // TODO: replace these with a context type
     depth: usize,
     symbolizer: &'a S,
+    renderers: &'a Renderers,
+    options: Options,
 }
 
 impl<'a, S: Symbolizer> fmt::Display for DisplayFields<'a, S> {
@@ -204,55 +562,174 @@ impl<'a, S: Symbolizer> fmt::Display for DisplayFields<'a, S> {
             }
             is_first = false;
 
-            if *name != "message" {
+            let verbose = self.options.profile == Profile::Verbose;
+            if *name != "message" || verbose {
+                let name = Paint::new(name, self.options.theme.field_name, self.options.color);
+                if self.options.profile == Profile::Compact {
+                    write!(f, "{name}=")?;
+                } else {
+                    write!(f, "{name}: ")?;
+                }
+            }
+
+            match self.renderers.render(name, value) {
+                Some(rendered) => f.write_str(&rendered)?,
+                None => self.write_value(value, f)?,
+            }
+
+            if verbose {
+                let ty = format!("<{:?}>", value.field_type());
                 write!(
                     f,
-                    "{}: ",
-                    name.if_supports_color(Stream::Stdout, |n| n.bold())
+                    " {}",
+                    Paint::new(ty, self.options.theme.field_type, self.options.color)
                 )?;
             }
-
-            write_value(value, f, self.depth, self.symbolizer)?;
         }
 
         Ok(())
     }
 }
 
-fn write_value<S: Symbolizer>(
-    value: &proto::Value<'_>,
-    f: &mut fmt::Formatter,
-    depth: usize,
-    symbolizer: &S,
-) -> fmt::Result {
-    match value {
-        proto::Value::KernelAddress(address) => symbolizer.symbolize(*address, f),
-        proto::Value::String(s) => {
-            let mut is_first = true;
-            let mut lines = s.lines().peekable();
-
-            while let Some(line) = lines.next() {
-                if !is_first {
-                    write!(f, "{}", Indent::spaces(depth))?;
+impl<'a, S: Symbolizer> DisplayFields<'a, S> {
+    fn write_value(&self, value: &proto::Value<'_>, f: &mut fmt::Formatter) -> fmt::Result {
+        let (color, theme) = (self.options.color, self.options.theme);
+        match value {
+            proto::Value::KernelAddress(address) => self.symbolizer.symbolize(*address, f),
+            proto::Value::String(s) if self.options.profile == Profile::Compact => {
+                // Keep everything on one line
+                write!(f, "{}", s.escape_debug())
+            }
+            proto::Value::String(s) => {
+                let mut is_first = true;
+                let mut lines = s.lines().peekable();
+
+                while let Some(line) = lines.next() {
+                    if !is_first {
+                        write!(f, "{}", Indent::spaces(self.depth))?;
+                    }
+                    f.write_str(line)?;
+                    is_first = false;
+
+                    if lines.peek().is_some() {
+                        f.write_str("\n")?;
+                    }
+                }
+
+                Ok(())
+            }
+            proto::Value::U64(x) => write!(f, "{x}"),
+            proto::Value::ByteSize(size) => {
+                write!(f, "{}", HumanBytes(*size))?;
+                if self.options.profile == Profile::Verbose && *size >= 1024 {
+                    write!(f, " ({size} bytes)")?;
                 }
-                f.write_str(line)?;
-                is_first = false;
+                Ok(())
+            }
+            proto::Value::Bytes { bytes, len } => {
+                write!(f, "{}", Hex::bytes(bytes, *len, self.options.max_bytes))
+            }
+            proto::Value::U64Array { values, len } => {
+                write!(
+                    f,
+                    "{}",
+                    Hex::array(values, *len, self.options.max_array_len)
+                )
+            }
+            proto::Value::PhysicalAddress(addr) => {
+                Paint::new(format_args!("{addr:#012x}"), theme.physical_address, color).fmt(f)
+            }
+            proto::Value::VirtualAddress(addr) => {
+                Paint::new(format_args!("{addr:#012x}"), theme.virtual_address, color).fmt(f)
+            }
+        }
+    }
+}
 
-                if lines.peek().is_some() {
-                    f.write_str("\n")?;
+/// Byte slices and arrays in hex, truncated to a maximum length
+pub struct Hex<'a> {
+    values: HexValues<'a>,
+    /// Length of the original value, which may be more than was recorded
+    len: u64,
+    max: usize,
+}
+
+enum HexValues<'a> {
+    Bytes(&'a [u8]),
+    Array(&'a [u64]),
+}
+
+impl<'a> Hex<'a> {
+    /// Print at most `max` of `bytes`, which were recorded from a `len`-byte
+    /// slice
+    pub fn bytes(bytes: &'a [u8], len: u64, max: usize) -> Self {
+        Self {
+            values: HexValues::Bytes(bytes),
+            len,
+            max,
+        }
+    }
+
+    /// Print at most `max` of `values`, which were recorded from a
+    /// `len`-element array
+    pub fn array(values: &'a [u64], len: u64, max: usize) -> Self {
+        Self {
+            values: HexValues::Array(values),
+            len,
+            max,
+        }
+    }
+}
+
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.values {
+            HexValues::Bytes(bytes) => {
+                let shown = bytes.len().min(self.max);
+                for byte in &bytes[..shown] {
+                    write!(f, "{byte:02x}")?;
+                }
+                if (shown as u64) < self.len {
+                    write!(f, "… ({} bytes)", self.len)?;
                 }
             }
+            HexValues::Array(values) => {
+                let shown = values.len().min(self.max);
+                f.write_str("[")?;
+                for (i, value) in values[..shown].iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{value:#x}")?;
+                }
+                if (shown as u64) < self.len {
+                    write!(f, ", …] ({} elements)", self.len)?;
+                } else {
+                    f.write_str("]")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Byte size in human-readable units
+struct HumanBytes(u64);
 
-            Ok(())
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: &[(u64, &str)] = &[(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")];
+
+        for (size, suffix) in UNITS {
+            if self.0 >= *size {
+                return if self.0.is_multiple_of(*size) {
+                    write!(f, "{} {suffix}", self.0 / size)
+                } else {
+                    write!(f, "{:.1} {suffix}", self.0 as f64 / *size as f64)
+                };
+            }
         }
-        proto::Value::U64(x) => write!(f, "{x}"),
-        // Color-code addresses so they can be disambiguated
-        proto::Value::PhysicalAddress(addr) => format_args!("{addr:#012x}")
-            .if_supports_color(Stream::Stdout, |a| a.magenta())
-            .fmt(f),
-        proto::Value::VirtualAddress(addr) => format_args!("{addr:#012x}")
-            .if_supports_color(Stream::Stdout, |a| a.cyan())
-            .fmt(f),
+        write!(f, "{} B", self.0)
     }
 }
 
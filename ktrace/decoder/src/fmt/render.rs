@@ -0,0 +1,246 @@
+//! Pluggable rendering for field values.
+//!
+//! By default, values are rendered based on their schema type alone. Some
+//! fields deserve more: a physical address means more as a region of the
+//! memory map, and a status code as the name it stands for. A [`Renderers`]
+//! registry maps field names and schema types to [`Renderer`]s, which the
+//! text [`Formatter`](super::Formatter) and HTML [`Report`](crate::report::Report)
+//! both consult before falling back to their default rendering.
+//!
+//! Renderers registered for a field name take precedence over ones for its
+//! type, and a renderer can decline a value by returning `None`. Built-ins
+//! cover the common cases: [`MemoryMap`] names the regions physical addresses
+//! fall in, [`ByteSizes`] prints plain integers in human-readable units, and
+//! [`Names`] maps codes to names.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use platypos_ktrace_proto as proto;
+use proto::FieldType;
+
+use super::HumanBytes;
+
+/// Custom rendering for field values
+pub trait Renderer: Send + Sync {
+    /// Render `value`, or return `None` to use the default rendering
+    fn render(&self, value: &proto::Value<'_>) -> Option<String>;
+}
+
+impl<F> Renderer for F
+where
+    F: Fn(&proto::Value<'_>) -> Option<String> + Send + Sync,
+{
+    fn render(&self, value: &proto::Value<'_>) -> Option<String> {
+        self(value)
+    }
+}
+
+/// Renderers to use for particular fields and schema types
+#[derive(Clone, Default)]
+pub struct Renderers {
+    by_name: BTreeMap<String, Arc<dyn Renderer>>,
+    by_type: Vec<(FieldType, Arc<dyn Renderer>)>,
+}
+
+impl Renderers {
+    /// An empty registry, which leaves every value to the default rendering
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render every field named `name` with `renderer`
+    pub fn register_field(&mut self, name: impl Into<String>, renderer: impl Renderer + 'static) {
+        self.by_name.insert(name.into(), Arc::new(renderer));
+    }
+
+    /// Render every field of type `ty` with `renderer`, unless there's a
+    /// renderer for its name
+    pub fn register_type(&mut self, ty: FieldType, renderer: impl Renderer + 'static) {
+        self.by_type.retain(|(t, _)| *t != ty);
+        self.by_type.push((ty, Arc::new(renderer)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty() && self.by_type.is_empty()
+    }
+
+    /// Render field `name`'s `value`, if a registered renderer accepts it
+    pub fn render(&self, name: &str, value: &proto::Value<'_>) -> Option<String> {
+        if let Some(rendered) = self.by_name.get(name).and_then(|r| r.render(value)) {
+            return Some(rendered);
+        }
+        let ty = value.field_type();
+        self.by_type
+            .iter()
+            .find(|(t, _)| *t == ty)
+            .and_then(|(_, r)| r.render(value))
+    }
+}
+
+impl fmt::Debug for Renderers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Renderers")
+            .field("fields", &self.by_name.keys().collect::<Vec<_>>())
+            .field(
+                "types",
+                &self.by_type.iter().map(|(t, _)| t).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// Names the memory map region physical addresses fall in, like
+/// `0x0000101000 (kernel+0x1000)`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    /// `(start, end, name)`, sorted by start address. Ends are exclusive.
+    regions: Vec<(u64, u64, String)>,
+}
+
+impl MemoryMap {
+    /// Parse a memory map, with one `START END NAME` line per region. Addresses
+    /// are in hex, with or without a `0x` prefix, and ends are exclusive.
+    /// Blank lines and `#` comments are ignored. Returns `None` if any other
+    /// line is malformed.
+    pub fn parse(text: &str) -> Option<Self> {
+        let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+        let mut regions = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let mut parts = line.splitn(3, char::is_whitespace);
+                let start = hex(parts.next()?)?;
+                let end = hex(parts.next()?.trim())?;
+                let name = parts.next()?.trim();
+                (start < end && !name.is_empty()).then(|| (start, end, name.to_string()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        regions.sort_by_key(|&(start, _, _)| start);
+        Some(Self { regions })
+    }
+
+    /// The region containing `address`, and how far into it the address is
+    pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self
+            .regions
+            .partition_point(|&(start, _, _)| start <= address)
+            .checked_sub(1)?;
+        let (start, end, ref name) = self.regions[index];
+        (address < end).then(|| (name.as_str(), address - start))
+    }
+}
+
+impl Renderer for MemoryMap {
+    fn render(&self, value: &proto::Value<'_>) -> Option<String> {
+        let proto::Value::PhysicalAddress(address) = *value else {
+            return None;
+        };
+        let (name, offset) = self.lookup(address)?;
+        Some(format!("{address:#012x} ({name}+{offset:#x})"))
+    }
+}
+
+/// Prints integers as byte sizes in human-readable units, for `u64` fields
+/// that hold sizes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByteSizes;
+
+impl Renderer for ByteSizes {
+    fn render(&self, value: &proto::Value<'_>) -> Option<String> {
+        match *value {
+            proto::Value::U64(size) | proto::Value::ByteSize(size) => {
+                Some(HumanBytes(size).to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Prints integer codes as names, like error codes as their enum variants.
+/// Codes without a name are left to the default rendering.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Names(pub BTreeMap<u64, String>);
+
+impl Names {
+    pub fn new<S: Into<String>>(names: impl IntoIterator<Item = (u64, S)>) -> Self {
+        Self(
+            names
+                .into_iter()
+                .map(|(code, name)| (code, name.into()))
+                .collect(),
+        )
+    }
+}
+
+impl Renderer for Names {
+    fn render(&self, value: &proto::Value<'_>) -> Option<String> {
+        let proto::Value::U64(code) = *value else {
+            return None;
+        };
+        let name = self.0.get(&code)?;
+        Some(format!("{name} ({code})"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_map() {
+        let map = MemoryMap::parse(
+            "# start end name\n\
+             0x100000 0x200000 kernel\n\
+             1000 9f000 low memory\n",
+        )
+        .unwrap();
+        assert_eq!(map.lookup(0x1234), Some(("low memory", 0x234)));
+        assert_eq!(map.lookup(0x100000), Some(("kernel", 0)));
+        assert_eq!(map.lookup(0x200000), None);
+        assert_eq!(map.lookup(0x500), None);
+        assert_eq!(
+            map.render(&proto::Value::PhysicalAddress(0x101000))
+                .as_deref(),
+            Some("0x0000101000 (kernel+0x1000)")
+        );
+        assert_eq!(map.render(&proto::Value::U64(0x101000)), None);
+
+        assert_eq!(MemoryMap::parse("0x2000 0x1000 backwards"), None);
+        assert_eq!(MemoryMap::parse("0x1000 0x2000"), None);
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut renderers = Renderers::new();
+        renderers.register_type(FieldType::U64, ByteSizes);
+        renderers.register_field("status", Names::new([(0, "Ok"), (2, "NotFound")]));
+
+        // Field names take precedence over types
+        assert_eq!(
+            renderers.render("status", &proto::Value::U64(2)).as_deref(),
+            Some("NotFound (2)")
+        );
+        // A field renderer that declines falls back to the type's
+        assert_eq!(
+            renderers
+                .render("status", &proto::Value::U64(2048))
+                .as_deref(),
+            Some("2 KiB")
+        );
+        assert_eq!(renderers.render("count", &proto::Value::String("x")), None);
+
+        renderers.register_field("message", |value: &proto::Value<'_>| match value {
+            proto::Value::String(s) => Some(s.to_uppercase()),
+            _ => None,
+        });
+        assert_eq!(
+            renderers
+                .render("message", &proto::Value::String("hi"))
+                .as_deref(),
+            Some("HI")
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Sources of ktrace data.
+//!
+//! Besides files and stdin, traces can be read from a socket, which is how
+//! QEMU exposes a serial port with `-serial tcp:...,server` or
+//! `-serial unix:...,server`. Since QEMU may not be listening yet right after
+//! it starts, [`Source::open_with_retry`] retries socket connections.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+
+/// How long to wait between connection attempts in [`Source::open_with_retry`]
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where to read ktrace data from. This parses from:
+/// * `-` for stdin
+/// * `tcp://<host>:<port>` for a TCP connection
+/// * `unix://<path>` for a Unix socket
+/// * anything else as a file path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Stdin,
+    File(PathBuf),
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Source {
+    /// Open this source for reading
+    pub fn open(&self) -> Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Source::Stdin => Box::new(io::stdin()),
+            Source::File(path) => Box::new(
+                File::open(path).wrap_err_with(|| format!("could not open {}", path.display()))?,
+            ),
+            Source::Tcp(addr) => Box::new(
+                TcpStream::connect(addr)
+                    .wrap_err_with(|| format!("could not connect to {addr}"))?,
+            ),
+            #[cfg(unix)]
+            Source::Unix(path) => Box::new(
+                UnixStream::connect(path)
+                    .wrap_err_with(|| format!("could not connect to {}", path.display()))?,
+            ),
+        })
+    }
+
+    /// Open this source for reading. If it's a socket that isn't accepting
+    /// connections yet, keep retrying for up to `timeout`. This is useful when
+    /// the other end (like QEMU) was just started.
+    pub fn open_with_retry(&self, timeout: Duration) -> Result<Box<dyn Read + Send>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.open() {
+                Ok(reader) => return Ok(reader),
+                Err(_) if self.is_socket() && Instant::now() < deadline => {
+                    thread::sleep(RETRY_INTERVAL)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Whether this source is a socket connection
+    pub fn is_socket(&self) -> bool {
+        match self {
+            Source::Stdin | Source::File(_) => false,
+            Source::Tcp(_) => true,
+            #[cfg(unix)]
+            Source::Unix(_) => true,
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            Ok(Source::Stdin)
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            Ok(Source::Tcp(addr.to_string()))
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            #[cfg(unix)]
+            return Ok(Source::Unix(path.into()));
+            #[cfg(not(unix))]
+            return Ok(Source::File(path.into()));
+        } else {
+            Ok(Source::File(s.into()))
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Stdin => f.write_str("-"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Tcp(addr) => write!(f, "tcp://{addr}"),
+            #[cfg(unix)]
+            Source::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
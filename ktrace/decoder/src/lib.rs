@@ -1,16 +1,57 @@
-use std::collections::VecDeque;
-use std::io::{Read, Write};
+//! Decoder for ktrace output.
+//!
+//! [`Decoder::decode`] passes borrowed messages to a callback as they're read.
+//! [`DecoderStream`] is an iterator over [`OwnedMessage`]s instead, which is
+//! easier to embed in other tools. Either can read from any [`Read`]er,
+//! including the sockets opened by [`input::Source`].
+//!
+//! [`Decoder::decode_with_offsets`] also passes how far into the input each
+//! message ended, which [`clock::ReceiveLog`] maps back to when it arrived.
+//!
+//! The kernel interns callsite metadata, sending it once and then referring to
+//! it by ID. The decoder keeps track of the definitions, and fills the
+//! metadata back in, so every span and event it passes on has its metadata.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
 
 use color_eyre::eyre::bail;
 use color_eyre::Result;
-use platypos_ktrace_proto::{ReceiverMessage, START_OF_OUTPUT};
+use platypos_ktrace_proto::{MetadataId, ReceiverMessage, START_OF_OUTPUT};
 
+pub mod bench;
+pub mod boot_env;
+pub mod boot_time;
+pub mod clock;
+pub mod crash;
+pub mod filter;
 pub mod fmt;
+pub mod input;
+pub mod milestones;
+pub mod owned;
+pub mod perfetto;
+pub mod profile;
+pub mod regressions;
+pub mod replay;
+pub mod report;
+pub mod schema;
+pub mod spans;
+pub mod stats;
+pub mod test_results;
+pub mod theme;
+pub mod topology;
+
+pub use owned::{OwnedMessage, OwnedMetadata, OwnedValue};
+pub use platypos_ktrace_proto as proto;
 
 /// Decoder for ktrace messages
 pub struct Decoder {
     buf: VecDeque<u8>,
     read_header: bool,
+    /// Total bytes read from the input so far
+    read: u64,
+    /// Callsite metadata the kernel has interned
+    callsites: HashMap<MetadataId, OwnedMetadata>,
 }
 
 impl Decoder {
@@ -18,6 +59,8 @@ impl Decoder {
         Self {
             buf: VecDeque::new(),
             read_header: false,
+            read: 0,
+            callsites: HashMap::new(),
         }
     }
 
@@ -32,6 +75,7 @@ impl Decoder {
             if count == 0 {
                 bail!("could not find ktrace marker");
             }
+            self.read += count as u64;
 
             self.buf.extend(&input_buf[..count]);
             let slice = self.buf.make_contiguous();
@@ -51,43 +95,167 @@ impl Decoder {
         Ok(())
     }
 
-    pub fn decode<R, W, F>(&mut self, mut input: R, mut drain: W, mut f: F) -> Result<()>
+    pub fn decode<R, W, F>(&mut self, input: R, drain: W, mut f: F) -> Result<()>
     where
         R: Read,
         W: Write,
         F: FnMut(ReceiverMessage) -> Result<()>,
+    {
+        self.decode_with_offsets(input, drain, |msg, _| f(msg))
+    }
+
+    /// Like [`Decoder::decode`], but also passes `f` the offset into `input`
+    /// just past the end of each message
+    pub fn decode_with_offsets<R, W, F>(
+        &mut self,
+        mut input: R,
+        mut drain: W,
+        mut f: F,
+    ) -> Result<()>
+    where
+        R: Read,
+        W: Write,
+        F: FnMut(ReceiverMessage, u64) -> Result<()>,
     {
         self.read_initial(&mut input, &mut drain)?;
         drop(drain); // In case it's locked stdout
 
+        while self.fill(&mut input)? {
+            while let Some(result) = self.take(&mut f)? {
+                result?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read more data from `input` into the buffer, returning `false` at the
+    /// end of input
+    fn fill<R: Read>(&mut self, input: &mut R) -> Result<bool> {
         let mut input_buf = [0u8; 64];
-        loop {
-            let count = input.read(&mut input_buf)?;
-            if count == 0 {
-                break;
+        let count = input.read(&mut input_buf)?;
+        self.buf.extend(&input_buf[..count]);
+        self.read += count as u64;
+        Ok(count > 0)
+    }
+
+    /// Decode the next buffered message and pass it to `f` along with the
+    /// input offset it ended at, or return `None` if a whole message isn't
+    /// buffered yet
+    fn take<T, F>(&mut self, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(ReceiverMessage, u64) -> T,
+    {
+        let slice = self.buf.make_contiguous();
+        match postcard::take_from_bytes(slice) {
+            Err(postcard::Error::DeserializeUnexpectedEnd) => Ok(None),
+            Err(other) => Err(other.into()),
+            Ok((msg, unused)) => {
+                let used = slice.len() - unused.len();
+                let end = self.read - unused.len() as u64;
+                let result = f(resolve_metadata(&mut self.callsites, msg), end);
+                // Drain off the data that was used
+                self.buf.drain(..used);
+                Ok(Some(result))
             }
+        }
+    }
+}
 
-            self.buf.extend(&input_buf[..count]);
-            self.buf.make_contiguous();
-            'decode: loop {
-                let slice = match self.buf.as_slices() {
-                    (slice, &[]) => slice,
-                    _ => panic!("data not contiguous"),
-                };
-                match postcard::take_from_bytes(slice) {
-                    Err(postcard::Error::DeserializeUnexpectedEnd) => break 'decode,
-                    Err(other) => return Err(other.into()),
-                    Ok((msg, unused)) => {
-                        f(msg)?;
-                        // Drain off the data that was used
-                        let used = slice.len() - unused.len();
-                        self.buf.drain(..used);
-                    }
-                }
+/// Record interned metadata definitions in `callsites`, and replace interned
+/// metadata in `msg` with the full metadata. Metadata that was never defined
+/// becomes [`proto::Metadata::UNKNOWN`].
+fn resolve_metadata<'a>(
+    callsites: &'a mut HashMap<MetadataId, OwnedMetadata>,
+    msg: ReceiverMessage<'a>,
+) -> ReceiverMessage<'a> {
+    let lookup = |callsites: &'a HashMap<_, OwnedMetadata>, id| {
+        callsites
+            .get(&id)
+            .map_or(proto::Metadata::UNKNOWN, OwnedMetadata::as_metadata)
+    };
+    match msg {
+        proto::Message::MetadataDefined { id, metadata } => {
+            callsites.insert(id, (&metadata).into());
+            proto::Message::MetadataDefined { id, metadata }
+        }
+        proto::Message::InternedSpanCreated(span) => {
+            proto::Message::SpanCreated(proto::SpanCreated {
+                id: span.id,
+                parent: span.parent,
+                context: span.context,
+                metadata: lookup(callsites, span.metadata),
+                fields: span.fields,
+            })
+        }
+        proto::Message::InternedEvent(event) => proto::Message::Event(proto::Event {
+            span_id: event.span_id,
+            context: event.context,
+            metadata: lookup(callsites, event.metadata),
+            fields: event.fields,
+        }),
+        other => other,
+    }
+}
+
+/// Iterator over the messages decoded from a reader. Any output before the
+/// start of ktrace data is written to the passthrough writer, which discards
+/// it by default.
+pub struct DecoderStream<R, W = io::Sink> {
+    decoder: Decoder,
+    input: R,
+    passthrough: Option<W>,
+    done: bool,
+}
+
+impl<R: Read> DecoderStream<R> {
+    pub fn new(input: R) -> Self {
+        Self::with_passthrough(input, io::sink())
+    }
+}
+
+impl<R: Read, W: Write> DecoderStream<R, W> {
+    /// Create a stream which writes non-ktrace output to `passthrough`
+    pub fn with_passthrough(input: R, passthrough: W) -> Self {
+        Self {
+            decoder: Decoder::new(),
+            input,
+            passthrough: Some(passthrough),
+            done: false,
+        }
+    }
+
+    fn next_message(&mut self) -> Result<Option<OwnedMessage>> {
+        if let Some(mut passthrough) = self.passthrough.take() {
+            self.decoder
+                .read_initial(&mut self.input, &mut passthrough)?;
+        }
+
+        loop {
+            if let Some(message) = self.decoder.take(|msg, _| OwnedMessage::from(&msg))? {
+                return Ok(Some(message));
+            }
+            if !self.decoder.fill(&mut self.input)? {
+                return Ok(None);
             }
         }
+    }
+}
 
-        Ok(())
+impl<R: Read, W: Write> Iterator for DecoderStream<R, W> {
+    type Item = Result<OwnedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.next_message().transpose();
+        // Stop after the end of input or the first error, since the stream can't resynchronize
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
     }
 }
 
@@ -96,3 +264,54 @@ impl Default for Decoder {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_interned_metadata() {
+        type Fields = BTreeMap<&'static str, u64>;
+        let metadata = proto::Metadata {
+            name: "tick",
+            target: "platypos_kernel::sched",
+            level: proto::Level::Debug,
+            file: Some("sched.rs"),
+            line: Some(42),
+        };
+        let event = |id| {
+            proto::Message::<Fields, Fields>::InternedEvent(proto::Event {
+                span_id: proto::Parent::Root,
+                context: proto::ExecutionContext::Task,
+                metadata: id,
+                fields: Fields::new(),
+            })
+        };
+        let messages = [
+            proto::Message::MetadataDefined { id: 7, metadata },
+            event(7),
+            // Never defined
+            event(8),
+        ];
+
+        let mut input = START_OF_OUTPUT.to_vec();
+        let mut buf = [0u8; 128];
+        for message in &messages {
+            input.extend_from_slice(postcard::to_slice(message, &mut buf).unwrap());
+        }
+        let decoded = DecoderStream::new(&input[..])
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let events = decoded
+            .iter()
+            .filter_map(|message| match message {
+                OwnedMessage::Event { metadata, .. } => Some(metadata.as_metadata()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events, [metadata, proto::Metadata::UNKNOWN]);
+    }
+}
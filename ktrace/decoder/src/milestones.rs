@@ -0,0 +1,120 @@
+//! Checks that the kernel reaches a set of boot milestones, in order.
+//!
+//! The kernel marks milestones with events under
+//! [`MILESTONE_TARGET`](proto::MILESTONE_TARGET). Test runners can feed
+//! decoded messages to a [`Milestones`] tracker and fail with the first missing
+//! or out-of-order milestone, rather than waiting for a generic timeout.
+
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+/// Tracks which of an expected sequence of milestones have been reached
+#[derive(Debug)]
+pub struct Milestones {
+    expected: Vec<String>,
+    /// Number of expected milestones reached so far, in order
+    reached: usize,
+    error: Option<MilestoneError>,
+}
+
+/// Ways the kernel can fail to reach its milestones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MilestoneError {
+    /// A milestone was reached before one that should have come first
+    OutOfOrder { reached: String, expected: String },
+    /// The kernel stopped (or was stopped) before reaching a milestone
+    Missing {
+        expected: String,
+        last: Option<String>,
+    },
+}
+
+impl Milestones {
+    /// Expect `expected` to be reached in order. Milestones that aren't in
+    /// `expected` are ignored.
+    pub fn new(expected: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            expected: expected.into_iter().map(Into::into).collect(),
+            reached: 0,
+            error: None,
+        }
+    }
+
+    /// Update the tracker with a decoded message
+    pub fn receive(&mut self, message: &proto::ReceiverMessage) {
+        let proto::Message::Event(event) = message else {
+            return;
+        };
+        if event.metadata.target != proto::MILESTONE_TARGET || self.error.is_some() {
+            return;
+        }
+        let Some(name) = event.fields.iter().find_map(|(field, value)| match value {
+            proto::Value::String(name) if *field == "message" => Some(*name),
+            _ => None,
+        }) else {
+            return;
+        };
+
+        log::debug!("Reached milestone {name}");
+        match self.expected.iter().position(|m| m == name) {
+            Some(i) if i == self.reached => self.reached += 1,
+            Some(i) if i > self.reached => {
+                self.error = Some(MilestoneError::OutOfOrder {
+                    reached: name.to_string(),
+                    expected: self.expected[self.reached].clone(),
+                });
+            }
+            // Repeated or unexpected milestones
+            _ => (),
+        }
+    }
+
+    /// Whether every expected milestone has been reached
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none() && self.reached == self.expected.len()
+    }
+
+    /// Whether a milestone was reached out of order, so the check has already
+    /// failed
+    pub fn has_failed(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Check that every expected milestone was reached, in order
+    pub fn finish(&self) -> Result<(), MilestoneError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        match self.expected.get(self.reached) {
+            Some(expected) => Err(MilestoneError::Missing {
+                expected: expected.clone(),
+                last: self
+                    .reached
+                    .checked_sub(1)
+                    .map(|i| self.expected[i].clone()),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for MilestoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MilestoneError::OutOfOrder { reached, expected } => {
+                write!(f, "reached milestone {reached} before {expected}")
+            }
+            MilestoneError::Missing {
+                expected,
+                last: Some(last),
+            } => write!(f, "never reached milestone {expected} (last was {last})"),
+            MilestoneError::Missing {
+                expected,
+                last: None,
+            } => write!(f, "never reached milestone {expected} (or any before it)"),
+        }
+    }
+}
+
+impl std::error::Error for MilestoneError {}
@@ -0,0 +1,314 @@
+//! Owned versions of ktrace messages.
+//!
+//! [`ReceiverMessage`](proto::ReceiverMessage)s borrow from the decoder's
+//! buffer, so they can only be used inside a callback. These types own their
+//! data instead, so they can be collected, sent between threads, or returned
+//! from an iterator.
+
+use platypos_ktrace_proto as proto;
+
+use crate::crash::Crash;
+
+pub use proto::{
+    ContextId, ExecutionContext, FieldType, Level, MetadataId, Parent, ProcessorId,
+    ProcessorLocation, SchemaKind, SpanId,
+};
+
+/// An owned ktrace message
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedMessage {
+    SpanCreated {
+        id: SpanId,
+        parent: Parent,
+        context: ExecutionContext,
+        metadata: OwnedMetadata,
+        fields: Vec<(String, OwnedValue)>,
+    },
+    Event {
+        parent: Parent,
+        context: ExecutionContext,
+        metadata: OwnedMetadata,
+        fields: Vec<(String, OwnedValue)>,
+    },
+    SpanEntered {
+        id: SpanId,
+        processor: ProcessorId,
+        ticks: u64,
+    },
+    SpanExited {
+        id: SpanId,
+        processor: ProcessorId,
+        ticks: u64,
+    },
+    SpanClosed {
+        id: SpanId,
+    },
+    Schema {
+        name: String,
+        kind: SchemaKind,
+        fields: Vec<(String, FieldType)>,
+    },
+    Suppressed {
+        metadata: OwnedMetadata,
+        count: u64,
+    },
+    ContextSwitched {
+        processor: ProcessorId,
+        context: ContextId,
+        ticks: u64,
+    },
+    Processor {
+        processor: ProcessorId,
+        location: ProcessorLocation,
+    },
+    Calibration {
+        ticks_per_second: u64,
+    },
+    MetadataDefined {
+        id: MetadataId,
+        metadata: OwnedMetadata,
+    },
+    Crash(Crash),
+    PreviousCrash(Crash),
+    /// Messages as the kernel encoded them, to decode with
+    /// [`previous_trace`](crate::crash::previous_trace)
+    PreviousTrace(Vec<u8>),
+    ProfileSample {
+        processor: ProcessorId,
+        context: ExecutionContext,
+        /// Interrupted instruction pointer, then return addresses
+        stack: Vec<u64>,
+    },
+    ProfileSamplesDropped {
+        processor: ProcessorId,
+        count: u64,
+    },
+}
+
+/// Metadata for a span or event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedMetadata {
+    pub name: String,
+    pub target: String,
+    pub level: Level,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl OwnedMetadata {
+    /// Borrow this as decoded metadata
+    pub fn as_metadata(&self) -> proto::Metadata<'_> {
+        proto::Metadata {
+            name: &self.name,
+            target: &self.target,
+            level: self.level,
+            file: self.file.as_deref(),
+            line: self.line,
+        }
+    }
+}
+
+/// A field value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedValue {
+    KernelAddress(u64),
+    PhysicalAddress(u64),
+    VirtualAddress(u64),
+    String(String),
+    U64(u64),
+    ByteSize(u64),
+    /// Bytes recorded by the kernel, which may be a prefix of a `len`-byte
+    /// slice
+    Bytes {
+        bytes: Vec<u8>,
+        len: u64,
+    },
+    /// Values recorded by the kernel, which may be a prefix of a
+    /// `len`-element array
+    U64Array {
+        values: Vec<u64>,
+        len: u64,
+    },
+}
+
+impl OwnedValue {
+    /// The schema type of this value
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            OwnedValue::KernelAddress(_) => FieldType::KernelAddress,
+            OwnedValue::PhysicalAddress(_) => FieldType::PhysicalAddress,
+            OwnedValue::VirtualAddress(_) => FieldType::VirtualAddress,
+            OwnedValue::String(_) => FieldType::String,
+            OwnedValue::U64(_) => FieldType::U64,
+            OwnedValue::ByteSize(_) => FieldType::ByteSize,
+            OwnedValue::Bytes { .. } => FieldType::Bytes,
+            OwnedValue::U64Array { .. } => FieldType::U64Array,
+        }
+    }
+
+    /// Borrow this as a decoded value. Arrays are copied.
+    pub fn as_value(&self) -> proto::Value<'_> {
+        match self {
+            OwnedValue::KernelAddress(x) => proto::Value::KernelAddress(*x),
+            OwnedValue::PhysicalAddress(x) => proto::Value::PhysicalAddress(*x),
+            OwnedValue::VirtualAddress(x) => proto::Value::VirtualAddress(*x),
+            OwnedValue::String(s) => proto::Value::String(s),
+            OwnedValue::U64(x) => proto::Value::U64(*x),
+            OwnedValue::ByteSize(x) => proto::Value::ByteSize(*x),
+            OwnedValue::Bytes { bytes, len } => proto::Value::Bytes { bytes, len: *len },
+            OwnedValue::U64Array { values, len } => proto::Value::U64Array {
+                values: values.clone(),
+                len: *len,
+            },
+        }
+    }
+}
+
+impl From<&proto::ReceiverMessage<'_>> for OwnedMessage {
+    fn from(message: &proto::ReceiverMessage<'_>) -> Self {
+        match message {
+            proto::Message::SpanCreated(span) => OwnedMessage::SpanCreated {
+                id: span.id,
+                parent: span.parent,
+                context: span.context,
+                metadata: (&span.metadata).into(),
+                fields: owned_fields(&span.fields),
+            },
+            proto::Message::Event(event) => OwnedMessage::Event {
+                parent: event.span_id,
+                context: event.context,
+                metadata: (&event.metadata).into(),
+                fields: owned_fields(&event.fields),
+            },
+            proto::Message::SpanEntered {
+                id,
+                processor,
+                ticks,
+            } => OwnedMessage::SpanEntered {
+                id: *id,
+                processor: *processor,
+                ticks: *ticks,
+            },
+            proto::Message::SpanExited {
+                id,
+                processor,
+                ticks,
+            } => OwnedMessage::SpanExited {
+                id: *id,
+                processor: *processor,
+                ticks: *ticks,
+            },
+            proto::Message::SpanClosed { id } => OwnedMessage::SpanClosed { id: *id },
+            proto::Message::Schema(schema) => OwnedMessage::Schema {
+                name: schema.name.to_string(),
+                kind: schema.kind,
+                fields: schema
+                    .fields
+                    .as_slice()
+                    .iter()
+                    .map(|f| (f.name.to_string(), f.ty))
+                    .collect(),
+            },
+            proto::Message::Suppressed { metadata, count } => OwnedMessage::Suppressed {
+                metadata: metadata.into(),
+                count: *count,
+            },
+            proto::Message::ContextSwitched {
+                processor,
+                context,
+                ticks,
+            } => OwnedMessage::ContextSwitched {
+                processor: *processor,
+                context: *context,
+                ticks: *ticks,
+            },
+            proto::Message::Processor {
+                processor,
+                location,
+            } => OwnedMessage::Processor {
+                processor: *processor,
+                location: *location,
+            },
+            proto::Message::Calibration { ticks_per_second } => OwnedMessage::Calibration {
+                ticks_per_second: *ticks_per_second,
+            },
+            proto::Message::MetadataDefined { id, metadata } => OwnedMessage::MetadataDefined {
+                id: *id,
+                metadata: metadata.into(),
+            },
+            // The decoder resolves interned metadata, so these only show up in messages that
+            // didn't come from it
+            proto::Message::InternedSpanCreated(span) => OwnedMessage::SpanCreated {
+                id: span.id,
+                parent: span.parent,
+                context: span.context,
+                metadata: (&proto::Metadata::UNKNOWN).into(),
+                fields: owned_fields(&span.fields),
+            },
+            proto::Message::InternedEvent(event) => OwnedMessage::Event {
+                parent: event.span_id,
+                context: event.context,
+                metadata: (&proto::Metadata::UNKNOWN).into(),
+                fields: owned_fields(&event.fields),
+            },
+            proto::Message::Crash(report) => OwnedMessage::Crash(report.into()),
+            proto::Message::PreviousCrash(report) => OwnedMessage::PreviousCrash(report.into()),
+            proto::Message::PreviousTrace(data) => OwnedMessage::PreviousTrace(data.to_vec()),
+            proto::Message::ProfileSample {
+                processor,
+                context,
+                stack,
+            } => OwnedMessage::ProfileSample {
+                processor: *processor,
+                context: *context,
+                stack: stack.iter().collect(),
+            },
+            proto::Message::ProfileSamplesDropped { processor, count } => {
+                OwnedMessage::ProfileSamplesDropped {
+                    processor: *processor,
+                    count: *count,
+                }
+            }
+        }
+    }
+}
+
+impl From<&proto::Metadata<'_>> for OwnedMetadata {
+    fn from(metadata: &proto::Metadata<'_>) -> Self {
+        OwnedMetadata {
+            name: metadata.name.to_string(),
+            target: metadata.target.to_string(),
+            level: metadata.level,
+            file: metadata.file.map(str::to_string),
+            line: metadata.line,
+        }
+    }
+}
+
+impl From<&proto::Value<'_>> for OwnedValue {
+    fn from(value: &proto::Value<'_>) -> Self {
+        match value {
+            proto::Value::KernelAddress(x) => OwnedValue::KernelAddress(*x),
+            proto::Value::PhysicalAddress(x) => OwnedValue::PhysicalAddress(*x),
+            proto::Value::VirtualAddress(x) => OwnedValue::VirtualAddress(*x),
+            proto::Value::String(s) => OwnedValue::String(s.to_string()),
+            proto::Value::U64(x) => OwnedValue::U64(*x),
+            proto::Value::ByteSize(x) => OwnedValue::ByteSize(*x),
+            proto::Value::Bytes { bytes, len } => OwnedValue::Bytes {
+                bytes: bytes.to_vec(),
+                len: *len,
+            },
+            proto::Value::U64Array { values, len } => OwnedValue::U64Array {
+                values: values.clone(),
+                len: *len,
+            },
+        }
+    }
+}
+
+fn owned_fields(fields: &proto::DeserializedFields) -> Vec<(String, OwnedValue)> {
+    fields
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.into()))
+        .collect()
+}
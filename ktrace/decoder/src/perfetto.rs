@@ -0,0 +1,422 @@
+//! Export to the Chrome trace event format, which [Perfetto] and
+//! `chrome://tracing` can open.
+//!
+//! Span transitions are timestamped, so each time a span is entered and exited
+//! becomes a slice. As in [`SpanTree`](crate::spans::SpanTree), spans entered
+//! by a task follow it between processors, so each task has its own track, and
+//! each processor has one for spans entered outside any task.
+//!
+//! Events become instants on the track they were recorded on. Most events
+//! don't carry a timestamp, so they're placed at their processor's last span
+//! transition or context switch, unless they have a `ticks` field like the
+//! scheduler's events.
+//!
+//! [Perfetto]: https://ui.perfetto.dev
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::Write;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use platypos_ktrace_proto as proto;
+use serde_json::{json, Map, Value};
+
+use crate::clock::KernelTime;
+
+/// Chrome trace process that holds the processor tracks
+const PROCESSORS_PID: u64 = 1;
+/// Chrome trace process that holds the task tracks
+const TASKS_PID: u64 = 2;
+
+/// Where a slice or instant is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Track {
+    /// Events that weren't recorded in a processor's current span. These are
+    /// global instants, which aren't drawn on a thread's track.
+    Global,
+    Processor(proto::ProcessorId),
+    Context(proto::ContextId),
+}
+
+impl Track {
+    /// Chrome trace process and thread IDs
+    fn ids(self) -> (u64, u64) {
+        match self {
+            Track::Global => (PROCESSORS_PID, 0),
+            Track::Processor(processor) => (PROCESSORS_PID, u64::from(processor)),
+            Track::Context(context) => (TASKS_PID, context),
+        }
+    }
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Track::Global => f.write_str("global"),
+            Track::Processor(processor) => write!(f, "CPU {processor}"),
+            Track::Context(context) => write!(f, "task {context}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Begin,
+    End,
+    Instant,
+}
+
+#[derive(Debug)]
+struct Record {
+    phase: Phase,
+    track: Track,
+    ticks: u64,
+    name: String,
+    args: Map<String, Value>,
+}
+
+/// Builds a Chrome trace from decoded ktrace messages
+#[derive(Debug, Default)]
+pub struct ChromeTrace {
+    span_names: HashMap<proto::SpanId, String>,
+    /// Context each processor is switched to
+    contexts: HashMap<proto::ProcessorId, proto::ContextId>,
+    /// Latest timestamp from each processor
+    clocks: HashMap<proto::ProcessorId, u64>,
+    /// Latest timestamp from any processor
+    latest: u64,
+    ticks_per_second: Option<u64>,
+    records: Vec<Record>,
+}
+
+impl ChromeTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn receive(&mut self, message: &proto::ReceiverMessage) {
+        match message {
+            proto::Message::SpanCreated(span) => {
+                self.span_names
+                    .insert(span.id, span.metadata.name.to_string());
+            }
+            proto::Message::SpanClosed { id } => {
+                self.span_names.remove(id);
+            }
+            proto::Message::SpanEntered {
+                id,
+                processor,
+                ticks,
+            } => self.slice(Phase::Begin, *id, *processor, *ticks),
+            proto::Message::SpanExited {
+                id,
+                processor,
+                ticks,
+            } => self.slice(Phase::End, *id, *processor, *ticks),
+            proto::Message::ContextSwitched {
+                processor,
+                context,
+                ticks,
+            } => {
+                self.advance(*processor, *ticks);
+                self.contexts.insert(*processor, *context);
+            }
+            proto::Message::Calibration { ticks_per_second } => {
+                self.ticks_per_second = Some(*ticks_per_second).filter(|&t| t != 0);
+            }
+            proto::Message::Event(event) => self.instant(event),
+            _ => {}
+        }
+    }
+
+    /// Track for whatever `processor` is running
+    fn track(&self, processor: proto::ProcessorId) -> Track {
+        match self.contexts.get(&processor) {
+            Some(&context) if context != 0 => Track::Context(context),
+            _ => Track::Processor(processor),
+        }
+    }
+
+    /// Record that `processor`'s clock read `ticks`. Timestamps from before
+    /// the kernel's clock was set up are 0, and are ignored.
+    fn advance(&mut self, processor: proto::ProcessorId, ticks: u64) {
+        if ticks != 0 {
+            self.clocks.insert(processor, ticks);
+            self.latest = self.latest.max(ticks);
+        }
+    }
+
+    fn slice(
+        &mut self,
+        phase: Phase,
+        id: proto::SpanId,
+        processor: proto::ProcessorId,
+        ticks: u64,
+    ) {
+        self.advance(processor, ticks);
+        if ticks == 0 {
+            return;
+        }
+        let name = self
+            .span_names
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("span {id}"));
+        self.records.push(Record {
+            phase,
+            track: self.track(processor),
+            ticks,
+            name,
+            args: Map::new(),
+        });
+    }
+
+    fn instant(
+        &mut self,
+        event: &proto::Event<proto::DeserializedFields<'_>, proto::Metadata<'_>>,
+    ) {
+        let (track, clock) = match event.span_id {
+            proto::Parent::Current(processor) => {
+                (self.track(processor), self.clocks.get(&processor).copied())
+            }
+            _ => (Track::Global, None),
+        };
+        let ticks = match KernelTime::from_event(&event.fields) {
+            Some(KernelTime::Ticks(ticks)) => ticks,
+            _ => clock.unwrap_or(self.latest),
+        };
+        if ticks == 0 {
+            return;
+        }
+
+        let mut name = event.metadata.name.to_string();
+        let mut args = Map::new();
+        for (field, value) in event.fields.iter() {
+            match value {
+                proto::Value::String(message) if *field == "message" => {
+                    name = message.to_string();
+                }
+                _ => {
+                    args.insert(field.to_string(), json_value(value));
+                }
+            }
+        }
+        self.records.push(Record {
+            phase: Phase::Instant,
+            track,
+            ticks,
+            name,
+            args,
+        });
+    }
+
+    /// Write the trace as JSON
+    ///
+    /// # Errors
+    /// If the kernel never sent its clock calibration, so timestamps can't be
+    /// converted, or if writing fails
+    pub fn write_json<W: Write>(&self, w: W) -> Result<()> {
+        let ticks_per_second = self
+            .ticks_per_second
+            .ok_or_else(|| eyre!("the kernel never sent its clock calibration"))?;
+        let micros = |ticks: u64| ticks as f64 * 1e6 / ticks_per_second as f64;
+
+        let mut events = vec![
+            json!({
+                "ph": "M",
+                "name": "process_name",
+                "pid": PROCESSORS_PID,
+                "args": { "name": "Processors" },
+            }),
+            json!({
+                "ph": "M",
+                "name": "process_name",
+                "pid": TASKS_PID,
+                "args": { "name": "Tasks" },
+            }),
+        ];
+        let tracks: BTreeSet<Track> = self.records.iter().map(|r| r.track).collect();
+        for track in tracks.into_iter().filter(|&t| t != Track::Global) {
+            let (pid, tid) = track.ids();
+            events.push(json!({
+                "ph": "M",
+                "name": "thread_name",
+                "pid": pid,
+                "tid": tid,
+                "args": { "name": track.to_string() },
+            }));
+        }
+
+        for record in &self.records {
+            let (pid, tid) = record.track.ids();
+            let mut event = json!({
+                "name": record.name,
+                "pid": pid,
+                "tid": tid,
+                "ts": micros(record.ticks),
+            });
+            match record.phase {
+                Phase::Begin => event["ph"] = json!("B"),
+                Phase::End => event["ph"] = json!("E"),
+                Phase::Instant => {
+                    event["ph"] = json!("i");
+                    event["s"] = json!(if record.track == Track::Global {
+                        "g"
+                    } else {
+                        "t"
+                    });
+                    event["args"] = Value::Object(record.args.clone());
+                }
+            }
+            events.push(event);
+        }
+
+        serde_json::to_writer(
+            w,
+            &json!({ "displayTimeUnit": "ns", "traceEvents": events }),
+        )?;
+        Ok(())
+    }
+}
+
+/// Field value as JSON. Addresses are hex strings, like the pretty-printer
+/// shows them.
+fn json_value(value: &proto::Value) -> Value {
+    match value {
+        proto::Value::String(s) => json!(s),
+        proto::Value::U64(n) | proto::Value::ByteSize(n) => json!(n),
+        proto::Value::KernelAddress(address)
+        | proto::Value::PhysicalAddress(address)
+        | proto::Value::VirtualAddress(address) => json!(format!("{address:#x}")),
+        proto::Value::Bytes { bytes, .. } => json!(bytes),
+        proto::Value::U64Array { values, .. } => json!(values),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    type Fields = BTreeMap<&'static str, u64>;
+    type Message = proto::Message<'static, Fields, Fields>;
+
+    fn metadata(name: &'static str) -> proto::Metadata<'static> {
+        proto::Metadata {
+            name,
+            target: "platypos_kernel::sched",
+            level: proto::Level::Info,
+            file: None,
+            line: None,
+        }
+    }
+
+    fn span(id: proto::SpanId, name: &'static str) -> Message {
+        proto::Message::SpanCreated(proto::SpanCreated {
+            id,
+            parent: proto::Parent::Root,
+            context: proto::ExecutionContext::Task,
+            metadata: metadata(name),
+            fields: Fields::new(),
+        })
+    }
+
+    /// Export `messages`, after a round trip through postcard to get
+    /// [`ReceiverMessage`](proto::ReceiverMessage)s, and parse the result
+    fn export(messages: &[Message]) -> Vec<Value> {
+        let mut trace = ChromeTrace::new();
+        let mut buf = [0u8; 256];
+        for message in messages {
+            let bytes = postcard::to_slice(message, &mut buf).unwrap();
+            trace.receive(&postcard::from_bytes(bytes).unwrap());
+        }
+        let mut json = Vec::new();
+        trace.write_json(&mut json).unwrap();
+        let json: Value = serde_json::from_slice(&json).unwrap();
+        json["traceEvents"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn test_slices_follow_tasks() {
+        let events = export(&[
+            proto::Message::Calibration {
+                ticks_per_second: 1_000_000,
+            },
+            span(1, "boot"),
+            span(2, "work"),
+            proto::Message::SpanEntered {
+                id: 1,
+                processor: 0,
+                ticks: 10,
+            },
+            proto::Message::ContextSwitched {
+                processor: 1,
+                context: 7,
+                ticks: 15,
+            },
+            proto::Message::SpanEntered {
+                id: 2,
+                processor: 1,
+                ticks: 20,
+            },
+            proto::Message::Event(proto::Event {
+                span_id: proto::Parent::Current(1),
+                context: proto::ExecutionContext::Task,
+                metadata: metadata("tick"),
+                fields: Fields::from([("count", 3)]),
+            }),
+            proto::Message::SpanExited {
+                id: 2,
+                processor: 1,
+                ticks: 30,
+            },
+            proto::Message::SpanExited {
+                id: 1,
+                processor: 0,
+                ticks: 40,
+            },
+        ]);
+
+        let slices: Vec<_> = events
+            .iter()
+            .filter(|e| e["ph"] != "M")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["pid"].as_u64().unwrap(),
+                    e["tid"].as_u64().unwrap(),
+                    e["ts"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            slices,
+            [
+                ("B", "boot", PROCESSORS_PID, 0, 10.0),
+                ("B", "work", TASKS_PID, 7, 20.0),
+                ("i", "tick", TASKS_PID, 7, 20.0),
+                ("E", "work", TASKS_PID, 7, 30.0),
+                ("E", "boot", PROCESSORS_PID, 0, 40.0),
+            ]
+        );
+        assert!(events
+            .iter()
+            .any(|e| e["name"] == "thread_name" && e["args"]["name"] == "task 7"));
+        let tick = events.iter().find(|e| e["name"] == "tick").unwrap();
+        assert_eq!(tick["args"]["count"], 3);
+    }
+
+    #[test]
+    fn test_needs_calibration() {
+        let mut trace = ChromeTrace::new();
+        trace.receive(&proto::Message::SpanEntered {
+            id: 1,
+            processor: 0,
+            ticks: 10,
+        });
+        assert!(trace.write_json(Vec::new()).is_err());
+    }
+}
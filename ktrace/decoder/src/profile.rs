@@ -0,0 +1,251 @@
+//! Sampling profiles.
+//!
+//! The kernel's profiler periodically records what each processor was running,
+//! as [`proto::Message::ProfileSample`]s. A [`Profile`] counts how often each
+//! stack was sampled. Once a [`Symbolizer`] names their functions, it can
+//! write them out as folded stacks for a flamegraph, or as a flat profile of
+//! the functions samples landed in.
+//!
+//! Return addresses point just past a call, which can be the start of the next
+//! line or even the next function, so they're looked up one byte earlier.
+//! Samples taken while an interrupt handler was running are rooted under an
+//! `[irq]` or `[nmi]` frame, so handler time stands out.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use platypos_ktrace_proto as proto;
+
+use crate::fmt::Symbolizer;
+
+/// Sample counts per stack, collected from decoded ktrace messages
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Number of times each stack was sampled, innermost frame first
+    stacks: HashMap<(proto::ExecutionContext, Vec<u64>), u64>,
+    /// Samples from each processor
+    by_processor: BTreeMap<proto::ProcessorId, u64>,
+    /// Samples the kernel couldn't send
+    dropped: u64,
+}
+
+/// Function names for each address looked up so far
+type NameCache = HashMap<u64, Vec<String>>;
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn receive(&mut self, message: &proto::ReceiverMessage) {
+        match message {
+            proto::Message::ProfileSample {
+                processor,
+                context,
+                stack,
+            } => {
+                *self
+                    .stacks
+                    .entry((*context, stack.iter().collect()))
+                    .or_default() += 1;
+                *self.by_processor.entry(*processor).or_default() += 1;
+            }
+            proto::Message::ProfileSamplesDropped { count, .. } => self.dropped += count,
+            _ => {}
+        }
+    }
+
+    /// Number of samples received
+    pub fn samples(&self) -> u64 {
+        self.by_processor.values().sum()
+    }
+
+    /// Number of samples the kernel reported dropping
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Write the sample counts per stack in the folded format used by
+    /// flamegraph tools (one `outer;inner count` line per stack), naming
+    /// functions with `symbolizer`
+    pub fn write_folded<S: Symbolizer, W: Write>(
+        &self,
+        symbolizer: &S,
+        mut w: W,
+    ) -> io::Result<()> {
+        let mut names = NameCache::new();
+        let mut folded = BTreeMap::<String, u64>::new();
+        for ((context, stack), count) in &self.stacks {
+            let frames = frames(symbolizer, &mut names, *context, stack);
+            *folded.entry(frames.join(";")).or_default() += count;
+        }
+        for (stack, count) in folded {
+            writeln!(w, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+
+    /// Write a flat profile of the `limit` functions that samples most often
+    /// landed in. Each function's self share counts samples taken while it was
+    /// running, and its total share counts samples with it anywhere on the
+    /// stack.
+    pub fn write_summary<S: Symbolizer, W: Write>(
+        &self,
+        symbolizer: &S,
+        limit: usize,
+        mut w: W,
+    ) -> io::Result<()> {
+        let mut names = NameCache::new();
+        // Self and total samples per function
+        let mut functions = HashMap::<String, (u64, u64)>::new();
+        for ((context, stack), &count) in &self.stacks {
+            let mut frames = frames(symbolizer, &mut names, *context, stack);
+            if let Some(leaf) = frames.last() {
+                functions.entry(leaf.clone()).or_default().0 += count;
+            }
+            // Recursive functions are only on the stack once
+            frames.sort_unstable();
+            frames.dedup();
+            for frame in frames {
+                functions.entry(frame).or_default().1 += count;
+            }
+        }
+        let mut functions = functions.into_iter().collect::<Vec<_>>();
+        functions.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then_with(|| a_name.cmp(b_name)));
+
+        let samples = self.samples();
+        write!(w, "{samples} samples")?;
+        if self.dropped > 0 {
+            write!(w, " ({} dropped)", self.dropped)?;
+        }
+        writeln!(w)?;
+        for (processor, count) in &self.by_processor {
+            writeln!(w, "  CPU {processor}: {count}")?;
+        }
+        writeln!(w)?;
+
+        writeln!(w, "{:>7} {:>7}  function", "self", "total")?;
+        let percent = |count| 100.0 * count as f64 / samples.max(1) as f64;
+        for (name, (self_count, total)) in functions.into_iter().take(limit) {
+            writeln!(
+                w,
+                "{:>6.1}% {:>6.1}%  {name}",
+                percent(self_count),
+                percent(total)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Names of the frames in `stack`, outermost first, with functions inlined
+/// into a frame after it
+fn frames<S: Symbolizer>(
+    symbolizer: &S,
+    names: &mut NameCache,
+    context: proto::ExecutionContext,
+    stack: &[u64],
+) -> Vec<String> {
+    let mut frames = Vec::new();
+    match context {
+        proto::ExecutionContext::Task => {}
+        proto::ExecutionContext::Irq => frames.push("[irq]".to_string()),
+        proto::ExecutionContext::Nmi => frames.push("[nmi]".to_string()),
+    }
+    for (i, &address) in stack.iter().enumerate().rev() {
+        // Only the first address is where the processor was, rather than a return address
+        let lookup = if i == 0 {
+            address
+        } else {
+            address.saturating_sub(1)
+        };
+        let functions = names.entry(lookup).or_insert_with(|| {
+            symbolizer
+                .functions(lookup)
+                .into_iter()
+                // Semicolons separate frames in folded stacks
+                .map(|name| name.replace(';', ":"))
+                .collect()
+        });
+        if functions.is_empty() {
+            frames.push(format!("{address:#x}"));
+        } else {
+            frames.extend(functions.iter().rev().cloned());
+        }
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    /// Names addresses by which 0x100-byte block they're in, with everything
+    /// in block 3 inlined into block 2
+    struct Blocks;
+
+    impl Symbolizer for Blocks {
+        fn symbolize(&self, address: u64, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{address:#x}")
+        }
+
+        fn functions(&self, address: u64) -> Vec<String> {
+            match address >> 8 {
+                0 => Vec::new(),
+                3 => vec!["inlined".to_string(), "block2".to_string()],
+                block => vec![format!("block{block}")],
+            }
+        }
+    }
+
+    fn sample(context: proto::ExecutionContext, stack: &[u64]) -> proto::ReceiverMessage<'static> {
+        let buf = Vec::leak(vec![0; stack.len() * 8]);
+        proto::Message::ProfileSample {
+            processor: 0,
+            context,
+            stack: proto::Words::encode(stack.iter().copied(), buf),
+        }
+    }
+
+    fn profile() -> Profile {
+        let mut profile = Profile::new();
+        let task = proto::ExecutionContext::Task;
+        // A return address right after a call at the end of block 1 still names block 1
+        profile.receive(&sample(task, &[0x210, 0x200]));
+        profile.receive(&sample(task, &[0x210, 0x200]));
+        profile.receive(&sample(task, &[0x310, 0x150]));
+        profile.receive(&sample(proto::ExecutionContext::Irq, &[0x20]));
+        profile.receive(&proto::Message::ProfileSamplesDropped {
+            processor: 0,
+            count: 3,
+        });
+        profile
+    }
+
+    #[test]
+    fn test_folded() {
+        let mut folded = Vec::new();
+        profile().write_folded(&Blocks, &mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "[irq];0x20 1\nblock1;block2 2\nblock1;block2;inlined 1\n"
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let profile = profile();
+        assert_eq!((profile.samples(), profile.dropped()), (4, 3));
+
+        let mut summary = Vec::new();
+        profile.write_summary(&Blocks, 2, &mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        let lines = summary.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "4 samples (3 dropped)");
+        assert_eq!(lines[4], "  50.0%   75.0%  block2");
+        assert_eq!(lines[5], "  25.0%   25.0%  0x20");
+        assert_eq!(lines.len(), 6);
+    }
+}
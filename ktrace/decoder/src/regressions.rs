@@ -0,0 +1,343 @@
+//! Checks test runs against code size and boot time budgets.
+//!
+//! Budgets are stored in `regressions.toml`, at the root of the repository:
+//!
+//! ```toml
+//! # How far over budget a value can go, in percent, before it fails
+//! tolerance = 5
+//!
+//! # Bytes per kernel section, or for all loaded sections together
+//! [size]
+//! ".text" = 1_048_576
+//! total = 2_097_152
+//!
+//! # Microseconds from the start of boot to each boot phase
+//! [boot-time]
+//! first-task = 2_500_000
+//! ```
+//!
+//! Only this subset of TOML is supported: top-level and table keys with
+//! integer values, and comments. Boot phases are named as in the kernel's
+//! [boot time summary](crate::boot_time). Budgets with nothing to compare
+//! against, like a section the kernel doesn't have, are skipped rather than
+//! failing, so that a single file can cover runs that measure different
+//! things.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::boot_time::BootTimes;
+
+/// Default for how far over budget a value can go, in percent
+const DEFAULT_TOLERANCE: u64 = 5;
+
+/// Code size and boot time budgets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Budgets {
+    /// How far over budget a value can go, in percent, before it fails
+    pub tolerance_percent: u64,
+    /// Bytes per kernel section, by section name, or `total` for all loaded
+    /// sections
+    pub sizes: BTreeMap<String, u64>,
+    /// Microseconds from the start of boot, by boot phase
+    pub boot_times: BTreeMap<String, u64>,
+}
+
+/// A malformed line in a budgets file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Line number, starting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+/// What a budget limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Size,
+    BootTime,
+}
+
+/// The result of checking one budget
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub kind: Kind,
+    pub name: String,
+    pub budget: u64,
+    /// The measured value, if there was one
+    pub actual: Option<u64>,
+    /// Largest value that passes, with the tolerance included
+    pub limit: u64,
+}
+
+/// Results of checking every budget, which format as a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checks(pub Vec<Check>);
+
+impl Budgets {
+    /// Parse a budgets file
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut budgets = Budgets {
+            tolerance_percent: DEFAULT_TOLERANCE,
+            sizes: BTreeMap::new(),
+            boot_times: BTreeMap::new(),
+        };
+        let mut table: Option<&str> = None;
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| ParseError {
+                line: i + 1,
+                message,
+            };
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                table = Some(name.trim());
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected `key = value`, got `{line}`")))?;
+            let key = unquote(key.trim());
+            let value = value.trim();
+            let value: u64 = value
+                .replace('_', "")
+                .parse()
+                .map_err(|_| error(format!("{key} must be an integer, got {value}")))?;
+
+            match table {
+                None if key == "tolerance" => budgets.tolerance_percent = value,
+                None => return Err(error(format!("unknown setting {key}"))),
+                Some("size") => {
+                    budgets.sizes.insert(key.to_string(), value);
+                }
+                Some("boot-time") => {
+                    budgets.boot_times.insert(key.to_string(), value);
+                }
+                Some(other) => return Err(error(format!("unknown table [{other}]"))),
+            }
+        }
+        Ok(budgets)
+    }
+
+    /// Check the measured section `sizes` and boot times against the
+    /// budgets, allowing `tolerance_percent` over each. Either measurement
+    /// can be missing, and its budgets are skipped.
+    pub fn check(
+        &self,
+        sizes: Option<&BTreeMap<String, u64>>,
+        boot_times: Option<&BootTimes>,
+        tolerance_percent: u64,
+    ) -> Checks {
+        let check = |kind, name: &String, budget: u64, actual| Check {
+            kind,
+            name: name.clone(),
+            budget,
+            actual,
+            limit: budget.saturating_add(budget.saturating_mul(tolerance_percent) / 100),
+        };
+
+        let sizes = self.sizes.iter().map(|(name, &budget)| {
+            let actual = sizes.and_then(|s| s.get(name).copied());
+            check(Kind::Size, name, budget, actual)
+        });
+        let boot_times = self.boot_times.iter().map(|(name, &budget)| {
+            let actual = boot_times.and_then(|times| {
+                times
+                    .phases
+                    .iter()
+                    .find_map(|(phase, micros)| (phase == name).then_some(*micros))
+            });
+            check(Kind::BootTime, name, budget, actual)
+        });
+        Checks(sizes.chain(boot_times).collect())
+    }
+}
+
+/// Remove a trailing `#` comment from `line`, unless the `#` is quoted
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(key: &str) -> &str {
+    key.strip_prefix('"')
+        .and_then(|k| k.strip_suffix('"'))
+        .unwrap_or(key)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Kind {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Kind::Size => "size",
+            Kind::BootTime => "boot-time",
+        }
+    }
+
+    /// Format a value of this kind for people
+    fn format(self, value: u64) -> String {
+        match self {
+            Kind::Size => format!("{:.1} KiB", value as f64 / 1024.0),
+            Kind::BootTime => format!("{:.1} ms", value as f64 / 1000.0),
+        }
+    }
+}
+
+impl Check {
+    /// Whether the measured value was within the limit. Checks with nothing
+    /// measured pass.
+    pub fn passed(&self) -> bool {
+        self.actual.is_none_or(|actual| actual <= self.limit)
+    }
+
+    /// Short description of the outcome, for the table
+    fn outcome(&self) -> String {
+        match self.actual {
+            None => "skipped".to_string(),
+            Some(actual) => {
+                let change = (actual as f64 / self.budget.max(1) as f64 - 1.0) * 100.0;
+                let verdict = if self.passed() { "ok" } else { "FAIL" };
+                format!("{verdict} ({change:+.1}%)")
+            }
+        }
+    }
+}
+
+impl Checks {
+    /// Checks that went over their limits
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.0.iter().filter(|c| !c.passed())
+    }
+
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+impl fmt::Display for Checks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self
+            .0
+            .iter()
+            .map(|c| {
+                [
+                    format!("{} {}", c.kind.name(), c.name),
+                    c.actual
+                        .map_or_else(|| "-".to_string(), |a| c.kind.format(a)),
+                    c.kind.format(c.budget),
+                    c.outcome(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let header = ["check", "actual", "budget", "result"].map(String::from);
+        let width = |column: usize| {
+            rows.iter()
+                .chain([&header])
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or(0)
+        };
+        let (name, actual, budget) = (width(0), width(1), width(2));
+
+        for row in [&header].into_iter().chain(&rows) {
+            writeln!(
+                f,
+                "{:<name$}  {:>actual$}  {:>budget$}  {}",
+                row[0], row[1], row[2], row[3]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUDGETS: &str = r#"
+# Comments and blank lines are ignored
+tolerance = 10
+
+[size]
+".text" = 1_000  # bytes
+total = 4000
+
+[boot-time]
+first-task = 2000
+ap-bring-up = 500
+"#;
+
+    #[test]
+    fn test_parse() {
+        let budgets = Budgets::parse(BUDGETS).unwrap();
+        assert_eq!(budgets.tolerance_percent, 10);
+        assert_eq!(
+            budgets.sizes.into_iter().collect::<Vec<_>>(),
+            [(".text".to_string(), 1000), ("total".to_string(), 4000)]
+        );
+        assert_eq!(budgets.boot_times.len(), 2);
+
+        assert_eq!(
+            Budgets::parse("").unwrap().tolerance_percent,
+            DEFAULT_TOLERANCE
+        );
+        let error = Budgets::parse("[size]\n.text = big").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(Budgets::parse("[sizes]\ntotal = 1").is_err());
+        assert!(Budgets::parse("speed = 1").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let budgets = Budgets::parse(BUDGETS).unwrap();
+        let sizes = BTreeMap::from([(".text".to_string(), 1100), ("total".to_string(), 4500)]);
+        let boot = BootTimes::parse("reset=0us first-task=1500us").unwrap();
+        let checks = budgets.check(Some(&sizes), Some(&boot), budgets.tolerance_percent);
+
+        let outcomes = checks
+            .0
+            .iter()
+            .map(|c| (c.name.as_str(), c.actual, c.passed()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                // Exactly at the limit passes
+                (".text", Some(1100), true),
+                ("total", Some(4500), false),
+                // No AP bring-up was reported, so there's nothing to check
+                ("ap-bring-up", None, true),
+                ("first-task", Some(1500), true),
+            ]
+        );
+        assert!(!checks.passed());
+        assert_eq!(
+            checks.to_string(),
+            "check                   actual   budget  result\n\
+             size .text             1.1 KiB  1.0 KiB  ok (+10.0%)\n\
+             size total             4.4 KiB  3.9 KiB  FAIL (+12.5%)\n\
+             boot-time ap-bring-up        -   0.5 ms  skipped\n\
+             boot-time first-task    1.5 ms   2.0 ms  ok (-25.0%)\n"
+        );
+
+        // Nothing measured at all
+        assert!(budgets.check(None, None, 0).passed());
+    }
+}
@@ -0,0 +1,501 @@
+//! Replays ktrace messages into a host-side [`tracing`](tracing_core)
+//! subscriber.
+//!
+//! This reconstructs kernel spans and events as real `tracing` data, so that
+//! existing tooling (`tracing-subscriber` layers, `tracing-tree`,
+//! OpenTelemetry exporters, etc.) can consume kernel traces.
+//!
+//! `tracing` expects metadata to be `'static` and registered through a
+//! [`Callsite`]. Kernel metadata arrives dynamically, so the replayer creates
+//! (and leaks) one callsite per unique combination of metadata and field names.
+//! This is bounded by the number of callsites in the kernel, so the leak is
+//! small in practice.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+use platypos_ktrace_proto as proto;
+use tracing_core::callsite::{self, Callsite};
+use tracing_core::field::{Field, FieldSet, Value, ValueSet};
+use tracing_core::metadata::Kind;
+use tracing_core::span::{self, Attributes};
+use tracing_core::{Dispatch, Event, Interest, Metadata};
+
+use crate::crash::Crash;
+use crate::fmt::{Hex, Symbolizer};
+use crate::spans::SpanTree;
+use crate::topology::ProcessorLabel;
+
+/// Maximum number of fields replayed for a single span or event. `tracing`
+/// value sets are fixed-size arrays, so any fields past this are dropped.
+const MAX_FIELDS: usize = 32;
+
+/// Re-emits decoded ktrace messages through a [`Dispatch`].
+pub struct Replayer<S: Symbolizer> {
+    dispatch: Dispatch,
+    symbolizer: S,
+    callsites: HashMap<CallsiteKey, &'static ReplayCallsite>,
+    /// Kernel spans, with the IDs assigned by the host subscriber
+    spans: SpanTree<span::Id>,
+}
+
+/// Identifies a unique replayed callsite
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct CallsiteKey {
+    is_span: bool,
+    name: String,
+    target: String,
+    level: proto::Level,
+    file: Option<String>,
+    line: Option<u32>,
+    fields: Vec<String>,
+}
+
+/// Dynamically-created callsite for replayed metadata
+struct ReplayCallsite {
+    metadata: OnceLock<Metadata<'static>>,
+}
+
+/// Rendered field value, which can be handed to `tracing` as a [`Value`]
+enum Rendered<'a> {
+    U64(u64),
+    Str(&'a str),
+    Owned(String),
+}
+
+impl<S: Symbolizer> Replayer<S> {
+    /// Create a replayer which sends traces to `dispatch`. Kernel addresses
+    /// are resolved using `symbolizer`.
+    pub fn new(dispatch: Dispatch, symbolizer: S) -> Self {
+        Self {
+            dispatch,
+            symbolizer,
+            callsites: HashMap::new(),
+            spans: SpanTree::new(),
+        }
+    }
+
+    /// Resolves a kernel parent reference into the corresponding host span
+    fn resolve_parent(&mut self, parent: &proto::Parent) -> Option<span::Id> {
+        let key = self.spans.resolve(parent)?;
+        self.spans.get(key).cloned()
+    }
+
+    pub fn receive(&mut self, message: &proto::ReceiverMessage) {
+        self.receive_message(message);
+        for anomaly in self.spans.take_anomalies() {
+            log::warn!("Span anomaly: {anomaly}");
+        }
+    }
+
+    fn receive_message(&mut self, message: &proto::ReceiverMessage) {
+        match message {
+            proto::Message::SpanCreated(span) => {
+                let metadata = self.callsite(true, &span.metadata, &span.fields);
+                let symbolizer = &self.symbolizer;
+                let dispatch = &self.dispatch;
+                self.spans.create(span.id, &span.parent, |_, parent| {
+                    with_values(metadata, &span.fields, symbolizer, |values| {
+                        let attrs = match parent {
+                            Some(parent) => Attributes::child_of(parent.clone(), metadata, values),
+                            None => Attributes::new_root(metadata, values),
+                        };
+                        dispatch.new_span(&attrs)
+                    })
+                });
+            }
+            proto::Message::Event(event) => {
+                let parent = self.resolve_parent(&event.span_id);
+                let metadata = self.callsite(false, &event.metadata, &event.fields);
+                if !self.dispatch.enabled(metadata) {
+                    return;
+                }
+                let dispatch = &self.dispatch;
+                with_values(metadata, &event.fields, &self.symbolizer, |values| {
+                    dispatch.event(&Event::new_child_of(parent, metadata, values))
+                });
+            }
+            proto::Message::SpanClosed { id } => {
+                if let Some(id) = self.spans.close(*id).and_then(|k| self.spans.get(k)) {
+                    self.dispatch.try_close(id.clone());
+                }
+            }
+            proto::Message::SpanEntered { id, processor, .. } => {
+                let entered = self.spans.enter(*id, *processor);
+                if let Some(id) = entered.and_then(|k| self.spans.get(k)) {
+                    self.dispatch.enter(id);
+                }
+            }
+            proto::Message::SpanExited { id, processor, .. } => {
+                if let Some(id) = self
+                    .spans
+                    .exit(*id, *processor)
+                    .and_then(|k| self.spans.get(k))
+                {
+                    self.dispatch.exit(id);
+                }
+            }
+            proto::Message::ContextSwitched {
+                processor, context, ..
+            } => {
+                self.spans.switch_context(*processor, *context);
+            }
+            proto::Message::Processor {
+                processor,
+                location,
+            } => {
+                log::info!("{}", ProcessorLabel::new(*processor, Some(*location)));
+            }
+            proto::Message::Calibration { ticks_per_second } => {
+                log::debug!("Clock runs at {ticks_per_second} Hz");
+            }
+            // Replayed fields are typed by `tracing` itself
+            proto::Message::Schema(_) => {}
+            proto::Message::Suppressed { metadata, count } => {
+                log::warn!("{} repeated {count} more times", metadata.name);
+            }
+            proto::Message::Crash(report) => {
+                let crash = Crash::from(report);
+                let symbolizer = &self.symbolizer;
+                log::error!("{}", crash.display(|a, f| symbolizer.symbolize(a, f)));
+            }
+            proto::Message::PreviousCrash(report) => {
+                let crash = Crash::from(report);
+                let symbolizer = &self.symbolizer;
+                log::warn!(
+                    "Previous boot: {}",
+                    crash.display(|a, f| symbolizer.symbolize(a, f))
+                );
+            }
+            // Replaying these as if they happened now would tangle them up with the current boot's
+            // spans
+            proto::Message::PreviousTrace(_) => {}
+            // Profiles are aggregated afterwards, rather than replayed as they arrive
+            proto::Message::ProfileSample { .. } => {}
+            proto::Message::ProfileSamplesDropped { .. } => {}
+            // The decoder resolves interned metadata
+            proto::Message::MetadataDefined { .. }
+            | proto::Message::InternedSpanCreated(_)
+            | proto::Message::InternedEvent(_) => {}
+        }
+    }
+
+    /// Get or create the callsite metadata for a replayed span or event
+    fn callsite(
+        &mut self,
+        is_span: bool,
+        metadata: &proto::Metadata,
+        fields: &proto::DeserializedFields,
+    ) -> &'static Metadata<'static> {
+        let key = CallsiteKey {
+            is_span,
+            name: metadata.name.to_string(),
+            target: metadata.target.to_string(),
+            level: metadata.level,
+            file: metadata.file.map(str::to_string),
+            line: metadata.line,
+            fields: fields
+                .iter()
+                .take(MAX_FIELDS)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+        };
+
+        let callsite = *self.callsites.entry(key).or_insert_with_key(|key| {
+            let site: &'static ReplayCallsite = Box::leak(Box::new(ReplayCallsite {
+                metadata: OnceLock::new(),
+            }));
+
+            let field_names: &'static [&'static str] = Box::leak(
+                key.fields
+                    .iter()
+                    .map(|f| leak_str(f))
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice(),
+            );
+
+            let _ = site.metadata.set(Metadata::new(
+                leak_str(&key.name),
+                leak_str(&key.target),
+                level_for(key.level),
+                key.file.as_deref().map(leak_str),
+                key.line,
+                None,
+                FieldSet::new(field_names, callsite::Identifier(site)),
+                if key.is_span { Kind::SPAN } else { Kind::EVENT },
+            ));
+
+            callsite::register(site);
+            site
+        });
+
+        callsite.metadata()
+    }
+}
+
+/// Builds a [`ValueSet`] for `fields` and passes it to `f`
+fn with_values<S: Symbolizer, R>(
+    metadata: &'static Metadata<'static>,
+    fields: &proto::DeserializedFields,
+    symbolizer: &S,
+    f: impl FnOnce(&ValueSet<'_>) -> R,
+) -> R {
+    let fieldset = metadata.fields();
+    let entries: Vec<(Field, Rendered)> = fields
+        .iter()
+        .take(MAX_FIELDS)
+        .filter_map(|(name, value)| Some((fieldset.field(name)?, render(value, symbolizer))))
+        .collect();
+
+    let Some((pad, _)) = entries.first() else {
+        let values: [(&Field, Option<&dyn Value>); 0] = [];
+        return f(&fieldset.value_set(&values));
+    };
+
+    // Unused slots are filled with an empty value for an arbitrary field, which
+    // `tracing` skips when recording
+    let values: [(&Field, Option<&dyn Value>); MAX_FIELDS] =
+        std::array::from_fn(|i| match entries.get(i) {
+            Some((field, value)) => (field, Some(value.as_value())),
+            None => (pad, None),
+        });
+    f(&fieldset.value_set(&values))
+}
+
+fn render<'a, S: Symbolizer>(value: &proto::Value<'a>, symbolizer: &S) -> Rendered<'a> {
+    match value {
+        proto::Value::KernelAddress(address) => Rendered::Owned(
+            Symbolized {
+                address: *address,
+                symbolizer,
+            }
+            .to_string(),
+        ),
+        proto::Value::PhysicalAddress(addr) | proto::Value::VirtualAddress(addr) => {
+            Rendered::Owned(format!("{addr:#012x}"))
+        }
+        proto::Value::U64(x) | proto::Value::ByteSize(x) => Rendered::U64(*x),
+        proto::Value::String(s) => Rendered::Str(s),
+        proto::Value::Bytes { bytes, len } => {
+            Rendered::Owned(Hex::bytes(bytes, *len, usize::MAX).to_string())
+        }
+        proto::Value::U64Array { values, len } => {
+            Rendered::Owned(Hex::array(values, *len, usize::MAX).to_string())
+        }
+    }
+}
+
+impl<'a> Rendered<'a> {
+    fn as_value(&self) -> &dyn Value {
+        match self {
+            Rendered::U64(x) => x,
+            Rendered::Str(s) => s,
+            Rendered::Owned(s) => s,
+        }
+    }
+}
+
+/// Adapter to render a [`Symbolizer`] result as a string
+struct Symbolized<'a, S: Symbolizer> {
+    address: u64,
+    symbolizer: &'a S,
+}
+
+impl<'a, S: Symbolizer> fmt::Display for Symbolized<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.symbolizer.symbolize(self.address, f)
+    }
+}
+
+fn level_for(level: proto::Level) -> tracing_core::Level {
+    match level {
+        proto::Level::Error => tracing_core::Level::ERROR,
+        proto::Level::Warn => tracing_core::Level::WARN,
+        proto::Level::Info => tracing_core::Level::INFO,
+        proto::Level::Debug => tracing_core::Level::DEBUG,
+        proto::Level::Trace => tracing_core::Level::TRACE,
+    }
+}
+
+fn leak_str(s: &str) -> &'static str {
+    Box::leak(s.to_string().into_boxed_str())
+}
+
+impl Callsite for ReplayCallsite {
+    fn set_interest(&self, _interest: Interest) {
+        // Interest is re-checked via `Dispatch::enabled` for every replayed
+        // event, so there's nothing to cache
+    }
+
+    fn metadata(&self) -> &Metadata<'_> {
+        self.metadata
+            .get()
+            .expect("replayed callsite registered before metadata was set")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use tracing_core::field::Visit;
+    use tracing_core::span::Record;
+    use tracing_core::Subscriber;
+
+    use super::*;
+
+    struct Names;
+
+    impl Symbolizer for Names {
+        fn symbolize(&self, address: u64, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "fn_{address:x}")
+        }
+    }
+
+    /// Subscriber that logs everything it's sent, with spans named instead of
+    /// numbered
+    #[derive(Clone, Default)]
+    struct Recorder {
+        log: Arc<Mutex<Vec<String>>>,
+        spans: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Recorder {
+        fn name(&self, id: &span::Id) -> &'static str {
+            self.spans.lock().unwrap()[id.into_u64() as usize - 1]
+        }
+
+        fn push(&self, entry: String) {
+            self.log.lock().unwrap().push(entry);
+        }
+    }
+
+    /// Renders fields as ` name=value`
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0 += &format!(" {field}={value}");
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0 += &format!(" {field}={value:?}");
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> span::Id {
+            let mut fields = Fields(String::new());
+            span.record(&mut fields);
+            let parent = span.parent().map_or("none", |parent| self.name(parent));
+            let name = span.metadata().name();
+            self.push(format!("new {name} (parent {parent}){}", fields.0));
+
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(name);
+            span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &span::Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            let parent = event.parent().map_or("none", |parent| self.name(parent));
+            let name = event.metadata().name();
+            self.push(format!("event {name} (parent {parent}){}", fields.0));
+        }
+
+        fn enter(&self, span: &span::Id) {
+            self.push(format!("enter {}", self.name(span)));
+        }
+
+        fn exit(&self, span: &span::Id) {
+            self.push(format!("exit {}", self.name(span)));
+        }
+
+        fn try_close(&self, span: span::Id) -> bool {
+            self.push(format!("close {}", self.name(&span)));
+            true
+        }
+    }
+
+    type Message =
+        proto::Message<'static, BTreeMap<&'static str, &'static str>, BTreeMap<&'static str, u64>>;
+
+    fn metadata(name: &'static str) -> proto::Metadata<'static> {
+        proto::Metadata {
+            name,
+            target: "platypos_kernel::sched",
+            level: proto::Level::Info,
+            file: Some("kernel/src/sched.rs"),
+            line: Some(10),
+        }
+    }
+
+    fn span(id: proto::SpanId, name: &'static str, fields: BTreeMap<&'static str, u64>) -> Message {
+        proto::Message::SpanCreated(proto::SpanCreated {
+            id,
+            parent: proto::Parent::Current(0),
+            context: proto::ExecutionContext::Task,
+            metadata: metadata(name),
+            fields,
+        })
+    }
+
+    #[test]
+    fn test_replay() {
+        let messages = [
+            span(1, "outer", BTreeMap::from([("count", 3), ("at", 0x1234)])),
+            proto::Message::SpanEntered {
+                id: 1,
+                processor: 0,
+                ticks: 0,
+            },
+            span(2, "inner", BTreeMap::new()),
+            proto::Message::Event(proto::Event {
+                span_id: proto::Parent::Current(0),
+                context: proto::ExecutionContext::Task,
+                metadata: metadata("tick"),
+                fields: BTreeMap::from([("message", "hello")]),
+            }),
+            proto::Message::SpanExited {
+                id: 1,
+                processor: 0,
+                ticks: 0,
+            },
+            proto::Message::SpanClosed { id: 2 },
+            proto::Message::SpanClosed { id: 1 },
+        ];
+
+        let recorder = Recorder::default();
+        let mut replayer = Replayer::new(Dispatch::new(recorder.clone()), Names);
+        let mut buf = [0u8; 256];
+        for message in &messages {
+            let bytes = postcard::to_slice(message, &mut buf).unwrap();
+            let decoded: proto::ReceiverMessage = postcard::from_bytes(bytes).unwrap();
+            replayer.receive(&decoded);
+        }
+
+        assert_eq!(
+            *recorder.log.lock().unwrap(),
+            [
+                "new outer (parent none) at=fn_1234 count=3",
+                "enter outer",
+                "new inner (parent outer)",
+                "event tick (parent outer) message=hello",
+                "exit outer",
+                "close inner",
+                "close outer",
+            ]
+        );
+    }
+}
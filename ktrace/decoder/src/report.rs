@@ -0,0 +1,804 @@
+//! Self-contained HTML reports of a trace capture.
+//!
+//! A [`Report`] collects decoded messages and renders them as a single HTML
+//! file, with its styles and scripts inline, so it can be attached to an
+//! issue or kept as a CI artifact and opened in any browser:
+//! * A collapsible span tree for each processor, with the number of events
+//!   recorded in each span
+//! * A table of events, which can be filtered by level and by text
+//! * A timeline of the boot milestones, and the kernel's boot time summary
+//! * Bar charts of events by level and target, and over the capture
+//!
+//! Field values are rendered like compact text output, including any custom
+//! [`Renderers`] set with [`Report::set_renderers`].
+//!
+//! As with [`stats`](crate::stats), positions on the timeline and in the
+//! charts are message numbers rather than times, since most messages don't
+//! carry timestamps. Messages added with [`Report::receive_at`] also get a
+//! time of day in the event table, and the report notes how the kernel's clock
+//! lines up with the host's (see [`clock`](crate::clock)).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use platypos_ktrace_proto as proto;
+
+use crate::boot_time::BootTimes;
+use crate::clock::{ClockSync, KernelTime, WallClock};
+use crate::fmt::Renderers;
+use crate::owned::{OwnedMessage, OwnedValue};
+use crate::spans::SpanTree;
+
+/// Maximum number of events to include in the event table. Later events are
+/// still counted in the charts and span tree.
+const MAX_EVENTS: usize = 50_000;
+
+/// Number of columns in the events-over-capture chart
+const TIMELINE_BUCKETS: usize = 60;
+
+/// Every level, in order of declaration
+const LEVELS: [proto::Level; 5] = [
+    proto::Level::Error,
+    proto::Level::Warn,
+    proto::Level::Info,
+    proto::Level::Debug,
+    proto::Level::Trace,
+];
+
+/// Maximum number of targets to chart individually
+const MAX_TARGETS: usize = 15;
+
+/// Collects decoded messages for an HTML report
+pub struct Report {
+    title: String,
+    spans: SpanTree<usize>,
+    /// Every span created, indexed by the data in `spans`
+    nodes: Vec<SpanNode>,
+    events: Vec<EventRow>,
+    /// Events left out of the table because it was full
+    omitted_events: u64,
+    /// Message number of every event, for the timeline chart
+    event_positions: Vec<u64>,
+    /// Event counts, indexed by level
+    events_by_level: [u64; LEVELS.len()],
+    events_by_target: BTreeMap<String, u64>,
+    milestones: Vec<(u64, String)>,
+    boot_times: Option<BootTimes>,
+    messages: u64,
+    anomalies: usize,
+    clock: ClockSync,
+    renderers: Renderers,
+}
+
+struct SpanNode {
+    name: String,
+    fields: String,
+    parent: Option<usize>,
+    /// Processor the span was created or first entered on
+    processor: Option<proto::ProcessorId>,
+    events: u64,
+    closed: bool,
+}
+
+struct EventRow {
+    position: u64,
+    level: proto::Level,
+    target: String,
+    processor: Option<proto::ProcessorId>,
+    context: proto::ExecutionContext,
+    span: Option<usize>,
+    fields: String,
+    kernel_time: Option<KernelTime>,
+    received: Option<SystemTime>,
+}
+
+impl Report {
+    pub fn new(title: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            spans: SpanTree::new(),
+            nodes: Vec::new(),
+            events: Vec::new(),
+            omitted_events: 0,
+            event_positions: Vec::new(),
+            events_by_level: [0; LEVELS.len()],
+            events_by_target: BTreeMap::new(),
+            milestones: Vec::new(),
+            boot_times: None,
+            messages: 0,
+            anomalies: 0,
+            clock: ClockSync::new(),
+            renderers: Renderers::new(),
+        }
+    }
+
+    /// Render fields with `renderers` where they apply. This only affects
+    /// messages received afterwards.
+    pub fn set_renderers(&mut self, renderers: Renderers) {
+        self.renderers = renderers;
+    }
+
+    /// Add the next decoded message to the report
+    pub fn receive(&mut self, message: &OwnedMessage) {
+        self.record(message, None);
+    }
+
+    /// Add the next decoded message to the report, which the host received at
+    /// `received`
+    pub fn receive_at(&mut self, message: &OwnedMessage, received: SystemTime) {
+        self.record(message, Some(received));
+    }
+
+    fn record(&mut self, message: &OwnedMessage, received: Option<SystemTime>) {
+        let position = self.messages;
+        self.messages += 1;
+
+        match message {
+            OwnedMessage::SpanCreated {
+                id,
+                parent,
+                metadata,
+                fields,
+                ..
+            } => {
+                let index = self.nodes.len();
+                let mut parent_index = None;
+                self.spans.create(*id, parent, |_, parent| {
+                    parent_index = parent.copied();
+                    index
+                });
+                let processor = match parent {
+                    proto::Parent::Current(processor) => Some(*processor),
+                    _ => parent_index.and_then(|p| self.nodes[p].processor),
+                };
+                self.nodes.push(SpanNode {
+                    name: metadata.name.clone(),
+                    fields: format_fields(fields, &self.renderers),
+                    parent: parent_index,
+                    processor,
+                    events: 0,
+                    closed: false,
+                });
+            }
+            OwnedMessage::Event {
+                parent,
+                context,
+                metadata,
+                fields,
+            } => {
+                let span = self
+                    .spans
+                    .resolve(parent)
+                    .and_then(|key| self.spans.get(key))
+                    .copied();
+                if let Some(span) = span {
+                    self.nodes[span].events += 1;
+                }
+                let processor = match parent {
+                    proto::Parent::Current(processor) => Some(*processor),
+                    _ => span.and_then(|s| self.nodes[s].processor),
+                };
+
+                let message = fields.iter().find_map(|(name, value)| match value {
+                    OwnedValue::String(s) if name == "message" => Some(s.as_str()),
+                    _ => None,
+                });
+                if let Some(message) = message {
+                    if metadata.target == proto::MILESTONE_TARGET {
+                        self.milestones.push((position, message.to_string()));
+                    } else if metadata.target == proto::BOOT_TIME_TARGET {
+                        self.boot_times = BootTimes::parse(message).or(self.boot_times.take());
+                    }
+                }
+
+                self.events_by_level[metadata.level as usize] += 1;
+                *self
+                    .events_by_target
+                    .entry(metadata.target.clone())
+                    .or_default() += 1;
+                self.event_positions.push(position);
+
+                let kernel_time = KernelTime::from_fields(fields.iter().filter_map(
+                    |(name, value)| match value {
+                        OwnedValue::U64(n) => Some((name.as_str(), *n)),
+                        _ => None,
+                    },
+                ));
+                if let (Some(time), Some(received)) = (kernel_time, received) {
+                    self.clock.add_sample(time, received);
+                }
+
+                if self.events.len() < MAX_EVENTS {
+                    self.events.push(EventRow {
+                        position,
+                        level: metadata.level,
+                        target: metadata.target.clone(),
+                        processor,
+                        context: *context,
+                        span,
+                        fields: format_fields(fields, &self.renderers),
+                        kernel_time,
+                        received,
+                    });
+                } else {
+                    self.omitted_events += 1;
+                }
+            }
+            OwnedMessage::SpanEntered { id, processor, .. } => {
+                if let Some(key) = self.spans.enter(*id, *processor) {
+                    let index = self.spans.get(key).copied();
+                    if let Some(index) = index {
+                        self.nodes[index].processor.get_or_insert(*processor);
+                    }
+                }
+            }
+            OwnedMessage::SpanExited { id, processor, .. } => {
+                self.spans.exit(*id, *processor);
+            }
+            OwnedMessage::SpanClosed { id } => {
+                if let Some(key) = self.spans.close(*id) {
+                    if let Some(&index) = self.spans.get(key) {
+                        self.nodes[index].closed = true;
+                    }
+                }
+            }
+            OwnedMessage::ContextSwitched {
+                processor, context, ..
+            } => {
+                self.spans.switch_context(*processor, *context);
+            }
+            OwnedMessage::Calibration { ticks_per_second } => {
+                self.clock.set_ticks_per_second(*ticks_per_second);
+            }
+            _ => {}
+        }
+
+        self.anomalies += self.spans.take_anomalies().len();
+    }
+
+    /// Render the report as a standalone HTML page
+    pub fn write_html<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "<!DOCTYPE html>")?;
+        writeln!(w, "<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">")?;
+        writeln!(w, "<title>{}</title>", Escaped(&self.title))?;
+        writeln!(w, "<style>{STYLE}</style>\n</head>\n<body>")?;
+        writeln!(w, "<h1>{}</h1>", Escaped(&self.title))?;
+        writeln!(
+            w,
+            "<p>{} messages, {} events, {} spans",
+            self.messages,
+            self.event_positions.len(),
+            self.nodes.len()
+        )?;
+        if self.anomalies > 0 {
+            write!(
+                w,
+                ", <span class=\"warn\">{} span anomalies (trace data may have been lost)</span>",
+                self.anomalies
+            )?;
+        }
+        writeln!(w, "</p>")?;
+        if let Some(fit) = self.clock.fit() {
+            writeln!(
+                w,
+                "<p class=\"muted\">Times are estimated from when the host received each \
+                 message: {}.</p>",
+                Escaped(&fit.to_string())
+            )?;
+        }
+
+        self.write_boot(&mut w)?;
+        self.write_charts(&mut w)?;
+        self.write_span_trees(&mut w)?;
+        self.write_events(&mut w)?;
+
+        writeln!(w, "<script>{SCRIPT}</script>\n</body>\n</html>")
+    }
+
+    fn write_boot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "<h2>Boot</h2>")?;
+        if self.milestones.is_empty() {
+            writeln!(w, "<p>No milestones were reached.</p>")?;
+        } else {
+            // One row per milestone, so labels never overlap
+            let height = self.milestones.len() * ROW_HEIGHT + ROW_HEIGHT;
+            writeln!(
+                w,
+                "<svg class=\"chart\" width=\"{}\" height=\"{height}\">",
+                LABEL_WIDTH + BAR_WIDTH + 20
+            )?;
+            let scale = BAR_WIDTH as f64 / self.messages.max(1) as f64;
+            for (i, (position, name)) in self.milestones.iter().enumerate() {
+                let x = LABEL_WIDTH as f64 + *position as f64 * scale;
+                let y = i * ROW_HEIGHT + ROW_HEIGHT / 2;
+                writeln!(
+                    w,
+                    "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>\
+                     <line class=\"axis\" x1=\"{LABEL_WIDTH}\" x2=\"{x:.1}\" y1=\"{y}\" \
+                     y2=\"{y}\"/><circle cx=\"{x:.1}\" cy=\"{y}\" r=\"4\">\
+                     <title>message {position}</title></circle>",
+                    LABEL_WIDTH - 8,
+                    y + 4,
+                    Escaped(name)
+                )?;
+            }
+            writeln!(
+                w,
+                "<text class=\"muted\" x=\"{LABEL_WIDTH}\" y=\"{}\">message 0 to {}</text>",
+                height - 4,
+                self.messages
+            )?;
+            writeln!(w, "</svg>")?;
+        }
+
+        if let Some(boot_times) = &self.boot_times {
+            write_bar_chart(w, "Time from start of boot (us)", &boot_times.phases)?;
+        }
+        Ok(())
+    }
+
+    fn write_charts<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "<h2>Metrics</h2>")?;
+
+        let levels: Vec<(String, u64)> = LEVELS
+            .iter()
+            .zip(self.events_by_level)
+            .filter(|&(_, count)| count > 0)
+            .map(|(level, count)| (level.to_string(), count))
+            .collect();
+        write_bar_chart(w, "Events by level", &levels)?;
+
+        let mut targets: Vec<(String, u64)> = self
+            .events_by_target
+            .iter()
+            .map(|(target, count)| (target.clone(), *count))
+            .collect();
+        targets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        if targets.len() > MAX_TARGETS {
+            let other = targets.drain(MAX_TARGETS..).map(|(_, count)| count).sum();
+            targets.push(("(other)".to_string(), other));
+        }
+        write_bar_chart(w, "Events by target", &targets)?;
+
+        let mut buckets = [0u64; TIMELINE_BUCKETS];
+        let per_bucket = self.messages.div_ceil(TIMELINE_BUCKETS as u64).max(1);
+        for position in &self.event_positions {
+            buckets[(position / per_bucket) as usize] += 1;
+        }
+        write_column_chart(w, "Events over the capture", &buckets, per_bucket)
+    }
+
+    fn write_span_trees<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "<h2>Spans</h2>")?;
+
+        let mut children: Vec<Vec<usize>> = vec![Vec::new(); self.nodes.len()];
+        let mut roots: BTreeMap<Option<proto::ProcessorId>, Vec<usize>> = BTreeMap::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            match node.parent {
+                Some(parent) => children[parent].push(index),
+                None => roots.entry(node.processor).or_default().push(index),
+            }
+        }
+
+        if roots.is_empty() {
+            writeln!(w, "<p>No spans were recorded.</p>")?;
+        }
+        // Spans that were never entered, and have no parent to place them by, come last
+        let unattributed = roots.remove(&None);
+        let groups = roots
+            .into_iter()
+            .map(|(processor, spans)| (ProcessorName(processor), spans))
+            .chain(unattributed.map(|spans| (ProcessorName(None), spans)));
+        for (processor, spans) in groups {
+            writeln!(
+                w,
+                "<details class=\"processor\" open><summary>{processor} \
+                 <span class=\"muted\">({} root spans)</span></summary>",
+                spans.len()
+            )?;
+            for span in spans {
+                self.write_span(w, span, &children)?;
+            }
+            writeln!(w, "</details>")?;
+        }
+        Ok(())
+    }
+
+    fn write_span<W: Write>(
+        &self,
+        w: &mut W,
+        index: usize,
+        children: &[Vec<usize>],
+    ) -> io::Result<()> {
+        let node = &self.nodes[index];
+        let label = format!(
+            "<span class=\"name\">{}</span> {} <span class=\"muted\">{} events{}</span>",
+            Escaped(&node.name),
+            Escaped(&node.fields),
+            node.events,
+            if node.closed { "" } else { ", never closed" }
+        );
+        if children[index].is_empty() {
+            return writeln!(w, "<div class=\"leaf\">{label}</div>");
+        }
+        writeln!(w, "<details><summary>{label}</summary>")?;
+        for &child in &children[index] {
+            self.write_span(w, child, children)?;
+        }
+        writeln!(w, "</details>")
+    }
+
+    fn write_events<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "<h2>Events</h2>")?;
+        writeln!(
+            w,
+            "<p><select id=\"level\"><option value=\"5\">All levels</option>\
+             <option value=\"0\">ERROR</option><option value=\"1\">WARN and above</option>\
+             <option value=\"2\">INFO and above</option>\
+             <option value=\"3\">DEBUG and above</option></select> \
+             <input id=\"filter\" type=\"search\" placeholder=\"Filter events\"></p>"
+        )?;
+        if self.omitted_events > 0 {
+            writeln!(
+                w,
+                "<p class=\"warn\">Only the first {MAX_EVENTS} events are shown; {} more were \
+                 left out.</p>",
+                self.omitted_events
+            )?;
+        }
+        // Only show times if the messages came with receive times
+        let show_times = self.events.iter().any(|e| e.received.is_some());
+        let fit = self.clock.fit();
+        writeln!(
+            w,
+            "<table id=\"events\"><thead><tr><th>#</th>{}<th>Level</th><th>CPU</th>\
+             <th>Context</th><th>Target</th><th>Span</th><th>Fields</th></tr></thead><tbody>",
+            if show_times { "<th>Time</th>" } else { "" }
+        )?;
+        for event in &self.events {
+            let time = if show_times {
+                let fitted = event
+                    .kernel_time
+                    .zip(fit)
+                    .and_then(|(time, fit)| fit.wall_clock(time));
+                match (fitted, event.received) {
+                    (Some(time), _) => format!("<td>{}</td>", WallClock(time)),
+                    (None, Some(received)) => format!("<td>~{}</td>", WallClock(received)),
+                    (None, None) => "<td></td>".to_string(),
+                }
+            } else {
+                String::new()
+            };
+            let span = event
+                .span
+                .map(|s| self.nodes[s].name.as_str())
+                .unwrap_or("");
+            let processor = match event.processor {
+                Some(processor) => processor.to_string(),
+                None => String::new(),
+            };
+            writeln!(
+                w,
+                "<tr data-level=\"{}\" class=\"{}\"><td>{}</td>{time}<td>{}</td>\
+                 <td>{processor}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                event.level as u8,
+                level_class(event.level),
+                event.position,
+                event.level,
+                event.context,
+                Escaped(&event.target),
+                Escaped(span),
+                Escaped(&event.fields)
+            )?;
+        }
+        writeln!(w, "</tbody></table>")
+    }
+}
+
+/// Height of each row in the timeline and bar charts
+const ROW_HEIGHT: usize = 20;
+/// Width of the label column in the timeline and bar charts
+const LABEL_WIDTH: usize = 260;
+/// Width of the longest bar
+const BAR_WIDTH: usize = 480;
+
+/// Horizontal bar chart with one labelled bar per entry
+fn write_bar_chart<W: Write>(w: &mut W, title: &str, bars: &[(String, u64)]) -> io::Result<()> {
+    writeln!(w, "<h3>{}</h3>", Escaped(title))?;
+    if bars.is_empty() {
+        return writeln!(w, "<p class=\"muted\">Nothing recorded.</p>");
+    }
+    let max = bars
+        .iter()
+        .map(|(_, value)| *value)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    writeln!(
+        w,
+        "<svg class=\"chart\" width=\"{}\" height=\"{}\">",
+        LABEL_WIDTH + BAR_WIDTH + 80,
+        bars.len() * ROW_HEIGHT
+    )?;
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let y = i * ROW_HEIGHT;
+        let width = (*value as f64 / max as f64 * BAR_WIDTH as f64).max(1.0);
+        writeln!(
+            w,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>\
+             <rect x=\"{LABEL_WIDTH}\" y=\"{}\" width=\"{width:.1}\" height=\"{}\"/>\
+             <text x=\"{:.1}\" y=\"{}\">{value}</text>",
+            LABEL_WIDTH - 8,
+            y + 14,
+            Escaped(label),
+            y + 3,
+            ROW_HEIGHT - 6,
+            LABEL_WIDTH as f64 + width + 6.0,
+            y + 14
+        )?;
+    }
+    writeln!(w, "</svg>")
+}
+
+/// Column chart of `columns`, each covering `span` messages
+fn write_column_chart<W: Write>(
+    w: &mut W,
+    title: &str,
+    columns: &[u64],
+    span: u64,
+) -> io::Result<()> {
+    const HEIGHT: usize = 120;
+    const COLUMN_WIDTH: usize = 10;
+
+    writeln!(w, "<h3>{}</h3>", Escaped(title))?;
+    let max = columns.iter().copied().max().unwrap_or(0).max(1);
+    writeln!(
+        w,
+        "<svg class=\"chart\" width=\"{}\" height=\"{}\">",
+        columns.len() * COLUMN_WIDTH,
+        HEIGHT + ROW_HEIGHT
+    )?;
+    for (i, &value) in columns.iter().enumerate() {
+        let height = value as f64 / max as f64 * HEIGHT as f64;
+        writeln!(
+            w,
+            "<rect x=\"{}\" y=\"{:.1}\" width=\"{}\" height=\"{height:.1}\">\
+             <title>messages {}-{}: {value} events</title></rect>",
+            i * COLUMN_WIDTH,
+            HEIGHT as f64 - height,
+            COLUMN_WIDTH - 2,
+            i as u64 * span,
+            (i as u64 + 1) * span - 1
+        )?;
+    }
+    writeln!(
+        w,
+        "<text class=\"muted\" x=\"0\" y=\"{}\">{} messages per column, at most {max} \
+         events</text>",
+        HEIGHT + ROW_HEIGHT - 4,
+        span
+    )?;
+    writeln!(w, "</svg>")
+}
+
+fn level_class(level: proto::Level) -> &'static str {
+    match level {
+        proto::Level::Error => "error",
+        proto::Level::Warn => "warn",
+        _ => "",
+    }
+}
+
+/// Fields in compact `name=value` form, with the message first and unlabelled
+fn format_fields(fields: &[(String, OwnedValue)], renderers: &Renderers) -> String {
+    use std::fmt::Write as _;
+
+    let mut out = String::new();
+    for (name, value) in fields {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        if name != "message" {
+            let _ = write!(out, "{name}=");
+        }
+        if !renderers.is_empty() {
+            if let Some(rendered) = renderers.render(name, &value.as_value()) {
+                out.push_str(&rendered);
+                continue;
+            }
+        }
+        let _ = match value {
+            OwnedValue::String(s) => write!(out, "{s}"),
+            OwnedValue::U64(x) => write!(out, "{x}"),
+            OwnedValue::ByteSize(x) => write!(out, "{x}B"),
+            OwnedValue::KernelAddress(x)
+            | OwnedValue::PhysicalAddress(x)
+            | OwnedValue::VirtualAddress(x) => write!(out, "{x:#x}"),
+            OwnedValue::Bytes { bytes, len } => {
+                write!(out, "{}", crate::fmt::Hex::bytes(bytes, *len, 32))
+            }
+            OwnedValue::U64Array { values, len } => {
+                write!(
+                    out,
+                    "{}",
+                    crate::fmt::Hex::array(values, *len, proto::MAX_ARRAY_LEN)
+                )
+            }
+        };
+    }
+    out
+}
+
+struct ProcessorName(Option<proto::ProcessorId>);
+
+impl fmt::Display for ProcessorName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(processor) => write!(f, "Processor {processor}"),
+            None => f.write_str("Unattributed"),
+        }
+    }
+}
+
+/// Text escaped for HTML element content and attribute values
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => fmt::Write::write_char(f, c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; }
+.muted { color: #777; }
+.warn { color: #b36b00; }
+.error { color: #c0392b; }
+.chart text { font-size: 12px; fill: #222; }
+.chart text.muted { fill: #777; }
+.chart rect, .chart circle { fill: #4a7ab5; }
+.chart .axis { stroke: #ccc; }
+details { margin-left: 1.2em; }
+details.processor { margin-left: 0; }
+summary { cursor: pointer; }
+.leaf { margin-left: 2.4em; }
+.name { font-weight: 600; }
+table { border-collapse: collapse; font-family: monospace; font-size: 12px; }
+th, td { text-align: left; vertical-align: top; padding: 2px 8px; }
+td { border-bottom: 1px solid #eee; }
+td:last-child { white-space: pre-wrap; }
+";
+
+const SCRIPT: &str = "
+const level = document.getElementById('level');
+const filter = document.getElementById('filter');
+function update() {
+  const max = Number(level.value);
+  const text = filter.value.toLowerCase();
+  for (const row of document.querySelectorAll('#events tbody tr')) {
+    const shown = Number(row.dataset.level) <= max
+      && (text === '' || row.textContent.toLowerCase().includes(text));
+    row.hidden = !shown;
+  }
+}
+level.addEventListener('change', update);
+filter.addEventListener('input', update);
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::owned::OwnedMetadata;
+
+    fn metadata(name: &str, target: &str) -> OwnedMetadata {
+        OwnedMetadata {
+            name: name.to_string(),
+            target: target.to_string(),
+            level: proto::Level::Info,
+            file: None,
+            line: None,
+        }
+    }
+
+    fn event(processor: proto::ProcessorId, target: &str, message: &str) -> OwnedMessage {
+        OwnedMessage::Event {
+            parent: proto::Parent::Current(processor),
+            context: proto::ExecutionContext::Task,
+            metadata: metadata("event", target),
+            fields: vec![(
+                "message".to_string(),
+                OwnedValue::String(message.to_string()),
+            )],
+        }
+    }
+
+    fn span(id: proto::SpanId, parent: proto::Parent, name: &str) -> OwnedMessage {
+        OwnedMessage::SpanCreated {
+            id,
+            parent,
+            context: proto::ExecutionContext::Task,
+            metadata: metadata(name, "kernel"),
+            fields: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_span_trees() {
+        let mut report = Report::new("test");
+        for message in [
+            span(1, proto::Parent::Root, "boot"),
+            OwnedMessage::SpanEntered {
+                id: 1,
+                processor: 0,
+                ticks: 0,
+            },
+            span(2, proto::Parent::Current(0), "memory"),
+            span(3, proto::Parent::Root, "ap"),
+            OwnedMessage::SpanEntered {
+                id: 3,
+                processor: 1,
+                ticks: 0,
+            },
+            event(1, "kernel", "hello"),
+            OwnedMessage::SpanExited {
+                id: 3,
+                processor: 1,
+                ticks: 0,
+            },
+            OwnedMessage::SpanClosed { id: 3 },
+            span(3, proto::Parent::Explicit(1), "reused"),
+        ] {
+            report.receive(&message);
+        }
+
+        let summary: Vec<_> = report
+            .nodes
+            .iter()
+            .map(|n| (n.name.as_str(), n.parent, n.processor, n.events, n.closed))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("boot", None, Some(0), 0, false),
+                ("memory", Some(0), Some(0), 0, false),
+                ("ap", None, Some(1), 1, true),
+                ("reused", Some(0), Some(0), 0, false),
+            ]
+        );
+        assert_eq!(report.events[0].span, Some(2));
+        assert_eq!(report.anomalies, 0);
+    }
+
+    #[test]
+    fn test_milestones_and_escaping() {
+        let mut report = Report::new("<b>capture</b>");
+        report.receive(&event(0, proto::MILESTONE_TARGET, "heap"));
+        report.receive(&event(0, "kernel", "<script>alert('hi')</script>"));
+        report.receive(&event(
+            0,
+            proto::BOOT_TIME_TARGET,
+            "loader-handoff=0us heap=12us",
+        ));
+        assert_eq!(report.milestones, [(0, "heap".to_string())]);
+        assert_eq!(report.boot_times.as_ref().unwrap().total_micros(), 12);
+
+        let mut html = Vec::new();
+        report.write_html(&mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("<title>&lt;b&gt;capture&lt;/b&gt;</title>"));
+        assert!(html.contains("&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt;"));
+        assert_eq!(html.matches("<script>").count(), 1);
+    }
+}
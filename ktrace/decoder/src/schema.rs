@@ -0,0 +1,96 @@
+//! Validation of spans and events against the schemas the kernel declares at
+//! startup.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+/// Schemas received from the kernel
+#[derive(Debug, Default)]
+pub struct Schemas {
+    declared: HashMap<(proto::SchemaKind, String), Vec<(String, proto::FieldType)>>,
+}
+
+/// A way in which a span or event didn't match its schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The field isn't part of the schema
+    Undeclared(String),
+    /// The schema declares the field, but it wasn't recorded
+    Missing(String),
+    /// The field was recorded with a different type than declared
+    WrongType {
+        field: String,
+        expected: proto::FieldType,
+        actual: proto::FieldType,
+    },
+}
+
+impl Schemas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a schema declaration
+    pub fn declare(&mut self, schema: &proto::Schema) {
+        let fields = schema
+            .fields
+            .as_slice()
+            .iter()
+            .map(|f| (f.name.to_string(), f.ty))
+            .collect();
+        self.declared
+            .insert((schema.kind, schema.name.to_string()), fields);
+    }
+
+    /// Check the fields of the span or event `name` against its schema. Spans
+    /// and events without a schema always match.
+    pub fn check(
+        &self,
+        kind: proto::SchemaKind,
+        name: &str,
+        fields: &proto::DeserializedFields,
+    ) -> Vec<Mismatch> {
+        let Some(declared) = self.declared.get(&(kind, name.to_string())) else {
+            return Vec::new();
+        };
+
+        let mut mismatches = Vec::new();
+        for (field, value) in fields.iter() {
+            match declared.iter().find(|(name, _)| name == field) {
+                None => mismatches.push(Mismatch::Undeclared(field.to_string())),
+                Some((_, expected)) if *expected != value.field_type() => {
+                    mismatches.push(Mismatch::WrongType {
+                        field: field.to_string(),
+                        expected: *expected,
+                        actual: value.field_type(),
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+
+        for (name, _) in declared {
+            if !fields.iter().any(|(field, _)| field == name) {
+                mismatches.push(Mismatch::Missing(name.clone()));
+            }
+        }
+
+        mismatches
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Undeclared(field) => write!(f, "field {field} is not in the schema"),
+            Mismatch::Missing(field) => write!(f, "missing field {field}"),
+            Mismatch::WrongType {
+                field,
+                expected,
+                actual,
+            } => write!(f, "field {field} should be {expected:?}, got {actual:?}"),
+        }
+    }
+}
@@ -0,0 +1,476 @@
+//! Span tree reconstruction.
+//!
+//! The kernel allocates span IDs from a slab, so an ID is reused as soon as
+//! its span closes. Tracking spans by ID alone mis-attributes events and
+//! children to whichever span currently has the ID. [`SpanTree`] tells
+//! incarnations of an ID apart with a generation number, bumped each time the
+//! ID is created, and identifies spans by [`SpanKey`]s that include it:
+//! * Span stacks record the key that was entered, so events in a span stay
+//!   attributed to it even if they're decoded after the ID is reused.
+//! * Closed spans stay in the tree as long as they have open descendants, so
+//!   ancestry is still correct after their IDs are reused.
+//!
+//! Each processor has its own span stack, except while it's switched to
+//! another execution context (usually a kernel task), which has a stack that
+//! follows it between processors.
+//!
+//! Messages that don't fit this model are recorded as [`SpanAnomaly`]s rather
+//! than panicking, since they're usually caused by trace data being lost or
+//! the decoder attaching to a kernel that's already running.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+/// A single incarnation of a span ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanKey {
+    pub id: proto::SpanId,
+    /// Number of times `id` was created before this span, starting from 0
+    pub generation: u32,
+}
+
+impl fmt::Display for SpanKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.generation == 0 {
+            write!(f, "{}", self.id)
+        } else {
+            write!(f, "{}.{}", self.id, self.generation)
+        }
+    }
+}
+
+/// A message that doesn't match the spans the decoder has seen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanAnomaly {
+    /// A message referred to a span that was never created
+    Unknown { id: proto::SpanId },
+    /// A message referred to a span after it closed
+    Stale { span: SpanKey },
+    /// A span was created with the ID of a span that hadn't closed yet, so
+    /// the close was probably lost. The old span is treated as closed.
+    ReusedOpen { old: SpanKey },
+    /// A span was created under a parent that was unknown or already closed,
+    /// so it was attached to the root instead
+    Orphaned {
+        span: SpanKey,
+        parent: proto::SpanId,
+    },
+    /// A span was exited on a processor where it wasn't the current span
+    UnbalancedExit {
+        id: proto::SpanId,
+        processor: proto::ProcessorId,
+        current: Option<SpanKey>,
+    },
+}
+
+impl fmt::Display for SpanAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpanAnomaly::Unknown { id } => write!(f, "span {id} was never created"),
+            SpanAnomaly::Stale { span } => write!(f, "span {span} was used after closing"),
+            SpanAnomaly::ReusedOpen { old } => {
+                write!(f, "span ID {} was reused before {old} closed", old.id)
+            }
+            SpanAnomaly::Orphaned { span, parent } => {
+                write!(f, "span {span} has an unknown or closed parent {parent}")
+            }
+            SpanAnomaly::UnbalancedExit {
+                id,
+                processor,
+                current: Some(current),
+            } => write!(
+                f,
+                "span {id} exited on processor {processor}, but {current} was current"
+            ),
+            SpanAnomaly::UnbalancedExit {
+                id,
+                processor,
+                current: None,
+            } => write!(
+                f,
+                "span {id} exited on processor {processor}, which wasn't in a span"
+            ),
+        }
+    }
+}
+
+/// Owner of a span stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Stack {
+    Processor(proto::ProcessorId),
+    Context(proto::ContextId),
+}
+
+struct Node<T> {
+    data: T,
+    parent: Option<SpanKey>,
+    /// Number of spans with this one as their parent that are still in the
+    /// tree
+    children: usize,
+    closed: bool,
+}
+
+/// Reconstructed tree of kernel spans, with data of type `T` attached to each
+pub struct SpanTree<T> {
+    nodes: HashMap<SpanKey, Node<T>>,
+    /// The latest incarnation of each ID that's been created
+    latest: HashMap<proto::SpanId, SpanKey>,
+    stacks: HashMap<Stack, Vec<SpanKey>>,
+    /// Context each processor is switched to, if it's not in its own
+    contexts: HashMap<proto::ProcessorId, proto::ContextId>,
+    /// Closed spans to remove from the tree before the next change, kept
+    /// until then so that callers can look at spans they just closed
+    pending_removal: Vec<SpanKey>,
+    anomalies: Vec<SpanAnomaly>,
+}
+
+impl<T> Default for SpanTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SpanTree<T> {
+    pub fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            latest: HashMap::new(),
+            stacks: HashMap::new(),
+            contexts: HashMap::new(),
+            pending_removal: Vec::new(),
+            anomalies: Vec::new(),
+        }
+    }
+
+    /// Record a new span with ID `id` under `parent`, with data created by
+    /// `make` from the span's key and its parent's data. Returns the key.
+    pub fn create(
+        &mut self,
+        id: proto::SpanId,
+        parent: &proto::Parent,
+        make: impl FnOnce(SpanKey, Option<&T>) -> T,
+    ) -> SpanKey {
+        self.flush_removals();
+
+        let key = match self.latest.get(&id) {
+            Some(&old) => {
+                if self.nodes.get(&old).is_some_and(|n| !n.closed) {
+                    self.anomalies.push(SpanAnomaly::ReusedOpen { old });
+                    self.close_key(old);
+                    self.flush_removals();
+                }
+                SpanKey {
+                    id,
+                    generation: old.generation + 1,
+                }
+            }
+            None => SpanKey { id, generation: 0 },
+        };
+        self.latest.insert(id, key);
+
+        let parent_key = match *parent {
+            proto::Parent::Root => None,
+            proto::Parent::Current(processor) => self.current(processor),
+            proto::Parent::Explicit(parent_id) => {
+                let open = self
+                    .latest
+                    .get(&parent_id)
+                    .copied()
+                    .filter(|k| self.nodes.get(k).is_some_and(|n| !n.closed));
+                if open.is_none() {
+                    self.anomalies.push(SpanAnomaly::Orphaned {
+                        span: key,
+                        parent: parent_id,
+                    });
+                }
+                open
+            }
+        };
+        let parent = parent_key.and_then(|k| self.nodes.get_mut(&k));
+        let data = make(key, parent.as_ref().map(|p| &p.data));
+        if let Some(parent) = parent {
+            parent.children += 1;
+        }
+
+        self.nodes.insert(
+            key,
+            Node {
+                data,
+                parent: parent_key,
+                children: 0,
+                closed: false,
+            },
+        );
+        key
+    }
+
+    /// Resolve the parent of an event
+    pub fn resolve(&mut self, parent: &proto::Parent) -> Option<SpanKey> {
+        match *parent {
+            proto::Parent::Root => None,
+            proto::Parent::Current(processor) => self.current(processor),
+            proto::Parent::Explicit(id) => self.lookup(id),
+        }
+    }
+
+    /// Record that span `id` was entered on `processor`, returning its key if
+    /// it's open
+    pub fn enter(&mut self, id: proto::SpanId, processor: proto::ProcessorId) -> Option<SpanKey> {
+        let key = self.lookup(id);
+        // Unknown spans are still pushed, so that the matching exit balances the stack
+        let entry = key.unwrap_or_else(|| {
+            let generation = self.latest.get(&id).map_or(0, |k| k.generation);
+            SpanKey { id, generation }
+        });
+        self.stacks
+            .entry(self.stack(processor))
+            .or_default()
+            .push(entry);
+        key
+    }
+
+    /// Record that span `id` was exited on `processor`, returning its key if
+    /// it was the current span
+    pub fn exit(&mut self, id: proto::SpanId, processor: proto::ProcessorId) -> Option<SpanKey> {
+        let stack = self.stacks.entry(self.stack(processor)).or_default();
+        match stack.last() {
+            Some(current) if current.id == id => stack.pop(),
+            current => {
+                let current = current.copied();
+                // Unwind to the exited span if it's further down the stack, so a lost exit
+                // doesn't throw off everything after it
+                if let Some(pos) = stack.iter().rposition(|k| k.id == id) {
+                    stack.truncate(pos);
+                }
+                self.anomalies.push(SpanAnomaly::UnbalancedExit {
+                    id,
+                    processor,
+                    current,
+                });
+                None
+            }
+        }
+    }
+
+    /// Record that span `id` closed, returning it. The span stays accessible
+    /// until the next change to the tree.
+    pub fn close(&mut self, id: proto::SpanId) -> Option<SpanKey> {
+        self.flush_removals();
+        let key = self.lookup(id)?;
+        self.close_key(key);
+        Some(key)
+    }
+
+    /// The span entered most recently on `processor`, if it's still in the
+    /// tree
+    pub fn current(&mut self, processor: proto::ProcessorId) -> Option<SpanKey> {
+        let key = *self.stacks.get(&self.stack(processor))?.last()?;
+        match self.nodes.get(&key) {
+            Some(node) if !node.closed => Some(key),
+            Some(_) => {
+                self.anomalies.push(SpanAnomaly::Stale { span: key });
+                Some(key)
+            }
+            None => {
+                self.anomalies.push(SpanAnomaly::Stale { span: key });
+                None
+            }
+        }
+    }
+
+    /// Record that `processor` switched to `context`, or back to its own
+    /// stack if `context` is 0
+    pub fn switch_context(&mut self, processor: proto::ProcessorId, context: proto::ContextId) {
+        // Contexts aren't reused, so there's no point keeping empty stacks around
+        let previous = self.stack(processor);
+        if let Stack::Context(_) = previous {
+            if self.stacks.get(&previous).is_some_and(Vec::is_empty) {
+                self.stacks.remove(&previous);
+            }
+        }
+
+        if context == 0 {
+            self.contexts.remove(&processor);
+        } else {
+            self.contexts.insert(processor, context);
+        }
+    }
+
+    pub fn get(&self, key: SpanKey) -> Option<&T> {
+        self.nodes.get(&key).map(|n| &n.data)
+    }
+
+    pub fn parent(&self, key: SpanKey) -> Option<SpanKey> {
+        self.nodes.get(&key)?.parent
+    }
+
+    /// Number of ancestors `key` has in the tree
+    pub fn depth(&self, key: SpanKey) -> usize {
+        self.ancestors(key).count().saturating_sub(1)
+    }
+
+    /// `key` and its ancestors, innermost first
+    pub fn ancestors(&self, key: SpanKey) -> impl Iterator<Item = (SpanKey, &T)> + '_ {
+        let mut current = Some(key);
+        std::iter::from_fn(move || {
+            let key = current?;
+            let node = self.nodes.get(&key)?;
+            current = node.parent;
+            Some((key, &node.data))
+        })
+    }
+
+    /// Take the anomalies found since the last call
+    pub fn take_anomalies(&mut self) -> Vec<SpanAnomaly> {
+        std::mem::take(&mut self.anomalies)
+    }
+
+    /// The span stack `processor` is currently using
+    fn stack(&self, processor: proto::ProcessorId) -> Stack {
+        match self.contexts.get(&processor) {
+            Some(&context) => Stack::Context(context),
+            None => Stack::Processor(processor),
+        }
+    }
+
+    /// The open incarnation of `id`, recording an anomaly if there isn't one
+    fn lookup(&mut self, id: proto::SpanId) -> Option<SpanKey> {
+        let Some(&key) = self.latest.get(&id) else {
+            self.anomalies.push(SpanAnomaly::Unknown { id });
+            return None;
+        };
+        match self.nodes.get(&key) {
+            Some(node) if !node.closed => Some(key),
+            _ => {
+                self.anomalies.push(SpanAnomaly::Stale { span: key });
+                None
+            }
+        }
+    }
+
+    fn close_key(&mut self, key: SpanKey) {
+        if let Some(node) = self.nodes.get_mut(&key) {
+            node.closed = true;
+            if node.children == 0 {
+                self.pending_removal.push(key);
+            }
+        }
+    }
+
+    /// Remove closed spans without children, and any closed ancestors left
+    /// without children as a result
+    fn flush_removals(&mut self) {
+        while let Some(key) = self.pending_removal.pop() {
+            let Some(node) = self.nodes.remove(&key) else {
+                continue;
+            };
+            if let Some(parent_key) = node.parent {
+                if let Some(parent) = self.nodes.get_mut(&parent_key) {
+                    parent.children -= 1;
+                    if parent.closed && parent.children == 0 {
+                        self.pending_removal.push(parent_key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reused_id_keeps_ancestry() {
+        let mut tree = SpanTree::new();
+        let outer = tree.create(1, &proto::Parent::Root, |_, _| "outer");
+        tree.enter(1, 0);
+        let inner = tree.create(2, &proto::Parent::Current(0), |_, parent| {
+            assert_eq!(parent, Some(&"outer"));
+            "inner"
+        });
+        assert_eq!(tree.parent(inner), Some(outer));
+        tree.exit(1, 0);
+
+        // The outer span closes while the inner one is still open, and its ID is reused
+        tree.close(1);
+        let reused = tree.create(1, &proto::Parent::Root, |_, _| "reused");
+        assert_eq!(reused.generation, 1);
+
+        let names: Vec<_> = tree.ancestors(inner).map(|(_, name)| *name).collect();
+        assert_eq!(names, ["inner", "outer"]);
+        assert!(tree.take_anomalies().is_empty());
+
+        // Once the inner span closes, the outer one is removed
+        tree.close(2);
+        tree.create(3, &proto::Parent::Root, |_, _| "other");
+        assert_eq!(tree.get(outer), None);
+    }
+
+    #[test]
+    fn test_events_in_stale_span() {
+        let mut tree = SpanTree::new();
+        tree.create(1, &proto::Parent::Root, |_, _| ());
+        tree.enter(1, 0);
+        let key = tree.create(2, &proto::Parent::Current(0), |_, _| ());
+        tree.close(1);
+        tree.close(2);
+        tree.create(1, &proto::Parent::Root, |_, _| ());
+
+        // The processor is still in the closed span, not the new span with its ID
+        assert_eq!(tree.resolve(&proto::Parent::Current(0)), None);
+        assert_eq!(
+            tree.take_anomalies(),
+            [SpanAnomaly::Stale {
+                span: SpanKey {
+                    id: 1,
+                    generation: 0
+                }
+            }]
+        );
+        assert_eq!(tree.resolve(&proto::Parent::Explicit(2)), None);
+        assert_eq!(tree.take_anomalies(), [SpanAnomaly::Stale { span: key }]);
+    }
+
+    #[test]
+    fn test_span_follows_context() {
+        let mut tree = SpanTree::new();
+        let task = tree.create(1, &proto::Parent::Root, |_, _| ());
+        tree.switch_context(0, 7);
+        tree.enter(1, 0);
+        tree.switch_context(0, 0);
+
+        // The processor's own stack doesn't include the task's span
+        assert_eq!(tree.resolve(&proto::Parent::Current(0)), None);
+
+        // The task resumes on another processor, still in its span
+        tree.switch_context(1, 7);
+        assert_eq!(tree.resolve(&proto::Parent::Current(1)), Some(task));
+        assert_eq!(tree.exit(1, 1), Some(task));
+        assert!(tree.take_anomalies().is_empty());
+    }
+
+    #[test]
+    fn test_reused_while_open() {
+        let mut tree = SpanTree::new();
+        let old = tree.create(1, &proto::Parent::Root, |_, _| ());
+        tree.create(1, &proto::Parent::Root, |_, _| ());
+        assert_eq!(tree.take_anomalies(), [SpanAnomaly::ReusedOpen { old }]);
+
+        tree.create(2, &proto::Parent::Explicit(7), |_, _| ());
+        tree.exit(2, 0);
+        assert!(matches!(
+            tree.take_anomalies()[..],
+            [
+                SpanAnomaly::Orphaned { parent: 7, .. },
+                SpanAnomaly::UnbalancedExit {
+                    id: 2,
+                    current: None,
+                    ..
+                }
+            ]
+        ));
+    }
+}
@@ -0,0 +1,617 @@
+//! Aggregate statistics over a stream of ktrace messages.
+//!
+//! Most ktrace events don't carry timestamps, so most of these are counts.
+//! [`Stats::write_folded`] weights each span stack by the number of events
+//! emitted inside it, which is useful for finding the noisiest code paths.
+//!
+//! Span transitions and context switches are timestamped, though, so
+//! [`Stats::write_time_folded`] weights each span stack by how long processors
+//! spent in it instead. The scheduler's context switch and thread state events
+//! carry their own timestamps too, so per-thread CPU time and scheduling
+//! latency can be worked out from them. Timestamps are clock ticks, which are
+//! converted with the kernel's clock calibration.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use platypos_ktrace_proto as proto;
+
+use crate::topology::ProcessorMap;
+
+/// Collects statistics from decoded ktrace messages
+#[derive(Debug, Default)]
+pub struct Stats {
+    messages: u64,
+    events_by_level: BTreeMap<String, u64>,
+    events_by_target: BTreeMap<String, u64>,
+    /// Events from task code and interrupt handlers
+    events_by_context: BTreeMap<proto::ExecutionContext, u64>,
+    /// Contextual events, by the processor they were recorded on
+    events_by_processor: BTreeMap<proto::ProcessorId, u64>,
+    processors: ProcessorMap,
+    spans_by_name: BTreeMap<String, SpanStats>,
+    /// Names of spans that haven't been closed yet
+    span_names: HashMap<proto::SpanId, String>,
+    /// Span stacks, by processor or by the context it's switched to
+    span_stacks: HashMap<(proto::ProcessorId, proto::ContextId), Vec<proto::SpanId>>,
+    contexts: HashMap<proto::ProcessorId, proto::ContextId>,
+    /// Number of events emitted in each span stack, keyed by the stack in
+    /// folded (`outer;inner`) form
+    folded: BTreeMap<String, u64>,
+    /// Clock ticks spent in each span stack, keyed like `folded`
+    folded_ticks: BTreeMap<String, u64>,
+    /// Timestamp of each processor's last span transition or context switch
+    last_transition: HashMap<proto::ProcessorId, u64>,
+    /// Number of spans and events dropped by rate limiting, by callsite name
+    suppressed: BTreeMap<String, u64>,
+    sched: SchedStats,
+}
+
+#[derive(Debug, Default)]
+struct SpanStats {
+    created: u64,
+    entered: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn receive(&mut self, message: &proto::ReceiverMessage) {
+        self.messages += 1;
+        match message {
+            proto::Message::SpanCreated(span) => {
+                self.spans_by_name
+                    .entry(span.metadata.name.to_string())
+                    .or_default()
+                    .created += 1;
+                self.span_names
+                    .insert(span.id, span.metadata.name.to_string());
+            }
+            proto::Message::Event(event) => {
+                *self
+                    .events_by_level
+                    .entry(format!("{:?}", event.metadata.level))
+                    .or_default() += 1;
+                *self
+                    .events_by_target
+                    .entry(event.metadata.target.to_string())
+                    .or_default() += 1;
+                *self.events_by_context.entry(event.context).or_default() += 1;
+
+                let stack = match event.span_id {
+                    proto::Parent::Current(processor) => self.folded_stack(processor),
+                    proto::Parent::Explicit(id) => self.span_name(id).to_string(),
+                    proto::Parent::Root => String::new(),
+                };
+                let stack = if stack.is_empty() {
+                    "<root>".to_string()
+                } else {
+                    stack
+                };
+                *self.folded.entry(stack).or_default() += 1;
+
+                let processor = match event.span_id {
+                    proto::Parent::Current(processor) => {
+                        *self.events_by_processor.entry(processor).or_default() += 1;
+                        processor
+                    }
+                    _ => 0,
+                };
+                self.sched.receive(processor, event);
+            }
+            proto::Message::SpanEntered {
+                id,
+                processor,
+                ticks,
+            } => {
+                if let Some(name) = self.span_names.get(id) {
+                    if let Some(stats) = self.spans_by_name.get_mut(name) {
+                        stats.entered += 1;
+                    }
+                }
+                self.charge_time(*processor, *ticks);
+                let stack = self.stack_key(*processor);
+                self.span_stacks.entry(stack).or_default().push(*id);
+            }
+            proto::Message::SpanExited {
+                id,
+                processor,
+                ticks,
+            } => {
+                self.charge_time(*processor, *ticks);
+                let stack = self
+                    .span_stacks
+                    .entry(self.stack_key(*processor))
+                    .or_default();
+                if let Some(pos) = stack.iter().rposition(|s| s == id) {
+                    stack.truncate(pos);
+                }
+            }
+            proto::Message::SpanClosed { id } => {
+                self.span_names.remove(id);
+            }
+            proto::Message::Schema(_) => {}
+            proto::Message::Suppressed { metadata, count } => {
+                *self
+                    .suppressed
+                    .entry(metadata.name.to_string())
+                    .or_default() += count;
+            }
+            proto::Message::ContextSwitched {
+                processor,
+                context,
+                ticks,
+            } => {
+                self.charge_time(*processor, *ticks);
+                self.contexts.insert(*processor, *context);
+            }
+            proto::Message::Processor {
+                processor,
+                location,
+            } => {
+                self.processors.insert(*processor, *location);
+            }
+            proto::Message::Calibration { ticks_per_second } => {
+                self.sched.ticks_per_second = Some(*ticks_per_second).filter(|&t| t != 0);
+            }
+            // The decoder resolves interned metadata
+            proto::Message::MetadataDefined { .. }
+            | proto::Message::InternedSpanCreated(_)
+            | proto::Message::InternedEvent(_) => {}
+            // Crashes are rendered rather than counted
+            proto::Message::Crash(_)
+            | proto::Message::PreviousCrash(_)
+            | proto::Message::PreviousTrace(_) => {}
+            // Samples are aggregated by a `Profile` instead
+            proto::Message::ProfileSample { .. } => {}
+            proto::Message::ProfileSamplesDropped { .. } => {}
+        }
+    }
+
+    fn span_name(&self, id: proto::SpanId) -> &str {
+        self.span_names
+            .get(&id)
+            .map(String::as_str)
+            .unwrap_or("<unknown>")
+    }
+
+    /// Key for the span stack `processor` is using. Tasks' stacks aren't tied
+    /// to a processor, but processors' own stacks are under context 0.
+    fn stack_key(&self, processor: proto::ProcessorId) -> (proto::ProcessorId, proto::ContextId) {
+        match self.contexts.get(&processor) {
+            Some(&context) if context != 0 => (0, context),
+            _ => (processor, 0),
+        }
+    }
+
+    /// Charge the time since `processor`'s last transition to the span stack
+    /// it's been in since then. Time outside any span isn't counted, and
+    /// neither is time before the kernel's clock was set up, when timestamps
+    /// are 0.
+    fn charge_time(&mut self, processor: proto::ProcessorId, ticks: u64) {
+        let last = self.last_transition.insert(processor, ticks);
+        let Some(last) = last.filter(|&last| last != 0 && ticks != 0) else {
+            return;
+        };
+        let stack = self.folded_stack(processor);
+        if !stack.is_empty() {
+            *self.folded_ticks.entry(stack).or_default() += ticks.saturating_sub(last);
+        }
+    }
+
+    /// Current span stack on `processor`, in folded form
+    fn folded_stack(&self, processor: proto::ProcessorId) -> String {
+        let Some(stack) = self.span_stacks.get(&self.stack_key(processor)) else {
+            return String::new();
+        };
+        let names: Vec<&str> = stack.iter().map(|id| self.span_name(*id)).collect();
+        names.join(";")
+    }
+
+    /// Write a human-readable summary
+    pub fn write_summary<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "Messages: {}", self.messages)?;
+
+        writeln!(w, "\nEvents by level:")?;
+        for (level, count) in self.events_by_level.iter() {
+            writeln!(w, "  {level:<8} {count}")?;
+        }
+
+        writeln!(w, "\nEvents by context:")?;
+        for (context, count) in self.events_by_context.iter() {
+            writeln!(w, "  {:<8} {count}", context.to_string())?;
+        }
+
+        writeln!(w, "\nEvents by target:")?;
+        for (target, count) in self.events_by_target.iter() {
+            writeln!(w, "  {target:<40} {count}")?;
+        }
+
+        if !self.events_by_processor.is_empty() {
+            writeln!(w, "\nEvents by core:")?;
+            for (core, count) in self.events_by_core() {
+                writeln!(w, "  {core:<40} {count}")?;
+            }
+        }
+
+        writeln!(w, "\nSpans (created / entered):")?;
+        for (name, stats) in self.spans_by_name.iter() {
+            writeln!(w, "  {name:<40} {} / {}", stats.created, stats.entered)?;
+        }
+
+        if !self.suppressed.is_empty() {
+            writeln!(w, "\nSuppressed by rate limiting:")?;
+            for (name, count) in self.suppressed.iter() {
+                writeln!(w, "  {name:<40} {count}")?;
+            }
+        }
+
+        self.sched.write_summary(&mut w)
+    }
+
+    /// Contextual event counts, adding up hardware threads of the same core.
+    /// Processors the kernel didn't announce are counted on their own.
+    fn events_by_core(&self) -> Vec<(String, u64)> {
+        let mut cores: BTreeMap<(u32, u32, proto::ProcessorId), (Vec<proto::ProcessorId>, u64)> =
+            BTreeMap::new();
+        for (&processor, &count) in self.events_by_processor.iter() {
+            let key = match self.processors.location(processor) {
+                Some(location) => (location.package, location.core, 0),
+                None => (u32::MAX, u32::MAX, processor),
+            };
+            let entry = cores.entry(key).or_default();
+            entry.0.push(processor);
+            entry.1 += count;
+        }
+
+        cores
+            .into_iter()
+            .map(|((package, core, _), (processors, count))| {
+                let processors: Vec<String> = processors.iter().map(|p| p.to_string()).collect();
+                let name = if package == u32::MAX {
+                    format!("CPU {}", processors.join(", "))
+                } else {
+                    format!("pkg {package} core {core} (CPU {})", processors.join(", "))
+                };
+                (name, count)
+            })
+            .collect()
+    }
+
+    /// Write the event counts per span stack in the folded format used by
+    /// flamegraph tools (one `outer;inner count` line per stack)
+    pub fn write_folded<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for (stack, count) in self.folded.iter() {
+            writeln!(w, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+
+    /// Whether [`Stats::write_time_folded`] has anything to write: some time
+    /// was spent in spans, and the kernel sent its clock calibration
+    pub fn has_span_time(&self) -> bool {
+        self.sched.ticks_per_second.is_some() && self.folded_ticks.values().any(|&t| t > 0)
+    }
+
+    /// Write the time spent in each span stack, in microseconds, in the folded
+    /// format used by flamegraph tools. This writes nothing if the kernel
+    /// never sent its clock calibration.
+    pub fn write_time_folded<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let Some(ticks_per_second) = self.sched.ticks_per_second else {
+            return Ok(());
+        };
+        for (stack, &ticks) in self.folded_ticks.iter() {
+            let micros = u128::from(ticks) * 1_000_000 / u128::from(ticks_per_second);
+            if micros > 0 {
+                writeln!(w, "{stack} {micros}")?;
+            }
+        }
+        Ok(())
+    }
+}
+/// Number of buckets in a [`Histogram`]
+const HISTOGRAM_BUCKETS: usize = 24;
+
+/// Power-of-2 histogram of durations in microseconds. Bucket `i` counts
+/// durations below `2^i` microseconds (and at least `2^(i-1)`), and the last
+/// bucket also counts anything longer.
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    total: u64,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, micros: u64) {
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+        self.total += micros;
+        self.count += 1;
+        self.max = self.max.max(micros);
+    }
+
+    fn mean(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+
+    fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let Some(last) = self.buckets.iter().rposition(|&n| n > 0) else {
+            return Ok(());
+        };
+        let first = self.buckets.iter().position(|&n| n > 0).unwrap_or(0);
+        for (i, count) in self.buckets.iter().enumerate().take(last + 1).skip(first) {
+            let limit = 1u64 << i;
+            let bar = "#".repeat((count * 40).div_ceil(self.count) as usize);
+            if i == HISTOGRAM_BUCKETS - 1 {
+                writeln!(w, "  >= {:>9}us {count:>8} {bar}", limit >> 1)?;
+            } else {
+                writeln!(w, "  <  {limit:>9}us {count:>8} {bar}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scheduler statistics, from context switch and thread state events.
+/// Threads are identified by their ktrace context.
+#[derive(Debug, Default)]
+struct SchedStats {
+    /// Thread running on each processor, and when it started
+    running: HashMap<proto::ProcessorId, (proto::ContextId, u64)>,
+    /// When each runnable thread became runnable
+    runnable_since: HashMap<proto::ContextId, u64>,
+    threads: BTreeMap<proto::ContextId, ThreadStats>,
+    latency: Histogram,
+    switches: u64,
+    max_depth: u64,
+    /// Rate of the clock behind `ticks` timestamps, once the kernel sends it
+    ticks_per_second: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct ThreadStats {
+    cpu_us: u64,
+    runs: u64,
+    latency: Histogram,
+    exited: bool,
+}
+
+impl SchedStats {
+    fn receive(
+        &mut self,
+        processor: proto::ProcessorId,
+        event: &proto::Event<proto::DeserializedFields<'_>, proto::Metadata<'_>>,
+    ) {
+        let name = event.metadata.name;
+        if name != proto::CONTEXT_SWITCH_EVENT && name != proto::THREAD_STATE_EVENT {
+            return;
+        }
+
+        let mut numbers = HashMap::new();
+        let mut strings = HashMap::new();
+        for (field, value) in event.fields.iter() {
+            match value {
+                proto::Value::U64(n) => {
+                    numbers.insert(*field, *n);
+                }
+                proto::Value::String(s) => {
+                    strings.insert(*field, *s);
+                }
+                _ => {}
+            }
+        }
+        let Some(time) = self.timestamp_us(&numbers) else {
+            return;
+        };
+
+        if name == proto::CONTEXT_SWITCH_EVENT {
+            if let Some(&next) = numbers.get("next") {
+                let depth = numbers.get("depth").copied().unwrap_or(0);
+                self.switch(processor, next, depth, time);
+            }
+        } else if let (Some(&thread), Some(&state)) = (numbers.get("thread"), strings.get("state"))
+        {
+            self.state(thread, state, time);
+        }
+    }
+
+    /// An event's timestamp in microseconds, if it has one that can be
+    /// converted
+    fn timestamp_us(&self, numbers: &HashMap<&str, u64>) -> Option<u64> {
+        if let Some(&time) = numbers.get("time_us") {
+            return Some(time);
+        }
+        let ticks = *numbers.get("ticks")?;
+        let ticks_per_second = self.ticks_per_second?;
+        Some((u128::from(ticks) * 1_000_000 / u128::from(ticks_per_second)) as u64)
+    }
+
+    /// Record that `processor` switched to thread `next` at `time`, with
+    /// `depth` threads left waiting. The thread it switched from is the one
+    /// the last switch on `processor` started, which is more reliable than
+    /// the event's `prev` field if the decoder missed some events.
+    fn switch(
+        &mut self,
+        processor: proto::ProcessorId,
+        next: proto::ContextId,
+        depth: u64,
+        time: u64,
+    ) {
+        self.switches += 1;
+        self.max_depth = self.max_depth.max(depth);
+
+        if let Some((running, since)) = self.running.remove(&processor) {
+            let thread = self.threads.entry(running).or_default();
+            thread.cpu_us += time.saturating_sub(since);
+        }
+
+        if next != 0 {
+            self.running.insert(processor, (next, time));
+            let thread = self.threads.entry(next).or_default();
+            thread.runs += 1;
+            if let Some(runnable) = self.runnable_since.remove(&next) {
+                let latency = time.saturating_sub(runnable);
+                thread.latency.record(latency);
+                self.latency.record(latency);
+            }
+        }
+    }
+
+    /// Record that `thread` changed to `state` at `time`
+    fn state(&mut self, thread: proto::ContextId, state: &str, time: u64) {
+        match state {
+            "runnable" => {
+                self.runnable_since.entry(thread).or_insert(time);
+            }
+            "exited" => {
+                self.runnable_since.remove(&thread);
+                self.threads.entry(thread).or_default().exited = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn write_summary<W: Write>(&self, mut w: W) -> io::Result<()> {
+        if self.switches == 0 {
+            return Ok(());
+        }
+
+        writeln!(
+            w,
+            "\nScheduler: {} context switches, max run queue depth {}",
+            self.switches, self.max_depth
+        )?;
+        writeln!(
+            w,
+            "  {:<10} {:>12} {:>8} {:>12} {:>12}",
+            "thread", "cpu time", "runs", "mean wait", "max wait"
+        )?;
+        for (context, thread) in self.threads.iter() {
+            let name = if thread.exited {
+                format!("{context} (exited)")
+            } else {
+                context.to_string()
+            };
+            writeln!(
+                w,
+                "  {name:<10} {:>10}us {:>8} {:>10}us {:>10}us",
+                thread.cpu_us,
+                thread.runs,
+                thread.latency.mean(),
+                thread.latency.max
+            )?;
+        }
+
+        writeln!(w, "\nScheduling latency:")?;
+        self.latency.write(&mut w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_time_and_latency() {
+        let mut sched = SchedStats::default();
+        sched.state(1, "runnable", 100);
+        sched.state(2, "runnable", 100);
+        sched.switch(0, 1, 1, 150);
+        sched.state(1, "blocked", 400);
+        sched.switch(0, 2, 0, 400);
+        sched.state(1, "runnable", 500);
+        sched.switch(0, 0, 0, 1000);
+        sched.switch(0, 1, 0, 1100);
+        sched.state(1, "exited", 1200);
+        sched.switch(0, 0, 0, 1200);
+
+        let one = &sched.threads[&1];
+        assert_eq!((one.cpu_us, one.runs, one.latency.max), (350, 2, 600));
+        assert!(one.exited);
+        let two = &sched.threads[&2];
+        assert_eq!((two.cpu_us, two.latency.max), (600, 300));
+        assert_eq!(sched.latency.count, 3);
+        assert_eq!(sched.max_depth, 1);
+    }
+
+    #[test]
+    fn test_tick_timestamps() {
+        let mut sched = SchedStats::default();
+        let ticks = HashMap::from([("ticks", 5_000_000)]);
+        assert_eq!(sched.timestamp_us(&ticks), None);
+        sched.ticks_per_second = Some(2_000_000_000);
+        assert_eq!(sched.timestamp_us(&ticks), Some(2_500));
+        assert_eq!(
+            sched.timestamp_us(&HashMap::from([("time_us", 7)])),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn test_time_folded() {
+        let mut stats = Stats::new();
+        stats
+            .span_names
+            .extend([(1, "outer".to_string()), (2, "inner".to_string())]);
+        let messages: [proto::ReceiverMessage; 5] = [
+            proto::Message::Calibration {
+                ticks_per_second: 1_000_000,
+            },
+            proto::Message::SpanEntered {
+                id: 1,
+                processor: 0,
+                ticks: 100,
+            },
+            proto::Message::SpanEntered {
+                id: 2,
+                processor: 0,
+                ticks: 150,
+            },
+            proto::Message::SpanExited {
+                id: 2,
+                processor: 0,
+                ticks: 400,
+            },
+            proto::Message::SpanExited {
+                id: 1,
+                processor: 0,
+                ticks: 500,
+            },
+        ];
+        for message in &messages {
+            stats.receive(message);
+        }
+
+        let mut folded = Vec::new();
+        stats.write_time_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "outer 150\nouter;inner 250\n"
+        );
+    }
+
+    #[test]
+    fn test_events_by_core() {
+        let mut stats = Stats::new();
+        for (processor, thread) in [(0, 0), (1, 1)] {
+            let location = proto::ProcessorLocation {
+                package: 0,
+                core: 0,
+                thread,
+            };
+            stats.processors.insert(processor, location);
+        }
+        stats.events_by_processor.extend([(0, 3), (1, 4), (5, 1)]);
+        assert_eq!(
+            stats.events_by_core(),
+            [
+                ("pkg 0 core 0 (CPU 0, 1)".to_string(), 7),
+                ("CPU 5".to_string(), 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Collects kernel test results, including from runs split across several VMs.
+//!
+//! The ktest runner reports each test's outcome with an event under
+//! [`TEST_RESULT_TARGET`](proto::TEST_RESULT_TARGET). A [`TestReport`] merges
+//! the results from every shard of a run into one summary, and also flags
+//! shards that failed without a failing test, which usually means the kernel
+//! crashed or hung partway through.
+
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+/// One test's outcome
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+}
+
+impl TestResult {
+    /// Extract a test result from `message`, if it is one
+    pub fn from_message(message: &proto::ReceiverMessage) -> Option<Self> {
+        let proto::Message::Event(event) = message else {
+            return None;
+        };
+        if event.metadata.target != proto::TEST_RESULT_TARGET {
+            return None;
+        }
+
+        let mut name = None;
+        let mut outcome = None;
+        for (field, value) in event.fields.iter() {
+            match (*field, value) {
+                ("test", proto::Value::String(s)) => name = Some(s.to_string()),
+                ("outcome", proto::Value::String(s)) => outcome = Some(*s),
+                _ => {}
+            }
+        }
+        Some(TestResult {
+            name: name?,
+            passed: outcome? == "pass",
+        })
+    }
+}
+
+/// Results from one VM's share of a test run
+#[derive(Debug, Clone, Default)]
+pub struct ShardResults {
+    pub results: Vec<TestResult>,
+    /// Whether the VM exited reporting that every test passed
+    pub succeeded: bool,
+}
+
+/// Combined results from every shard of a test run
+#[derive(Debug, Clone, Default)]
+pub struct TestReport {
+    pub shards: Vec<ShardResults>,
+}
+
+impl TestReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the next shard's results
+    pub fn add_shard(&mut self, results: Vec<TestResult>, succeeded: bool) {
+        self.shards.push(ShardResults { results, succeeded });
+    }
+
+    fn results(&self) -> impl Iterator<Item = (usize, &TestResult)> {
+        self.shards
+            .iter()
+            .enumerate()
+            .flat_map(|(i, shard)| shard.results.iter().map(move |r| (i, r)))
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results().filter(|(_, r)| r.passed).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results().filter(|(_, r)| !r.passed).count()
+    }
+
+    /// Shards that failed without reporting a failing test
+    pub fn incomplete(&self) -> impl Iterator<Item = (usize, &ShardResults)> {
+        self.shards
+            .iter()
+            .enumerate()
+            .filter(|(_, shard)| !shard.succeeded && shard.results.iter().all(|r| r.passed))
+    }
+
+    /// Whether every shard ran all of its tests successfully
+    pub fn succeeded(&self) -> bool {
+        self.shards.iter().all(|shard| shard.succeeded) && self.failed() == 0
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.shards.len();
+        writeln!(
+            f,
+            "{} passed and {} failed across {} shards",
+            self.passed(),
+            self.failed(),
+            count
+        )?;
+        for (shard, result) in self.results().filter(|(_, r)| !r.passed) {
+            writeln!(f, "  FAIL {} (shard {shard}/{count})", result.name)?;
+        }
+        for (shard, results) in self.incomplete() {
+            writeln!(
+                f,
+                "  shard {shard}/{count} stopped after {} tests without a failure",
+                results.results.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(name: &str, passed: bool) -> TestResult {
+        TestResult {
+            name: name.to_string(),
+            passed,
+        }
+    }
+
+    #[test]
+    fn test_merge_shards() {
+        let mut report = TestReport::new();
+        report.add_shard(vec![result("a::one", true), result("a::two", false)], false);
+        report.add_shard(vec![result("b::one", true)], true);
+        report.add_shard(vec![result("c::one", true)], false);
+
+        assert_eq!((report.passed(), report.failed()), (3, 1));
+        assert!(!report.succeeded());
+        assert_eq!(
+            report.to_string(),
+            "3 passed and 1 failed across 3 shards\n  FAIL a::two (shard 0/3)\n  shard 2/3 \
+             stopped after 1 tests without a failure\n"
+        );
+    }
+
+    #[test]
+    fn test_all_passed() {
+        let mut report = TestReport::new();
+        report.add_shard(vec![result("a::one", true)], true);
+        report.add_shard(Vec::new(), true);
+        assert!(report.succeeded());
+        assert_eq!(report.incomplete().count(), 0);
+    }
+}
@@ -0,0 +1,177 @@
+//! Colors and styling shared by trace output and the host tools.
+//!
+//! [`Theme`] maps trace levels and kinds of values to styles, and [`Paint`]
+//! applies a style according to a [`ColorMode`]. Tools call
+//! [`ColorMode::set_global`] once at startup, which makes `Auto` output follow
+//! their `--color` flag, as well as `NO_COLOR` and whether stdout is a
+//! terminal.
+//!
+//! Escape codes count towards `{:width$}` padding, so styled values are padded
+//! with [`Padded`] before they're painted.
+
+use std::fmt::{self, Display};
+
+use owo_colors::{OwoColorize, Stream, Style};
+use platypos_ktrace_proto as proto;
+
+/// When to use colors in output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    /// Use colors if stdout supports them, `NO_COLOR` isn't set, and they
+    /// haven't been turned off globally
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Decide what `Auto` means for the whole process. `Auto` leaves it to
+    /// the terminal and `NO_COLOR`.
+    pub fn set_global(self) {
+        match self {
+            ColorMode::Auto => owo_colors::unset_override(),
+            ColorMode::Always => owo_colors::set_override(true),
+            ColorMode::Never => owo_colors::set_override(false),
+        }
+    }
+}
+
+/// Styles for each kind of output
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    pub error: Style,
+    pub warn: Style,
+    pub info: Style,
+    pub debug: Style,
+    pub trace: Style,
+    pub field_name: Style,
+    /// Declared field types, in verbose output
+    pub field_type: Style,
+    pub physical_address: Style,
+    pub virtual_address: Style,
+    /// Tag on spans and events from interrupt handlers
+    pub irq: Style,
+    /// Tag on spans and events from NMI handlers
+    pub nmi: Style,
+}
+
+impl Theme {
+    pub fn level(&self, level: proto::Level) -> Style {
+        match level {
+            proto::Level::Error => self.error,
+            proto::Level::Warn => self.warn,
+            proto::Level::Info => self.info,
+            proto::Level::Debug => self.debug,
+            proto::Level::Trace => self.trace,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            error: Style::new().red(),
+            warn: Style::new().yellow(),
+            info: Style::new().green(),
+            debug: Style::new().blue(),
+            trace: Style::new().dimmed(),
+            field_name: Style::new().bold(),
+            field_type: Style::new().dimmed(),
+            // Different colors, so the two kinds of address can be told apart
+            physical_address: Style::new().magenta(),
+            virtual_address: Style::new().cyan(),
+            irq: Style::new().yellow().bold(),
+            nmi: Style::new().red().bold(),
+        }
+    }
+}
+
+/// A value which is styled if colors are enabled
+pub struct Paint<D> {
+    value: D,
+    style: Style,
+    color: ColorMode,
+}
+
+impl<D: Display> Paint<D> {
+    pub fn new(value: D, style: Style, color: ColorMode) -> Self {
+        Self {
+            value,
+            style,
+            color,
+        }
+    }
+}
+
+impl<D: Display> Display for Paint<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.color {
+            ColorMode::Auto => self
+                .value
+                .if_supports_color(Stream::Stdout, |v| v.style(self.style))
+                .fmt(f),
+            ColorMode::Always => self.value.style(self.style).fmt(f),
+            ColorMode::Never => self.value.fmt(f),
+        }
+    }
+}
+
+/// A value padded with spaces to a minimum width, counted in characters
+pub struct Padded<D> {
+    value: D,
+    width: usize,
+    align_left: bool,
+}
+
+impl<D: Display> Padded<D> {
+    /// Pad on the right, so the value is left-aligned
+    pub fn left(value: D, width: usize) -> Self {
+        Self {
+            value,
+            width,
+            align_left: true,
+        }
+    }
+
+    /// Pad on the left, so the value is right-aligned
+    pub fn right(value: D, width: usize) -> Self {
+        Self {
+            value,
+            width,
+            align_left: false,
+        }
+    }
+}
+
+impl<D: Display> Display for Padded<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.value.to_string();
+        let padding = self.width.saturating_sub(value.chars().count());
+        if !self.align_left {
+            write!(f, "{:padding$}", "")?;
+        }
+        f.write_str(&value)?;
+        if self.align_left {
+            write!(f, "{:padding$}", "")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(Padded::right("WARN", 5).to_string(), " WARN");
+        assert_eq!(Padded::left("é", 3).to_string(), "é  ");
+        assert_eq!(Padded::left("ERROR", 3).to_string(), "ERROR");
+
+        // Padding inside the styling keeps escape codes out of the width
+        let padded = Padded::right("WARN", 5);
+        let painted = Paint::new(padded, Style::new().yellow(), ColorMode::Always);
+        assert!(painted.to_string().contains(" WARN"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Processor topology, from the kernel's
+//! [`Processor`](proto::Message::Processor) messages.
+//!
+//! The kernel identifies processors by small consecutive IDs. Each one is
+//! announced with its package, core, and hardware thread as it comes online,
+//! so output can say which core something ran on, and per-core statistics can
+//! group hardware threads together.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use platypos_ktrace_proto as proto;
+
+/// Locations of every processor the kernel has announced
+#[derive(Debug, Default, Clone)]
+pub struct ProcessorMap {
+    locations: HashMap<proto::ProcessorId, proto::ProcessorLocation>,
+}
+
+impl ProcessorMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, processor: proto::ProcessorId, location: proto::ProcessorLocation) {
+        self.locations.insert(processor, location);
+    }
+
+    pub fn location(&self, processor: proto::ProcessorId) -> Option<proto::ProcessorLocation> {
+        self.locations.get(&processor).copied()
+    }
+
+    /// Label for `processor`, like `CPU 2 (core 1, pkg 0)`
+    pub fn label(&self, processor: proto::ProcessorId) -> ProcessorLabel {
+        ProcessorLabel::new(processor, self.location(processor))
+    }
+
+    /// Number of processors announced
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+}
+
+/// A processor ID, with its location if it's known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessorLabel {
+    processor: proto::ProcessorId,
+    location: Option<proto::ProcessorLocation>,
+}
+
+impl ProcessorLabel {
+    pub fn new(processor: proto::ProcessorId, location: Option<proto::ProcessorLocation>) -> Self {
+        Self {
+            processor,
+            location,
+        }
+    }
+}
+
+impl fmt::Display for ProcessorLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CPU {}", self.processor)?;
+        if let Some(location) = self.location {
+            write!(f, " ({location})")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        let mut map = ProcessorMap::new();
+        map.insert(
+            2,
+            proto::ProcessorLocation {
+                package: 0,
+                core: 1,
+                thread: 0,
+            },
+        );
+        map.insert(
+            3,
+            proto::ProcessorLocation {
+                package: 0,
+                core: 1,
+                thread: 1,
+            },
+        );
+        assert_eq!(map.label(2).to_string(), "CPU 2 (core 1, pkg 0)");
+        assert_eq!(map.label(3).to_string(), "CPU 3 (core 1, pkg 0, thread 1)");
+        assert_eq!(map.label(7).to_string(), "CPU 7");
+    }
+}
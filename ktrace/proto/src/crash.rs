@@ -0,0 +1,134 @@
+//! Crash reports shared by the kernel and loader.
+//!
+//! A crash is reported the same way no matter which boot stage it happened
+//! in, so the decoder can render it and xtask can detect it without knowing
+//! where it came from. Only the kernel sends these today, since the loader is
+//! an external crate, but any stage that writes ktrace framing to the serial
+//! port can.
+
+use core::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Length of a build ID. The kernel is linked with `--build-id=sha1`, so its
+/// build ID is a SHA-1 hash.
+pub const BUILD_ID_LEN: usize = 20;
+
+/// Names of the registers in [`CrashReport::registers`], in order
+pub const CRASH_REGISTERS: [&str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip", "rflags",
+];
+
+/// Why a boot stage stopped, in a format shared by every stage that writes to
+/// the serial port. A stage sends this once, as the last thing it does before
+/// halting, rebooting, or exiting the VM, so the host can tell a crash from a
+/// hang and render crashes in one place no matter where they happened.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrashReport<'a> {
+    pub source: CrashSource,
+    /// The panic message or fault description
+    pub reason: &'a str,
+    /// Where the crash was reported from, if known
+    #[serde(borrow)]
+    pub location: Option<CrashLocation<'a>>,
+    /// Register state when the crash was reported, named by
+    /// [`CRASH_REGISTERS`], or nothing if the stage couldn't capture it
+    #[serde(borrow)]
+    pub registers: Words<'a>,
+    /// Return addresses, innermost first
+    #[serde(borrow)]
+    pub backtrace: Words<'a>,
+    /// Whether the backtrace was cut off
+    pub frames_omitted: bool,
+    /// Build ID of the crashed image, to match the report with the binary
+    /// that symbolizes it
+    pub build_id: Option<[u8; BUILD_ID_LEN]>,
+}
+
+/// Which boot stage crashed
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CrashSource {
+    Loader,
+    Kernel,
+}
+
+/// Source location of a crash
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrashLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// 64-bit values packed as little-endian bytes. Unlike a `[u64; N]`, these
+/// borrow from the message, so crash reports don't make every [`Message`]
+/// bigger.
+///
+/// [`Message`]: crate::Message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Words<'a>(&'a [u8]);
+
+impl<'a> CrashReport<'a> {
+    /// A report with no location, registers, backtrace, or build ID
+    pub const fn new(source: CrashSource, reason: &'a str) -> Self {
+        CrashReport {
+            source,
+            reason,
+            location: None,
+            registers: Words::EMPTY,
+            backtrace: Words::EMPTY,
+            frames_omitted: false,
+            build_id: None,
+        }
+    }
+}
+
+impl<'a> Words<'a> {
+    pub const EMPTY: Words<'static> = Words(&[]);
+
+    /// Pack `words` into `buf`, which must have room for 8 bytes per word
+    pub fn encode(words: impl IntoIterator<Item = u64>, buf: &'a mut [u8]) -> Self {
+        let mut len = 0;
+        for (word, chunk) in words.into_iter().zip(buf.chunks_exact_mut(8)) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+            len += 8;
+        }
+        Words(&buf[..len])
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() / 8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + 'a {
+        self.0
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+    }
+}
+
+impl Serialize for Words<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Words<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <&'de [u8]>::deserialize(deserializer).map(Words)
+    }
+}
+
+impl fmt::Display for CrashSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CrashSource::Loader => "loader",
+            CrashSource::Kernel => "kernel",
+        })
+    }
+}
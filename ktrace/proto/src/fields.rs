@@ -1,10 +1,9 @@
 //! Serializable adapter for trace attributes
 
 use alloc::vec::Vec;
-use core::fmt::{Arguments, Debug, Formatter};
-use phf::phf_map;
+use core::fmt::{self, Arguments, Debug, Formatter, Write as _};
 use serde::de::{Error as _, MapAccess, Visitor};
-use serde::ser::{Error as _, SerializeMap};
+use serde::ser::{Error as _, SerializeMap, SerializeTuple};
 use serde::{Deserialize, Serialize, Serializer};
 use tracing::field::{Field, Visit};
 use tracing::span::Attributes;
@@ -115,7 +114,7 @@ impl<'de: 'a, 'a> Deserialize<'de> for DeserializedFields<'a> {
             {
                 let mut fields = Vec::new();
                 while let Some(field) = map.next_key()? {
-                    if let Some(ty) = TYPES.get(field) {
+                    if let Some(ty) = FieldType::of(field) {
                         let value = ty.read(&mut map)?;
                         fields.push((field, value));
                     } else {
@@ -131,6 +130,14 @@ impl<'de: 'a, 'a> Deserialize<'de> for DeserializedFields<'a> {
     }
 }
 
+/// Maximum number of bytes sent for a [`FieldType::Bytes`] field. Longer
+/// slices are truncated, but their full length is still sent.
+pub const MAX_BYTES: usize = 64;
+
+/// Maximum number of elements sent for a [`FieldType::U64Array`] field.
+/// Longer arrays are truncated, but their full length is still sent.
+pub const MAX_ARRAY_LEN: usize = 16;
+
 /// Dynamic field value, for use on the materialized side
 #[derive(Debug, PartialEq, Eq)]
 pub enum Value<'a> {
@@ -139,34 +146,165 @@ pub enum Value<'a> {
     VirtualAddress(u64),
     String(&'a str),
     U64(u64),
+    ByteSize(u64),
+    /// Up to [`MAX_BYTES`] bytes of a slice that was `len` bytes long
+    Bytes {
+        bytes: &'a [u8],
+        len: u64,
+    },
+    /// Up to [`MAX_ARRAY_LEN`] elements of an array that was `len` elements
+    /// long
+    U64Array {
+        values: Vec<u64>,
+        len: u64,
+    },
+}
+
+impl<'a> Value<'a> {
+    /// The schema type this value was decoded as
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            Value::KernelAddress(_) => FieldType::KernelAddress,
+            Value::PhysicalAddress(_) => FieldType::PhysicalAddress,
+            Value::VirtualAddress(_) => FieldType::VirtualAddress,
+            Value::String(_) => FieldType::String,
+            Value::U64(_) => FieldType::U64,
+            Value::ByteSize(_) => FieldType::ByteSize,
+            Value::Bytes { .. } => FieldType::Bytes,
+            Value::U64Array { .. } => FieldType::U64Array,
+        }
+    }
+}
+
+/// Records a byte slice as a [`FieldType::Bytes`] field, like
+/// `tracing::info!(mac = ?ByteSlice(&mac))`.
+///
+/// `tracing` can only pass slices to the subscriber through their `Debug`
+/// implementation, so this formats the bytes as hex and the subscriber parses
+/// them back.
+pub struct ByteSlice<'a>(pub &'a [u8]);
+
+impl<'a> Debug for ByteSlice<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Records a short array of integers as a [`FieldType::U64Array`] field,
+/// like `tracing::debug!(registers = ?U64Slice(&regs))`. Like [`ByteSlice`],
+/// this goes through `Debug`.
+pub struct U64Slice<'a>(pub &'a [u64]);
+
+impl<'a> Debug for U64Slice<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, value) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(',')?;
+            }
+            write!(f, "{value:x}")?;
+        }
+        Ok(())
+    }
 }
 
 /// Mapping of known fields to their expected types. This forms a dynamic
 /// schema, where any given data point can contain 0 or more known fields.
-static TYPES: phf::Map<&'static str, FieldType> = phf_map! {
-    "at" => FieldType::KernelAddress,
-    "message" => FieldType::String,
-    "count" => FieldType::U64,
-    "size" => FieldType::U64,
-    "vaddr" => FieldType::VirtualAddress,
-    "paddr" => FieldType::PhysicalAddress,
-    "range" => FieldType::String,
-};
-
-#[derive(Clone, Copy, Debug)]
+///
+/// This is a plain array rather than a hash map so that it can be checked at
+/// compile time, by `ktrace::schema!`.
+pub const KNOWN_FIELDS: &[(&str, FieldType)] = &[
+    ("at", FieldType::KernelAddress),
+    ("message", FieldType::String),
+    ("count", FieldType::U64),
+    ("size", FieldType::ByteSize),
+    ("vaddr", FieldType::VirtualAddress),
+    ("paddr", FieldType::PhysicalAddress),
+    ("range", FieldType::String),
+    ("seed", FieldType::U64),
+    ("rate", FieldType::U64),
+    ("elapsed_us", FieldType::U64),
+    ("phase_us", FieldType::U64),
+    ("bank", FieldType::U64),
+    ("status", FieldType::U64),
+    ("mac", FieldType::Bytes),
+    ("hash", FieldType::Bytes),
+    ("data", FieldType::Bytes),
+    ("registers", FieldType::U64Array),
+    ("prev", FieldType::U64),
+    ("next", FieldType::U64),
+    ("reason", FieldType::String),
+    ("depth", FieldType::U64),
+    ("time_us", FieldType::U64),
+    ("thread", FieldType::U64),
+    ("state", FieldType::String),
+    ("loader", FieldType::String),
+    ("vendor", FieldType::String),
+    ("revision", FieldType::U64),
+    ("secure_boot", FieldType::String),
+    ("route", FieldType::String),
+    ("min_ns", FieldType::U64),
+    ("median_ns", FieldType::U64),
+    ("p99_ns", FieldType::U64),
+    ("max_ns", FieldType::U64),
+    ("ticks", FieldType::U64),
+    ("vector", FieldType::U64),
+    ("processor", FieldType::U64),
+    ("bench", FieldType::String),
+    ("pages", FieldType::U64),
+    ("ops", FieldType::U64),
+    ("ns_per_op", FieldType::U64),
+    ("test", FieldType::String),
+    ("outcome", FieldType::String),
+    ("tick_ps", FieldType::U64),
+    ("corrected_ps", FieldType::U64),
+    ("queue", FieldType::String),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FieldType {
     KernelAddress,
     PhysicalAddress,
     VirtualAddress,
     String,
     U64,
+    /// A size in bytes
+    ByteSize,
+    /// Raw bytes, like a MAC address or hash. See [`ByteSlice`].
+    Bytes,
+    /// A short array of integers, like a register dump. See [`U64Slice`].
+    U64Array,
 }
 
 impl FieldType {
+    /// Look up the expected type of a field
+    pub const fn of(name: &str) -> Option<FieldType> {
+        let mut i = 0;
+        while i < KNOWN_FIELDS.len() {
+            if str_eq(KNOWN_FIELDS[i].0, name) {
+                return Some(KNOWN_FIELDS[i].1);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Check whether `name` is a known field of type `ty`. This is `const` so
+    /// that schemas can be checked at compile time.
+    pub const fn is_declared_as(name: &str, ty: FieldType) -> bool {
+        match Self::of(name) {
+            Some(declared) => declared as u8 == ty as u8,
+            None => false,
+        }
+    }
+
     fn write_u64<S: SerializeMap>(self, name: &str, value: u64, s: &mut S) -> Result<(), S::Error> {
         match self {
             FieldType::KernelAddress
             | FieldType::U64
+            | FieldType::ByteSize
             | FieldType::PhysicalAddress
             | FieldType::VirtualAddress => s.serialize_entry(name, &value),
             other => Err(S::Error::custom(format_args!(
@@ -210,6 +348,25 @@ impl FieldType {
 
                 s.serialize_entry(name, &SerializeDebug(value))
             }
+            FieldType::Bytes => {
+                let mut capture = HexCapture::new();
+                if write!(capture, "{value:?}").is_err() || capture.pending.is_some() {
+                    return Err(S::Error::custom(format_args!(
+                        "{name} value must be recorded with ByteSlice"
+                    )));
+                }
+                s.serialize_entry(name, &capture)
+            }
+            FieldType::U64Array => {
+                let mut capture = ArrayCapture::new();
+                if write!(capture, "{value:?}").is_err() {
+                    return Err(S::Error::custom(format_args!(
+                        "{name} value must be recorded with U64Slice"
+                    )));
+                }
+                capture.finish();
+                s.serialize_entry(name, &capture)
+            }
             other => Err(S::Error::custom(format_args!(
                 "{name} value must be a {other:?}, got str"
             ))),
@@ -220,11 +377,160 @@ impl FieldType {
         match self {
             FieldType::KernelAddress => Ok(Value::KernelAddress(map.next_value()?)),
             FieldType::U64 => Ok(Value::U64(map.next_value()?)),
+            FieldType::ByteSize => Ok(Value::ByteSize(map.next_value()?)),
             FieldType::PhysicalAddress => Ok(Value::PhysicalAddress(map.next_value()?)),
             FieldType::VirtualAddress => Ok(Value::VirtualAddress(map.next_value()?)),
             FieldType::String => Ok(Value::String(map.next_value()?)),
+            FieldType::Bytes => {
+                let (len, bytes) = map.next_value()?;
+                Ok(Value::Bytes { bytes, len })
+            }
+            FieldType::U64Array => {
+                let (len, values) = map.next_value()?;
+                Ok(Value::U64Array { values, len })
+            }
+        }
+    }
+}
+
+/// Parses the hex written by [`ByteSlice`]'s `Debug` implementation, keeping
+/// the first [`MAX_BYTES`] bytes. It's serialized as the slice's full length,
+/// followed by the bytes that were kept.
+struct HexCapture {
+    bytes: [u8; MAX_BYTES],
+    len: usize,
+    /// High nibble of a byte whose low nibble hasn't been written yet
+    pending: Option<u8>,
+}
+
+impl HexCapture {
+    fn new() -> Self {
+        Self {
+            bytes: [0; MAX_BYTES],
+            len: 0,
+            pending: None,
+        }
+    }
+}
+
+impl fmt::Write for HexCapture {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let nibble = c.to_digit(16).ok_or(fmt::Error)? as u8;
+            match self.pending.take() {
+                None => self.pending = Some(nibble),
+                Some(high) => {
+                    if let Some(byte) = self.bytes.get_mut(self.len) {
+                        *byte = (high << 4) | nibble;
+                    }
+                    self.len += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for HexCapture {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        struct Bytes<'a>(&'a [u8]);
+
+        impl<'a> Serialize for Bytes<'a> {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&(self.len as u64))?;
+        tuple.serialize_element(&Bytes(&self.bytes[..self.len.min(MAX_BYTES)]))?;
+        tuple.end()
+    }
+}
+
+/// Parses the comma-separated hex written by [`U64Slice`]'s `Debug`
+/// implementation, keeping the first [`MAX_ARRAY_LEN`] values. It's
+/// serialized like [`HexCapture`].
+struct ArrayCapture {
+    values: [u64; MAX_ARRAY_LEN],
+    len: usize,
+    /// Value being parsed, if any digits have been written since the last comma
+    current: Option<u64>,
+}
+
+impl ArrayCapture {
+    fn new() -> Self {
+        Self {
+            values: [0; MAX_ARRAY_LEN],
+            len: 0,
+            current: None,
+        }
+    }
+
+    /// Record the last value
+    fn finish(&mut self) {
+        if let Some(value) = self.current.take() {
+            if let Some(slot) = self.values.get_mut(self.len) {
+                *slot = value;
+            }
+            self.len += 1;
+        }
+    }
+}
+
+impl fmt::Write for ArrayCapture {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == ',' {
+                if self.current.is_none() {
+                    return Err(fmt::Error);
+                }
+                self.finish();
+            } else {
+                let digit = u64::from(c.to_digit(16).ok_or(fmt::Error)?);
+                let value = self.current.unwrap_or(0);
+                if value >> 60 != 0 {
+                    return Err(fmt::Error);
+                }
+                self.current = Some((value << 4) | digit);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ArrayCapture {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&(self.len as u64))?;
+        tuple.serialize_element(&self.values[..self.len.min(MAX_ARRAY_LEN)])?;
+        tuple.end()
+    }
+}
+
+/// `const` string equality, since `PartialEq` can't be used in `const fn`s
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
         }
+        i += 1;
     }
+    true
 }
 
 struct FieldVisitor<S: SerializeMap> {
@@ -257,7 +563,7 @@ impl<S: SerializeMap> Visit for FieldVisitor<S> {
     fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
         if self.state.is_ok() {
             let name = field.name();
-            if let Some(ty) = TYPES.get(name) {
+            if let Some(ty) = FieldType::of(name) {
                 self.state = ty.write_debug(field.name(), value, &mut self.serializer);
             } else {
                 panic!("unknown field: {field}");
@@ -275,7 +581,7 @@ impl<S: SerializeMap> Visit for FieldVisitor<S> {
 
     fn record_u64(&mut self, field: &Field, value: u64) {
         if self.state.is_ok() {
-            if let Some(ty) = TYPES.get(field.name()) {
+            if let Some(ty) = FieldType::of(field.name()) {
                 self.state = ty.write_u64(field.name(), value, &mut self.serializer);
             } else {
                 panic!("unknown field: {field}")
@@ -297,7 +603,7 @@ impl<S: SerializeMap> Visit for FieldVisitor<S> {
 
     fn record_str(&mut self, field: &Field, value: &str) {
         if self.state.is_ok() {
-            if let Some(ty) = TYPES.get(field.name()) {
+            if let Some(ty) = FieldType::of(field.name()) {
                 self.state = ty.write_str(field.name(), value, &mut self.serializer);
             } else {
                 panic!("unknown field: {field}")
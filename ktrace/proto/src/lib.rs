@@ -6,14 +6,56 @@ use core::fmt;
 
 use serde::{Deserialize, Serialize};
 
+mod crash;
 mod fields;
+mod schema;
 
-pub use fields::{DeserializedFields, FieldType, InternalEvent, Value};
+pub use crash::{CrashLocation, CrashReport, CrashSource, Words, BUILD_ID_LEN, CRASH_REGISTERS};
+pub use fields::{
+    ByteSlice, DeserializedFields, FieldType, InternalEvent, U64Slice, Value, KNOWN_FIELDS,
+    MAX_ARRAY_LEN, MAX_BYTES,
+};
+pub use schema::{FieldDecl, Schema, SchemaFields, SchemaKind};
 
 /// Marker written by the kernel to indicate that it's started writing to the
 /// serial port (and not the bootloader).
 pub const START_OF_OUTPUT: [u8; 4] = [255, 0, 255, 0];
 
+/// Target of milestone events, which mark progress through boot. Each
+/// milestone event's message is the name of the milestone.
+pub const MILESTONE_TARGET: &str = "milestone";
+
+/// Target of kernel test result events, one per test, with the `test` name
+/// and its `outcome`, either `pass` or `fail`
+pub const TEST_RESULT_TARGET: &str = "ktest::result";
+
+/// Target of the kernel's boot time summary event. The message is a single
+/// line of `phase=<microseconds>us` pairs.
+pub const BOOT_TIME_TARGET: &str = "boot_time";
+
+/// Name of the scheduler's context switch event, with `prev` and `next`
+/// thread contexts, the `reason` `prev` stopped, the run queue `depth`, and a
+/// `ticks` timestamp. Context 0 is the idle scheduler.
+///
+/// Timestamps are raw clock ticks, which convert to time using the
+/// [`Message::Calibration`] the kernel sends. Older kernels sent `time_us`
+/// instead.
+pub const CONTEXT_SWITCH_EVENT: &str = "context_switch";
+
+/// Name of the scheduler's thread state event, with the `thread` context, its
+/// new `state`, and a `ticks` timestamp, like [`CONTEXT_SWITCH_EVENT`]
+pub const THREAD_STATE_EVENT: &str = "thread_state";
+
+/// Name of the frame allocator benchmark result event, with the `bench` name,
+/// the number of `pages` per allocation, how many `ops` were timed, and the
+/// mean `ns_per_op`
+pub const ALLOC_BENCH_EVENT: &str = "alloc_bench";
+
+/// Name of the kernel's boot environment event, with the `loader` version,
+/// firmware `vendor` and `revision`, `secure_boot` state, a `hash` of the
+/// loader's memory map, and the kernel's `build_id`
+pub const BOOT_ENVIRONMENT_EVENT: &str = "boot_environment";
+
 pub type SenderMessage<'a> =
     Message<'a, fields::SerializeEvent<'a>, fields::SerializeAttributes<'a>>;
 
@@ -27,56 +69,173 @@ pub type InternalMessage<'a> =
 pub type SpanId = u64;
 /// Identifier for a processor (or a core in a multi-core CPU)
 pub type ProcessorId = u32;
+/// Identifier for an execution context, like a kernel task, with its own
+/// span stack. Context 0 is the processor's own context.
+pub type ContextId = u64;
+/// Identifier the kernel assigns to a callsite's [`Metadata`], so that spans
+/// and events can refer to it instead of repeating it
+pub type MetadataId = u32;
 
 /// Root type for KTrace messages
 #[derive(Deserialize, Serialize, Debug)]
 pub enum Message<'a, E, A> {
-    SpanCreated(#[serde(borrow)] SpanCreated<'a, A>),
-    Event(#[serde(borrow)] Event<'a, E>),
+    SpanCreated(#[serde(borrow)] SpanCreated<A, Metadata<'a>>),
+    Event(#[serde(borrow)] Event<E, Metadata<'a>>),
 
     /// A new span has been entered on one processor
     SpanEntered {
         id: SpanId,
         processor: ProcessorId,
+        /// When the span was entered, in raw clock ticks like the scheduler's
+        /// events. 0 if the kernel has no clock yet.
+        ticks: u64,
     },
 
     /// A span has been exited on a processor
     SpanExited {
         id: SpanId,
         processor: ProcessorId,
+        /// When the span was exited, like [`Message::SpanEntered::ticks`]
+        ticks: u64,
     },
 
     /// A span has been closed, so it can no longer be entered
     SpanClosed {
         id: SpanId,
     },
+
+    /// Declares the fields of a span or event. These are sent once, before any
+    /// spans or events.
+    Schema(#[serde(borrow)] Schema<'a>),
+
+    /// Spans or events from a rate-limited callsite were dropped
+    Suppressed {
+        #[serde(borrow)]
+        metadata: Metadata<'a>,
+        /// Number of spans or events dropped since the last report
+        count: u64,
+    },
+
+    /// A processor switched to a different execution context. Until the next
+    /// switch, spans are entered and exited on the context's span stack, so
+    /// that a task's spans follow it rather than the processor.
+    ContextSwitched {
+        processor: ProcessorId,
+        context: ContextId,
+        /// When the processor switched, like [`Message::SpanEntered::ticks`]
+        ticks: u64,
+    },
+
+    /// Where a processor sits in the system's topology. The kernel sends this
+    /// once for each processor as it comes online, so the host can build a
+    /// map of processor IDs to cores and packages.
+    Processor {
+        processor: ProcessorId,
+        location: ProcessorLocation,
+    },
+
+    /// The rate of the clock that timestamps in events are measured with.
+    /// The kernel sends this once it's calibrated the clock, and timestamps
+    /// from before then are still valid.
+    Calibration {
+        ticks_per_second: u64,
+    },
+
+    /// Assigns `id` to a callsite's metadata. The kernel sends this once per
+    /// callsite, before any [`Message::InternedSpanCreated`] or
+    /// [`Message::InternedEvent`] that refers to it.
+    MetadataDefined {
+        id: MetadataId,
+        #[serde(borrow)]
+        metadata: Metadata<'a>,
+    },
+
+    /// Like [`Message::SpanCreated`], but with metadata from an earlier
+    /// [`Message::MetadataDefined`]
+    InternedSpanCreated(SpanCreated<A, MetadataId>),
+
+    /// Like [`Message::Event`], but with metadata from an earlier
+    /// [`Message::MetadataDefined`]
+    InternedEvent(Event<E, MetadataId>),
+
+    /// A boot stage crashed. This is the last message it sends.
+    Crash(#[serde(borrow)] CrashReport<'a>),
+
+    /// A crash from an earlier boot, which the kernel found in its crash dump.
+    /// This doesn't mean the current boot crashed.
+    PreviousCrash(#[serde(borrow)] CrashReport<'a>),
+
+    /// The last messages the kernel sent before the crash in the preceding
+    /// [`Message::PreviousCrash`], encoded back to back. Metadata they were
+    /// interned with was defined earlier in that boot, so it may be missing.
+    PreviousTrace(&'a [u8]),
+
+    /// The profiler sampled what `processor` was running. `stack` starts with
+    /// the interrupted instruction pointer, followed by return addresses,
+    /// innermost first, if the kernel walked the stack.
+    ProfileSample {
+        processor: ProcessorId,
+        context: ExecutionContext,
+        #[serde(borrow)]
+        stack: Words<'a>,
+    },
+
+    /// The profiler dropped `count` samples from `processor` since the last
+    /// report, because they were taken faster than they could be sent
+    ProfileSamplesDropped {
+        processor: ProcessorId,
+        count: u64,
+    },
+}
+
+/// Position of a processor in the topology: hardware thread `thread` of core
+/// `core` in package (socket) `package`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProcessorLocation {
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+}
+
+impl fmt::Display for ProcessorLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "core {}, pkg {}", self.core, self.package)?;
+        if self.thread != 0 {
+            write!(f, ", thread {}", self.thread)?;
+        }
+        Ok(())
+    }
 }
 
-/// A new span was created
+/// A new span was created. The metadata is either sent in full or, for
+/// [`Message::InternedSpanCreated`], a [`MetadataId`].
 #[derive(Deserialize, Serialize, Debug)]
-pub struct SpanCreated<'a, A> {
+pub struct SpanCreated<A, M> {
     pub id: SpanId,
     pub parent: Parent,
+    /// What the processor was running when the span was created
+    pub context: ExecutionContext,
 
-    #[serde(borrow)]
-    pub metadata: Metadata<'a>,
+    pub metadata: M,
 
     pub fields: A,
 }
 
-/// A tracing event occurred
+/// A tracing event occurred. The metadata is either sent in full or, for
+/// [`Message::InternedEvent`], a [`MetadataId`].
 #[derive(Deserialize, Serialize, Debug)]
-pub struct Event<'a, E> {
+pub struct Event<E, M> {
     pub span_id: Parent,
+    /// What the processor was running when the event was recorded
+    pub context: ExecutionContext,
 
-    #[serde(borrow)]
-    pub metadata: Metadata<'a>,
+    pub metadata: M,
 
     pub fields: E,
 }
 
 /// The parent span for an event or new span
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Parent {
     /// This is a root, with no parent
     Root,
@@ -86,7 +245,28 @@ pub enum Parent {
     Explicit(SpanId),
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Whether a span or event came from normal kernel code or an interrupt
+/// handler. This is unrelated to [`ContextId`]s, which identify tasks.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ExecutionContext {
+    Task,
+    /// A maskable interrupt handler
+    Irq,
+    /// A non-maskable interrupt handler
+    Nmi,
+}
+
+impl fmt::Display for ExecutionContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ExecutionContext::Task => "task",
+            ExecutionContext::Irq => "irq",
+            ExecutionContext::Nmi => "nmi",
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Metadata<'a> {
     pub name: &'a str,
     pub target: &'a str,
@@ -96,6 +276,18 @@ pub struct Metadata<'a> {
     pub line: Option<u32>,
 }
 
+impl Metadata<'static> {
+    /// Stand-in for interned metadata that was never defined, for example
+    /// because decoding started partway through a trace
+    pub const UNKNOWN: Self = Metadata {
+        name: "<unknown callsite>",
+        target: "<unknown>",
+        level: Level::Info,
+        file: None,
+        line: None,
+    };
+}
+
 impl<'a> Metadata<'a> {
     pub fn from_tracing(m: &tracing::Metadata<'a>) -> Metadata<'a> {
         Metadata {
@@ -108,7 +300,7 @@ impl<'a> Metadata<'a> {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Level {
     Error,
     Warn,
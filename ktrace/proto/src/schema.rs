@@ -0,0 +1,92 @@
+//! Schema declarations for individual spans and events.
+//!
+//! Every field has a fixed type (see [`KNOWN_FIELDS`](crate::KNOWN_FIELDS)).
+//! On top of that, kernel code can declare exactly which fields a span or event
+//! records using `ktrace::schema!`. The kernel sends these declarations at
+//! startup, so the decoder can check that what it receives matches.
+
+use alloc::vec::Vec;
+use core::fmt::Formatter;
+
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::FieldType;
+
+/// Fields recorded by the span or event called `name`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Schema<'a> {
+    pub name: &'a str,
+    pub kind: SchemaKind,
+    #[serde(borrow)]
+    pub fields: SchemaFields<'a>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SchemaKind {
+    Span,
+    Event,
+}
+
+/// A single declared field
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldDecl<'a> {
+    pub name: &'a str,
+    pub ty: FieldType,
+}
+
+/// Declared fields, which are statically allocated in the kernel and
+/// heap-allocated when decoded
+#[derive(Debug, Clone)]
+pub enum SchemaFields<'a> {
+    Static(&'a [FieldDecl<'a>]),
+    Decoded(Vec<FieldDecl<'a>>),
+}
+
+impl<'a> SchemaFields<'a> {
+    pub fn as_slice(&self) -> &[FieldDecl<'a>] {
+        match self {
+            SchemaFields::Static(fields) => fields,
+            SchemaFields::Decoded(fields) => fields,
+        }
+    }
+}
+
+impl<'a> Serialize for SchemaFields<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.as_slice().serialize(serializer)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for SchemaFields<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = SchemaFields<'de>;
+
+            fn expecting(&self, formatter: &mut Formatter) -> alloc::fmt::Result {
+                formatter.write_str("field declarations")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut fields = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(field) = seq.next_element()? {
+                    fields.push(field);
+                }
+                Ok(SchemaFields::Decoded(fields))
+            }
+        }
+
+        deserializer.deserialize_seq(FieldsVisitor)
+    }
+}
@@ -0,0 +1,104 @@
+//! Crash dumps that outlive the boot that wrote them.
+//!
+//! The kernel writes a dump into memory it set aside when it crashes, and
+//! looks for one on the next boot, so a crash isn't lost just because
+//! nothing was reading the serial port. A dump holds the crash report and
+//! the [tail](crate::Worker::copy_tail) of the trace:
+//!
+//! | Offset | Size | Contents                                  |
+//! |--------|------|-------------------------------------------|
+//! | 0      | 8    | [`MAGIC`]                                 |
+//! | 8      | 4    | Format version                            |
+//! | 12     | 4    | Length of the report                      |
+//! | 16     | 4    | Length of the trace tail                  |
+//! | 20     | 4    | Reserved                                  |
+//! | 24     | 8    | FNV-1a hash of the report and trace tail  |
+//! | 32     |      | The report, in postcard, then the tail    |
+//!
+//! All fields are little-endian. Memory that doesn't start with a header
+//! whose hash matches isn't a dump, which covers both memory that was never
+//! written and a dump that was only partly written.
+
+use crate::proto;
+
+/// Marks the start of a dump
+pub const MAGIC: [u8; 8] = *b"PLATDUMP";
+
+/// Bumped whenever the layout changes, so old dumps are ignored
+const VERSION: u32 = 1;
+
+/// Size of the header before the report
+pub const HEADER_LEN: usize = 32;
+
+/// Longest trace tail a dump holds. This leaves room to send it in one
+/// [`proto::Message::PreviousTrace`].
+pub const MAX_TRACE_LEN: usize = 960;
+
+/// A crash dump read back from memory
+#[derive(Debug, Clone, Copy)]
+pub struct Dump<'a> {
+    pub report: proto::CrashReport<'a>,
+    /// Messages sent just before the crash
+    pub trace: &'a [u8],
+}
+
+/// Write a dump of `report` and `trace` to `region`, returning whether it
+/// fit. `trace` is cut down to [`MAX_TRACE_LEN`].
+pub fn write(region: &mut [u8], report: &proto::CrashReport, trace: &[u8]) -> bool {
+    let trace = &trace[..trace.len().min(MAX_TRACE_LEN)];
+    let Some(body) = region.get_mut(HEADER_LEN..) else {
+        return false;
+    };
+    let report_len = match postcard::to_slice(report, body) {
+        Ok(encoded) => encoded.len(),
+        Err(_) => return false,
+    };
+    let Some(trace_out) = body.get_mut(report_len..report_len + trace.len()) else {
+        return false;
+    };
+    trace_out.copy_from_slice(trace);
+    let hash = fnv1a(&body[..report_len + trace.len()]);
+
+    let header = &mut region[..HEADER_LEN];
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(report_len as u32).to_le_bytes());
+    header[16..20].copy_from_slice(&(trace.len() as u32).to_le_bytes());
+    header[20..24].fill(0);
+    header[24..32].copy_from_slice(&hash.to_le_bytes());
+    // Write the magic last, so a dump cut off partway through isn't valid
+    header[..8].copy_from_slice(&MAGIC);
+    true
+}
+
+/// Read the dump in `region`, if there's a valid one
+pub fn read(region: &[u8]) -> Option<Dump<'_>> {
+    let header = region.get(..HEADER_LEN)?;
+    let word = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    if header[..8] != MAGIC || word(8) != VERSION {
+        return None;
+    }
+    let (report_len, trace_len) = (word(12) as usize, word(16) as usize);
+    let body = region.get(HEADER_LEN..HEADER_LEN + report_len.checked_add(trace_len)?)?;
+    if fnv1a(body) != u64::from_le_bytes(header[24..32].try_into().unwrap()) {
+        return None;
+    }
+
+    let (report, trace) = body.split_at(report_len);
+    Some(Dump {
+        report: postcard::from_bytes(report).ok()?,
+        trace,
+    })
+}
+
+/// Mark `region` as not holding a dump, once its dump has been reported
+pub fn clear(region: &mut [u8]) {
+    let len = region.len().min(HEADER_LEN);
+    region[..len].fill(0);
+}
+
+/// 64-bit FNV-1a hash
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
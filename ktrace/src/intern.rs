@@ -0,0 +1,119 @@
+//! Callsite metadata interning.
+//!
+//! Span and event metadata is mostly strings (the name, target, and file), so
+//! repeating it in every message would dominate the trace's bandwidth.
+//! Instead, each callsite gets a small [`MetadataId`](proto::MetadataId) the
+//! first time it's used, and its metadata is sent once in a
+//! [`MetadataDefined`](proto::Message::MetadataDefined) message. Later spans
+//! and events refer to the ID.
+//!
+//! A callsite's ID is only used once its definition is queued, so the decoder
+//! always sees the definition first. If the definition can't be queued, or
+//! another processor is still queueing it, or every ID is taken, spans and
+//! events carry their metadata in full instead.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU8, Ordering};
+
+use tracing_core::Metadata;
+
+use crate::proto;
+
+/// Maximum number of interned callsites. Callsites beyond this always send
+/// their metadata in full.
+const MAX_CALLSITES: usize = 512;
+
+/// The callsite's definition hasn't been sent
+const UNSENT: u8 = 0;
+/// A processor is queueing the callsite's definition
+const SENDING: u8 = 1;
+/// The callsite's definition is queued, so its ID can be used
+const SENT: u8 = 2;
+
+pub(crate) static INTERNER: Interner = Interner::new();
+
+pub(crate) struct Interner {
+    slots: [Slot; MAX_CALLSITES],
+}
+
+struct Slot {
+    /// The callsite this slot is assigned to, if any. The slot's index is the
+    /// callsite's ID.
+    metadata: AtomicPtr<Metadata<'static>>,
+    state: AtomicU8,
+}
+
+impl Interner {
+    const fn new() -> Self {
+        Self {
+            slots: [const { Slot::new() }; MAX_CALLSITES],
+        }
+    }
+
+    /// Get the ID for `metadata`'s callsite, assigning one if it doesn't have
+    /// one yet. If the callsite's definition hasn't been sent, this calls
+    /// `define` to send it, which returns whether it succeeded.
+    ///
+    /// Returns `None` if the metadata has to be sent in full.
+    pub(crate) fn intern(
+        &self,
+        metadata: &'static Metadata<'static>,
+        define: impl FnOnce(proto::MetadataId) -> bool,
+    ) -> Option<proto::MetadataId> {
+        let key = metadata as *const Metadata<'static> as *mut Metadata<'static>;
+        let (id, slot) = self.probe(key).find(|(_, slot)| {
+            let existing = slot.metadata.load(Ordering::Acquire);
+            if !existing.is_null() {
+                return existing == key;
+            }
+            // Another processor may claim the slot first, possibly for the same callsite
+            match slot.metadata.compare_exchange(
+                ptr::null_mut(),
+                key,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => true,
+                Err(existing) => existing == key,
+            }
+        })?;
+
+        match slot
+            .state
+            .compare_exchange(UNSENT, SENDING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // Publishing SENT after the definition is queued means that anything using the
+                // ID is queued after it
+                let sent = define(id);
+                slot.state
+                    .store(if sent { SENT } else { UNSENT }, Ordering::Release);
+                sent.then_some(id)
+            }
+            Err(SENT) => Some(id),
+            Err(_) => None,
+        }
+    }
+
+    /// Slots to try for `key`, in order, with their IDs
+    fn probe(
+        &self,
+        key: *mut Metadata<'static>,
+    ) -> impl Iterator<Item = (proto::MetadataId, &Slot)> {
+        // Metadata is at least word-aligned, so skip the low bits
+        let start = (key as usize >> 3) % MAX_CALLSITES;
+        (0..MAX_CALLSITES).map(move |i| {
+            let index = (start + i) % MAX_CALLSITES;
+            (index as proto::MetadataId, &self.slots[index])
+        })
+    }
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            metadata: AtomicPtr::new(ptr::null_mut()),
+            state: AtomicU8::new(UNSENT),
+        }
+    }
+}
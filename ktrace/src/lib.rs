@@ -13,7 +13,9 @@
 //! # Design
 //! `ktrace` introduces a lightweight [schema](https://dl.acm.org/doi/10.1145/3544497.3544500) on top of
 //! [`tracing_core`]'s APIs. In particular, attributes of an event or span must
-//! be predeclared and of a specific type.
+//! be predeclared and of a specific type. Individual spans and events can also
+//! declare exactly which fields they record with [`schema!`], which is checked
+//! at compile time and sent to the host so the decoder can validate traces.
 //!
 //! Serialized trace data is streamed over a serial (or other I/O) port, and a
 //! tool on the other end reconstructs and formats the traces.
@@ -27,7 +29,28 @@
 //! during interrupt handling and memory allocation. It also avoids contention
 //! between cores when tracing. However, interrupts must still be disabled
 //! during modifications of internal tracing data structures, which cannot be
-//! updated reentrantly.
+//! updated reentrantly. NMIs can't be disabled, so those modifications are
+//! also [NMI-unsafe critical sections](platypos_hal::interrupts::nmi_safe),
+//! which NMI handlers check before tracing anything. Pushing to the message
+//! queue is lock-free, so events themselves are safe to emit from an NMI.
+//!
+//! Noisy callsites can be [rate-limited](set_rate_limits), so that they can't
+//! crowd everything else out of the queue. When the system is under load, for
+//! example short on memory, debug and trace events can be
+//! [shed](set_shedding) altogether.
+//!
+//! Spans and events are tagged with whether they came from task code or an
+//! interrupt handler, as reported by the [context hook](set_context_hook).
+//!
+//! Callsite metadata is interned: it's sent once per callsite, and spans and
+//! events refer to it by ID.
+//!
+//! The worker batches serialized messages into larger writes, since every
+//! write to a UART has overhead. A [`FlushPolicy`] decides how long messages
+//! can wait in the batch. It also keeps a [tail](Worker::copy_tail) of the
+//! last messages it wrote, which the kernel saves in a [crash dump](dump).
+//! Writes can be given a [timeout](Worker::set_write_timeout), so a sink that
+//! stops draining costs dropped trace data instead of a hung worker.
 #![no_std]
 #![feature(maybe_uninit_uninit_array)]
 
@@ -35,12 +58,14 @@ extern crate alloc;
 
 use core::convert::Infallible;
 use core::num::NonZeroU64;
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
 use hashbrown::hash_map::Entry;
+use platypos_common::sync::Global;
+use platypos_hal::interrupts::{nmi_safe, ExecutionContext};
+use platypos_hal::io::{TimeoutError, WriteTimeout};
+use platypos_hal::time::{Clock, Deadline, Duration};
 use platypos_hal::topology::PerProcessor;
-use platypos_hal::Write;
-use platypos_ktrace_proto as proto;
 
 use hashbrown::HashMap;
 use platypos_slab::Slab;
@@ -48,23 +73,119 @@ use serde::Serialize;
 // use stack::SpanStack;
 use thingbuf::recycling::{self, Recycle};
 use thingbuf::StaticThingBuf;
-use tracing_core::{span, Dispatch, Subscriber};
-
+use tracing_core::subscriber::Interest;
+use tracing_core::{span, Dispatch, Level, LevelFilter, Subscriber};
+
+pub mod dump;
+mod intern;
+mod rate_limit;
+mod schema;
+mod tail;
 // mod stack;
 
+pub use rate_limit::{set_rate_limits, Directive, ParseError, RateLimit};
+pub use schema::SCHEMAS;
+
+use intern::INTERNER;
+use rate_limit::RATE_LIMITER;
+use tail::Tail;
+
+// Used by the `schema!` macro
+#[doc(hidden)]
+pub use linkme;
+#[doc(hidden)]
+pub use platypos_ktrace_proto as proto;
+#[doc(hidden)]
+pub use tracing;
+
 // Maximum number of spans which can exist at once
 const MAX_SPANS: usize = 128;
 
+/// Span slab index width. With 32-bit indices, this leaves 22 bits of
+/// generation and allows 255 concurrent references to a span's state, far more
+/// than the one per processor that lookups hold at a time.
+const SPAN_INDEX_BITS: u32 = 10;
+
 /// Shared kernel tracing subscriber
 pub struct KTrace<TP: platypos_hal::topology::Topology + 'static> {
-    spans: Slab<MAX_SPANS, SpanState, TP>,
+    spans: Slab<MAX_SPANS, SpanState, TP, u32, SPAN_INDEX_BITS>,
+    topology: &'static TP,
     // stack: PerProcessor<SpanStack, &'static TP>,
 }
 
 /// Worker task which sends serialized trace events to the host
-pub struct Worker<W: Write> {
+pub struct Worker<W: WriteTimeout> {
     writer: W,
+    /// Clock and time limit for each write, if writes can time out
+    write_timeout: Option<(&'static dyn Clock, Duration)>,
     total_events: usize,
+    /// Messages waiting to be written
+    batch: heapless::Vec<u8, BATCH_CAPACITY>,
+    /// Clock reading when the first message in `batch` was added
+    batch_started: u64,
+    policy: FlushPolicy,
+    /// Clock for the idle flush deadline, in microseconds. Without one, every
+    /// call to [`Worker::work`] writes out the batch.
+    clock: Option<fn() -> u64>,
+    stats: WorkerStats,
+    /// The last messages written, for crash dumps
+    tail: Tail,
+}
+
+/// Most bytes the worker can batch up before writing them out
+const BATCH_CAPACITY: usize = 2048;
+
+/// When the worker writes out batched messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Write out the batch once adding a message would take it past this many
+    /// bytes. This is capped at 2 KiB.
+    pub max_batch: usize,
+    /// Write out the batch once its oldest message has waited this long, in
+    /// microseconds. The batch is also written whenever the worker finds the
+    /// queue empty, so traces appear promptly when the system is quiet.
+    pub idle_flush_us: u64,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_batch: BATCH_CAPACITY,
+            idle_flush_us: 10_000,
+        }
+    }
+}
+
+/// Counters for tuning the [`FlushPolicy`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Messages written, including ones the worker produced itself
+    pub messages: u64,
+    /// Bytes written
+    pub bytes: u64,
+    /// Calls to the underlying writer
+    pub writes: u64,
+    /// Batches written because they were full
+    pub full_flushes: u64,
+    /// Batches written because the queue was empty
+    pub idle_flushes: u64,
+    /// Batches written because they reached the idle flush deadline
+    pub deadline_flushes: u64,
+    /// Batches written by [`Worker::flush`]
+    pub forced_flushes: u64,
+    /// Writes that timed out, dropping the rest of what they were writing
+    pub timeouts: u64,
+    /// Bytes dropped by writes that timed out
+    pub dropped_bytes: u64,
+}
+
+/// Why a batch was written out
+#[derive(Debug, Clone, Copy)]
+enum FlushReason {
+    Full,
+    Idle,
+    Deadline,
+    Forced,
 }
 
 /// Per-span state that is needed kernel-side (as opposed to processor-side)
@@ -74,6 +195,10 @@ struct SpanState {
     metadata: &'static tracing_core::Metadata<'static>,
 }
 
+static CONTEXT_HOOK: Global<fn() -> ExecutionContext> = Global::new();
+
+static TIMESTAMP_HOOK: Global<fn() -> u64> = Global::new();
+
 static QUEUE: StaticThingBuf<Message, 64, recycling::WithCapacity> =
     StaticThingBuf::with_recycle(recycling::WithCapacity::new());
 
@@ -89,7 +214,7 @@ struct Message {
 ///
 /// The returned worker must be driven periodically for events to be processed.
 pub fn init<
-    W: Write<Error = Infallible> + Send + 'static,
+    W: WriteTimeout<Error = Infallible> + Send + 'static,
     TP: platypos_hal::topology::Topology + 'static,
 >(
     mut writer: W,
@@ -98,22 +223,204 @@ pub fn init<
     writer
         .write_all(&proto::START_OF_OUTPUT)
         .expect("Could not write start-of-output");
+    let mut worker = Worker::new(writer);
+    worker.write_schemas();
+    worker.flush();
+
     let dispatch = Dispatch::new(KTrace::new(topology));
     tracing_core::dispatcher::set_global_default(dispatch).expect("Tracing initialized twice");
-    Worker::new(writer)
+    worker
+}
+
+/// Switch `processor` to the span stack of `context`, for example when the
+/// scheduler starts polling a different task. Pass a context of 0 to switch
+/// back to the processor's own span stack.
+///
+/// Spans entered in one context stay entered when switching away from it, so
+/// a task that's suspended inside a span is still in it when it resumes,
+/// even on another processor.
+pub fn switch_context(processor: proto::ProcessorId, context: proto::ContextId) {
+    if let Ok(mut slot) = QUEUE.push_ref() {
+        slot.write_message(&proto::Message::ContextSwitched {
+            processor,
+            context,
+            ticks: timestamp(),
+        });
+    }
+    // TODO: like entering and exiting spans, a dropped switch confuses the decoder
+}
+
+/// Tell the host where `processor` is in the system's topology. Call this as
+/// each processor comes online, so the decoder can label its output.
+pub fn announce_processor(processor: proto::ProcessorId, location: proto::ProcessorLocation) {
+    if let Ok(mut slot) = QUEUE.push_ref() {
+        slot.write_message(&proto::Message::Processor {
+            processor,
+            location,
+        });
+    }
+}
+
+/// Whether debug and trace events are currently being dropped
+static SHEDDING: AtomicBool = AtomicBool::new(false);
+
+/// Drop debug and trace events while `shed` is true, keeping only info and
+/// above. Spans are unaffected, so the decoder's span trees stay intact.
+pub fn set_shedding(shed: bool) {
+    SHEDDING.store(shed, Ordering::Relaxed);
+}
+
+/// Event levels, from least to most verbose
+const LEVELS: [Level; 5] = [
+    Level::ERROR,
+    Level::WARN,
+    Level::INFO,
+    Level::DEBUG,
+    Level::TRACE,
+];
+
+/// How many of [`LEVELS`] are sent, so 0 drops every event
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(LEVELS.len() as u8);
+
+/// Drop events more verbose than `level`. Like shedding, this leaves spans
+/// alone.
+pub fn set_max_level(level: LevelFilter) {
+    let max = LEVELS
+        .iter()
+        .take_while(|&&l| LevelFilter::from_level(l) <= level)
+        .count();
+    MAX_LEVEL.store(max as u8, Ordering::Relaxed);
+}
+
+/// Register the function that tells whether the current processor is running
+/// an interrupt handler, so that spans and events can be tagged with it. Until
+/// this is called, everything is tagged as task context.
+pub fn set_context_hook(hook: fn() -> ExecutionContext) {
+    CONTEXT_HOOK.init(hook);
+}
+
+/// Register the function that reads the clock, in raw ticks, so that span
+/// transitions and context switches can be timestamped. Until this is called,
+/// their timestamps are 0.
+pub fn set_timestamp_hook(hook: fn() -> u64) {
+    TIMESTAMP_HOOK.init(hook);
+}
+
+fn timestamp() -> u64 {
+    TIMESTAMP_HOOK.try_get().map_or(0, |hook| hook())
+}
+
+fn execution_context() -> proto::ExecutionContext {
+    to_proto(
+        CONTEXT_HOOK
+            .try_get()
+            .map_or(ExecutionContext::Task, |hook| hook()),
+    )
+}
+
+fn to_proto(context: ExecutionContext) -> proto::ExecutionContext {
+    match context {
+        ExecutionContext::Task => proto::ExecutionContext::Task,
+        ExecutionContext::Irq => proto::ExecutionContext::Irq,
+        ExecutionContext::Nmi => proto::ExecutionContext::Nmi,
+    }
+}
+
+/// The interned ID for `metadata`'s callsite, queueing its definition first if
+/// it hasn't been sent yet. Returns `None` if the metadata has to be sent in
+/// full.
+fn intern(metadata: &'static tracing_core::Metadata<'static>) -> Option<proto::MetadataId> {
+    INTERNER.intern(metadata, |id| {
+        let Ok(mut slot) = QUEUE.push_ref() else {
+            return false;
+        };
+        slot.write_message(&proto::Message::MetadataDefined {
+            id,
+            metadata: proto::Metadata::from_tracing(metadata),
+        });
+        slot.error.is_none()
+    })
+}
+
+/// Tell the host how fast the clock behind event timestamps runs. Call this
+/// once the clock is calibrated.
+pub fn announce_calibration(calibration: platypos_hal::time::Calibration) {
+    if let Ok(mut slot) = QUEUE.push_ref() {
+        slot.write_message(&proto::Message::Calibration {
+            ticks_per_second: calibration.ticks_per_second(),
+        });
+    }
+}
+
+/// Most addresses a [profile sample](send_profile_sample) can have
+pub const MAX_SAMPLE_FRAMES: usize = 16;
+
+/// Send a profile sample of what `processor` was running in `context`: the
+/// instruction pointer, then any return addresses, innermost first. Frames
+/// past [`MAX_SAMPLE_FRAMES`] are left out. Returns `false` if the queue was
+/// full, in which case the sample can be sent again later.
+pub fn send_profile_sample(
+    processor: proto::ProcessorId,
+    context: ExecutionContext,
+    stack: &[u64],
+) -> bool {
+    let mut buf = [0; MAX_SAMPLE_FRAMES * 8];
+    let stack = proto::Words::encode(stack.iter().copied(), &mut buf);
+    let Ok(mut slot) = QUEUE.push_ref() else {
+        return false;
+    };
+    slot.write_message(&proto::Message::ProfileSample {
+        processor,
+        context: to_proto(context),
+        stack,
+    });
+    true
+}
+
+/// Tell the host that `count` profile samples from `processor` were dropped.
+/// Returns `false` if the queue was full.
+pub fn report_dropped_samples(processor: proto::ProcessorId, count: u64) -> bool {
+    let Ok(mut slot) = QUEUE.push_ref() else {
+        return false;
+    };
+    slot.write_message(&proto::Message::ProfileSamplesDropped { processor, count });
+    true
+}
+
+/// Tell the host that the kernel crashed. Call this once, just before the
+/// kernel stops for good, and flush the trace afterwards.
+pub fn report_crash(report: &proto::CrashReport) {
+    if let Ok(mut slot) = QUEUE.push_ref() {
+        slot.write_message(&proto::Message::Crash(*report));
+    }
+}
+
+/// Tell the host about a crash from an earlier boot, read back from a
+/// [crash dump](dump)
+pub fn report_previous_crash(dump: &dump::Dump) {
+    if let Ok(mut slot) = QUEUE.push_ref() {
+        slot.write_message(&proto::Message::PreviousCrash(dump.report));
+    }
+    if dump.trace.is_empty() {
+        return;
+    }
+    if let Ok(mut slot) = QUEUE.push_ref() {
+        slot.write_message(&proto::Message::PreviousTrace(dump.trace));
+    }
 }
 
 impl<TP: platypos_hal::topology::Topology + 'static> KTrace<TP> {
     fn new(topology: &'static TP) -> Self {
         KTrace {
             spans: Slab::new(topology),
+            topology,
             // stack: PerProcessor::new(topology),
         }
     }
 
     /// Current processor ID to report, for contextual spans and events
     fn processor_id(&self) -> proto::ProcessorId {
-        0
+        self.topology.current_processor().into()
     }
 
     /// Handler for fatal internal tracing errors. This is used instead of
@@ -125,18 +432,29 @@ impl<TP: platypos_hal::topology::Topology + 'static> KTrace<TP> {
 }
 
 impl<TP: platypos_hal::topology::Topology + 'static> Subscriber for KTrace<TP> {
-    fn enabled(&self, _metadata: &tracing_core::Metadata<'_>) -> bool {
+    fn register_callsite(&self, metadata: &'static tracing_core::Metadata<'static>) -> Interest {
+        // Only rate-limited callsites need to be checked every time
+        if RATE_LIMITER.register(metadata) {
+            Interest::sometimes()
+        } else {
+            Interest::always()
+        }
+    }
+
+    fn enabled(&self, metadata: &tracing_core::Metadata<'_>) -> bool {
         // TODO: filtering directives
-        true
+        RATE_LIMITER.check(metadata)
     }
 
     fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+        let section = nmi_safe::enter();
         let Ok(idx) = self.spans.insert(SpanState {
             references: AtomicUsize::new(1),
             metadata: span.metadata()
         }) else {
             self.fatal_error("exceeded max span limit")
         };
+        drop(section);
 
         let id = span::Id::from_u64(idx.into());
 
@@ -149,13 +467,26 @@ impl<TP: platypos_hal::topology::Topology + 'static> Subscriber for KTrace<TP> {
             proto::Parent::Explicit(span.parent().map_or(0, |s| s.into_u64()))
         };
 
+        let metadata = span.metadata();
+        let interned = intern(metadata);
         if let Ok(mut slot) = QUEUE.push_ref() {
-            slot.write_message(&proto::Message::SpanCreated(proto::SpanCreated {
-                id: idx.into(),
-                parent,
-                metadata: proto::Metadata::from_tracing(span.metadata()),
-                fields: span.into(),
-            }));
+            let (id, context, fields) = (idx.into(), execution_context(), span.into());
+            slot.write_message(&match interned {
+                Some(metadata) => proto::Message::InternedSpanCreated(proto::SpanCreated {
+                    id,
+                    parent,
+                    context,
+                    metadata,
+                    fields,
+                }),
+                None => proto::Message::SpanCreated(proto::SpanCreated {
+                    id,
+                    parent,
+                    context,
+                    metadata: proto::Metadata::from_tracing(metadata),
+                    fields,
+                }),
+            });
         }
         // Otherwise, the queue is full - drop this span
 
@@ -180,12 +511,24 @@ impl<TP: platypos_hal::topology::Topology + 'static> Subscriber for KTrace<TP> {
             proto::Parent::Explicit(event.parent().map_or(0, |s| s.into_u64()))
         };
 
+        let metadata = event.metadata();
+        let interned = intern(metadata);
         if let Ok(mut slot) = QUEUE.push_ref() {
-            slot.write_message(&proto::Message::Event(proto::Event {
-                span_id,
-                metadata: proto::Metadata::from_tracing(event.metadata()),
-                fields: event.into(),
-            }));
+            let (context, fields) = (execution_context(), event.into());
+            slot.write_message(&match interned {
+                Some(metadata) => proto::Message::InternedEvent(proto::Event {
+                    span_id,
+                    context,
+                    metadata,
+                    fields,
+                }),
+                None => proto::Message::Event(proto::Event {
+                    span_id,
+                    context,
+                    metadata: proto::Metadata::from_tracing(metadata),
+                    fields,
+                }),
+            });
         }
         // Otherwise, the queue is full - drop this event
     }
@@ -195,6 +538,7 @@ impl<TP: platypos_hal::topology::Topology + 'static> Subscriber for KTrace<TP> {
             slot.write_message(&proto::Message::SpanEntered {
                 id: span.into_u64(),
                 processor: self.processor_id(),
+                ticks: timestamp(),
             });
         }
         // TODO: should probably panic if the queue is full, since tracking will
@@ -206,6 +550,7 @@ impl<TP: platypos_hal::topology::Topology + 'static> Subscriber for KTrace<TP> {
             slot.write_message(&proto::Message::SpanExited {
                 id: span.into_u64(),
                 processor: self.processor_id(),
+                ticks: timestamp(),
             });
         }
         // TODO: should probably panic if the queue is full, since tracking will
@@ -230,6 +575,7 @@ impl<TP: platypos_hal::topology::Topology + 'static> Subscriber for KTrace<TP> {
         if references == 1 {
             // This was the last reference
             drop(state);
+            let _section = nmi_safe::enter();
             self.spans.remove(idx);
             true
         } else {
@@ -245,8 +591,11 @@ impl<TP: platypos_hal::topology::Topology + 'static> Subscriber for KTrace<TP> {
         None
     }
 
-    fn event_enabled(&self, _event: &tracing_core::Event<'_>) -> bool {
-        true
+    fn event_enabled(&self, event: &tracing_core::Event<'_>) -> bool {
+        let level = event.metadata().level();
+        let max = usize::from(MAX_LEVEL.load(Ordering::Relaxed));
+        LEVELS[..max].contains(level)
+            && (!SHEDDING.load(Ordering::Relaxed) || *level <= tracing_core::Level::INFO)
     }
 }
 
@@ -304,27 +653,172 @@ impl Recycle<Message> for recycling::WithCapacity {
     }
 }
 
-impl<W: Write> Worker<W> {
+impl<W: WriteTimeout> Worker<W> {
     fn new(writer: W) -> Self {
         Self {
             writer,
+            write_timeout: None,
             total_events: 0,
+            batch: heapless::Vec::new(),
+            batch_started: 0,
+            policy: FlushPolicy::default(),
+            clock: None,
+            stats: WorkerStats::default(),
+            tail: Tail::new(),
         }
     }
 
-    /// Process any queued tracing events
+    /// Batch messages according to `policy`, using `clock_micros` for its
+    /// deadline
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy, clock_micros: fn() -> u64) {
+        self.policy = policy;
+        self.clock = Some(clock_micros);
+    }
+
+    /// Give up on writes that take longer than `timeout` on `clock`, dropping
+    /// whatever's left of them and counting it in the [stats](Self::stats).
+    /// Without a timeout, a sink that never drains blocks the worker forever.
+    /// A message cut off partway leaves the host out of sync, like any other
+    /// lost trace data.
+    pub fn set_write_timeout(&mut self, clock: &'static dyn Clock, timeout: Duration) {
+        self.write_timeout = Some((clock, timeout));
+    }
+
+    /// Counters describing how well batching is working
+    pub fn stats(&self) -> WorkerStats {
+        self.stats
+    }
+
+    /// Copy the newest messages the worker has handled that fit into `buf`,
+    /// oldest first, returning how many bytes were copied. Only whole
+    /// messages are copied, so they can be decoded without the rest of the
+    /// trace, except for references to interned metadata.
+    pub fn copy_tail(&self, buf: &mut [u8]) -> usize {
+        self.tail.copy_to(buf)
+    }
+
+    /// Process any queued tracing events. They may stay batched afterwards,
+    /// depending on the [`FlushPolicy`].
     pub fn work(&mut self) {
+        let mut drained = 0;
         while let Some(event) = QUEUE.pop_ref() {
+            drained += 1;
             self.total_events += 1;
             if let Some(ref err) = event.error {
                 self.report_error(err);
             }
 
             if !event.data.is_empty() {
-                // Ignore I/O errors, since there's nowhere to report them anyways
                 // TODO: now that data is buffered anyways, use COBS for error recovery
-                let _ = self.writer.write_all(&event.data);
+                self.buffer(&event.data);
+            }
+        }
+
+        self.write_suppressed();
+
+        if self.batch.is_empty() {
+            return;
+        }
+        if drained == 0 {
+            self.write_batch(FlushReason::Idle);
+        } else if self.clock.map_or(true, |now| {
+            now().saturating_sub(self.batch_started) >= self.policy.idle_flush_us
+        }) {
+            self.write_batch(FlushReason::Deadline);
+        }
+    }
+
+    /// Process any queued tracing events, and write out everything
+    pub fn flush(&mut self) {
+        self.work();
+        if !self.batch.is_empty() {
+            self.write_batch(FlushReason::Forced);
+        }
+    }
+
+    /// Add a serialized message to the batch, writing the batch out first if
+    /// it's full
+    fn buffer(&mut self, data: &[u8]) {
+        self.stats.messages += 1;
+        self.tail.push(data);
+        let limit = self.policy.max_batch.min(BATCH_CAPACITY);
+        if !self.batch.is_empty() && self.batch.len() + data.len() > limit {
+            self.write_batch(FlushReason::Full);
+        }
+
+        if data.len() > limit {
+            self.write(data);
+            return;
+        }
+        if self.batch.is_empty() {
+            self.batch_started = self.clock.map_or(0, |now| now());
+        }
+        // Can't fail, since the batch is empty or has room
+        let _ = self.batch.extend_from_slice(data);
+    }
+
+    fn write_batch(&mut self, reason: FlushReason) {
+        let counter = match reason {
+            FlushReason::Full => &mut self.stats.full_flushes,
+            FlushReason::Idle => &mut self.stats.idle_flushes,
+            FlushReason::Deadline => &mut self.stats.deadline_flushes,
+            FlushReason::Forced => &mut self.stats.forced_flushes,
+        };
+        *counter += 1;
+
+        let timeout = self.write_timeout;
+        Self::send(&mut self.writer, timeout, &mut self.stats, &self.batch);
+        self.batch.clear();
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        Self::send(&mut self.writer, self.write_timeout, &mut self.stats, data);
+    }
+
+    /// Write `data` to `writer`, dropping the rest of it if the write times
+    /// out. This doesn't borrow the whole worker, so the batch can be written.
+    fn send(
+        writer: &mut W,
+        timeout: Option<(&'static dyn Clock, Duration)>,
+        stats: &mut WorkerStats,
+        data: &[u8],
+    ) {
+        stats.writes += 1;
+        let written = match timeout {
+            Some((clock, timeout)) => {
+                let deadline = Deadline::after(clock, timeout);
+                match writer.write_all_timeout(data, &deadline) {
+                    Err(TimeoutError::TimedOut { written }) => {
+                        stats.timeouts += 1;
+                        stats.dropped_bytes += (data.len() - written) as u64;
+                        written
+                    }
+                    // Ignore I/O errors, since there's nowhere to report them anyways
+                    _ => data.len(),
+                }
+            }
+            None => {
+                let _ = writer.write_all(data);
+                data.len()
             }
+        };
+        stats.bytes += written as u64;
+    }
+
+    /// Report how many spans and events rate limiting dropped
+    fn write_suppressed(&mut self) {
+        RATE_LIMITER.drain_suppressed(|metadata, count| {
+            self.write_message::<512, (), ()>(&proto::Message::Suppressed {
+                metadata: proto::Metadata::from_tracing(metadata),
+                count,
+            });
+        });
+    }
+
+    /// Send every declared schema to the host
+    fn write_schemas(&mut self) {
+        for schema in SCHEMAS {
+            self.write_message::<1024, (), ()>(&proto::Message::Schema(schema.clone()));
         }
     }
 
@@ -334,9 +828,7 @@ impl<W: Write> Worker<W> {
         msg: &proto::Message<'_, E, A>,
     ) {
         match postcard::to_vec::<_, CAP>(msg) {
-            Ok(data) => {
-                let _ = self.writer.write_all(&data);
-            }
+            Ok(data) => self.buffer(&data),
             Err(err) => {
                 #[cfg(debug_assertions)]
                 panic!("Internal write failed: {}", err);
@@ -363,9 +855,9 @@ impl<W: Write> Worker<W> {
     }
 }
 
-impl<W: Write> Drop for Worker<W> {
+impl<W: WriteTimeout> Drop for Worker<W> {
     fn drop(&mut self) {
         // Ensure any queued events are flushed on exit
-        self.work();
+        self.flush();
     }
 }
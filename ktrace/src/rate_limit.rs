@@ -0,0 +1,433 @@
+//! Per-callsite rate limiting.
+//!
+//! A hot trace point can produce spans and events faster than they can be
+//! written out, filling the queue and starving everything else. Callsites
+//! matched by a rate-limit directive get a token bucket: each span or event
+//! takes a token, and tokens refill at a fixed rate up to the bucket's burst
+//! size. Spans and events that find their bucket empty are dropped and counted,
+//! and the worker periodically reports the counts so the decoder can show how
+//! many were suppressed.
+//!
+//! Directives are comma-separated `target=N/s` pairs, like
+//! `platypos_kernel::mm=100/s`, which allows bursts of up to `N` and refills
+//! at `N` per second. A directive applies to its target module and everything
+//! under it, and the most specific directive wins.
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use platypos_common::sync::Global;
+use tracing_core::Metadata;
+
+/// Maximum number of rate-limited callsites. Callsites beyond this aren't
+/// limited.
+const MAX_CALLSITES: usize = 128;
+
+/// Maximum number of rate-limit directives
+const MAX_DIRECTIVES: usize = 16;
+
+const MICROS_PER_SECOND: u64 = 1_000_000;
+
+// Bucket state is packed into one word, so it can be updated atomically: the
+// time of the last refill, in microseconds, above the number of tokens left
+const TOKEN_BITS: u32 = 16;
+const TOKEN_MASK: u64 = (1 << TOKEN_BITS) - 1;
+const TIME_MASK: u64 = u64::MAX >> TOKEN_BITS;
+
+// A bucket's packed limit has this bit set once the bucket is ready to use
+const LIMIT_READY: u64 = 1 << 63;
+
+pub(crate) static RATE_LIMITER: RateLimiter = RateLimiter::new();
+
+/// Rate limit for one callsite
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Maximum number of spans or events that can be recorded at once
+    pub burst: u16,
+    /// Number of spans or events allowed per second, after the first burst
+    pub per_second: u16,
+}
+
+/// Applies a rate limit to every callsite in `target` or its submodules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Directive {
+    pub target: &'static str,
+    pub limit: RateLimit,
+}
+
+/// Error returned for malformed rate-limit directives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError(&'static str);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid rate-limit directive '{}'", self.0)
+    }
+}
+
+struct Config {
+    directives: heapless::Vec<Directive, MAX_DIRECTIVES>,
+    /// Monotonic clock, in microseconds
+    clock: fn() -> u64,
+}
+
+/// Configure rate limiting from comma-separated `target=N/s` directives.
+/// `clock` must return a monotonic timestamp in microseconds. This can only be
+/// called once.
+///
+/// # Errors
+/// If a directive is malformed, or there are too many of them.
+pub fn set_rate_limits(directives: &'static str, clock: fn() -> u64) -> Result<(), ParseError> {
+    let directives = parse_directives(directives)?;
+    RATE_LIMITER.config.init(Config { directives, clock });
+    // Callsites that were already registered need to be rechecked against the new directives
+    tracing_core::callsite::rebuild_interest_cache();
+    Ok(())
+}
+
+fn parse_directives(
+    directives: &'static str,
+) -> Result<heapless::Vec<Directive, MAX_DIRECTIVES>, ParseError> {
+    let mut parsed = heapless::Vec::new();
+    for directive in directives.split(',').map(str::trim) {
+        if directive.is_empty() {
+            continue;
+        }
+
+        let error = ParseError(directive);
+        let (target, limit) = directive.split_once('=').ok_or(error)?;
+        let count = limit
+            .trim()
+            .strip_suffix("/s")
+            .and_then(|count| count.parse().ok())
+            .ok_or(error)?;
+        parsed
+            .push(Directive {
+                target: target.trim(),
+                limit: RateLimit {
+                    burst: count,
+                    per_second: count,
+                },
+            })
+            .map_err(|_| error)?;
+    }
+    Ok(parsed)
+}
+
+pub(crate) struct RateLimiter {
+    config: Global<Config>,
+    buckets: [Bucket; MAX_CALLSITES],
+    /// Set when any bucket has a suppressed count to report
+    any_suppressed: AtomicBool,
+}
+
+struct Bucket {
+    /// The callsite's metadata, or null if the bucket is unclaimed. Claiming
+    /// the bucket sets this.
+    metadata: AtomicPtr<Metadata<'static>>,
+    /// Packed [`RateLimit`], published after the rest of the bucket is
+    /// initialized
+    limit: AtomicU64,
+    /// Packed refill time and token count
+    state: AtomicU64,
+    /// Number of spans and events dropped since the last report
+    suppressed: AtomicU64,
+}
+
+impl RateLimiter {
+    const fn new() -> Self {
+        Self {
+            config: Global::new(),
+            buckets: [const { Bucket::new() }; MAX_CALLSITES],
+            any_suppressed: AtomicBool::new(false),
+        }
+    }
+
+    /// Set up a bucket for `metadata`'s callsite if it's rate-limited. Returns
+    /// whether it's rate-limited, in which case every span or event from it
+    /// must go through [`RateLimiter::check`].
+    pub(crate) fn register(&self, metadata: &'static Metadata<'static>) -> bool {
+        let Some(config) = self.config.try_get() else {
+            return false;
+        };
+        let Some(limit) = limit_for(&config.directives, metadata.target()) else {
+            return false;
+        };
+
+        let key = metadata as *const Metadata<'static> as *mut Metadata<'static>;
+        for bucket in self.probe(key) {
+            match bucket.metadata.compare_exchange(
+                ptr::null_mut(),
+                key,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    bucket.init(limit, (config.clock)());
+                    return true;
+                }
+                // Interest is rebuilt by re-registering every callsite
+                Err(owner) if owner == key => return true,
+                Err(_) => continue,
+            }
+        }
+
+        // Out of buckets, so this callsite can't be limited
+        false
+    }
+
+    /// Take a token for a span or event from `metadata`'s callsite, returning
+    /// whether it should be recorded
+    pub(crate) fn check(&self, metadata: &Metadata<'_>) -> bool {
+        let Some(config) = self.config.try_get() else {
+            return true;
+        };
+        let key = metadata as *const Metadata<'_> as *mut Metadata<'static>;
+        let Some(bucket) = self
+            .probe(key)
+            .map(|b| (b, b.metadata.load(Ordering::Acquire)))
+            .take_while(|(_, owner)| !owner.is_null())
+            .find_map(|(b, owner)| (owner == key).then_some(b))
+        else {
+            return true;
+        };
+
+        if bucket.take((config.clock)()) {
+            true
+        } else {
+            bucket.suppressed.fetch_add(1, Ordering::Relaxed);
+            self.any_suppressed.store(true, Ordering::Release);
+            false
+        }
+    }
+
+    /// Call `report` with the number of spans and events suppressed from each
+    /// callsite since the last call
+    pub(crate) fn drain_suppressed(&self, mut report: impl FnMut(&'static Metadata<'static>, u64)) {
+        if !self.any_suppressed.swap(false, Ordering::Acquire) {
+            return;
+        }
+
+        for bucket in &self.buckets {
+            let metadata = bucket.metadata.load(Ordering::Acquire);
+            if metadata.is_null() {
+                continue;
+            }
+            let count = bucket.suppressed.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                // SAFETY: buckets only hold pointers to 'static callsite metadata
+                report(unsafe { &*metadata }, count);
+            }
+        }
+    }
+
+    /// Buckets to try for `key`, in order
+    fn probe(&self, key: *mut Metadata<'static>) -> impl Iterator<Item = &Bucket> {
+        // Metadata is at least word-aligned, so skip the low bits
+        let start = (key as usize >> 3) % MAX_CALLSITES;
+        (0..MAX_CALLSITES).map(move |i| &self.buckets[(start + i) % MAX_CALLSITES])
+    }
+}
+
+impl Bucket {
+    const fn new() -> Self {
+        Self {
+            metadata: AtomicPtr::new(ptr::null_mut()),
+            limit: AtomicU64::new(0),
+            state: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Fill a just-claimed bucket as of `now`, and mark it ready
+    fn init(&self, limit: RateLimit, now: u64) {
+        let now = now & TIME_MASK;
+        self.state.store(
+            (now << TOKEN_BITS) | u64::from(limit.burst),
+            Ordering::Relaxed,
+        );
+        self.limit.store(
+            LIMIT_READY | (u64::from(limit.burst) << 32) | u64::from(limit.per_second),
+            Ordering::Release,
+        );
+    }
+
+    /// Refill the bucket as of `now` and try to take a token from it
+    fn take(&self, now: u64) -> bool {
+        let limit = self.limit.load(Ordering::Acquire);
+        if limit & LIMIT_READY == 0 {
+            // Another processor is still setting the bucket up
+            return true;
+        }
+        let burst = (limit >> 32) & 0xffff;
+        let per_second = limit & 0xffff_ffff;
+        let now = now & TIME_MASK;
+
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            let mut last = state >> TOKEN_BITS;
+            let mut tokens = state & TOKEN_MASK;
+
+            if per_second > 0 && now > last {
+                let refill = (now - last).saturating_mul(per_second) / MICROS_PER_SECOND;
+                if tokens + refill >= burst {
+                    tokens = burst;
+                    last = now;
+                } else if refill > 0 {
+                    tokens += refill;
+                    // Keep the remainder, so slow trickles still refill
+                    last += refill * MICROS_PER_SECOND / per_second;
+                }
+            }
+
+            if tokens == 0 {
+                return false;
+            }
+
+            let new = (last << TOKEN_BITS) | (tokens - 1);
+            match self
+                .state
+                .compare_exchange_weak(state, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+}
+
+/// Find the most specific directive matching `target`
+fn limit_for(directives: &[Directive], target: &str) -> Option<RateLimit> {
+    directives
+        .iter()
+        .filter(|d| {
+            target
+                .strip_prefix(d.target)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|d| d.target.len())
+        .map(|d| d.limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    const SECOND: u64 = MICROS_PER_SECOND;
+
+    fn limit(burst: u16, per_second: u16) -> RateLimit {
+        RateLimit { burst, per_second }
+    }
+
+    fn directive(target: &'static str, burst: u16, per_second: u16) -> Directive {
+        Directive {
+            target,
+            limit: limit(burst, per_second),
+        }
+    }
+
+    fn bucket(limit: RateLimit, now: u64) -> Bucket {
+        let bucket = Bucket::new();
+        bucket.init(limit, now);
+        bucket
+    }
+
+    #[test]
+    fn test_take_burst() {
+        let bucket = bucket(limit(3, 1), 10 * SECOND);
+        for _ in 0..3 {
+            assert!(bucket.take(10 * SECOND));
+        }
+        assert!(!bucket.take(10 * SECOND));
+    }
+
+    #[test]
+    fn test_take_refills() {
+        let bucket = bucket(limit(2, 2), 0);
+        assert!(bucket.take(0));
+        assert!(bucket.take(0));
+        assert!(!bucket.take(SECOND / 4));
+
+        // Half a second at 2/s refills one token
+        assert!(bucket.take(SECOND / 2));
+        assert!(!bucket.take(SECOND / 2));
+
+        // The refill is capped at the burst size
+        assert!(bucket.take(10 * SECOND));
+        assert!(bucket.take(10 * SECOND));
+        assert!(!bucket.take(10 * SECOND));
+    }
+
+    #[test]
+    fn test_take_keeps_partial_refill() {
+        let bucket = bucket(limit(2, 1), 0);
+        assert!(bucket.take(0));
+        assert!(bucket.take(0));
+        // 1.5s refills one token and leaves half a second toward the next
+        assert!(bucket.take(3 * SECOND / 2));
+        assert!(bucket.take(2 * SECOND));
+        assert!(!bucket.take(2 * SECOND));
+    }
+
+    #[test]
+    fn test_take_without_refill() {
+        let bucket = bucket(limit(1, 0), 0);
+        assert!(bucket.take(0));
+        assert!(!bucket.take(100 * SECOND));
+    }
+
+    #[test]
+    fn test_take_unready() {
+        // A bucket that's claimed but not set up yet lets everything through
+        let bucket = Bucket::new();
+        assert!(bucket.take(0));
+    }
+
+    #[test]
+    fn test_parse_directives() {
+        let parsed = parse_directives(" platypos_kernel::mm=100/s,, sched = 5/s ").unwrap();
+        assert_eq!(
+            parsed,
+            [
+                directive("platypos_kernel::mm", 100, 100),
+                directive("sched", 5, 5)
+            ]
+        );
+        assert!(parse_directives("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_directives_errors() {
+        assert_eq!(parse_directives("mm"), Err(ParseError("mm")));
+        assert_eq!(parse_directives("mm=100"), Err(ParseError("mm=100")));
+        assert_eq!(parse_directives("a=1/s,mm=x/s"), Err(ParseError("mm=x/s")));
+        assert_eq!(
+            parse_directives("mm=70000/s"),
+            Err(ParseError("mm=70000/s"))
+        );
+
+        let too_many = vec!["a=1/s"; MAX_DIRECTIVES + 1].join(",").leak();
+        assert_eq!(parse_directives(too_many), Err(ParseError("a=1/s")));
+    }
+
+    #[test]
+    fn test_limit_for() {
+        let directives = [
+            directive("platypos_kernel", 100, 100),
+            directive("platypos_kernel::mm", 10, 10),
+            directive("platypos_kernel::mm::bootmem", 1, 1),
+        ];
+        let burst_for = |target| limit_for(&directives, target).map(|limit| limit.burst);
+        assert_eq!(burst_for("platypos_kernel"), Some(100));
+        assert_eq!(burst_for("platypos_kernel::sched"), Some(100));
+        assert_eq!(burst_for("platypos_kernel::mm::root_allocator"), Some(10));
+        assert_eq!(burst_for("platypos_kernel::mm::bootmem"), Some(1));
+        // Targets only match whole path segments
+        assert_eq!(burst_for("platypos_kernel::mmio"), Some(100));
+        assert_eq!(burst_for("platypos_kernel_test"), None);
+        assert_eq!(burst_for("platypos_hal"), None);
+    }
+}
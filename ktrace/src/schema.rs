@@ -0,0 +1,138 @@
+//! Typed span and event declarations.
+
+use linkme::distributed_slice;
+
+use crate::proto;
+
+/// Every schema declared with [`schema!`](crate::schema), sent to the host when
+/// tracing starts
+#[distributed_slice]
+pub static SCHEMAS: [proto::Schema<'static>] = [..];
+
+/// Declare spans and events with a fixed set of typed fields.
+///
+/// Each declaration generates a function of the same name which takes one
+/// argument per field. Event functions record the event, and span functions
+/// return a new [`Span`](tracing::Span). Field types are:
+/// * `u64`: an integer
+/// * `str`: a string
+/// * `addr`, `paddr`, and `kaddr`: virtual, physical, and kernel code
+///   addresses
+/// * `bytes`: a size in bytes
+/// * `hex`: a byte slice, like a MAC address or hash
+/// * `u64s`: a short array of integers, like a register dump
+///
+/// Every field must be a known field of the same type, which is checked at
+/// compile time. The declarations are sent to the decoder when tracing
+/// starts, so it can check that recorded spans and events match.
+///
+/// ```ignore
+/// ktrace::schema! {
+///     /// A page of memory was mapped
+///     pub event page_mapped(DEBUG) {
+///         vaddr: addr,
+///         paddr: paddr,
+///     }
+///
+///     pub span heap_grow(TRACE) {
+///         size: bytes,
+///     }
+/// }
+///
+/// page_mapped(0x1000, 0x2000);
+/// let _span = heap_grow(4096).entered();
+/// ```
+#[macro_export]
+macro_rules! schema {
+    ($(
+        $(#[$meta:meta])*
+        $vis:vis $kind:ident $name:ident($level:ident) {
+            $($field:ident: $ty:ident),* $(,)?
+        }
+    )*) => {
+        $(
+            $crate::schema!(@item $(#[$meta])* $vis $kind $name($level) { $($field: $ty),* });
+        )*
+    };
+
+    (@item $(#[$meta:meta])* $vis:vis event $name:ident($level:ident) { $($field:ident: $ty:ident),* }) => {
+        $(#[$meta])*
+        $vis fn $name($($field: $crate::schema!(@rust_type $ty)),*) {
+            $crate::tracing::event!(
+                name: stringify!($name),
+                $crate::tracing::Level::$level,
+                $($field = $crate::schema!(@value $ty $field)),*
+            );
+        }
+
+        $crate::schema!(@register Event $name { $($field: $ty),* });
+    };
+
+    (@item $(#[$meta:meta])* $vis:vis span $name:ident($level:ident) { $($field:ident: $ty:ident),* }) => {
+        $(#[$meta])*
+        #[must_use]
+        $vis fn $name($($field: $crate::schema!(@rust_type $ty)),*) -> $crate::tracing::Span {
+            $crate::tracing::span!(
+                $crate::tracing::Level::$level,
+                stringify!($name),
+                $($field = $crate::schema!(@value $ty $field)),*
+            )
+        }
+
+        $crate::schema!(@register Span $name { $($field: $ty),* });
+    };
+
+    (@register $kind:ident $name:ident { $($field:ident: $ty:ident),* }) => {
+        const _: () = {
+            $(
+                assert!(
+                    $crate::proto::FieldType::is_declared_as(
+                        stringify!($field),
+                        $crate::schema!(@field_type $ty),
+                    ),
+                    concat!("`", stringify!($field), "` is not a known `", stringify!($ty), "` field"),
+                );
+            )*
+
+            #[$crate::linkme::distributed_slice($crate::SCHEMAS)]
+            #[linkme(crate = $crate::linkme)]
+            static SCHEMA: $crate::proto::Schema<'static> = $crate::proto::Schema {
+                name: stringify!($name),
+                kind: $crate::proto::SchemaKind::$kind,
+                fields: $crate::proto::SchemaFields::Static(&[$(
+                    $crate::proto::FieldDecl {
+                        name: stringify!($field),
+                        ty: $crate::schema!(@field_type $ty),
+                    }
+                ),*]),
+            };
+        };
+    };
+
+    (@rust_type u64) => { u64 };
+    (@rust_type str) => { &str };
+    (@rust_type addr) => { u64 };
+    (@rust_type paddr) => { u64 };
+    (@rust_type kaddr) => { u64 };
+    (@rust_type bytes) => { u64 };
+    (@rust_type hex) => { &[u8] };
+    (@rust_type u64s) => { &[u64] };
+
+    // Slices go through their `Debug` implementations, since `tracing` can't record them directly
+    (@value hex $value:ident) => {
+        $crate::tracing::field::debug($crate::proto::ByteSlice($value))
+    };
+    (@value u64s $value:ident) => {
+        $crate::tracing::field::debug($crate::proto::U64Slice($value))
+    };
+    (@value $ty:ident $value:ident) => { $value };
+
+    (@field_type u64) => { $crate::proto::FieldType::U64 };
+    (@field_type str) => { $crate::proto::FieldType::String };
+    (@field_type addr) => { $crate::proto::FieldType::VirtualAddress };
+    (@field_type paddr) => { $crate::proto::FieldType::PhysicalAddress };
+    (@field_type kaddr) => { $crate::proto::FieldType::KernelAddress };
+    (@field_type bytes) => { $crate::proto::FieldType::ByteSize };
+    (@field_type hex) => { $crate::proto::FieldType::Bytes };
+    (@field_type u64s) => { $crate::proto::FieldType::U64Array };
+}
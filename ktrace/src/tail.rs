@@ -0,0 +1,109 @@
+//! The most recent messages the worker wrote.
+//!
+//! If the kernel crashes without a host on the other end of the serial port,
+//! everything it wrote is lost. The worker keeps its last few messages in a
+//! [`Tail`], so they can be saved in a crash dump and sent on the next boot.
+//! Messages are kept whole, so a copy of the tail can be decoded on its own.
+
+/// Bytes of messages, including their length prefixes, that the tail holds
+const TAIL_CAPACITY: usize = 1024;
+
+/// Length prefix before each message in the ring
+const PREFIX_LEN: usize = 2;
+
+/// Ring buffer of whole serialized messages, each stored after a
+/// little-endian `u16` length. Adding a message drops the oldest ones until
+/// it fits.
+pub(crate) struct Tail {
+    ring: [u8; TAIL_CAPACITY],
+    /// Offset of the oldest message's length prefix
+    start: usize,
+    /// Bytes in use, starting at `start` and wrapping around
+    len: usize,
+}
+
+impl Tail {
+    pub(crate) const fn new() -> Self {
+        Self {
+            ring: [0; TAIL_CAPACITY],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Add a message. Messages too big for the tail are left out.
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        let needed = PREFIX_LEN + data.len();
+        if needed > TAIL_CAPACITY {
+            return;
+        }
+        while self.len + needed > TAIL_CAPACITY {
+            let dropped = PREFIX_LEN + self.message_len(self.start);
+            self.start = (self.start + dropped) % TAIL_CAPACITY;
+            self.len -= dropped;
+        }
+
+        let end = (self.start + self.len) % TAIL_CAPACITY;
+        self.copy_in(end, &(data.len() as u16).to_le_bytes());
+        self.copy_in((end + PREFIX_LEN) % TAIL_CAPACITY, data);
+        self.len += needed;
+    }
+
+    /// Copy the newest messages that fit into `buf`, oldest first and
+    /// without their length prefixes, returning how many bytes were copied
+    pub(crate) fn copy_to(&self, buf: &mut [u8]) -> usize {
+        // Skip the oldest messages until the rest fit
+        let mut offset = self.start;
+        let mut remaining = self.len;
+        let mut total = self.len - self.count() * PREFIX_LEN;
+        while total > buf.len() {
+            let len = self.message_len(offset);
+            offset = (offset + PREFIX_LEN + len) % TAIL_CAPACITY;
+            remaining -= PREFIX_LEN + len;
+            total -= len;
+        }
+
+        let mut copied = 0;
+        while remaining > 0 {
+            let len = self.message_len(offset);
+            let data = (offset + PREFIX_LEN) % TAIL_CAPACITY;
+            self.copy_out(data, &mut buf[copied..copied + len]);
+            copied += len;
+            offset = (offset + PREFIX_LEN + len) % TAIL_CAPACITY;
+            remaining -= PREFIX_LEN + len;
+        }
+        copied
+    }
+
+    /// Number of messages in the tail
+    fn count(&self) -> usize {
+        let (mut offset, mut remaining, mut count) = (self.start, self.len, 0);
+        while remaining > 0 {
+            let len = PREFIX_LEN + self.message_len(offset);
+            offset = (offset + len) % TAIL_CAPACITY;
+            remaining -= len;
+            count += 1;
+        }
+        count
+    }
+
+    /// Length of the message whose prefix starts at `offset`
+    fn message_len(&self, offset: usize) -> usize {
+        let mut prefix = [0; PREFIX_LEN];
+        self.copy_out(offset, &mut prefix);
+        u16::from_le_bytes(prefix) as usize
+    }
+
+    fn copy_in(&mut self, offset: usize, data: &[u8]) {
+        let first = data.len().min(TAIL_CAPACITY - offset);
+        self.ring[offset..offset + first].copy_from_slice(&data[..first]);
+        self.ring[..data.len() - first].copy_from_slice(&data[first..]);
+    }
+
+    fn copy_out(&self, offset: usize, buf: &mut [u8]) {
+        let first = buf.len().min(TAIL_CAPACITY - offset);
+        buf[..first].copy_from_slice(&self.ring[offset..offset + first]);
+        let rest = buf.len() - first;
+        buf[first..].copy_from_slice(&self.ring[..rest]);
+    }
+}
@@ -6,76 +6,69 @@
 use core::sync::atomic::Ordering;
 
 use hal::topology::PerProcessor;
-use modular_bitfield::bitfield;
-use modular_bitfield::specifiers::{B18, B46};
 use platypos_hal as hal;
 
+use crate::idx::{Packing, Word};
 use crate::sync::AtomicU64;
 use crate::Slot;
 
 /// Concurrent, global free list.
-pub(crate) struct GlobalFreeList {
+///
+/// Entries in the list pack a slot number into the low `INDEX_BITS` bits and
+/// a tag into the rest, so an entry with a tag of 0 is just a slot number.
+pub(crate) struct GlobalFreeList<W: Word, const INDEX_BITS: u32> {
     /// The top of the free stack
-    head: AtomicU64,
-    /// Counter incremented on every push to avoid ABA issues
+    head: W::Atomic,
+    /// Counter incremented on every push to avoid ABA issues. Only as many
+    /// bits as a generation are used.
     tag: AtomicU64,
 }
 
-#[bitfield]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[repr(u64)]
-#[allow(dead_code, clippy::identity_op)]
-struct Entry {
-    index: B18,
-    #[allow(unused)]
-    tag: B46,
-}
-
 /// Per-core free list.
 pub(crate) struct LocalFreeList<TP: hal::topology::Topology> {
     free_lists: PerProcessor<usize, TP>,
 }
 
-/// Tag for if a free list is empty.
-pub const EMPTY: u64 = u64::MAX;
+impl<W: Word, const INDEX_BITS: u32> GlobalFreeList<W, INDEX_BITS> {
+    /// Tag for if a free list is empty.
+    pub const EMPTY: u64 = Packing::<W, INDEX_BITS>::EMPTY;
 
-impl GlobalFreeList {
     pub fn new(head: u64) -> Self {
         Self {
-            head: AtomicU64::new(head),
+            head: W::new_atomic(head),
             tag: AtomicU64::new(0),
         }
     }
 
     pub fn new_empty() -> Self {
-        Self::new(EMPTY)
+        Self::new(Self::EMPTY)
     }
 
     /// Push slot `index` onto the global free list.
-    pub fn push<T>(&self, index: usize, storage: &[Slot<T>]) {
+    pub fn push<T>(&self, index: usize, storage: &[Slot<T, W, INDEX_BITS>]) {
         // TODO: this should verify that `index` is not allocated and not already in a
         // free list
 
+        // Only the low bits of the tag are kept, so it wraps around on its own
         let new_tag = self.tag.fetch_add(1, Ordering::Relaxed);
-        // TODO: wrap on tag overflow (1<<46)
-
-        let new_head: u64 = Entry::new()
-            .with_index(index.try_into().unwrap())
-            .with_tag(new_tag)
-            .into();
+        let new_head = Packing::<W, INDEX_BITS>::entry(index, new_tag);
 
         loop {
-            let old_head = self.head.load(Ordering::Acquire);
+            let old_head = W::load(&self.head, Ordering::Acquire);
             // Safety: `index` points to a slot being added to the free list, so we know
             // it's not referenced by anyone else
             unsafe {
                 storage[index].set_next(old_head);
             }
 
-            if self
-                .head
-                .compare_exchange(old_head, new_head, Ordering::AcqRel, Ordering::Acquire)
-                .is_ok()
+            if W::compare_exchange(
+                &self.head,
+                old_head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
             {
                 break;
             }
@@ -83,22 +76,26 @@ impl GlobalFreeList {
     }
 
     /// Pop an element off the global free list
-    pub fn pop<T>(&self, storage: &[Slot<T>]) -> Option<usize> {
+    pub fn pop<T>(&self, storage: &[Slot<T, W, INDEX_BITS>]) -> Option<usize> {
         loop {
-            let old_head = self.head.load(Ordering::Acquire);
-            if old_head == EMPTY {
+            let old_head = W::load(&self.head, Ordering::Acquire);
+            if old_head == Self::EMPTY {
                 return None;
             }
 
-            let old_head_index = Entry::from(old_head).index() as usize;
+            let old_head_index = Packing::<W, INDEX_BITS>::entry_index(old_head);
             // Safety: we got old_head_index from the free list, so we know it's not
             // allocated and has a valid next pointer. Use of next is safe per the Treiber
             // algorithm (we read it here, then CAS)
             let new_head = unsafe { storage[old_head_index].next() };
-            if self
-                .head
-                .compare_exchange(old_head, new_head, Ordering::AcqRel, Ordering::Acquire)
-                .is_ok()
+            if W::compare_exchange(
+                &self.head,
+                old_head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
             {
                 return Some(old_head_index);
             }
@@ -114,11 +111,15 @@ impl<TP: hal::topology::Topology> LocalFreeList<TP> {
     }
 
     /// Push slot `index` onto the local free list
-    pub fn push<T>(&self, index: usize, storage: &[Slot<T>]) {
+    pub fn push<T, W: Word, const INDEX_BITS: u32>(
+        &self,
+        index: usize,
+        storage: &[Slot<T, W, INDEX_BITS>],
+    ) {
         // TODO: this should verify that `index` is not allocated and not already in a
         // free list
         self.free_lists.with_mut(|head| {
-            let old_head = head.map(|v| v as u64).unwrap_or(EMPTY);
+            let old_head = head.map_or(GlobalFreeList::<W, INDEX_BITS>::EMPTY, |v| v as u64);
             // Safety: `index` points to a slot being added to the free list, so we know
             // it's not referenced by anyone else
             unsafe {
@@ -129,10 +130,13 @@ impl<TP: hal::topology::Topology> LocalFreeList<TP> {
     }
 
     /// Pop a slot off the local free list
-    pub fn pop<T>(&self, storage: &[Slot<T>]) -> Option<usize> {
+    pub fn pop<T, W: Word, const INDEX_BITS: u32>(
+        &self,
+        storage: &[Slot<T, W, INDEX_BITS>],
+    ) -> Option<usize> {
         self.free_lists.with_mut(|head| {
             let Some(old_head) = *head else { return None };
-            if old_head == EMPTY as usize {
+            if old_head as u64 == GlobalFreeList::<W, INDEX_BITS>::EMPTY {
                 None
             } else {
                 // Safety: we got old_head from the local free list, so know it has a valid next
@@ -146,15 +150,18 @@ impl<TP: hal::topology::Topology> LocalFreeList<TP> {
 
 #[cfg(all(loom, test))]
 mod test {
-    use super::{GlobalFreeList, EMPTY};
+    use super::GlobalFreeList;
     use crate::slot::Slot;
 
     use loom::sync::Arc;
 
+    type List = GlobalFreeList<u64, 18>;
+    const EMPTY: u64 = List::EMPTY;
+
     #[test]
     fn test_single_processor() {
         loom::model(|| {
-            let storage: [Slot<()>; 6] = [
+            let storage: [Slot<(), u64, 18>; 6] = [
                 Slot::new_unallocated(EMPTY),
                 Slot::new_unallocated(EMPTY),
                 Slot::new_unallocated(EMPTY),
@@ -163,7 +170,7 @@ mod test {
                 Slot::new_unallocated(EMPTY),
             ];
 
-            let free_list = GlobalFreeList::new_empty();
+            let free_list = List::new_empty();
 
             free_list.push(1, &storage);
             assert_eq!(free_list.pop(&storage), Some(1));
@@ -183,14 +190,14 @@ mod test {
     #[test]
     fn test_concurrent_push() {
         loom::model(|| {
-            let storage: [Slot<()>; 4] = [
+            let storage: [Slot<(), u64, 18>; 4] = [
                 Slot::new_unallocated(EMPTY),
                 Slot::new_unallocated(EMPTY),
                 Slot::new_unallocated(EMPTY),
                 Slot::new_unallocated(EMPTY),
             ];
             let storage = Arc::new(storage);
-            let free_list = Arc::new(GlobalFreeList::new_empty());
+            let free_list = Arc::new(List::new_empty());
 
             let t1 = {
                 let storage = storage.clone();
@@ -226,10 +233,10 @@ mod test {
     #[test]
     fn test_concurrent_pop() {
         loom::model(|| {
-            let storage: [Slot<()>; 2] =
+            let storage: [Slot<(), u64, 18>; 2] =
                 [Slot::new_unallocated(EMPTY), Slot::new_unallocated(EMPTY)];
             let storage = Arc::new(storage);
-            let free_list = Arc::new(GlobalFreeList::new_empty());
+            let free_list = Arc::new(List::new_empty());
             free_list.push(0, &*storage);
             free_list.push(1, &*storage);
 
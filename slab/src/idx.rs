@@ -0,0 +1,287 @@
+//! Packed slab indices.
+//!
+//! An [`Idx`] packs a slot number and that slot's generation into a single
+//! [`Word`]: the slot number takes the top `INDEX_BITS` bits, and the
+//! generation the rest. Slot lifecycles and free list links are packed into
+//! the same type, so the choice of word and index width is a trade-off
+//! between memory and headroom:
+//! * `INDEX_BITS` bounds the slab's size, and each slot's reference count has
+//!   two bits fewer (the other two hold the slot's state).
+//! * Generations wrap around after `2^(W::BITS - INDEX_BITS) - 1` reuses of a
+//!   slot, after which a stale index could be mistaken for a live one.
+//!
+//! The default, a `u64` with 18 index bits, suits large slabs. A slab of a
+//! few dozen entries can use `u32` throughout, which roughly halves its
+//! per-slot bookkeeping.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+
+use crate::sync::{AtomicU32, AtomicU64};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Unsigned integer type that a slab packs its indices, slot lifecycles, and
+/// free list links into. This is implemented for `u32` and `u64`.
+pub trait Word: sealed::Sealed + Copy + Eq + fmt::Debug + Send + Sync + 'static {
+    const BITS: u32;
+
+    #[doc(hidden)]
+    type Atomic: Send + Sync;
+
+    /// Truncate `value` to this type's width
+    fn from_u64(value: u64) -> Self;
+
+    fn to_u64(self) -> u64;
+
+    #[doc(hidden)]
+    fn new_atomic(value: u64) -> Self::Atomic;
+
+    #[doc(hidden)]
+    fn load(atomic: &Self::Atomic, order: Ordering) -> u64;
+
+    #[doc(hidden)]
+    fn store(atomic: &Self::Atomic, value: u64, order: Ordering);
+
+    #[doc(hidden)]
+    fn compare_exchange(
+        atomic: &Self::Atomic,
+        current: u64,
+        new: u64,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<u64, u64>;
+}
+
+macro_rules! impl_word {
+    ($ty:ty, $atomic:ty) => {
+        impl sealed::Sealed for $ty {}
+
+        impl Word for $ty {
+            const BITS: u32 = <$ty>::BITS;
+
+            type Atomic = $atomic;
+
+            fn from_u64(value: u64) -> Self {
+                value as $ty
+            }
+
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+
+            fn new_atomic(value: u64) -> Self::Atomic {
+                <$atomic>::new(value as $ty)
+            }
+
+            fn load(atomic: &Self::Atomic, order: Ordering) -> u64 {
+                atomic.load(order) as u64
+            }
+
+            fn store(atomic: &Self::Atomic, value: u64, order: Ordering) {
+                atomic.store(value as $ty, order)
+            }
+
+            fn compare_exchange(
+                atomic: &Self::Atomic,
+                current: u64,
+                new: u64,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<u64, u64> {
+                atomic
+                    .compare_exchange(current as $ty, new as $ty, success, failure)
+                    .map(|v| v as u64)
+                    .map_err(|v| v as u64)
+            }
+        }
+    };
+}
+
+impl_word!(u32, AtomicU32);
+impl_word!(u64, AtomicU64);
+
+/// Bits of a slot's lifecycle taken by its state
+pub(crate) const STATE_BITS: u32 = 2;
+
+/// Fewest index bits a slab can use, leaving room for a reference count of at
+/// least 15
+const MIN_INDEX_BITS: u32 = STATE_BITS + 4;
+
+/// How values are packed into `W` for a slab with `INDEX_BITS`-bit slot
+/// numbers
+pub(crate) struct Packing<W, const INDEX_BITS: u32>(PhantomData<W>);
+
+impl<W: Word, const INDEX_BITS: u32> Packing<W, INDEX_BITS> {
+    /// Bits of generation in an index and in a slot's lifecycle
+    pub(crate) const GENERATION_BITS: u32 = W::BITS - INDEX_BITS;
+
+    pub(crate) const GENERATION_MASK: u64 = mask(Self::GENERATION_BITS);
+
+    /// Bits of reference count in a slot's lifecycle
+    pub(crate) const REFCOUNT_BITS: u32 = INDEX_BITS - STATE_BITS;
+
+    /// Marks an empty free list. Its slot number is all ones, which is never a
+    /// valid slot, since slabs must have fewer than `2^INDEX_BITS` of them.
+    pub(crate) const EMPTY: u64 = mask(W::BITS);
+
+    /// Evaluating this fails to compile if the layout is unusable
+    pub(crate) const CHECK: () = {
+        assert!(INDEX_BITS < W::BITS, "indices need some generation bits");
+        assert!(
+            INDEX_BITS >= MIN_INDEX_BITS,
+            "too few index bits for a useful reference count"
+        );
+    };
+
+    /// Whether a slab with `size` slots fits
+    pub(crate) const fn fits(size: usize) -> bool {
+        (size as u64) < (1 << INDEX_BITS)
+    }
+
+    /// Pack slot number `index` with `generation`, which is truncated to fit
+    pub(crate) fn pack(index: usize, generation: u64) -> u64 {
+        ((index as u64) << Self::GENERATION_BITS) | (generation & Self::GENERATION_MASK)
+    }
+
+    pub(crate) fn index(packed: u64) -> usize {
+        (packed >> Self::GENERATION_BITS) as usize & mask(INDEX_BITS) as usize
+    }
+
+    pub(crate) fn generation(packed: u64) -> u64 {
+        packed & Self::GENERATION_MASK
+    }
+
+    /// Pack slot number `index` with a free list `tag`, which is truncated to
+    /// fit. Unlike indices, the slot number is in the low bits, so an entry
+    /// with a tag of 0 is the plain slot number.
+    pub(crate) fn entry(index: usize, tag: u64) -> u64 {
+        (index as u64) | ((tag & Self::GENERATION_MASK) << INDEX_BITS)
+    }
+
+    pub(crate) fn entry_index(entry: u64) -> usize {
+        (entry & mask(INDEX_BITS)) as usize
+    }
+
+    /// The generation after `generation`. This wraps around, but skips 0, so
+    /// a packed index is never 0.
+    pub(crate) fn next_generation(generation: u64) -> u64 {
+        match (generation + 1) & Self::GENERATION_MASK {
+            0 => 1,
+            next => next,
+        }
+    }
+}
+
+/// A mask of the low `bits` bits
+pub(crate) const fn mask(bits: u32) -> u64 {
+    if bits >= u64::BITS {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// Index pointing to a slab allocation. Indices are never 0.
+pub struct Idx<W: Word = u64, const INDEX_BITS: u32 = 18> {
+    bits: W,
+}
+
+impl<W: Word, const INDEX_BITS: u32> Idx<W, INDEX_BITS> {
+    pub(crate) fn new(index: usize, generation: u64) -> Self {
+        Self::from_bits(W::from_u64(Packing::<W, INDEX_BITS>::pack(
+            index, generation,
+        )))
+    }
+
+    pub fn from_bits(bits: W) -> Self {
+        Self { bits }
+    }
+
+    pub fn into_bits(self) -> W {
+        self.bits
+    }
+
+    /// The slot this index points to
+    pub fn index(self) -> usize {
+        Packing::<W, INDEX_BITS>::index(self.bits.to_u64())
+    }
+
+    /// The generation of the slot this index was allocated in
+    pub fn generation(self) -> u64 {
+        Packing::<W, INDEX_BITS>::generation(self.bits.to_u64())
+    }
+}
+
+impl<W: Word, const INDEX_BITS: u32> Clone for Idx<W, INDEX_BITS> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<W: Word, const INDEX_BITS: u32> Copy for Idx<W, INDEX_BITS> {}
+
+impl<W: Word, const INDEX_BITS: u32> PartialEq for Idx<W, INDEX_BITS> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits == other.bits
+    }
+}
+
+impl<W: Word, const INDEX_BITS: u32> Eq for Idx<W, INDEX_BITS> {}
+
+impl<W: Word, const INDEX_BITS: u32> fmt::Debug for Idx<W, INDEX_BITS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Idx")
+            .field("index", &self.index())
+            .field("generation", &self.generation())
+            .finish()
+    }
+}
+
+impl<W: Word, const INDEX_BITS: u32> From<Idx<W, INDEX_BITS>> for u64 {
+    fn from(idx: Idx<W, INDEX_BITS>) -> Self {
+        idx.bits.to_u64()
+    }
+}
+
+/// Bits past the width of `W` are ignored, so this is only meaningful for
+/// values that came from an [`Idx`] of the same type.
+impl<W: Word, const INDEX_BITS: u32> From<u64> for Idx<W, INDEX_BITS> {
+    fn from(bits: u64) -> Self {
+        Self::from_bits(W::from_u64(bits))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_layout() {
+        // Same layout as the original bitfield: a 46-bit generation under an 18-bit index
+        let idx = Idx::<u64, 18>::new(5, 7);
+        assert_eq!(u64::from(idx), (5 << 46) | 7);
+        assert_eq!((idx.index(), idx.generation()), (5, 7));
+        assert_eq!(Idx::<u64, 18>::from(u64::from(idx)), idx);
+    }
+
+    #[test]
+    fn test_compact_layout() {
+        type P = Packing<u32, 8>;
+        assert_eq!((P::GENERATION_BITS, P::REFCOUNT_BITS), (24, 6));
+        assert!(P::fits(255) && !P::fits(256));
+
+        let idx = Idx::<u32, 8>::new(200, 0x1ff_ffff);
+        assert_eq!((idx.index(), idx.generation()), (200, 0xff_ffff));
+        assert_eq!(P::entry_index(P::EMPTY), 255);
+        assert_eq!(P::entry(3, 0), 3);
+        assert_eq!(P::entry_index(P::entry(3, 0x1ff_ffff)), 3);
+
+        // Generations wrap, but never back to 0
+        assert_eq!(P::next_generation(0xff_fffe), 0xff_ffff);
+        assert_eq!(P::next_generation(0xff_ffff), 1);
+    }
+}
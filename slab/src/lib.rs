@@ -5,6 +5,10 @@
 //!   processor ID.
 //! - Static, rather than dynamic, allocation, so that all operations after
 //!   initialization are guaranteed not to allocate.
+//! - Optional drop hooks (see [`Slab::with_drop_hook`]), for releasing
+//!   resources associated with an entry once it's actually destroyed.
+//! - Configurable index and generation widths (see [`Idx`]), so that small
+//!   slabs can use 32-bit bookkeeping.
 
 #![cfg_attr(not(loom), no_std)]
 #![feature(maybe_uninit_array_assume_init)]
@@ -12,59 +16,98 @@
 use core::mem::MaybeUninit;
 use core::ops::Deref;
 
-use modular_bitfield::specifiers::{B18, B46};
-use modular_bitfield::{bitfield, Specifier};
 use platypos_hal as hal;
 
 mod free_lists;
+mod idx;
 mod slot;
 mod sync;
 
 use free_lists::{GlobalFreeList, LocalFreeList};
+use idx::Packing;
+pub use idx::{Idx, Word};
 use slot::Slot;
 use sync::ConstPtr;
 
+/// A slab of `SIZE` entries, whose indices are packed into `W` with
+/// `INDEX_BITS` bits of slot number. See [`Idx`] for choosing a layout; the
+/// default suits slabs of up to 2^18 entries.
 pub struct Slab<
     const SIZE: usize,
     T: Sized,
     // IC: hal::interrupts::Controller,
     TP: hal::topology::Topology + 'static,
+    W: Word = u64,
+    const INDEX_BITS: u32 = 18,
 > {
     // interrupts: IC,
     topology: &'static TP,
     local_free_list: LocalFreeList<&'static TP>,
-    global_free_list: GlobalFreeList,
+    global_free_list: GlobalFreeList<W, INDEX_BITS>,
+    /// Called with each value once it's removed and has no references left
+    drop_hook: Option<fn(T)>,
 
-    slots: [Slot<T>; SIZE],
-}
-
-/// Index pointing to a slab allocation
-#[bitfield]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[repr(u64)]
-pub struct Idx {
-    generation: B46,
-    index: B18,
+    slots: [Slot<T, W, INDEX_BITS>; SIZE],
 }
 
 /// Reference to a live slab allocation. Active references prevent slab entries
 /// from being removed.
-pub struct Ref<'a, const SIZE: usize, T, TP: hal::topology::Topology + 'static> {
+pub struct Ref<
+    'a,
+    const SIZE: usize,
+    T,
+    TP: hal::topology::Topology + 'static,
+    W: Word = u64,
+    const INDEX_BITS: u32 = 18,
+> {
     value: Option<ConstPtr<MaybeUninit<T>>>,
-    index: Idx,
-    slab: &'a Slab<SIZE, T, TP>,
+    index: Idx<W, INDEX_BITS>,
+    slab: &'a Slab<SIZE, T, TP, W, INDEX_BITS>,
 }
 
-impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SIZE, T, TP> {
-    pub fn new(topology: &'static TP) -> Self {
+impl<
+        const SIZE: usize,
+        T: Sized,
+        TP: hal::topology::Topology + 'static,
+        W: Word,
+        const INDEX_BITS: u32,
+    > Slab<SIZE, T, TP, W, INDEX_BITS>
+{
+    /// Evaluating this fails to compile if the layout is unusable, or `SIZE`
+    /// slots don't fit in `INDEX_BITS`
+    const CHECK: () = {
+        #[allow(clippy::let_unit_value)]
+        let () = Packing::<W, INDEX_BITS>::CHECK;
         assert!(
-            SIZE < (1 << B18::BITS),
-            "Size {SIZE} exceeds maximum slab size"
+            Packing::<W, INDEX_BITS>::fits(SIZE),
+            "slab size exceeds the maximum for its index bits"
         );
+    };
+
+    pub fn new(topology: &'static TP) -> Self {
+        Self::new_inner(topology, None)
+    }
+
+    /// Create a slab which passes removed values to `hook` instead of
+    /// dropping them. The hook runs once per value, when it's actually
+    /// destroyed: immediately on [`Slab::remove`] if there are no references
+    /// to it, or else when the last [`Ref`] is dropped. It never runs while
+    /// references exist, and the value's slot may already have been reused by
+    /// the time it runs.
+    ///
+    /// The hook runs on whichever processor released the value, possibly in
+    /// interrupt context, so it must not block.
+    pub fn with_drop_hook(topology: &'static TP, hook: fn(T)) -> Self {
+        Self::new_inner(topology, Some(hook))
+    }
+
+    fn new_inner(topology: &'static TP, drop_hook: Option<fn(T)>) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CHECK;
 
         // The slab is initialized such that all slots are in the global free list, in
         // order.
-        let mut slots: [MaybeUninit<Slot<T>>; SIZE] = unsafe {
+        let mut slots: [MaybeUninit<Slot<T, W, INDEX_BITS>>; SIZE] = unsafe {
             // Safety: we can call assume_init because we're claiming that a bunch of
             // MaybeUninits, which don't require initialization, are initialized.
             MaybeUninit::uninit().assume_init()
@@ -72,7 +115,7 @@ impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SI
 
         for (idx, elem) in slots.iter_mut().enumerate() {
             let next = if idx == SIZE - 1 {
-                free_lists::EMPTY
+                GlobalFreeList::<W, INDEX_BITS>::EMPTY
             } else {
                 (idx + 1) as u64
             };
@@ -86,13 +129,14 @@ impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SI
             topology,
             local_free_list: LocalFreeList::new(topology),
             global_free_list,
+            drop_hook,
             slots,
         }
     }
 
     /// Insert a new value into the slab, returning its allocated index. If
     /// there is no space left, this fails and returns the value.
-    pub fn insert(&self, value: T) -> Result<Idx, T> {
+    pub fn insert(&self, value: T) -> Result<Idx<W, INDEX_BITS>, T> {
         let Some(index) = self
             .local_free_list
             .pop(&self.slots)
@@ -106,16 +150,14 @@ impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SI
 
         let generation = unsafe { slot.allocate(value, self.topology.current_processor()) };
 
-        Ok(Idx::new()
-            .with_generation(generation)
-            .with_index(index.try_into().unwrap()))
+        Ok(Idx::new(index, generation))
     }
 
     /// Removes the value at `idx` in the slab, returning `true` on success. If
     /// there are outstanding references, the value may not be immediately
     /// cleared. If the index is invalid, returns `false` instead.
-    pub fn remove(&self, idx: Idx) -> bool {
-        let index = idx.index() as usize;
+    pub fn remove(&self, idx: Idx<W, INDEX_BITS>) -> bool {
+        let index = idx.index();
         let Some(slot) = self.slots.get(index) else {
             return false
         };
@@ -138,14 +180,16 @@ impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SI
         }
     }
 
-    /// Clear a slot and add it to the appropriate free list.
+    /// Clear a slot, add it to the appropriate free list, and destroy its
+    /// value.
     ///
     /// # Safety
     /// The caller must guarantee that the slot is unallocated, per
     /// [`Slot::clear`].
     unsafe fn return_slot(&self, index: usize) {
         let slot = &self.slots[index];
-        match slot.clear() {
+        let (value, processor) = slot.clear();
+        match processor {
             Some(processor) if processor == self.topology.current_processor() => {
                 self.local_free_list.push(index, &self.slots);
             }
@@ -153,10 +197,17 @@ impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SI
                 self.global_free_list.push(index, &self.slots);
             }
         }
+
+        // The value was moved out of the slot, so this is fine even if the slot has
+        // already been reused
+        match self.drop_hook {
+            Some(hook) => hook(value),
+            None => drop(value),
+        }
     }
 
-    pub fn get(&self, idx: Idx) -> Option<Ref<'_, SIZE, T, TP>> {
-        let slot = &self.slots.get(idx.index() as usize)?;
+    pub fn get(&self, idx: Idx<W, INDEX_BITS>) -> Option<Ref<'_, SIZE, T, TP, W, INDEX_BITS>> {
+        let slot = &self.slots.get(idx.index())?;
 
         let ptr = slot.acquire_reference(idx.generation()).ok()?;
         Some(Ref {
@@ -166,10 +217,10 @@ impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SI
         })
     }
 
-    fn drop_reference(&self, idx: Idx) {
+    fn drop_reference(&self, idx: Idx<W, INDEX_BITS>) {
         // Use panicking array access since this is only called from Ref::drop, and all
         // Refs should have a valid index
-        let slot = &self.slots[idx.index() as usize];
+        let slot = &self.slots[idx.index()];
 
         let should_clear = slot
             .release_reference(idx.generation())
@@ -177,18 +228,29 @@ impl<const SIZE: usize, T: Sized, TP: hal::topology::Topology + 'static> Slab<SI
         if should_clear {
             // Safety: should_clear indicates that this was the last reference to a zombie
             // slot, so it can be returned
-            unsafe { self.return_slot(idx.index() as usize) };
+            unsafe { self.return_slot(idx.index()) };
         }
     }
 }
 
-unsafe impl<const SIZE: usize, T: Sized + Sync, TP: hal::topology::Topology + 'static> Sync
-    for Slab<SIZE, T, TP>
+unsafe impl<
+        const SIZE: usize,
+        T: Sized + Sync,
+        TP: hal::topology::Topology + 'static,
+        W: Word,
+        const INDEX_BITS: u32,
+    > Sync for Slab<SIZE, T, TP, W, INDEX_BITS>
 {
 }
 
-impl<'a, const SIZE: usize, T, TP: hal::topology::Topology + 'static> Deref
-    for Ref<'a, SIZE, T, TP>
+impl<
+        'a,
+        const SIZE: usize,
+        T,
+        TP: hal::topology::Topology + 'static,
+        W: Word,
+        const INDEX_BITS: u32,
+    > Deref for Ref<'a, SIZE, T, TP, W, INDEX_BITS>
 {
     type Target = T;
 
@@ -206,8 +268,14 @@ impl<'a, const SIZE: usize, T, TP: hal::topology::Topology + 'static> Deref
     }
 }
 
-impl<'a, const SIZE: usize, T, TP: hal::topology::Topology + 'static> Drop
-    for Ref<'a, SIZE, T, TP>
+impl<
+        'a,
+        const SIZE: usize,
+        T,
+        TP: hal::topology::Topology + 'static,
+        W: Word,
+        const INDEX_BITS: u32,
+    > Drop for Ref<'a, SIZE, T, TP, W, INDEX_BITS>
 {
     fn drop(&mut self) {
         self.value = None; // Ensure our reference to the contents UnsafeCell is inaccessible before
@@ -225,6 +293,7 @@ impl<'a, const SIZE: usize, T, TP: hal::topology::Topology + 'static> Drop
 
 #[cfg(all(test, loom))]
 mod test {
+    use loom::sync::atomic::{AtomicBool, Ordering};
     use loom::sync::Arc;
 
     use super::*;
@@ -292,4 +361,46 @@ mod test {
             assert!(slab.get(idx).is_none());
         })
     }
+
+    struct Tracked {
+        value: i32,
+        destroyed: Arc<AtomicBool>,
+    }
+
+    fn mark_destroyed(tracked: Tracked) {
+        tracked.destroyed.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_drop_hook_waits_for_references() {
+        loom::model(|| {
+            let slab: Arc<Slab<4, Tracked, _>> =
+                Arc::new(Slab::with_drop_hook(&TOPOLOGY, mark_destroyed));
+            let destroyed = Arc::new(AtomicBool::new(false));
+            let idx = slab
+                .insert(Tracked {
+                    value: 42,
+                    destroyed: destroyed.clone(),
+                })
+                .unwrap_or_else(|_| panic!("slab is full"));
+
+            let reader = {
+                let slab = slab.clone();
+                let destroyed = destroyed.clone();
+                loom::thread::spawn(move || {
+                    if let Some(reference) = slab.get(idx) {
+                        assert_eq!((*reference).value, 42);
+                        assert!(
+                            !destroyed.load(Ordering::SeqCst),
+                            "drop hook ran with a live reference"
+                        );
+                    }
+                })
+            };
+
+            assert!(slab.remove(idx));
+            reader.join().unwrap();
+            assert!(destroyed.load(Ordering::SeqCst));
+        })
+    }
 }
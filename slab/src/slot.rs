@@ -2,18 +2,16 @@
 //! Each slot contains:
 //! - a lifecycle field
 
+use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use core::sync::atomic::Ordering;
 
-use modular_bitfield::specifiers::{B16, B46};
-use modular_bitfield::{bitfield, BitfieldSpecifier};
 use platypos_hal::topology::ProcessorId;
 
-use crate::sync::{AtomicU64, ConstPtr, UnsafeCell};
+use crate::idx::{mask, Packing, Word, STATE_BITS};
+use crate::sync::{ConstPtr, UnsafeCell};
 
-#[derive(BitfieldSpecifier)]
-#[bits = 2]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum State {
     /// Marks a slot that is allocated
     Allocated,
@@ -24,28 +22,96 @@ pub(crate) enum State {
     Zombie,
 }
 
-/// Lifecycle status of a slot
-#[bitfield]
-#[derive(Debug)]
-#[repr(u64)]
-#[allow(dead_code)]
-pub(crate) struct Lifecycle {
+/// Lifecycle status of a slot. This is packed into a `W`, with the state in
+/// the low bits, then the generation, then the reference count.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Lifecycle<W, const INDEX_BITS: u32> {
     /// The state of the slot (allocated/unallocated)
     state: State,
     /// The current generation number of this slot. It's incremented on every
     /// insertion to avoid the ABA problem.
-    generation: B46,
+    generation: u64,
     /// Number of active references to this slot, if it's allocated.
-    refcount: B16,
+    refcount: u64,
+    _word: PhantomData<W>,
+}
+
+impl<W: Word, const INDEX_BITS: u32> Lifecycle<W, INDEX_BITS> {
+    const MAX_REFCOUNT: u64 = mask(Packing::<W, INDEX_BITS>::REFCOUNT_BITS);
+
+    fn new(state: State) -> Self {
+        Self {
+            state,
+            generation: 0,
+            refcount: 0,
+            _word: PhantomData,
+        }
+    }
+
+    fn state(&self) -> State {
+        self.state
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn set_generation(&mut self, generation: u64) {
+        self.generation = generation & Packing::<W, INDEX_BITS>::GENERATION_MASK;
+    }
+
+    fn refcount(&self) -> u64 {
+        self.refcount
+    }
+
+    fn set_refcount(&mut self, refcount: u64) {
+        debug_assert!(refcount <= Self::MAX_REFCOUNT);
+        self.refcount = refcount;
+    }
+}
+
+impl<W: Word, const INDEX_BITS: u32> From<u64> for Lifecycle<W, INDEX_BITS> {
+    fn from(packed: u64) -> Self {
+        let state = match packed & mask(STATE_BITS) {
+            0 => State::Allocated,
+            1 => State::Unallocated,
+            _ => State::Zombie,
+        };
+        let generation = (packed >> STATE_BITS) & Packing::<W, INDEX_BITS>::GENERATION_MASK;
+        let refcount = packed >> (STATE_BITS + Packing::<W, INDEX_BITS>::GENERATION_BITS);
+        Self {
+            state,
+            generation,
+            refcount: refcount & Self::MAX_REFCOUNT,
+            _word: PhantomData,
+        }
+    }
+}
+
+impl<W: Word, const INDEX_BITS: u32> From<Lifecycle<W, INDEX_BITS>> for u64 {
+    fn from(lifecycle: Lifecycle<W, INDEX_BITS>) -> Self {
+        let state = match lifecycle.state {
+            State::Allocated => 0,
+            State::Unallocated => 1,
+            State::Zombie => 2,
+        };
+        state
+            | (lifecycle.generation << STATE_BITS)
+            | (lifecycle.refcount << (STATE_BITS + Packing::<W, INDEX_BITS>::GENERATION_BITS))
+    }
 }
 
-pub(crate) struct Slot<T> {
+pub(crate) struct Slot<T, W: Word, const INDEX_BITS: u32> {
     /// Lifecycle of the slot. This is a packed [`Lifecycle`] value.
-    lifecycle: AtomicU64,
+    lifecycle: W::Atomic,
     /// Contents of the slot, only initialized if it is allocated or a zombie.
     contents: UnsafeCell<MaybeUninit<T>>,
     /// The next free list entry after this one, if it is in a free list.
-    next: UnsafeCell<u64>,
+    next: UnsafeCell<W>,
     /// The processor this slot was most recently allocated on
     processor: UnsafeCell<Option<ProcessorId>>,
 }
@@ -67,24 +133,37 @@ pub(crate) enum LifecycleError {
     },
 }
 
-impl<T> Slot<T> {
+impl<T, W: Word, const INDEX_BITS: u32> Slot<T, W, INDEX_BITS> {
     /// A new, unallocated slot. This slot will be in the [`State::Unallocated`]
     /// state, with its free list pointer set to `next`.
     pub(crate) fn new_unallocated(next: u64) -> Self {
+        let lifecycle: Lifecycle<W, INDEX_BITS> = Lifecycle::new(State::Unallocated);
         Self {
-            lifecycle: AtomicU64::new(
-                Lifecycle::new()
-                    .with_generation(0)
-                    .with_state(State::Unallocated)
-                    .with_refcount(0)
-                    .into(),
-            ),
+            lifecycle: W::new_atomic(lifecycle.into()),
             contents: UnsafeCell::new(MaybeUninit::uninit()),
-            next: UnsafeCell::new(next),
+            next: UnsafeCell::new(W::from_u64(next)),
             processor: UnsafeCell::new(None),
         }
     }
 
+    fn load(&self, order: Ordering) -> u64 {
+        W::load(&self.lifecycle, order)
+    }
+
+    fn unpack(packed: u64) -> Lifecycle<W, INDEX_BITS> {
+        Lifecycle::from(packed)
+    }
+
+    fn compare_exchange(
+        &self,
+        current: u64,
+        new: Lifecycle<W, INDEX_BITS>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<u64, u64> {
+        W::compare_exchange(&self.lifecycle, current, new.into(), success, failure)
+    }
+
     /// Initialize this slot as allocated.
     ///
     /// # Safety
@@ -94,15 +173,14 @@ impl<T> Slot<T> {
     pub(crate) unsafe fn allocate(&self, value: T, current_processor: ProcessorId) -> u64 {
         // Relaxed ordering is OK here because this is an unallocated,
         // not-on-the-free-list slot, so no other cores should access it
-        let mut lifecycle = Lifecycle::from(self.lifecycle.load(Ordering::Relaxed));
-        let next_generation = lifecycle
-            .generation()
-            .checked_add(1)
-            .expect("generation overflow");
+        let mut lifecycle = Self::unpack(self.load(Ordering::Relaxed));
+        // Wrapping around is what makes narrow generations usable, at the cost of eventually
+        // accepting a very stale index
+        let next_generation = Packing::<W, INDEX_BITS>::next_generation(lifecycle.generation());
         lifecycle.set_generation(next_generation);
         lifecycle.set_state(State::Allocated);
         debug_assert!(lifecycle.refcount() == 0, "unallocated slot had references");
-        self.lifecycle.store(lifecycle.into(), Ordering::Relaxed);
+        W::store(&self.lifecycle, lifecycle.into(), Ordering::Relaxed);
 
         // Safety: this slot has been allocated but not yet returned, so no
         // other cores have access to it
@@ -123,9 +201,9 @@ impl<T> Slot<T> {
         &self,
         expected_generation: u64,
     ) -> Result<ConstPtr<MaybeUninit<T>>, LifecycleError> {
-        let mut prev_lifecycle = self.lifecycle.load(Ordering::Acquire);
+        let mut prev_lifecycle = self.load(Ordering::Acquire);
         loop {
-            let mut lifecycle = Lifecycle::from(prev_lifecycle);
+            let mut lifecycle = Self::unpack(prev_lifecycle);
             if lifecycle.generation() != expected_generation {
                 return Err(LifecycleError::WrongGeneration {
                     expected_generation,
@@ -139,15 +217,14 @@ impl<T> Slot<T> {
                 });
             }
 
-            lifecycle.set_refcount(
-                lifecycle
-                    .refcount()
-                    .checked_add(1)
-                    .expect("refcount overflow"),
+            assert!(
+                lifecycle.refcount() < Lifecycle::<W, INDEX_BITS>::MAX_REFCOUNT,
+                "refcount overflow"
             );
-            match self.lifecycle.compare_exchange(
+            lifecycle.set_refcount(lifecycle.refcount() + 1);
+            match self.compare_exchange(
                 prev_lifecycle,
-                lifecycle.into(),
+                lifecycle,
                 Ordering::Release,
                 Ordering::Acquire,
             ) {
@@ -166,9 +243,9 @@ impl<T> Slot<T> {
         &self,
         expected_generation: u64,
     ) -> Result<bool, LifecycleError> {
-        let mut prev_lifecycle = self.lifecycle.load(Ordering::Acquire);
+        let mut prev_lifecycle = self.load(Ordering::Acquire);
         loop {
-            let mut lifecycle = Lifecycle::from(prev_lifecycle);
+            let mut lifecycle = Self::unpack(prev_lifecycle);
             if lifecycle.generation() != expected_generation {
                 return Err(LifecycleError::WrongGeneration {
                     expected_generation,
@@ -192,9 +269,9 @@ impl<T> Slot<T> {
             // once we successfully update the lifecycle
             let should_clear = lifecycle.state() == State::Zombie && lifecycle.refcount() == 0;
 
-            match self.lifecycle.compare_exchange(
+            match self.compare_exchange(
                 prev_lifecycle,
-                lifecycle.into(),
+                lifecycle,
                 Ordering::Release,
                 Ordering::Acquire,
             ) {
@@ -262,37 +339,41 @@ impl<T> Slot<T> {
         // in the meantime, switch back to fetch_update? or loop the whole
         // thing?
 
-        let mut was_unallocated = None;
-        let outcome =
-            self.lifecycle
-                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |lifecycle| {
-                    let mut lifecycle = Lifecycle::from(lifecycle);
-
-                    if lifecycle.generation() != expected_generation
-                        || lifecycle.state() != State::Allocated
-                    {
-                        // The slot was in an invalid state, so we can't mark it
-                        None
-                    } else if lifecycle.refcount() > 0 {
-                        // There are outstanding references, so mark the slot as
-                        // a zombie
-                        lifecycle.set_state(State::Zombie);
-                        was_unallocated = Some(false);
-                        Some(lifecycle.into())
-                    } else {
-                        // There are no outstanding references, so mark the slot as unallocated
-                        lifecycle.set_state(State::Unallocated);
-                        was_unallocated = Some(true);
-                        Some(lifecycle.into())
-                    }
-                });
+        // Equivalent to `fetch_update`, which isn't available for every word type
+        let mut prev_lifecycle = self.load(Ordering::Acquire);
+        let outcome = loop {
+            let mut lifecycle = Self::unpack(prev_lifecycle);
+
+            let was_unallocated = if lifecycle.generation() != expected_generation
+                || lifecycle.state() != State::Allocated
+            {
+                // The slot was in an invalid state, so we can't mark it
+                break Err(prev_lifecycle);
+            } else if lifecycle.refcount() > 0 {
+                // There are outstanding references, so mark the slot as a zombie
+                lifecycle.set_state(State::Zombie);
+                false
+            } else {
+                // There are no outstanding references, so mark the slot as unallocated
+                lifecycle.set_state(State::Unallocated);
+                true
+            };
+
+            match self.compare_exchange(
+                prev_lifecycle,
+                lifecycle,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break Ok(was_unallocated),
+                Err(actual) => prev_lifecycle = actual,
+            }
+        };
 
         match outcome {
-            // If fetch_update returns Ok, then it applied the update to the stored value, and so
-            // was_unallocated must have been set
-            Ok(_) => Ok(was_unallocated.unwrap()),
+            Ok(was_unallocated) => Ok(was_unallocated),
             Err(lifecycle) => {
-                let lifecycle = Lifecycle::from(lifecycle);
+                let lifecycle = Self::unpack(lifecycle);
                 if lifecycle.generation() != expected_generation {
                     Err(LifecycleError::WrongGeneration {
                         expected_generation,
@@ -310,7 +391,8 @@ impl<T> Slot<T> {
 
     /// Clears out this slot by:
     /// 1. Marking it as unallocated
-    /// 2. Dropping its contents
+    /// 2. Moving out its contents, which are returned along with the processor
+    ///    the slot was allocated on
     ///
     /// # Safety
     /// The caller must be allowed to clear the slot. Either a
@@ -318,27 +400,29 @@ impl<T> Slot<T> {
     /// just removed the last reference to a zombie slot.
     ///
     /// If these conditions are not met, existing data will be overwritten.
-    pub(crate) unsafe fn clear(&self) -> Option<ProcessorId> {
+    pub(crate) unsafe fn clear(&self) -> (T, Option<ProcessorId>) {
         // Relaxed ordering is fine since the slot is being cleared - no other cores are
         // using it
-        let mut lifecycle = Lifecycle::from(self.lifecycle.load(Ordering::Relaxed));
+        let mut lifecycle = Self::unpack(self.load(Ordering::Relaxed));
         debug_assert_eq!(lifecycle.refcount(), 0, "clearing a slot with references");
         lifecycle.set_state(State::Unallocated);
-        self.lifecycle.store(lifecycle.into(), Ordering::Relaxed);
+        W::store(&self.lifecycle, lifecycle.into(), Ordering::Relaxed);
 
-        self.contents.with_mut(|ptr| unsafe {
-            // Safety: the slot was previously allocated, so we know it has contents to drop
+        let value = self.contents.with_mut(|ptr| unsafe {
+            // Safety: the slot was previously allocated, so we know it has contents to move out.
+            // It's now unallocated, so they won't be read again until it's reallocated.
             ptr.as_mut()
                 .expect("slot contents pointer is null")
-                .assume_init_drop();
+                .assume_init_read()
         });
         // Safety: as above, no one else can access this slot, so we can clear out the
         // processor field
-        self.processor.with_mut(|ptr| unsafe {
+        let processor = self.processor.with_mut(|ptr| unsafe {
             let prev = *ptr;
             *ptr = None;
             prev
-        })
+        });
+        (value, processor)
     }
 
     /// Set the `next` pointer of this slot
@@ -348,7 +432,7 @@ impl<T> Slot<T> {
     /// any other cores. In general, that means it should only be called when
     /// adding a slot to the free list.
     pub(crate) unsafe fn set_next(&self, next: u64) {
-        self.next.with_mut(|ptr| *ptr = next);
+        self.next.with_mut(|ptr| *ptr = W::from_u64(next));
     }
 
     /// Get the `next` pointer of this slot
@@ -356,6 +440,6 @@ impl<T> Slot<T> {
     /// # Safety
     /// The caller must ensure that no other cores are mutating the slot
     pub(crate) unsafe fn next(&self) -> u64 {
-        self.next.with(|ptr| *ptr)
+        self.next.with(|ptr| (*ptr).to_u64())
     }
 }
@@ -5,7 +5,7 @@ pub(crate) use loom::cell::UnsafeCell;
 pub(crate) use loom::cell::ConstPtr;
 
 #[cfg(loom)]
-pub(crate) use loom::sync::atomic::AtomicU64;
+pub(crate) use loom::sync::atomic::{AtomicU32, AtomicU64};
 
 #[cfg(not(loom))]
 pub(crate) struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
@@ -14,7 +14,7 @@ pub(crate) struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
 pub(crate) struct ConstPtr<T>(*const T);
 
 #[cfg(not(loom))]
-pub(crate) use core::sync::atomic::AtomicU64;
+pub(crate) use core::sync::atomic::{AtomicU32, AtomicU64};
 
 #[cfg(not(loom))]
 impl<T> UnsafeCell<T> {
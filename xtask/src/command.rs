@@ -1,13 +1,33 @@
-use std::rc::Rc;
+use std::fs::{self, File};
+use std::io;
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use platypos_hal::power::ExitCode;
+use platypos_ktrace_decoder::bench::{Baseline, BenchResult};
+use platypos_ktrace_decoder::boot_time::BootTimes;
+use platypos_ktrace_decoder::clock::ReceiveLog;
+use platypos_ktrace_decoder::filter::{Filter, Matcher};
+use platypos_ktrace_decoder::fmt::{self, Profile};
+use platypos_ktrace_decoder::perfetto::ChromeTrace;
+use platypos_ktrace_decoder::profile;
+use platypos_ktrace_decoder::proto::{FieldType, ReceiverMessage};
+use platypos_ktrace_decoder::report::Report;
+use platypos_ktrace_decoder::stats::Stats;
+use platypos_ktrace_decoder::test_results::{TestReport, TestResult};
+use platypos_ktrace_decoder::{Decoder, OwnedMessage};
 
 use crate::output::OutputOpts;
+use crate::regressions;
 use crate::tools::cargo::{self, Cargo};
 
 use crate::prelude::*;
-use crate::tools::gdb;
-use crate::tools::qemu::{self, Qemu};
+use crate::tools::efivars::{BootVar, VarStore};
+use crate::tools::qemu::{self, Qemu, SerialTransport};
+use crate::tools::{efivars, gdb, image};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
@@ -37,18 +57,88 @@ struct ToolOpts {
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    Build,
+    Build(BuildOpts),
     Run(QemuOpts),
     Test(QemuOpts),
     Gdb,
+    /// Run the kernel headless and save trace artifacts to
+    /// `target/traces/<timestamp>/`
+    Trace(TraceOpts),
+    /// Render a saved trace capture as a self-contained HTML report
+    Report(ReportOpts),
+    /// Build a bootable disk image, for running on real UEFI hardware
+    Image(ImageOpts),
+    /// Run the frame allocator benchmarks, and compare them to a stored
+    /// baseline
+    Bench(BenchOpts),
+    /// Set or list the persistent boot variables that `run` and `test` use
+    /// as defaults. They're saved as UEFI variables in `target/OVMF_VARS.fd`.
+    BootVar(BootVarOpts),
+}
+
+#[derive(Debug, Args)]
+struct BuildOpts {
+    /// Set of kernel features to build with
+    #[arg(long, value_enum, default_value_t = BootProfile::Minimal)]
+    profile: BootProfile,
+}
+
+#[derive(Debug, Args)]
+struct ImageOpts {
+    /// Set of kernel features to build with
+    #[arg(long, value_enum, default_value_t = BootProfile::Minimal)]
+    profile: BootProfile,
+
+    /// Where to save the image. Defaults to `target/platypos-<platform>.img`
+    #[arg(long, short)]
+    output: Option<Utf8PathBuf>,
+
+    /// Write the image to this drive, like `/dev/sdb`, erasing it
+    #[arg(long, value_name = "DEVICE")]
+    flash: Option<Utf8PathBuf>,
+
+    /// Don't ask for confirmation before flashing
+    #[arg(long, requires = "flash")]
+    yes: bool,
+}
+
+#[derive(Debug, Args)]
+struct BootVarOpts {
+    /// Variable to set, as `NAME=VALUE`. With no variables to set or unset,
+    /// the current values are listed.
+    #[arg(value_name = "NAME=VALUE", value_parser = parse_boot_var)]
+    set: Vec<(BootVar, String)>,
+
+    /// Variable to delete, so the usual default applies
+    #[arg(long, value_enum)]
+    unset: Vec<BootVar>,
+}
+
+/// Parse a `NAME=VALUE` boot variable assignment
+fn parse_boot_var(s: &str) -> Result<(BootVar, String)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| eyre!("expected NAME=VALUE, got {s}"))?;
+    let var = BootVar::from_str(name, false).map_err(|err| eyre!(err))?;
+    Ok((var, value.to_string()))
 }
 
 #[derive(Debug, Args)]
 struct QemuOpts {
+    /// Set of kernel features to build with. Defaults to `minimal` for `run`
+    /// and `test` for `test`.
+    #[arg(long, value_enum)]
+    profile: Option<BootProfile>,
+
     /// Number of CPUs for the QEMU VM
     #[arg(long, default_value = "1")]
     cpus: u8,
 
+    /// Give the VM's CPUs 5-level paging support. With KVM, the host CPU must
+    /// support it too.
+    #[arg(long)]
+    la57: bool,
+
     /// Memory for the QEMU VM
     #[arg(long, default_value = "1G")]
     memory: String,
@@ -60,11 +150,311 @@ struct QemuOpts {
     /// Wait for GDB to attach. Implies `--debugger`
     #[arg(long, short = 'w')]
     debugger_wait: bool,
+
+    /// Replay kernel traces through a `tracing-subscriber` formatter instead
+    /// of the built-in one
+    #[arg(long)]
+    replay: bool,
+
+    /// Layout for decoded kernel traces
+    #[arg(long, value_enum, default_value_t = TraceFormat::Pretty)]
+    trace_format: TraceFormat,
+
+    /// Maximum number of bytes to print for byte slice fields in traces,
+    /// like hashes or raw data. Longer values are truncated.
+    #[arg(long, default_value = "32")]
+    max_bytes: usize,
+
+    /// Stamp decoded kernel trace events with the host's time of day, using
+    /// the kernel's timestamps where events have them
+    #[arg(long)]
+    wall_clock: bool,
+
+    /// Memory map to name the regions physical addresses in traces fall in,
+    /// with one `START END NAME` line per region and addresses in hex
+    #[arg(long, value_name = "FILE")]
+    memory_map: Option<Utf8PathBuf>,
+
+    /// Only print the kernel trace spans and events that match this filter
+    /// expression, like `target == "platypos_kernel::mm" && level <= debug`.
+    /// See the decoder's `filter` module for the syntax.
+    #[arg(long, value_name = "EXPR")]
+    trace_filter: Option<Filter>,
+
+    /// How to read kernel traces from QEMU. The socket transports keep trace
+    /// data off of QEMU's stdio, so it can be used interactively.
+    #[arg(long, value_enum, default_value_t = SerialTransport::Stdio)]
+    serial: SerialTransport,
+
+    /// Boot the kernel image from before the last rebuild first, to fall back
+    /// from a change that breaks booting. With `run`, either image can also be
+    /// picked from the firmware's boot menu.
+    #[arg(long)]
+    previous: bool,
+
+    /// Boot milestones that tests must reach, in order
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_values = ["memory-init-complete", "aps-online"]
+    )]
+    milestones: Vec<String>,
+
+    /// How long tests have to reach every boot milestone, in seconds
+    #[arg(long, default_value = "30")]
+    milestone_timeout: u64,
+
+    /// Code size and boot time budgets for tests to stay within. Checking is
+    /// skipped if the file doesn't exist.
+    #[arg(long, default_value = "regressions.toml")]
+    budgets: Utf8PathBuf,
+
+    /// How far over budget, in percent, a value can go before tests fail.
+    /// Defaults to the budgets file's `tolerance`.
+    #[arg(long)]
+    budget_tolerance: Option<u64>,
+
+    /// Pass a file to the kernel through QEMU's fw_cfg interface, as
+    /// `NAME=PATH`. Names without a `/` are put under `opt/platypos/`.
+    #[arg(long = "fw-cfg", value_name = "NAME=PATH")]
+    fw_cfg: Vec<qemu::FwCfgItem>,
+
+    /// Only run tests whose names contain one of these patterns. This is
+    /// passed through fw_cfg, so changing it doesn't rebuild the kernel.
+    #[arg(long, value_delimiter = ',')]
+    filter: Vec<String>,
+
+    /// Split tests across this many VMs, running in parallel. Each VM's
+    /// serial output is saved under `target/test-shards/` instead of being
+    /// printed, and the results are merged into one report.
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    shards: u32,
+
+    /// Fail tests that leave more than this many bytes allocated, from
+    /// either the heap or the frame allocator. Passed through fw_cfg.
+    #[arg(long)]
+    leak_threshold: Option<u64>,
+
+    /// Inject faults at annotated points in the kernel, seeding the decisions
+    /// with this value. Requires the `debug` or `test` profile.
+    #[arg(long)]
+    fault_seed: Option<u64>,
+
+    /// With `--fault-seed`, fail one in this many calls to each fault point
+    #[arg(long, default_value = "100")]
+    fault_rate: u32,
+
+    /// Limit trace output to this many bytes per second, to simulate a slow
+    /// serial link. Requires the `debug` or `test` profile.
+    #[arg(long)]
+    trace_bandwidth: Option<u64>,
+
+    /// What the kernel does after a panic: `halt` (the default), `exit` QEMU,
+    /// or `reboot=SECONDS` after a delay
+    #[arg(long)]
+    panic_policy: Option<String>,
+
+    /// Most verbose level of kernel events to send: `off`, `error`, `warn`,
+    /// `info`, `debug`, or `trace` (the default)
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Mask interrupt vectors delivered more than this many times a second to
+    /// one processor, or never mask them with 0
+    #[arg(long)]
+    irq_storm_threshold: Option<u32>,
+}
+
+impl QemuOpts {
+    /// Fill in options that weren't given on the command line from the boot
+    /// variables, if any have been set
+    fn with_boot_vars(mut self) -> Result<Self> {
+        if !Utf8Path::new(efivars::VARS_FILE).exists() {
+            return Ok(self);
+        }
+        let vars = VarStore::load()?;
+        self.log_level = self.log_level.or_else(|| vars.get(BootVar::LogLevel));
+        self.panic_policy = self.panic_policy.or_else(|| vars.get(BootVar::PanicPolicy));
+        self.previous |= vars.get(BootVar::KernelImage).as_deref() == Some("previous");
+        Ok(self)
+    }
+
+    /// Blobs to pass to the kernel through fw_cfg
+    fn fw_cfg_items(&self) -> Vec<qemu::FwCfgItem> {
+        let mut items = self.fw_cfg.clone();
+        if !self.filter.is_empty() {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/test-filter".to_string(),
+                contents: qemu::FwCfgContents::String(self.filter.join(",")),
+            });
+        }
+        if let Some(threshold) = self.leak_threshold {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/test-leak-threshold".to_string(),
+                contents: qemu::FwCfgContents::String(threshold.to_string()),
+            });
+        }
+        if let Some(seed) = self.fault_seed {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/fault-injection".to_string(),
+                contents: qemu::FwCfgContents::String(format!(
+                    "seed={seed},rate={}",
+                    self.fault_rate
+                )),
+            });
+        }
+        if let Some(ref policy) = self.panic_policy {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/panic-policy".to_string(),
+                contents: qemu::FwCfgContents::String(policy.clone()),
+            });
+        }
+        if let Some(ref level) = self.log_level {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/log-level".to_string(),
+                contents: qemu::FwCfgContents::String(level.clone()),
+            });
+        }
+        if let Some(bandwidth) = self.trace_bandwidth {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/trace-bandwidth".to_string(),
+                contents: qemu::FwCfgContents::String(bandwidth.to_string()),
+            });
+        }
+        if let Some(threshold) = self.irq_storm_threshold {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/irq-storm-threshold".to_string(),
+                contents: qemu::FwCfgContents::String(threshold.to_string()),
+            });
+        }
+        items
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TraceFormat {
+    /// Nested span boxes
+    Pretty,
+    /// One line per event
+    Compact,
+    /// Nested span boxes, with field types and source locations
+    Verbose,
+}
+
+impl From<TraceFormat> for Profile {
+    fn from(format: TraceFormat) -> Self {
+        match format {
+            TraceFormat::Pretty => Profile::Pretty,
+            TraceFormat::Compact => Profile::Compact,
+            TraceFormat::Verbose => Profile::Verbose,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct TraceOpts {
+    /// Number of CPUs for the QEMU VM
+    #[arg(long, default_value = "1")]
+    cpus: u8,
+
+    /// Memory for the QEMU VM
+    #[arg(long, default_value = "1G")]
+    memory: String,
+
+    /// How long to let the kernel run, in seconds
+    #[arg(long, default_value = "10")]
+    duration: u64,
+
+    /// Set of kernel features to build with
+    #[arg(long, value_enum, default_value_t = BootProfile::Minimal)]
+    profile: BootProfile,
+
+    /// Extra kernel features to enable, on top of the profile's
+    #[arg(long, value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// Only include the spans and events that match this filter expression
+    /// in the output, summary, and report, as for `run --trace-filter`. The
+    /// capture always has everything.
+    #[arg(long, value_name = "EXPR")]
+    filter: Option<Filter>,
+
+    /// Sample where the kernel is running this many times a second, and save
+    /// a CPU profile and flamegraph of the samples. At most the scheduler
+    /// tick rate, 1000.
+    #[arg(long, value_name = "HZ")]
+    profile_rate: Option<u32>,
+
+    /// With `--profile-rate`, also record this many return addresses per
+    /// sample (at most 7). This needs a kernel built with frame pointers
+    /// (`-Cforce-frame-pointers`), or it can fault.
+    #[arg(long, default_value = "0")]
+    profile_depth: u32,
+}
+
+impl TraceOpts {
+    /// Blobs to pass to the kernel through fw_cfg
+    fn fw_cfg_items(&self) -> Vec<qemu::FwCfgItem> {
+        let mut items = Vec::new();
+        if let Some(rate) = self.profile_rate {
+            items.push(qemu::FwCfgItem {
+                name: "opt/platypos/profile".to_string(),
+                contents: qemu::FwCfgContents::String(format!(
+                    "rate={rate},depth={}",
+                    self.profile_depth
+                )),
+            });
+        }
+        items
+    }
+}
+
+#[derive(Debug, Args)]
+struct ReportOpts {
+    /// Captured ktrace output, like `target/traces/<timestamp>/trace.bin`.
+    /// If the capture's receive log, `trace.times`, is next to it, events
+    /// are shown with their time of day.
+    capture: Utf8PathBuf,
+
+    /// Where to save the report. Defaults to `report.html` next to the
+    /// capture
+    #[arg(long, short)]
+    output: Option<Utf8PathBuf>,
+
+    /// Memory map to name the regions physical addresses fall in, as for
+    /// `run`
+    #[arg(long, value_name = "FILE")]
+    memory_map: Option<Utf8PathBuf>,
+
+    /// Only include the spans and events that match this filter expression,
+    /// as for `run --trace-filter`
+    #[arg(long, value_name = "EXPR")]
+    filter: Option<Filter>,
+}
+
+#[derive(Debug, Args)]
+struct BenchOpts {
+    /// Memory for the QEMU VM
+    #[arg(long, default_value = "1G")]
+    memory: String,
+
+    /// File with the baseline results to compare against
+    #[arg(long, default_value = "kernel/benches/frame-allocator.txt")]
+    baseline: Utf8PathBuf,
+
+    /// How much slower than its baseline a benchmark can get, in percent,
+    /// before it counts as a regression
+    #[arg(long, default_value = "15")]
+    tolerance: u64,
+
+    /// Save the results as the new baseline instead of comparing against it
+    #[arg(long)]
+    save_baseline: bool,
 }
 
 struct Context {
     platform: Platform,
-    cargo: Rc<Cargo>,
+    cargo: Arc<Cargo>,
     qemu: Qemu,
     defmt_filter: String,
 }
@@ -78,10 +468,15 @@ impl XTask {
         let context = Context::new(self.tools.platform, self.tools.cargo, self.tools.defmt);
 
         match self.command {
-            Command::Build => do_build(&context),
+            Command::Build(opts) => do_build(&context, opts),
             Command::Run(opts) => do_run(&context, opts),
             Command::Test(opts) => do_test(&context, opts),
             Command::Gdb => do_gdb(),
+            Command::Trace(opts) => do_trace(&context, opts),
+            Command::Report(opts) => do_report(opts),
+            Command::Image(opts) => do_image(&context, opts),
+            Command::Bench(opts) => do_bench(&context, opts),
+            Command::BootVar(opts) => do_boot_var(opts),
         }
     }
 }
@@ -92,7 +487,7 @@ impl Context {
         cargo_override: Option<Utf8PathBuf>,
         defmt_filter: String,
     ) -> Context {
-        let cargo = Rc::new(Cargo::new(cargo_override));
+        let cargo = Arc::new(Cargo::new(cargo_override));
         let qemu = Qemu::new(cargo.clone());
         Context {
             platform,
@@ -102,87 +497,638 @@ impl Context {
         }
     }
 
-    fn build(&self, crate_name: &str) -> Result<Utf8PathBuf> {
+    fn build(
+        &self,
+        crate_name: &str,
+        profile: BootProfile,
+        features: &[String],
+    ) -> Result<Utf8PathBuf> {
         let output = self.cargo.build(&cargo::BuildSpec {
             crate_name,
             platform: self.platform,
             test: false,
             defmt_filter: &self.defmt_filter,
+            profile,
+            features,
         })?;
-        let binary = output.executable(crate_name)?;
+        let binary = output.profile_executable(crate_name, profile)?;
         log::info!(
-            "Built {} at {}",
+            "Built {} ({} profile) at {}",
             crate_name.if_supports_color(Stream::Stdout, |c| c.green()),
+            profile.if_supports_color(Stream::Stdout, |p| p.blue()),
             binary.if_supports_color(Stream::Stdout, |c| c.magenta())
         );
-        Ok(binary.to_owned())
+        Ok(binary)
     }
 }
 
-fn do_build(context: &Context) -> Result<()> {
-    context.build(KERNEL_CRATE)?;
+fn do_build(context: &Context, opts: BuildOpts) -> Result<()> {
+    context.build(KERNEL_CRATE, opts.profile, &[])?;
     Ok(())
 }
 
 fn do_run(context: &Context, opts: QemuOpts) -> Result<()> {
-    let binary = context.build(KERNEL_CRATE)?;
+    let opts = opts.with_boot_vars()?;
+    let profile = opts.profile.unwrap_or(BootProfile::Minimal);
+    let binary = context.build(KERNEL_CRATE, profile, &[])?;
 
     let gdb = gdb_server(&opts, &binary)?;
 
-    let status = context.qemu.run(qemu::Spec {
+    let outcome = context.qemu.run(qemu::Spec {
         crate_name: KERNEL_CRATE,
         binary: &binary,
         platform: context.platform,
         memory: &opts.memory,
         cpus: opts.cpus.into(),
+        la57: opts.la57,
         debugger: gdb,
+        replay: opts.replay,
+        format: opts.trace_format.into(),
+        max_bytes: opts.max_bytes,
+        wall_clock: opts.wall_clock,
+        renderers: renderers(opts.memory_map.as_deref())?,
+        filter: opts.trace_filter.clone().unwrap_or_default(),
+        headless: false,
+        capture: None,
+        timeout: None,
+        serial: opts.serial,
+        previous: opts.previous,
+        boot_menu: true,
+        boot_image: None,
+        scratch_disk: None,
+        quiet: false,
+        milestones: None,
+        fw_cfg: opts.fw_cfg_items(),
     })?;
 
-    if !status.success() {
-        Err(eyre!("QEMU failed: {status}"))
+    if let Some(crash) = outcome.crash {
+        Err(eyre!("{} crashed: {}", crash.source, crash.reason))
+    } else if !outcome.status.success() {
+        Err(eyre!("QEMU failed: {}", outcome.status))
     } else {
         Ok(())
     }
 }
 
 fn do_test(context: &Context, opts: QemuOpts) -> Result<()> {
+    let opts = opts.with_boot_vars()?;
+    let profile = opts.profile.unwrap_or(BootProfile::Test);
     let output = context.cargo.build(&cargo::BuildSpec {
         crate_name: KERNEL_CRATE,
         platform: context.platform,
         test: true,
         defmt_filter: &context.defmt_filter,
+        profile,
+        features: &[],
     })?;
-    let test_kernel = output.executable(KERNEL_CRATE)?;
+    let test_kernel = output.profile_executable(KERNEL_CRATE, profile)?;
+
+    if opts.shards > 1 {
+        return do_test_sharded(context, &opts, profile, &test_kernel);
+    }
 
-    let gdb = gdb_server(&opts, test_kernel)?;
+    let gdb = gdb_server(&opts, &test_kernel)?;
 
-    let status = context.qemu.run(qemu::Spec {
+    let outcome = context.qemu.run(qemu::Spec {
         crate_name: KERNEL_CRATE,
-        binary: test_kernel,
+        binary: &test_kernel,
         platform: context.platform,
         memory: &opts.memory,
         cpus: opts.cpus.into(),
+        la57: opts.la57,
         debugger: gdb,
+        replay: opts.replay,
+        format: opts.trace_format.into(),
+        max_bytes: opts.max_bytes,
+        wall_clock: opts.wall_clock,
+        renderers: renderers(opts.memory_map.as_deref())?,
+        filter: opts.trace_filter.clone().unwrap_or_default(),
+        headless: false,
+        capture: None,
+        timeout: None,
+        serial: opts.serial,
+        previous: opts.previous,
+        boot_menu: false,
+        boot_image: None,
+        scratch_disk: None,
+        quiet: false,
+        milestones: Some(qemu::MilestoneSpec {
+            names: &opts.milestones,
+            timeout: Duration::from_secs(opts.milestone_timeout),
+        }),
+        fw_cfg: opts.fw_cfg_items(),
     })?;
 
-    match status.code() {
-        Some(code) => {
-            // Match the success code set in ktest/src/lib.rs - QEMU's debug exit device
-            // can't exit with 0
-            if code != 3 {
-                bail!("Tests failed")
-            }
-        }
+    if let Some(crash) = outcome.crash {
+        bail!("Tests failed: {} crashed: {}", crash.source, crash.reason);
+    }
+    let status = outcome.status;
+    match status.code().map(ExitCode::from_qemu_status) {
+        Some(Some(ExitCode::Success)) => {}
+        Some(Some(code)) => bail!("Tests failed: {}", code.description()),
+        Some(None) => bail!("Tests failed: QEMU exited with {status}"),
         None => bail!("QEMU killed by signal: {status}"),
     }
+    check_budgets(context, &opts, profile, outcome.boot_times.as_ref())
+}
 
-    Ok(())
+/// Check a passing test run against the budgets in `opts.budgets`, if there
+/// are any. Sizes are measured on the kernel as it's built to run, rather
+/// than the test kernel.
+fn check_budgets(
+    context: &Context,
+    opts: &QemuOpts,
+    profile: BootProfile,
+    boot_times: Option<&BootTimes>,
+) -> Result<()> {
+    let Some(budgets) = regressions::load_budgets(&opts.budgets)? else {
+        log::debug!("No budgets at {}, skipping regression checks", opts.budgets);
+        return Ok(());
+    };
+    let sizes = if budgets.sizes.is_empty() {
+        None
+    } else {
+        let binary = context.build(KERNEL_CRATE, profile, &[])?;
+        Some(regressions::section_sizes(&binary)?)
+    };
+    let tolerance = opts.budget_tolerance.unwrap_or(budgets.tolerance_percent);
+    regressions::check(&budgets, sizes.as_ref(), boot_times, tolerance)
+}
+
+/// Whether the kernel exited QEMU with [`ExitCode::Success`]
+fn exited_successfully(status: ExitStatus) -> bool {
+    status.code().and_then(ExitCode::from_qemu_status) == Some(ExitCode::Success)
+}
+
+/// Directory for sharded test runs' serial captures and merged report
+const TEST_SHARDS_DIR: &str = "target/test-shards";
+
+fn do_test_sharded(
+    context: &Context,
+    opts: &QemuOpts,
+    profile: BootProfile,
+    test_kernel: &Utf8Path,
+) -> Result<()> {
+    if opts.debugger || opts.debugger_wait {
+        bail!("--shards can't be combined with --debugger");
+    }
+
+    let dir = Utf8PathBuf::from(TEST_SHARDS_DIR);
+    fs::create_dir_all(&dir)?;
+    let capture = |index: u32| dir.join(format!("shard-{index}.bin"));
+
+    // Every VM boots the same image, so build it once up front rather than racing to rebuild it
+    let boot_image = context
+        .qemu
+        .boot_image(KERNEL_CRATE, test_kernel, opts.previous)?;
+
+    log::info!("Running tests in {} shards", opts.shards);
+    let outcomes = thread::scope(|scope| {
+        let handles = (0..opts.shards)
+            .map(|index| {
+                let capture = capture(index);
+                let scratch_disk = dir.join(format!("scratch-{index}.img"));
+                let boot_image = &boot_image;
+                scope.spawn(move || {
+                    let mut fw_cfg = opts.fw_cfg_items();
+                    fw_cfg.push(qemu::FwCfgItem {
+                        name: "opt/platypos/test-shard".to_string(),
+                        contents: qemu::FwCfgContents::String(format!("{index}/{}", opts.shards)),
+                    });
+                    context.qemu.run(qemu::Spec {
+                        crate_name: KERNEL_CRATE,
+                        binary: test_kernel,
+                        platform: context.platform,
+                        memory: &opts.memory,
+                        cpus: opts.cpus.into(),
+                        la57: opts.la57,
+                        debugger: None,
+                        replay: false,
+                        format: opts.trace_format.into(),
+                        max_bytes: opts.max_bytes,
+                        wall_clock: opts.wall_clock,
+                        renderers: fmt::Renderers::new(),
+                        filter: Filter::default(),
+                        headless: true,
+                        capture: Some(&capture),
+                        timeout: None,
+                        serial: SerialTransport::Stdio,
+                        previous: false,
+                        boot_menu: false,
+                        boot_image: Some(boot_image),
+                        scratch_disk: Some(&scratch_disk),
+                        quiet: true,
+                        milestones: Some(qemu::MilestoneSpec {
+                            names: &opts.milestones,
+                            timeout: Duration::from_secs(opts.milestone_timeout),
+                        }),
+                        fw_cfg,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("shard runner panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    let mut report = TestReport::new();
+    for (index, outcome) in (0..opts.shards).zip(outcomes) {
+        let outcome = outcome.wrap_err_with(|| format!("could not run test shard {index}"))?;
+        let mut results = Vec::new();
+        Decoder::new()
+            .decode(File::open(capture(index))?, io::sink(), |msg| {
+                results.extend(TestResult::from_message(&msg));
+                Ok(())
+            })
+            .wrap_err_with(|| format!("could not decode test shard {index}"))?;
+        let succeeded = exited_successfully(outcome.status) && outcome.crash.is_none();
+        report.add_shard(results, succeeded);
+    }
+
+    fs::write(dir.join("report.txt"), report.to_string())?;
+    if report.succeeded() {
+        log::info!("{report}");
+        // VMs booting in parallel slow each other down, so their boot times aren't comparable to
+        // the budgets
+        check_budgets(context, opts, profile, None)
+    } else {
+        log::error!("{report}");
+        log::info!(
+            "Serial captures saved to {}",
+            dir.if_supports_color(Stream::Stdout, |d| d.magenta())
+        );
+        bail!("Tests failed")
+    }
 }
 
 fn do_gdb() -> Result<()> {
     gdb::run()
 }
 
+fn do_trace(context: &Context, opts: TraceOpts) -> Result<()> {
+    let binary = context.build(KERNEL_CRATE, opts.profile, &opts.features)?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let dir = Utf8PathBuf::from(format!("target/traces/{timestamp}"));
+    fs::create_dir_all(&dir)?;
+    let capture = dir.join("trace.bin");
+
+    // The kernel doesn't shut down on its own, so QEMU's exit status isn't
+    // meaningful here
+    context.qemu.run(qemu::Spec {
+        crate_name: KERNEL_CRATE,
+        binary: &binary,
+        platform: context.platform,
+        memory: &opts.memory,
+        cpus: opts.cpus.into(),
+        la57: false,
+        debugger: None,
+        replay: false,
+        format: Profile::Compact,
+        max_bytes: fmt::Options::default().max_bytes,
+        wall_clock: false,
+        renderers: fmt::Renderers::new(),
+        filter: opts.filter.clone().unwrap_or_default(),
+        headless: true,
+        capture: Some(&capture),
+        timeout: Some(Duration::from_secs(opts.duration)),
+        serial: SerialTransport::Stdio,
+        previous: false,
+        boot_menu: false,
+        boot_image: None,
+        scratch_disk: None,
+        quiet: false,
+        milestones: None,
+        fw_cfg: opts.fw_cfg_items(),
+    })?;
+
+    let mut stats = Stats::new();
+    let mut chrome_trace = ChromeTrace::new();
+    let mut samples = profile::Profile::new();
+    let mut report = Report::new(format!("Trace {timestamp}"));
+    let times = receive_log(&capture)?;
+    let mut matcher = Matcher::new(opts.filter.unwrap_or_default());
+    Decoder::new()
+        .decode_with_offsets(File::open(&capture)?, io::sink(), |msg, offset| {
+            // Samples aren't spans or events, so the filter doesn't apply to them
+            samples.receive(&msg);
+            if matcher.matches(&msg) {
+                stats.receive(&msg);
+                chrome_trace.receive(&msg);
+                report_message(&mut report, &msg, offset, times.as_ref());
+            }
+            Ok(())
+        })
+        .wrap_err("could not decode captured trace")?;
+
+    stats.write_summary(File::create(dir.join("summary.txt"))?)?;
+    report.write_html(io::BufWriter::new(File::create(dir.join("report.html"))?))?;
+    if let Err(err) =
+        chrome_trace.write_json(io::BufWriter::new(File::create(dir.join("trace.json"))?))
+    {
+        log::warn!("Could not export trace for Perfetto: {err}");
+    }
+
+    // Weight the flamegraph by time where possible, but a kernel that never calibrated its clock
+    // only has event counts
+    stats.write_folded(File::create(dir.join("events.folded"))?)?;
+    let mut flamegraph_opts = inferno::flamegraph::Options::default();
+    let folded = if stats.has_span_time() {
+        let folded = dir.join("stacks.folded");
+        stats.write_time_folded(File::create(&folded)?)?;
+        flamegraph_opts.title = "Kernel time by span".to_string();
+        flamegraph_opts.count_name = "us".to_string();
+        folded
+    } else {
+        log::warn!("No timestamped spans, so the flamegraph is weighted by event count");
+        flamegraph_opts.title = "Kernel events by span".to_string();
+        flamegraph_opts.count_name = "events".to_string();
+        dir.join("events.folded")
+    };
+    if let Err(err) = inferno::flamegraph::from_files(
+        &mut flamegraph_opts,
+        &[folded.into_std_path_buf()],
+        File::create(dir.join("flamegraph.svg"))?,
+    ) {
+        log::warn!("Could not generate flamegraph: {err}");
+    }
+
+    if samples.samples() > 0 {
+        write_profile(&samples, &binary, &dir)?;
+    }
+
+    log::info!(
+        "Trace artifacts saved to {}",
+        dir.if_supports_color(Stream::Stdout, |d| d.magenta())
+    );
+    Ok(())
+}
+
+/// Save a CPU profile of `samples` in `dir`: a flat profile of the hottest
+/// functions, and a flamegraph of where samples landed
+fn write_profile(samples: &profile::Profile, binary: &Utf8Path, dir: &Utf8Path) -> Result<()> {
+    let symbolizer = qemu::GimliSymbolizer::new(binary)?;
+    samples.write_summary(&&symbolizer, 40, File::create(dir.join("profile.txt"))?)?;
+
+    let folded = dir.join("profile.folded");
+    samples.write_folded(&&symbolizer, File::create(&folded)?)?;
+
+    let mut flamegraph_opts = inferno::flamegraph::Options::default();
+    flamegraph_opts.title = "Kernel CPU profile".to_string();
+    flamegraph_opts.count_name = "samples".to_string();
+    if let Err(err) = inferno::flamegraph::from_files(
+        &mut flamegraph_opts,
+        &[folded.into_std_path_buf()],
+        File::create(dir.join("profile.svg"))?,
+    ) {
+        log::warn!("Could not generate profile flamegraph: {err}");
+    }
+
+    if samples.dropped() > 0 {
+        log::warn!(
+            "The kernel dropped {} of {} profile samples",
+            samples.dropped(),
+            samples.dropped() + samples.samples()
+        );
+    }
+    Ok(())
+}
+
+fn do_report(opts: ReportOpts) -> Result<()> {
+    let output = opts.output.unwrap_or_else(|| {
+        opts.capture
+            .parent()
+            .unwrap_or(Utf8Path::new(""))
+            .join("report.html")
+    });
+
+    let mut report = Report::new(opts.capture.as_str());
+    report.set_renderers(renderers(opts.memory_map.as_deref())?);
+    let times = receive_log(&opts.capture)?;
+    let mut matcher = Matcher::new(opts.filter.unwrap_or_default());
+    Decoder::new()
+        .decode_with_offsets(
+            File::open(&opts.capture)
+                .wrap_err_with(|| format!("could not open {}", opts.capture))?,
+            io::sink(),
+            |msg, offset| {
+                if matcher.matches(&msg) {
+                    report_message(&mut report, &msg, offset, times.as_ref());
+                }
+                Ok(())
+            },
+        )
+        .wrap_err("could not decode captured trace")?;
+    report.write_html(io::BufWriter::new(File::create(&output)?))?;
+
+    log::info!(
+        "Report saved to {}",
+        output.if_supports_color(Stream::Stdout, |o| o.magenta())
+    );
+    Ok(())
+}
+
+/// Renderers for trace field values, naming physical addresses with the memory
+/// map in `memory_map`, if given
+fn renderers(memory_map: Option<&Utf8Path>) -> Result<fmt::Renderers> {
+    let mut renderers = fmt::Renderers::new();
+    if let Some(path) = memory_map {
+        let text = fs::read_to_string(path).wrap_err_with(|| format!("could not read {path}"))?;
+        let map =
+            fmt::MemoryMap::parse(&text).ok_or_else(|| eyre!("malformed memory map {path}"))?;
+        renderers.register_type(FieldType::PhysicalAddress, map);
+    }
+    Ok(renderers)
+}
+
+/// Load the receive log saved next to `capture`, if there is one
+fn receive_log(capture: &Utf8Path) -> Result<Option<ReceiveLog>> {
+    let path = ReceiveLog::path_for(capture.as_std_path());
+    if path.exists() {
+        ReceiveLog::load(&path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Add a message that ended at `offset` in the capture to `report`, with its
+/// receive time from `times` if known
+fn report_message(
+    report: &mut Report,
+    msg: &ReceiverMessage,
+    offset: u64,
+    times: Option<&ReceiveLog>,
+) {
+    let msg = OwnedMessage::from(msg);
+    match times.and_then(|t| t.received(offset)) {
+        Some(received) => report.receive_at(&msg, received),
+        None => report.receive(&msg),
+    }
+}
+
+fn do_image(context: &Context, opts: ImageOpts) -> Result<()> {
+    let binary = context.build(KERNEL_CRATE, opts.profile, &[])?;
+    let boot_image = context.qemu.build_boot_image(KERNEL_CRATE, &binary)?;
+
+    let output = opts
+        .output
+        .unwrap_or_else(|| Utf8PathBuf::from(format!("target/platypos-{}.img", context.platform)));
+    fs::copy(&boot_image, &output)
+        .wrap_err_with(|| format!("could not copy boot image to {output}"))?;
+    log::info!(
+        "Boot image saved to {}",
+        output.if_supports_color(Stream::Stdout, |o| o.magenta())
+    );
+
+    if let Some(device) = opts.flash {
+        image::flash(&output, &device, opts.yes)?;
+    }
+    Ok(())
+}
+
+fn do_bench(context: &Context, opts: BenchOpts) -> Result<()> {
+    let profile = BootProfile::Test;
+    let output = context.cargo.build(&cargo::BuildSpec {
+        crate_name: KERNEL_CRATE,
+        platform: context.platform,
+        test: true,
+        defmt_filter: &context.defmt_filter,
+        profile,
+        features: &["bench".to_string()],
+    })?;
+    let test_kernel = output.profile_executable(KERNEL_CRATE, profile)?;
+
+    let dir = Utf8PathBuf::from("target/bench");
+    fs::create_dir_all(&dir)?;
+    let capture = dir.join("trace.bin");
+
+    let outcome = context.qemu.run(qemu::Spec {
+        crate_name: KERNEL_CRATE,
+        binary: &test_kernel,
+        platform: context.platform,
+        memory: &opts.memory,
+        cpus: 1,
+        la57: false,
+        debugger: None,
+        replay: false,
+        format: Profile::Compact,
+        max_bytes: fmt::Options::default().max_bytes,
+        wall_clock: false,
+        renderers: fmt::Renderers::new(),
+        filter: Filter::default(),
+        headless: true,
+        capture: Some(&capture),
+        timeout: Some(Duration::from_secs(300)),
+        serial: SerialTransport::Stdio,
+        previous: false,
+        boot_menu: false,
+        boot_image: None,
+        scratch_disk: None,
+        quiet: false,
+        milestones: None,
+        fw_cfg: vec![qemu::FwCfgItem {
+            name: "opt/platypos/test-filter".to_string(),
+            contents: qemu::FwCfgContents::String("root_allocator::bench".to_string()),
+        }],
+    })?;
+    if !exited_successfully(outcome.status) {
+        bail!("Benchmarks failed: {}", outcome.status);
+    }
+
+    let mut results = Vec::new();
+    Decoder::new()
+        .decode(File::open(&capture)?, io::sink(), |msg| {
+            results.extend(BenchResult::from_message(&msg));
+            Ok(())
+        })
+        .wrap_err("could not decode benchmark trace")?;
+    if results.is_empty() {
+        bail!("The kernel didn't report any benchmark results");
+    }
+
+    if opts.save_baseline {
+        if let Some(parent) = opts.baseline.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&opts.baseline, Baseline::from_results(&results).to_string())
+            .wrap_err_with(|| format!("could not write baseline {}", opts.baseline))?;
+        for result in results.iter() {
+            log::info!("{result}");
+        }
+        log::info!(
+            "Saved baseline to {}",
+            opts.baseline
+                .if_supports_color(Stream::Stdout, |b| b.magenta())
+        );
+        return Ok(());
+    }
+
+    let baseline = match fs::read_to_string(&opts.baseline) {
+        Ok(text) => Baseline::parse(&text)
+            .ok_or_else(|| eyre!("malformed benchmark baseline {}", opts.baseline))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            for result in results.iter() {
+                log::info!("{result}");
+            }
+            log::warn!(
+                "No baseline at {}, run with --save-baseline to create one",
+                opts.baseline
+            );
+            return Ok(());
+        }
+        Err(err) => return Err(err).wrap_err_with(|| format!("could not read {}", opts.baseline)),
+    };
+
+    let comparisons = baseline.compare(&results, opts.tolerance);
+    let mut regressions = 0;
+    for comparison in comparisons.iter() {
+        if comparison.regressed {
+            regressions += 1;
+            log::error!("{comparison}");
+        } else {
+            log::info!("{comparison}");
+        }
+    }
+    if regressions > 0 {
+        bail!(
+            "{regressions} benchmark(s) regressed by more than {}%",
+            opts.tolerance
+        );
+    }
+    Ok(())
+}
+
+fn do_boot_var(opts: BootVarOpts) -> Result<()> {
+    let mut vars = VarStore::load()?;
+    if opts.set.is_empty() && opts.unset.is_empty() {
+        for var in BootVar::ALL {
+            let name = var.to_possible_value().unwrap();
+            match vars.get(var) {
+                Some(value) => println!("{}={value}", name.get_name()),
+                None => println!("{} is not set", name.get_name()),
+            }
+        }
+        return Ok(());
+    }
+
+    for var in opts.unset {
+        vars.unset(var);
+    }
+    for (var, value) in opts.set {
+        vars.set(var, &value)?;
+    }
+    vars.save()?;
+    log::info!(
+        "Saved boot variables to {}",
+        efivars::VARS_FILE.if_supports_color(Stream::Stdout, |f| f.magenta())
+    );
+    Ok(())
+}
+
 /// Builds a GDB server configuration from the runner options
 fn gdb_server(opts: &QemuOpts, target_binary: &Utf8Path) -> Result<Option<gdb::Server>> {
     if opts.debugger || opts.debugger_wait {
@@ -4,6 +4,8 @@ mod command;
 mod output;
 mod platform;
 mod prelude;
+mod profile;
+mod regressions;
 mod tools;
 
 use command::XTask;
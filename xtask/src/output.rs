@@ -2,8 +2,8 @@ use clap::{Args, ValueEnum};
 use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use log::{LevelFilter, Log};
-use owo_colors::OwoColorize;
-use supports_color::Stream;
+use platypos_ktrace_decoder::proto::Level;
+use platypos_ktrace_decoder::theme::{ColorMode, Paint, Theme};
 
 #[derive(Debug, Args)]
 pub struct OutputOpts {
@@ -21,26 +21,28 @@ enum Color {
     Never,
 }
 
+impl From<Color> for ColorMode {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::Always => ColorMode::Always,
+            Color::Auto => ColorMode::Auto,
+            Color::Never => ColorMode::Never,
+        }
+    }
+}
+
 /// Very simple logger to respect command-line color/verbosity preferences
 struct OutputLog {
     filter: LevelFilter,
+    theme: Theme,
 }
 
 impl OutputOpts {
     pub(crate) fn init(self) -> Result<()> {
         color_eyre::install()?;
 
-        match self.color {
-            Color::Always => {
-                owo_colors::set_override(true);
-            }
-            Color::Auto => {
-                owo_colors::unset_override();
-            }
-            Color::Never => {
-                owo_colors::set_override(false);
-            }
-        }
+        // Everything else, including decoded traces, follows this through `ColorMode::Auto`
+        ColorMode::from(self.color).set_global();
 
         if matches!(self.color, Color::Always | Color::Auto) {
             enable_ansi_support::enable_ansi_support()
@@ -54,6 +56,7 @@ impl OutputOpts {
         };
         log::set_boxed_logger(Box::new(OutputLog {
             filter: level_filter,
+            theme: Theme::default(),
         }))?;
         log::set_max_level(level_filter);
 
@@ -68,13 +71,10 @@ impl Log for OutputLog {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
+            let paint = |icon, level| Paint::new(icon, self.theme.level(level), ColorMode::Auto);
             match record.level() {
-                log::Level::Error => {
-                    print!("{} ", "🚨".if_supports_color(Stream::Stdout, |c| c.red()))
-                }
-                log::Level::Warn => {
-                    print!("{}", "⚠️".if_supports_color(Stream::Stdout, |c| c.yellow()))
-                }
+                log::Level::Error => print!("{} ", paint("🚨", Level::Error)),
+                log::Level::Warn => print!("{}", paint("⚠️", Level::Warn)),
                 _ => (),
             }
 
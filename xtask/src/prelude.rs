@@ -4,3 +4,4 @@ pub use color_eyre::Result;
 pub use owo_colors::{OwoColorize, Stream};
 
 pub use crate::platform::Platform;
+pub use crate::profile::BootProfile;
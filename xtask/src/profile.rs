@@ -0,0 +1,40 @@
+use std::fmt;
+
+use clap::ValueEnum;
+
+/// Named set of kernel features, so that builds with different features don't
+/// overwrite each other's artifacts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BootProfile {
+    /// No optional features
+    Minimal,
+    /// Extra diagnostics and bookkeeping, for tracking down bugs
+    Debug,
+    /// Configuration for running in-kernel tests
+    Test,
+}
+
+impl BootProfile {
+    pub fn name(self) -> &'static str {
+        match self {
+            BootProfile::Minimal => "minimal",
+            BootProfile::Debug => "debug",
+            BootProfile::Test => "test",
+        }
+    }
+
+    /// Kernel features enabled by this profile
+    pub fn features(self) -> &'static [&'static str] {
+        match self {
+            BootProfile::Minimal => &[],
+            BootProfile::Debug => &["diagnostics", "splash"],
+            BootProfile::Test => &["diagnostics"],
+        }
+    }
+}
+
+impl fmt::Display for BootProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
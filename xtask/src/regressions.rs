@@ -0,0 +1,62 @@
+//! Code size and boot time regression gates for `cargo xtask test`
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use addr2line::object::{Object, ObjectSection};
+use platypos_ktrace_decoder::boot_time::BootTimes;
+use platypos_ktrace_decoder::regressions::Budgets;
+
+use crate::prelude::*;
+
+/// Load the budgets in `path`, if it exists
+pub fn load_budgets(path: &Utf8Path) -> Result<Option<Budgets>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Budgets::parse(&text)
+            .map(Some)
+            .wrap_err_with(|| format!("malformed budgets file {path}")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).wrap_err_with(|| format!("could not read {path}")),
+    }
+}
+
+/// Size of each loaded section in `binary`, plus their `total`
+pub fn section_sizes(binary: &Utf8Path) -> Result<BTreeMap<String, u64>> {
+    let data = fs::read(binary).wrap_err_with(|| format!("could not read {binary}"))?;
+    let object = addr2line::object::File::parse(&*data)
+        .wrap_err_with(|| format!("could not parse {binary}"))?;
+
+    let mut sizes = BTreeMap::<String, u64>::new();
+    let mut total = 0;
+    // Sections that aren't loaded, like debug info, have no address
+    for section in object.sections().filter(|s| s.address() != 0) {
+        let name = section.name().wrap_err("unreadable section name")?;
+        *sizes.entry(name.to_string()).or_default() += section.size();
+        total += section.size();
+    }
+    sizes.insert("total".to_string(), total);
+    Ok(sizes)
+}
+
+/// Check a run against `budgets`, logging a table of the results, and fail if
+/// anything went over
+pub fn check(
+    budgets: &Budgets,
+    sizes: Option<&BTreeMap<String, u64>>,
+    boot_times: Option<&BootTimes>,
+    tolerance_percent: u64,
+) -> Result<()> {
+    let checks = budgets.check(sizes, boot_times, tolerance_percent);
+    if checks.0.is_empty() {
+        return Ok(());
+    }
+    if checks.passed() {
+        log::info!("Within budget:\n{checks}");
+        Ok(())
+    } else {
+        log::error!("Over budget:\n{checks}");
+        let failures = checks.failures().count();
+        bail!("{failures} budget(s) exceeded by more than {tolerance_percent}%")
+    }
+}
@@ -1,3 +1,5 @@
 pub mod cargo;
+pub mod efivars;
 pub mod gdb;
+pub mod image;
 pub mod qemu;
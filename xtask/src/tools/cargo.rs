@@ -1,6 +1,7 @@
 //! Wrapper for running Cargo
 
 use std::collections::HashMap;
+use std::fs;
 use std::io::BufReader;
 use std::process::{Command, Stdio};
 
@@ -20,6 +21,10 @@ pub struct BuildSpec<'a> {
     /// Build as a test binary
     pub test: bool,
     pub defmt_filter: &'a str,
+    /// Profile selecting the crate's features
+    pub profile: BootProfile,
+    /// Extra crate features to enable, on top of the profile's
+    pub features: &'a [String],
 }
 
 pub struct BuildOutput {
@@ -85,6 +90,17 @@ impl Cargo {
             cmd.arg("--tests");
         }
 
+        let features = spec
+            .profile
+            .features()
+            .iter()
+            .copied()
+            .chain(spec.features.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        if !features.is_empty() {
+            cmd.args(["--features", &features.join(",")]);
+        }
+
         if !flags.rust_flags.is_empty() {
             let f = flags.rust_flags.join(" ");
             log::debug!("RUSTFLAGS = {f}");
@@ -98,6 +114,7 @@ impl Cargo {
         }
 
         cmd.env("DEFMT_LOG", spec.defmt_filter);
+        cmd.env("PLATYPOS_PROFILE", spec.profile.name());
 
         log::debug!("Cargo command line: {cmd:?}");
 
@@ -139,6 +156,9 @@ impl Cargo {
                     "-Clink-arg=-z".to_string(),
                     "-Clink-arg=nostart-stop-gc".to_string(),
                     "-Clink-arg=-T./link/eh_frame.ld".to_string(),
+                    "-Clink-arg=-T./link/sections.ld".to_string(),
+                    // Crash reports include the build ID, to match them with the binary
+                    "-Clink-arg=--build-id=sha1".to_string(),
                 ],
                 cxx_flags: vec!["-fno-stack-protector".to_string()],
             },
@@ -153,4 +173,29 @@ impl BuildOutput {
             .map(|p| p.as_path())
             .ok_or_else(|| eyre!("no executable for crate {crate_name}"))
     }
+
+    /// Copy the executable for `crate_name` to a name that includes
+    /// `profile`, so that artifacts derived from it (like boot images) are
+    /// kept separately for each profile
+    pub fn profile_executable(
+        &self,
+        crate_name: &str,
+        profile: BootProfile,
+    ) -> Result<Utf8PathBuf> {
+        let executable = self.executable(crate_name)?;
+        let copy =
+            executable.with_file_name(format!("{}-{profile}", executable.file_name().unwrap()));
+
+        // Only copy if Cargo rebuilt the executable, so the copy's timestamp
+        // says when the kernel last changed
+        let stale = match (fs::metadata(executable), fs::metadata(&copy)) {
+            (Ok(original), Ok(copied)) => original.modified()? > copied.modified()?,
+            _ => true,
+        };
+        if stale {
+            fs::copy(executable, &copy)
+                .wrap_err_with(|| format!("could not copy {executable} to {copy}"))?;
+        }
+        Ok(copy)
+    }
 }
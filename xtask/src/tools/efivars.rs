@@ -0,0 +1,320 @@
+//! PlatypOS's persistent boot variables, kept as UEFI variables in a copy of
+//! OVMF's variable store.
+//!
+//! OVMF keeps non-volatile variables in `OVMF_VARS.fd`, which QEMU maps as the
+//! second flash device. The file is a firmware volume holding a variable
+//! store: a header, then each variable's header, UTF-16 name, and data, one
+//! after another, with the unused space erased to `0xff`. Variables are never
+//! changed in place. Setting one marks the old copy deleted and appends a new
+//! one, which is also how the firmware does it, so the firmware can still
+//! read and reclaim the store.
+//!
+//! The bootloader can't pass these variables to the kernel, so xtask reads
+//! them back before each run and forwards them through fw_cfg.
+
+use std::fs;
+
+use clap::ValueEnum;
+
+use crate::prelude::*;
+
+/// Variable store the firmware starts from, with no variables set
+pub const TEMPLATE: &str = "/usr/share/ovmf/x64/OVMF_VARS.fd";
+
+/// Copy of the variable store with PlatypOS's variables in it. It's created
+/// the first time a variable is set.
+pub const VARS_FILE: &str = "target/OVMF_VARS.fd";
+
+/// Vendor GUID that PlatypOS's variables are namespaced under
+/// (`5c9e7a04-3f1d-4b6a-9c2e-8d0f6a1b7e35`), in its on-disk byte order
+const VENDOR_GUID: [u8; 16] = [
+    0x04, 0x7a, 0x9e, 0x5c, 0x1d, 0x3f, 0x6a, 0x4b, 0x9c, 0x2e, 0x8d, 0x0f, 0x6a, 0x1b, 0x7e, 0x35,
+];
+
+/// `gEfiAuthenticatedVariableGuid`, for stores with authenticated variable
+/// headers, which OVMF uses when built with Secure Boot
+const AUTHENTICATED_STORE_GUID: [u8; 16] = [
+    0x78, 0x2c, 0xf3, 0xaa, 0x7b, 0x94, 0x9a, 0x43, 0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92,
+];
+
+/// `gEfiVariableGuid`, for stores with plain variable headers
+const STORE_GUID: [u8; 16] = [
+    0x16, 0x36, 0xcf, 0xdd, 0x75, 0x32, 0x64, 0x41, 0x98, 0xb6, 0xfe, 0x85, 0x70, 0x7f, 0xfe, 0x7d,
+];
+
+/// Firmware volume header signature, `_FVH`
+const VOLUME_SIGNATURE: &[u8; 4] = b"_FVH";
+/// Offset of the signature in the firmware volume header
+const VOLUME_SIGNATURE_OFFSET: usize = 40;
+/// Offset of the firmware volume header's length
+const VOLUME_HEADER_LENGTH_OFFSET: usize = 48;
+
+/// Size of the variable store header
+const STORE_HEADER_SIZE: usize = 28;
+/// Variable store `Format` of a formatted store
+const STORE_FORMATTED: u8 = 0x5a;
+/// Variable store `State` of a healthy store
+const STORE_HEALTHY: u8 = 0xfe;
+
+/// `StartId` at the start of every variable header
+const VARIABLE_START: u16 = 0x55aa;
+/// Variable states. They're written by clearing bits, since flash can only be
+/// erased to all ones.
+const VAR_ADDED: u8 = 0x3f;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xfe;
+const VAR_DELETED: u8 = 0xfd;
+
+/// `EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS |
+/// EFI_VARIABLE_RUNTIME_ACCESS`
+const ATTRIBUTES: u32 = 0x7;
+
+/// A PlatypOS boot variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BootVar {
+    /// Most verbose level of kernel events to send: `off`, `error`, `warn`,
+    /// `info`, `debug`, or `trace`
+    LogLevel,
+    /// Which kernel image to boot first: `current` or `previous`
+    KernelImage,
+    /// What the kernel does after a panic: `halt`, `exit`, or
+    /// `reboot=SECONDS`
+    PanicPolicy,
+}
+
+impl BootVar {
+    pub const ALL: [BootVar; 3] = [
+        BootVar::LogLevel,
+        BootVar::KernelImage,
+        BootVar::PanicPolicy,
+    ];
+
+    /// UEFI variable name
+    fn name(self) -> &'static str {
+        match self {
+            BootVar::LogLevel => "LogLevel",
+            BootVar::KernelImage => "KernelImage",
+            BootVar::PanicPolicy => "PanicPolicy",
+        }
+    }
+
+    /// Check that `value` makes sense for this variable. The kernel ignores
+    /// malformed values, so it's better to catch them here.
+    fn validate(self, value: &str) -> Result<()> {
+        let valid = match self {
+            BootVar::LogLevel => {
+                ["off", "error", "warn", "info", "debug", "trace"].contains(&value)
+            }
+            BootVar::KernelImage => ["current", "previous"].contains(&value),
+            BootVar::PanicPolicy => match value.split_once('=') {
+                None => ["halt", "exit", "reboot"].contains(&value),
+                Some((policy, delay)) => policy == "reboot" && delay.parse::<u64>().is_ok(),
+            },
+        };
+        if !valid {
+            bail!(
+                "invalid {}: {value}",
+                self.to_possible_value().unwrap().get_name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A variable found in the store
+struct Variable {
+    /// Offset of its header
+    offset: usize,
+    name: String,
+    vendor: [u8; 16],
+    data: Vec<u8>,
+}
+
+/// A variable store, loaded into memory
+pub struct VarStore {
+    bytes: Vec<u8>,
+    /// Offset of the first variable
+    start: usize,
+    /// Offset just past the end of the store
+    end: usize,
+    /// Size of each variable's header
+    header_size: usize,
+}
+
+impl VarStore {
+    /// Load the store from [`VARS_FILE`], or from [`TEMPLATE`] if no variables
+    /// have been set yet
+    pub fn load() -> Result<VarStore> {
+        let path = if Utf8Path::new(VARS_FILE).exists() {
+            VARS_FILE
+        } else {
+            TEMPLATE
+        };
+        let bytes = fs::read(path).wrap_err_with(|| format!("could not read {path}"))?;
+        VarStore::parse(bytes).wrap_err_with(|| format!("{path} is not an OVMF variable store"))
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<VarStore> {
+        if bytes.get(VOLUME_SIGNATURE_OFFSET..VOLUME_SIGNATURE_OFFSET + 4)
+            != Some(&VOLUME_SIGNATURE[..])
+        {
+            bail!("missing firmware volume signature");
+        }
+        let header = usize::from(u16::from_le_bytes(
+            bytes[VOLUME_HEADER_LENGTH_OFFSET..VOLUME_HEADER_LENGTH_OFFSET + 2]
+                .try_into()
+                .unwrap(),
+        ));
+        let store = bytes
+            .get(header..header + STORE_HEADER_SIZE)
+            .ok_or_else(|| eyre!("truncated variable store header"))?;
+
+        let header_size = match store[..16].try_into().unwrap() {
+            AUTHENTICATED_STORE_GUID => 60,
+            STORE_GUID => 32,
+            _ => bail!("unknown variable store format"),
+        };
+        let size = u32::from_le_bytes(store[16..20].try_into().unwrap()) as usize;
+        if store[20] != STORE_FORMATTED || store[21] != STORE_HEALTHY {
+            bail!("variable store isn't formatted, or is damaged");
+        }
+        let end = header + size;
+        if end > bytes.len() {
+            bail!("variable store runs past the end of the file");
+        }
+
+        Ok(VarStore {
+            bytes,
+            start: align(header + STORE_HEADER_SIZE),
+            end,
+            header_size,
+        })
+    }
+
+    /// Save the store to [`VARS_FILE`]
+    pub fn save(&self) -> Result<()> {
+        fs::write(VARS_FILE, &self.bytes).wrap_err_with(|| format!("could not write {VARS_FILE}"))
+    }
+
+    /// The value of `var`, if it's set
+    pub fn get(&self, var: BootVar) -> Option<String> {
+        let variable = self.find(var)?;
+        String::from_utf8(variable.data).ok()
+    }
+
+    /// Set `var` to `value`
+    pub fn set(&mut self, var: BootVar, value: &str) -> Result<()> {
+        var.validate(value)?;
+        let name: Vec<u8> = var
+            .name()
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect();
+
+        let offset = self.free_space();
+        let size = self.header_size + name.len() + value.len();
+        if offset + size > self.end {
+            bail!("the variable store is full; delete {VARS_FILE} to start over");
+        }
+
+        // Delete the old copy first, like the firmware does, so there's never
+        // more than one live copy
+        self.unset(var);
+
+        let header = &mut self.bytes[offset..offset + self.header_size];
+        header.fill(0);
+        header[0..2].copy_from_slice(&VARIABLE_START.to_le_bytes());
+        header[2] = VAR_ADDED;
+        header[4..8].copy_from_slice(&ATTRIBUTES.to_le_bytes());
+        // The authenticated header's monotonic count, timestamp, and key index
+        // are left zero, as for any variable that isn't authenticated
+        let sizes = self.header_size - 24;
+        header[sizes..sizes + 4].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        header[sizes + 4..sizes + 8].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        header[sizes + 8..sizes + 24].copy_from_slice(&VENDOR_GUID);
+
+        let name_offset = offset + self.header_size;
+        self.bytes[name_offset..name_offset + name.len()].copy_from_slice(&name);
+        let data_offset = name_offset + name.len();
+        self.bytes[data_offset..data_offset + value.len()].copy_from_slice(value.as_bytes());
+        Ok(())
+    }
+
+    /// Delete `var`, if it's set
+    pub fn unset(&mut self, var: BootVar) {
+        if let Some(variable) = self.find(var) {
+            self.bytes[variable.offset + 2] &= VAR_DELETED;
+        }
+    }
+
+    fn find(&self, var: BootVar) -> Option<Variable> {
+        self.variables()
+            .find(|v| v.vendor == VENDOR_GUID && v.name == var.name())
+    }
+
+    /// Every live variable in the store
+    fn variables(&self) -> impl Iterator<Item = Variable> + '_ {
+        let mut offset = self.start;
+        std::iter::from_fn(move || loop {
+            let (variable, next) = self.variable_at(offset)?;
+            offset = next;
+            if let Some(variable) = variable {
+                return Some(variable);
+            }
+        })
+    }
+
+    /// Read the variable at `offset`, returning it if it's live along with
+    /// the offset of the next one. Returns `None` at the end of the
+    /// variables.
+    fn variable_at(&self, offset: usize) -> Option<(Option<Variable>, usize)> {
+        let header = self.bytes.get(offset..offset + self.header_size)?;
+        if offset + self.header_size > self.end
+            || u16::from_le_bytes([header[0], header[1]]) != VARIABLE_START
+        {
+            return None;
+        }
+        let state = header[2];
+        let sizes = self.header_size - 24;
+        let word = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap()) as usize;
+        let (name_size, data_size) = (word(sizes), word(sizes + 4));
+        let vendor = header[sizes + 8..sizes + 24].try_into().unwrap();
+
+        let name_offset = offset + self.header_size;
+        let data_offset = name_offset + name_size;
+        let next = align(data_offset + data_size);
+        if next > self.end {
+            return None;
+        }
+
+        let live = state == VAR_ADDED || state == VAR_ADDED & VAR_IN_DELETED_TRANSITION;
+        let variable = live.then(|| {
+            let name: Vec<u16> = self.bytes[name_offset..data_offset]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0)
+                .collect();
+            Variable {
+                offset,
+                name: String::from_utf16_lossy(&name),
+                vendor,
+                data: self.bytes[data_offset..data_offset + data_size].to_vec(),
+            }
+        });
+        Some((variable, next))
+    }
+
+    /// Offset of the erased space after the last variable
+    fn free_space(&self) -> usize {
+        let mut offset = self.start;
+        while let Some((_, next)) = self.variable_at(offset) {
+            offset = next;
+        }
+        offset
+    }
+}
+
+/// Variable headers are 4-byte aligned
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
@@ -0,0 +1,89 @@
+//! Writing boot images to removable drives, for testing on real hardware
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+
+use crate::prelude::*;
+
+/// Copy `image` onto the whole of `device`, erasing it. Unless `confirmed`,
+/// the user must type the device path to continue.
+pub fn flash(image: &Utf8Path, device: &Utf8Path, confirmed: bool) -> Result<()> {
+    check_device(device)?;
+
+    let mut target = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .wrap_err_with(|| format!("could not open {device} (are you allowed to write to it?)"))?;
+    let device_size = target.seek(SeekFrom::End(0))?;
+    target.rewind()?;
+
+    let image_size = fs::metadata(image)?.len();
+    if image_size > device_size {
+        bail!("{image} ({image_size} bytes) doesn't fit on {device} ({device_size} bytes)");
+    }
+
+    if !confirmed {
+        confirm(device, device_size)?;
+    }
+
+    log::info!(
+        "Writing {} to {}",
+        image.if_supports_color(Stream::Stdout, |i| i.magenta()),
+        device.if_supports_color(Stream::Stdout, |d| d.magenta())
+    );
+    io::copy(&mut File::open(image)?, &mut target)
+        .wrap_err_with(|| format!("could not write {image} to {device}"))?;
+    target
+        .sync_all()
+        .wrap_err_with(|| format!("could not flush writes to {device}"))?;
+    log::info!("Done, {device} can be removed");
+    Ok(())
+}
+
+/// Make sure `device` is a block device that isn't in use, to make it harder
+/// to overwrite the wrong thing
+#[cfg(unix)]
+fn check_device(device: &Utf8Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata =
+        fs::metadata(device).wrap_err_with(|| format!("could not find device {device}"))?;
+    if !metadata.file_type().is_block_device() {
+        bail!("{device} is not a block device");
+    }
+
+    // Only Linux has /proc/mounts. Elsewhere, the user has to check.
+    if let Ok(mounts) = fs::read_to_string("/proc/mounts") {
+        // Partitions are named after their disk, like /dev/sdb1 or /dev/nvme0n1p1
+        if let Some(mount) = mounts.lines().find(|line| {
+            line.split(' ')
+                .next()
+                .map_or(false, |m| m.starts_with(device.as_str()))
+        }) {
+            bail!("{device} is in use, unmount it first ({mount})");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_device(_device: &Utf8Path) -> Result<()> {
+    bail!("flashing is only supported on Unix-like systems")
+}
+
+/// Ask the user to confirm erasing `device` by typing its path
+fn confirm(device: &Utf8Path, size: u64) -> Result<()> {
+    eprint!(
+        "This will erase everything on {} ({:.1} GiB). Type the device path to continue: ",
+        device.if_supports_color(Stream::Stderr, |d| d.red()),
+        size as f64 / (1024.0 * 1024.0 * 1024.0)
+    );
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if answer.trim() != device.as_str() {
+        bail!("not writing to {device}");
+    }
+    Ok(())
+}
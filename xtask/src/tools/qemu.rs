@@ -1,17 +1,34 @@
 //! Wrapper around QEMU
 
 use std::ffi::OsString;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
 use std::process::ExitStatus;
-use std::rc::Rc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use platypos_ktrace_decoder::fmt::Formatter;
+use clap::ValueEnum;
+use platypos_hal::power::DEBUG_EXIT_PORT;
+use platypos_ktrace_decoder::boot_env::BootEnvironment;
+use platypos_ktrace_decoder::boot_time::BootTimes;
+use platypos_ktrace_decoder::clock::ReceiveLog;
+use platypos_ktrace_decoder::crash::{Crash, CrashSource};
+use platypos_ktrace_decoder::filter::{Filter, Matcher};
+use platypos_ktrace_decoder::fmt::{self, Formatter, Profile};
+use platypos_ktrace_decoder::input::Source;
+use platypos_ktrace_decoder::milestones::Milestones;
+use platypos_ktrace_decoder::proto::ReceiverMessage;
+use platypos_ktrace_decoder::replay::Replayer;
 use platypos_ktrace_decoder::Decoder;
 
 use crate::prelude::*;
-use crate::tools::qemu::symbolizer::GimliSymbolizer;
+pub(crate) use crate::tools::qemu::symbolizer::GimliSymbolizer;
 
-use super::cargo::Cargo;
+use super::cargo::{self, Cargo};
+use super::efivars;
 use super::gdb;
 
 mod symbolizer;
@@ -28,8 +45,186 @@ pub struct Spec<'a> {
     pub memory: &'a str,
     /// Number of CPUs for the VM
     pub cpus: usize,
+    /// Enable 5-level paging support (LA57) on the VM's CPUs
+    pub la57: bool,
     /// Debugger configuration
     pub debugger: Option<gdb::Server>,
+    /// Replay kernel traces through a host `tracing` subscriber instead of the
+    /// built-in formatter
+    pub replay: bool,
+    /// Layout for decoded traces, if not replaying them
+    pub format: Profile,
+    /// Maximum number of bytes to print for byte slice fields
+    pub max_bytes: usize,
+    /// Stamp decoded traces with the host's time of day
+    pub wall_clock: bool,
+    /// Custom rendering for trace field values
+    pub renderers: fmt::Renderers,
+    /// Which spans and events to print. Everything is still saved to
+    /// `capture`.
+    pub filter: Filter,
+    /// Run without a graphical display
+    pub headless: bool,
+    /// File to save the raw serial output to. When each part of it arrived
+    /// is saved alongside, in a [`ReceiveLog`].
+    pub capture: Option<&'a Utf8Path>,
+    /// Kill QEMU if it's still running after this long
+    pub timeout: Option<Duration>,
+    /// How to connect to the kernel's serial port
+    pub serial: SerialTransport,
+    /// Boot the image from before `binary` was last rebuilt, as a fallback
+    /// when a change breaks booting
+    pub previous: bool,
+    /// Give the firmware's boot menu a chance to pick another image, if there
+    /// is one. Needs a display.
+    pub boot_menu: bool,
+    /// Boot this image instead of building one from `binary`, so that several
+    /// VMs can share it
+    pub boot_image: Option<&'a Utf8Path>,
+    /// Scratch disk to attach, created if it doesn't exist. Defaults to
+    /// `scratch.img` next to `binary`. VMs running at the same time need
+    /// separate disks, since QEMU locks them.
+    pub scratch_disk: Option<&'a Utf8Path>,
+    /// Don't print decoded traces, only save them to `capture`
+    pub quiet: bool,
+    /// Boot milestones the kernel must reach
+    pub milestones: Option<MilestoneSpec<'a>>,
+    /// Named blobs to pass to the kernel through fw_cfg
+    pub fw_cfg: Vec<FwCfgItem>,
+}
+
+/// A named blob passed to the kernel through QEMU's fw_cfg interface
+#[derive(Debug, Clone)]
+pub struct FwCfgItem {
+    /// Name of the blob. The kernel expects names under `opt/platypos/`.
+    pub name: String,
+    pub contents: FwCfgContents,
+}
+
+#[derive(Debug, Clone)]
+pub enum FwCfgContents {
+    File(Utf8PathBuf),
+    String(String),
+}
+
+impl FwCfgItem {
+    /// QEMU `-fw_cfg` argument for this blob
+    fn to_arg(&self) -> String {
+        // QEMU option values escape commas by doubling them
+        let escape = |s: &str| s.replace(',', ",,");
+        match &self.contents {
+            FwCfgContents::File(path) => {
+                format!("name={},file={}", escape(&self.name), escape(path.as_str()))
+            }
+            FwCfgContents::String(s) => {
+                format!("name={},string={}", escape(&self.name), escape(s))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for FwCfgItem {
+    type Err = color_eyre::Report;
+
+    /// Parse a `NAME=PATH` pair. Names without a `/` are put under
+    /// `opt/platypos/`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, path) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("expected NAME=PATH, got {s}"))?;
+        let name = if name.contains('/') {
+            name.to_string()
+        } else {
+            format!("opt/platypos/{name}")
+        };
+        Ok(FwCfgItem {
+            name,
+            contents: FwCfgContents::File(Utf8PathBuf::from(path)),
+        })
+    }
+}
+
+/// How a QEMU run went
+pub struct Outcome {
+    pub status: ExitStatus,
+    /// The kernel's boot time summary, if it got far enough to log one
+    pub boot_times: Option<BootTimes>,
+    /// Why the kernel or loader crashed, if it did. This catches crashes even
+    /// if the panic policy kept QEMU running until the timeout.
+    pub crash: Option<Crash>,
+}
+
+/// Boot milestones that the kernel must reach, in order, before a timeout
+pub struct MilestoneSpec<'a> {
+    pub names: &'a [String],
+    /// How long the kernel has to reach every milestone
+    pub timeout: Duration,
+}
+
+/// How QEMU exposes the serial port that kernel traces are written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SerialTransport {
+    /// Multiplex the serial port onto QEMU's stdio
+    Stdio,
+    /// Serve the serial port on a local TCP port, keeping QEMU's stdio free
+    Tcp,
+    /// Serve the serial port on a Unix socket, keeping QEMU's stdio free
+    #[cfg(unix)]
+    Unix,
+}
+
+/// Chardev ID for the kernel serial port, when it's served over a socket
+const SERIAL_CHARDEV: &str = "ktrace0";
+
+/// How long to wait for QEMU to start listening on the serial socket
+const SERIAL_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the firmware waits for a key to open its boot menu, when there's
+/// more than one image to pick from
+const BOOT_MENU_TIMEOUT: Duration = Duration::from_secs(3);
+
+impl SerialTransport {
+    /// Pick a socket for QEMU to serve the serial port on, if this transport
+    /// uses one
+    fn socket(self) -> Result<Option<Source>> {
+        match self {
+            SerialTransport::Stdio => Ok(None),
+            SerialTransport::Tcp => {
+                // Let the OS pick a free port. There's a small window for something else to take
+                // it before QEMU binds it, but then QEMU will fail loudly.
+                let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+                Ok(Some(Source::Tcp(format!("127.0.0.1:{port}"))))
+            }
+            #[cfg(unix)]
+            SerialTransport::Unix => {
+                let path = std::env::temp_dir()
+                    .join(format!("platypos-ktrace-{}.sock", std::process::id()));
+                // Clean up a stale socket from a previous run with the same PID
+                let _ = fs::remove_file(&path);
+                Ok(Some(Source::Unix(path)))
+            }
+        }
+    }
+}
+
+/// QEMU `-chardev` argument that serves the serial port on `socket`. QEMU
+/// waits for the decoder to connect before starting the VM, so no output is
+/// lost.
+fn chardev_for(socket: &Source) -> Result<String> {
+    let address = match socket {
+        Source::Tcp(addr) => {
+            let (host, port) = addr
+                .rsplit_once(':')
+                .ok_or_else(|| eyre!("invalid TCP address {addr}"))?;
+            format!("host={host},port={port}")
+        }
+        #[cfg(unix)]
+        Source::Unix(path) => format!("path={}", path.display()),
+        other => bail!("{other} is not a socket"),
+    };
+    Ok(format!(
+        "socket,id={SERIAL_CHARDEV},{address},server=on,wait=on"
+    ))
 }
 
 /// Creates a new QEMU command for `platform`, including any
@@ -37,21 +232,28 @@ pub struct Spec<'a> {
 fn command_for(platform: Platform) -> (&'static str, Vec<OsString>) {
     match platform {
         Platform::X86_64 => {
-            let args: Vec<OsString> = [
+            let mut args: Vec<OsString> = [
                 // UEFI firmware
                 "-drive",
                 "if=pflash,format=raw,readonly=on,file=/usr/share/ovmf/x64/OVMF_CODE.fd",
-                "-drive",
-                "if=pflash,format=raw,readonly=on,file=/usr/share/ovmf/x64/OVMF_VARS.fd",
                 // CPU type
                 "-machine",
                 "q35,accel=kvm",
-                // Debug exit device
-                "-device",
-                "isa-debug-exit,iobase=0xf4,iosize=0x04",
             ]
             .map(Into::into)
             .into();
+            // UEFI variables, including PlatypOS's boot variables if any are set. The store is
+            // read-only, so VMs running at the same time can share it.
+            let vars = if Utf8Path::new(efivars::VARS_FILE).exists() {
+                efivars::VARS_FILE
+            } else {
+                efivars::TEMPLATE
+            };
+            args.push("-drive".into());
+            args.push(format!("if=pflash,format=raw,readonly=on,file={vars}").into());
+            // Debug exit device, for the kernel to report an `ExitCode`
+            args.push("-device".into());
+            args.push(format!("isa-debug-exit,iobase={DEBUG_EXIT_PORT:#x},iosize=0x04").into());
             ("qemu-system-x86_64", args)
         }
     }
@@ -60,25 +262,46 @@ fn command_for(platform: Platform) -> (&'static str, Vec<OsString>) {
 pub struct Qemu {
     /// Cargo wrapper, used for platforms that require additional bootloader
     /// compilation
-    cargo: Rc<Cargo>,
+    cargo: Arc<Cargo>,
 }
 
 impl Qemu {
-    pub fn new(cargo: Rc<Cargo>) -> Qemu {
+    pub fn new(cargo: Arc<Cargo>) -> Qemu {
         Qemu { cargo }
     }
 
-    pub fn run(&self, spec: Spec) -> Result<ExitStatus> {
+    pub fn run(&self, spec: Spec) -> Result<Outcome> {
         let (exe, mut args) = command_for(spec.platform);
-        // TODO: fifo for serial console so monitor can use stdio
-        args.extend(["--no-reboot", "-serial", "stdio", "-m", spec.memory].map(Into::into));
+        let socket = spec.serial.socket()?;
+        match socket {
+            Some(ref socket) => {
+                args.push("-chardev".into());
+                args.push(chardev_for(socket)?.into());
+                args.push("-serial".into());
+                args.push(format!("chardev:{SERIAL_CHARDEV}").into());
+            }
+            None => args.extend(["-serial", "stdio"].map(Into::into)),
+        }
+        args.extend(["--no-reboot", "-m", spec.memory].map(Into::into));
         args.push("-smp".into());
         args.push(format!("cpus={}", spec.cpus).into());
+        if spec.la57 {
+            args.extend(["-cpu", "qemu64,+la57"].map(Into::into));
+        }
 
         args.push("-d".into());
         args.push("cpu_reset,int".into());
 
+        if spec.headless {
+            args.extend(["-display", "none"].map(Into::into));
+        }
+
         self.add_binary(&mut args, &spec)?;
+        self.add_scratch_disk(&mut args, &spec)?;
+        for item in &spec.fw_cfg {
+            args.push("-fw_cfg".into());
+            args.push(item.to_arg().into());
+        }
 
         if let Some(ref gdb) = spec.debugger {
             self.add_gdb(&mut args, gdb);
@@ -88,29 +311,308 @@ impl Qemu {
 
         log::debug!("QEMU command: {cmd:?}");
 
-        // ReaderHandle will kill QEMU if it's dropped due to an error
-        let mut output = cmd.reader().wrap_err("could not start qemu")?;
+        match socket {
+            Some(socket) => {
+                // QEMU's own output goes straight to the terminal
+                let handle = cmd.start().wrap_err("could not start qemu")?;
+                log::debug!("Connecting to kernel serial port at {socket}");
+                let input = match socket.open_with_retry(SERIAL_CONNECT_TIMEOUT) {
+                    Ok(input) => input,
+                    Err(err) => {
+                        let _ = handle.kill();
+                        return Err(err.wrap_err("could not connect to QEMU serial port"));
+                    }
+                };
+
+                let (boot_times, crash) = self.decode_until_exit(input, &spec, || {
+                    let _ = handle.kill();
+                })?;
+                Ok(Outcome {
+                    status: handle.wait()?.status,
+                    boot_times,
+                    crash,
+                })
+            }
+            None => {
+                // ReaderHandle will kill QEMU if it's dropped due to an error
+                let output = cmd.reader().wrap_err("could not start qemu")?;
+
+                let (boot_times, crash) = self.decode_until_exit(&output, &spec, || {
+                    let _ = output.kill();
+                })?;
+
+                // Guaranteed that if the reader completed, this will return Ok(Some(_))
+                Ok(Outcome {
+                    status: output.try_wait().unwrap().unwrap().status,
+                    boot_times,
+                    crash,
+                })
+            }
+        }
+    }
+
+    /// Decode kernel output from `input` until QEMU exits, calling `kill` to
+    /// stop QEMU if it runs past the timeout. Returns the kernel's boot time
+    /// summary and crash report, if it sent them.
+    fn decode_until_exit<R: Read>(
+        &self,
+        input: R,
+        spec: &Spec,
+        kill: impl Fn() + Sync,
+    ) -> Result<(Option<BootTimes>, Option<Crash>)> {
+        let input: Box<dyn Read + '_> = match spec.capture {
+            Some(path) => {
+                let times = ReceiveLog::path_for(path.as_std_path());
+                Box::new(Tee {
+                    reader: input,
+                    copy: File::create(path)
+                        .wrap_err_with(|| format!("could not create capture file {path}"))?,
+                    times: io::BufWriter::new(File::create(&times).wrap_err_with(|| {
+                        format!("could not create receive log {}", times.display())
+                    })?),
+                    offset: 0,
+                })
+            }
+            None => Box::new(input),
+        };
+
+        let mut milestones = spec.milestones.as_ref().map(|m| Milestones::new(m.names));
+        let mut boot_times = None;
+        let mut boot_env = None;
+        let mut crash = None;
+
+        thread::scope(|scope| {
+            let kill = &kill;
+            let (done_tx, done_rx) = mpsc::channel::<()>();
+            if let Some(timeout) = spec.timeout {
+                scope.spawn(move || {
+                    if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+                        log::info!("Stopping QEMU after {}s", timeout.as_secs());
+                        kill();
+                    }
+                });
+            }
+
+            let (reached_tx, reached_rx) = mpsc::channel::<()>();
+            if let Some(ref m) = spec.milestones {
+                let timeout = m.timeout;
+                scope.spawn(move || {
+                    if let Err(RecvTimeoutError::Timeout) = reached_rx.recv_timeout(timeout) {
+                        log::error!(
+                            "Boot milestones not reached after {}s, stopping QEMU",
+                            timeout.as_secs()
+                        );
+                        kill();
+                    }
+                });
+            }
+
+            let result = self.decode(input, spec, |msg| {
+                if let Some(times) = BootTimes::from_message(msg) {
+                    boot_times = Some(times);
+                }
+                if let Some(env) = BootEnvironment::from_message(msg) {
+                    boot_env = Some(env);
+                }
+                if let Some(report) = Crash::from_message(msg) {
+                    crash = Some(report);
+                }
+                if let Some(ref mut milestones) = milestones {
+                    milestones.receive(msg);
+                    if milestones.is_complete() {
+                        let _ = reached_tx.send(());
+                    } else if milestones.has_failed() {
+                        kill();
+                    }
+                }
+            });
+            drop(done_tx);
+            drop(reached_tx);
+            result
+        })?;
+
+        if let Some(env) = boot_env {
+            record_boot_environment(spec, &env)?;
+        }
+        if let Some(ref times) = boot_times {
+            record_boot_times(spec, times)?;
+        }
+        if let Some(ref crash) = crash {
+            check_build_id(spec, crash);
+        }
+        if let Some(milestones) = milestones {
+            milestones.finish()?;
+        }
+        Ok((boot_times, crash))
+    }
 
+    /// Decode and print kernel output from `input`, passing each message to
+    /// `observe` as well
+    fn decode<R: Read>(
+        &self,
+        input: R,
+        spec: &Spec,
+        mut observe: impl FnMut(&ReceiverMessage),
+    ) -> Result<()> {
         // let filter = SymbolizeFilter::new(spec.binary)?;
-        let stdout = io::stdout().lock();
         let mut decoder = Decoder::new();
-        let symbolizer = GimliSymbolizer::new(spec.binary)?;
-        let mut formatter = Formatter::new(&symbolizer);
-        decoder.decode(&mut output, stdout, |msg| {
-            formatter.receive(&msg);
-            Ok(())
-        })?;
+        if spec.quiet {
+            return decoder.decode(input, io::sink(), |msg| {
+                observe(&msg);
+                Ok(())
+            });
+        }
 
-        // Guaranteed that if the reader completed, this will return Ok(Some(_))
-        Ok(output.try_wait().unwrap().unwrap().status)
+        let stdout = io::stdout().lock();
+        let symbolizer = GimliSymbolizer::new(spec.binary)?;
+        let mut matcher = Matcher::new(spec.filter.clone());
+        if spec.replay {
+            let dispatch = tracing::Dispatch::new(tracing_subscriber::fmt().finish());
+            let mut replayer = Replayer::new(dispatch, &symbolizer);
+            decoder.decode(input, stdout, |msg| {
+                observe(&msg);
+                if matcher.matches(&msg) {
+                    replayer.receive(&msg);
+                }
+                Ok(())
+            })
+        } else {
+            let mut formatter = Formatter::with_options(
+                &symbolizer,
+                fmt::Options {
+                    profile: spec.format,
+                    max_bytes: spec.max_bytes,
+                    wall_clock: spec.wall_clock,
+                    ..Default::default()
+                },
+            );
+            formatter.set_renderers(spec.renderers.clone());
+            decoder.decode(input, stdout, |msg| {
+                observe(&msg);
+                if matcher.matches(&msg) {
+                    formatter.receive_at(&msg, SystemTime::now());
+                }
+                Ok(())
+            })
+        }
     }
 
     /// Configure QEMU to boot `spec.binary` via the platform-appropriate
-    /// bootloader
+    /// bootloader. Unless `spec` names a specific image, the image from before
+    /// the last rebuild is attached too, as a second boot disk. The firmware
+    /// boots the disks in order, and with `spec.boot_menu`, it first waits
+    /// [`BOOT_MENU_TIMEOUT`] for a key that opens its boot menu, to pick the
+    /// other image.
     fn add_binary(&self, args: &mut Vec<OsString>, spec: &Spec) -> Result<()> {
-        let boot_image = x86_64::build_boot_image(spec.binary)?;
+        let images = match spec.boot_image {
+            Some(image) => vec![image.to_owned()],
+            None => self.boot_images(spec.crate_name, spec.binary, spec.previous)?,
+        };
+        for (index, image) in images.iter().enumerate() {
+            args.push("-drive".into());
+            args.push(format!("if=none,id=boot{index},format=raw,file={image}").into());
+            args.push("-device".into());
+            args.push(format!("ide-hd,drive=boot{index},bootindex={index}").into());
+        }
+        if images.len() > 1 && spec.boot_menu && !spec.headless {
+            args.push("-boot".into());
+            args.push(format!("menu=on,splash-time={}", BOOT_MENU_TIMEOUT.as_millis()).into());
+        }
+        Ok(())
+    }
+
+    /// Images to boot `binary` from, in boot order: a freshly-built one and
+    /// the one from before `binary` was last rebuilt, if there is one. With
+    /// `previous`, the older image comes first, and the newer one isn't
+    /// rebuilt, since that would replace the older one.
+    fn boot_images(
+        &self,
+        crate_name: &str,
+        binary: &Utf8Path,
+        previous: bool,
+    ) -> Result<Vec<Utf8PathBuf>> {
+        if previous {
+            let mut images = vec![self.boot_image(crate_name, binary, true)?];
+            images.extend(x86_64::existing_boot_image(binary)?);
+            Ok(images)
+        } else {
+            let mut images = vec![self.build_boot_image(crate_name, binary)?];
+            images.extend(x86_64::previous_boot_image(binary).ok());
+            Ok(images)
+        }
+    }
+
+    /// Get the image to boot `binary` from: either a freshly-built one, or
+    /// with `previous`, the one from before `binary` was last rebuilt
+    pub fn boot_image(
+        &self,
+        crate_name: &str,
+        binary: &Utf8Path,
+        previous: bool,
+    ) -> Result<Utf8PathBuf> {
+        if previous {
+            let image = x86_64::previous_boot_image(binary)?;
+            log::info!("Booting previous image {image}");
+            Ok(image)
+        } else {
+            self.build_boot_image(crate_name, binary)
+        }
+    }
+
+    /// Build a bootable disk image for `binary`, which was built from
+    /// `crate_name`, including the crate's initrd. The image has a GPT
+    /// partition table and an EFI system partition with the UEFI loader and
+    /// kernel, so it also boots on real hardware.
+    pub fn build_boot_image(&self, crate_name: &str, binary: &Utf8Path) -> Result<Utf8PathBuf> {
+        let initrd = self.build_initrd(crate_name, binary)?;
+        x86_64::build_boot_image(binary, initrd.as_deref())
+    }
+
+    /// Pack the crate's `initrd` directory, if it has one, into a tar archive
+    /// next to the kernel binary
+    fn build_initrd(&self, crate_name: &str, binary: &Utf8Path) -> Result<Option<Utf8PathBuf>> {
+        let source = cargo::manifest_path(crate_name).with_file_name("initrd");
+        if !source.is_dir() {
+            return Ok(None);
+        }
+
+        let archive = binary.with_file_name("initrd.tar");
+        let file = File::create(&archive)
+            .wrap_err_with(|| format!("could not create initrd {archive}"))?;
+        let mut builder = tar::Builder::new(file);
+        builder.mode(tar::HeaderMode::Deterministic);
+        builder
+            .append_dir_all(".", &source)
+            .and_then(|_| builder.finish())
+            .wrap_err_with(|| format!("could not pack initrd from {source}"))?;
+        Ok(Some(archive))
+    }
+
+    /// Attach a scratch disk as a legacy virtio-blk device, for testing the
+    /// kernel's storage paths. The disk is created next to the boot image if
+    /// it doesn't exist yet, and keeps its contents between runs.
+    fn add_scratch_disk(&self, args: &mut Vec<OsString>, spec: &Spec) -> Result<()> {
+        const SCRATCH_DISK_SIZE: u64 = 1024 * 1024;
+
+        let path = match spec.scratch_disk {
+            Some(path) => path.to_owned(),
+            None => spec
+                .binary
+                .parent()
+                .ok_or(eyre!("unexpected kernel location"))?
+                .join("scratch.img"),
+        };
+        if !path.exists() {
+            File::create(&path)
+                .and_then(|f| f.set_len(SCRATCH_DISK_SIZE))
+                .wrap_err_with(|| format!("could not create scratch disk {path}"))?;
+        }
+
         args.push("-drive".into());
-        args.push(format!("format=raw,file={boot_image}").into());
+        args.push(format!("if=none,id=scratch,format=raw,file={path}").into());
+        args.push("-device".into());
+        // The kernel only supports the legacy virtio interface
+        args.push("virtio-blk-pci,drive=scratch,disable-modern=on".into());
         Ok(())
     }
 
@@ -131,3 +633,111 @@ impl Qemu {
         }
     }
 }
+
+/// Reader adapter that saves a copy of everything read
+struct Tee<R, W> {
+    reader: R,
+    copy: W,
+    /// Receive log for `copy`
+    times: io::BufWriter<File>,
+    /// Bytes copied so far
+    offset: u64,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.reader.read(buf)?;
+        self.copy.write_all(&buf[..count])?;
+        if count > 0 {
+            self.offset += count as u64;
+            ReceiveLog::write_entry(&mut self.times, self.offset, SystemTime::now())?;
+        }
+        Ok(count)
+    }
+}
+
+/// File that boot time summaries are appended to, one line per boot
+const BOOT_TIMES_LOG: &str = "target/boot-times.log";
+
+/// Log the kernel's boot time summary, and append it to [`BOOT_TIMES_LOG`]
+/// along with the time and binary, for tracking regressions
+fn record_boot_times(spec: &Spec, times: &BootTimes) -> Result<()> {
+    log::info!("Booted in {}us ({times})", times.total_micros());
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(BOOT_TIMES_LOG)
+        .wrap_err_with(|| format!("could not open {BOOT_TIMES_LOG}"))?;
+    writeln!(
+        log,
+        "{timestamp} {} {times}",
+        spec.binary.file_name().unwrap_or(spec.crate_name)
+    )?;
+    Ok(())
+}
+
+/// Warn if a kernel crash report came from a different build than the binary
+/// being run, since its backtrace would be symbolized against the wrong code
+fn check_build_id(spec: &Spec, crash: &Crash) {
+    if crash.source != CrashSource::Kernel {
+        return;
+    }
+    let Some(reported) = crash.build_id else {
+        return;
+    };
+    match build_id(spec.binary) {
+        Some(expected) if expected != reported => log::warn!(
+            "Crash report is from a different build than {}, so its backtrace may be wrong",
+            spec.binary
+        ),
+        Some(_) => {}
+        None => log::debug!("Could not read the build ID of {}", spec.binary),
+    }
+}
+
+/// The GNU build ID `binary` was linked with, if it can be read
+fn build_id(binary: &Utf8Path) -> Option<Vec<u8>> {
+    use addr2line::object::Object;
+
+    let data = fs::read(binary).ok()?;
+    let object = addr2line::object::File::parse(&*data).ok()?;
+    object.build_id().ok()?.map(<[u8]>::to_vec)
+}
+
+/// File that boot environment reports are appended to, one line per boot
+const BOOT_ENVIRONMENTS_LOG: &str = "target/boot-environments.log";
+
+/// Append the kernel's boot environment report to [`BOOT_ENVIRONMENTS_LOG`],
+/// so flaky boots can be matched up with the firmware they ran on. Which image
+/// booted is worked out from the kernel's build ID, since it may have been
+/// picked from the firmware's boot menu.
+fn record_boot_environment(spec: &Spec, env: &BootEnvironment) -> Result<()> {
+    let current: Option<String> =
+        build_id(spec.binary).map(|id| id.iter().map(|b| format!("{b:02x}")).collect());
+    let image = if current.as_ref() == Some(&env.build_id) {
+        "current"
+    } else if spec.boot_image.is_none() && x86_64::previous_boot_image(spec.binary).is_ok() {
+        log::info!("Booted the previous image");
+        "previous"
+    } else {
+        "unknown"
+    };
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(BOOT_ENVIRONMENTS_LOG)
+        .wrap_err_with(|| format!("could not open {BOOT_ENVIRONMENTS_LOG}"))?;
+    writeln!(
+        log,
+        "{timestamp} {} image={image} {env}",
+        spec.binary.file_name().unwrap_or(spec.crate_name)
+    )?;
+    Ok(())
+}
@@ -84,4 +84,19 @@ impl<'a> Symbolizer for &'a GimliSymbolizer {
 
         Ok(())
     }
+
+    fn functions(&self, address: u64) -> Vec<String> {
+        let Ok(frames) = self.context.find_frames(address) else {
+            return Vec::new();
+        };
+        frames
+            .filter_map(|frame| {
+                // The demangled name can borrow from `name`, so copy it before `name` is dropped
+                Ok(frame
+                    .function
+                    .and_then(|name| name.demangle().ok().map(String::from)))
+            })
+            .collect()
+            .unwrap_or_default()
+    }
 }
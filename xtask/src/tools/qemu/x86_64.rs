@@ -1,8 +1,48 @@
 //! Platform-specific QEMU setup code for x86-64
 
+use std::fs;
+
 use crate::prelude::*;
 
-pub fn build_boot_image(binary: &Utf8Path) -> Result<Utf8PathBuf> {
+/// Build a UEFI disk image for `binary`. If the binary was rebuilt since the
+/// last image, the old image is kept as a fallback (see
+/// [`previous_boot_image`]).
+pub fn build_boot_image(binary: &Utf8Path, initrd: Option<&Utf8Path>) -> Result<Utf8PathBuf> {
+    let uefi_image_path = boot_image_path(binary, "img")?;
+    if is_newer(binary, &uefi_image_path)? {
+        let previous = boot_image_path(binary, "prev.img")?;
+        fs::rename(&uefi_image_path, &previous)
+            .wrap_err_with(|| format!("could not keep previous boot image as {previous}"))?;
+    }
+
+    let mut boot = bootloader::UefiBoot::new(binary.as_std_path());
+    if let Some(initrd) = initrd {
+        boot.set_ramdisk(initrd.as_std_path());
+    }
+    boot.create_disk_image(uefi_image_path.as_std_path())
+        .map_err(|err| eyre!(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+        .wrap_err("error creating UEFI disk image")?;
+    Ok(uefi_image_path)
+}
+
+/// The UEFI disk image last built for `binary`, if there is one. It may be
+/// older than `binary`.
+pub fn existing_boot_image(binary: &Utf8Path) -> Result<Option<Utf8PathBuf>> {
+    let path = boot_image_path(binary, "img")?;
+    Ok(path.exists().then_some(path))
+}
+
+/// The UEFI disk image `binary` had before it was last rebuilt, including the
+/// initrd it was built with
+pub fn previous_boot_image(binary: &Utf8Path) -> Result<Utf8PathBuf> {
+    let path = boot_image_path(binary, "prev.img")?;
+    if !path.exists() {
+        bail!("no previous boot image for {binary} (expected {path})");
+    }
+    Ok(path)
+}
+
+fn boot_image_path(binary: &Utf8Path, extension: &str) -> Result<Utf8PathBuf> {
     // To get to the target directory, go up two levels (kernel binary is in
     // `target/$mode/$target/`)
     let target_dir = binary
@@ -11,10 +51,15 @@ pub fn build_boot_image(binary: &Utf8Path) -> Result<Utf8PathBuf> {
         .ok_or(eyre!("unexpected kernel location"))?;
 
     let binary_name = binary.file_name().unwrap();
-    let uefi_image_path = target_dir.join(format!("uefi-{binary_name}.img"));
-    bootloader::UefiBoot::new(binary.as_std_path())
-        .create_disk_image(uefi_image_path.as_std_path())
-        .map_err(|err| eyre!(Box::<dyn std::error::Error + Send + Sync>::from(err)))
-        .wrap_err("error creating UEFI disk image")?;
-    Ok(uefi_image_path)
+    Ok(target_dir.join(format!("uefi-{binary_name}.{extension}")))
+}
+
+/// Whether `binary` was modified after `image` was created. Returns `false` if
+/// there's no image yet.
+fn is_newer(binary: &Utf8Path, image: &Utf8Path) -> Result<bool> {
+    let image_modified = match fs::metadata(image) {
+        Ok(metadata) => metadata.modified()?,
+        Err(_) => return Ok(false),
+    };
+    Ok(fs::metadata(binary)?.modified()? > image_modified)
 }